@@ -15,6 +15,8 @@
  * -----------------------------------------------------------------------------
  */
 
+#[cfg(feature = "grpc")]
+extern crate protoc_grpcio;
 extern crate protoc_rust;
 
 use protoc_rust::Customize;
@@ -28,18 +30,32 @@ use std::path::Path;
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("protos");
-    let proto_path = Path::new("./protos");
     fs::create_dir_all(&dest_path).unwrap();
 
-    // Run protoc
+    // Run protoc; the messages only derive serde's traits if the crate is built with `with-serde`
+    let customize = Customize {
+        serde_derive: Some(true),
+        ..Default::default()
+    };
     protoc_rust::run(protoc_rust::Args {
         out_dir: &dest_path.to_str().unwrap(),
-        input: &[proto_path.join("pbft_message.proto").to_str().unwrap()],
-        includes: &[proto_path.to_str().unwrap()],
-        customize: Customize::default(),
-    }).expect("Protoc Error");
+        input: &["pbft_message.proto", "pbft_admin.proto"],
+        includes: &["."],
+        customize: customize.clone(),
+    })
+    .expect("Protoc Error");
+
+    // The admin service itself is only generated with the `grpc` feature, since it needs grpcio
+    #[cfg(feature = "grpc")]
+    protoc_grpcio::compile_grpc_protos(&["pbft_admin.proto"], &["."], &dest_path, Some(customize))
+        .expect("Couldn't generate the admin service");
 
     // Create mod.rs accordingly
     let mut mod_file = File::create(dest_path.join("mod.rs")).unwrap();
-    mod_file.write_all(b"pub mod pbft_message;\n").unwrap();
+    mod_file
+        .write_all(b"pub mod pbft_admin;\npub mod pbft_message;\n")
+        .unwrap();
+    if cfg!(feature = "grpc") {
+        mod_file.write_all(b"pub mod pbft_admin_grpc;\n").unwrap();
+    }
 }
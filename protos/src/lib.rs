@@ -0,0 +1,97 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Sawtooth PBFT's Protobuf messages, on their own
+//!
+//! The `pbft_message` module has the messages that PBFT nodes send each other (`PbftMessage`,
+//! `PbftNewView`, and the rest) and `PbftSeal`, the proof that a block was committed, generated
+//! from `pbft_message.proto` with rust-protobuf. Block explorers, auditors, and test tools can
+//! parse PBFT payloads with this crate and `protobuf::parse_from_bytes`, without depending on the
+//! consensus engine or the Sawtooth SDK. To check a seal, rather than just read it, use the
+//! `sawtooth-pbft` library's `seal-verification` feature.
+//!
+//! With the `with-serde` feature, every message derives serde's `Serialize` and `Deserialize`,
+//! for tools that work with JSON.
+//!
+//! The `pbft_admin` module has the messages of the admin service that a node serves with the
+//! engine's `grpc-admin` feature, generated from `pbft_admin.proto`. With the `grpc` feature, the
+//! `pbft_admin_grpc` module has the service's grpcio server trait and client as well.
+
+#[cfg(feature = "grpc")]
+extern crate futures;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
+extern crate protobuf;
+#[cfg(feature = "with-serde")]
+extern crate serde;
+#[cfg(feature = "with-serde")]
+#[macro_use]
+extern crate serde_derive;
+
+// Includes the autogenerated protobuf messages
+include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
+
+mod hash;
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "with-serde")]
+    extern crate serde_json;
+
+    use super::pbft_message::{PbftMessage, PbftMessageInfo, PbftSeal};
+    use protobuf::{self, Message, RepeatedField};
+
+    fn seal() -> PbftSeal {
+        let mut info = PbftMessageInfo::new();
+        info.set_msg_type(String::from("Commit"));
+        info.set_view(2);
+        info.set_seq_num(7);
+        info.set_signer_id(vec![0xaa]);
+        let mut commit = PbftMessage::new();
+        commit.set_info(info);
+        commit.mut_block().set_block_id(vec![0x01, 0x02]);
+
+        let mut seal = PbftSeal::new();
+        seal.set_version(1);
+        seal.set_seq_num(7);
+        seal.set_block(commit.get_block().clone());
+        seal.set_commit_messages(RepeatedField::from_vec(vec![commit]));
+        seal
+    }
+
+    /// Make sure that a seal written by a node can be parsed back with this crate alone
+    #[test]
+    fn seal_round_trip() {
+        let seal = seal();
+        let bytes = seal.write_to_bytes().unwrap();
+        let parsed: PbftSeal = protobuf::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, seal);
+        assert_eq!(parsed.get_commit_messages()[0].get_info().get_view(), 2);
+    }
+
+    /// Make sure that, with the `with-serde` feature, messages can be written as JSON and read
+    /// back
+    #[cfg(feature = "with-serde")]
+    #[test]
+    fn seal_json() {
+        let seal = seal();
+        let json = serde_json::to_string(&seal).unwrap();
+        assert!(json.contains("\"seq_num\":7"));
+        let parsed: PbftSeal = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, seal);
+    }
+}
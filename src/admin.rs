@@ -0,0 +1,609 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Commands for operators to run on a live node
+//!
+//! If the engine is given an admin address and a token file, an `AdminServer` takes commands over
+//! HTTP at that address, from clients that send the token as `Authorization: Bearer <token>`:
+//!
+//! - `GET /state` and `GET /peers` show the node's state and what it knows about each other node
+//! - `POST /dump` writes the node's state and message log to the crash dump directory
+//! - `POST /view-change` starts a view change
+//! - `POST /maintenance` and `DELETE /maintenance` put the node in maintenance mode and take it out
+//! - `POST /catch-up` asks the node that's furthest along for the seals this node is missing
+//!
+//! With the `grpc-admin` feature, the same commands can be taken over gRPC as well, as the
+//! `PbftAdmin` service in `pbft_admin.proto`, from clients that send the same token (see
+//! `admin_grpc`).
+//!
+//! Clients are served on threads of their own, which read each request and check its token, so
+//! a slow or hostile client can't hold up the engine. Authorized commands are handed to the
+//! engine's event loop, which runs them when it polls the server, between updates, and never at
+//! the same time as the node is handling one.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "grpc-admin")]
+use grpcio;
+use hex;
+use serde_json::{self, Value};
+
+#[cfg(feature = "grpc-admin")]
+use admin_grpc;
+use consensus_service::ConsensusService;
+use crash_dump;
+use node::PbftNode;
+use state::PbftState;
+use status::{self, NodeStatus};
+use view_stats::ViewChangeReason;
+
+/// How long to wait for a client that is slow to send its command or to read the response
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long a client waits for the engine to run its command before being told to try again
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A response's HTTP status and JSON body
+type Response = (&'static str, String);
+
+/// A command that an operator can run on the node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminCommand {
+    GetState,
+    GetPeers,
+    Dump,
+    StartViewChange,
+    SetMaintenance(bool),
+    CatchUp,
+}
+
+/// What running a command did, besides what the node's state shows
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Done,
+
+    /// The dump was written to this path
+    Dumped(PathBuf),
+
+    /// The sequence number the node is catching up to (`None` if no node is further along)
+    CatchingUp(Option<u64>),
+}
+
+/// Why a command couldn't be run
+#[derive(Debug, PartialEq)]
+pub enum AdminError {
+    /// A dump was asked for, but the node has nowhere to write one
+    NoDumpDirectory,
+
+    Failed(String),
+}
+
+/// Turns what a command did into the reply for its client, on the engine's thread, where the
+/// node's state can be read
+pub type Reply = Box<FnOnce(&PbftState, Result<Outcome, AdminError>) + Send>;
+
+/// A command from an authorized client, waiting for the engine to run it
+pub struct Command {
+    pub command: AdminCommand,
+    pub reply: Reply,
+}
+
+/// Takes operators' commands over HTTP, and over gRPC with the `grpc-admin` feature
+pub struct AdminServer {
+    token: String,
+
+    /// Where the HTTP server is listening, and its thread
+    http: Option<(SocketAddr, JoinHandle<()>)>,
+
+    #[cfg(feature = "grpc-admin")]
+    grpc: Option<grpcio::Server>,
+
+    /// Set to stop the server's threads
+    stop: Arc<AtomicBool>,
+
+    /// Commands that clients are waiting on
+    sender: Sender<Command>,
+    commands: Receiver<Command>,
+
+    /// Where `POST /dump` writes dumps (dumps can't be written if `None`)
+    crash_dump_dir: Option<PathBuf>,
+}
+
+impl AdminServer {
+    /// Get ready to take commands from clients with the token in the given file; they're taken
+    /// once the server is listening on an address
+    pub fn new(token_file: &Path, crash_dump_dir: Option<PathBuf>) -> io::Result<Self> {
+        let token = fs::read_to_string(token_file)?.trim().to_string();
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Admin token file {:?} is empty", token_file),
+            ));
+        }
+
+        let (sender, commands) = mpsc::channel();
+        Ok(AdminServer {
+            token,
+            http: None,
+            #[cfg(feature = "grpc-admin")]
+            grpc: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            sender,
+            commands,
+            crash_dump_dir,
+        })
+    }
+
+    /// Start listening for commands over HTTP on the given address, in a thread of its own, from
+    /// clients with the token in the given file
+    pub fn bind(
+        addr: &SocketAddr,
+        token_file: &Path,
+        crash_dump_dir: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        let mut server = AdminServer::new(token_file, crash_dump_dir)?;
+        server.listen(addr)?;
+        Ok(server)
+    }
+
+    /// Take commands over HTTP on the given address, in a thread of its own
+    pub fn listen(&mut self, addr: &SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let token = self.token.clone();
+        let sender = self.sender.clone();
+        let stopped = Arc::clone(&self.stop);
+        let thread = thread::Builder::new()
+            .name(String::from("pbft-admin"))
+            .spawn(move || serve(&listener, &token, &sender, &stopped))?;
+
+        self.http = Some((local_addr, thread));
+        Ok(())
+    }
+
+    /// Take commands over gRPC on the given address, on grpcio's threads
+    #[cfg(feature = "grpc-admin")]
+    pub fn listen_grpc(&mut self, addr: &SocketAddr) -> io::Result<()> {
+        let server = admin_grpc::serve(addr, self.token.clone(), self.sender.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        self.grpc = Some(server);
+        Ok(())
+    }
+
+    /// The address the HTTP server is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.http
+            .as_ref()
+            .map(|&(addr, _)| addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Not listening for HTTP"))
+    }
+
+    /// Run every command that is waiting, without waiting for any more
+    pub fn poll<S: ConsensusService + ?Sized>(&self, node: &mut PbftNode<S>) {
+        while let Ok(command) = self.commands.try_recv() {
+            let result = self.run(command.command, node);
+            (command.reply)(&node.state, result);
+        }
+    }
+
+    // Run a command on the node
+    fn run<S: ConsensusService + ?Sized>(
+        &self,
+        command: AdminCommand,
+        node: &mut PbftNode<S>,
+    ) -> Result<Outcome, AdminError> {
+        let result = match command {
+            AdminCommand::GetState | AdminCommand::GetPeers => Ok(()),
+            AdminCommand::Dump => return self.dump(node),
+            AdminCommand::StartViewChange => {
+                warn!("{}: Operator asked for a view change", node.state);
+                node.start_view_change(ViewChangeReason::Operator)
+            }
+            AdminCommand::SetMaintenance(maintenance) => node.set_maintenance(maintenance),
+            AdminCommand::CatchUp => {
+                return node
+                    .force_catch_up()
+                    .map(Outcome::CatchingUp)
+                    .map_err(|err| AdminError::Failed(err.to_string()));
+            }
+        };
+        result
+            .map(|()| Outcome::Done)
+            .map_err(|err| AdminError::Failed(err.to_string()))
+    }
+
+    fn dump<S: ConsensusService + ?Sized>(
+        &self,
+        node: &PbftNode<S>,
+    ) -> Result<Outcome, AdminError> {
+        let dir = match self.crash_dump_dir {
+            Some(ref dir) => dir,
+            None => return Err(AdminError::NoDumpDirectory),
+        };
+        match crash_dump::write_crash_dump(dir, "operator request", &node.state, &node.msg_log) {
+            Ok(path) => {
+                info!("Wrote dump to {:?}, as an operator asked", path);
+                Ok(Outcome::Dumped(path))
+            }
+            Err(err) => Err(AdminError::Failed(err.to_string())),
+        }
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some((addr, thread)) = self.http.take() {
+            // The thread is waiting for a connection, so give it one to notice it should stop
+            let _ = TcpStream::connect_timeout(&addr, CLIENT_TIMEOUT);
+            let _ = thread.join();
+        }
+    }
+}
+
+// Answer clients, one at a time, until told to stop
+fn serve(listener: &TcpListener, token: &str, commands: &Sender<Command>, stop: &AtomicBool) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = respond(stream, token, commands, stop) {
+                    debug!("Couldn't answer admin command: {}", err);
+                }
+            }
+            Err(err) => warn!("Couldn't accept admin connection: {}", err),
+        }
+    }
+}
+
+// Read a client's request, have the engine run its command if the client sent the token, and
+// send back the result
+fn respond(
+    mut stream: TcpStream,
+    token: &str,
+    commands: &Sender<Command>,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // The request may come in pieces, so its headers are all read before it's answered
+    let mut request = [0; 4096];
+    let mut len = 0;
+    while len < request.len() && !request[..len].windows(4).any(|end| end == b"\r\n\r\n") {
+        match stream.read(&mut request[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let request = String::from_utf8_lossy(&request[..len]);
+    let (status, body) = run_command(&request, token, commands, stop);
+
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        body.len() + 1
+    )?;
+    writeln!(stream, "{}", body)
+}
+
+// Hand the command in the given request to the engine, if its client sent the token, and wait for
+// the engine to run it
+fn run_command(
+    request: &str,
+    token: &str,
+    commands: &Sender<Command>,
+    stop: &AtomicBool,
+) -> Response {
+    let mut words = request.split_whitespace();
+    let method = words.next().unwrap_or("");
+    let path = words.next().unwrap_or("");
+
+    if !is_authorized(token, request) {
+        warn!("Refused admin command {} {}; wrong token", method, path);
+        return ("401 Unauthorized", error_json("wrong or missing token"));
+    }
+
+    let command = match parse_command(method, path) {
+        Some(command) => command,
+        None => return ("404 Not Found", error_json("unknown command")),
+    };
+
+    let (reply, response) = mpsc::channel();
+    let command = Command {
+        command,
+        reply: Box::new(move |state: &PbftState, result| {
+            // The client may have given up waiting
+            let _ = reply.send(http_response(command, state, result));
+        }),
+    };
+    if commands.send(command).is_err() {
+        return (
+            "503 Service Unavailable",
+            error_json("node is shutting down"),
+        );
+    }
+
+    // Waited on in slices, so the thread can stop even if the engine never gets to the command
+    let started = Instant::now();
+    loop {
+        match response.recv_timeout(CLIENT_TIMEOUT) {
+            Ok(response) => return response,
+            Err(RecvTimeoutError::Timeout)
+                if !stop.load(Ordering::SeqCst) && started.elapsed() < COMMAND_TIMEOUT => {}
+            Err(_) => {
+                return (
+                    "503 Service Unavailable",
+                    error_json("node didn't run the command in time"),
+                )
+            }
+        }
+    }
+}
+
+// The command that an HTTP method and path stand for
+fn parse_command(method: &str, path: &str) -> Option<AdminCommand> {
+    match (method, path) {
+        ("GET", "/state") => Some(AdminCommand::GetState),
+        ("GET", "/peers") => Some(AdminCommand::GetPeers),
+        ("POST", "/dump") => Some(AdminCommand::Dump),
+        ("POST", "/view-change") => Some(AdminCommand::StartViewChange),
+        ("POST", "/maintenance") => Some(AdminCommand::SetMaintenance(true)),
+        ("DELETE", "/maintenance") => Some(AdminCommand::SetMaintenance(false)),
+        ("POST", "/catch-up") => Some(AdminCommand::CatchUp),
+        _ => None,
+    }
+}
+
+// The status and body of the HTTP response to a command
+fn http_response(
+    command: AdminCommand,
+    state: &PbftState,
+    result: Result<Outcome, AdminError>,
+) -> Response {
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(AdminError::NoDumpDirectory) => {
+            return ("409 Conflict", error_json("no crash dump directory"))
+        }
+        Err(AdminError::Failed(err)) => return ("500 Internal Server Error", error_json(&err)),
+    };
+
+    let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+    match (command, outcome) {
+        (AdminCommand::GetState, _) => return ("200 OK", status::state_to_json(state)),
+        (AdminCommand::GetPeers, _) => return ("200 OK", peers_to_json(state)),
+        (_, Outcome::Dumped(path)) => {
+            fields.insert(
+                String::from("path"),
+                Value::from(path.to_string_lossy().into_owned()),
+            );
+        }
+        (_, Outcome::CatchingUp(target)) => {
+            fields.insert(
+                String::from("target_seq_num"),
+                target.map_or(Value::Null, Value::from),
+            );
+        }
+        (_, Outcome::Done) => return ("200 OK", NodeStatus::new(state).to_json()),
+    }
+    (
+        "200 OK",
+        serde_json::to_string(&fields).expect("Couldn't write JSON"),
+    )
+}
+
+// Whether the request has the expected token in its `Authorization` header
+fn is_authorized(expected: &str, request: &str) -> bool {
+    let authorization = request
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("authorization") => {
+                    Some(value)
+                }
+                _ => None,
+            }
+        })
+        .next();
+    has_token(expected, authorization)
+}
+
+/// Whether an `Authorization` value is `Bearer` with the expected token, compared without stopping
+/// at the first difference, so how long it takes doesn't give away how much of a guess was right
+pub fn has_token(expected: &str, authorization: Option<&str>) -> bool {
+    let token = authorization.map(|value| value.trim()).and_then(|value| {
+        if value.starts_with("Bearer ") {
+            Some(value["Bearer ".len()..].trim())
+        } else {
+            None
+        }
+    });
+
+    match token {
+        Some(token) if token.len() == expected.len() => {
+            token
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+/// Describe what this node knows about each other member of the network as JSON, by peer ID
+pub fn peers_to_json(state: &PbftState) -> String {
+    let own_peer_id = state.get_own_peer_id();
+    let quarantined = state.quarantine.quarantined();
+    let mut peers: BTreeMap<String, Value> = BTreeMap::new();
+    for (id, peer_id) in state.peers().iter().enumerate() {
+        if *peer_id == own_peer_id {
+            continue;
+        }
+
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+        fields.insert(String::from("id"), Value::from(id as u64));
+        fields.insert(
+            String::from("connected"),
+            Value::Bool(!state.outbox.is_disconnected(peer_id)),
+        );
+        fields.insert(
+            String::from("quarantined"),
+            Value::Bool(quarantined.contains(&peer_id)),
+        );
+        if let Some(stats) = state.peer_stats.get(peer_id) {
+            let total = |counts: &BTreeMap<String, u64>| Value::from(counts.values().sum::<u64>());
+            fields.insert(String::from("sent"), total(&stats.sent));
+            fields.insert(String::from("received"), total(&stats.received));
+            fields.insert(String::from("rejected"), total(&stats.rejected));
+            fields.insert(
+                String::from("highest_seq_num"),
+                Value::from(stats.highest_seq_num),
+            );
+            fields.insert(
+                String::from("seconds_since_last_seen"),
+                stats
+                    .since_last_seen()
+                    .map_or(Value::Null, |since| Value::from(since.as_secs())),
+            );
+            fields.insert(
+                String::from("clock_offset_ms"),
+                stats
+                    .clock_skew
+                    .map_or(Value::Null, |skew| Value::from(skew.offset_ms)),
+            );
+        }
+
+        let peer = hex::encode(Vec::<u8>::from(peer_id.clone()));
+        peers.insert(peer, Value::Object(fields.into_iter().collect()));
+    }
+    serde_json::to_string(&peers).expect("Couldn't write peers as JSON")
+}
+
+fn error_json(error: &str) -> String {
+    let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+    fields.insert(String::from("error"), Value::from(error));
+    serde_json::to_string(&fields).expect("Couldn't write JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use std::env;
+
+    fn token_file(name: &str, token: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pbft-admin-{}-{}", name, ::std::process::id()));
+        fs::write(&path, token).unwrap();
+        path
+    }
+
+    /// Make sure that the admin server won't start without a token, and only runs commands from
+    /// clients that send the right one
+    #[test]
+    fn admin_token() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let empty = token_file("empty", " \n");
+        assert!(AdminServer::bind(&addr, &empty, None).is_err());
+        fs::remove_file(empty).unwrap();
+
+        let file = token_file("token", "s3cret\n");
+        let server = AdminServer::bind(&addr, &file, None).unwrap();
+        fs::remove_file(file).unwrap();
+        assert_ne!(server.local_addr().unwrap().port(), 0);
+
+        let authorized = |auth: &str| {
+            let request = format!("POST /view-change HTTP/1.0\r\n{}\r\n\r\n", auth);
+            is_authorized("s3cret", &request)
+        };
+        assert!(authorized("Authorization: Bearer s3cret"));
+        assert!(authorized("authorization:  Bearer s3cret "));
+        assert!(!authorized("Authorization: Bearer s3crex"));
+        assert!(!authorized("Authorization: Bearer s3cre"));
+        assert!(!authorized("Authorization: Basic s3cret"));
+        assert!(!authorized("X-Token: s3cret"));
+        // The token has to be in the headers, not the body
+        assert!(!is_authorized(
+            "s3cret",
+            "POST /dump HTTP/1.0\r\n\r\nAuthorization: Bearer s3cret"
+        ));
+    }
+
+    /// Make sure that clients are answered by the server's own thread, which only hands the
+    /// engine commands from clients with the token, and sends back what the engine answers
+    #[test]
+    fn admin_thread() {
+        let file = token_file("thread", "s3cret");
+        let server = AdminServer::bind(&"127.0.0.1:0".parse().unwrap(), &file, None).unwrap();
+        fs::remove_file(file).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let send = move |request: &'static str| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            })
+        };
+
+        // A client without the token is refused without the engine being involved
+        let refused = send("POST /view-change HTTP/1.0\r\n\r\n").join().unwrap();
+        assert!(refused.starts_with("HTTP/1.0 401 Unauthorized\r\n"));
+        assert!(server.commands.try_recv().is_err());
+
+        // A slow client doesn't keep the next one from being answered
+        let _idle = TcpStream::connect(addr).unwrap();
+        let client = send("GET /peers HTTP/1.0\r\nAuthorization: Bearer s3cret\r\n\r\n");
+        let command = server
+            .commands
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(command.command, AdminCommand::GetPeers);
+        let state = PbftState::new(0, &mock_config(4));
+        (command.reply)(&state, Ok(Outcome::Done));
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with(&format!("\r\n\r\n{}\n", peers_to_json(&state))));
+
+        // A command the server doesn't know is refused without the engine being involved too
+        let unknown = send("GET /nothing HTTP/1.0\r\nAuthorization: Bearer s3cret\r\n\r\n")
+            .join()
+            .unwrap();
+        assert!(unknown.starts_with("HTTP/1.0 404 Not Found\r\n"));
+        assert!(server.commands.try_recv().is_err());
+    }
+}
@@ -0,0 +1,413 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Operators' commands over gRPC
+//!
+//! With the `grpc-admin` feature, an `AdminServer` can serve the `PbftAdmin` service from
+//! `pbft_admin.proto` as well as its HTTP endpoints, with grpcio. Calls need the same token as
+//! HTTP requests, as `authorization: Bearer <token>` metadata, and are handed to the engine's event
+//! loop the same way. Their replies are made from the node's state on the engine's thread, as the
+//! service's messages rather than JSON, and sent back on grpcio's own threads, so a client that's
+//! slow to take its reply doesn't hold up the engine either.
+
+use std::net::SocketAddr;
+use std::str;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use futures::channel::oneshot;
+use futures::{Future, FutureExt};
+use grpcio::{
+    self, Environment, Metadata, RpcContext, RpcStatus, RpcStatusCode, ServerBuilder, UnarySink,
+};
+use protobuf::RepeatedField;
+
+use admin::{self, AdminCommand, AdminError, Command, Outcome};
+use protos::pbft_admin::{
+    CatchUpRequest, CatchUpResponse, DumpRequest, DumpResponse, GetPeersRequest, GetPeersResponse,
+    GetStateRequest, NodeState, NodeStatus, PeerInfo, SetMaintenanceRequest,
+    StartViewChangeRequest, Timer, TimerStatus, WorkingBlock, WorkingBlockKind,
+};
+use protos::pbft_admin_grpc::{self, PbftAdmin};
+use state::{PbftMode, PbftState, WorkingBlockOption};
+use status;
+use timing::{millis, Timeout};
+
+/// Serve the admin service on the given address, handing the commands of clients with the given
+/// token to the engine
+pub fn serve(
+    addr: &SocketAddr,
+    token: String,
+    commands: Sender<Command>,
+) -> grpcio::Result<grpcio::Server> {
+    let env = Arc::new(Environment::new(1));
+    let service = pbft_admin_grpc::create_pbft_admin(AdminService { token, commands });
+    let mut server = ServerBuilder::new(env)
+        .register_service(service)
+        .bind(addr.ip().to_string(), addr.port())
+        .build()?;
+    server.start();
+    Ok(server)
+}
+
+#[derive(Clone)]
+struct AdminService {
+    token: String,
+    commands: Sender<Command>,
+}
+
+impl AdminService {
+    // Hand a call's command to the engine, if its client sent the token, and answer the call with
+    // what `reply` makes of the command's outcome and the node's state afterwards
+    fn call<T, F>(&self, ctx: &RpcContext, sink: UnarySink<T>, command: AdminCommand, reply: F)
+    where
+        T: Send + 'static,
+        F: FnOnce(&PbftState, Outcome) -> T + Send + 'static,
+    {
+        if !admin::has_token(&self.token, authorization(ctx.request_headers())) {
+            warn!("Refused admin call {:?}; wrong token", command);
+            ctx.spawn(answer(
+                sink,
+                Err(RpcStatus::with_message(
+                    RpcStatusCode::UNAUTHENTICATED,
+                    String::from("wrong or missing token"),
+                )),
+            ));
+            return;
+        }
+
+        let (sender, response) = oneshot::channel();
+        let command = Command {
+            command,
+            reply: Box::new(move |state: &PbftState, result| {
+                // The client may have given up waiting
+                let _ = sender.send(
+                    result
+                        .map(|outcome| reply(state, outcome))
+                        .map_err(error_status),
+                );
+            }),
+        };
+        if self.commands.send(command).is_err() {
+            ctx.spawn(answer(sink, Err(shutting_down())));
+            return;
+        }
+
+        // The engine drops the reply without sending it if it shuts down first
+        ctx.spawn(response.then(move |response| {
+            let response = response.unwrap_or_else(|_| Err(shutting_down()));
+            answer(sink, response)
+        }));
+    }
+}
+
+impl PbftAdmin for AdminService {
+    fn get_state(&mut self, ctx: RpcContext, _: GetStateRequest, sink: UnarySink<NodeState>) {
+        self.call(&ctx, sink, AdminCommand::GetState, |state, _| {
+            node_state(state)
+        });
+    }
+
+    fn get_peers(
+        &mut self,
+        ctx: RpcContext,
+        _: GetPeersRequest,
+        sink: UnarySink<GetPeersResponse>,
+    ) {
+        self.call(&ctx, sink, AdminCommand::GetPeers, |state, _| peers(state));
+    }
+
+    fn dump(&mut self, ctx: RpcContext, _: DumpRequest, sink: UnarySink<DumpResponse>) {
+        self.call(&ctx, sink, AdminCommand::Dump, |_, outcome| {
+            let mut response = DumpResponse::new();
+            if let Outcome::Dumped(path) = outcome {
+                response.set_path(path.to_string_lossy().into_owned());
+            }
+            response
+        });
+    }
+
+    fn start_view_change(
+        &mut self,
+        ctx: RpcContext,
+        _: StartViewChangeRequest,
+        sink: UnarySink<NodeStatus>,
+    ) {
+        self.call(&ctx, sink, AdminCommand::StartViewChange, |state, _| {
+            node_status(&status::NodeStatus::new(state))
+        });
+    }
+
+    fn set_maintenance(
+        &mut self,
+        ctx: RpcContext,
+        request: SetMaintenanceRequest,
+        sink: UnarySink<NodeStatus>,
+    ) {
+        let command = AdminCommand::SetMaintenance(request.get_maintenance());
+        self.call(&ctx, sink, command, |state, _| {
+            node_status(&status::NodeStatus::new(state))
+        });
+    }
+
+    fn catch_up(&mut self, ctx: RpcContext, _: CatchUpRequest, sink: UnarySink<CatchUpResponse>) {
+        self.call(&ctx, sink, AdminCommand::CatchUp, |_, outcome| {
+            let mut response = CatchUpResponse::new();
+            if let Outcome::CatchingUp(Some(target)) = outcome {
+                response.set_catching_up(true);
+                response.set_target_seq_num(target);
+            }
+            response
+        });
+    }
+}
+
+// The value of a call's `authorization` metadata, if it has any
+fn authorization(headers: &Metadata) -> Option<&str> {
+    headers
+        .iter()
+        .find(|&(key, _)| key.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| str::from_utf8(value).ok())
+}
+
+// Send a call its reply, or tell it why there isn't one
+fn answer<T>(sink: UnarySink<T>, response: Result<T, RpcStatus>) -> impl Future<Output = ()> {
+    match response {
+        Ok(reply) => sink.success(reply),
+        Err(status) => sink.fail(status),
+    }
+    .map(|result| {
+        if let Err(err) = result {
+            debug!("Couldn't answer admin call: {}", err);
+        }
+    })
+}
+
+fn error_status(err: AdminError) -> RpcStatus {
+    match err {
+        AdminError::NoDumpDirectory => RpcStatus::with_message(
+            RpcStatusCode::FAILED_PRECONDITION,
+            String::from("no crash dump directory"),
+        ),
+        AdminError::Failed(err) => RpcStatus::with_message(RpcStatusCode::INTERNAL, err),
+    }
+}
+
+fn shutting_down() -> RpcStatus {
+    RpcStatus::with_message(
+        RpcStatusCode::UNAVAILABLE,
+        String::from("node is shutting down"),
+    )
+}
+
+/// Describe the node's live state as a `NodeState`, with the same fields as `GET /state`
+pub fn node_state(state: &PbftState) -> NodeState {
+    let mut node_state = NodeState::new();
+    node_state.set_id(state.id);
+    node_state.set_peer_id(Vec::<u8>::from(state.get_own_peer_id()));
+    node_state.set_primary(Vec::<u8>::from(state.get_primary_peer_id()));
+    node_state.set_is_primary(state.is_primary());
+    node_state.set_view(state.view);
+    node_state.set_seq_num(state.seq_num);
+    node_state.set_phase(format!("{:?}", state.phase));
+    node_state.set_mode(format!("{:?}", state.mode));
+    node_state.set_maintenance(state.maintenance);
+    if state.mode == PbftMode::Recovering {
+        node_state.set_recovery_target(state.recovery_target);
+    }
+    node_state.set_members(state.peers().len() as u64);
+    node_state.set_f(state.f);
+    node_state.set_chain_head(Vec::<u8>::from(state.chain_head.clone()));
+    node_state.set_chain_head_num(state.chain_head_num);
+    node_state.set_working_block(working_block(&state.working_block));
+    node_state.set_commit_timeout(timer(&state.timeout));
+    node_state.set_view_change_timeout(timer(&state.view_change_timeout));
+    if let Some(ref idle_timeout) = state.idle_timeout {
+        node_state.set_idle_timeout(timer(idle_timeout));
+    }
+    node_state
+}
+
+fn working_block(working_block: &WorkingBlockOption) -> WorkingBlock {
+    let mut message = WorkingBlock::new();
+    match working_block {
+        WorkingBlockOption::NoWorkingBlock => message.set_kind(WorkingBlockKind::NONE),
+        WorkingBlockOption::TentativeWorkingBlock(block_id) => {
+            message.set_kind(WorkingBlockKind::TENTATIVE);
+            message.set_block_id(Vec::<u8>::from(block_id.clone()));
+        }
+        WorkingBlockOption::WorkingBlock(block) => {
+            message.set_kind(WorkingBlockKind::WORKING);
+            message.set_block_id(block.get_block_id().to_vec());
+            message.set_block_num(block.get_block_num());
+            message.set_signer_id(block.get_signer_id().to_vec());
+        }
+    }
+    message
+}
+
+fn timer(timeout: &Timeout) -> Timer {
+    let mut timer = Timer::new();
+    match timeout.remaining() {
+        None => timer.set_status(TimerStatus::STOPPED),
+        Some(remaining) => {
+            let expired = remaining.as_secs() == 0 && remaining.subsec_nanos() == 0;
+            timer.set_status(if expired {
+                TimerStatus::EXPIRED
+            } else {
+                TimerStatus::RUNNING
+            });
+            timer.set_remaining_ms(millis(remaining));
+        }
+    }
+    timer.set_duration_ms(millis(timeout.duration()));
+    timer
+}
+
+/// Describe the node's health as a `NodeStatus`, with the same fields as `/status`
+pub fn node_status(status: &status::NodeStatus) -> NodeStatus {
+    let mut message = NodeStatus::new();
+    message.set_health(status.health.to_string());
+    message.set_problems(RepeatedField::from_vec(status.problems.clone()));
+    message.set_is_primary(status.is_primary);
+    message.set_mode(format!("{:?}", status.mode));
+    message.set_phase(format!("{:?}", status.phase));
+    message.set_view(status.view);
+    message.set_seq_num(status.seq_num);
+    message.set_chain_head_num(status.chain_head_num);
+    message.set_lag(status.lag);
+    if let Some(since) = status.since_last_commit {
+        message.set_has_committed(true);
+        message.set_seconds_since_last_commit(since.as_secs());
+    }
+    message.set_peers_heard_from(status.peers_heard_from as u64);
+    message.set_peers(status.peers as u64);
+    message.set_peers_connected(status.peers_connected as u64);
+    message.set_quarantined(RepeatedField::from_vec(
+        status
+            .quarantined
+            .iter()
+            .map(|peer_id| Vec::<u8>::from(peer_id.clone()))
+            .collect(),
+    ));
+    message
+}
+
+/// Describe what this node knows about each other member of the network, as `GET /peers` does
+pub fn peers(state: &PbftState) -> GetPeersResponse {
+    let own_peer_id = state.get_own_peer_id();
+    let quarantined = state.quarantine.quarantined();
+    let mut peers = Vec::new();
+    for (id, peer_id) in state.peers().iter().enumerate() {
+        if *peer_id == own_peer_id {
+            continue;
+        }
+
+        let mut peer = PeerInfo::new();
+        peer.set_peer_id(Vec::<u8>::from(peer_id.clone()));
+        peer.set_id(id as u64);
+        peer.set_connected(!state.outbox.is_disconnected(peer_id));
+        peer.set_quarantined(quarantined.contains(&peer_id));
+        if let Some(stats) = state.peer_stats.get(peer_id) {
+            peer.set_sent(stats.sent.values().sum());
+            peer.set_received(stats.received.values().sum());
+            peer.set_rejected(stats.rejected.values().sum());
+            peer.set_highest_seq_num(stats.highest_seq_num);
+            if let Some(since) = stats.since_last_seen() {
+                peer.set_heard_from(true);
+                peer.set_seconds_since_last_seen(since.as_secs());
+            }
+            if let Some(skew) = stats.clock_skew {
+                peer.set_has_clock_offset(true);
+                peer.set_clock_offset_ms(skew.offset_ms);
+            }
+        }
+        peers.push(peer);
+    }
+
+    let mut response = GetPeersResponse::new();
+    response.set_peers(RepeatedField::from_vec(peers));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use grpcio::MetadataBuilder;
+
+    /// Make sure that a call's token is read from its `authorization` metadata, whatever case the
+    /// key is in
+    #[test]
+    fn grpc_authorization() {
+        let metadata = |key: &str, value: &str| {
+            let mut builder = MetadataBuilder::new();
+            builder.add_str(key, value).unwrap();
+            builder.build()
+        };
+        let authorized = |metadata: &Metadata| admin::has_token("s3cret", authorization(metadata));
+
+        assert!(authorized(&metadata("authorization", "Bearer s3cret")));
+        assert!(authorized(&metadata("Authorization", "Bearer s3cret")));
+        assert!(!authorized(&metadata("authorization", "Bearer s3crex")));
+        assert!(!authorized(&metadata("authorization", "s3cret")));
+        assert!(!authorized(&metadata("x-token", "s3cret")));
+        assert!(!authorized(&MetadataBuilder::new().build()));
+    }
+
+    /// Make sure that the node's state and peers are described with the same values as over HTTP
+    #[test]
+    fn grpc_messages() {
+        let mut state = PbftState::new(1, &mock_config(4));
+        state.view = 2;
+        state.maintenance = true;
+
+        let node_state = node_state(&state);
+        assert_eq!(node_state.get_id(), 1);
+        assert_eq!(node_state.get_view(), 2);
+        assert!(node_state.get_maintenance());
+        assert_eq!(node_state.get_members(), 4);
+        assert_eq!(node_state.get_f(), 1);
+        assert_eq!(
+            node_state.get_primary(),
+            Vec::<u8>::from(state.get_primary_peer_id()).as_slice()
+        );
+        assert_eq!(
+            node_state.get_working_block().get_kind(),
+            WorkingBlockKind::NONE
+        );
+        assert_eq!(
+            node_state.get_commit_timeout().get_duration_ms(),
+            millis(state.timeout.duration())
+        );
+        assert_eq!(node_state.has_idle_timeout(), state.idle_timeout.is_some());
+
+        let peers = peers(&state).take_peers().into_vec();
+        assert_eq!(
+            peers.iter().map(|peer| peer.get_id()).collect::<Vec<_>>(),
+            vec![0, 2, 3]
+        );
+        assert!(peers
+            .iter()
+            .all(|peer| peer.get_connected() && !peer.get_heard_from()));
+
+        let status = node_status(&status::NodeStatus::new(&state));
+        assert_eq!(status.get_view(), 2);
+        assert_eq!(status.get_peers(), 3);
+        assert!(!status.get_has_committed());
+    }
+}
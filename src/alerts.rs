@@ -0,0 +1,419 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Alerts on consensus anomalies, without an external metrics pipeline
+//!
+//! A node started with an alerts file watches for the conditions it describes, and when one
+//! occurs, POSTs the alert as JSON to a webhook, runs a command, or both:
+//!
+//! ```json
+//! {
+//!     "webhook": "http://alerts.example.com:9000/pbft",
+//!     "command": "/usr/local/bin/page-operator",
+//!     "view_changes": { "max": 3, "minutes": 10 },
+//!     "equivocation": true,
+//!     "max_lag": 5,
+//!     "incidents": true
+//! }
+//! ```
+//!
+//! + `view_changes`: alert when the node goes through more than `max` view changes within
+//!   `minutes` minutes
+//! + `equivocation`: alert when another node is caught sending conflicting messages
+//! + `max_lag`: alert when the node falls more than this many blocks behind the network; it
+//!   doesn't alert again until it has caught up
+//! + `incidents`: alert when a problem that needs an operator starts, such as on-chain settings
+//!   that can't be used (see `incidents`)
+//!
+//! The command is given the kind of alert and its message as arguments. Alerts are sent from
+//! separate threads, so a slow webhook or command doesn't hold up the node.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hex;
+use serde_json::{self, Value};
+
+use error::PbftError;
+use events::ConsensusEvent;
+use incidents::Incident;
+use message_log::PbftLog;
+use metrics;
+use state::PbftState;
+use status;
+use timing;
+
+/// Where to send alerts, and which conditions to alert on
+#[derive(Debug, Default)]
+pub struct AlertConfig {
+    /// The `host:port` and path of the webhook to POST alerts to
+    pub webhook: Option<(String, String)>,
+
+    /// A program to run for each alert
+    pub command: Option<String>,
+
+    /// Alert on more than this many view changes within this long
+    pub view_changes: Option<(usize, Duration)>,
+
+    pub equivocation: bool,
+
+    /// Alert when the node is more than this many blocks behind
+    pub max_lag: Option<u64>,
+
+    pub incidents: bool,
+}
+
+impl AlertConfig {
+    /// Read the alert configuration from a JSON file
+    pub fn load(path: &Path) -> Result<Self, PbftError> {
+        let config = fs::read_to_string(path).map_err(|err| {
+            PbftError::InvalidConfig(format!("Couldn't read alerts file {:?}: {}", path, err))
+        })?;
+        AlertConfig::from_json(&config)
+    }
+
+    /// Parse the alert configuration from JSON; conditions that are left out aren't alerted on
+    pub fn from_json(config: &str) -> Result<Self, PbftError> {
+        let value: Value = serde_json::from_str(config)
+            .map_err(|err| PbftError::InvalidConfig(format!("Invalid alerts file: {}", err)))?;
+
+        let webhook = match value.get("webhook").and_then(Value::as_str) {
+            Some(url) => Some(parse_webhook(url)?),
+            None => None,
+        };
+
+        let view_changes = match value.get("view_changes") {
+            Some(window) => {
+                let max = window.get("max").and_then(Value::as_u64);
+                let minutes = window.get("minutes").and_then(Value::as_u64);
+                match (max, minutes) {
+                    (Some(max), Some(minutes)) if minutes > 0 => {
+                        Some((max as usize, Duration::from_secs(minutes * 60)))
+                    }
+                    _ => {
+                        return Err(PbftError::InvalidConfig(String::from(
+                            "Invalid alerts file: view_changes needs a max and minutes",
+                        )))
+                    }
+                }
+            }
+            None => None,
+        };
+
+        Ok(AlertConfig {
+            webhook,
+            command: value
+                .get("command")
+                .and_then(Value::as_str)
+                .map(String::from),
+            view_changes,
+            equivocation: value
+                .get("equivocation")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            max_lag: value.get("max_lag").and_then(Value::as_u64),
+            incidents: value
+                .get("incidents")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+}
+
+// Split an `http://host:port/path` URL into its address and path
+fn parse_webhook(url: &str) -> Result<(String, String), PbftError> {
+    let rest = if url.starts_with("http://") {
+        &url["http://".len()..]
+    } else {
+        return Err(PbftError::InvalidConfig(format!(
+            "Invalid webhook {}: only http:// URLs are supported",
+            url
+        )));
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        String::from(host)
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((address, String::from(path)))
+}
+
+/// Something unusual that an operator should hear about
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    /// What kind of condition occurred: `view_changes`, `equivocation`, `lag`, or `incident`
+    pub kind: &'static str,
+    pub message: String,
+}
+
+/// Watches a node for the conditions in an `AlertConfig`, and sends alerts when they occur
+pub struct AlertMonitor {
+    config: AlertConfig,
+
+    /// When each of the view changes within the window happened
+    view_changes: VecDeque<Instant>,
+
+    /// How many equivocations had been detected when the log was last checked
+    equivocations: u64,
+
+    /// Whether the node has been alerted about as being behind, and hasn't caught up since
+    lagging: bool,
+}
+
+impl AlertMonitor {
+    pub fn new(config: AlertConfig) -> Self {
+        info!("Alerting on {:?}", config);
+        AlertMonitor {
+            config,
+            view_changes: VecDeque::new(),
+            equivocations: 0,
+            lagging: false,
+        }
+    }
+
+    /// Check the node for the configured conditions, using the events since the last check, and
+    /// send an alert for each one that occurred. Returns the alerts that were sent.
+    pub fn check(&mut self, state: &PbftState, msg_log: &PbftLog) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+
+        if let Some((max, window)) = self.config.view_changes {
+            let now = timing::now();
+            for event in &state.events {
+                if let ConsensusEvent::ViewChanged { .. } = event {
+                    self.view_changes.push_back(now);
+                }
+            }
+            while self
+                .view_changes
+                .front()
+                .map_or(false, |&time| now - time > window)
+            {
+                self.view_changes.pop_front();
+            }
+            if self.view_changes.len() > max {
+                alerts.push(Alert {
+                    kind: "view_changes",
+                    message: format!(
+                        "{} view changes in the last {} minutes, now in view {}",
+                        self.view_changes.len(),
+                        window.as_secs() / 60,
+                        state.view
+                    ),
+                });
+                // Only view changes after this alert count toward the next one
+                self.view_changes.clear();
+            }
+        }
+
+        if self.config.equivocation {
+            for evidence in msg_log.equivocations_since(self.equivocations) {
+                let info = evidence.conflicting.get_info();
+                alerts.push(Alert {
+                    kind: "equivocation",
+                    message: format!(
+                        "Node {} sent conflicting {} messages for view {}, sequence number {}",
+                        hex::encode(info.get_signer_id()),
+                        info.get_msg_type(),
+                        info.get_view(),
+                        info.get_seq_num()
+                    ),
+                });
+            }
+            self.equivocations = msg_log.equivocation_count();
+        }
+
+        if let Some(max_lag) = self.config.max_lag {
+            let lag = status::lag(state);
+            if lag > max_lag && !self.lagging {
+                alerts.push(Alert {
+                    kind: "lag",
+                    message: format!(
+                        "{} blocks behind the network, at sequence number {}",
+                        lag, state.seq_num
+                    ),
+                });
+            }
+            self.lagging = lag > max_lag;
+        }
+
+        if self.config.incidents {
+            for event in &state.events {
+                if let ConsensusEvent::IncidentStarted { incident, detail } = event {
+                    alerts.push(Alert {
+                        kind: "incident",
+                        message: incident_message(*incident, detail),
+                    });
+                }
+            }
+        }
+
+        for alert in &alerts {
+            self.send(state, alert);
+        }
+        alerts
+    }
+
+    fn send(&self, state: &PbftState, alert: &Alert) {
+        warn!("{}: Alert ({}): {}", state, alert.kind, alert.message);
+
+        if let Some((ref address, ref path)) = self.config.webhook {
+            let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+            fields.insert(String::from("alert"), Value::from(alert.kind));
+            fields.insert(String::from("message"), Value::from(alert.message.clone()));
+            fields.insert(
+                String::from("node"),
+                Value::from(hex::encode(Vec::<u8>::from(state.get_own_peer_id()))),
+            );
+            fields.insert(String::from("view"), Value::from(state.view));
+            fields.insert(String::from("seq_num"), Value::from(state.seq_num));
+            let body = serde_json::to_string(&fields).expect("Couldn't write alert as JSON");
+
+            let address = address.clone();
+            let path = path.clone();
+            thread::spawn(move || {
+                if let Err(err) =
+                    metrics::http_post(&address, &path, "application/json", body.as_bytes())
+                {
+                    warn!("Couldn't send alert to {}{}: {}", address, path, err);
+                }
+            });
+        }
+
+        if let Some(ref command) = self.config.command {
+            let command = command.clone();
+            let kind = alert.kind;
+            let message = alert.message.clone();
+            thread::spawn(
+                move || match Command::new(&command).arg(kind).arg(&message).status() {
+                    Ok(status) if status.success() => (),
+                    Ok(status) => warn!("Alert command {} failed: {}", command, status),
+                    Err(err) => warn!("Couldn't run alert command {}: {}", command, err),
+                },
+            );
+        }
+    }
+}
+
+// Describe an incident for an operator, naming it the way the events and audit trail do
+fn incident_message(incident: Incident, detail: &str) -> String {
+    format!(
+        "Needs an operator ({}): {}; {}",
+        incident.name(),
+        incident,
+        detail
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use peer_stats::PeerStats;
+
+    /// Make sure that alerts are raised for too many view changes and for falling behind, once
+    /// per occurrence, and that invalid configurations are rejected
+    #[test]
+    fn alerts() {
+        assert!(AlertConfig::from_json(r#"{"webhook": "https://example.com"}"#).is_err());
+        assert!(AlertConfig::from_json(r#"{"view_changes": {"max": 2}}"#).is_err());
+
+        let config = AlertConfig::from_json(
+            r#"{
+                "webhook": "http://alerts.local/pbft",
+                "view_changes": {"max": 2, "minutes": 10},
+                "max_lag": 5,
+                "incidents": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.webhook,
+            Some((String::from("alerts.local:80"), String::from("/pbft")))
+        );
+        assert!(!config.equivocation);
+
+        timing::start_virtual_time(1);
+        let mut monitor = AlertMonitor::new(AlertConfig {
+            webhook: None,
+            ..config
+        });
+        let mut state = PbftState::new(0, &mock_config(4));
+        let msg_log = PbftLog::new(&mock_config(4));
+        let view_changed = ConsensusEvent::ViewChanged {
+            from_view: 0,
+            view: 1,
+            reason: None,
+        };
+
+        // Two view changes are allowed, but a third within ten minutes isn't; one long ago doesn't
+        // count
+        state.events.push(view_changed.clone());
+        assert!(monitor.check(&state, &msg_log).is_empty());
+        timing::set_virtual_time(timing::now() + Duration::from_secs(11 * 60));
+        state.events.push(view_changed.clone());
+        assert!(monitor.check(&state, &msg_log).is_empty());
+        state.events = vec![view_changed.clone(), view_changed.clone()];
+        let alerts = monitor.check(&state, &msg_log);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "view_changes");
+        state.events.clear();
+        assert!(monitor.check(&state, &msg_log).is_empty());
+
+        // Falling behind alerts once, until the node catches up
+        for peer_id in state.peers()[1..].to_vec() {
+            let mut stats = PeerStats::default();
+            stats.record_seq_num(10);
+            state.peer_stats.insert(peer_id, stats);
+        }
+        let alerts = monitor.check(&state, &msg_log);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "lag");
+        assert!(monitor.check(&state, &msg_log).is_empty());
+        state.seq_num = 10;
+        assert!(monitor.check(&state, &msg_log).is_empty());
+        state.seq_num = 0;
+        assert_eq!(monitor.check(&state, &msg_log).len(), 1);
+
+        // Incidents alert when they start, not when they end
+        state.events = vec![
+            ConsensusEvent::IncidentStarted {
+                incident: Incident::Stuck,
+                detail: String::from("no block committed for 301s"),
+            },
+            ConsensusEvent::IncidentEnded {
+                incident: Incident::Stuck,
+            },
+        ];
+        let alerts = monitor.check(&state, &msg_log);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "incident");
+        assert_eq!(
+            alerts[0].message,
+            "Needs an operator (stuck): not making progress; no block committed for 301s"
+        );
+
+        timing::stop_virtual_time();
+    }
+}
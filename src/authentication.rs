@@ -0,0 +1,320 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Signing the messages a node sends, and checking the signatures on the messages it receives
+//!
+//! The validator tells the engine which peer each message came from, but the signer ID inside a
+//! message is only as trustworthy as the node that sent it. When message authentication is
+//! enabled, every message is wrapped in a `PbftSignedMessage` and signed with the sending node's
+//! private key. Since peer IDs are the validators' public keys, a receiver can check the signature
+//! against the signer ID in the message itself, no matter how the message reached it.
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use protobuf::{self, Message};
+
+use sawtooth_sdk::consensus::engine::{PeerId, PeerMessage};
+use sawtooth_sdk::signing::secp256k1::Secp256k1PrivateKey;
+use sawtooth_sdk::signing::{self, create_context, Context, PrivateKey};
+
+use protos::pbft_message::PbftSignedMessage;
+
+use error::PbftError;
+use message_extensions::{check_canonical, parse_msg_info};
+use message_type::PbftMessageType;
+use signature;
+
+/// Signs the messages that this node sends with its private key
+pub struct MessageSigner {
+    context: Box<Context>,
+    private_key: Box<PrivateKey>,
+    signer_id: Vec<u8>,
+}
+
+impl MessageSigner {
+    /// Create a signer from a hex-encoded secp256k1 private key
+    pub fn from_hex(private_key: &str) -> Result<Self, PbftError> {
+        let context = create_context("secp256k1").map_err(signing_error)?;
+        let private_key =
+            Secp256k1PrivateKey::from_hex(private_key.trim()).map_err(signing_error)?;
+        let signer_id = context
+            .get_public_key(&private_key)
+            .map_err(signing_error)?
+            .as_slice()
+            .to_vec();
+
+        Ok(MessageSigner {
+            context,
+            private_key: Box::new(private_key),
+            signer_id,
+        })
+    }
+
+    /// Create a signer from a file containing a hex-encoded secp256k1 private key, such as the
+    /// validator's key file
+    pub fn load(path: &Path) -> Result<Self, PbftError> {
+        let private_key = fs::read_to_string(path).map_err(|err| {
+            PbftError::InvalidConfig(format!("Couldn't read key file {:?}: {}", path, err))
+        })?;
+        MessageSigner::from_hex(&private_key)
+    }
+
+    /// Get the peer ID (public key) that this signer's signatures belong to
+    pub fn get_signer_id(&self) -> PeerId {
+        PeerId::from(self.signer_id.clone())
+    }
+
+    /// Wrap a serialized message in a signed `PbftSignedMessage`
+    pub fn sign(&self, msg_bytes: Vec<u8>) -> Result<Vec<u8>, PbftError> {
+        let signature = self
+            .context
+            .sign(&msg_bytes, &*self.private_key)
+            .map_err(signing_error)?;
+
+        let mut signed = PbftSignedMessage::new();
+        signed.set_message(msg_bytes);
+        signed.set_signer_id(self.signer_id.clone());
+        signed.set_signature(signature);
+        signed
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)
+    }
+}
+
+/// Unwrap a peer message from its `PbftSignedMessage`, making sure that the signature is valid and
+/// that the message was signed by the node it says it's from
+pub fn verify(msg: &PeerMessage) -> Result<PeerMessage, PbftError> {
+    let signed = protobuf::parse_from_bytes::<PbftSignedMessage>(&msg.content)
+        .map_err(PbftError::SerializationError)?;
+    check_signature(&signed)?;
+
+    // Only the signed bytes themselves are checked, but a node could sign the same message
+    // encoded in more than one way; requiring the canonical encoding rules that out
+    let msg_type = PbftMessageType::from(msg.message_type.as_str());
+    check_canonical(&msg_type, signed.get_message())?;
+
+    let info = parse_msg_info(&msg_type, signed.get_message())?;
+    if info.get_signer_id() != signed.get_signer_id()
+        || info.get_msg_type() != msg.message_type.as_str()
+    {
+        return Err(PbftError::InvalidSignature);
+    }
+
+    Ok(PeerMessage {
+        message_type: msg.message_type.clone(),
+        content: signed.get_message().to_vec(),
+    })
+}
+
+/// Check a signed copy of a message that was passed on by a node other than the one that signed
+/// it, such as a `ViewChange` in a `NewView`: it must be the given message, in its canonical
+/// encoding, and be signed by the node that the message says it's from
+pub fn verify_vote(
+    signed: &PbftSignedMessage,
+    msg_bytes: &[u8],
+    signer_id: &[u8],
+) -> Result<(), PbftError> {
+    if signed.get_message() != msg_bytes || signed.get_signer_id() != signer_id {
+        return Err(PbftError::InvalidSignature);
+    }
+    check_signature(signed)
+}
+
+/// Check signed votes that were passed on together, such as the `Commit`s in a seal, as
+/// `verify_vote` does; each is given with the message it must be and the node it must be from.
+/// Each signature can be checked on its own, so they're split between up to `threads` threads.
+pub fn verify_votes(
+    votes: &[(&PbftSignedMessage, Vec<u8>, &[u8])],
+    threads: usize,
+) -> Result<(), PbftError> {
+    let verify_all = |votes: &[(&PbftSignedMessage, Vec<u8>, &[u8])]| {
+        votes
+            .iter()
+            .map(|&(signed, ref msg_bytes, signer_id)| verify_vote(signed, msg_bytes, signer_id))
+            .collect::<Result<(), PbftError>>()
+    };
+
+    let threads = threads.min(votes.len());
+    if threads <= 1 {
+        return verify_all(votes);
+    }
+
+    let chunk_size = (votes.len() + threads - 1) / threads;
+    thread::scope(|scope| {
+        let workers: Vec<_> = votes
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || verify_all(chunk)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker.join().unwrap_or_else(|_| {
+                    Err(PbftError::InternalError(String::from(
+                        "Verifying signatures panicked",
+                    )))
+                })
+            })
+            .collect()
+    })
+}
+
+// Make sure that a message was signed with the private key for the public key it names
+fn check_signature(signed: &PbftSignedMessage) -> Result<(), PbftError> {
+    if !signature::is_valid(signed) {
+        return Err(PbftError::InvalidSignature);
+    }
+    Ok(())
+}
+
+fn signing_error(err: signing::Error) -> PbftError {
+    PbftError::InternalError(format!("Signing error: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::make_msg_info;
+    use protos::pbft_message::PbftMessage;
+
+    const PRIVATE_KEY: &str = "2f1e7b7a130d7ba9da0068b3bb0ba1d79e7e77110302c9f746c3c2a63fe40088";
+
+    fn make_msg_bytes(signer_id: PeerId) -> Vec<u8> {
+        let mut msg = PbftMessage::new();
+        msg.set_info(make_msg_info(&PbftMessageType::Commit, 0, 1, signer_id));
+        msg.write_to_bytes().unwrap()
+    }
+
+    fn peer_message(content: Vec<u8>) -> PeerMessage {
+        PeerMessage {
+            message_type: String::from(&PbftMessageType::Commit),
+            content,
+        }
+    }
+
+    /// Make sure that a signed message is unwrapped intact, and that messages that were tampered
+    /// with, signed by a different node than the one they claim to be from, or not in their
+    /// canonical encoding are rejected
+    #[test]
+    fn sign_and_verify() {
+        let signer = MessageSigner::from_hex(PRIVATE_KEY).unwrap();
+        let msg_bytes = make_msg_bytes(signer.get_signer_id());
+
+        let signed_bytes = signer.sign(msg_bytes.clone()).unwrap();
+        let verified = verify(&peer_message(signed_bytes.clone())).unwrap();
+        assert_eq!(verified.content, msg_bytes);
+
+        // The message was changed after it was signed
+        let mut signed = protobuf::parse_from_bytes::<PbftSignedMessage>(&signed_bytes).unwrap();
+        signed.set_message(make_msg_bytes(PeerId::from(vec![1, 2, 3])));
+        assert!(verify(&peer_message(signed.write_to_bytes().unwrap())).is_err());
+
+        // The message claims to be from a different node than the one that signed it
+        let forged_bytes = signer
+            .sign(make_msg_bytes(PeerId::from(vec![1, 2, 3])))
+            .unwrap();
+        assert!(verify(&peer_message(forged_bytes)).is_err());
+
+        // The message isn't signed at all
+        assert!(verify(&peer_message(msg_bytes.clone())).is_err());
+
+        // The message has a field that it's not supposed to have, or has a field more than once
+        let mut unknown_field = msg_bytes.clone();
+        unknown_field.extend_from_slice(&[0xf8, 0x01, 0x01]);
+        assert!(verify(&peer_message(signer.sign(unknown_field).unwrap())).is_err());
+        let mut repeated_field = msg_bytes.clone();
+        repeated_field.extend_from_slice(&msg_bytes);
+        assert!(verify(&peer_message(signer.sign(repeated_field).unwrap())).is_err());
+    }
+
+    /// Make sure that a vote passed on by another node is only accepted if it's the message it
+    /// stands for, signed by the node that the message is from
+    #[test]
+    fn vote() {
+        let signer = MessageSigner::from_hex(PRIVATE_KEY).unwrap();
+        let signer_id = Vec::<u8>::from(signer.get_signer_id());
+        let msg_bytes = make_msg_bytes(signer.get_signer_id());
+        let signed = protobuf::parse_from_bytes::<PbftSignedMessage>(
+            &signer.sign(msg_bytes.clone()).unwrap(),
+        )
+        .unwrap();
+
+        assert!(verify_vote(&signed, &msg_bytes, &signer_id).is_ok());
+
+        // The vote is for a different message, or names a different signer
+        let other_bytes = make_msg_bytes(PeerId::from(vec![1, 2, 3]));
+        assert!(verify_vote(&signed, &other_bytes, &signer_id).is_err());
+        assert!(verify_vote(&signed, &msg_bytes, &[1, 2, 3]).is_err());
+
+        // The signature is someone else's
+        let mut forged = signed.clone();
+        forged.set_signature(String::from("00"));
+        assert!(verify_vote(&forged, &msg_bytes, &signer_id).is_err());
+    }
+
+    /// Make sure that votes checked together on several threads are accepted or rejected the same
+    /// way as when they're checked one at a time
+    #[test]
+    fn votes() {
+        let signers: Vec<MessageSigner> = (1..8)
+            .map(|i| MessageSigner::from_hex(&format!("{:064x}", i)).unwrap())
+            .collect();
+        let signer_ids: Vec<Vec<u8>> = signers
+            .iter()
+            .map(|signer| Vec::<u8>::from(signer.get_signer_id()))
+            .collect();
+        let signed: Vec<PbftSignedMessage> = signers
+            .iter()
+            .map(|signer| {
+                protobuf::parse_from_bytes(
+                    &signer.sign(make_msg_bytes(signer.get_signer_id())).unwrap(),
+                )
+                .unwrap()
+            })
+            .collect();
+        fn votes<'a>(
+            signed: &'a [PbftSignedMessage],
+            signer_ids: &'a [Vec<u8>],
+        ) -> Vec<(&'a PbftSignedMessage, Vec<u8>, &'a [u8])> {
+            signed
+                .iter()
+                .zip(signer_ids)
+                .map(|(signed, signer_id)| {
+                    (
+                        signed,
+                        make_msg_bytes(PeerId::from(signer_id.clone())),
+                        signer_id.as_slice(),
+                    )
+                })
+                .collect()
+        }
+
+        for threads in &[0, 1, 3, 20] {
+            assert!(verify_votes(&votes(&signed, &signer_ids), *threads).is_ok());
+        }
+
+        // One vote that doesn't check out, wherever it is, fails them all
+        for i in &[0, 3, 6] {
+            let mut forged = signed.clone();
+            forged[*i].set_signature(String::from("00"));
+            for threads in &[1, 3] {
+                assert!(verify_votes(&votes(&forged, &signer_ids), *threads).is_err());
+            }
+        }
+    }
+}
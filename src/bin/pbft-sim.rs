@@ -0,0 +1,404 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Runs a network of PBFT nodes in one process, on a virtual clock, and reports how many blocks
+//! it commits and how long it goes without committing any. Each node has a simulated validator
+//! that publishes empty blocks; see `pbft::simulation`.
+
+#[macro_use]
+extern crate clap;
+extern crate log;
+extern crate pbft;
+
+use std::env;
+use std::ffi::OsString;
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clap::{App, ArgMatches, Error, ErrorKind};
+
+use pbft::config::PbftConfig;
+use pbft::recent_log::RecentLog;
+use pbft::simulation::{self, Simulation};
+use pbft::timing::millis;
+
+/// How much virtual time passes between progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How the network is set up and run
+#[derive(Debug, PartialEq)]
+struct Options {
+    nodes: usize,
+    duration: Duration,
+    seed: u64,
+    min_latency: Duration,
+    max_latency: Duration,
+    loss: f64,
+    /// How long the primary waits before finalizing each block (the config's default if `None`)
+    block_duration: Option<Duration>,
+    crash: Vec<usize>,
+    crash_after: Duration,
+    verbosity: u64,
+}
+
+fn main() {
+    let Options {
+        nodes,
+        duration,
+        seed,
+        min_latency,
+        max_latency,
+        loss,
+        block_duration,
+        crash,
+        crash_after,
+        verbosity,
+    } = match parse_options(env::args_os()) {
+        Ok(options) => options,
+        // Asking for --help or --version isn't an error
+        Err(ref err) if !err.use_stderr() => err.exit(),
+        Err(err) => fail(&err.message),
+    };
+
+    let log_level = match verbosity {
+        0 => log::Level::Warn,
+        1 => log::Level::Info,
+        2 => log::Level::Debug,
+        3 | _ => log::Level::Trace,
+    };
+    RecentLog::init(log_level).expect("Unable to initialize logger");
+
+    let mut config = PbftConfig::default();
+    config.peers = simulation::peer_ids(nodes);
+    if let Some(block_duration) = block_duration {
+        config.block_duration = block_duration;
+    }
+
+    println!(
+        "Simulating {} nodes for {}s of virtual time (seed {}, latency {}-{}ms, loss {})",
+        nodes,
+        duration.as_secs(),
+        seed,
+        millis(min_latency),
+        millis(max_latency),
+        loss
+    );
+
+    let started = Instant::now();
+    let mut sim = Simulation::new(&config, seed);
+    sim.set_latency(min_latency, max_latency);
+    sim.set_loss(loss);
+
+    let mut crashed = crash.is_empty();
+    while sim.elapsed() < duration {
+        if !crashed && sim.elapsed() >= crash_after {
+            for node in &crash {
+                println!("{:>6}s: crashing node {}", sim.elapsed().as_secs(), node);
+                sim.crash(*node);
+            }
+            crashed = true;
+        }
+
+        // Stop at the next progress report, the crash, or the end of the run, whichever is first
+        let interval = PROGRESS_INTERVAL.as_secs();
+        let next_report = Duration::from_secs((sim.elapsed().as_secs() / interval + 1) * interval);
+        let mut until = next_report.min(duration);
+        if !crashed {
+            until = until.min(crash_after);
+        }
+        let report = until == next_report || until == duration;
+        sim.run_until(until - sim.elapsed(), |_| false);
+        if report {
+            println!(
+                "{:>6}s: {} blocks committed",
+                sim.elapsed().as_secs(),
+                sim.min_height()
+            );
+        }
+    }
+    let wall_time = started.elapsed();
+
+    sim.check_safety();
+
+    let commit_times = sim.commit_times();
+    let committed = sim.min_height();
+    let elapsed = sim.elapsed();
+    let longest_gap = commit_times
+        .iter()
+        .chain(Some(&elapsed))
+        .scan(Duration::from_secs(0), |last, time| {
+            let gap = *time - *last;
+            *last = *time;
+            Some(gap)
+        })
+        .max()
+        .unwrap_or_default();
+    let view = (0..nodes).map(|node| sim.node(node).state.view).max();
+    let (sent, lost) = sim.message_counts();
+
+    println!();
+    println!("Blocks committed by every node: {}", committed);
+    println!(
+        "Throughput: {:.2} blocks per second",
+        committed as f64 / (millis(elapsed) as f64 / 1000.0)
+    );
+    println!(
+        "Longest time without a new block: {:.3}s",
+        millis(longest_gap) as f64 / 1000.0
+    );
+    println!("View changes: {}", view.unwrap_or(0));
+    println!("Messages between nodes: {} sent, {} lost", sent, lost);
+    println!("No two nodes committed different blocks at the same height");
+    println!(
+        "Simulated {}s in {:.3}s",
+        elapsed.as_secs(),
+        millis(wall_time) as f64 / 1000.0
+    );
+
+    if committed == 0 {
+        process::exit(1);
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    clap_app!(pbft_sim =>
+        (version: crate_version!())
+        (about: "Simulate a network of PBFT nodes in one process")
+        (@arg nodes: -n --nodes +takes_value
+         "number of nodes in the network; 1, or at least 4 to tolerate a faulty node (default: 4)")
+        (@arg duration: -d --duration +takes_value
+         "seconds of virtual time to run the network for (default: 60)")
+        (@arg seed: --seed +takes_value
+         "seed for the network's randomness; runs with the same seed go the same way (default: 1)")
+        (@arg min_latency: --min_latency +takes_value
+         "fewest milliseconds a message between nodes takes to arrive (default: 1)")
+        (@arg max_latency: --max_latency +takes_value
+         "most milliseconds a message between nodes takes to arrive (default: 10)")
+        (@arg loss: --loss +takes_value
+         "chance of each message between nodes being lost, from 0 to 1 (default: 0)")
+        (@arg block_duration: --block_duration +takes_value
+         "milliseconds the primary waits before finalizing each block (default: 200)")
+        (@arg crash: --crash +takes_value +multiple
+         "index of a node to crash, starting from 0 for the first primary; may be given more than \
+          once")
+        (@arg crash_after: --crash_after +takes_value
+         "seconds of virtual time to wait before crashing the nodes given by --crash (default: 0)")
+        (@arg verbose: -v --verbose +multiple
+         "increase output verbosity"))
+}
+
+// Parse the command line (starting with the program's name), and check that the options make sense
+// together
+fn parse_options<I, T>(args: I) -> Result<Options, Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = app().get_matches_from_safe(args)?;
+
+    let options = Options {
+        nodes: arg(&matches, "nodes", 4)?,
+        duration: Duration::from_secs(arg(&matches, "duration", 60)?),
+        seed: arg(&matches, "seed", 1)?,
+        min_latency: Duration::from_millis(arg(&matches, "min_latency", 1)?),
+        max_latency: Duration::from_millis(arg(&matches, "max_latency", 10)?),
+        loss: arg(&matches, "loss", 0.0)?,
+        block_duration: match matches.value_of("block_duration") {
+            Some(value) => Some(Duration::from_millis(parse(
+                &matches,
+                "block_duration",
+                value,
+            )?)),
+            None => None,
+        },
+        crash: matches
+            .values_of("crash")
+            .map_or(Ok(Vec::new()), |values| {
+                values
+                    .map(|value| parse(&matches, "crash", value))
+                    .collect()
+            })?,
+        crash_after: Duration::from_secs(arg(&matches, "crash_after", 0)?),
+        verbosity: matches.occurrences_of("verbose"),
+    };
+
+    // With 2 or 3 nodes, f is 0, so the network can't tolerate a faulty node, and yet every node
+    // has to vote; that's never what's wanted
+    if options.nodes != 1 && options.nodes < 4 {
+        return Err(usage_error(
+            &matches,
+            "--nodes must be 1, or at least 4 (3f + 1 nodes tolerate f faulty ones)",
+        ));
+    }
+    if options.min_latency > options.max_latency {
+        return Err(usage_error(
+            &matches,
+            "--min_latency can't be more than --max_latency",
+        ));
+    }
+    // NaN isn't from 0 to 1 either, though it parses as a float
+    if !(0.0..=1.0).contains(&options.loss) {
+        return Err(usage_error(&matches, "--loss must be from 0 to 1"));
+    }
+    if let Some(node) = options.crash.iter().find(|node| **node >= options.nodes) {
+        return Err(usage_error(
+            &matches,
+            &format!(
+                "Can't crash node {}; there are only {} nodes",
+                node, options.nodes
+            ),
+        ));
+    }
+
+    Ok(options)
+}
+
+// Parse an option's value, or use the default if it wasn't given
+fn arg<T: FromStr>(matches: &ArgMatches, name: &str, default: T) -> Result<T, Error> {
+    match matches.value_of(name) {
+        Some(value) => parse(matches, name, value),
+        None => Ok(default),
+    }
+}
+
+fn parse<T: FromStr>(matches: &ArgMatches, name: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| usage_error(matches, &format!("Invalid value for --{}: {}", name, value)))
+}
+
+// An error in the options that clap can't check itself, reported the way clap reports its own
+fn usage_error(matches: &ArgMatches, message: &str) -> Error {
+    Error::with_description(
+        &format!(
+            "{}\n\n{}\n\nFor more information try --help",
+            message,
+            matches.usage()
+        ),
+        ErrorKind::ValueValidation,
+    )
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Options, Error> {
+        parse_options(Some("pbft-sim").into_iter().chain(args.iter().cloned()))
+    }
+
+    /// Make sure that the command line is parsed as documented, and that options that don't make
+    /// sense, including networks that can't tolerate a faulty node, are refused with the usage
+    #[test]
+    fn options() {
+        assert_eq!(
+            parse_args(&[]).unwrap(),
+            Options {
+                nodes: 4,
+                duration: Duration::from_secs(60),
+                seed: 1,
+                min_latency: Duration::from_millis(1),
+                max_latency: Duration::from_millis(10),
+                loss: 0.0,
+                block_duration: None,
+                crash: vec![],
+                crash_after: Duration::from_secs(0),
+                verbosity: 0,
+            }
+        );
+
+        assert_eq!(
+            parse_args(&[
+                "-n",
+                "7",
+                "--duration",
+                "5",
+                "--seed",
+                "9",
+                "--min_latency",
+                "2",
+                "--max_latency",
+                "3",
+                "--loss",
+                "0.5",
+                "--block_duration",
+                "50",
+                "--crash",
+                "1",
+                "--crash",
+                "6",
+                "--crash_after",
+                "2",
+                "-vv",
+            ])
+            .unwrap(),
+            Options {
+                nodes: 7,
+                duration: Duration::from_secs(5),
+                seed: 9,
+                min_latency: Duration::from_millis(2),
+                max_latency: Duration::from_millis(3),
+                loss: 0.5,
+                block_duration: Some(Duration::from_millis(50)),
+                crash: vec![1, 6],
+                crash_after: Duration::from_secs(2),
+                verbosity: 2,
+            }
+        );
+
+        // A single node is fine; it doesn't need anyone else's votes
+        assert_eq!(parse_args(&["--nodes", "1"]).unwrap().nodes, 1);
+
+        for nodes in &["0", "2", "3"] {
+            let err = parse_args(&["--nodes", nodes]).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::ValueValidation);
+            assert!(err.message.contains("--nodes must be 1, or at least 4"));
+            assert!(err.message.contains("USAGE:"));
+        }
+
+        let invalid: &[&[&str]] = &[
+            &["--nodes", "four"],
+            &["--min_latency", "5", "--max_latency", "4"],
+            &["--loss", "2"],
+            &["--loss", "NaN"],
+            &["--crash", "4"],
+        ];
+        for args in invalid {
+            assert_eq!(
+                parse_args(args).unwrap_err().kind,
+                ErrorKind::ValueValidation
+            );
+        }
+
+        // Options that don't exist are refused by clap itself, and asking for help isn't an error
+        assert_eq!(
+            parse_args(&["--nodes"]).unwrap_err().kind,
+            ErrorKind::EmptyValue
+        );
+        assert_eq!(
+            parse_args(&["--bogus"]).unwrap_err().kind,
+            ErrorKind::UnknownArgument
+        );
+        assert!(!parse_args(&["--help"]).unwrap_err().use_stderr());
+    }
+}
@@ -0,0 +1,398 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Backing off from the validator when calls to it keep failing
+//!
+//! If the validator stops answering, every tick of the engine would otherwise make the same calls
+//! and log the same failures, burying the first one that says what went wrong. The engine wraps
+//! its service in a `BreakerService`, whose `CircuitBreaker` counts calls that fail because the
+//! validator couldn't be reached. After `FAILURE_THRESHOLD` of them in a row, the breaker opens:
+//! calls fail straight away, without reaching the validator, until a backoff delay has passed.
+//! The next call is then let through as a trial; if it succeeds, the breaker closes again, and if
+//! it fails, the breaker opens for longer. While it's open, the node reports itself as degraded.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error, PeerId};
+use sawtooth_sdk::consensus::service::Service;
+
+use retry::RetryStrategy;
+use timing;
+
+/// How many calls in a row have to fail before the breaker opens
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// How long the breaker stays open: 1s the first time, then twice as long each time the trial
+/// call fails, up to a minute
+const BACKOFF: RetryStrategy = RetryStrategy::Exponential {
+    initial: Duration::from_secs(1),
+    max: Duration::from_secs(60),
+};
+
+/// Whether calls to the validator are being let through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakerState {
+    /// Calls are let through
+    Closed,
+
+    /// Calls fail without reaching the validator
+    Open,
+
+    /// The next call is let through, to see if the validator has recovered
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Decides whether to let calls to the validator through, based on how recent calls went
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    /// How many calls have failed in a row
+    failures: u32,
+
+    /// How many times in a row the breaker has opened, without a call succeeding in between
+    trips: u32,
+
+    /// Until when calls are refused, if the breaker is open (or was, and is waiting for a trial)
+    open_until: Option<Instant>,
+
+    /// The error that made the breaker open
+    cause: Option<String>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        CircuitBreaker::default()
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if timing::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// How long until a trial call will be let through (zero if calls are let through now)
+    pub fn retry_in(&self) -> Duration {
+        self.open_until.map_or(Duration::from_secs(0), |until| {
+            let now = timing::now();
+            if until > now {
+                until - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
+    /// The error that made the breaker open, if it's not closed
+    pub fn cause(&self) -> Option<&str> {
+        self.cause.as_ref().map(String::as_str)
+    }
+
+    /// Whether a call should be let through now
+    pub fn allow(&self) -> bool {
+        self.state() != BreakerState::Open
+    }
+
+    /// Record that a call reached the validator
+    pub fn succeed(&mut self) {
+        if self.open_until.is_some() {
+            info!("Calls to the validator are succeeding again; resuming them");
+        }
+        self.failures = 0;
+        self.trips = 0;
+        self.open_until = None;
+        self.cause = None;
+    }
+
+    /// Record that a call failed because the validator couldn't be reached; opens the breaker if
+    /// enough calls have failed in a row, or if this was the trial call
+    pub fn fail(&mut self, err: &Error) {
+        self.failures += 1;
+        if self.open_until.is_none() && self.failures < FAILURE_THRESHOLD {
+            return;
+        }
+
+        let backoff = BACKOFF.delay(self.trips);
+        self.trips += 1;
+        self.open_until = Some(timing::now() + backoff);
+        self.cause = Some(err.to_string());
+        warn!(
+            "{} calls to the validator failed in a row, most recently with {}; pausing calls \
+             for {:?}",
+            self.failures, err, backoff
+        );
+    }
+}
+
+// Whether a call failed because the validator couldn't be reached, rather than because of what
+// was asked of it (an unknown block, for instance)
+fn is_connection_failure(err: &Error) -> bool {
+    match err {
+        Error::SendError(_) | Error::ReceiveError(_) => true,
+        _ => false,
+    }
+}
+
+/// A `Service` that stops calling the one it wraps while its `CircuitBreaker` is open
+pub struct BreakerService {
+    inner: Box<Service>,
+    breaker: Rc<RefCell<CircuitBreaker>>,
+}
+
+impl BreakerService {
+    /// Wrap the given service; the breaker is shared, so the node's state can report on it
+    pub fn new(inner: Box<Service>, breaker: Rc<RefCell<CircuitBreaker>>) -> Self {
+        BreakerService { inner, breaker }
+    }
+
+    // Make a call, unless the breaker is open, and record how it went
+    fn call<T, F>(&mut self, call: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Service) -> Result<T, Error>,
+    {
+        if !self.breaker.borrow().allow() {
+            return Err(Error::SendError(format!(
+                "calls to the validator are paused for {:?} after repeated failures",
+                self.breaker.borrow().retry_in()
+            )));
+        }
+
+        let res = call(&mut *self.inner);
+        match res {
+            Err(ref err) if is_connection_failure(err) => self.breaker.borrow_mut().fail(err),
+            _ => self.breaker.borrow_mut().succeed(),
+        }
+        res
+    }
+}
+
+impl Service for BreakerService {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.call(|service| service.send_to(peer, message_type, payload))
+    }
+
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        self.call(|service| service.broadcast(message_type, payload))
+    }
+
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error> {
+        self.call(|service| service.initialize_block(previous_id))
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        self.call(|service| service.summarize_block())
+    }
+
+    fn finalize_block(&mut self, data: Vec<u8>) -> Result<BlockId, Error> {
+        self.call(|service| service.finalize_block(data))
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        self.call(|service| service.cancel_block())
+    }
+
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error> {
+        self.call(|service| service.check_blocks(priority))
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.call(|service| service.commit_block(block_id))
+    }
+
+    fn ignore_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.call(|service| service.ignore_block(block_id))
+    }
+
+    fn fail_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.call(|service| service.fail_block(block_id))
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        self.call(|service| service.get_blocks(block_ids))
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        self.call(|service| service.get_chain_head())
+    }
+
+    fn get_settings(
+        &mut self,
+        block_id: BlockId,
+        settings: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        self.call(|service| service.get_settings(block_id, settings))
+    }
+
+    fn get_state(
+        &mut self,
+        block_id: BlockId,
+        addresses: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u8>>, Error> {
+        self.call(|service| service.get_state(block_id, addresses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A service whose calls to `cancel_block` fail until it's told the validator is back
+    struct FlakyService {
+        reachable: Rc<RefCell<bool>>,
+        calls: Rc<RefCell<u32>>,
+    }
+
+    impl Service for FlakyService {
+        fn send_to(
+            &mut self,
+            _peer: &PeerId,
+            _message_type: &str,
+            _payload: Vec<u8>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+        fn broadcast(&mut self, _message_type: &str, _payload: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn initialize_block(&mut self, _previous_id: Option<BlockId>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+            Ok(Default::default())
+        }
+        fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
+            Ok(Default::default())
+        }
+        fn cancel_block(&mut self) -> Result<(), Error> {
+            *self.calls.borrow_mut() += 1;
+            if *self.reachable.borrow() {
+                Ok(())
+            } else {
+                Err(Error::ReceiveError(String::from("validator is gone")))
+            }
+        }
+        fn check_blocks(&mut self, _priority: Vec<BlockId>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn commit_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Err(Error::UnknownBlock(String::from("no such block")))
+        }
+        fn get_blocks(
+            &mut self,
+            _block_ids: Vec<BlockId>,
+        ) -> Result<HashMap<BlockId, Block>, Error> {
+            Ok(Default::default())
+        }
+        fn get_chain_head(&mut self) -> Result<Block, Error> {
+            Err(Error::NoChainHead)
+        }
+        fn get_settings(
+            &mut self,
+            _block_id: BlockId,
+            _settings: Vec<String>,
+        ) -> Result<HashMap<String, String>, Error> {
+            Ok(Default::default())
+        }
+        fn get_state(
+            &mut self,
+            _block_id: BlockId,
+            _addresses: Vec<String>,
+        ) -> Result<HashMap<String, Vec<u8>>, Error> {
+            Ok(Default::default())
+        }
+    }
+
+    /// Make sure that the breaker opens after enough failures in a row, refuses calls while it's
+    /// open, waits longer after a failed trial, closes once a trial succeeds, and doesn't count
+    /// errors that don't mean the validator is unreachable
+    #[test]
+    fn circuit_breaker() {
+        timing::start_virtual_time(1);
+        let reachable = Rc::new(RefCell::new(false));
+        let calls = Rc::new(RefCell::new(0));
+        let breaker = Rc::new(RefCell::new(CircuitBreaker::new()));
+        let mut service = BreakerService::new(
+            Box::new(FlakyService {
+                reachable: Rc::clone(&reachable),
+                calls: Rc::clone(&calls),
+            }),
+            Rc::clone(&breaker),
+        );
+
+        // Errors about what was asked don't count
+        for _ in 0..FAILURE_THRESHOLD {
+            assert!(service.fail_block(Default::default()).is_err());
+        }
+        assert_eq!(breaker.borrow().state(), BreakerState::Closed);
+
+        for _ in 0..FAILURE_THRESHOLD {
+            assert!(service.cancel_block().is_err());
+        }
+        assert_eq!(breaker.borrow().state(), BreakerState::Open);
+        assert!(breaker
+            .borrow()
+            .cause()
+            .unwrap()
+            .contains("validator is gone"));
+        assert_eq!(breaker.borrow().retry_in(), Duration::from_secs(1));
+
+        // Refused without reaching the validator
+        assert!(service.cancel_block().is_err());
+        assert_eq!(*calls.borrow(), FAILURE_THRESHOLD);
+
+        // The trial fails, so the breaker opens for longer
+        timing::set_virtual_time(timing::now() + Duration::from_secs(1));
+        assert_eq!(breaker.borrow().state(), BreakerState::HalfOpen);
+        assert!(service.cancel_block().is_err());
+        assert_eq!(*calls.borrow(), FAILURE_THRESHOLD + 1);
+        assert_eq!(breaker.borrow().state(), BreakerState::Open);
+        assert_eq!(breaker.borrow().retry_in(), Duration::from_secs(2));
+
+        // The validator comes back, and the next trial closes the breaker
+        *reachable.borrow_mut() = true;
+        timing::set_virtual_time(timing::now() + Duration::from_secs(2));
+        assert!(service.cancel_block().is_ok());
+        assert_eq!(breaker.borrow().state(), BreakerState::Closed);
+        assert_eq!(breaker.borrow().cause(), None);
+    }
+}
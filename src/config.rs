@@ -24,10 +24,12 @@ use std::collections::HashMap;
 
 use std::time::Duration;
 
-use sawtooth_sdk::consensus::{
-    engine::{BlockId, PeerId},
-    service::Service,
-};
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+
+use consensus_service::ConsensusService;
+use error::PbftError;
+use message_type::PbftMessageType;
+use primary::{Blacklist, PrimarySelection};
 
 /// Contains the initial configuration loaded from on-chain settings, if present, or defaults in
 /// their absence.
@@ -42,15 +44,102 @@ pub struct PbftConfig {
     /// How long to wait for a message to arrive
     pub message_timeout: Duration,
 
+    /// If set, the primary publishes a block as soon as one is ready, as long as this long has
+    /// passed since the last block was committed, instead of only every `block_duration`
+    pub min_block_interval: Option<Duration>,
+
     /// How long to wait to initiate a ViewChange if we suspect the primary's faulty
     /// Should be longer than block_duration
     pub view_change_timeout: Duration,
 
-    /// How many requests in between each checkpoint
+    /// Whether to adjust the view change timeout based on how long recent blocks took to commit
+    pub adaptive_timeout: bool,
+
+    /// What to multiply the 99th percentile of recent commit latencies by to get the adaptive
+    /// timeout
+    pub adaptive_timeout_factor: f64,
+
+    /// The lowest the adaptive timeout can go (the highest is `view_change_timeout`)
+    pub min_view_change_timeout: Duration,
+
+    /// How long to wait for a view change to complete before trying the next view; this grows
+    /// with each consecutive view change that doesn't complete
+    pub view_change_duration: Duration,
+
+    /// How often to check that the primary is responding while no block is in progress (no
+    /// checks if `None`)
+    pub probe_interval: Option<Duration>,
+
+    /// How many checks in a row the primary can fail to respond to before a view change starts
+    pub max_probe_failures: u64,
+
+    /// How often the primary tells the other nodes that it's still running while no block is in
+    /// progress (no heartbeats if `None`)
+    pub heartbeat_interval: Option<Duration>,
+
+    /// How long to go without hearing from the primary while no block is in progress before
+    /// starting a view change (only used if `heartbeat_interval` is set)
+    pub idle_timeout: Duration,
+
+    /// How often to compare this node's clock with the other nodes' (no comparisons if `None`)
+    pub clock_sync_interval: Option<Duration>,
+
+    /// How far another node's clock can be from this node's before it's warned about
+    pub max_clock_skew: Duration,
+
+    /// How many blocks in between each checkpoint
     pub checkpoint_period: u64,
 
+    /// The most faulty nodes to tolerate, if fewer than the network's size allows; quorums are
+    /// made larger to make up for it (`floor((n - 1) / 3)` if `None`)
+    pub fault_tolerance: Option<u64>,
+
     /// How large the PbftLog is allowed to get
     pub max_log_size: u64,
+
+    /// How many blocks can wait in the log for their turn; the IDs of any more blocks are kept
+    /// instead, and the blocks are fetched again once there's room for them
+    pub max_block_backlog: u64,
+
+    /// How many seals for committed blocks are kept after their messages have been garbage
+    /// collected, so nodes that fell far behind can still catch up
+    pub seal_history_size: u64,
+
+    /// Whether the seal of a block committed in a later view than it was proposed in includes the
+    /// `NewView` message for that view, as evidence that the view change happened
+    pub seal_view_change_evidence: bool,
+
+    /// Whether to commit a block as soon as `Prepare` messages are received from all nodes,
+    /// instead of waiting for `Commit` messages
+    pub fast_path: bool,
+
+    /// With the fast path, how long a node waits for `Prepare` messages from all nodes once a
+    /// block is valid, before sending its `Commit` and falling back to the normal `Commit` phase
+    pub fast_path_timeout: Duration,
+
+    /// Whether every message is signed by the node that sends it, and dropped by receivers if the
+    /// signature doesn't match the node it says it's from
+    pub authenticate_messages: bool,
+
+    /// How many views or sequence numbers behind or ahead of this node's own a peer message can be
+    /// before it is dropped
+    pub message_window: u64,
+
+    /// How many recently received messages to remember, so that copies of them can be dropped
+    pub replay_cache_size: u64,
+
+    /// The largest peer message, in bytes, that will be decoded
+    pub max_message_size: u64,
+
+    /// How many messages of each type (by name) each node may send per second; types that aren't
+    /// listed aren't limited
+    pub rate_limits: HashMap<String, f64>,
+
+    /// How to choose the primary for each view
+    pub primary_selection: PrimarySelection,
+
+    /// When to temporarily skip nodes that have failed as primary too often
+    pub primary_blacklist: Blacklist,
 }
 
 impl PbftConfig {
@@ -59,9 +148,36 @@ impl PbftConfig {
             peers: Vec::new(),
             block_duration: Duration::from_millis(200),
             message_timeout: Duration::from_millis(10),
+            min_block_interval: None,
             view_change_timeout: Duration::from_millis(4000),
+            adaptive_timeout: false,
+            adaptive_timeout_factor: 3.0,
+            min_view_change_timeout: Duration::from_millis(1000),
+            view_change_duration: Duration::from_millis(5000),
+            probe_interval: None,
+            max_probe_failures: 3,
+            heartbeat_interval: None,
+            idle_timeout: Duration::from_millis(3000),
+            clock_sync_interval: None,
+            max_clock_skew: Duration::from_millis(1000),
             checkpoint_period: 100,
+            fault_tolerance: None,
             max_log_size: 1000,
+            max_block_backlog: 100,
+            seal_history_size: 1000,
+            seal_view_change_evidence: false,
+            fast_path: false,
+            fast_path_timeout: Duration::from_millis(100),
+            authenticate_messages: false,
+            message_window: 100,
+            replay_cache_size: 1000,
+            max_message_size: 10 * 1024 * 1024,
+            rate_limits: HashMap::new(),
+            primary_selection: PrimarySelection::RoundRobin,
+            primary_blacklist: Blacklist {
+                threshold: 0,
+                cooldown: 100,
+            },
         }
     }
 }
@@ -71,16 +187,64 @@ impl PbftConfig {
 /// Configuration loads the following settings:
 /// + `sawtooth.consensus.pbft.peers` (required)
 /// + `sawtooth.consensus.pbft.block_duration` (optional, default 200 ms)
-/// + `sawtooth.consensus.pbft.checkpoint_period` (optional, default 10 ms)
+/// + `sawtooth.consensus.pbft.min_block_interval` (optional, default 0 ms (disabled))
+/// + `sawtooth.consensus.pbft.checkpoint_period` (optional, default 100 blocks)
 /// + `sawtooth.consensus.pbft.view_change_timeout` (optional, default 4000 ms)
-/// + `sawtooth.consensus.pbft.message_timeout` (optional, default 100 blocks)
+/// + `sawtooth.consensus.pbft.message_timeout` (optional, default 10 ms)
 /// + `sawtooth.consensus.pbft.max_log_size` (optional, default 1000 messages)
+/// + `sawtooth.consensus.pbft.max_block_backlog` (optional, default 100 blocks)
+/// + `sawtooth.consensus.pbft.seal_history_size` (optional, default 1000 seals)
+/// + `sawtooth.consensus.pbft.seal_view_change_evidence` (optional, default false)
+/// + `sawtooth.consensus.pbft.fast_path` (optional, default false)
+/// + `sawtooth.consensus.pbft.fast_path_timeout` (optional, default 100 ms)
+/// + `sawtooth.consensus.pbft.authenticate_messages` (optional, default false)
+/// + `sawtooth.consensus.pbft.message_window` (optional, default 100)
+/// + `sawtooth.consensus.pbft.replay_cache_size` (optional, default 1000 messages)
+/// + `sawtooth.consensus.pbft.rate_limits` (optional, default no limits)
+/// + `sawtooth.consensus.pbft.max_message_size` (optional, default 10 MiB)
+/// + `sawtooth.consensus.pbft.adaptive_timeout` (optional, default false)
+/// + `sawtooth.consensus.pbft.adaptive_timeout_factor` (optional, default 3.0)
+/// + `sawtooth.consensus.pbft.min_view_change_timeout` (optional, default 1000 ms)
+/// + `sawtooth.consensus.pbft.view_change_duration` (optional, default 5000 ms)
+/// + `sawtooth.consensus.pbft.probe_interval` (optional, default 0 ms (disabled))
+/// + `sawtooth.consensus.pbft.max_probe_failures` (optional, default 3)
+/// + `sawtooth.consensus.pbft.fault_tolerance` (optional, default `floor((n - 1) / 3)`)
+/// + `sawtooth.consensus.pbft.heartbeat_interval` (optional, default 0 ms (disabled))
+/// + `sawtooth.consensus.pbft.idle_timeout` (optional, default 3000 ms)
+/// + `sawtooth.consensus.pbft.clock_sync_interval` (optional, default 0 ms (disabled))
+/// + `sawtooth.consensus.pbft.max_clock_skew` (optional, default 1000 ms)
+/// + `sawtooth.consensus.pbft.primary_selection` (optional, default `round_robin`)
+/// + `sawtooth.consensus.pbft.primary_weights` (optional, used by `weighted` primary selection)
+/// + `sawtooth.consensus.pbft.primary_failure_threshold` (optional, default 0 (disabled))
+/// + `sawtooth.consensus.pbft.primary_cooldown` (optional, default 100 views)
 ///
-/// # Panics
-/// + If the `sawtooth.consensus.pbft.peers` setting is not provided
+/// # Errors
+/// + If the `sawtooth.consensus.pbft.peers` setting is not provided, or isn't a list of at least
+///   four nodes (or just one)
 /// + If settings loading fails entirely
 /// + If block duration is greater than the view change timeout
-pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig {
+/// + If the minimum view change timeout is greater than the view change timeout
+/// + If the heartbeat interval isn't less than the idle timeout
+/// + If the checkpoint period is 0, or isn't less than the maximum log size
+///
+/// Every node loads the same settings, so a bad value is an error to handle rather than a panic;
+/// otherwise, it would stop every node in the network at once.
+pub fn load_pbft_config<S: ConsensusService + ?Sized>(
+    block_id: BlockId,
+    service: &mut S,
+) -> Result<PbftConfig, PbftError> {
+    let config = read_pbft_config(block_id, service)?;
+    check_timeouts(&config)?;
+    check_checkpoint_period(&config)?;
+    Ok(config)
+}
+
+/// Like `load_pbft_config`, but without checking that the timeouts and checkpoint period make
+/// sense, so that they can be checked (and reported on) separately
+pub fn read_pbft_config<S: ConsensusService + ?Sized>(
+    block_id: BlockId,
+    service: &mut S,
+) -> Result<PbftConfig, PbftError> {
     let mut config = PbftConfig::default();
 
     let sawtooth_settings: HashMap<String, String> = service
@@ -89,29 +253,57 @@ pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig
             vec![
                 String::from("sawtooth.consensus.pbft.peers"),
                 String::from("sawtooth.consensus.pbft.block_duration"),
+                String::from("sawtooth.consensus.pbft.min_block_interval"),
                 String::from("sawtooth.consensus.pbft.checkpoint_period"),
                 String::from("sawtooth.consensus.pbft.view_change_timeout"),
                 String::from("sawtooth.consensus.pbft.message_timeout"),
                 String::from("sawtooth.consensus.pbft.max_log_size"),
+                String::from("sawtooth.consensus.pbft.max_block_backlog"),
+                String::from("sawtooth.consensus.pbft.seal_history_size"),
+                String::from("sawtooth.consensus.pbft.seal_view_change_evidence"),
+                String::from("sawtooth.consensus.pbft.fast_path"),
+                String::from("sawtooth.consensus.pbft.fast_path_timeout"),
+                String::from("sawtooth.consensus.pbft.authenticate_messages"),
+                String::from("sawtooth.consensus.pbft.message_window"),
+                String::from("sawtooth.consensus.pbft.replay_cache_size"),
+                String::from("sawtooth.consensus.pbft.rate_limits"),
+                String::from("sawtooth.consensus.pbft.max_message_size"),
+                String::from("sawtooth.consensus.pbft.adaptive_timeout"),
+                String::from("sawtooth.consensus.pbft.adaptive_timeout_factor"),
+                String::from("sawtooth.consensus.pbft.min_view_change_timeout"),
+                String::from("sawtooth.consensus.pbft.view_change_duration"),
+                String::from("sawtooth.consensus.pbft.probe_interval"),
+                String::from("sawtooth.consensus.pbft.max_probe_failures"),
+                String::from("sawtooth.consensus.pbft.fault_tolerance"),
+                String::from("sawtooth.consensus.pbft.heartbeat_interval"),
+                String::from("sawtooth.consensus.pbft.idle_timeout"),
+                String::from("sawtooth.consensus.pbft.clock_sync_interval"),
+                String::from("sawtooth.consensus.pbft.max_clock_skew"),
+                String::from("sawtooth.consensus.pbft.primary_selection"),
+                String::from("sawtooth.consensus.pbft.primary_weights"),
+                String::from("sawtooth.consensus.pbft.primary_failure_threshold"),
+                String::from("sawtooth.consensus.pbft.primary_cooldown"),
             ],
         )
-        .expect("Failed to get on-chain settings");
+        .map_err(|err| {
+            PbftError::ServiceError(String::from("Failed to get on-chain settings"), err)
+        })?;
 
-    // Get the peers associated with this node (including ourselves). Panic if it is not provided;
-    // the network cannot function without this setting.
+    // Get the peers associated with this node (including ourselves); the network cannot function
+    // without this setting
     let peers_string = sawtooth_settings
         .get("sawtooth.consensus.pbft.peers")
-        .expect("'sawtooth.consensus.pbft.peers' must be set");
-
-    let peers: Vec<String> = serde_json::from_str(peers_string)
-        .expect("Invalid value in 'sawtooth.consensus.pbft.peers'");
-
-    let peers: Vec<PeerId> = peers
-        .into_iter()
-        .map(|s| PeerId::from(hex::decode(s).expect("PeerId is not valid hex")))
-        .collect();
+        .ok_or_else(|| {
+            PbftError::InvalidConfig(String::from("'sawtooth.consensus.pbft.peers' must be set"))
+        })?;
 
-    config.peers = peers;
+    config.peers = parse_peers(peers_string)?;
+    if config.peers.len() < 4 && config.peers.len() != 1 {
+        return Err(PbftError::InvalidConfig(format!(
+            "Network of {} nodes would not be fault tolerant",
+            config.peers.len()
+        )));
+    }
 
     // Get various durations
     if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.block_duration") {
@@ -129,10 +321,67 @@ pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig
             config.view_change_timeout = Duration::from_millis(view_change_timeout);
         }
     }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.fast_path_timeout") {
+        if let Ok(fast_path_timeout) = s.parse() {
+            config.fast_path_timeout = Duration::from_millis(fast_path_timeout);
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.min_view_change_timeout") {
+        if let Ok(min_view_change_timeout) = s.parse() {
+            config.min_view_change_timeout = Duration::from_millis(min_view_change_timeout);
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.view_change_duration") {
+        if let Ok(view_change_duration) = s.parse() {
+            config.view_change_duration = Duration::from_millis(view_change_duration);
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.probe_interval") {
+        if let Ok(probe_interval) = s.parse::<u64>() {
+            config.probe_interval = if probe_interval > 0 {
+                Some(Duration::from_millis(probe_interval))
+            } else {
+                None
+            };
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.heartbeat_interval") {
+        if let Ok(heartbeat_interval) = s.parse::<u64>() {
+            config.heartbeat_interval = if heartbeat_interval > 0 {
+                Some(Duration::from_millis(heartbeat_interval))
+            } else {
+                None
+            };
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.idle_timeout") {
+        if let Ok(idle_timeout) = s.parse() {
+            config.idle_timeout = Duration::from_millis(idle_timeout);
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.clock_sync_interval") {
+        if let Ok(clock_sync_interval) = s.parse::<u64>() {
+            config.clock_sync_interval = if clock_sync_interval > 0 {
+                Some(Duration::from_millis(clock_sync_interval))
+            } else {
+                None
+            };
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.max_clock_skew") {
+        if let Ok(max_clock_skew) = s.parse() {
+            config.max_clock_skew = Duration::from_millis(max_clock_skew);
+        }
+    }
 
-    // Check to make sure block_duration < view_change_timeout
-    if config.block_duration >= config.view_change_timeout {
-        panic!("Block duration must be less than the view change timeout");
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.min_block_interval") {
+        if let Ok(min_block_interval) = s.parse::<u64>() {
+            config.min_block_interval = if min_block_interval > 0 {
+                Some(Duration::from_millis(min_block_interval))
+            } else {
+                None
+            };
+        }
     }
 
     // Get various integer constants
@@ -146,8 +395,267 @@ pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig
             config.max_log_size = max_log_size;
         }
     }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.max_block_backlog") {
+        if let Ok(max_block_backlog) = s.parse() {
+            config.max_block_backlog = max_block_backlog;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.seal_history_size") {
+        if let Ok(seal_history_size) = s.parse() {
+            config.seal_history_size = seal_history_size;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.message_window") {
+        if let Ok(message_window) = s.parse() {
+            config.message_window = message_window;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.replay_cache_size") {
+        if let Ok(replay_cache_size) = s.parse() {
+            config.replay_cache_size = replay_cache_size;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.max_message_size") {
+        if let Ok(max_message_size) = s.parse() {
+            config.max_message_size = max_message_size;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.max_probe_failures") {
+        if let Ok(max_probe_failures) = s.parse() {
+            config.max_probe_failures = max_probe_failures;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.fault_tolerance") {
+        if let Ok(fault_tolerance) = s.parse::<u64>() {
+            let max_faulty = (config.peers.len() as u64).saturating_sub(1) / 3;
+            if fault_tolerance > max_faulty {
+                warn!(
+                    "Can't tolerate {} faulty nodes in a network of {}; tolerating {}",
+                    fault_tolerance,
+                    config.peers.len(),
+                    max_faulty
+                );
+            }
+            config.fault_tolerance = Some(fault_tolerance);
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.primary_failure_threshold") {
+        if let Ok(threshold) = s.parse() {
+            config.primary_blacklist.threshold = threshold;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.primary_cooldown") {
+        if let Ok(cooldown) = s.parse() {
+            config.primary_blacklist.cooldown = cooldown;
+        }
+    }
 
-    config
+    // Get flags
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.seal_view_change_evidence") {
+        if let Ok(seal_view_change_evidence) = s.parse() {
+            config.seal_view_change_evidence = seal_view_change_evidence;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.fast_path") {
+        if let Ok(fast_path) = s.parse() {
+            config.fast_path = fast_path;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.authenticate_messages") {
+        if let Ok(authenticate_messages) = s.parse() {
+            config.authenticate_messages = authenticate_messages;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.adaptive_timeout") {
+        if let Ok(adaptive_timeout) = s.parse() {
+            config.adaptive_timeout = adaptive_timeout;
+        }
+    }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.adaptive_timeout_factor") {
+        if let Ok(adaptive_timeout_factor) = s.parse() {
+            config.adaptive_timeout_factor = adaptive_timeout_factor;
+        }
+    }
+
+    // Get the rate limits for each message type
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.rate_limits") {
+        match parse_rate_limits(s) {
+            Ok(rate_limits) => config.rate_limits = rate_limits,
+            Err(err) => warn!("Not limiting message rates: {}", err),
+        }
+    }
+
+    // Get the primary selection strategy
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.primary_selection") {
+        let weights = sawtooth_settings.get("sawtooth.consensus.pbft.primary_weights");
+        match parse_primary_selection(s, weights.map(String::as_str)) {
+            Ok(primary_selection) => config.primary_selection = primary_selection,
+            Err(err) => warn!("Using round robin primary selection: {}", err),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Check that the timeouts work together: a block has to be published before the view change
+/// timeout runs out, and heartbeats have to come before the idle timeout does
+///
+/// # Errors
+/// + If block duration is greater than the view change timeout
+/// + If the minimum view change timeout is greater than the view change timeout
+/// + If the heartbeat interval isn't less than the idle timeout
+pub fn check_timeouts(config: &PbftConfig) -> Result<(), PbftError> {
+    if config.block_duration >= config.view_change_timeout {
+        return Err(PbftError::InvalidConfig(String::from(
+            "Block duration must be less than the view change timeout",
+        )));
+    }
+    if config.min_view_change_timeout > config.view_change_timeout {
+        return Err(PbftError::InvalidConfig(String::from(
+            "Minimum view change timeout must not be greater than the view change timeout",
+        )));
+    }
+    if let Some(heartbeat_interval) = config.heartbeat_interval {
+        if heartbeat_interval >= config.idle_timeout {
+            return Err(PbftError::InvalidConfig(String::from(
+                "Heartbeat interval must be less than the idle timeout",
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check that checkpoints can be taken: the period has to be at least one block, a checkpoint
+/// has to fall between the log's water marks so that the log can be garbage collected, and the
+/// `Checkpoint` messages for it mustn't be dropped as stale before the next one is taken
+///
+/// # Errors
+/// + If the checkpoint period is 0
+/// + If the checkpoint period isn't less than the maximum log size
+/// + If the message window is less than the checkpoint period
+pub fn check_checkpoint_period(config: &PbftConfig) -> Result<(), PbftError> {
+    if config.checkpoint_period == 0 {
+        return Err(PbftError::InvalidConfig(String::from(
+            "Checkpoint period must be greater than 0",
+        )));
+    }
+    if config.checkpoint_period >= config.max_log_size {
+        return Err(PbftError::InvalidConfig(String::from(
+            "Checkpoint period must be less than the maximum log size",
+        )));
+    }
+    if config.message_window < config.checkpoint_period {
+        return Err(PbftError::InvalidConfig(String::from(
+            "Message window must not be less than the checkpoint period",
+        )));
+    }
+    Ok(())
+}
+
+/// Load the `sawtooth.consensus.pbft.peers` setting as of the given block, so that membership
+/// changes can be picked up while the node is running.
+pub fn load_peers<S: ConsensusService + ?Sized>(
+    block_id: BlockId,
+    service: &mut S,
+) -> Result<Vec<PeerId>, PbftError> {
+    let sawtooth_settings: HashMap<String, String> = service
+        .get_settings(
+            block_id,
+            vec![String::from("sawtooth.consensus.pbft.peers")],
+        )
+        .map_err(|err| PbftError::ServiceError(String::from("Failed to get settings"), err))?;
+
+    let peers_string = sawtooth_settings
+        .get("sawtooth.consensus.pbft.peers")
+        .ok_or_else(|| {
+            PbftError::InvalidConfig(String::from("'sawtooth.consensus.pbft.peers' is not set"))
+        })?;
+
+    parse_peers(peers_string)
+}
+
+// Parse the JSON list of hex-encoded public keys in the `sawtooth.consensus.pbft.peers` setting
+fn parse_peers(peers_string: &str) -> Result<Vec<PeerId>, PbftError> {
+    let peers: Vec<String> = serde_json::from_str(peers_string).map_err(|err| {
+        PbftError::InvalidConfig(format!(
+            "Invalid value in 'sawtooth.consensus.pbft.peers': {}",
+            err
+        ))
+    })?;
+
+    peers
+        .into_iter()
+        .map(|s| {
+            hex::decode(s).map(PeerId::from).map_err(|err| {
+                PbftError::InvalidConfig(format!("PeerId is not valid hex: {}", err))
+            })
+        })
+        .collect()
+}
+
+// Parse the JSON map of message type names to messages per second in the
+// `sawtooth.consensus.pbft.rate_limits` setting
+fn parse_rate_limits(rate_limits: &str) -> Result<HashMap<String, f64>, PbftError> {
+    let rate_limits: HashMap<String, f64> = serde_json::from_str(rate_limits).map_err(|err| {
+        PbftError::InvalidConfig(format!(
+            "Invalid value in 'sawtooth.consensus.pbft.rate_limits': {}",
+            err
+        ))
+    })?;
+
+    for (msg_type, rate) in &rate_limits {
+        if PbftMessageType::from(msg_type.as_str()) == PbftMessageType::Unset {
+            return Err(PbftError::InvalidConfig(format!(
+                "Unknown message type '{}'",
+                msg_type
+            )));
+        }
+        if *rate <= 0.0 {
+            return Err(PbftError::InvalidConfig(format!(
+                "Rate limit for {} must be positive",
+                msg_type
+            )));
+        }
+    }
+
+    Ok(rate_limits)
+}
+
+// Parse the `sawtooth.consensus.pbft.primary_selection` setting, along with the JSON map of
+// hex-encoded public keys to weights in `sawtooth.consensus.pbft.primary_weights`
+fn parse_primary_selection(
+    selection: &str,
+    weights: Option<&str>,
+) -> Result<PrimarySelection, PbftError> {
+    match selection {
+        "round_robin" => Ok(PrimarySelection::RoundRobin),
+        "reputation" => Ok(PrimarySelection::Reputation),
+        "weighted" => {
+            let weights: HashMap<String, u64> = serde_json::from_str(weights.unwrap_or("{}"))
+                .map_err(|err| {
+                    PbftError::InvalidConfig(format!(
+                        "Invalid value in 'sawtooth.consensus.pbft.primary_weights': {}",
+                        err
+                    ))
+                })?;
+
+            weights
+                .into_iter()
+                .map(|(key, weight)| {
+                    hex::decode(key)
+                        .map(|key| (PeerId::from(key), weight))
+                        .map_err(|err| {
+                            PbftError::InvalidConfig(format!("PeerId is not valid hex: {}", err))
+                        })
+                })
+                .collect::<Result<_, _>>()
+                .map(PrimarySelection::Weighted)
+        }
+        _ => Err(PbftError::InvalidConfig(format!(
+            "Unknown primary selection strategy '{}'",
+            selection
+        ))),
+    }
 }
 
 /// Create a mock configuration, given a number of nodes. PeerIds are generated using a Sha256
@@ -168,3 +676,29 @@ pub fn mock_config(num_nodes: usize) -> PbftConfig {
     config.peers = ids;
     config
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that a checkpoint period of 0, one that doesn't fit in the log, or one longer
+    /// than the message window is rejected
+    #[test]
+    fn checkpoint_period() {
+        let mut config = mock_config(4);
+        assert!(check_checkpoint_period(&config).is_ok());
+
+        config.checkpoint_period = 0;
+        assert!(check_checkpoint_period(&config).is_err());
+
+        config.checkpoint_period = config.max_log_size;
+        assert!(check_checkpoint_period(&config).is_err());
+
+        config.checkpoint_period = config.max_log_size - 1;
+        config.message_window = config.checkpoint_period;
+        assert!(check_checkpoint_period(&config).is_ok());
+
+        config.message_window = config.checkpoint_period - 1;
+        assert!(check_checkpoint_period(&config).is_err());
+    }
+}
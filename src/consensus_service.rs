@@ -0,0 +1,235 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! What the engine needs from the validator
+//!
+//! The node and the code that loads its settings only use part of the Sawtooth SDK's `Service`,
+//! and `ConsensusService` is that part. Everything that depends on the validator is generic over
+//! it, so a different backend, a mock, or a simulator only has to implement these calls. Every
+//! `Service` is a `ConsensusService`, so the SDK's connection to the validator, and the services
+//! that wrap it (`BreakerService` and `TracedService`), can be used as they are.
+
+use std::collections::HashMap;
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error, PeerId};
+use sawtooth_sdk::consensus::service::Service;
+
+/// The calls the engine makes to the validator
+pub trait ConsensusService {
+    /// Send a consensus message to a specific, connected peer
+    fn send_to(&mut self, peer: &PeerId, message_type: &str, payload: Vec<u8>)
+        -> Result<(), Error>;
+
+    /// Send a consensus message to all connected peers
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error>;
+
+    /// Start a block on top of the given one (or the chain head, if `None`), and start adding
+    /// batches to it
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error>;
+
+    /// Stop adding batches to the block in progress, and return a summary of its contents
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// Add the given consensus data to the block in progress, and publish it
+    fn finalize_block(&mut self, data: Vec<u8>) -> Result<BlockId, Error>;
+
+    /// Stop adding batches to the block in progress, and abandon it
+    fn cancel_block(&mut self) -> Result<(), Error>;
+
+    /// Have the given blocks checked, in order; each one is reported valid or invalid
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error>;
+
+    /// Make the given block the chain head
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error>;
+
+    /// Let the validator know that the given block won't be committed, for now
+    fn ignore_block(&mut self, block_id: BlockId) -> Result<(), Error>;
+
+    /// Mark the given block and everything built on it as invalid
+    fn fail_block(&mut self, block_id: BlockId) -> Result<(), Error>;
+
+    /// Get the given blocks
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error>;
+
+    /// Get the chain head
+    fn get_chain_head(&mut self) -> Result<Block, Error>;
+
+    /// Get the values of the given on-chain settings, as of the given block
+    fn get_settings(
+        &mut self,
+        block_id: BlockId,
+        settings: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error>;
+}
+
+impl<S: Service + ?Sized> ConsensusService for S {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        Service::send_to(self, peer, message_type, payload)
+    }
+
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        Service::broadcast(self, message_type, payload)
+    }
+
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error> {
+        Service::initialize_block(self, previous_id)
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        Service::summarize_block(self)
+    }
+
+    fn finalize_block(&mut self, data: Vec<u8>) -> Result<BlockId, Error> {
+        Service::finalize_block(self, data)
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        Service::cancel_block(self)
+    }
+
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error> {
+        Service::check_blocks(self, priority)
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        Service::commit_block(self, block_id)
+    }
+
+    fn ignore_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        Service::ignore_block(self, block_id)
+    }
+
+    fn fail_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        Service::fail_block(self, block_id)
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        Service::get_blocks(self, block_ids)
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        Service::get_chain_head(self)
+    }
+
+    fn get_settings(
+        &mut self,
+        block_id: BlockId,
+        settings: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        Service::get_settings(self, block_id, settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{self, mock_config};
+    use node::PbftNode;
+
+    /// A backend that isn't a Sawtooth `Service`, which only knows the chain head and the
+    /// network's membership
+    struct MinimalBackend {
+        head: Block,
+        initialized: usize,
+    }
+
+    impl ConsensusService for MinimalBackend {
+        fn send_to(&mut self, _peer: &PeerId, _: &str, _payload: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn broadcast(&mut self, _message_type: &str, _payload: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn initialize_block(&mut self, _previous_id: Option<BlockId>) -> Result<(), Error> {
+            self.initialized += 1;
+            Ok(())
+        }
+        fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+            Err(Error::BlockNotReady)
+        }
+        fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
+            Err(Error::BlockNotReady)
+        }
+        fn cancel_block(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn check_blocks(&mut self, _priority: Vec<BlockId>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn commit_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_blocks(
+            &mut self,
+            _block_ids: Vec<BlockId>,
+        ) -> Result<HashMap<BlockId, Block>, Error> {
+            Ok(HashMap::new())
+        }
+        fn get_chain_head(&mut self) -> Result<Block, Error> {
+            Ok(self.head.clone())
+        }
+        fn get_settings(
+            &mut self,
+            _block_id: BlockId,
+            _settings: Vec<String>,
+        ) -> Result<HashMap<String, String>, Error> {
+            let mut settings = HashMap::new();
+            settings.insert(
+                String::from("sawtooth.consensus.pbft.peers"),
+                String::from(r#"["aa", "bb", "cc", "dd"]"#),
+            );
+            Ok(settings)
+        }
+    }
+
+    /// Make sure that a node can run on a backend that only implements `ConsensusService`, and
+    /// that its settings can be loaded from one
+    #[test]
+    fn minimal_backend() {
+        let head = Block {
+            block_id: BlockId::from(vec![1]),
+            previous_id: BlockId::from(vec![0]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 1,
+            payload: vec![],
+            summary: vec![],
+        };
+        let mut backend = MinimalBackend {
+            head: head.clone(),
+            initialized: 0,
+        };
+        let peers = config::load_peers(head.block_id.clone(), &mut backend).unwrap();
+        assert_eq!(peers.len(), 4);
+        assert_eq!(peers[0], PeerId::from(vec![0xaa]));
+
+        let mut node = PbftNode::new(0, &mock_config(4), Box::new(backend));
+        assert_eq!(node.state.chain_head, head.block_id);
+        assert_eq!(node.service.initialized, 1);
+        node.try_publish().unwrap();
+    }
+}
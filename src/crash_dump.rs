@@ -0,0 +1,278 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Crash dumps of a node's state and message log, written when the engine hits a fatal error
+//!
+//! Panics are dumped too, once `install_panic_hook` has been called: the hook writes the panic's
+//! message and backtrace and the most recent log entries, and if it was the engine's thread that
+//! panicked, the node's state and message log are added to the same dump as the node is dropped
+//! during unwinding.
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use message_log::PbftLog;
+use recent_log::RecentLog;
+use state::PbftState;
+
+thread_local! {
+    // The dump written for the panic this thread is unwinding from, if any
+    static PANIC_DUMP: RefCell<Option<PathBuf>> = RefCell::new(None);
+}
+
+/// Write the node's current state and message log to a new file in `dir`, creating the directory
+/// if necessary. Returns the path of the file that was written.
+pub fn write_crash_dump(
+    dir: &Path,
+    reason: &str,
+    state: &PbftState,
+    msg_log: &PbftLog,
+) -> io::Result<PathBuf> {
+    let path = new_dump_path(dir, &format!("node-{}", state.id))?;
+    let mut file = File::create(&path)?;
+    writeln!(file, "Reason: {}", reason)?;
+    write_dump(&mut file, state, msg_log)?;
+    file.sync_all()?;
+
+    Ok(path)
+}
+
+/// Write a crash dump to `dir` whenever a thread panics, with the panic's message, a backtrace,
+/// and the most recent entries from the given log. The default hook still runs first, so the
+/// panic is reported on standard error as usual.
+pub fn install_panic_hook(dir: PathBuf, recent_log: &'static RecentLog) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let reason = format!(
+            "Thread '{}' {}",
+            thread::current().name().unwrap_or("<unnamed>"),
+            info
+        );
+        // The panic may have come from the logger, so this is written to standard error directly
+        match write_panic_dump(&dir, &reason, &Backtrace::force_capture(), recent_log) {
+            Ok(path) => {
+                eprintln!("Wrote crash dump to {:?}", path);
+                PANIC_DUMP.with(|dump| *dump.borrow_mut() = Some(path));
+            }
+            Err(err) => eprintln!("Couldn't write crash dump to {:?}: {}", dir, err),
+        }
+    }));
+}
+
+/// Add the node's state and message log to the crash dump for the panic this thread is unwinding
+/// from, if one was written; otherwise do nothing
+pub fn add_state_on_panic(state: &PbftState, msg_log: &PbftLog) {
+    if !thread::panicking() {
+        return;
+    }
+    let path = match PANIC_DUMP.with(|dump| dump.borrow_mut().take()) {
+        Some(path) => path,
+        None => return,
+    };
+
+    let res = OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            write_dump(&mut file, state, msg_log)?;
+            file.sync_all()
+        });
+    if let Err(err) = res {
+        eprintln!(
+            "Couldn't add the node's state to crash dump {:?}: {}",
+            path, err
+        );
+    }
+}
+
+fn write_panic_dump(
+    dir: &Path,
+    reason: &str,
+    backtrace: &Backtrace,
+    recent_log: &RecentLog,
+) -> io::Result<PathBuf> {
+    let path = new_dump_path(dir, "panic")?;
+    let mut file = File::create(&path)?;
+
+    writeln!(file, "Reason: {}", reason)?;
+    writeln!(file, "\n== Backtrace ==\n{}", backtrace)?;
+    writeln!(file, "\n== Recent log entries ==")?;
+    for entry in recent_log.recent() {
+        writeln!(file, "{}", entry)?;
+    }
+    file.sync_all()?;
+
+    Ok(path)
+}
+
+// A path for a new crash dump in `dir`, creating the directory if necessary; dumps are named for
+// when they were written, and what by
+fn new_dump_path(dir: &Path, source: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(dir.join(format!(
+        "pbft-crash-{}.{:09}-{}.log",
+        now.as_secs(),
+        now.subsec_nanos(),
+        source
+    )))
+}
+
+fn write_dump<W: Write>(out: &mut W, state: &PbftState, msg_log: &PbftLog) -> io::Result<()> {
+    writeln!(out, "\n== State ==\n{:#?}", state)?;
+
+    writeln!(out, "\n== Views ==")?;
+    for stats in state.view_history.ended() {
+        writeln!(out, "{}", stats)?;
+    }
+    writeln!(out, "{} (current)", state.view_history.current())?;
+
+    writeln!(out, "\n== Peers ==")?;
+    for (id, peer_id) in state.peers().iter().enumerate() {
+        match state.peer_stats.get(peer_id) {
+            Some(stats) => writeln!(out, "Node {:02} ({:?}): {}", id, peer_id, stats)?,
+            None => writeln!(out, "Node {:02} ({:?}): never seen", id, peer_id)?,
+        }
+    }
+
+    writeln!(out, "\n== Log =={}", msg_log)?;
+
+    writeln!(out, "\n== Messages ==")?;
+    for msg in msg_log.messages() {
+        writeln!(out, "{:?}", msg)?;
+    }
+
+    writeln!(out, "\n== View changes ==")?;
+    for vc in msg_log.view_changes() {
+        writeln!(out, "{:?}", vc)?;
+    }
+
+    writeln!(out, "\n== Backlog ==")?;
+    for msg in msg_log.backlog() {
+        writeln!(out, "{} ({} bytes)", msg.message_type, msg.content.len())?;
+    }
+
+    writeln!(out, "\n== Block backlog ==")?;
+    for block in msg_log.block_backlog() {
+        writeln!(out, "{:?}", block)?;
+    }
+
+    writeln!(out, "\n== Equivocations ==")?;
+    for evidence in msg_log.get_equivocations() {
+        writeln!(out, "{:?}", evidence)?;
+    }
+
+    writeln!(out, "\n== Block conflicts ==")?;
+    for evidence in msg_log.get_block_conflicts() {
+        writeln!(out, "{:?}", evidence)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use log::Level;
+    use std::env;
+    use std::io::Read;
+
+    /// Make sure that a crash dump is written to the given directory, and that it contains the
+    /// reason and the node's state
+    #[test]
+    fn crash_dump() {
+        let cfg = mock_config(4);
+        let state = PbftState::new(2, &cfg);
+        let msg_log = PbftLog::new(&cfg);
+
+        let dir = env::temp_dir().join("pbft-crash-dump-test");
+        let path = write_crash_dump(&dir, "Testing crash dumps", &state, &msg_log).unwrap();
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert!(contents.starts_with("Reason: Testing crash dumps"));
+        assert!(contents.contains("== State =="));
+        assert!(contents.contains("view 0: 0 blocks committed, 0 failed proposals (current)"));
+        assert!(contents.contains("== Peers ==\nNode 00 ("));
+        assert!(contents.contains("== Messages =="));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Make sure that a panic's dump has its reason, backtrace, and recent log entries, and that
+    /// the node's state is added to it only while the thread is unwinding from the panic
+    #[test]
+    fn panic_dump() {
+        let cfg = mock_config(4);
+        let dir = env::temp_dir().join("pbft-panic-dump-test");
+        let _ = fs::remove_dir_all(&dir);
+
+        let recent_log = RecentLog::new(Level::Info, 10);
+        let path = write_panic_dump(
+            &dir,
+            "Thread 'main' panicked at 'Testing panic dumps'",
+            &Backtrace::force_capture(),
+            &recent_log,
+        )
+        .unwrap();
+
+        // Without a panic, the state isn't added
+        PANIC_DUMP.with(|dump| *dump.borrow_mut() = Some(path.clone()));
+        add_state_on_panic(&PbftState::new(2, &cfg), &PbftLog::new(&cfg));
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("Reason: Thread 'main' panicked at 'Testing panic dumps'"));
+        assert!(contents.contains("== Backtrace =="));
+        assert!(contents.contains("== Recent log entries =="));
+        assert!(!contents.contains("== State =="));
+
+        // A node dropped while its thread unwinds adds its state
+        struct Node(PbftState, PbftLog);
+        impl Drop for Node {
+            fn drop(&mut self) {
+                add_state_on_panic(&self.0, &self.1);
+            }
+        }
+        let unwinding_path = path.clone();
+        let res = thread::spawn(move || {
+            PANIC_DUMP.with(|dump| *dump.borrow_mut() = Some(unwinding_path));
+            let _node = Node(PbftState::new(2, &cfg), PbftLog::new(&cfg));
+            panic!("Testing panic dumps");
+        })
+        .join();
+        assert!(res.is_err());
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("== State =="));
+        assert!(contents.contains("== Messages =="));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Checking that a node is ready to start, before it's started
+//!
+//! `sawtooth-pbft doctor` checks what would otherwise only show up in the engine's log once it's
+//! running: that the validator can be reached, that the state directory can be written to and
+//! what's saved there can be read, that the signing key can be loaded, that the on-chain members
+//! setting parses and includes this node, and that the timeouts work together. Every check is
+//! run, rather than stopping at the first failure, and the results are printed as a report. A
+//! check that depends on one that failed (or on an option that wasn't given) is skipped.
+//!
+//! To read the on-chain settings, the doctor registers with the validator as the consensus
+//! engine, so it should be run while the engine itself isn't.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use hex;
+
+use sawtooth_sdk::consensus::engine::{Engine, PeerId, StartupState, Update};
+use sawtooth_sdk::consensus::service::Service;
+use sawtooth_sdk::consensus::zmq_driver::{Stop, ZmqDriver};
+
+use authentication::MessageSigner;
+use config::{self, PbftConfig};
+use error::PbftError;
+use storage::ViewChangeStorage;
+
+/// How long to wait to connect to the validator
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the validator to accept the doctor as its engine and answer it
+const VALIDATOR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The name of the file written to a directory to check that it can be written to
+const PROBE_FILE: &str = ".pbft-doctor-probe";
+
+/// How a check turned out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outcome = match self {
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        };
+        write!(f, "{}", outcome)
+    }
+}
+
+/// The result of one check, with what was found
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+/// The results of all of the checks, in the order they were run
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Whether no check failed
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.outcome != Outcome::Fail)
+    }
+
+    fn add(&mut self, name: &'static str, outcome: Outcome, detail: String) {
+        self.checks.push(Check {
+            name,
+            outcome,
+            detail,
+        });
+    }
+
+    // Add a check that passed or failed, depending on the result
+    fn add_result(&mut self, name: &'static str, result: Result<String, String>) {
+        match result {
+            Ok(detail) => self.add(name, Outcome::Pass, detail),
+            Err(detail) => self.add(name, Outcome::Fail, detail),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{}] {}: {}", check.outcome, check.name, check.detail)?;
+        }
+        let count = |outcome| {
+            self.checks
+                .iter()
+                .filter(|check| check.outcome == outcome)
+                .count()
+        };
+        writeln!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            count(Outcome::Pass),
+            count(Outcome::Fail),
+            count(Outcome::Skip)
+        )
+    }
+}
+
+/// Run every check, against the validator at the given endpoint and the directories and key file
+/// the engine would be started with
+pub fn run(
+    endpoint: &str,
+    state_dir: Option<&Path>,
+    crash_dump_dir: Option<&Path>,
+    signing_key: Option<&Path>,
+) -> Report {
+    let mut report = Report::default();
+
+    match state_dir {
+        Some(dir) => report.add_result("state directory", check_state_dir(dir)),
+        None => report.add("state directory", Outcome::Skip, no_option("state_dir")),
+    }
+    match crash_dump_dir {
+        Some(dir) => report.add_result("crash dump directory", check_writable(dir)),
+        None => report.add(
+            "crash dump directory",
+            Outcome::Skip,
+            no_option("crash_dump_dir"),
+        ),
+    }
+    let signer = signing_key.map(MessageSigner::load);
+    match signer {
+        Some(Ok(ref signer)) => report.add(
+            "signing key",
+            Outcome::Pass,
+            format!("public key {}", display_peer_id(&signer.get_signer_id())),
+        ),
+        Some(Err(ref err)) => report.add("signing key", Outcome::Fail, err.to_string()),
+        None => report.add("signing key", Outcome::Skip, no_option("signing_key")),
+    }
+
+    let validator = check_endpoint(endpoint).and_then(|detail| {
+        query_validator(endpoint).map(|(peer_id, config)| (detail, peer_id, config))
+    });
+    let (peer_id, config) = match validator {
+        Ok((detail, peer_id, config)) => {
+            report.add("validator", Outcome::Pass, detail);
+            (peer_id, config)
+        }
+        Err(detail) => {
+            report.add("validator", Outcome::Fail, detail);
+            for name in &["members", "membership", "timeouts"] {
+                report.add(name, Outcome::Skip, String::from("needs the validator"));
+            }
+            return report;
+        }
+    };
+
+    let config = match config {
+        Ok(config) => {
+            report.add(
+                "members",
+                Outcome::Pass,
+                format!("{} members", config.peers.len()),
+            );
+            config
+        }
+        Err(err) => {
+            report.add("members", Outcome::Fail, err.to_string());
+            for name in &["membership", "timeouts"] {
+                report.add(
+                    name,
+                    Outcome::Skip,
+                    String::from("needs the on-chain settings"),
+                );
+            }
+            return report;
+        }
+    };
+
+    report.add_result(
+        "membership",
+        check_membership(&config, &peer_id, signer.and_then(Result::ok)),
+    );
+    report.add_result(
+        "timeouts",
+        config::check_timeouts(&config)
+            .map(|_| {
+                format!(
+                    "block duration {:?}, view change timeout {:?}",
+                    config.block_duration, config.view_change_timeout
+                )
+            })
+            .map_err(|err| err.to_string()),
+    );
+    report.add_result(
+        "checkpoints",
+        config::check_checkpoint_period(&config)
+            .map(|_| {
+                format!(
+                    "checkpoint period {}, max log size {}",
+                    config.checkpoint_period, config.max_log_size
+                )
+            })
+            .map_err(|err| err.to_string()),
+    );
+
+    report
+}
+
+fn no_option(option: &str) -> String {
+    format!("--{} not given", option)
+}
+
+fn display_peer_id(peer_id: &PeerId) -> String {
+    hex::encode(Vec::<u8>::from(peer_id.clone()))
+}
+
+// Check that the state directory can be written to, and that the progress saved there (if any)
+// can be read
+fn check_state_dir(dir: &Path) -> Result<String, String> {
+    check_writable(dir)?;
+    let mut storage = ViewChangeStorage::new(dir).map_err(|err| err.to_string())?;
+    match storage.load() {
+        Ok(Some(progress)) => Ok(format!(
+            "writable; saved progress is in view {}",
+            progress.get_view()
+        )),
+        Ok(None) => Ok(String::from("writable; no progress saved yet")),
+        Err(err) => Err(format!("saved progress can't be read: {}", err)),
+    }
+}
+
+// Check that a file can be created in the directory, creating the directory if necessary
+fn check_writable(dir: &Path) -> Result<String, String> {
+    let probe = dir.join(PROBE_FILE);
+    fs::create_dir_all(dir)
+        .and_then(|_| File::create(&probe))
+        .and_then(|mut file| file.write_all(b"ok"))
+        .and_then(|_| fs::remove_file(&probe))
+        .map(|_| String::from("writable"))
+        .map_err(|err| format!("{:?} isn't writable: {}", dir, err))
+}
+
+// Check that something is listening at the endpoint, such as `tcp://localhost:5050`
+fn check_endpoint(endpoint: &str) -> Result<String, String> {
+    let address = endpoint.trim_start_matches("tcp://");
+    let addrs = address
+        .to_socket_addrs()
+        .map_err(|err| format!("{} isn't a valid endpoint: {}", endpoint, err))?;
+
+    let mut last_err = format!("{} didn't resolve to any address", endpoint);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(_) => return Ok(format!("{} is reachable", endpoint)),
+            Err(err) => last_err = format!("{} isn't reachable: {}", endpoint, err),
+        }
+    }
+    Err(last_err)
+}
+
+// Check that this node is one of the members, and that its signing key (if any) is its own
+fn check_membership(
+    config: &PbftConfig,
+    peer_id: &PeerId,
+    signer: Option<MessageSigner>,
+) -> Result<String, String> {
+    let position = config
+        .peers
+        .iter()
+        .position(|member| member == peer_id)
+        .ok_or_else(|| {
+            format!(
+                "this node ({}) isn't in sawtooth.consensus.pbft.peers",
+                display_peer_id(peer_id)
+            )
+        })?;
+
+    if let Some(signer) = signer {
+        if &signer.get_signer_id() != peer_id {
+            return Err(format!(
+                "the signing key is for {}, not this node ({})",
+                display_peer_id(&signer.get_signer_id()),
+                display_peer_id(peer_id)
+            ));
+        }
+    }
+
+    Ok(format!(
+        "this node ({}) is member {}",
+        display_peer_id(peer_id),
+        position
+    ))
+}
+
+/// What the doctor learns from the validator: this node's ID, and the on-chain settings
+type ValidatorAnswer = (PeerId, Result<PbftConfig, PbftError>);
+
+// Register with the validator as its consensus engine, and read this node's ID and the on-chain
+// settings as of the chain head
+fn query_validator(endpoint: &str) -> Result<ValidatorAnswer, String> {
+    let (sender, receiver) = mpsc::channel();
+    let (driver, stop) = ZmqDriver::new();
+    let engine = DoctorEngine {
+        answers: sender,
+        stop,
+    };
+
+    let endpoint = endpoint.to_string();
+    thread::spawn(move || {
+        if let Err(err) = driver.start(&endpoint, engine) {
+            error!("{}", err);
+        }
+    });
+
+    wait_for_answer(&receiver)
+}
+
+fn wait_for_answer(receiver: &Receiver<ValidatorAnswer>) -> Result<ValidatorAnswer, String> {
+    receiver.recv_timeout(VALIDATOR_TIMEOUT).map_err(|_| {
+        format!(
+            "the validator didn't accept this engine within {:?}; is another consensus engine \
+             registered, or sawtooth.consensus.algorithm.name set to something else?",
+            VALIDATOR_TIMEOUT
+        )
+    })
+}
+
+/// An engine that reads what the doctor needs from the validator, then stops
+struct DoctorEngine {
+    answers: Sender<ValidatorAnswer>,
+    stop: Stop,
+}
+
+impl Engine for DoctorEngine {
+    fn start(
+        &mut self,
+        _updates: Receiver<Update>,
+        mut service: Box<Service>,
+        startup_state: StartupState,
+    ) {
+        let config = config::read_pbft_config(startup_state.chain_head.block_id, &mut *service);
+        let _ = self
+            .answers
+            .send((startup_state.local_peer_info.peer_id, config));
+        self.stop.stop();
+    }
+
+    fn version(&self) -> String {
+        String::from(env!("CARGO_PKG_VERSION"))
+    }
+
+    fn name(&self) -> String {
+        String::from(env!("CARGO_PKG_NAME"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use std::env;
+
+    /// Make sure that the local checks catch an unreadable state directory, an unreachable
+    /// validator, and a node that isn't a member, and that the report fails if any check does
+    #[test]
+    fn doctor() {
+        let dir = env::temp_dir().join("pbft-doctor-test");
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            check_state_dir(&dir),
+            Ok(String::from("writable; no progress saved yet"))
+        );
+        fs::write(dir.join("pbft-view-change-progress"), b"\xff\xff\xff").unwrap();
+        assert!(check_state_dir(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(check_endpoint("tcp://localhost:not-a-port").is_err());
+
+        let config = mock_config(4);
+        assert!(check_membership(&config, &config.peers[2], None).is_ok());
+        assert!(check_membership(&config, &PeerId::from(vec![9]), None).is_err());
+
+        let mut report = Report::default();
+        report.add_result("first", Ok(String::from("fine")));
+        report.add("second", Outcome::Skip, String::from("not needed"));
+        assert!(report.passed());
+        report.add_result("third", Err(String::from("broken")));
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "[PASS] first: fine\n[SKIP] second: not needed\n[FAIL] third: broken\n\
+             1 passed, 1 failed, 1 skipped\n"
+        );
+    }
+}
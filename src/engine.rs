@@ -17,23 +17,331 @@
 
 //! Entry point for the consensus algorithm, including the main event loop
 
-use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use sawtooth_sdk::consensus::{engine::*, service::Service};
+use tracing;
 
 use node::PbftNode;
 
+use admin::AdminServer;
+use alerts::{AlertConfig, AlertMonitor};
+use authentication::MessageSigner;
+use circuit_breaker::{BreakerService, CircuitBreaker};
 use config;
+use consensus_service::ConsensusService;
+use crash_dump;
+use metrics::{InfluxReporter, Metrics, MetricsServer};
+use otlp::OtlpExporter;
+use pipeline::DecodePool;
+use retry::{Retry, RetryStrategy};
+use status::StateSummary;
+use storage::ViewChangeStorage;
 use timing;
+use traced_service::TracedService;
+use update_queue::UpdateQueue;
+use view_stats::ViewChangeReason;
+use watchdog::Watchdog;
 
 use error::PbftError;
+use events::{ConsensusEvent, EventPublisher};
+#[cfg(feature = "test-faults")]
+use faults::{FaultInjector, FaultScenario};
+use log_throttle::LogThrottle;
+
+/// Where the validator's private key is, if no other signing key is given
+const DEFAULT_SIGNING_KEY: &str = "/etc/sawtooth/keys/validator.priv";
+
+/// How often metrics are sent to InfluxDB or an OTLP receiver, if one was given
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a summary of the node's state is logged, unless another interval is given
+const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the event loop can go without making progress before the watchdog reports it, unless
+/// another interval is given
+const DEFAULT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most peer messages that are decoded together, so a flood of them can't keep the node from
+/// handling other updates
+const MAX_DECODE_BATCH: usize = 64;
 
 #[derive(Default)]
-pub struct PbftEngine {}
+pub struct PbftEngine {
+    /// Where to write crash dumps when the engine hits a fatal error (no dumps if `None`)
+    crash_dump_dir: Option<PathBuf>,
+
+    /// The private key to sign messages with, if message authentication is enabled (the
+    /// validator's key if `None`)
+    signing_key: Option<PathBuf>,
+
+    /// Where to save the node's view change progress, so it can resume after a restart (not saved
+    /// if `None`)
+    state_dir: Option<PathBuf>,
+
+    /// Where to serve the node's metrics (not served if `None`)
+    metrics_address: Option<SocketAddr>,
+
+    /// Where to take operators' commands, and the file with the token they need (no commands if
+    /// `None`)
+    admin: Option<(SocketAddr, PathBuf)>,
+
+    /// Where to take operators' commands over gRPC, and the file with the token they need (not
+    /// taken over gRPC if `None`)
+    #[cfg(feature = "grpc-admin")]
+    admin_grpc: Option<(SocketAddr, PathBuf)>,
+
+    /// Where to publish consensus events (not published if `None`)
+    events_socket: Option<PathBuf>,
+
+    /// Where to send consensus events in the embedding process (not sent if `None`)
+    event_sender: Option<Sender<ConsensusEvent>>,
+
+    /// Where to send the node's metrics every `METRICS_REPORT_INTERVAL` (not sent if `None`)
+    influx_reporter: Option<InfluxReporter>,
+
+    /// Where to send the node's metrics and spans every `METRICS_REPORT_INTERVAL` with OTLP (not
+    /// sent if `None`)
+    otlp_exporter: Option<OtlpExporter>,
+
+    /// A file describing which consensus anomalies to alert on, and how (no alerts if `None`)
+    alerts: Option<PathBuf>,
+
+    /// How often to log a summary of the node's state (not logged if `None`)
+    summary_interval: Option<Duration>,
+
+    /// How long the event loop can go without making progress before the watchdog reports it
+    /// (not watched if `None`)
+    watchdog_interval: Option<Duration>,
+
+    /// Whether the watchdog aborts the process when it reports the event loop
+    watchdog_abort: bool,
+
+    /// How many threads decode and verify peer messages (they're decoded on the event loop if
+    /// 0)
+    decode_threads: usize,
+
+    /// Set by an `EngineHandle` to shut the engine down, if it was started with `spawn`
+    shutdown: Option<Arc<AtomicBool>>,
+
+    /// The network this engine runs consensus for, if its process runs more than one (shown in
+    /// logs and thread names)
+    network: Option<String>,
+
+    /// A file describing faults for the node to exhibit
+    #[cfg(feature = "test-faults")]
+    fault_scenario: Option<PathBuf>,
+}
 
 impl PbftEngine {
-    pub fn new() -> Self {
-        PbftEngine {}
+    pub fn new(crash_dump_dir: Option<PathBuf>, signing_key: Option<PathBuf>) -> Self {
+        PbftEngine {
+            crash_dump_dir,
+            signing_key,
+            state_dir: None,
+            metrics_address: None,
+            admin: None,
+            #[cfg(feature = "grpc-admin")]
+            admin_grpc: None,
+            influx_reporter: None,
+            otlp_exporter: None,
+            events_socket: None,
+            event_sender: None,
+            alerts: None,
+            summary_interval: Some(DEFAULT_SUMMARY_INTERVAL),
+            watchdog_interval: Some(DEFAULT_WATCHDOG_INTERVAL),
+            watchdog_abort: false,
+            decode_threads: 0,
+            shutdown: None,
+            network: None,
+            #[cfg(feature = "test-faults")]
+            fault_scenario: None,
+        }
+    }
+
+    /// Save the node's view change progress in the given directory
+    pub fn with_state_dir(mut self, state_dir: PathBuf) -> Self {
+        self.state_dir = Some(state_dir);
+        self
+    }
+
+    /// Serve the node's metrics over HTTP at the given address
+    pub fn with_metrics_address(mut self, metrics_address: SocketAddr) -> Self {
+        self.metrics_address = Some(metrics_address);
+        self
+    }
+
+    /// Publish consensus events on a Unix socket at the given path
+    pub fn with_events_socket(mut self, events_socket: PathBuf) -> Self {
+        self.events_socket = Some(events_socket);
+        self
+    }
+
+    /// Send consensus events on the given channel, for the process the engine is embedded in; the
+    /// channel is dropped once its receiver hangs up
+    pub fn with_event_sender(mut self, event_sender: Sender<ConsensusEvent>) -> Self {
+        self.event_sender = Some(event_sender);
+        self
+    }
+
+    /// Send the node's metrics to the given InfluxDB server (`host:port`) and database
+    pub fn with_influx_reporter(mut self, address: String, database: String) -> Self {
+        self.influx_reporter = Some(InfluxReporter::new(address, database));
+        self
+    }
+
+    /// Send the node's metrics, and the spans recorded by the exporter's layer, with OTLP
+    pub fn with_otlp_exporter(mut self, exporter: OtlpExporter) -> Self {
+        self.otlp_exporter = Some(exporter);
+        self
+    }
+
+    /// Log a summary of the node's state at the given interval, or never if it's `None`
+    pub fn with_summary_interval(mut self, summary_interval: Option<Duration>) -> Self {
+        self.summary_interval = summary_interval;
+        self
+    }
+
+    /// Watch for the event loop going longer than the given interval without making progress (or
+    /// don't watch it, if `None`)
+    pub fn with_watchdog_interval(mut self, watchdog_interval: Option<Duration>) -> Self {
+        self.watchdog_interval = watchdog_interval;
+        self
+    }
+
+    /// Abort the process when the watchdog reports the event loop, so a supervisor can restart
+    /// the node
+    pub fn with_watchdog_abort(mut self) -> Self {
+        self.watchdog_abort = true;
+        self
+    }
+
+    /// Decode and verify peer messages on the given number of threads, so the event loop only has
+    /// to apply them; the signatures of the votes in a seal or `NewView` are checked on as many
+    pub fn with_decode_threads(mut self, decode_threads: usize) -> Self {
+        self.decode_threads = decode_threads;
+        self
+    }
+
+    /// Take operators' commands on the given address, from clients with the token in the given
+    /// file
+    pub fn with_admin(mut self, admin_address: SocketAddr, token_file: PathBuf) -> Self {
+        self.admin = Some((admin_address, token_file));
+        self
+    }
+
+    /// Take operators' commands over gRPC on the given address, from clients with the token in
+    /// the given file; if commands are taken over HTTP as well, both need the same token
+    #[cfg(feature = "grpc-admin")]
+    pub fn with_admin_grpc(mut self, admin_address: SocketAddr, token_file: PathBuf) -> Self {
+        self.admin_grpc = Some((admin_address, token_file));
+        self
+    }
+
+    /// Send alerts on the consensus anomalies described in the given alerts file
+    pub fn with_alerts(mut self, alerts: PathBuf) -> Self {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    /// Name the network this engine runs consensus for, to tell its logs apart from those of the
+    /// other networks in the same process
+    pub fn with_network(mut self, network: String) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Make the node misbehave as described in the given fault scenario file
+    #[cfg(feature = "test-faults")]
+    pub fn with_fault_scenario(mut self, fault_scenario: PathBuf) -> Self {
+        self.fault_scenario = Some(fault_scenario);
+        self
+    }
+
+    /// Run the engine on a thread of its own, embedded in another process rather than connected to
+    /// a validator by the ZMQ driver. The embedding process takes the driver's place: it sends the
+    /// validator's updates on the channel that `updates` comes from, `service` makes the engine's
+    /// calls to the validator, and `startup_state` is what the validator reported when the engine
+    /// registered with it. The engine runs until it gets `Update::Shutdown`, the channel is
+    /// closed, or the returned handle shuts it down.
+    pub fn spawn(
+        mut self,
+        updates: Receiver<Update>,
+        service: Box<Service + Send>,
+        startup_state: StartupState,
+    ) -> io::Result<EngineHandle> {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        self.shutdown = Some(Arc::clone(&shutdown));
+        let name = self.network.as_ref().map_or_else(
+            || String::from("pbft-engine"),
+            |id| format!("pbft-engine-{}", id),
+        );
+        let thread = thread::Builder::new()
+            .name(name)
+            .spawn(move || self.start(updates, service, startup_state))?;
+        Ok(EngineHandle {
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    // Whether the process the engine is embedded in asked it to shut down
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .map_or(false, |shutdown| shutdown.load(Ordering::SeqCst))
+    }
+
+    // Start taking operators' commands wherever they were asked for: over HTTP, over gRPC, or
+    // both, with the same token
+    fn admin_server(&self) -> Result<Option<AdminServer>, String> {
+        #[cfg(feature = "grpc-admin")]
+        let grpc = self.admin_grpc.as_ref();
+        #[cfg(not(feature = "grpc-admin"))]
+        let grpc: Option<&(SocketAddr, PathBuf)> = None;
+
+        let token_file = match self.admin.as_ref().or(grpc) {
+            Some(&(_, ref token_file)) => token_file,
+            None => return Ok(None),
+        };
+        let mut server = AdminServer::new(token_file, self.crash_dump_dir.clone())
+            .map_err(|err| format!("Couldn't take admin commands: {}", err))?;
+
+        if let Some(&(ref addr, _)) = self.admin.as_ref() {
+            server
+                .listen(addr)
+                .map_err(|err| format!("Couldn't take admin commands on {}: {}", addr, err))?;
+        }
+        #[cfg(feature = "grpc-admin")]
+        {
+            if let Some(&(ref addr, _)) = grpc {
+                server
+                    .listen_grpc(addr)
+                    .map_err(|err| format!("Couldn't take admin calls on {}: {}", addr, err))?;
+            }
+        }
+
+        Ok(Some(server))
+    }
+
+    // Write the node's state and log to the crash dump directory, if one was configured
+    fn dump_on_fatal_error(&self, node: &PbftNode, reason: &str) {
+        if let Some(ref dir) = self.crash_dump_dir {
+            match crash_dump::write_crash_dump(dir, reason, &node.state, &node.msg_log) {
+                Ok(path) => error!("Wrote crash dump to {:?}", path),
+                Err(err) => error!("Couldn't write crash dump to {:?}: {}", dir, err),
+            }
+        }
     }
 }
 
@@ -50,66 +358,365 @@ impl Engine for PbftEngine {
             local_peer_info,
         } = startup_state;
 
-        // Load on-chain settings
-        let config = config::load_pbft_config(chain_head.block_id, &mut *service);
+        // Measures how far behind the validator's updates this loop is
+        let updates = UpdateQueue::new(updates);
+
+        // Updates that arrived while waiting to retry loading the settings, with how long each
+        // was queued; they're handled first once the node is running
+        let mut held_updates = VecDeque::new();
+        let mut shutting_down = false;
 
-        let node_id = config
+        // Load on-chain settings, retrying if the validator couldn't be asked for them, since that
+        // may pass; without usable ones, this node can't take part, so it shuts down instead of
+        // panicking
+        let config = match startup_retry().run(
+            || config::load_pbft_config(chain_head.block_id.clone(), &mut *service),
+            |err, delay| match err.kind() {
+                PbftError::ServiceError(_, _) => {
+                    warn!(
+                        "Couldn't load on-chain settings; retrying in {:?}: {}",
+                        delay, err
+                    );
+                    shutting_down = !wait_for_retry(&updates, delay, &mut held_updates)
+                        || self.shutdown_requested();
+                    !shutting_down
+                }
+                _ => false,
+            },
+        ) {
+            Ok(config) => config,
+            Err(_) if shutting_down => {
+                info!("Shut down while loading on-chain settings");
+                return;
+            }
+            Err(err) => {
+                error!("Couldn't load on-chain settings; shutting down: {}", err);
+                return;
+            }
+        };
+
+        let mut working_ticker = timing::Ticker::new(config.block_duration);
+        let mut backlog_ticker = timing::Ticker::new(config.message_timeout);
+        let mut probe_ticker = config.probe_interval.map(timing::Ticker::new);
+        let mut heartbeat_ticker = config.heartbeat_interval.map(timing::Ticker::new);
+        let mut clock_sync_ticker = config.clock_sync_interval.map(timing::Ticker::new);
+        let mut metrics_report_ticker = timing::Ticker::new(METRICS_REPORT_INTERVAL);
+        let mut summary_ticker = self.summary_interval.map(timing::Ticker::new);
+
+        // Stop calling the validator for a while if it keeps failing, and report it in the status
+        let breaker = Rc::new(RefCell::new(CircuitBreaker::new()));
+        let service = BreakerService::new(service, Rc::clone(&breaker));
+        let service: Box<ConsensusService> = Box::new(TracedService::new(Box::new(service)));
+
+        // A node that isn't a member follows the network without voting, until it's added
+        let mut node: PbftNode = match config
             .peers
             .iter()
             .position(|ref id| id == &&local_peer_info.peer_id)
-            .expect("This node is not in the peers list, which is necessary")
-            as u64;
+        {
+            Some(node_id) => PbftNode::new(node_id as u64, &config, service),
+            None => match PbftNode::new_observer(local_peer_info.peer_id.clone(), &config, service)
+            {
+                Ok(node) => {
+                    warn!(
+                        "This node is not in the peers list; following the network without \
+                         voting until it's added"
+                    );
+                    node
+                }
+                Err(err) => {
+                    error!(
+                        "This node is not in the peers list, and can't follow the network; \
+                         shutting down: {}",
+                        err
+                    );
+                    return;
+                }
+            },
+        };
+        node.state.service_breaker = breaker;
+        node.state.network = self.network.clone();
+        node.state.verify_threads = self.decode_threads;
 
-        let mut working_ticker = timing::Ticker::new(config.block_duration);
-        let mut backlog_ticker = timing::Ticker::new(config.message_timeout);
+        // Sign messages with the validator's key, since peer IDs are validator public keys
+        if config.authenticate_messages {
+            let path = self
+                .signing_key
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_SIGNING_KEY));
+            let signer = match MessageSigner::load(&path) {
+                Ok(signer) => signer,
+                Err(err) => {
+                    error!("Couldn't load signing key; shutting down: {}", err);
+                    return;
+                }
+            };
+            if signer.get_signer_id() != local_peer_info.peer_id {
+                error!(
+                    "The signing key {:?} doesn't belong to this validator; shutting down",
+                    path
+                );
+                return;
+            }
+            node.signer = Some(signer);
+        }
+
+        #[cfg(feature = "test-faults")]
+        {
+            if let Some(ref path) = self.fault_scenario {
+                match FaultScenario::load(path) {
+                    Ok(scenario) => node.faults = Some(FaultInjector::new(scenario)),
+                    Err(err) => {
+                        error!("Couldn't load fault scenario; shutting down: {}", err);
+                        return;
+                    }
+                }
+            }
+        }
+
+        if let Some(ref dir) = self.state_dir {
+            let storage = match ViewChangeStorage::new(dir) {
+                Ok(storage) => storage,
+                Err(err) => {
+                    error!(
+                        "Couldn't use state directory {:?}; shutting down: {}",
+                        dir, err
+                    );
+                    return;
+                }
+            };
+            node.storage = Some(storage);
+            handle_pbft_result(node.restore_view_change_progress(), &mut node.state.metrics);
+        }
+
+        let mut metrics_server = match self.metrics_address {
+            Some(addr) => match MetricsServer::bind(&addr) {
+                Ok(server) => Some(server),
+                Err(err) => {
+                    error!("Couldn't serve metrics on {}; shutting down: {}", addr, err);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let admin_server = match self.admin_server() {
+            Ok(server) => server,
+            Err(err) => {
+                error!("{}; shutting down", err);
+                return;
+            }
+        };
+
+        let mut event_publisher = match self.events_socket {
+            Some(ref path) => match EventPublisher::bind(path) {
+                Ok(publisher) => Some(publisher),
+                Err(err) => {
+                    error!(
+                        "Couldn't publish events on {:?}; shutting down: {}",
+                        path, err
+                    );
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let mut alert_monitor = match self.alerts {
+            Some(ref path) => match AlertConfig::load(path) {
+                Ok(config) => Some(AlertMonitor::new(config)),
+                Err(err) => {
+                    error!("Couldn't load alerts; shutting down: {}", err);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let mut state_summary = StateSummary::new(&node.state);
 
-        let mut node = PbftNode::new(node_id, &config, service);
+        // A faulty node can cause the same error for every message it sends
+        let mut peer_error_throttle = LogThrottle::new();
 
         debug!("Starting state: {:#?}", node.state);
 
+        // Reports the event loop if it stops going around, since the loop can't report itself
+        let watchdog = self.watchdog_interval.and_then(|interval| {
+            Watchdog::start(interval, self.watchdog_abort)
+                .map_err(|err| error!("Couldn't start watchdog: {}", err))
+                .ok()
+        });
+
+        // Decodes batches of peer messages in parallel, while the loop applies them in order
+        let decode_pool = if self.decode_threads > 0 {
+            DecodePool::new(self.decode_threads)
+                .map_err(|err| error!("Couldn't start decode threads: {}", err))
+                .ok()
+        } else {
+            None
+        };
+
         // Event loop. Keep going until we receive a shutdown message.
         loop {
-            let incoming_message = updates.recv_timeout(config.message_timeout);
+            if let Some(stall) = watchdog.as_ref().and_then(Watchdog::feed) {
+                node.state.metrics.record_engine_stall(stall);
+            }
+
+            if self.shutdown_requested() {
+                info!("Shutting down, as the embedding process asked");
+                handle_pbft_result(node.hand_off(), &mut node.state.metrics);
+                break;
+            }
+
+            let incoming_message = match held_updates.pop_front() {
+                Some(held) => Ok(held),
+                None => updates.recv_timeout(config.message_timeout),
+            };
+            node.state.metrics.record_update_queue(
+                updates.depth(),
+                incoming_message.as_ref().ok().map(|&(_, queued)| queued),
+            );
+            let incoming_message = incoming_message.map(|(update, _)| update);
+
+            // Everything done for this update, including the periodic work below, is in its span
+            let _span = tracing::debug_span!("update").entered();
 
             let res = match incoming_message {
                 Ok(Update::BlockNew(block)) => node.on_block_new(block),
                 Ok(Update::BlockValid(block_id)) => node.on_block_valid(block_id),
-                Ok(Update::BlockInvalid(_)) => {
-                    warn!(
-                        "{}: BlockInvalid received, starting view change",
-                        node.state
-                    );
-                    node.start_view_change()
-                }
+                Ok(Update::BlockInvalid(block_id)) => node.on_block_invalid(block_id),
                 Ok(Update::BlockCommit(block_id)) => node.on_block_commit(block_id),
-                Ok(Update::PeerMessage(message, _sender_id)) => node.on_peer_message(&message),
-                Ok(Update::Shutdown) => break,
-                Ok(Update::PeerConnected(_)) | Ok(Update::PeerDisconnected(_)) => {
-                    error!("PBFT currently only supports static networks");
+                Ok(Update::PeerMessage(message, sender_id)) => {
+                    match decode_pool {
+                        Some(ref pool) => {
+                            let batch = take_peer_messages(
+                                (message, sender_id),
+                                &updates,
+                                &mut held_updates,
+                                &mut node.state.metrics,
+                            );
+                            for (sender_id, res) in node.on_network_messages(batch, pool) {
+                                handle_peer_message_result(
+                                    res,
+                                    &sender_id,
+                                    &mut peer_error_throttle,
+                                    &mut node.state.metrics,
+                                );
+                            }
+                        }
+                        None => {
+                            let res = node.on_network_message(&message, &sender_id);
+                            handle_peer_message_result(
+                                res,
+                                &sender_id,
+                                &mut peer_error_throttle,
+                                &mut node.state.metrics,
+                            );
+                        }
+                    }
                     Ok(())
                 }
+                Ok(Update::Shutdown) => {
+                    handle_pbft_result(node.hand_off(), &mut node.state.metrics);
+                    break;
+                }
+                Ok(Update::PeerConnected(info)) => node.on_peer_connected(info.peer_id),
+                Ok(Update::PeerDisconnected(peer_id)) => node.on_peer_disconnected(peer_id),
                 Err(RecvTimeoutError::Timeout) => Err(PbftError::Timeout),
                 Err(RecvTimeoutError::Disconnected) => {
                     error!("Disconnected from validator");
+                    self.dump_on_fatal_error(&node, "Disconnected from validator");
                     break;
                 }
             };
-            handle_pbft_result(res);
+            handle_pbft_result(res, &mut node.state.metrics);
+            handle_pbft_result(node.try_publish_early(), &mut node.state.metrics);
+            node.save_view_change_progress();
 
-            working_ticker.tick(|| {
-                if let Err(e) = node.try_publish() {
-                    error!("{}", e);
+            #[cfg(feature = "test-faults")]
+            node.send_delayed_messages();
+
+            if let Some(ref mut server) = metrics_server {
+                server.update(&node.state, &node.msg_log);
+            }
+            if let Some(ref server) = admin_server {
+                server.poll(&mut node);
+            }
+            if let Some(ref mut publisher) = event_publisher {
+                publisher.publish(&node.state.events);
+            }
+            let receiver_gone = self.event_sender.as_ref().map_or(false, |sender| {
+                node.state
+                    .events
+                    .iter()
+                    .any(|event| sender.send(event.clone()).is_err())
+            });
+            if receiver_gone {
+                debug!("Event receiver hung up; not sending events anymore");
+                self.event_sender = None;
+            }
+            if let Some(ref mut monitor) = alert_monitor {
+                monitor.check(&node.state, &node.msg_log);
+            }
+            node.state.events.clear();
+            metrics_report_ticker.tick(|| {
+                if let Some(ref reporter) = self.influx_reporter {
+                    reporter.report(&node.state, &node.msg_log);
                 }
+                if let Some(ref exporter) = self.otlp_exporter {
+                    exporter.export(&node.state, &node.msg_log);
+                }
+            });
+
+            working_ticker.tick(|| {
+                handle_pbft_result(node.try_publish(), &mut node.state.metrics);
+
+                // Give a block that's almost out of time one more chance before giving up on it
+                handle_pbft_result(node.rebroadcast_if_stalled(), &mut node.state.metrics);
 
                 // Every so often, check to see if timeout has expired; initiate ViewChange if necessary
                 if node.check_timeout_expired() {
-                    handle_pbft_result(node.start_view_change());
+                    handle_pbft_result(
+                        node.start_view_change(ViewChangeReason::CommitTimeout),
+                        &mut node.state.metrics,
+                    );
                 }
+
+                // Make sure the validator didn't move on (or back) without this node
+                handle_pbft_result(node.check_chain_head(), &mut node.state.metrics);
+
+                // Let an operator know if the node is stuck, since it can't get itself going
+                node.check_progress();
             });
 
             backlog_ticker.tick(|| {
-                handle_pbft_result(node.retry_backlog());
-            })
+                handle_pbft_result(node.retry_backlog(), &mut node.state.metrics);
+                handle_pbft_result(node.retransmit(), &mut node.state.metrics);
+                handle_pbft_result(node.continue_recovery(), &mut node.state.metrics);
+                handle_pbft_result(node.check_fast_path_timeout(), &mut node.state.metrics);
+            });
+
+            if let Some(ref mut ticker) = probe_ticker {
+                ticker.tick(|| {
+                    handle_pbft_result(node.probe_primary(), &mut node.state.metrics);
+                })
+            }
+
+            if let Some(ref mut ticker) = heartbeat_ticker {
+                ticker.tick(|| {
+                    handle_pbft_result(node.heartbeat(), &mut node.state.metrics);
+                })
+            }
+
+            if let Some(ref mut ticker) = clock_sync_ticker {
+                ticker.tick(|| {
+                    handle_pbft_result(node.sync_clocks(), &mut node.state.metrics);
+                })
+            }
+
+            if let Some(ref mut ticker) = summary_ticker {
+                ticker.tick(|| info!("{}: {}", node.state, state_summary.summarize(&node.state)))
+            }
         }
     }
 
@@ -122,12 +729,351 @@ impl Engine for PbftEngine {
     }
 }
 
-fn handle_pbft_result(res: Result<(), PbftError>) {
+/// Lets the same engine be registered with the validator again, such as after the validator
+/// restarts. Each registration starts the engine over with the same options, the chain head and
+/// settings the validator has then, and the progress saved in its state directory.
+impl<'a> Engine for &'a mut PbftEngine {
+    fn start(
+        &mut self,
+        updates: Receiver<Update>,
+        service: Box<Service>,
+        startup_state: StartupState,
+    ) {
+        (**self).start(updates, service, startup_state)
+    }
+
+    fn version(&self) -> String {
+        (**self).version()
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+}
+
+/// An engine running on its own thread, started by `PbftEngine::spawn`; the engine is shut down
+/// when its handle is dropped
+pub struct EngineHandle {
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EngineHandle {
+    /// Shut the engine down, as if the validator had sent `Update::Shutdown`, and wait for it to
+    /// stop. Returns an error if the engine panicked.
+    pub fn shutdown(mut self) -> thread::Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.join_thread()
+    }
+
+    /// Wait for the engine to stop by itself, after it gets `Update::Shutdown` or its updates'
+    /// channel is closed. Returns an error if the engine panicked.
+    pub fn join(mut self) -> thread::Result<()> {
+        self.join_thread()
+    }
+
+    fn join_thread(&mut self) -> thread::Result<()> {
+        self.thread.take().map_or(Ok(()), JoinHandle::join)
+    }
+}
+
+impl Drop for EngineHandle {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let _ = self.join_thread();
+    }
+}
+
+// How the engine retries loading the on-chain settings at startup: waiting 1s, then twice as long
+// after each failure up to 30s, and giving up after 10 attempts
+fn startup_retry() -> Retry {
+    Retry::new(RetryStrategy::Exponential {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(30),
+    })
+    .with_max_attempts(10)
+}
+
+// Wait for the given delay before retrying, holding on to any updates that arrive meanwhile.
+// Returns `false` if the validator asked the engine to shut down or disconnected, so there's no
+// point in retrying.
+fn wait_for_retry(
+    updates: &UpdateQueue,
+    delay: Duration,
+    held: &mut VecDeque<(Update, Duration)>,
+) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        match updates.recv_timeout(until - now) {
+            Ok((Update::Shutdown, _)) | Err(RecvTimeoutError::Disconnected) => return false,
+            Ok(update) => held.push_back(update),
+            Err(RecvTimeoutError::Timeout) => return true,
+        }
+    }
+}
+
+// Take the peer messages that are already waiting right behind the given one, up to
+// `MAX_DECODE_BATCH` in all, so they can be decoded together. Doesn't wait for more to arrive; the
+// first update that isn't a peer message is held, to be handled next.
+fn take_peer_messages(
+    first: (PeerMessage, PeerId),
+    updates: &UpdateQueue,
+    held: &mut VecDeque<(Update, Duration)>,
+    metrics: &mut Metrics,
+) -> Vec<(PeerMessage, PeerId)> {
+    let mut batch = vec![first];
+    while batch.len() < MAX_DECODE_BATCH {
+        let next = match held.pop_front().or_else(|| updates.try_recv()) {
+            Some(next) => next,
+            None => break,
+        };
+        match next {
+            (Update::PeerMessage(message, sender_id), queued) => {
+                metrics.record_update_queue(updates.depth(), Some(queued));
+                batch.push((message, sender_id));
+            }
+            other => {
+                held.push_front(other);
+                break;
+            }
+        }
+    }
+    batch
+}
+
+// Log an error, and count it by its code
+fn handle_pbft_result(res: Result<(), PbftError>, metrics: &mut Metrics) {
     if let Err(e) = res {
-        match e {
-            PbftError::Timeout => (),
+        match e.kind() {
+            PbftError::Timeout => return,
             PbftError::WrongNumMessages(_, _, _) | PbftError::NotReadyForMessage => trace!("{}", e),
             _ => error!("{}", e),
         }
+        metrics.count_error(&e);
+    }
+}
+
+// Like `handle_pbft_result`, but for the result of handling a message from the given peer; errors
+// with the same code from the same peer are throttled
+fn handle_peer_message_result(
+    res: Result<(), PbftError>,
+    sender_id: &PeerId,
+    throttle: &mut LogThrottle<(PeerId, &'static str)>,
+    metrics: &mut Metrics,
+) {
+    if let Err(e) = res {
+        match e.kind() {
+            PbftError::Timeout => return,
+            PbftError::WrongNumMessages(_, _, _) | PbftError::NotReadyForMessage => trace!("{}", e),
+            _ => {
+                let key = (sender_id.clone(), e.code());
+                if let Some(suppressed) = throttle.check(key) {
+                    error!("{}{}", e, suppressed);
+                }
+            }
+        }
+        metrics.count_error(&e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    /// A validator that only knows its chain head and the network's membership, and counts the
+    /// blocks the engine starts
+    struct EmbeddingService {
+        head: Block,
+        initialized: Arc<AtomicUsize>,
+    }
+
+    impl Service for EmbeddingService {
+        fn send_to(&mut self, _peer: &PeerId, _: &str, _payload: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn broadcast(&mut self, _message_type: &str, _payload: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn initialize_block(&mut self, _previous_id: Option<BlockId>) -> Result<(), Error> {
+            self.initialized.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+            Err(Error::BlockNotReady)
+        }
+        fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
+            Err(Error::BlockNotReady)
+        }
+        fn cancel_block(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn check_blocks(&mut self, _priority: Vec<BlockId>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn commit_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_blocks(
+            &mut self,
+            _block_ids: Vec<BlockId>,
+        ) -> Result<HashMap<BlockId, Block>, Error> {
+            Ok(HashMap::new())
+        }
+        fn get_chain_head(&mut self) -> Result<Block, Error> {
+            Ok(self.head.clone())
+        }
+        fn get_settings(
+            &mut self,
+            _block_id: BlockId,
+            _settings: Vec<String>,
+        ) -> Result<HashMap<String, String>, Error> {
+            let mut settings = HashMap::new();
+            settings.insert(
+                String::from("sawtooth.consensus.pbft.peers"),
+                String::from(r#"["aa", "bb", "cc", "dd"]"#),
+            );
+            Ok(settings)
+        }
+        fn get_state(
+            &mut self,
+            _block_id: BlockId,
+            _addresses: Vec<String>,
+        ) -> Result<HashMap<String, Vec<u8>>, Error> {
+            Ok(HashMap::new())
+        }
+    }
+
+    /// Make sure that an engine can be run on its own thread, fed updates over a channel, and
+    /// shut down with its handle, or by closing the channel
+    #[test]
+    fn embedded_engine() {
+        let head = Block {
+            block_id: BlockId::from(vec![1]),
+            previous_id: BlockId::from(vec![0]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 1,
+            payload: vec![],
+            summary: vec![],
+        };
+        let startup_state = StartupState {
+            chain_head: head.clone(),
+            peers: vec![],
+            local_peer_info: PeerInfo {
+                peer_id: PeerId::from(vec![0xaa]),
+            },
+        };
+        let initialized = Arc::new(AtomicUsize::new(0));
+        let service = || {
+            Box::new(EmbeddingService {
+                head: head.clone(),
+                initialized: Arc::clone(&initialized),
+            })
+        };
+
+        // The node is the primary, so it starts a block as soon as it's running
+        let (sender, updates) = mpsc::channel();
+        let handle = PbftEngine::new(None, None)
+            .spawn(updates, service(), startup_state.clone())
+            .unwrap();
+        sender
+            .send(Update::PeerConnected(PeerInfo {
+                peer_id: PeerId::from(vec![0xbb]),
+            }))
+            .unwrap();
+        let start = Instant::now();
+        while initialized.load(Ordering::SeqCst) == 0 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(initialized.load(Ordering::SeqCst) > 0);
+        handle.shutdown().unwrap();
+
+        let (sender, updates) = mpsc::channel();
+        let handle = PbftEngine::new(None, None)
+            .spawn(updates, service(), startup_state)
+            .unwrap();
+        drop(sender);
+        handle.join().unwrap();
+    }
+
+    /// Make sure that an engine that can't set up what it was asked to logs why and stops, rather
+    /// than panicking
+    #[test]
+    fn setup_failure_shuts_down() {
+        let head = Block {
+            block_id: BlockId::from(vec![1]),
+            previous_id: BlockId::from(vec![0]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 1,
+            payload: vec![],
+            summary: vec![],
+        };
+        let startup_state = StartupState {
+            chain_head: head.clone(),
+            peers: vec![],
+            local_peer_info: PeerInfo {
+                peer_id: PeerId::from(vec![0xaa]),
+            },
+        };
+        let service = Box::new(EmbeddingService {
+            head,
+            initialized: Arc::new(AtomicUsize::new(0)),
+        });
+
+        // The validator is still connected, so the engine only stops because of the bad alerts
+        let (_sender, updates) = mpsc::channel();
+        let handle = PbftEngine::new(None, None)
+            .with_alerts(PathBuf::from("/nonexistent/pbft-alerts.json"))
+            .spawn(updates, service, startup_state)
+            .unwrap();
+        assert!(handle.join().is_ok());
+    }
+
+    /// Make sure that the same engine can be registered again after its connection to the
+    /// validator is lost, and starts over each time
+    #[test]
+    fn reregistered_engine() {
+        let head = Block {
+            block_id: BlockId::from(vec![1]),
+            previous_id: BlockId::from(vec![0]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 1,
+            payload: vec![],
+            summary: vec![],
+        };
+        let startup_state = StartupState {
+            chain_head: head.clone(),
+            peers: vec![],
+            local_peer_info: PeerInfo {
+                peer_id: PeerId::from(vec![0xaa]),
+            },
+        };
+        let initialized = Arc::new(AtomicUsize::new(0));
+
+        let mut engine = PbftEngine::new(None, None);
+        for registration in 1..=2 {
+            // The validator's connection closes right away, so each registration only starts the
+            // node, which starts a block since it's the primary
+            let (sender, updates) = mpsc::channel();
+            drop(sender);
+            let service = Box::new(EmbeddingService {
+                head: head.clone(),
+                initialized: Arc::clone(&initialized),
+            });
+            Engine::start(&mut &mut engine, updates, service, startup_state.clone());
+            assert_eq!(initialized.load(Ordering::SeqCst), registration);
+        }
     }
 }
@@ -16,16 +16,22 @@
  */
 
 //! PBFT-specific error messages
+//!
+//! Every error has a stable code (like `E104`) and a category, which are written in front of it
+//! in logs and counted in the `pbft_errors_total` metric, so operators can alert on a class of
+//! failure without matching on message text. Codes are never reused or renumbered.
 
 use hex;
 use std::error::Error;
 use std::fmt;
 
 use protobuf::error::ProtobufError;
+use sawtooth_sdk::consensus::engine::{Error as EngineError, PeerId};
 
 use protos::pbft_message::PbftBlock;
 
 use message_type::PbftMessageType;
+use seal::SealError;
 
 /// Errors that might occur in a PbftNode
 #[derive(Debug)]
@@ -51,6 +57,13 @@ pub enum PbftError {
     /// Internal PBFT error (description)
     InternalError(String),
 
+    /// A call to the validator failed (what was being done, the validator's error)
+    ServiceError(String, EngineError),
+
+    /// An on-chain setting or a local configuration file is missing or can't be used
+    /// (description)
+    InvalidConfig(String),
+
     /// The requested node is not found on the network
     NodeNotFound,
 
@@ -65,6 +78,149 @@ pub enum PbftError {
 
     /// Not ready for this message type
     NotReadyForMessage,
+
+    /// The message wasn't signed by the node it says it's from
+    InvalidSignature,
+
+    /// The message is too large, of an unknown type, or missing required fields (description)
+    MalformedMessage(String),
+
+    /// An error, along with where it happened
+    Context(ErrorContext, Box<PbftError>),
+}
+
+/// Broad classes of errors, for alerting on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorCategory {
+    /// A message from another node couldn't be decoded, or doesn't agree with this node's state;
+    /// a steady stream of these points to a faulty node
+    Message,
+
+    /// Something isn't ready yet, which is a normal part of consensus
+    Progress,
+
+    /// The validator failed a request
+    Service,
+
+    /// The node's settings can't be used
+    Config,
+
+    /// Something that shouldn't happen did
+    Internal,
+}
+
+impl ErrorCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCategory::Message => "message",
+            ErrorCategory::Progress => "progress",
+            ErrorCategory::Service => "service",
+            ErrorCategory::Config => "config",
+            ErrorCategory::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Where an error happened: the view and sequence number of the message that was being handled,
+/// and the node it came from, as far as they're known
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorContext {
+    pub view: Option<u64>,
+    pub seq_num: Option<u64>,
+    pub peer: Option<PeerId>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(view) = self.view {
+            parts.push(format!("view {}", view));
+        }
+        if let Some(seq_num) = self.seq_num {
+            parts.push(format!("seq {}", seq_num));
+        }
+        if let Some(ref peer) = self.peer {
+            let peer = hex::encode(Vec::<u8>::from(peer.clone()));
+            parts.push(format!("from {}", &peer[..peer.len().min(6)]));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl PbftError {
+    /// The error's stable code
+    pub fn code(&self) -> &'static str {
+        use self::PbftError::*;
+        match self {
+            SerializationError(_) => "E101",
+            MalformedMessage(_) => "E102",
+            InvalidSignature => "E103",
+            MessageMismatch(_) => "E104",
+            BlockMismatch(_, _) => "E105",
+            ViewMismatch(_, _) => "E106",
+            MessageExists(_) => "E107",
+            NodeNotFound => "E108",
+            WrongNumMessages(_, _, _) => "E201",
+            NotReadyForMessage => "E202",
+            NoWorkingBlock => "E203",
+            Timeout => "E204",
+            ServiceError(_, _) => "E301",
+            WrongNumBlocks => "E302",
+            InvalidConfig(_) => "E401",
+            InternalError(_) => "E901",
+            Context(_, err) => err.code(),
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        use self::PbftError::*;
+        match self {
+            SerializationError(_)
+            | MalformedMessage(_)
+            | InvalidSignature
+            | MessageMismatch(_)
+            | BlockMismatch(_, _)
+            | ViewMismatch(_, _)
+            | MessageExists(_)
+            | NodeNotFound => ErrorCategory::Message,
+            WrongNumMessages(_, _, _) | NotReadyForMessage | NoWorkingBlock | Timeout => {
+                ErrorCategory::Progress
+            }
+            ServiceError(_, _) | WrongNumBlocks => ErrorCategory::Service,
+            InvalidConfig(_) => ErrorCategory::Config,
+            InternalError(_) => ErrorCategory::Internal,
+            Context(_, err) => err.category(),
+        }
+    }
+
+    /// Note where this error happened; an error that already has context keeps it
+    pub fn with_context(self, view: Option<u64>, seq_num: Option<u64>, peer: &PeerId) -> Self {
+        match self {
+            PbftError::Context(_, _) => self,
+            err => PbftError::Context(
+                ErrorContext {
+                    view,
+                    seq_num,
+                    peer: Some(peer.clone()),
+                },
+                Box::new(err),
+            ),
+        }
+    }
+
+    /// The error without its context, for matching on
+    pub fn kind(&self) -> &PbftError {
+        match self {
+            PbftError::Context(_, err) => err.kind(),
+            err => err,
+        }
+    }
 }
 
 impl Error for PbftError {
@@ -78,18 +234,36 @@ impl Error for PbftError {
             MessageMismatch(_) => "MessageMismatch",
             ViewMismatch(_, _) => "ViewMismatch",
             InternalError(_) => "InternalError",
+            ServiceError(_, _) => "ServiceError",
+            InvalidConfig(_) => "InvalidConfig",
             NodeNotFound => "NodeNotFound",
             WrongNumBlocks => "WrongNumBlocks",
             Timeout => "Timeout",
             NoWorkingBlock => "NoWorkingBlock",
             NotReadyForMessage => "NotReadyForMessage",
+            InvalidSignature => "InvalidSignature",
+            MalformedMessage(_) => "MalformedMessage",
+            Context(_, err) => err.description(),
+        }
+    }
+
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match self {
+            PbftError::SerializationError(err) => Some(err),
+            PbftError::ServiceError(_, err) => Some(err),
+            PbftError::Context(_, err) => err.source(),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for PbftError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: ", self.description())?;
+        if let PbftError::Context(context, err) = self {
+            return write!(f, "{} ({})", err, context);
+        }
+
+        write!(f, "[{}] {}: ", self.code(), self.description())?;
         match self {
             PbftError::SerializationError(pb_err) => pb_err.fmt(f),
             PbftError::MessageExists(t) => write!(
@@ -114,8 +288,75 @@ impl fmt::Display for PbftError {
             PbftError::WrongNumBlocks => write!(f, "Incorrect number of blocks"),
             PbftError::Timeout => write!(f, "Timed out"),
             PbftError::InternalError(description) => write!(f, "{}", description),
+            PbftError::ServiceError(action, err) => write!(f, "{}: {}", action, err),
+            PbftError::InvalidConfig(description) => write!(f, "{}", description),
             PbftError::NoWorkingBlock => write!(f, "There is no working block"),
             PbftError::NotReadyForMessage => write!(f, "Not ready"),
+            PbftError::InvalidSignature => {
+                write!(f, "Message wasn't signed by the node it's from")
+            }
+            PbftError::MalformedMessage(description) => write!(f, "{}", description),
+            PbftError::Context(_, _) => Ok(()),
+        }
+    }
+}
+
+impl From<SealError> for PbftError {
+    fn from(err: SealError) -> Self {
+        match err {
+            SealError::WrongBlock | SealError::MessageMismatch => {
+                PbftError::MessageMismatch(PbftMessageType::Commit)
+            }
+            SealError::ViewMismatch(exp, got) => {
+                PbftError::ViewMismatch(exp as usize, got as usize)
+            }
+            SealError::UnknownSigner => PbftError::NodeNotFound,
+            SealError::NotEnoughCommits(exp, got) => {
+                PbftError::WrongNumMessages(PbftMessageType::Commit, exp as usize, got as usize)
+            }
+            SealError::InvalidViewEvidence => PbftError::MessageMismatch(PbftMessageType::NewView),
+            SealError::InvalidSignature => PbftError::InvalidSignature,
+            SealError::InvalidEncoding(err) => PbftError::MalformedMessage(err),
+            SealError::UnsupportedVersion(version) => {
+                PbftError::MalformedMessage(format!("Unsupported seal version {}", version))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that an error with context keeps its code and category, and says where it
+    /// happened
+    #[test]
+    fn error_context() {
+        let peer_id = PeerId::from(vec![0xab, 0xcd, 0xef, 0x01]);
+        let err = PbftError::MessageMismatch(PbftMessageType::Prepare);
+        assert_eq!(err.code(), "E104");
+        assert_eq!(err.category(), ErrorCategory::Message);
+
+        let err = err.with_context(Some(2), Some(7), &peer_id);
+        assert_eq!(err.code(), "E104");
+        assert_eq!(err.category(), ErrorCategory::Message);
+        match err.kind() {
+            PbftError::MessageMismatch(_) => (),
+            _ => panic!("Context hid the error"),
         }
+        assert_eq!(
+            err.to_string(),
+            "[E104] MessageMismatch: Prepare message mismatch (view 2, seq 7, from abcdef)"
+        );
+
+        // Context is only added once, and errors from the validator keep theirs as a source
+        let err = err.with_context(None, None, &PeerId::from(vec![1]));
+        assert!(err.to_string().ends_with("(view 2, seq 7, from abcdef)"));
+        let err = PbftError::ServiceError(
+            String::from("Failed to commit block"),
+            EngineError::NoChainHead,
+        );
+        assert_eq!(err.category(), ErrorCategory::Service);
+        assert!(err.source().is_some());
     }
 }
@@ -0,0 +1,369 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! A stream of consensus events for external tools
+//!
+//! As the node finalizes and commits blocks, changes views, and recovers from falling behind, it
+//! queues `ConsensusEvent`s in its state. If the engine is given an events socket, an
+//! `EventPublisher` sends them to every program connected to that Unix socket, one JSON object per
+//! line; if it's embedded in another process and given a channel, they're sent on that channel;
+//! otherwise they're discarded. The publisher is polled from the engine's event loop, and never
+//! waits for a subscriber: what a subscriber can't take right away is kept for it and sent on later
+//! polls. A subscriber that falls more than `MAX_SUBSCRIBER_BACKLOG` bytes behind is disconnected
+//! rather than allowed to hold the node up or use up its memory; it has missed events, so when it
+//! connects again it has to resync from the node's status and finality history rather than carry
+//! on from the events it got.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use hex;
+use serde_json::{self, Value};
+
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+
+use finality::FinalityRecord;
+use incidents::Incident;
+use view_stats::ViewChangeReason;
+
+/// How many bytes of events a subscriber can fall behind by before it's disconnected
+const MAX_SUBSCRIBER_BACKLOG: usize = 1024 * 1024;
+
+/// Something that happened to the node that external tools may want to react to
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsensusEvent {
+    /// A block became final on this node: a quorum of the network committed it, so it will be
+    /// committed on every node, and can be acted on before the validator has committed it here
+    BlockFinalized { record: FinalityRecord },
+
+    /// A block was committed at the given height
+    BlockCommitted { block_num: u64, block_id: BlockId },
+
+    /// The node moved from one view to another, for the given reason
+    ViewChanged {
+        from_view: u64,
+        view: u64,
+        reason: Option<ViewChangeReason>,
+    },
+
+    /// The primary of the given view was deemed faulty
+    PeerFaulty { peer_id: PeerId, view: u64 },
+
+    /// The given node's messages are being ignored for the given number of seconds, because it
+    /// sent too many that couldn't be decoded or validated
+    PeerQuarantined { peer_id: PeerId, seconds: u64 },
+
+    /// The node fell too far behind and started recovering up to the given sequence number
+    CatchUpStarted { target_seq_num: u64 },
+
+    /// The node finished recovering, at the given sequence number
+    CatchUpFinished { seq_num: u64 },
+
+    /// A problem that needs an operator's attention started, with the given details
+    IncidentStarted { incident: Incident, detail: String },
+
+    /// A problem that needed an operator's attention is over
+    IncidentEnded { incident: Incident },
+}
+
+impl ConsensusEvent {
+    /// Write the event as a single line of JSON, with its kind in the `event` field
+    pub fn to_json(&self) -> String {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+        let event = match self {
+            ConsensusEvent::BlockFinalized { record } => {
+                if let Value::Object(record) = record.to_value() {
+                    fields.extend(record);
+                }
+                "block_finalized"
+            }
+            ConsensusEvent::BlockCommitted {
+                block_num,
+                block_id,
+            } => {
+                fields.insert(String::from("block_num"), Value::from(*block_num));
+                fields.insert(
+                    String::from("block_id"),
+                    Value::from(hex::encode(Vec::<u8>::from(block_id.clone()))),
+                );
+                "block_committed"
+            }
+            ConsensusEvent::ViewChanged {
+                from_view,
+                view,
+                reason,
+            } => {
+                fields.insert(String::from("from_view"), Value::from(*from_view));
+                fields.insert(String::from("view"), Value::from(*view));
+                if let Some(reason) = reason {
+                    fields.insert(String::from("reason"), Value::from(format!("{:?}", reason)));
+                }
+                "view_changed"
+            }
+            ConsensusEvent::PeerFaulty { peer_id, view } => {
+                fields.insert(
+                    String::from("peer_id"),
+                    Value::from(hex::encode(Vec::<u8>::from(peer_id.clone()))),
+                );
+                fields.insert(String::from("view"), Value::from(*view));
+                "peer_faulty"
+            }
+            ConsensusEvent::PeerQuarantined { peer_id, seconds } => {
+                fields.insert(
+                    String::from("peer_id"),
+                    Value::from(hex::encode(Vec::<u8>::from(peer_id.clone()))),
+                );
+                fields.insert(String::from("seconds"), Value::from(*seconds));
+                "peer_quarantined"
+            }
+            ConsensusEvent::CatchUpStarted { target_seq_num } => {
+                fields.insert(String::from("target_seq_num"), Value::from(*target_seq_num));
+                "catch_up_started"
+            }
+            ConsensusEvent::CatchUpFinished { seq_num } => {
+                fields.insert(String::from("seq_num"), Value::from(*seq_num));
+                "catch_up_finished"
+            }
+            ConsensusEvent::IncidentStarted { incident, detail } => {
+                fields.insert(String::from("incident"), Value::from(incident.name()));
+                fields.insert(String::from("detail"), Value::from(detail.clone()));
+                "incident_started"
+            }
+            ConsensusEvent::IncidentEnded { incident } => {
+                fields.insert(String::from("incident"), Value::from(incident.name()));
+                "incident_ended"
+            }
+        };
+        fields.insert(String::from("event"), Value::from(event));
+
+        serde_json::to_string(&fields).expect("Couldn't write event as JSON")
+    }
+}
+
+/// Sends consensus events to the programs connected to a Unix socket
+pub struct EventPublisher {
+    path: PathBuf,
+    listener: UnixListener,
+    subscribers: Vec<Subscriber>,
+}
+
+// A program connected to the events socket, and the events it hasn't taken yet
+struct Subscriber {
+    stream: UnixStream,
+    backlog: VecDeque<u8>,
+}
+
+impl Subscriber {
+    // Queue the given bytes and send what the subscriber will take; returns false if the
+    // subscriber should be dropped
+    fn send(&mut self, bytes: &[u8]) -> bool {
+        self.backlog.extend(bytes);
+        if let Err(err) = self.flush() {
+            debug!("Dropping events subscriber: {}", err);
+            return false;
+        }
+        if self.backlog.len() > MAX_SUBSCRIBER_BACKLOG {
+            warn!(
+                "Dropping events subscriber that fell {} bytes behind; it has to resync",
+                self.backlog.len()
+            );
+            return false;
+        }
+        true
+    }
+
+    // Send as much of the backlog as the subscriber will take without waiting; returns an error if
+    // the subscriber went away
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.backlog.is_empty() {
+            let written = {
+                let (front, _) = self.backlog.as_slices();
+                match self.stream.write(front) {
+                    Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    Ok(written) => written,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => 0,
+                    Err(err) => return Err(err),
+                }
+            };
+            self.backlog.drain(..written);
+        }
+        Ok(())
+    }
+}
+
+impl EventPublisher {
+    /// Listen for subscribers at the given path. A socket left there by an earlier run is
+    /// replaced.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if let Err(err) = fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(EventPublisher {
+            path: path.to_path_buf(),
+            listener,
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Add every subscriber that is waiting to connect, then send them all the given events, along
+    /// with whatever they couldn't take on earlier calls
+    pub fn publish(&mut self, events: &[ConsensusEvent]) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.subscribers.push(Subscriber {
+                        stream,
+                        backlog: VecDeque::new(),
+                    }),
+                    Err(err) => warn!("Couldn't set up events subscriber: {}", err),
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("Couldn't accept events connection: {}", err);
+                    break;
+                }
+            }
+        }
+
+        let mut lines = String::new();
+        for event in events {
+            lines.push_str(&event.to_json());
+            lines.push('\n');
+        }
+
+        // A subscriber that went away, or that has fallen too far behind, is dropped
+        let mut i = 0;
+        while i < self.subscribers.len() {
+            if self.subscribers[i].send(lines.as_bytes()) {
+                i += 1;
+            } else {
+                self.subscribers.remove(i);
+            }
+        }
+    }
+}
+
+impl Drop for EventPublisher {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{BufRead, BufReader, Read};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Make sure that events are sent to every connected subscriber as lines of JSON
+    #[test]
+    fn publish_events() {
+        let path = env::temp_dir().join(format!("pbft-events-test-{}.sock", ::std::process::id()));
+        let mut publisher = EventPublisher::bind(&path).unwrap();
+
+        let subscribers: Vec<_> = (0..2)
+            .map(|_| BufReader::new(UnixStream::connect(&path).unwrap()))
+            .collect();
+        publisher.publish(&[
+            ConsensusEvent::BlockCommitted {
+                block_num: 3,
+                block_id: BlockId::from(vec![0xab, 0xcd]),
+            },
+            ConsensusEvent::ViewChanged {
+                from_view: 1,
+                view: 2,
+                reason: Some(ViewChangeReason::CommitTimeout),
+            },
+        ]);
+
+        for mut subscriber in subscribers {
+            let mut line = String::new();
+            subscriber.read_line(&mut line).unwrap();
+            assert_eq!(
+                line,
+                "{\"block_id\":\"abcd\",\"block_num\":3,\"event\":\"block_committed\"}\n"
+            );
+            line.clear();
+            subscriber.read_line(&mut line).unwrap();
+            assert_eq!(
+                line,
+                "{\"event\":\"view_changed\",\"from_view\":1,\"reason\":\"CommitTimeout\",\
+                 \"view\":2}\n"
+            );
+        }
+
+        // The socket goes away with the publisher
+        drop(publisher);
+        assert!(!path.exists());
+    }
+
+    /// Make sure that events a subscriber can't take right away are sent to it later, and that a
+    /// subscriber is only disconnected once it falls too far behind
+    #[test]
+    fn slow_subscriber() {
+        let path = env::temp_dir().join(format!("pbft-slow-test-{}.sock", ::std::process::id()));
+        let mut publisher = EventPublisher::bind(&path).unwrap();
+        let committed = |block_num| ConsensusEvent::BlockCommitted {
+            block_num,
+            block_id: BlockId::from(vec![0xab, 0xcd]),
+        };
+
+        // More events than the socket holds are kept for the subscriber until it reads them
+        let slow = UnixStream::connect(&path).unwrap();
+        let events: Vec<_> = (0..10_000).map(committed).collect();
+        publisher.publish(&events);
+        assert_eq!(publisher.subscribers.len(), 1);
+        assert!(!publisher.subscribers[0].backlog.is_empty());
+
+        let reader = thread::spawn(move || {
+            BufReader::new(slow)
+                .lines()
+                .take(10_000)
+                .map(|line| line.unwrap())
+                .collect::<Vec<_>>()
+        });
+        while !publisher.subscribers[0].backlog.is_empty() {
+            publisher.publish(&[]);
+            thread::sleep(Duration::from_millis(1));
+        }
+        let lines = reader.join().unwrap();
+        assert_eq!(lines.len(), 10_000);
+        assert_eq!(lines[9_999], committed(9_999).to_json());
+
+        // A subscriber that falls too far behind is dropped, and finds out by reaching the end of
+        // the stream; the one that went away is dropped too
+        let mut lagging = UnixStream::connect(&path).unwrap();
+        let events: Vec<_> = (0..30_000).map(committed).collect();
+        publisher.publish(&events);
+        assert!(publisher.subscribers.is_empty());
+
+        let mut received = Vec::new();
+        lagging.read_to_end(&mut received).unwrap();
+        assert!(!received.is_empty());
+        assert!(received.len() < events.iter().map(|event| event.to_json().len() + 1).sum());
+    }
+}
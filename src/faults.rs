@@ -0,0 +1,283 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Byzantine behavior on demand, for testing how well a network tolerates faulty nodes
+//!
+//! Only built with the `test-faults` feature. A node started with a fault scenario file misbehaves
+//! in the ways the scenario describes, once it reaches the scenario's starting sequence number:
+//!
+//! ```json
+//! {
+//!     "from_seq_num": 10,
+//!     "silent_primary": true,
+//!     "conflicting_pre_prepares": true,
+//!     "wrong_digests": ["Prepare", "Commit"],
+//!     "delay": 500
+//! }
+//! ```
+//!
+//! + `silent_primary`: while primary, don't publish blocks or send `PrePrepare`s
+//! + `conflicting_pre_prepares`: while primary, send every other node a `PrePrepare` for a
+//!   different block than the one sent to the rest
+//! + `wrong_digests`: in messages of these types, vote for a different block than the one being
+//!   voted on
+//! + `delay`: hold every outgoing message for this many milliseconds before sending it
+//!
+//! Only the copies of messages sent to other nodes are affected; the node itself keeps following
+//! the protocol, so it keeps sending the messages that the scenario changes.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use protobuf::{self, Message};
+use serde_json::{self, Value};
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use protos::pbft_message::PbftMessage;
+
+use error::PbftError;
+use message_type::PbftMessageType;
+
+/// The ways a node should misbehave, and when to start
+#[derive(Debug, Default)]
+pub struct FaultScenario {
+    pub from_seq_num: u64,
+    pub silent_primary: bool,
+    pub conflicting_pre_prepares: bool,
+    pub wrong_digests: Vec<String>,
+    pub delay: Option<Duration>,
+}
+
+impl FaultScenario {
+    /// Read a scenario from a JSON file
+    pub fn load(path: &Path) -> Result<Self, PbftError> {
+        let scenario = fs::read_to_string(path).map_err(|err| {
+            PbftError::InvalidConfig(format!("Couldn't read fault scenario {:?}: {}", path, err))
+        })?;
+        FaultScenario::from_json(&scenario)
+    }
+
+    /// Parse a scenario from JSON; fields that are left out don't cause any faults
+    pub fn from_json(scenario: &str) -> Result<Self, PbftError> {
+        let value: Value = serde_json::from_str(scenario)
+            .map_err(|err| PbftError::InvalidConfig(format!("Invalid fault scenario: {}", err)))?;
+
+        let wrong_digests = match value.get("wrong_digests").and_then(Value::as_array) {
+            Some(types) => types
+                .iter()
+                .map(|msg_type| match msg_type.as_str() {
+                    Some(msg_type) if PbftMessageType::from(msg_type) != PbftMessageType::Unset => {
+                        Ok(String::from(msg_type))
+                    }
+                    _ => Err(PbftError::InvalidConfig(format!(
+                        "Invalid message type in fault scenario: {}",
+                        msg_type
+                    ))),
+                })
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+
+        Ok(FaultScenario {
+            from_seq_num: value
+                .get("from_seq_num")
+                .and_then(Value::as_u64)
+                .unwrap_or(0),
+            silent_primary: value
+                .get("silent_primary")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            conflicting_pre_prepares: value
+                .get("conflicting_pre_prepares")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            wrong_digests,
+            delay: value
+                .get("delay")
+                .and_then(Value::as_u64)
+                .map(Duration::from_millis),
+        })
+    }
+}
+
+/// A message that is being held back before it's sent
+pub struct DelayedMessage {
+    pub peer_id: PeerId,
+    pub msg_type: String,
+    pub payload: Vec<u8>,
+    send_at: Instant,
+}
+
+/// Applies a `FaultScenario` to the messages a node sends
+pub struct FaultInjector {
+    scenario: FaultScenario,
+    delayed: VecDeque<DelayedMessage>,
+}
+
+impl FaultInjector {
+    pub fn new(scenario: FaultScenario) -> Self {
+        warn!("Injecting faults: {:?}", scenario);
+        FaultInjector {
+            scenario,
+            delayed: VecDeque::new(),
+        }
+    }
+
+    /// Whether the node should stay silent if it's primary at the given sequence number
+    pub fn is_silent_primary(&self, seq_num: u64) -> bool {
+        self.is_active(seq_num) && self.scenario.silent_primary
+    }
+
+    /// Change a message before it's sent to the node at `peer_index` in the peers list, if the
+    /// scenario calls for it
+    pub fn tamper(
+        &self,
+        msg_type: &PbftMessageType,
+        msg_bytes: Vec<u8>,
+        peer_index: usize,
+        seq_num: u64,
+    ) -> Vec<u8> {
+        if !self.is_active(seq_num) {
+            return msg_bytes;
+        }
+
+        let conflicting = self.scenario.conflicting_pre_prepares
+            && msg_type == &PbftMessageType::PrePrepare
+            && peer_index % 2 == 1;
+        let wrong_digest = self
+            .scenario
+            .wrong_digests
+            .iter()
+            .any(|wrong| wrong == &String::from(msg_type));
+        if !conflicting && !wrong_digest {
+            return msg_bytes;
+        }
+
+        match change_block_id(&msg_bytes) {
+            Ok(changed) => changed,
+            Err(err) => {
+                error!("Couldn't change {:?} message: {}", msg_type, err);
+                msg_bytes
+            }
+        }
+    }
+
+    /// Hold a message back instead of sending it, if the scenario has a delay. Returns the message
+    /// if it should be sent right away.
+    pub fn delay(
+        &mut self,
+        peer_id: &PeerId,
+        msg_type: &PbftMessageType,
+        payload: Vec<u8>,
+        seq_num: u64,
+    ) -> Option<Vec<u8>> {
+        match self.scenario.delay {
+            Some(delay) if self.is_active(seq_num) => {
+                self.delayed.push_back(DelayedMessage {
+                    peer_id: peer_id.clone(),
+                    msg_type: String::from(msg_type),
+                    payload,
+                    send_at: Instant::now() + delay,
+                });
+                None
+            }
+            _ => Some(payload),
+        }
+    }
+
+    /// Take the held back messages whose delay is over
+    pub fn take_ready(&mut self) -> Vec<DelayedMessage> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        while self.delayed.front().map_or(false, |msg| msg.send_at <= now) {
+            ready.extend(self.delayed.pop_front());
+        }
+        ready
+    }
+
+    fn is_active(&self, seq_num: u64) -> bool {
+        seq_num >= self.scenario.from_seq_num
+    }
+}
+
+// Replace the block ID in a serialized `PbftMessage` with a different one
+fn change_block_id(msg_bytes: &[u8]) -> Result<Vec<u8>, PbftError> {
+    let mut msg = protobuf::parse_from_bytes::<PbftMessage>(msg_bytes)
+        .map_err(PbftError::SerializationError)?;
+    let block_id: Vec<u8> = msg.get_block().get_block_id().iter().map(|b| !b).collect();
+    msg.mut_block().set_block_id(block_id);
+    msg.write_to_bytes().map_err(PbftError::SerializationError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::make_msg_info;
+
+    /// Make sure that a scenario is parsed correctly, and that messages are only changed in the
+    /// ways, and from the sequence number, that it calls for
+    #[test]
+    fn fault_injection() {
+        let scenario = FaultScenario::from_json(
+            r#"{"from_seq_num": 2, "conflicting_pre_prepares": true, "wrong_digests": ["Commit"]}"#,
+        )
+        .unwrap();
+        assert!(!scenario.silent_primary);
+        assert!(scenario.delay.is_none());
+        assert!(FaultScenario::from_json(r#"{"wrong_digests": ["Gossip"]}"#).is_err());
+
+        let faults = FaultInjector::new(scenario);
+        let msg_bytes = |msg_type| {
+            let mut msg = PbftMessage::new();
+            msg.set_info(make_msg_info(msg_type, 0, 2, PeerId::from(vec![1])));
+            msg.mut_block().set_block_id(vec![1, 2, 3]);
+            msg.write_to_bytes().unwrap()
+        };
+        let pre_prepare = msg_bytes(&PbftMessageType::PrePrepare);
+        let commit = msg_bytes(&PbftMessageType::Commit);
+        let prepare = msg_bytes(&PbftMessageType::Prepare);
+
+        // Not started yet
+        assert_eq!(
+            faults.tamper(&PbftMessageType::Commit, commit.clone(), 1, 1),
+            commit
+        );
+
+        // Every other node gets a different PrePrepare
+        assert_eq!(
+            faults.tamper(&PbftMessageType::PrePrepare, pre_prepare.clone(), 0, 2),
+            pre_prepare
+        );
+        assert_ne!(
+            faults.tamper(&PbftMessageType::PrePrepare, pre_prepare.clone(), 1, 2),
+            pre_prepare
+        );
+
+        // Only the listed message types vote for the wrong block
+        assert_ne!(
+            faults.tamper(&PbftMessageType::Commit, commit.clone(), 0, 2),
+            commit
+        );
+        assert_eq!(
+            faults.tamper(&PbftMessageType::Prepare, prepare.clone(), 0, 2),
+            prepare
+        );
+    }
+}
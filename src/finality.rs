@@ -0,0 +1,209 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! When each block became final on this node
+//!
+//! A block is final once this node has a quorum of `Commit` messages for it, either from consensus
+//! or from a verified seal when catching up. The wall-clock time that happens is recorded for each
+//! block, separately from the block's own timestamp (which is when the primary made it), so
+//! time-to-finality can be measured. The most recent records are kept in the node's state, where
+//! the metrics server serves them at `/finality`, and every record is added to an append-only file
+//! in the node's state directory, if it has one. Each record is also sent to the node's event
+//! subscribers as it's made (see `ConsensusEvent::BlockFinalized`), so they can act on a block as
+//! soon as it's final.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hex;
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+use serde_json::{self, Value};
+
+use timing::millis;
+
+/// How many blocks' finality records are kept in memory
+const MAX_FINALITY_HISTORY: usize = 1000;
+
+/// When a block became final on this node
+#[derive(Clone, Debug, PartialEq)]
+pub struct FinalityRecord {
+    pub seq_num: u64,
+    pub block_id: BlockId,
+
+    /// The view the block was committed in
+    pub view: u64,
+
+    /// When this node had a commit quorum for the block
+    pub time: SystemTime,
+
+    /// How long it took from when this node received the block (`None` if it wasn't timed, such
+    /// as for blocks committed from seals)
+    pub since_received: Option<Duration>,
+
+    /// Whether the quorum came from a seal, instead of from consensus
+    pub from_seal: bool,
+
+    /// The nodes whose `Commit`s made up the quorum; with the sequence number and block ID, they
+    /// identify the block's seal
+    pub seal_signers: Vec<PeerId>,
+}
+
+impl FinalityRecord {
+    /// Write the record as a single line of JSON; `time` is in milliseconds since the Unix epoch
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_value()).expect("Couldn't write finality record as JSON")
+    }
+
+    /// The record as a JSON object
+    pub fn to_value(&self) -> Value {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+        fields.insert(String::from("seq_num"), Value::from(self.seq_num));
+        fields.insert(
+            String::from("block_id"),
+            Value::from(hex::encode(Vec::<u8>::from(self.block_id.clone()))),
+        );
+        fields.insert(String::from("view"), Value::from(self.view));
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        fields.insert(String::from("time"), Value::from(millis(since_epoch)));
+        fields.insert(
+            String::from("since_received_ms"),
+            self.since_received
+                .map_or(Value::Null, |since| Value::from(millis(since))),
+        );
+        fields.insert(String::from("from_seal"), Value::Bool(self.from_seal));
+        fields.insert(
+            String::from("seal_signers"),
+            Value::Array(
+                self.seal_signers
+                    .iter()
+                    .map(|signer| Value::from(hex::encode(Vec::<u8>::from(signer.clone()))))
+                    .collect(),
+            ),
+        );
+        Value::Object(fields.into_iter().collect())
+    }
+}
+
+/// The most recent blocks' finality records, and the records that haven't been saved yet
+#[derive(Debug, Default)]
+pub struct FinalityHistory {
+    recent: VecDeque<FinalityRecord>,
+    unsaved: Vec<FinalityRecord>,
+}
+
+impl FinalityHistory {
+    pub fn new() -> Self {
+        FinalityHistory::default()
+    }
+
+    /// Note that a block has become final, as of now, and return the record of it
+    pub fn record(
+        &mut self,
+        seq_num: u64,
+        block_id: BlockId,
+        view: u64,
+        since_received: Option<Duration>,
+        from_seal: bool,
+        seal_signers: Vec<PeerId>,
+    ) -> FinalityRecord {
+        let record = FinalityRecord {
+            seq_num,
+            block_id,
+            view,
+            time: SystemTime::now(),
+            since_received,
+            from_seal,
+            seal_signers,
+        };
+        if self.recent.len() >= MAX_FINALITY_HISTORY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(record.clone());
+        self.unsaved.push(record.clone());
+        record
+    }
+
+    /// The record for the block with the given sequence number, if it's recent enough to be kept
+    pub fn get(&self, seq_num: u64) -> Option<&FinalityRecord> {
+        self.recent
+            .iter()
+            .rev()
+            .find(|record| record.seq_num == seq_num)
+    }
+
+    pub fn recent(&self) -> impl Iterator<Item = &FinalityRecord> {
+        self.recent.iter()
+    }
+
+    /// Take the records that haven't been saved yet, oldest first
+    pub fn take_unsaved(&mut self) -> Vec<FinalityRecord> {
+        mem::replace(&mut self.unsaved, vec![])
+    }
+
+    /// Write the recent records as a JSON array, oldest first
+    pub fn to_json(&self) -> String {
+        let records = self.recent.iter().map(FinalityRecord::to_value).collect();
+        serde_json::to_string(&Value::Array(records)).expect("Couldn't write finality as JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that records are kept for the most recent blocks only, and are handed out for
+    /// saving exactly once
+    #[test]
+    fn finality_history() {
+        let mut history = FinalityHistory::new();
+        for seq_num in 0..(MAX_FINALITY_HISTORY as u64 + 5) {
+            history.record(
+                seq_num,
+                BlockId::from(vec![seq_num as u8]),
+                0,
+                Some(Duration::from_millis(1500)),
+                false,
+                vec![],
+            );
+        }
+        assert_eq!(history.recent().count(), MAX_FINALITY_HISTORY);
+        assert!(history.get(4).is_none());
+        assert_eq!(history.get(5).map(|record| record.seq_num), Some(5));
+        assert_eq!(history.take_unsaved().len(), MAX_FINALITY_HISTORY + 5);
+        assert!(history.take_unsaved().is_empty());
+
+        history.record(
+            2000,
+            BlockId::from(vec![0xab]),
+            3,
+            None,
+            true,
+            vec![PeerId::from(vec![1]), PeerId::from(vec![2])],
+        );
+        let record: Value = serde_json::from_str(&history.get(2000).unwrap().to_json()).unwrap();
+        assert_eq!(record.get("block_id").and_then(Value::as_str), Some("ab"));
+        assert_eq!(record.get("view").and_then(Value::as_u64), Some(3));
+        assert_eq!(record.get("from_seal").and_then(Value::as_bool), Some(true));
+        assert_eq!(record.get("since_received_ms"), Some(&Value::Null));
+        assert_eq!(
+            record.get("seal_signers"),
+            Some(&Value::Array(vec![Value::from("01"), Value::from("02")]))
+        );
+        assert!(history.to_json().starts_with("[{"));
+    }
+}
@@ -19,34 +19,45 @@
 
 use hex;
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
-use std::error::Error;
+use std::hash::{Hash, Hasher};
 
+use protobuf::Message;
 use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerId, PeerMessage};
-use sawtooth_sdk::consensus::service::Service;
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protos::pbft_message::{
+    PbftBlock, PbftMessage, PbftMessageInfo, PbftNewView, PbftPreparedCertificate, PbftSeal,
+    PbftViewChange,
+};
 
+use authentication;
+use consensus_service::ConsensusService;
 use error::PbftError;
+use events::ConsensusEvent;
 use message_log::PbftLog;
 use message_type::{PbftHint, PbftMessageType};
+use protocol;
+use seal;
 use state::{PbftMode, PbftPhase, PbftState, WorkingBlockOption};
+use view_stats::ViewChangeReason;
 
 /// Take action based on a `PbftHint`
-/// Either push to backlog or add message to log, depending on which type of hint
+/// Either push to backlog or add message to log, depending on which type of hint. The message's
+/// content is only copied if it goes in the backlog.
 pub fn action_from_hint(
     msg_log: &mut PbftLog,
     hint: &PbftHint,
     pbft_message: &PbftMessage,
-    msg_content: Vec<u8>,
+    msg_content: &[u8],
 ) -> Result<(), PbftError> {
-    let msg = PeerMessage {
-        message_type: String::from(pbft_message.get_info().get_msg_type()),
-        content: msg_content,
-    };
     match hint {
         PbftHint::FutureMessage => {
-            msg_log.push_backlog(msg);
+            msg_log.push_backlog(PeerMessage {
+                message_type: String::from(pbft_message.get_info().get_msg_type()),
+                content: msg_content.to_vec(),
+            });
             Err(PbftError::NotReadyForMessage)
         }
         PbftHint::PastMessage => {
@@ -112,12 +123,13 @@ pub fn pre_prepare(
             ));
         }
     } else {
-        // Set this secondary's sequence number from the PrePrepare message
-        // (this was originally set by the primary)...
-        state.seq_num = info.get_seq_num();
+        // A late `PrePrepare` for a block that this node already committed (from a seal, say)
+        // would start it over on that block
+        if info.get_seq_num() <= state.committed_seq_num() {
+            return Err(PbftError::NotReadyForMessage);
+        }
 
-        // ...then update the BlockNew message we received with the correct
-        // sequence number
+        // Update the BlockNew message we received with the correct sequence number...
         let num_updated = msg_log.fix_seq_nums(
             &PbftMessageType::BlockNew,
             info.get_seq_num(),
@@ -139,6 +151,10 @@ pub fn pre_prepare(
                 num_updated,
             ));
         }
+
+        // ...then set this secondary's sequence number from the PrePrepare message (this was
+        // originally set by the primary), once it's known to be for the block in the log
+        state.seq_num = info.get_seq_num();
     }
 
     // Take the working block from PrePrepare message as our current working block
@@ -148,15 +164,15 @@ pub fn pre_prepare(
 }
 
 /// Handle a `Commit` message
-/// Once a `2f + 1` `Commit` messages are received, the primary node can commit the block to the
+/// Once a quorum of `Commit` messages are received, the primary node can commit the block to the
 /// chain. If the block in the message isn't the one that belongs on top of the current chain head,
 /// then the message gets pushed to the backlog.
-pub fn commit(
+pub fn commit<S: ConsensusService + ?Sized>(
     state: &mut PbftState,
     msg_log: &mut PbftLog,
-    service: &mut Service,
+    service: &mut S,
     pbft_message: &PbftMessage,
-    msg_content: Vec<u8>,
+    msg_content: &[u8],
 ) -> Result<(), PbftError> {
     let working_block = if let WorkingBlockOption::WorkingBlock(ref wb) = state.working_block {
         Ok(wb.clone())
@@ -185,7 +201,7 @@ pub fn commit(
     // Also make sure that we're committing on top of the current chain head
     let head = service
         .get_chain_head()
-        .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+        .map_err(|err| PbftError::ServiceError(String::from("Failed to get chain head"), err))?;
     let cur_block = get_block_by_id(
         &mut *service,
         &BlockId::from(pbft_message.get_block().get_block_id().to_vec()),
@@ -198,7 +214,7 @@ pub fn commit(
         );
         let msg = PeerMessage {
             message_type: String::from(pbft_message.get_info().get_msg_type()),
-            content: msg_content,
+            content: msg_content.to_vec(),
         };
         msg_log.push_backlog(msg);
         return Err(PbftError::BlockMismatch(
@@ -215,7 +231,76 @@ pub fn commit(
 
     service
         .commit_block(BlockId::from(pbft_message.get_block().block_id.clone()))
-        .map_err(|_| PbftError::InternalError(String::from("Failed to commit block")))?;
+        .map_err(|err| PbftError::ServiceError(String::from("Failed to commit block"), err))?;
+
+    let info = pbft_message.get_info();
+
+    // The timeout was started when the block was received
+    let since_received = if state.timeout.is_active() {
+        Some(state.timeout.elapsed())
+    } else {
+        None
+    };
+    let block_id = pbft_message.get_block().get_block_id();
+    let seal_signers = msg_log
+        .get_messages_of_type(
+            &PbftMessageType::Commit,
+            info.get_seq_num(),
+            info.get_view(),
+        )
+        .iter()
+        .filter(|commit| commit.get_block().get_block_id() == block_id)
+        .map(|commit| PeerId::from(commit.get_info().get_signer_id().to_vec()))
+        .collect();
+    let record = state.finality.record(
+        info.get_seq_num(),
+        BlockId::from(pbft_message.get_block().block_id.clone()),
+        info.get_view(),
+        since_received,
+        false,
+        seal_signers,
+    );
+    state.events.push(ConsensusEvent::BlockFinalized { record });
+
+    if let (Some(prepare_latency), Some(commit_latency)) = (
+        msg_log.get_pre_prepare_to_prepared_latency(
+            info.get_seq_num(),
+            info.get_view(),
+            state.quorum(),
+        ),
+        msg_log.get_prepared_to_committed_latency(
+            info.get_seq_num(),
+            info.get_view(),
+            state.quorum(),
+        ),
+    ) {
+        debug!(
+            "{}: Seq {} took {:?} from PrePrepare to prepared, {:?} from prepared to committed",
+            state,
+            info.get_seq_num(),
+            prepare_latency,
+            commit_latency
+        );
+        state.metrics.record_latency("preparing", prepare_latency);
+        state.metrics.record_latency("committing", commit_latency);
+    }
+
+    // How late each other node's votes were, so that a node that is slow to vote stands out
+    let own_peer_id = state.get_own_peer_id();
+    for msg_type in &[PbftMessageType::Prepare, PbftMessageType::Commit] {
+        for (signer, lag) in
+            msg_log.get_arrival_offsets(msg_type, info.get_seq_num(), info.get_view())
+        {
+            let peer_id = PeerId::from(signer);
+            if peer_id != own_peer_id {
+                state
+                    .peer_stats
+                    .entry(peer_id)
+                    .or_default()
+                    .record_arrival_lag(&String::from(msg_type), lag);
+            }
+        }
+    }
 
     // Previous block is sent to the validator; reset the working block
     state.working_block = WorkingBlockOption::NoWorkingBlock;
@@ -229,103 +314,127 @@ pub fn commit(
 /// (`PrePrepare`, `Prepare`, and `Commit`)
 pub fn multicast_hint(state: &PbftState, pbft_message: &PbftMessage) -> PbftHint {
     let msg_type = PbftMessageType::from(pbft_message.get_info().get_msg_type());
-
-    if pbft_message.get_info().get_seq_num() > state.seq_num {
-        debug!(
-            "{}: seq {} > {}, accept all.",
-            state,
-            pbft_message.get_info().get_seq_num(),
-            state.seq_num
-        );
-        return PbftHint::FutureMessage;
-    } else if pbft_message.get_info().get_seq_num() == state.seq_num {
-        if state.working_block.is_none() {
-            debug!(
-                "{}: seq {} == {}, in limbo",
-                state,
-                pbft_message.get_info().get_seq_num(),
-                state.seq_num,
-            );
-            return PbftHint::PastMessage;
-        }
-        let expecting_type = state.check_msg_type();
-        if msg_type < expecting_type {
-            debug!(
-                "{}: seq {} == {}, {} < {}, only add to log",
-                state, state.seq_num, state.seq_num, msg_type, expecting_type,
-            );
-            return PbftHint::PastMessage;
-        } else if msg_type > expecting_type {
-            debug!(
-                "{}: seq {} == {}, {} > {}, push to backlog.",
-                state, state.seq_num, state.seq_num, msg_type, expecting_type,
-            );
-            return PbftHint::FutureMessage;
-        }
-    } else {
-        if state.working_block.is_none() {
-            debug!(
-                "{}: seq {} == {}, in limbo",
-                state,
-                pbft_message.get_info().get_seq_num(),
-                state.seq_num,
-            );
-            return PbftHint::PastMessage;
-        }
-        debug!(
-            "{}: seq {} < {}, skip but add to log.",
-            state,
-            pbft_message.get_info().get_seq_num(),
-            state.seq_num
-        );
-        return PbftHint::PastMessage;
-    }
-    PbftHint::PresentMessage
+    let hint = protocol::classify_multicast(
+        &msg_type,
+        pbft_message.get_info().get_seq_num(),
+        state.seq_num,
+        &state.phase,
+        !state.working_block.is_none(),
+    );
+    debug!(
+        "{}: {} for seq {} is a {:?}",
+        state,
+        msg_type,
+        pbft_message.get_info().get_seq_num(),
+        hint
+    );
+    hint
 }
 
 /// Handle a `ViewChange` message
-/// Once a node receives `2f + 1` `ViewChange` messages, the node enters view `v + 1` and changes
+/// Once a node receives a quorum of `ViewChange` messages, the node enters view `v + 1` and changes
 /// itself into the appropriate role for that view (i.e. if `v = 1` and this is node 1, then this
 /// node is now the primary).
-pub fn view_change(
+pub fn view_change<S: ConsensusService + ?Sized>(
     state: &mut PbftState,
     msg_log: &mut PbftLog,
-    service: &mut Service,
+    service: &mut S,
     vc_message: &PbftViewChange,
 ) -> Result<(), PbftError> {
-    msg_log.check_msg_against_log(&vc_message, true, 2 * state.f + 1)?;
+    msg_log.check_view_changes(vc_message.get_info().get_view(), state.quorum())?;
+
+    enter_view(state, msg_log, service, vc_message.get_info().get_view())
+}
+
+/// Move this node into the given view, once the view change to it is complete, taking the
+/// appropriate role for that view
+pub fn enter_view<S: ConsensusService + ?Sized>(
+    state: &mut PbftState,
+    msg_log: &mut PbftLog,
+    service: &mut S,
+    view: u64,
+) -> Result<(), PbftError> {
+    // The primaries of the views this node is leaving were deemed faulty
+    for failed_view in state.view..view {
+        state.record_primary_failure(failed_view);
+    }
+
+    // A block that was in progress when the view change started doesn't have to be thrown away;
+    // find it before the view changes, since its sequence number depends on how far it got
+    let in_flight = in_flight_block(state, service);
+    // A block that can't be kept failed, unless it was already counted for being invalid
+    if in_flight.is_none()
+        && !state.working_block.is_none()
+        && state.view_history.current().end_reason != Some(ViewChangeReason::InvalidBlock)
+    {
+        state.view_history.record_failed_proposal();
+    }
 
     // Update current view and stop timeout
-    state.view = vc_message.get_info().get_view();
+    state.view = view;
     warn!("{}: Updating to view {}", state, state.view);
+    let ended = state.view_history.enter_view(view);
+    let voters = msg_log
+        .view_changes()
+        .filter(|vc| vc.get_info().get_view() == view)
+        .map(|vc| PeerId::from(vc.get_info().get_signer_id().to_vec()))
+        .collect();
+    state.record_view_change(&ended, voters);
 
     // Upgrade this node to primary, if its ID is correct
+    let mut carried = in_flight;
     if state.get_own_peer_id() == state.get_primary_peer_id() {
         state.upgrade_role();
         warn!("{}: I'm now a primary", state);
 
+        // A block that was prepared in an earlier view will be re-proposed in the `NewView`, so
+        // it mustn't be ignored or replaced with a new block
+        let view_changes: Vec<PbftViewChange> = msg_log
+            .view_changes()
+            .filter(|vc| vc.get_info().get_view() == state.view)
+            .cloned()
+            .collect();
+        let reproposal = get_reproposal(service, &new_view_pre_prepares(state, &view_changes))?;
+        if reproposal.is_some() {
+            carried = None;
+        }
+        let kept_id = reproposal
+            .as_ref()
+            .map(|pre_prepare| pre_prepare.get_block().get_block_id().to_vec())
+            .or_else(|| {
+                carried
+                    .as_ref()
+                    .map(|(block, _)| block.get_block_id().to_vec())
+            });
+
         // If we're the new primary, need to clean up the block mess from the view change and
         // initialize a new block.
         if let WorkingBlockOption::WorkingBlock(ref working_block) = state.working_block {
-            info!(
-                "{}: Ignoring block {}",
-                state,
-                &hex::encode(working_block.get_block_id())
-            );
-            service
-                .ignore_block(BlockId::from(working_block.get_block_id().to_vec()))
-                .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+            if kept_id.as_ref().map(|id| id.as_slice()) != Some(working_block.get_block_id()) {
+                info!(
+                    "{}: Ignoring block {}",
+                    state,
+                    &hex::encode(working_block.get_block_id())
+                );
+                service
+                    .ignore_block(BlockId::from(working_block.get_block_id().to_vec()))
+                    .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+            }
         } else if let WorkingBlockOption::TentativeWorkingBlock(ref block_id) = state.working_block
         {
-            info!("{}: Ignoring block {}", state, &hex::encode(block_id));
+            if kept_id != Some(Vec::<u8>::from(block_id.clone())) {
+                info!("{}: Ignoring block {}", state, &hex::encode(block_id));
+                service
+                    .ignore_block(block_id.clone())
+                    .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+            }
+        }
+        if kept_id.is_none() {
+            info!("{}: Initializing block", state);
             service
-                .ignore_block(block_id.clone())
-                .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+                .initialize_block(None)
+                .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
         }
-        info!("{}: Initializing block", state);
-        service
-            .initialize_block(None)
-            .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
     } else {
         warn!("{}: I'm now a secondary", state);
         state.downgrade_role();
@@ -334,15 +443,384 @@ pub fn view_change(
     state.phase = PbftPhase::NotStarted;
     state.mode = PbftMode::Normal;
     state.timeout.stop();
+    state.fast_path_timeout.stop();
+    state.view_change_timeout.stop();
+    state.view_change_backoff.reset();
     warn!(
         "{}: Entered normal mode in new view {} and stopped timeout",
         state, state.view
     );
+
+    if let Some((block, seq_num)) = carried {
+        carry_over_block(state, msg_log, block, seq_num);
+    }
+    Ok(())
+}
+
+// The block that this node was working on, with the sequence number it was (or would have been)
+// proposed at, if it still belongs right on top of the chain head
+fn in_flight_block<S: ConsensusService + ?Sized>(
+    state: &PbftState,
+    service: &mut S,
+) -> Option<(PbftBlock, u64)> {
+    let (block, seq_num) = match state.working_block {
+        WorkingBlockOption::WorkingBlock(ref block) => (block.clone(), state.seq_num),
+        WorkingBlockOption::TentativeWorkingBlock(ref block_id) => {
+            let block = service
+                .get_blocks(vec![block_id.clone()])
+                .ok()?
+                .remove(block_id)?;
+            (pbft_block_from_block(block), state.seq_num + 1)
+        }
+        WorkingBlockOption::NoWorkingBlock => return None,
+    };
+
+    let head = service.get_chain_head().ok()?;
+    if block.get_block_num() == head.block_num + 1 {
+        Some((block, seq_num))
+    } else {
+        None
+    }
+}
+
+// Treat a block from an earlier view as if it had just arrived in this one. The primary proposes
+// it again (see `PbftNode::propose_carried_block`); a secondary can accept a `PrePrepare` for it
+// without the validator having to send the block again.
+fn carry_over_block(state: &mut PbftState, msg_log: &mut PbftLog, block: PbftBlock, seq_num: u64) {
+    info!(
+        "{}: Keeping block {} from the last view at sequence number {}",
+        state,
+        &hex::encode(block.get_block_id())[..6],
+        seq_num
+    );
+
+    let mut block_new = PbftMessage::new();
+    if state.is_primary() {
+        block_new.set_info(make_msg_info(
+            &PbftMessageType::BlockNew,
+            state.view,
+            seq_num,
+            state.get_own_peer_id(),
+        ));
+        state.seq_num = seq_num;
+    } else {
+        block_new.set_info(make_msg_info(
+            &PbftMessageType::BlockNew,
+            state.view,
+            0,
+            state.get_own_peer_id(),
+        ));
+        state.seq_num = seq_num - 1;
+    }
+    state.working_block =
+        WorkingBlockOption::TentativeWorkingBlock(BlockId::from(block.get_block_id().to_vec()));
+    state.phase = PbftPhase::PrePreparing;
+    state.timeout.start();
+    block_new.set_block(block);
+    msg_log.add_message(block_new);
+}
+
+/// Handle a `NewView` message
+/// The `NewView` must be for this node's current view and pass `verify_new_view`; its
+/// `PrePrepare`s must be exactly the ones that the included `ViewChange` messages call for.
+/// Returns the `PrePrepare` for the block that should be re-proposed on top of the current chain
+/// head, if any.
+pub fn new_view<S: ConsensusService + ?Sized>(
+    state: &PbftState,
+    service: &mut S,
+    new_view: &PbftNewView,
+) -> Result<Option<PbftMessage>, PbftError> {
+    if new_view.get_info().get_view() != state.view {
+        return Err(PbftError::ViewMismatch(
+            new_view.get_info().get_view() as usize,
+            state.view as usize,
+        ));
+    }
+
+    verify_new_view(state, new_view)?;
+
+    let expected = new_view_pre_prepares(state, new_view.get_view_changes());
+    if expected.as_slice() != new_view.get_pre_prepares() {
+        return Err(PbftError::MessageMismatch(PbftMessageType::NewView));
+    }
+
+    get_reproposal(service, new_view.get_pre_prepares())
+}
+
+/// Verify that a `NewView` message proves that its view change happened: it must come from the
+/// primary of its view, and contain `ViewChange` messages for its view from at least a quorum of
+/// different nodes in the network, with no node's `ViewChange` included more than once. The
+/// primary is the one this node will have once it enters the view, so a `NewView` for a view this
+/// node hasn't reached yet can be checked before anything changes. If messages are signed, each
+/// `ViewChange` must come with its sender's signature, so the primary can't make any up.
+pub fn verify_new_view(state: &PbftState, new_view: &PbftNewView) -> Result<(), PbftError> {
+    let view = new_view.get_info().get_view();
+
+    if new_view.get_info().get_signer_id()
+        != Vec::<u8>::from(state.get_primary_peer_id_on_entering(view)).as_slice()
+    {
+        return Err(PbftError::MalformedMessage(String::from(
+            "NewView message was not sent by the primary",
+        )));
+    }
+
+    if state.authenticate_messages {
+        if new_view.get_signed_view_changes().len() != new_view.get_view_changes().len() {
+            return Err(PbftError::InvalidSignature);
+        }
+        let votes = new_view
+            .get_view_changes()
+            .iter()
+            .zip(new_view.get_signed_view_changes())
+            .map(|(vc, signed)| {
+                let vc_bytes = vc.write_to_bytes().map_err(PbftError::SerializationError)?;
+                Ok((signed, vc_bytes, vc.get_info().get_signer_id()))
+            })
+            .collect::<Result<Vec<_>, PbftError>>()?;
+        authentication::verify_votes(&votes, state.verify_threads)?;
+    }
+
+    let mut signers: HashSet<&[u8]> = HashSet::new();
+    for vc in new_view.get_view_changes() {
+        let info = vc.get_info();
+        if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::ViewChange {
+            return Err(PbftError::MessageMismatch(PbftMessageType::ViewChange));
+        }
+        if info.get_view() != view {
+            return Err(PbftError::ViewMismatch(
+                info.get_view() as usize,
+                view as usize,
+            ));
+        }
+        state.get_node_id_from_bytes(info.get_signer_id())?;
+        if !signers.insert(info.get_signer_id()) {
+            return Err(PbftError::MessageExists(PbftMessageType::ViewChange));
+        }
+    }
+
+    if (signers.len() as u64) < state.quorum() {
+        return Err(PbftError::WrongNumMessages(
+            PbftMessageType::ViewChange,
+            state.quorum() as usize,
+            signers.len(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decide which blocks the primary of the current view has to re-propose, given the
+/// `ViewChange` messages for the view: for every sequence number with a valid prepared certificate
+/// after the sender's stable checkpoint, the block from the certificate with the highest view.
+/// Returns `PrePrepare`s for those blocks in the current view, ordered by sequence number.
+///
+/// With the fast path, a block may have been committed with only `Prepare`s from every node,
+/// without any other node having a prepared certificate for it. Every node sent a `Prepare` for
+/// it though, so at least `f + 1` of the `ViewChange`s vouch for it (see
+/// `PbftViewChange.accepted_pre_prepares`). A block that `f + 1` nodes vouch for is re-proposed
+/// unless there's a certificate from the view they vouch for it in or a later one; since at least
+/// one of them is honest, faulty nodes can't push a block through this way on their own.
+pub fn new_view_pre_prepares(
+    state: &PbftState,
+    view_changes: &[PbftViewChange],
+) -> Vec<PbftMessage> {
+    let mut selected: HashMap<u64, &PbftMessage> = HashMap::new();
+
+    for vc in view_changes {
+        for cert in vc.get_prepared_certificates() {
+            let pre_prepare = cert.get_pre_prepare();
+            let info = pre_prepare.get_info();
+            if info.get_seq_num() <= vc.get_info().get_seq_num()
+                || !prepared_certificate_is_valid(state, cert)
+            {
+                continue;
+            }
+
+            let higher_view = selected
+                .get(&info.get_seq_num())
+                .map(|existing| info.get_view() > existing.get_info().get_view())
+                .unwrap_or(true);
+            if higher_view {
+                selected.insert(info.get_seq_num(), pre_prepare);
+            }
+        }
+    }
+
+    if state.fast_path {
+        for (seq_num, (view, pre_prepare)) in vouched_pre_prepares(state, view_changes) {
+            let later_view = selected
+                .get(&seq_num)
+                .map(|existing| view > existing.get_info().get_view())
+                .unwrap_or(true);
+            if later_view {
+                selected.insert(seq_num, pre_prepare);
+            }
+        }
+    }
+
+    let mut pre_prepares: Vec<PbftMessage> = selected
+        .into_iter()
+        .map(|(seq_num, old)| {
+            let mut pre_prepare = PbftMessage::new();
+            pre_prepare.set_info(make_msg_info(
+                &PbftMessageType::PrePrepare,
+                state.view,
+                seq_num,
+                state.get_primary_peer_id(),
+            ));
+            pre_prepare.set_block(old.get_block().clone());
+            pre_prepare
+        })
+        .collect();
+    pre_prepares.sort_by_key(|pre_prepare| pre_prepare.get_info().get_seq_num());
+    pre_prepares
+}
+
+// For each sequence number, the block that at least `f + 1` of the `ViewChange`s vouch for with
+// their `accepted_pre_prepares`, if there is one, along with the view they vouch for it in: the
+// `f + 1`-th highest of the views its vouchers accepted it in, so that at least one honest node
+// accepted it in that view or a later one. Only honest nodes can tip a block over `f + 1`, and an
+// honest node only vouches for the block it accepted in the highest view, so at most one block
+// per sequence number can qualify once any block has been committed on the fast path.
+fn vouched_pre_prepares<'a>(
+    state: &PbftState,
+    view_changes: &'a [PbftViewChange],
+) -> HashMap<u64, (u64, &'a PbftMessage)> {
+    // The views each block was accepted in, by sequence number and block ID, one per node
+    let mut vouches: HashMap<(u64, &[u8]), (Vec<u64>, &PbftMessage)> = HashMap::new();
+    for vc in view_changes {
+        let mut vouched: HashSet<u64> = HashSet::new();
+        for pre_prepare in vc.get_accepted_pre_prepares() {
+            let info = pre_prepare.get_info();
+            let primary = state.get_primary_peer_id_for_view(info.get_view());
+            if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::PrePrepare
+                || info.get_seq_num() <= vc.get_info().get_seq_num()
+                || info.get_signer_id() != Vec::<u8>::from(primary).as_slice()
+                || !vouched.insert(info.get_seq_num())
+            {
+                continue;
+            }
+            vouches
+                .entry((info.get_seq_num(), pre_prepare.get_block().get_block_id()))
+                .or_insert_with(|| (Vec::new(), pre_prepare))
+                .0
+                .push(info.get_view());
+        }
+    }
+
+    let threshold = state.f as usize + 1;
+    let mut vouched = HashMap::new();
+    for ((seq_num, _), (mut views, pre_prepare)) in vouches {
+        if views.len() < threshold {
+            continue;
+        }
+        views.sort_by(|a, b| b.cmp(a));
+        let view = views[threshold - 1];
+
+        // Ties are broken by block ID, so that every node picks the same block
+        let preferred = vouched
+            .get(&seq_num)
+            .map(|&(existing_view, existing): &(u64, &PbftMessage)| {
+                (view, existing.get_block().get_block_id())
+                    > (existing_view, pre_prepare.get_block().get_block_id())
+            })
+            .unwrap_or(true);
+        if preferred {
+            vouched.insert(seq_num, (view, pre_prepare));
+        }
+    }
+    vouched
+}
+
+// A prepared certificate is valid if its `PrePrepare` came from the primary of the certificate's
+// view, and it has a quorum of `Prepare`s from different nodes that match the `PrePrepare`
+fn prepared_certificate_is_valid(state: &PbftState, cert: &PbftPreparedCertificate) -> bool {
+    let pre_prepare = cert.get_pre_prepare();
+    let info = pre_prepare.get_info();
+    if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::PrePrepare {
+        return false;
+    }
+
+    let primary = state.get_primary_peer_id_for_view(info.get_view());
+    if info.get_signer_id() != Vec::<u8>::from(primary).as_slice() {
+        return false;
+    }
+
+    let mut signers: HashSet<&[u8]> = HashSet::new();
+    for prepare in cert.get_prepare_messages() {
+        let prepare_info = prepare.get_info();
+        if PbftMessageType::from(prepare_info.get_msg_type()) != PbftMessageType::Prepare
+            || prepare_info.get_view() != info.get_view()
+            || prepare_info.get_seq_num() != info.get_seq_num()
+            || prepare.get_block().get_block_id() != pre_prepare.get_block().get_block_id()
+            || state
+                .get_node_id_from_bytes(prepare_info.get_signer_id())
+                .is_err()
+            || !signers.insert(prepare_info.get_signer_id())
+        {
+            return false;
+        }
+    }
+
+    signers.len() as u64 >= state.quorum()
+}
+
+// Find the `PrePrepare` whose block comes right after the current chain head; blocks that have
+// already been committed don't need to be re-proposed
+fn get_reproposal<S: ConsensusService + ?Sized>(
+    service: &mut S,
+    pre_prepares: &[PbftMessage],
+) -> Result<Option<PbftMessage>, PbftError> {
+    let head = service
+        .get_chain_head()
+        .map_err(|err| PbftError::ServiceError(String::from("Failed to get chain head"), err))?;
+
+    Ok(pre_prepares
+        .iter()
+        .find(|pre_prepare| pre_prepare.get_block().get_block_num() == head.block_num + 1)
+        .cloned())
+}
+
+/// Verify that a seal proves that its block was committed at its sequence number: it must contain
+/// `Commit` messages for that block and sequence number, all in the same view, from at least
+/// a quorum of different nodes in the network. If messages are signed, each `Commit` must come
+/// with its sender's signature, so the node that passed the seal on can't have made any up.
+pub fn verify_seal(state: &PbftState, seal: &PbftSeal) -> Result<(), PbftError> {
+    let members: Vec<Vec<u8>> = state.peers().iter().map(|peer| peer.to_vec()).collect();
+    seal::verify_seal_structure(
+        seal,
+        &members,
+        seal.get_block().get_block_id(),
+        state.quorum(),
+    )
+    .map_err(PbftError::from)?;
+
+    if state.authenticate_messages {
+        if seal.get_signed_commit_messages().len() != seal.get_commit_messages().len() {
+            return Err(PbftError::InvalidSignature);
+        }
+        let votes = seal
+            .get_commit_messages()
+            .iter()
+            .zip(seal.get_signed_commit_messages())
+            .map(|(commit, signed)| {
+                let commit_bytes = commit
+                    .write_to_bytes()
+                    .map_err(PbftError::SerializationError)?;
+                Ok((signed, commit_bytes, commit.get_info().get_signer_id()))
+            })
+            .collect::<Result<Vec<_>, PbftError>>()?;
+        authentication::verify_votes(&votes, state.verify_threads)?;
+    }
+
     Ok(())
 }
 
 // There should only be one block with a matching ID
-fn get_block_by_id(service: &mut Service, block_id: &BlockId) -> Option<Block> {
+fn get_block_by_id<S: ConsensusService + ?Sized>(
+    service: &mut S,
+    block_id: &BlockId,
+) -> Option<Block> {
     let blocks: Vec<Block> = service
         .get_blocks(vec![block_id.clone()])
         .unwrap_or_default()
@@ -371,6 +849,32 @@ pub fn make_msg_info(
     info
 }
 
+/// How many bytes a message's trace ID has; the same as an OpenTelemetry trace ID, so it can be
+/// used as one
+pub const TRACE_ID_LENGTH: usize = 16;
+
+/// Make a trace ID for a block that's being proposed. It's a hash of the block and the proposal
+/// rather than random, so that it doesn't use up a simulation's random numbers.
+pub fn new_trace_id(block_id: &[u8], proposer: &PeerId, view: u64, seq_num: u64) -> Vec<u8> {
+    let mut trace_id = Vec::with_capacity(TRACE_ID_LENGTH);
+    for half in 0..(TRACE_ID_LENGTH / 8) {
+        let mut hasher = DefaultHasher::new();
+        (half, block_id, proposer, view, seq_num).hash(&mut hasher);
+        let hash = hasher.finish();
+        trace_id.extend((0..8).map(|byte| (hash >> (8 * byte)) as u8));
+    }
+    trace_id
+}
+
+/// Write a trace ID the way it's shown in logs and traces
+pub fn display_trace_id(trace_id: &[u8]) -> String {
+    if trace_id.is_empty() {
+        String::from("none")
+    } else {
+        hex::encode(trace_id)
+    }
+}
+
 /// Make a PbftBlock out of a consensus Block (PBFT doesn't need to use all the information about
 /// the block - this keeps blocks lighter weight)
 pub fn pbft_block_from_block(block: Block) -> PbftBlock {
@@ -385,9 +889,13 @@ pub fn pbft_block_from_block(block: Block) -> PbftBlock {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use authentication::MessageSigner;
     use config;
     use crypto::digest::Digest;
     use crypto::sha2::Sha256;
+    use primary::PrimarySelection;
+    use protobuf::{self, RepeatedField};
+    use protos::pbft_message::PbftSignedMessage;
 
     fn mock_peer_id(num: u64) -> PeerId {
         let mut sha = Sha256::new();
@@ -454,6 +962,357 @@ mod tests {
         assert_eq!(state1.seq_num, 1);
     }
 
+    /// Make sure that a secondary doesn't start over on a block it already committed when the
+    /// `PrePrepare` for it shows up late
+    #[test]
+    fn pre_prepare_for_committed_block() {
+        let cfg = config::mock_config(4);
+        let mut state = PbftState::new(1, &cfg);
+        let mut log = PbftLog::new(&cfg);
+        state.seq_num = 1;
+        state.phase = PbftPhase::PrePreparing;
+
+        let block_new = mock_msg(&PbftMessageType::BlockNew, 0, 0, mock_block(1), 0);
+        log.add_message(block_new.clone());
+
+        let pre_prep_msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, mock_block(1), 0);
+        assert!(pre_prepare(&mut state, &mut log, &pre_prep_msg).is_err());
+        assert!(state.working_block.is_none());
+        assert_eq!(
+            log.get_messages_of_type(&PbftMessageType::BlockNew, 0, 0),
+            vec![&block_new]
+        );
+    }
+
+    /// Make sure that the new primary re-proposes the block from the highest-view valid prepared
+    /// certificate for each sequence number, and ignores invalid certificates
+    #[test]
+    fn test_new_view_pre_prepares() {
+        let cfg = config::mock_config(4);
+        let mut state = PbftState::new(2, &cfg);
+        state.view = 2;
+
+        let member_msg = |msg_type: &PbftMessageType, view, seq_num, block_num, from: usize| {
+            let info = make_msg_info(msg_type, view, seq_num, cfg.peers[from].clone());
+            let mut pbft_msg = PbftMessage::new();
+            pbft_msg.set_info(info);
+            pbft_msg.set_block(pbft_block_from_block(mock_block(block_num)));
+            pbft_msg
+        };
+        let cert = |view, seq_num, block_num, primary, prepares: usize| {
+            let mut cert = PbftPreparedCertificate::new();
+            cert.set_pre_prepare(member_msg(
+                &PbftMessageType::PrePrepare,
+                view,
+                seq_num,
+                block_num,
+                primary,
+            ));
+            for peer in 0..prepares {
+                cert.mut_prepare_messages().push(member_msg(
+                    &PbftMessageType::Prepare,
+                    view,
+                    seq_num,
+                    block_num,
+                    peer,
+                ));
+            }
+            cert
+        };
+        let vc = |checkpoint, certs: Vec<PbftPreparedCertificate>| {
+            let mut vc = PbftViewChange::new();
+            vc.set_info(make_msg_info(
+                &PbftMessageType::ViewChange,
+                2,
+                checkpoint,
+                cfg.peers[0].clone(),
+            ));
+            for c in certs {
+                vc.mut_prepared_certificates().push(c);
+            }
+            vc
+        };
+
+        let view_changes = vec![
+            // Seq 2 was prepared in view 0 with block 2, then in view 1 with block 3
+            vc(0, vec![cert(0, 2, 2, 0, 3), cert(0, 1, 1, 0, 3)]),
+            vc(0, vec![cert(1, 2, 3, 1, 3)]),
+            // Not enough Prepares, and not from the primary of view 0
+            vc(0, vec![cert(0, 4, 4, 0, 2), cert(0, 5, 5, 1, 3)]),
+            // Before this node's stable checkpoint
+            vc(6, vec![cert(0, 6, 6, 0, 3)]),
+        ];
+
+        let pre_prepares = new_view_pre_prepares(&state, &view_changes);
+        assert_eq!(pre_prepares.len(), 2);
+        assert_eq!(pre_prepares[0].get_info().get_seq_num(), 1);
+        assert_eq!(pre_prepares[0].get_block().get_block_num(), 1);
+        assert_eq!(pre_prepares[1].get_info().get_seq_num(), 2);
+        assert_eq!(pre_prepares[1].get_block().get_block_num(), 3);
+        for pre_prepare in &pre_prepares {
+            assert_eq!(pre_prepare.get_info().get_view(), 2);
+            assert_eq!(
+                pre_prepare.get_info().get_signer_id(),
+                Vec::<u8>::from(cfg.peers[2].clone()).as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_new_view() {
+        let cfg = config::mock_config(4);
+        let mut state = PbftState::new(0, &cfg);
+        state.view = 1;
+
+        let vc = |view, from: usize| {
+            let mut vc = PbftViewChange::new();
+            vc.set_info(make_msg_info(
+                &PbftMessageType::ViewChange,
+                view,
+                0,
+                cfg.peers[from].clone(),
+            ));
+            vc
+        };
+        let nv = |view, from: usize, view_changes: Vec<PbftViewChange>| {
+            let mut nv = PbftNewView::new();
+            nv.set_info(make_msg_info(
+                &PbftMessageType::NewView,
+                view,
+                0,
+                cfg.peers[from].clone(),
+            ));
+            nv.set_view_changes(RepeatedField::from_vec(view_changes));
+            nv
+        };
+
+        // Valid, including for a view this node hasn't reached yet
+        assert!(verify_new_view(&state, &nv(1, 1, vec![vc(1, 0), vc(1, 1), vc(1, 2)])).is_ok());
+        assert!(verify_new_view(&state, &nv(2, 2, vec![vc(2, 0), vc(2, 1), vc(2, 3)])).is_ok());
+
+        // ViewChange for the wrong view
+        assert!(verify_new_view(&state, &nv(1, 1, vec![vc(1, 0), vc(1, 1), vc(2, 2)])).is_err());
+
+        // Same node's ViewChange included twice
+        assert!(verify_new_view(&state, &nv(1, 1, vec![vc(1, 0), vc(1, 1), vc(1, 1)])).is_err());
+
+        // Too few ViewChanges
+        assert!(verify_new_view(&state, &nv(1, 1, vec![vc(1, 0), vc(1, 1)])).is_err());
+
+        // ViewChange from a node that isn't in the network
+        let mut outsider = vc(1, 0);
+        outsider
+            .mut_info()
+            .set_signer_id(Vec::<u8>::from(mock_peer_id(7)));
+        assert!(verify_new_view(&state, &nv(1, 1, vec![vc(1, 0), vc(1, 1), outsider])).is_err());
+    }
+
+    fn view_change_from(view: u64, signer_id: PeerId) -> PbftViewChange {
+        let mut vc = PbftViewChange::new();
+        vc.set_info(make_msg_info(
+            &PbftMessageType::ViewChange,
+            view,
+            0,
+            signer_id,
+        ));
+        vc
+    }
+
+    fn new_view_from(
+        view: u64,
+        signer_id: PeerId,
+        view_changes: Vec<PbftViewChange>,
+    ) -> PbftNewView {
+        let mut nv = PbftNewView::new();
+        nv.set_info(make_msg_info(&PbftMessageType::NewView, view, 0, signer_id));
+        nv.set_view_changes(RepeatedField::from_vec(view_changes));
+        nv
+    }
+
+    /// Make sure that a `NewView` for a view this node hasn't reached yet is only accepted from
+    /// the node that will be primary once this node enters the view, which depends on the
+    /// failures that entering it records
+    #[test]
+    fn verify_new_view_primary() {
+        let mut cfg = config::mock_config(4);
+        cfg.primary_selection = PrimarySelection::Reputation;
+        let state = PbftState::new(0, &cfg);
+        let view_changes: Vec<PbftViewChange> = (0..3)
+            .map(|i| view_change_from(2, cfg.peers[i].clone()))
+            .collect();
+
+        // Node 2 would be primary in view 2 if nobody had failed, but entering view 2 records
+        // failures for views 0 (node 0) and 1 (node 2, since node 0 is skipped), so node 1 is
+        assert_eq!(state.get_primary_peer_id_for_view(2), cfg.peers[2]);
+        assert_eq!(state.get_primary_peer_id_on_entering(2), cfg.peers[1]);
+        assert!(verify_new_view(
+            &state,
+            &new_view_from(2, cfg.peers[2].clone(), view_changes.clone())
+        )
+        .is_err());
+        assert!(verify_new_view(
+            &state,
+            &new_view_from(2, cfg.peers[1].clone(), view_changes.clone())
+        )
+        .is_ok());
+    }
+
+    /// Make sure that when messages are signed, a `NewView` is only accepted if every
+    /// `ViewChange` in it comes with its sender's signature
+    #[test]
+    fn verify_signed_new_view() {
+        let signers: Vec<MessageSigner> = (1..5)
+            .map(|i| MessageSigner::from_hex(&format!("{:064x}", i)).unwrap())
+            .collect();
+        let mut cfg = config::mock_config(4);
+        cfg.peers = signers.iter().map(MessageSigner::get_signer_id).collect();
+        cfg.authenticate_messages = true;
+        let state = PbftState::new(0, &cfg);
+
+        let view_changes: Vec<PbftViewChange> = (0..3)
+            .map(|i| view_change_from(1, cfg.peers[i].clone()))
+            .collect();
+        let signed: Vec<PbftSignedMessage> = view_changes
+            .iter()
+            .zip(&signers)
+            .map(|(vc, signer)| {
+                protobuf::parse_from_bytes(&signer.sign(vc.write_to_bytes().unwrap()).unwrap())
+                    .unwrap()
+            })
+            .collect();
+        let new_view = |signed: Vec<PbftSignedMessage>| {
+            let mut nv = new_view_from(1, cfg.peers[1].clone(), view_changes.clone());
+            nv.set_signed_view_changes(RepeatedField::from_vec(signed));
+            nv
+        };
+
+        assert!(verify_new_view(&state, &new_view(signed.clone())).is_ok());
+
+        // The signatures are missing, or one is for another node's ViewChange
+        assert!(verify_new_view(&state, &new_view(vec![])).is_err());
+        let mut swapped = signed.clone();
+        swapped.swap(0, 1);
+        assert!(verify_new_view(&state, &new_view(swapped)).is_err());
+
+        // A ViewChange was made up by the primary, which signed it itself
+        let forged: Vec<PbftSignedMessage> = signed
+            .iter()
+            .enumerate()
+            .map(|(i, signed)| {
+                let mut forged = signed.clone();
+                if i == 2 {
+                    let by_primary: PbftSignedMessage = protobuf::parse_from_bytes(
+                        &signers[1].sign(signed.get_message().to_vec()).unwrap(),
+                    )
+                    .unwrap();
+                    forged.set_signature(by_primary.get_signature().to_string());
+                }
+                forged
+            })
+            .collect();
+        assert!(verify_new_view(&state, &new_view(forged)).is_err());
+    }
+
+    #[test]
+    fn test_verify_seal() {
+        let cfg = config::mock_config(4);
+        let state = PbftState::new(0, &cfg);
+
+        let seal = |commits: Vec<PbftMessage>| {
+            let mut seal = PbftSeal::new();
+            seal.set_seq_num(3);
+            seal.set_block(pbft_block_from_block(mock_block(3)));
+            seal.set_commit_messages(RepeatedField::from_vec(commits));
+            seal
+        };
+        let commit = |view, seq_num, block_num, from: usize| {
+            let mut msg = PbftMessage::new();
+            msg.set_info(make_msg_info(
+                &PbftMessageType::Commit,
+                view,
+                seq_num,
+                cfg.peers[from].clone(),
+            ));
+            msg.set_block(pbft_block_from_block(mock_block(block_num)));
+            msg
+        };
+
+        // Two valid Commits, along with the given one
+        let check = |last: PbftMessage| {
+            verify_seal(
+                &state,
+                &seal(vec![commit(0, 3, 3, 0), commit(0, 3, 3, 1), last]),
+            )
+        };
+
+        assert!(check(commit(0, 3, 3, 2)).is_ok());
+
+        // Not enough different nodes
+        assert!(check(commit(0, 3, 3, 1)).is_err());
+
+        // Wrong sequence number, block, or view
+        assert!(check(commit(0, 4, 3, 2)).is_err());
+        assert!(check(commit(0, 3, 4, 2)).is_err());
+        assert!(check(commit(1, 3, 3, 2)).is_err());
+    }
+
+    #[test]
+    fn verify_signed_seal() {
+        let signers: Vec<MessageSigner> = (1..5)
+            .map(|i| MessageSigner::from_hex(&format!("{:064x}", i)).unwrap())
+            .collect();
+        let mut cfg = config::mock_config(4);
+        cfg.peers = signers.iter().map(MessageSigner::get_signer_id).collect();
+        cfg.authenticate_messages = true;
+        let state = PbftState::new(0, &cfg);
+
+        let commits: Vec<PbftMessage> = (0..3)
+            .map(|i| {
+                let mut msg = PbftMessage::new();
+                msg.set_info(make_msg_info(
+                    &PbftMessageType::Commit,
+                    0,
+                    3,
+                    cfg.peers[i].clone(),
+                ));
+                msg.set_block(pbft_block_from_block(mock_block(3)));
+                msg
+            })
+            .collect();
+        let signed: Vec<PbftSignedMessage> = commits
+            .iter()
+            .zip(&signers)
+            .map(|(commit, signer)| {
+                protobuf::parse_from_bytes(&signer.sign(commit.write_to_bytes().unwrap()).unwrap())
+                    .unwrap()
+            })
+            .collect();
+        let seal = |signed: Vec<PbftSignedMessage>| {
+            let mut seal = PbftSeal::new();
+            seal.set_seq_num(3);
+            seal.set_block(pbft_block_from_block(mock_block(3)));
+            seal.set_commit_messages(RepeatedField::from_vec(commits.clone()));
+            seal.set_signed_commit_messages(RepeatedField::from_vec(signed));
+            seal
+        };
+
+        assert!(verify_seal(&state, &seal(signed.clone())).is_ok());
+
+        // The signatures are missing, or one is for another node's Commit
+        assert!(verify_seal(&state, &seal(vec![])).is_err());
+        let mut swapped = signed.clone();
+        swapped.swap(0, 1);
+        assert!(verify_seal(&state, &seal(swapped)).is_err());
+
+        // A Commit was made up by the node that passed the seal on, which signed it itself
+        let mut forged = signed.clone();
+        let by_sender: PbftSignedMessage =
+            protobuf::parse_from_bytes(&signers[0].sign(signed[2].get_message().to_vec()).unwrap())
+                .unwrap();
+        forged[2].set_signature(by_sender.get_signature().to_string());
+        assert!(verify_seal(&state, &seal(forged)).is_err());
+    }
+
     #[test]
     fn test_multicast_hint() {
         let cfg = config::mock_config(4);
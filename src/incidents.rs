@@ -0,0 +1,148 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Problems that the node can't solve by itself, and that need an operator
+//!
+//! Most trouble a node runs into, it works its way out of: it changes views, catches up, or retries.
+//! Some conditions don't go away without someone stepping in, though: on-chain settings that can't
+//! be loaded or don't make sense, a node that has stopped committing blocks, or seals from other
+//! nodes that keep failing verification. `Incidents` keeps track of which of these are going on,
+//! so each one is reported once when it starts and once when it's over, rather than with every
+//! error it causes.
+//!
+//! The consensus API doesn't give an engine a way to report problems to the validator; its
+//! `Service` only covers blocks, messages, settings, and state. So an incident is reported
+//! through everything the node has for operational tooling: an error in the log, an
+//! `incident_started` event on the events socket, a problem in the node's status, a record in the
+//! audit trail, and an alert, if alerting on incidents is enabled.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How many seals in a row have to fail verification before it's reported as an incident; one
+/// bad seal may be a single faulty node, but a run of them means this node's idea of the
+/// network's membership may be wrong
+pub const INVALID_SEALS_BEFORE_INCIDENT: u32 = 5;
+
+/// A condition that needs an operator's attention
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Incident {
+    /// The on-chain settings couldn't be loaded, or aren't valid
+    InvalidSettings,
+
+    /// The node hasn't committed a block in a long time, though it has blocks to commit
+    Stuck,
+
+    /// Seals from other nodes keep failing verification
+    InvalidSeals,
+}
+
+impl Incident {
+    /// The name used for the incident in events, alerts, and the audit trail
+    pub fn name(self) -> &'static str {
+        match self {
+            Incident::InvalidSettings => "invalid_settings",
+            Incident::Stuck => "stuck",
+            Incident::InvalidSeals => "invalid_seals",
+        }
+    }
+}
+
+impl fmt::Display for Incident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let incident = match self {
+            Incident::InvalidSettings => "on-chain settings can't be used",
+            Incident::Stuck => "not making progress",
+            Incident::InvalidSeals => "seals from other nodes keep failing verification",
+        };
+        write!(f, "{}", incident)
+    }
+}
+
+/// The incidents that are going on, with what's known about each one
+#[derive(Debug, Default)]
+pub struct Incidents {
+    ongoing: BTreeMap<Incident, String>,
+
+    /// How many seals in a row have failed verification
+    invalid_seals: u32,
+}
+
+impl Incidents {
+    pub fn new() -> Self {
+        Incidents::default()
+    }
+
+    /// Note that the given incident is going on, with the latest details of it. Returns whether
+    /// it just started.
+    pub fn start(&mut self, incident: Incident, detail: String) -> bool {
+        self.ongoing.insert(incident, detail).is_none()
+    }
+
+    /// Note that the given incident is over. Returns whether it was going on.
+    pub fn end(&mut self, incident: Incident) -> bool {
+        self.ongoing.remove(&incident).is_some()
+    }
+
+    pub fn is_ongoing(&self, incident: Incident) -> bool {
+        self.ongoing.contains_key(&incident)
+    }
+
+    /// The incidents that are going on, with their latest details
+    pub fn ongoing(&self) -> &BTreeMap<Incident, String> {
+        &self.ongoing
+    }
+
+    /// Count a seal that failed verification. Returns whether enough have failed in a row for
+    /// it to be an incident.
+    pub fn count_invalid_seal(&mut self) -> bool {
+        self.invalid_seals = self.invalid_seals.saturating_add(1);
+        self.invalid_seals >= INVALID_SEALS_BEFORE_INCIDENT
+    }
+
+    /// Note that a seal passed verification, which ends a run of invalid ones
+    pub fn count_valid_seal(&mut self) {
+        self.invalid_seals = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that an incident only starts and ends once however often it's noted, and that it
+    /// takes a run of invalid seals to make an incident
+    #[test]
+    fn incidents() {
+        let mut incidents = Incidents::new();
+        assert!(incidents.start(Incident::Stuck, String::from("first")));
+        assert!(!incidents.start(Incident::Stuck, String::from("second")));
+        assert!(incidents.is_ongoing(Incident::Stuck));
+        assert_eq!(incidents.ongoing()[&Incident::Stuck], "second");
+        assert!(incidents.end(Incident::Stuck));
+        assert!(!incidents.end(Incident::Stuck));
+
+        for _ in 1..INVALID_SEALS_BEFORE_INCIDENT {
+            assert!(!incidents.count_invalid_seal());
+        }
+        incidents.count_valid_seal();
+        for _ in 1..INVALID_SEALS_BEFORE_INCIDENT {
+            assert!(!incidents.count_invalid_seal());
+        }
+        assert!(incidents.count_invalid_seal());
+    }
+}
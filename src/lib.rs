@@ -0,0 +1,180 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Sawtooth PBFT as a library
+//!
+//! With the `engine` feature (on by default), the library has the whole consensus engine, which
+//! the `sawtooth-pbft` binary runs on the validator's ZMQ connection. Other projects can embed it
+//! in their own process instead, by feeding a `PbftEngine` updates over a channel and giving it a
+//! `Service` to make calls with; see `PbftEngine::spawn`. Given a channel with
+//! `PbftEngine::with_event_sender`, the engine sends the embedding process a `ConsensusEvent` for
+//! everything that happens to the node, including `ConsensusEvent::BlockFinalized` for every block
+//! as soon as it's final.
+//!
+//! With the `seal-verification` feature, block explorers and light clients can check that a
+//! block's seal proves it was committed with `verify_consensus_seal`: the seal must have `Commit`s
+//! for the block from enough members, each signed by the member it's from. Build the library with
+//! `default-features = false` to leave out the engine's dependencies, including the Sawtooth SDK;
+//! signatures are checked with the `secp256k1` crate instead.
+//!
+//! The protocol's core rules (quorum sizes, the order of the phases, and which messages a node can
+//! use yet) are in `protocol`, which is there with or without any features. With
+//! `default-features = false` and no other features, that's all the library has, so it builds
+//! without protoc, the Sawtooth SDK, or any other dependency; `cargo test --no-default-features`
+//! runs the protocol core's tests on their own.
+
+#[cfg(all(feature = "engine", test))]
+extern crate crypto;
+#[cfg(feature = "grpc-admin")]
+extern crate futures;
+#[cfg(feature = "grpc-admin")]
+extern crate grpcio;
+#[cfg(any(feature = "engine", feature = "seal-verification"))]
+extern crate hex;
+#[cfg(feature = "engine")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "protos")]
+extern crate protobuf;
+#[cfg(feature = "protos")]
+extern crate sawtooth_pbft_protos;
+#[cfg(feature = "engine")]
+extern crate sawtooth_sdk;
+#[cfg(any(feature = "engine", feature = "seal-verification"))]
+extern crate secp256k1;
+#[cfg(feature = "engine")]
+extern crate serde_json;
+#[cfg(any(feature = "engine", feature = "seal-verification"))]
+extern crate sha2;
+#[cfg(feature = "engine")]
+extern crate tracing;
+#[cfg(feature = "engine")]
+extern crate tracing_subscriber;
+
+#[cfg(feature = "engine")]
+pub mod admin;
+#[cfg(feature = "grpc-admin")]
+pub mod admin_grpc;
+#[cfg(feature = "engine")]
+pub mod alerts;
+#[cfg(feature = "engine")]
+pub mod authentication;
+#[cfg(feature = "engine")]
+pub mod circuit_breaker;
+#[cfg(feature = "engine")]
+pub mod config;
+#[cfg(feature = "engine")]
+pub mod consensus_service;
+#[cfg(feature = "engine")]
+pub mod crash_dump;
+#[cfg(feature = "engine")]
+pub mod doctor;
+#[cfg(feature = "engine")]
+pub mod engine;
+#[cfg(feature = "engine")]
+pub mod error;
+#[cfg(feature = "engine")]
+pub mod events;
+#[cfg(all(feature = "engine", feature = "test-faults"))]
+pub mod faults;
+#[cfg(feature = "engine")]
+pub mod finality;
+#[cfg(feature = "engine")]
+pub mod handlers;
+#[cfg(feature = "engine")]
+pub mod incidents;
+#[cfg(feature = "engine")]
+pub mod log_throttle;
+#[cfg(feature = "engine")]
+pub mod message_extensions;
+#[cfg(feature = "engine")]
+pub mod message_log;
+#[cfg(feature = "engine")]
+pub mod message_type;
+#[cfg(feature = "engine")]
+pub mod metrics;
+#[cfg(feature = "engine")]
+pub mod networks;
+#[cfg(feature = "engine")]
+pub mod node;
+#[cfg(feature = "engine")]
+pub mod otlp;
+#[cfg(feature = "engine")]
+pub mod outbox;
+#[cfg(feature = "engine")]
+pub mod peer_stats;
+#[cfg(feature = "engine")]
+pub mod pipeline;
+#[cfg(feature = "engine")]
+pub mod primary;
+pub mod protocol;
+#[cfg(feature = "protos")]
+pub mod protos;
+#[cfg(feature = "engine")]
+pub mod quarantine;
+#[cfg(feature = "engine")]
+pub mod rate_limit;
+#[cfg(feature = "engine")]
+pub mod recent_log;
+#[cfg(feature = "engine")]
+pub mod replay;
+#[cfg(feature = "engine")]
+pub mod retry;
+#[cfg(feature = "engine")]
+pub mod seal;
+#[cfg(all(feature = "seal-verification", not(feature = "engine")))]
+mod seal;
+#[cfg(feature = "engine")]
+pub mod signature;
+#[cfg(all(feature = "seal-verification", not(feature = "engine")))]
+mod signature;
+#[cfg(all(feature = "engine", any(test, feature = "simulator")))]
+pub mod simulation;
+#[cfg(feature = "engine")]
+pub mod state;
+#[cfg(feature = "engine")]
+pub mod status;
+#[cfg(feature = "engine")]
+pub mod storage;
+#[cfg(feature = "engine")]
+pub mod timing;
+#[cfg(feature = "engine")]
+pub mod traced_service;
+#[cfg(feature = "engine")]
+pub mod update_queue;
+#[cfg(feature = "engine")]
+pub mod validation;
+#[cfg(feature = "engine")]
+pub mod view_stats;
+#[cfg(feature = "engine")]
+pub mod votes;
+#[cfg(feature = "engine")]
+pub mod watchdog;
+
+#[cfg(feature = "engine")]
+pub use engine::{EngineHandle, PbftEngine};
+#[cfg(feature = "engine")]
+pub use events::ConsensusEvent;
+#[cfg(feature = "engine")]
+pub use finality::FinalityRecord;
+#[cfg(feature = "engine")]
+pub use networks::{NetworkError, Networks};
+#[cfg(feature = "seal-verification")]
+pub use seal::{
+    default_quorum, parse_seal, verify_consensus_seal, verify_consensus_seal_with_quorum,
+    SealError, SEAL_VERSION,
+};
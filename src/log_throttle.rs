@@ -0,0 +1,128 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Throttling for log messages that can repeat many times a second
+//!
+//! A faulty node that keeps sending bad messages, or a peer that can't be reached while messages
+//! for it are retried, would otherwise fill the log with the same line. A `LogThrottle` lets the
+//! first message for each key through, then counts the similar ones that follow within
+//! `THROTTLE_INTERVAL` instead of logging them; the next message that gets through says how many
+//! were suppressed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use timing;
+
+/// How long after a message is logged that similar messages are suppressed
+const THROTTLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many keys are remembered before the ones with nothing suppressed are forgotten
+const MAX_KEYS: usize = 1000;
+
+/// Decides which of a kind of repeated log message to log
+#[derive(Debug)]
+pub struct LogThrottle<K: Hash + Eq> {
+    /// For each key, when a message was last logged and how many have been suppressed since
+    keys: HashMap<K, (Instant, u64)>,
+}
+
+impl<K: Hash + Eq> LogThrottle<K> {
+    pub fn new() -> Self {
+        LogThrottle {
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Check whether a message with the given key should be logged now. If so, the returned
+    /// `Suppressed` is to be written after it, to say how many similar messages weren't logged.
+    pub fn check(&mut self, key: K) -> Option<Suppressed> {
+        let now = timing::now();
+
+        if let Some(&mut (ref mut logged, ref mut suppressed)) = self.keys.get_mut(&key) {
+            if now - *logged < THROTTLE_INTERVAL {
+                *suppressed += 1;
+                return None;
+            }
+            let count = *suppressed;
+            *logged = now;
+            *suppressed = 0;
+            return Some(Suppressed(count));
+        }
+
+        if self.keys.len() >= MAX_KEYS {
+            self.keys.retain(|_, &mut (logged, suppressed)| {
+                suppressed > 0 || now - logged < THROTTLE_INTERVAL
+            });
+        }
+        self.keys.insert(key, (now, 0));
+        Some(Suppressed(0))
+    }
+}
+
+impl<K: Hash + Eq> Default for LogThrottle<K> {
+    fn default() -> Self {
+        LogThrottle::new()
+    }
+}
+
+/// How many similar messages were suppressed before one that is logged; displayed as a note to
+/// put at the end of the message, or as nothing if none were
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Suppressed(pub u64);
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            1 => write!(f, " (suppressed 1 similar message)"),
+            count => write!(f, " (suppressed {} similar messages)", count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that only one message per key is let through each interval, and that the next
+    /// one says how many were suppressed
+    #[test]
+    fn log_throttle() {
+        timing::start_virtual_time(1);
+        let mut throttle = LogThrottle::new();
+
+        assert_eq!(throttle.check("a"), Some(Suppressed(0)));
+        assert_eq!(throttle.check("a"), None);
+        assert_eq!(throttle.check("a"), None);
+        assert_eq!(throttle.check("b"), Some(Suppressed(0)));
+
+        timing::set_virtual_time(timing::now() + THROTTLE_INTERVAL);
+        let suppressed = throttle.check("a").unwrap();
+        assert_eq!(suppressed, Suppressed(2));
+        assert_eq!(
+            format!("Bad message{}", suppressed),
+            "Bad message (suppressed 2 similar messages)"
+        );
+        assert_eq!(format!("{}", Suppressed(0)), "");
+        assert_eq!(throttle.check("b"), Some(Suppressed(0)));
+
+        timing::stop_virtual_time();
+    }
+}
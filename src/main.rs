@@ -21,41 +21,115 @@
 
 #[macro_use]
 extern crate clap;
-#[cfg(test)]
-extern crate crypto;
 #[macro_use]
 extern crate log;
-extern crate hex;
-extern crate protobuf;
+extern crate pbft;
 extern crate sawtooth_sdk;
-extern crate serde_json;
-extern crate simple_logger;
+extern crate tracing;
+extern crate tracing_subscriber;
 
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use pbft::retry::RetryStrategy;
+use pbft::{crash_dump, doctor, engine, otlp, recent_log};
 use sawtooth_sdk::consensus::zmq_driver::ZmqDriver;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
 
-pub mod config;
-pub mod engine;
-pub mod error;
-pub mod handlers;
-pub mod message_extensions;
-pub mod message_log;
-pub mod message_type;
-pub mod node;
-mod protos;
-pub mod state;
-pub mod timing;
+/// How long to wait before registering with the validator again after losing the connection to
+/// it: 1s, then twice as long after each failed registration, up to 30s
+const REREGISTER_STRATEGY: RetryStrategy = RetryStrategy::Exponential {
+    initial: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+};
+
+/// How long a registration has to last for the next wait to start from the beginning again
+const REREGISTER_RESET: Duration = Duration::from_secs(60);
 
 fn main() {
-    let matches = clap_app!(sawtooth_pbft =>
+    let app = clap_app!(sawtooth_pbft =>
         (version: crate_version!())
         (about: "PBFT consensus for Sawtooth")
         (@arg connect: -C --connect +takes_value
          "connection endpoint for validator")
         (@arg verbose: -v --verbose +multiple
-         "increase output verbosity"))
-        .get_matches();
+         "increase output verbosity")
+        (@arg crash_dump_dir: --crash_dump_dir +takes_value
+         "directory to write the node's state and message log to on fatal errors and panics")
+        (@arg state_dir: --state_dir +takes_value
+         "directory to save the node's view change progress in, so it resumes after a restart")
+        (@arg signing_key: --signing_key +takes_value
+         "private key file to sign messages with, if message authentication is enabled")
+        (@arg metrics_address: --metrics_address +takes_value
+         "address to serve Prometheus metrics on, such as 127.0.0.1:9184")
+        (@arg admin_address: --admin_address +takes_value
+         "address to take operators' commands on over HTTP, such as 127.0.0.1:9185; needs \
+          --admin_token_file")
+        (@arg admin_token_file: --admin_token_file +takes_value
+         "file with the token that clients must send to run operators' commands")
+        (@arg influx_address: --influx_address +takes_value
+         "host:port of an InfluxDB server to send metrics to")
+        (@arg influx_db: --influx_db +takes_value
+         "InfluxDB database to send metrics to (default: metrics)")
+        (@arg events_socket: --events_socket +takes_value
+         "Unix socket to publish consensus events on, as lines of JSON")
+        (@arg alerts: --alerts +takes_value
+         "JSON file describing consensus anomalies to alert on, and the webhook or command to alert with")
+        (@arg otlp_endpoint: --otlp_endpoint +takes_value
+         "host:port of an OTLP/HTTP receiver to send metrics and traces to")
+        (@arg summary_interval: --summary_interval +takes_value
+         "seconds between INFO summaries of the node's state, or 0 for none (default: 60)")
+        (@arg watchdog_interval: --watchdog_interval +takes_value
+         "seconds the event loop can go without making progress before it's reported, or 0 to not \
+          watch it; must be more than message_timeout (default: 60)")
+        (@arg watchdog_abort: --watchdog_abort
+         "abort when the event loop is reported, so a supervisor can restart the node")
+        (@arg exit_on_disconnect: --exit_on_disconnect
+         "exit when the connection to the validator is lost, instead of registering with it again")
+        (@arg decode_threads: --decode_threads +takes_value
+         "threads to decode and verify messages from other nodes on, or 0 to do it on the event \
+          loop (default: 0)")
+        (@arg trace_filter: --trace_filter +takes_value
+         "write timings of tracing spans matching the filter, such as pbft=debug, to stderr")
+        (@subcommand doctor =>
+         (about: "check that the validator, state directory, signing key, and on-chain settings are ready for this node, then exit")));
+
+    let app = app.arg(
+        clap::Arg::with_name("network")
+            .long("network")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "name=endpoint of a network to run consensus for, connecting to its validator at \
+                 the endpoint; may be given more than once (default: one network, at --connect)",
+            ),
+    );
+
+    #[cfg(feature = "test-faults")]
+    let app = app.arg(
+        clap::Arg::with_name("fault_scenario")
+            .long("fault_scenario")
+            .takes_value(true)
+            .help("JSON file describing faulty behavior for this node to exhibit"),
+    );
+
+    #[cfg(feature = "grpc-admin")]
+    let app = app.arg(
+        clap::Arg::with_name("admin_grpc_address")
+            .long("admin_grpc_address")
+            .takes_value(true)
+            .help(
+                "address to take operators' commands on over gRPC, such as 127.0.0.1:9186; needs \
+                 --admin_token_file",
+            ),
+    );
+
+    let matches = app.get_matches();
 
     let log_level = match matches.occurrences_of("verbose") {
         0 => log::Level::Warn,
@@ -70,16 +144,338 @@ fn main() {
             .unwrap_or("tcp://localhost:5050"),
     );
 
-    simple_logger::init_with_level(log_level).expect("Unable to initialize logger");
+    let recent_log = recent_log::RecentLog::init(log_level).expect("Unable to initialize logger");
+
+    if matches.subcommand_matches("doctor").is_some() {
+        let report = doctor::run(
+            &endpoint,
+            matches.value_of("state_dir").map(Path::new),
+            matches.value_of("crash_dump_dir").map(Path::new),
+            matches.value_of("signing_key").map(Path::new),
+        );
+        print!("{}", report);
+        process::exit(if report.passed() { 0 } else { 1 });
+    }
+
+    let trace_filter = |directives: &str| {
+        tracing_subscriber::EnvFilter::try_new(directives).unwrap_or_else(|err| {
+            error!("Invalid trace filter {}: {}", directives, err);
+            process::exit(1);
+        })
+    };
+
+    let otlp_exporter = matches
+        .value_of("otlp_endpoint")
+        .map(|addr| otlp::OtlpExporter::new(String::from(addr)));
+
+    if let Some(ref exporter) = otlp_exporter {
+        // Spans are exported whether or not their timings are also written to stderr
+        let directives = matches
+            .value_of("trace_filter")
+            .unwrap_or("pbft=debug");
+        let subscriber = tracing_subscriber::registry()
+            .with(trace_filter(directives))
+            .with(exporter.span_layer());
+        if matches.is_present("trace_filter") {
+            let subscriber = subscriber.with(
+                tracing_subscriber::fmt::layer()
+                    .with_span_events(FmtSpan::CLOSE)
+                    .with_writer(io::stderr),
+            );
+            tracing::subscriber::set_global_default(subscriber)
+                .expect("Unable to initialize tracing");
+        } else {
+            tracing::subscriber::set_global_default(subscriber)
+                .expect("Unable to initialize tracing");
+        }
+    } else if let Some(directives) = matches.value_of("trace_filter") {
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(trace_filter(directives))
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(io::stderr)
+            .finish();
+        tracing::subscriber::set_global_default(subscriber).expect("Unable to initialize tracing");
+    }
 
     warn!("Sawtooth PBFT Engine ({})", env!("CARGO_PKG_VERSION"));
 
-    let pbft_engine = engine::PbftEngine::new();
+    let crash_dump_dir = matches.value_of("crash_dump_dir").map(PathBuf::from);
+    if let Some(ref dir) = crash_dump_dir {
+        crash_dump::install_panic_hook(dir.clone(), recent_log);
+    }
+
+    let signing_key = matches.value_of("signing_key").map(PathBuf::from);
+
+    let networks = networks(&matches);
+    if networks.len() > 1 {
+        for option in &[
+            "metrics_address",
+            "admin_address",
+            "admin_grpc_address",
+            "events_socket",
+            "otlp_endpoint",
+        ] {
+            if matches.is_present(option) {
+                error!("--{} can't be shared by more than one --network", option);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Each network's engine has its own state and crash dumps, in a directory named after it
+    let mut otlp_exporter = otlp_exporter;
+    let mut build_engine = |network: Option<&str>| {
+        let pbft_engine = engine::PbftEngine::new(
+            in_network_dir(crash_dump_dir.clone(), network),
+            signing_key.clone(),
+        );
+
+        let pbft_engine = match network {
+            Some(network) => pbft_engine.with_network(String::from(network)),
+            None => pbft_engine,
+        };
+
+        let pbft_engine =
+            match in_network_dir(matches.value_of("state_dir").map(PathBuf::from), network) {
+                Some(dir) => pbft_engine.with_state_dir(dir),
+                None => pbft_engine,
+            };
+
+        let pbft_engine = match matches.value_of("metrics_address") {
+            Some(addr) => pbft_engine.with_metrics_address(addr.parse().unwrap_or_else(|err| {
+                error!("Invalid metrics address {}: {}", addr, err);
+                process::exit(1);
+            })),
+            None => pbft_engine,
+        };
+
+        let pbft_engine = match (
+            matches.value_of("admin_address"),
+            matches.value_of("admin_token_file"),
+        ) {
+            (Some(addr), Some(token_file)) => pbft_engine.with_admin(
+                addr.parse().unwrap_or_else(|err| {
+                    error!("Invalid admin address {}: {}", addr, err);
+                    process::exit(1);
+                }),
+                PathBuf::from(token_file),
+            ),
+            (Some(_), None) => {
+                error!(
+                    "--admin_address needs --admin_token_file, so commands can be authenticated"
+                );
+                process::exit(1);
+            }
+            _ => pbft_engine,
+        };
+
+        #[cfg(feature = "grpc-admin")]
+        let pbft_engine = match (
+            matches.value_of("admin_grpc_address"),
+            matches.value_of("admin_token_file"),
+        ) {
+            (Some(addr), Some(token_file)) => pbft_engine.with_admin_grpc(
+                addr.parse().unwrap_or_else(|err| {
+                    error!("Invalid admin gRPC address {}: {}", addr, err);
+                    process::exit(1);
+                }),
+                PathBuf::from(token_file),
+            ),
+            (Some(_), None) => {
+                error!(
+                    "--admin_grpc_address needs --admin_token_file, so calls can be authenticated"
+                );
+                process::exit(1);
+            }
+            _ => pbft_engine,
+        };
 
-    let (driver, _stop) = ZmqDriver::new();
+        let pbft_engine = match matches.value_of("events_socket") {
+            Some(path) => pbft_engine.with_events_socket(PathBuf::from(path)),
+            None => pbft_engine,
+        };
 
-    driver.start(&endpoint, pbft_engine).unwrap_or_else(|err| {
-        error!("{}", err);
+        let pbft_engine = match matches.value_of("alerts") {
+            Some(path) => pbft_engine.with_alerts(PathBuf::from(path)),
+            None => pbft_engine,
+        };
+
+        let pbft_engine = match matches.value_of("influx_address") {
+            Some(addr) => pbft_engine.with_influx_reporter(
+                String::from(addr),
+                String::from(matches.value_of("influx_db").unwrap_or("metrics")),
+            ),
+            None => pbft_engine,
+        };
+
+        let pbft_engine = match matches.value_of("summary_interval") {
+            Some(secs) => match secs.parse() {
+                Ok(0) => pbft_engine.with_summary_interval(None),
+                Ok(secs) => pbft_engine.with_summary_interval(Some(Duration::from_secs(secs))),
+                Err(err) => {
+                    error!("Invalid summary interval {}: {}", secs, err);
+                    process::exit(1);
+                }
+            },
+            None => pbft_engine,
+        };
+
+        let pbft_engine = match matches.value_of("watchdog_interval") {
+            Some(secs) => match secs.parse() {
+                Ok(0) => pbft_engine.with_watchdog_interval(None),
+                Ok(secs) => pbft_engine.with_watchdog_interval(Some(Duration::from_secs(secs))),
+                Err(err) => {
+                    error!("Invalid watchdog interval {}: {}", secs, err);
+                    process::exit(1);
+                }
+            },
+            None => pbft_engine,
+        };
+
+        let pbft_engine = if matches.is_present("watchdog_abort") {
+            pbft_engine.with_watchdog_abort()
+        } else {
+            pbft_engine
+        };
+
+        let pbft_engine = match matches.value_of("decode_threads") {
+            Some(threads) => match threads.parse() {
+                Ok(threads) => pbft_engine.with_decode_threads(threads),
+                Err(err) => {
+                    error!("Invalid number of decode threads {}: {}", threads, err);
+                    process::exit(1);
+                }
+            },
+            None => pbft_engine,
+        };
+
+        let pbft_engine = match otlp_exporter.take() {
+            Some(exporter) => pbft_engine.with_otlp_exporter(exporter),
+            None => pbft_engine,
+        };
+
+        #[cfg(feature = "test-faults")]
+        let pbft_engine = match matches.value_of("fault_scenario") {
+            Some(path) => pbft_engine.with_fault_scenario(PathBuf::from(path)),
+            None => pbft_engine,
+        };
+
+        pbft_engine
+    };
+
+    let reregister = !matches.is_present("exit_on_disconnect");
+
+    if networks.is_empty() {
+        if !run(&endpoint, build_engine(None), reregister) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Each network gets a connection to its own validator, on a thread of its own
+    let drivers: Vec<(String, thread::JoinHandle<bool>)> = networks
+        .into_iter()
+        .map(|(network, endpoint)| {
+            let pbft_engine = build_engine(Some(&network));
+            let name = network.clone();
+            let driver = thread::Builder::new()
+                .name(format!("pbft-{}", network))
+                .spawn(move || {
+                    let ok = run(&endpoint, pbft_engine, reregister);
+                    if !ok {
+                        error!("Network {} lost its validator", name);
+                    }
+                    ok
+                })
+                .unwrap_or_else(|err| {
+                    error!("Couldn't start a thread for network {}: {}", network, err);
+                    process::exit(1);
+                });
+            (network, driver)
+        })
+        .collect();
+
+    let mut failed = false;
+    for (network, driver) in drivers {
+        if !driver.join().unwrap_or(false) {
+            error!("Network {} stopped with an error", network);
+            failed = true;
+        }
+    }
+    if failed {
         process::exit(1);
-    });
+    }
+}
+
+// Register the engine with the validator at the given endpoint, and run it until the validator
+// shuts it down. If the connection to the validator is lost (because the validator restarted, for
+// instance) the engine registers again, unless `reregister` is false. Returns false if the engine
+// stopped because of an error.
+fn run(endpoint: &str, mut pbft_engine: engine::PbftEngine, reregister: bool) -> bool {
+    let mut failures = 0;
+    loop {
+        let registered = Instant::now();
+        let (driver, _stop) = ZmqDriver::new();
+        let err = match driver.start(endpoint, &mut pbft_engine) {
+            Ok(()) => return true,
+            Err(err) => err,
+        };
+        if !reregister {
+            error!("{}", err);
+            return false;
+        }
+
+        if registered.elapsed() >= REREGISTER_RESET {
+            failures = 0;
+        }
+        let delay = REREGISTER_STRATEGY.delay(failures);
+        failures = failures.saturating_add(1);
+        warn!(
+            "Lost the connection to the validator at {}; registering again in {:?}: {}",
+            endpoint, delay, err
+        );
+        thread::sleep(delay);
+    }
+}
+
+// The networks to run consensus for, by name, with their validators' endpoints, from `--network`
+fn networks(matches: &clap::ArgMatches) -> Vec<(String, String)> {
+    let mut networks: Vec<(String, String)> = vec![];
+    for value in matches.values_of("network").into_iter().flatten() {
+        let mut parts = value.splitn(2, '=');
+        let (name, endpoint) = match (parts.next(), parts.next()) {
+            (Some(name), Some(endpoint)) if !name.is_empty() && !endpoint.is_empty() => {
+                (name, endpoint)
+            }
+            _ => {
+                error!("Invalid network {}; expected name=endpoint", value);
+                process::exit(1);
+            }
+        };
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            error!(
+                "Invalid network name {}; only letters, digits, - and _ are allowed",
+                name
+            );
+            process::exit(1);
+        }
+        if networks.iter().any(|&(ref other, _)| other == name) {
+            error!("Network {} is given more than once", name);
+            process::exit(1);
+        }
+        networks.push((String::from(name), String::from(endpoint)));
+    }
+    networks
+}
+
+// The given directory's subdirectory for the given network, or the directory itself if there's
+// only one network
+fn in_network_dir(dir: Option<PathBuf>, network: Option<&str>) -> Option<PathBuf> {
+    match network {
+        Some(network) => dir.map(|dir| dir.join(network)),
+        None => dir,
+    }
 }
@@ -16,14 +16,19 @@
  */
 
 //! Extensions for the Protobuf-defined message types
+//!
+//! `Eq` and `Hash` for the messages are implemented in the `sawtooth-pbft-protos` crate, along
+//! with the messages themselves, since they can't be implemented for another crate's types here.
 
-// We know that the property `k1 == k2 ==>  hash(k1) == hash(k2)` holds, since protobuf just compares
-// every field in the struct and that's exactly what the implementation of Hash is doing below
-#![allow(unknown_lints, derive_hash_xor_eq)]
+use protobuf::{self, Message};
 
-use std::hash::{Hash, Hasher};
+use protos::pbft_message::{
+    PbftBlock, PbftClockSync, PbftMessage, PbftMessageInfo, PbftNewView, PbftPreparedCertificate,
+    PbftRetransmitRequest, PbftSeal, PbftStateRequest, PbftStateResponse, PbftViewChange,
+};
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use error::PbftError;
+use message_type::PbftMessageType;
 
 // All message types that have "info" inside of them
 pub trait PbftGetInfo<'a> {
@@ -42,40 +47,203 @@ impl<'a> PbftGetInfo<'a> for &'a PbftViewChange {
     }
 }
 
-impl Eq for PbftMessage {}
-impl Eq for PbftViewChange {}
+impl<'a> PbftGetInfo<'a> for &'a PbftNewView {
+    fn get_msg_info(&self) -> &'a PbftMessageInfo {
+        self.get_info()
+    }
+}
+
+/// Get the info of a serialized message of the given type
+pub fn parse_msg_info(
+    msg_type: &PbftMessageType,
+    msg_bytes: &[u8],
+) -> Result<PbftMessageInfo, PbftError> {
+    let info = match msg_type {
+        PbftMessageType::ViewChange => {
+            protobuf::parse_from_bytes::<PbftViewChange>(msg_bytes).map(|mut msg| msg.take_info())
+        }
+        PbftMessageType::NewView => {
+            protobuf::parse_from_bytes::<PbftNewView>(msg_bytes).map(|mut msg| msg.take_info())
+        }
+        PbftMessageType::StateRequest => {
+            protobuf::parse_from_bytes::<PbftStateRequest>(msg_bytes).map(|mut msg| msg.take_info())
+        }
+        PbftMessageType::StateResponse => {
+            protobuf::parse_from_bytes::<PbftStateResponse>(msg_bytes)
+                .map(|mut msg| msg.take_info())
+        }
+        PbftMessageType::RetransmitRequest => {
+            protobuf::parse_from_bytes::<PbftRetransmitRequest>(msg_bytes)
+                .map(|mut msg| msg.take_info())
+        }
+        PbftMessageType::ClockSync | PbftMessageType::ClockSyncResponse => {
+            protobuf::parse_from_bytes::<PbftClockSync>(msg_bytes).map(|mut msg| msg.take_info())
+        }
+        _ => protobuf::parse_from_bytes::<PbftMessage>(msg_bytes).map(|mut msg| msg.take_info()),
+    };
+    info.map_err(PbftError::SerializationError)
+}
+
+/// Check that a serialized message of the given type is in its canonical encoding, which is the
+/// one this node produces: fields in field number order, each at most once, fields with default
+/// values left out, and no fields that the message's definition doesn't have (including in the
+/// messages inside it). A message that isn't can't be re-encoded to the bytes that were signed.
+pub fn check_canonical(msg_type: &PbftMessageType, msg_bytes: &[u8]) -> Result<(), PbftError> {
+    let canonical = match msg_type {
+        PbftMessageType::ViewChange => canonical_encoding::<PbftViewChange>(msg_bytes),
+        PbftMessageType::NewView => canonical_encoding::<PbftNewView>(msg_bytes),
+        PbftMessageType::StateRequest => canonical_encoding::<PbftStateRequest>(msg_bytes),
+        PbftMessageType::StateResponse => canonical_encoding::<PbftStateResponse>(msg_bytes),
+        PbftMessageType::RetransmitRequest => {
+            canonical_encoding::<PbftRetransmitRequest>(msg_bytes)
+        }
+        PbftMessageType::ClockSync | PbftMessageType::ClockSyncResponse => {
+            canonical_encoding::<PbftClockSync>(msg_bytes)
+        }
+        _ => canonical_encoding::<PbftMessage>(msg_bytes),
+    }?;
+
+    if canonical.as_slice() != msg_bytes {
+        return Err(PbftError::MalformedMessage(format!(
+            "{} isn't in its canonical encoding",
+            msg_type
+        )));
+    }
+    Ok(())
+}
+
+// Re-encode a serialized message without any fields its definition doesn't have
+fn canonical_encoding<M: Message + StripUnknownFields>(
+    msg_bytes: &[u8],
+) -> Result<Vec<u8>, PbftError> {
+    let mut msg =
+        protobuf::parse_from_bytes::<M>(msg_bytes).map_err(PbftError::SerializationError)?;
+    msg.strip_unknown_fields();
+    msg.write_to_bytes().map_err(PbftError::SerializationError)
+}
+
+/// Messages that can have the fields their definition doesn't have (such as fields added in a
+/// newer version of the engine) removed, along with those of the messages inside them
+pub trait StripUnknownFields {
+    fn strip_unknown_fields(&mut self);
+}
+
+impl StripUnknownFields for PbftMessageInfo {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+    }
+}
+
+impl StripUnknownFields for PbftBlock {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+    }
+}
+
+impl StripUnknownFields for PbftMessage {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
+        }
+        if self.has_block() {
+            self.mut_block().strip_unknown_fields();
+        }
+    }
+}
+
+impl StripUnknownFields for PbftPreparedCertificate {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_pre_prepare() {
+            self.mut_pre_prepare().strip_unknown_fields();
+        }
+        for msg in self.mut_prepare_messages().iter_mut() {
+            msg.strip_unknown_fields();
+        }
+    }
+}
+
+impl StripUnknownFields for PbftViewChange {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
+        }
+        for msg in self.mut_checkpoint_messages().iter_mut() {
+            msg.strip_unknown_fields();
+        }
+        for cert in self.mut_prepared_certificates().iter_mut() {
+            cert.strip_unknown_fields();
+        }
+    }
+}
+
+impl StripUnknownFields for PbftNewView {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
+        }
+        for vc in self.mut_view_changes().iter_mut() {
+            vc.strip_unknown_fields();
+        }
+        for msg in self.mut_pre_prepares().iter_mut() {
+            msg.strip_unknown_fields();
+        }
+    }
+}
 
-impl Hash for PbftMessageInfo {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.get_msg_type().hash(state);
-        self.get_view().hash(state);
-        self.get_seq_num().hash(state);
-        self.get_signer_id().hash(state);
+impl StripUnknownFields for PbftSeal {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_block() {
+            self.mut_block().strip_unknown_fields();
+        }
+        for msg in self.mut_commit_messages().iter_mut() {
+            msg.strip_unknown_fields();
+        }
+        if self.has_new_view() {
+            self.mut_new_view().strip_unknown_fields();
+        }
     }
 }
 
-impl Hash for PbftBlock {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.get_block_id().hash(state);
-        self.get_block_num().hash(state);
-        self.get_summary().hash(state);
-        self.get_signer_id().hash(state);
+impl StripUnknownFields for PbftStateRequest {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
+        }
     }
 }
 
-impl Hash for PbftMessage {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.get_info().hash(state);
-        self.get_block().hash(state);
+impl StripUnknownFields for PbftStateResponse {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
+        }
+        for seal in self.mut_seals().iter_mut() {
+            seal.strip_unknown_fields();
+        }
+    }
+}
+
+impl StripUnknownFields for PbftRetransmitRequest {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
+        }
     }
 }
 
-impl Hash for PbftViewChange {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.get_info().hash(state);
-        for msg in self.get_checkpoint_messages().iter() {
-            msg.get_info().hash(state);
-            msg.get_block().hash(state);
+impl StripUnknownFields for PbftClockSync {
+    fn strip_unknown_fields(&mut self) {
+        self.mut_unknown_fields().clear();
+        if self.has_info() {
+            self.mut_info().strip_unknown_fields();
         }
     }
 }
@@ -19,19 +19,38 @@
 
 #![allow(unknown_lints)]
 
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::time::{Duration, Instant};
 
 use hex;
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protobuf::{self, Message, RepeatedField};
 
-use sawtooth_sdk::consensus::engine::{Block, PeerMessage};
+use protos::pbft_message::{
+    PbftBlock, PbftMessage, PbftMessageInfo, PbftNewView, PbftPreparedCertificate, PbftSeal,
+    PbftSignedMessage, PbftViewChange,
+};
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerMessage};
+use tracing;
 
 use config::PbftConfig;
 use error::PbftError;
 use message_extensions::PbftGetInfo;
 use message_type::PbftMessageType;
+use seal::SEAL_VERSION;
+use timing;
+
+/// How many equivocations the log keeps evidence of for each signer; later ones are only logged
+const MAX_EQUIVOCATIONS_PER_SIGNER: usize = 16;
+
+/// The type, view, sequence number, and signer of a message, which a signer may only send one
+/// message for
+type SlotKey = (String, u64, u64, Vec<u8>);
+
+/// The view, sequence number, and signer that evidence of an equivocation is kept by
+type EquivocationKey = (u64, u64, Vec<u8>);
 
 /// The log keeps track of the last stable checkpoint
 #[derive(Clone)]
@@ -40,14 +59,81 @@ pub struct PbftStableCheckpoint {
     pub checkpoint_messages: Vec<PbftMessage>,
 }
 
+/// Evidence that a node signed two conflicting messages: the same type, view, sequence number,
+/// and signer, but different blocks
+#[derive(Clone, Debug, PartialEq)]
+pub struct PbftEquivocation {
+    /// The message that was already in the log
+    pub original: PbftMessage,
+
+    /// The conflicting message that was rejected
+    pub conflicting: PbftMessage,
+}
+
+/// Evidence that two different blocks were offered at the same height on top of the same block,
+/// because the validator forked or the primary published more than one block
+#[derive(Clone, Debug, PartialEq)]
+pub struct PbftBlockConflict {
+    /// The block that both candidates were built on
+    pub previous_id: BlockId,
+
+    /// The height of both candidates
+    pub block_num: u64,
+
+    /// The candidate that arrived first
+    pub original: BlockId,
+
+    /// The candidate that arrived later
+    pub conflicting: BlockId,
+}
+
+/// How big the log is, and how much garbage collection has removed from it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogStats {
+    /// How many messages are in the log, including `ViewChange`s
+    pub messages: u64,
+
+    /// How many messages from other nodes are waiting in the backlog
+    pub backlog: u64,
+
+    /// How many blocks are waiting in the block backlog
+    pub block_backlog: u64,
+
+    /// Roughly how many bytes the messages, backlogs, and seals take, going by their encoded size
+    pub bytes: u64,
+
+    /// The lowest and highest sequence numbers of the messages in the log (`None` if it has none
+    /// that were assigned one)
+    pub seq_num_range: Option<(u64, u64)>,
+
+    pub low_water_mark: u64,
+    pub high_water_mark: u64,
+
+    /// How many messages the most recent garbage collection removed
+    pub last_pruned: u64,
+
+    /// How many messages garbage collection has removed in all, and in how many passes
+    pub pruned_total: u64,
+    pub gc_passes: u64,
+
+    /// How many blocks have been added to the log (`BlockNew` messages), garbage collected or not
+    pub cycles: u64,
+}
+
 /// Struct for storing messages that a PbftNode receives
 pub struct PbftLog {
-    /// Generic messages (BlockNew, PrePrepare, Prepare, Commit, Checkpoint)
-    messages: HashSet<PbftMessage>,
+    /// Generic messages (BlockNew, PrePrepare, Prepare, Commit, Checkpoint), along with the time
+    /// each one was first added to the log
+    messages: HashMap<PbftMessage, Instant>,
 
     /// View change messages
     view_changes: HashSet<PbftViewChange>,
 
+    /// Signed copies of the `Commit` and `ViewChange` messages in the log, if messages are
+    /// signed, by the bytes that were signed; along with each one's sequence number, so it's
+    /// garbage collected along with its message
+    signed_votes: HashMap<Vec<u8>, (u64, PbftSignedMessage)>,
+
     /// Watermarks (minimum/maximum sequence numbers)
     /// Ensure that log does not get too large
     low_water_mark: u64,
@@ -59,7 +145,7 @@ pub struct PbftLog {
     /// How many cycles through the algorithm we've done (BlockNew messages)
     cycles: u64,
 
-    /// How many cycles in between checkpoints
+    /// How many sequence numbers in between checkpoints
     checkpoint_period: u64,
 
     /// Backlog of messages (from peers)
@@ -68,15 +154,65 @@ pub struct PbftLog {
     /// Backlog of blocks (from BlockNews messages)
     block_backlog: VecDeque<Block>,
 
+    /// How many blocks the block backlog can hold
+    max_block_backlog: usize,
+
+    /// IDs of blocks that arrived while the block backlog was full
+    deferred_blocks: VecDeque<BlockId>,
+
     /// The most recent checkpoint that contains proof
     pub latest_stable_checkpoint: Option<PbftStableCheckpoint>,
+
+    /// The multicast and `Checkpoint` messages in `messages`, by type, view, sequence number, and
+    /// signer, for finding conflicting messages without searching the whole log
+    slots: HashMap<SlotKey, PbftMessage>,
+
+    /// Conflicting messages that have been detected, by the view, sequence number, and signer of
+    /// the conflict, along with the order they were detected in. Evidence below the low water
+    /// mark is pruned.
+    equivocations: HashMap<EquivocationKey, Vec<(u64, PbftEquivocation)>>,
+
+    /// How much evidence is in `equivocations` for each signer
+    equivocations_per_signer: HashMap<Vec<u8>, usize>,
+
+    /// How many equivocations have been recorded in all, including ones that have been pruned
+    equivocation_count: u64,
+
+    /// IDs and heights of the blocks that haven't been committed yet, by the ID of the block
+    /// they're built on
+    block_candidates: HashMap<BlockId, Vec<(BlockId, u64)>>,
+
+    /// Conflicting block candidates that have been detected
+    block_conflicts: Vec<PbftBlockConflict>,
+
+    /// Verified seals for blocks that this node missed and hasn't committed yet, by block ID
+    seals: HashMap<Vec<u8>, PbftSeal>,
+
+    /// IDs of the sealed blocks that the validator has already found to be valid
+    valid_sealed_blocks: HashSet<Vec<u8>>,
+
+    /// Seals for the most recently committed blocks, by sequence number, kept even after the
+    /// blocks' messages are garbage collected
+    seal_history: BTreeMap<u64, PbftSeal>,
+
+    /// How many seals `seal_history` can hold
+    seal_history_size: usize,
+
+    /// The `NewView` message for the most recent view this node has seen one for
+    new_view: Option<PbftNewView>,
+
+    /// How many messages the most recent garbage collection removed, how many have been removed
+    /// in all, and how many times the log has been garbage collected
+    last_pruned: u64,
+    pruned_total: u64,
+    gc_passes: u64,
 }
 
 impl fmt::Display for PbftLog {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let msg_infos: Vec<PbftMessageInfo> = self
             .messages
-            .iter()
+            .keys()
             .map(|ref msg| msg.get_info().clone())
             .chain(
                 self.view_changes
@@ -110,8 +246,9 @@ impl fmt::Display for PbftLog {
 impl PbftLog {
     pub fn new(config: &PbftConfig) -> Self {
         PbftLog {
-            messages: HashSet::new(),
+            messages: HashMap::new(),
             view_changes: HashSet::new(),
+            signed_votes: HashMap::new(),
             low_water_mark: 0,
             cycles: 0,
             checkpoint_period: config.checkpoint_period,
@@ -119,7 +256,23 @@ impl PbftLog {
             max_log_size: config.max_log_size,
             backlog: VecDeque::new(),
             block_backlog: VecDeque::new(),
+            max_block_backlog: config.max_block_backlog as usize,
+            deferred_blocks: VecDeque::new(),
             latest_stable_checkpoint: None,
+            slots: HashMap::new(),
+            equivocations: HashMap::new(),
+            equivocations_per_signer: HashMap::new(),
+            equivocation_count: 0,
+            block_candidates: HashMap::new(),
+            block_conflicts: vec![],
+            seals: HashMap::new(),
+            valid_sealed_blocks: HashSet::new(),
+            seal_history: BTreeMap::new(),
+            seal_history_size: config.seal_history_size as usize,
+            new_view: None,
+            last_pruned: 0,
+            pruned_total: 0,
+            gc_passes: 0,
         }
     }
 
@@ -130,6 +283,17 @@ impl PbftLog {
     ///  + `2f + 1` matching `Prepare` messages from different nodes that match
     ///    `PrePrepare` message above (including its own)
     pub fn prepared(&self, deser_msg: &PbftMessage, f: u64) -> Result<(), PbftError> {
+        self.prepared_with_quorum(deser_msg, 2 * f + 1)
+    }
+
+    /// `prepared` predicate for a quorum other than `2f + 1`, such as when `f` is configured lower
+    /// than the network could tolerate (see `PbftState::quorum`)
+    pub fn prepared_with_quorum(
+        &self,
+        deser_msg: &PbftMessage,
+        quorum: u64,
+    ) -> Result<(), PbftError> {
+        let _span = tracing::trace_span!("quorum_check", predicate = "prepared").entered();
         if deser_msg.get_info().get_msg_type() != String::from(&PbftMessageType::Prepare) {
             return Err(PbftError::NotReadyForMessage);
         }
@@ -176,7 +340,7 @@ impl PbftLog {
             }
         }
 
-        self.check_msg_against_log(&deser_msg, true, 2 * f + 1)?;
+        self.check_msg_against_log(&deser_msg, true, quorum)?;
 
         Ok(())
     }
@@ -186,19 +350,49 @@ impl PbftLog {
     ///   + `prepared` is true
     ///   + This node has accepted `2f + 1` `Commit` messages, including its own
     pub fn committed(&self, deser_msg: &PbftMessage, f: u64) -> Result<(), PbftError> {
+        self.committed_with_quorum(deser_msg, 2 * f + 1)
+    }
+
+    /// "committed" predicate for a quorum other than `2f + 1` (see `prepared_with_quorum`)
+    pub fn committed_with_quorum(
+        &self,
+        deser_msg: &PbftMessage,
+        quorum: u64,
+    ) -> Result<(), PbftError> {
+        let _span = tracing::trace_span!("quorum_check", predicate = "committed").entered();
         if deser_msg.get_info().get_msg_type() != String::from(&PbftMessageType::Commit) {
             return Err(PbftError::NotReadyForMessage);
         }
-        self.check_msg_against_log(&deser_msg, true, 2 * f + 1)?;
+        self.check_msg_against_log(&deser_msg, true, quorum)?;
 
         let mut prep_msg = deser_msg.clone();
         let mut info = prep_msg.get_info().clone();
         info.set_msg_type(String::from(&PbftMessageType::Prepare));
         prep_msg.set_info(info);
-        self.prepared(&prep_msg, f)?;
+        self.prepared_with_quorum(&prep_msg, quorum)?;
         Ok(())
     }
 
+    /// "prepared by all" predicate, used for the optional fast path
+    /// `prepared by all` is true if for this node:
+    ///   + `prepared` is true
+    ///   + This node has accepted matching `Prepare` messages from all `n` nodes, including its own
+    /// When it is, the block can be committed without waiting for `quorum` `Commit` messages.
+    pub fn prepared_by_all(
+        &self,
+        deser_msg: &PbftMessage,
+        quorum: u64,
+        n: u64,
+    ) -> Result<(), PbftError> {
+        let _span = tracing::trace_span!("quorum_check", predicate = "prepared_by_all").entered();
+        let mut prep_msg = deser_msg.clone();
+        let mut info = prep_msg.get_info().clone();
+        info.set_msg_type(String::from(&PbftMessageType::Prepare));
+        prep_msg.set_info(info);
+        self.prepared_with_quorum(&prep_msg, quorum)?;
+        self.check_msg_against_log(&&prep_msg, true, n)
+    }
+
     /// Check an incoming message against its counterparts in the message log
     pub fn check_msg_against_log<'a, T: PbftGetInfo<'a>>(
         &self,
@@ -235,28 +429,221 @@ impl PbftLog {
         Ok(())
     }
 
+    /// Check that there are `ViewChange`s for the given view from at least `num_cutoff` nodes.
+    /// Unlike other messages, they don't have to match each other's sequence numbers: each one's is
+    /// its sender's stable checkpoint, and nodes that lost `Checkpoint` messages reach those at
+    /// different times.
+    pub fn check_view_changes(&self, view: u64, num_cutoff: u64) -> Result<(), PbftError> {
+        let vc_infos: Vec<&PbftMessageInfo> = self
+            .view_changes
+            .iter()
+            .map(|vc| vc.get_info())
+            .filter(|info| info.get_view() == view)
+            .collect();
+
+        let num_vc_msgs = num_unique_signers(&vc_infos);
+        if num_vc_msgs < num_cutoff {
+            return Err(PbftError::WrongNumMessages(
+                PbftMessageType::ViewChange,
+                num_cutoff as usize,
+                num_vc_msgs as usize,
+            ));
+        }
+        Ok(())
+    }
+
     /// Add a generic PBFT message to the log
+    /// Messages with sequence numbers outside of the low and high water marks are dropped, except
+    /// for `BlockNew` messages that haven't been assigned a sequence number yet.
     pub fn add_message(&mut self, msg: PbftMessage) {
-        if msg.get_info().get_seq_num() < self.high_water_mark
-            || msg.get_info().get_seq_num() >= self.low_water_mark
-        {
-            // If the message wasn't already in the log, increment cycles
+        let _span = tracing::trace_span!("log_insert").entered();
+        let seq_num = msg.get_info().get_seq_num();
+        let unassigned_block_new = seq_num == 0
+            && PbftMessageType::from(msg.get_info().get_msg_type()) == PbftMessageType::BlockNew;
+
+        if self.within_water_marks(seq_num) || unassigned_block_new {
             let msg_type = PbftMessageType::from(msg.get_info().get_msg_type());
-            let inserted = self.messages.insert(msg);
-            if msg_type == PbftMessageType::BlockNew && inserted {
-                self.cycles += 1;
+
+            // Don't store a message that conflicts with one from the same signer; keep it as
+            // evidence instead. One that only has a different trace ID is just a copy.
+            if msg_type.is_multicast() || msg_type == PbftMessageType::Checkpoint {
+                if let Some(original) = self.find_same_slot_message(&msg) {
+                    if original.get_block() != msg.get_block() {
+                        self.record_equivocation(original, msg);
+                    }
+                    return;
+                }
+            }
+
+            if !self.messages.contains_key(&msg) {
+                if msg_type.is_multicast() || msg_type == PbftMessageType::Checkpoint {
+                    self.slots.insert(slot_key(&msg), msg.clone());
+                }
+                if msg_type == PbftMessageType::BlockNew {
+                    self.cycles += 1;
+                }
+                self.messages.insert(msg, timing::now());
             }
             trace!("{}", self);
         } else {
             warn!(
                 "Not adding message with sequence number {}; outside of log bounds ({}, {})",
-                msg.get_info().get_seq_num(),
-                self.low_water_mark,
-                self.high_water_mark,
+                seq_num, self.low_water_mark, self.high_water_mark,
             );
         }
     }
 
+    // Find a message in the log with the same type, view, sequence number, and signer as the
+    // given message, but that isn't exactly the same message
+    fn find_same_slot_message(&self, msg: &PbftMessage) -> Option<PbftMessage> {
+        self.slots
+            .get(&slot_key(msg))
+            .filter(|&existing| existing != msg)
+            .cloned()
+    }
+
+    fn record_equivocation(&mut self, original: PbftMessage, conflicting: PbftMessage) {
+        let key = {
+            let info = conflicting.get_info();
+            warn!(
+                "Equivocation detected from {}: {} (view {}, seq {}) for both {} and {}",
+                hex::encode(info.get_signer_id()),
+                info.get_msg_type(),
+                info.get_view(),
+                info.get_seq_num(),
+                hex::encode(original.get_block().get_block_id()),
+                hex::encode(conflicting.get_block().get_block_id()),
+            );
+            (
+                info.get_view(),
+                info.get_seq_num(),
+                info.get_signer_id().to_vec(),
+            )
+        };
+
+        let recorded = self
+            .equivocations_per_signer
+            .get(&key.2)
+            .cloned()
+            .unwrap_or(0);
+        let evidence = PbftEquivocation {
+            original,
+            conflicting,
+        };
+        if self
+            .equivocations
+            .get(&key)
+            .map(|slot| slot.iter().any(|&(_, ref existing)| *existing == evidence))
+            .unwrap_or(false)
+        {
+            return;
+        }
+        if recorded >= MAX_EQUIVOCATIONS_PER_SIGNER {
+            debug!(
+                "Already keeping evidence of {} equivocations from {}; not keeping this one",
+                recorded,
+                hex::encode(&key.2)
+            );
+            return;
+        }
+
+        self.equivocations_per_signer
+            .insert(key.2.clone(), recorded + 1);
+        self.equivocations
+            .entry(key)
+            .or_insert_with(Vec::new)
+            .push((self.equivocation_count, evidence));
+        self.equivocation_count += 1;
+    }
+
+    /// Get the evidence of the equivocations (conflicting messages from the same signer) that
+    /// this log has detected and not pruned yet, in the order they were detected
+    pub fn get_equivocations(&self) -> Vec<&PbftEquivocation> {
+        self.equivocations_since(0)
+    }
+
+    /// Get the evidence of the equivocations that were detected after the first `count`, and
+    /// haven't been pruned yet, in the order they were detected
+    pub fn equivocations_since(&self, count: u64) -> Vec<&PbftEquivocation> {
+        let mut evidence: Vec<&(u64, PbftEquivocation)> = self
+            .equivocations
+            .values()
+            .flat_map(|slot| slot.iter())
+            .filter(|&&(detected, _)| detected >= count)
+            .collect();
+        evidence.sort_by_key(|&&(detected, _)| detected);
+        evidence.into_iter().map(|&(_, ref e)| e).collect()
+    }
+
+    /// How many equivocations this log has detected in all, including ones whose evidence has
+    /// been pruned
+    pub fn equivocation_count(&self) -> u64 {
+        self.equivocation_count
+    }
+
+    /// Remember a block that the validator sent, and find a different block that was sent
+    /// earlier at the same height on top of the same block, if there is one. Each conflict is
+    /// recorded as evidence the first time it's found.
+    pub fn add_block_candidate(&mut self, block: &Block) -> Option<BlockId> {
+        let candidates = self
+            .block_candidates
+            .entry(block.previous_id.clone())
+            .or_insert_with(Vec::new);
+        let original = candidates
+            .iter()
+            .find(|(id, num)| *num == block.block_num && id != &block.block_id)
+            .map(|(id, _)| id.clone());
+
+        if candidates.iter().any(|(id, _)| id == &block.block_id) {
+            return original;
+        }
+        candidates.push((block.block_id.clone(), block.block_num));
+
+        if let Some(ref original) = original {
+            warn!(
+                "Conflicting block candidates: block_num={} previous_id={} original={} \
+                 conflicting={}",
+                block.block_num,
+                hex::encode(&block.previous_id),
+                hex::encode(original),
+                hex::encode(&block.block_id),
+            );
+            self.block_conflicts.push(PbftBlockConflict {
+                previous_id: block.previous_id.clone(),
+                block_num: block.block_num,
+                original: original.clone(),
+                conflicting: block.block_id.clone(),
+            });
+        }
+
+        original
+    }
+
+    /// Forget the candidates at or below the height of a block that was just committed, and
+    /// return the ones that weren't committed, since they never can be now
+    pub fn remove_block_candidates(&mut self, committed: &BlockId, block_num: u64) -> Vec<BlockId> {
+        let mut losers = Vec::new();
+        for candidates in self.block_candidates.values_mut() {
+            candidates.retain(|(id, num)| {
+                if *num > block_num {
+                    return true;
+                }
+                if id != committed {
+                    losers.push(id.clone());
+                }
+                false
+            });
+        }
+        self.block_candidates
+            .retain(|_, candidates| !candidates.is_empty());
+        losers
+    }
+
+    /// Get the evidence of all conflicting block candidates that this log has detected
+    pub fn get_block_conflicts(&self) -> &[PbftBlockConflict] {
+        &self.block_conflicts
+    }
+
     /// Obtain messages from the log that match a given type, sequence number, and view
     pub fn get_messages_of_type(
         &self,
@@ -264,17 +651,111 @@ impl PbftLog {
         sequence_number: u64,
         view: u64,
     ) -> Vec<&PbftMessage> {
-        self.messages
-            .iter()
+        self.messages_of_type(msg_type)
             .filter(|&msg| {
-                let info = (*msg).get_info();
-                info.get_msg_type() == String::from(msg_type)
-                    && info.get_seq_num() == sequence_number
-                    && info.get_view() == view
+                let info = msg.get_info();
+                info.get_seq_num() == sequence_number && info.get_view() == view
             })
             .collect()
     }
 
+    /// Iterate over all generic messages in the log
+    pub fn messages(&self) -> impl Iterator<Item = &PbftMessage> {
+        self.messages.keys()
+    }
+
+    /// Whether this node has received the message of a given type, sequence number, and view from
+    /// the given node, either in the log or in the backlog
+    pub fn has_message_from(
+        &self,
+        msg_type: &PbftMessageType,
+        sequence_number: u64,
+        view: u64,
+        signer_id: &[u8],
+    ) -> bool {
+        let matches = |info: &PbftMessageInfo| {
+            info.get_seq_num() == sequence_number
+                && info.get_view() == view
+                && info.get_signer_id() == signer_id
+        };
+
+        self.messages_of_type(msg_type)
+            .any(|msg| matches(msg.get_info()))
+            || self
+                .backlog
+                .iter()
+                .filter(|msg| msg.message_type == String::from(msg_type))
+                .filter_map(|msg| protobuf::parse_from_bytes::<PbftMessage>(&msg.content).ok())
+                .any(|msg| matches(msg.get_info()))
+    }
+
+    /// Iterate over the generic messages in the log of a given type
+    pub fn messages_of_type<'a>(
+        &'a self,
+        msg_type: &PbftMessageType,
+    ) -> impl Iterator<Item = &'a PbftMessage> + 'a {
+        let msg_type = String::from(msg_type);
+        self.messages
+            .keys()
+            .filter(move |&msg| msg.get_info().get_msg_type() == msg_type)
+    }
+
+    /// The trace ID the primary gave the block at the given sequence number, from the most recent
+    /// view's `PrePrepare` for it that has one
+    pub fn get_trace_id(&self, seq_num: u64, block_id: &[u8]) -> Option<&[u8]> {
+        self.messages_of_type(&PbftMessageType::PrePrepare)
+            .filter(|msg| {
+                msg.get_info().get_seq_num() == seq_num
+                    && msg.get_block().get_block_id() == block_id
+                    && !msg.get_info().get_trace_id().is_empty()
+            })
+            .max_by_key(|msg| msg.get_info().get_view())
+            .map(|msg| msg.get_info().get_trace_id())
+    }
+
+    /// Iterate over the generic messages in the log from a given view
+    pub fn messages_in_view(&self, view: u64) -> impl Iterator<Item = &PbftMessage> {
+        self.messages
+            .keys()
+            .filter(move |&msg| msg.get_info().get_view() == view)
+    }
+
+    /// Iterate over the generic messages in the log with a sequence number in the range
+    /// `[low, high)`
+    pub fn messages_in_seq_range(&self, low: u64, high: u64) -> impl Iterator<Item = &PbftMessage> {
+        self.messages.keys().filter(move |&msg| {
+            let seq_num = msg.get_info().get_seq_num();
+            seq_num >= low && seq_num < high
+        })
+    }
+
+    /// Iterate over the `ViewChange` messages in the log
+    pub fn view_changes(&self) -> impl Iterator<Item = &PbftViewChange> {
+        self.view_changes.iter()
+    }
+
+    /// Iterate over the blocks referenced by the generic messages in the log. Each block is only
+    /// yielded once, no matter how many messages reference it.
+    pub fn blocks(&self) -> impl Iterator<Item = &PbftBlock> {
+        let mut seen: HashSet<&[u8]> = HashSet::new();
+        self.messages
+            .keys()
+            .map(|msg| msg.get_block())
+            .filter(move |&block| {
+                !block.get_block_id().is_empty() && seen.insert(block.get_block_id())
+            })
+    }
+
+    /// Iterate over the peer messages waiting in the backlog, oldest first
+    pub fn backlog(&self) -> impl Iterator<Item = &PeerMessage> {
+        self.backlog.iter()
+    }
+
+    /// Iterate over the blocks waiting in the block backlog, oldest first
+    pub fn block_backlog(&self) -> impl Iterator<Item = &Block> {
+        self.block_backlog.iter()
+    }
+
     /// Obtain message information objects from the log that match a given type, sequence number,
     /// and view
     pub fn get_message_infos(
@@ -284,7 +765,7 @@ impl PbftLog {
         view: u64,
     ) -> Vec<&PbftMessageInfo> {
         let mut infos = vec![];
-        for msg in &self.messages {
+        for msg in self.messages.keys() {
             let info = msg.get_info();
             if info.get_msg_type() == String::from(msg_type)
                 && info.get_seq_num() == sequence_number
@@ -315,19 +796,24 @@ impl PbftLog {
         view: u64,
         block: &PbftBlock,
     ) -> usize {
+        // Only the messages for this block are taken out of the log; the others are for other
+        // blocks, such as the one a secondary is working on when a stale `PrePrepare` arrives
         #[allow(map_clone)]
         let zero_seq_msgs: Vec<PbftMessage> = self
             .get_messages_of_type(msg_type, 0, view)
             .iter()
+            .filter(|msg| msg.get_block().get_block_id() == block.get_block_id())
             .map(|&msg| msg.clone())
             .collect();
 
+        // Keep track of when the original messages arrived
+        let mut arrival_times = Vec::new();
         for m in &zero_seq_msgs {
-            self.messages.remove(m);
+            arrival_times.push(self.messages.remove(m).unwrap_or_else(timing::now));
         }
 
-        let mut fixed_msgs = Vec::<PbftMessage>::new();
-        for mut m in zero_seq_msgs {
+        let mut fixed_msgs = Vec::<(PbftMessage, Instant)>::new();
+        for (m, arrived) in zero_seq_msgs.into_iter().zip(arrival_times) {
             if m.get_info().get_msg_type() == String::from(msg_type)
                 && m.get_info().get_seq_num() == 0
                 && m.get_block().get_block_id() == block.get_block_id()
@@ -336,22 +822,332 @@ impl PbftLog {
                 let mut new_msg = m.clone();
                 info.set_seq_num(new_sequence_number);
                 new_msg.set_info(info);
-                fixed_msgs.push(new_msg.clone());
+                fixed_msgs.push((new_msg.clone(), arrived));
             }
         }
 
         let changed_msgs = fixed_msgs.len();
-        for m in fixed_msgs {
-            self.messages.insert(m);
+        for (m, arrived) in fixed_msgs {
+            self.messages.insert(m, arrived);
         }
         changed_msgs
     }
 
+    /// Get the time at which a generic message was first added to the log
+    pub fn get_arrival_time(&self, msg: &PbftMessage) -> Option<Instant> {
+        self.messages.get(msg).cloned()
+    }
+
+    /// Get the arrival time of each signer's message of the given type, sequence number, and
+    /// view, relative to the arrival of the `PrePrepare` for that sequence number and view. Peers
+    /// whose messages consistently arrive late show up with larger offsets here.
+    pub fn get_arrival_offsets(
+        &self,
+        msg_type: &PbftMessageType,
+        sequence_number: u64,
+        view: u64,
+    ) -> Vec<(Vec<u8>, Duration)> {
+        let pre_prepared =
+            match self.get_quorum_time(&PbftMessageType::PrePrepare, sequence_number, view, 1) {
+                Some(time) => time,
+                None => return vec![],
+            };
+
+        let mut offsets: Vec<(Vec<u8>, Duration)> = self
+            .get_first_arrivals(msg_type, sequence_number, view)
+            .into_iter()
+            .map(|(signer, arrived)| (signer.to_vec(), duration_between(pre_prepared, arrived)))
+            .collect();
+        offsets.sort_by_key(|&(_, offset)| offset);
+        offsets
+    }
+
+    /// How long it took for the given sequence number and view to go from receiving the
+    /// `PrePrepare` to being `prepared` (receiving `quorum` matching `Prepare` messages)
+    pub fn get_pre_prepare_to_prepared_latency(
+        &self,
+        sequence_number: u64,
+        view: u64,
+        quorum: u64,
+    ) -> Option<Duration> {
+        let pre_prepared =
+            self.get_quorum_time(&PbftMessageType::PrePrepare, sequence_number, view, 1)?;
+        let prepared = self.get_prepared_time(sequence_number, view, quorum)?;
+        Some(duration_between(pre_prepared, prepared))
+    }
+
+    /// How long it took for the given sequence number and view to go from being `prepared` to
+    /// being `committed` (receiving `quorum` matching `Commit` messages)
+    pub fn get_prepared_to_committed_latency(
+        &self,
+        sequence_number: u64,
+        view: u64,
+        quorum: u64,
+    ) -> Option<Duration> {
+        let prepared = self.get_prepared_time(sequence_number, view, quorum)?;
+        let committed =
+            self.get_quorum_time(&PbftMessageType::Commit, sequence_number, view, quorum)?;
+        Some(duration_between(
+            prepared,
+            ::std::cmp::max(prepared, committed),
+        ))
+    }
+
+    // The point in time when the node had both the `PrePrepare` and `quorum` `Prepare` messages
+    fn get_prepared_time(&self, sequence_number: u64, view: u64, quorum: u64) -> Option<Instant> {
+        let pre_prepared =
+            self.get_quorum_time(&PbftMessageType::PrePrepare, sequence_number, view, 1)?;
+        let prepared =
+            self.get_quorum_time(&PbftMessageType::Prepare, sequence_number, view, quorum)?;
+        Some(::std::cmp::max(pre_prepared, prepared))
+    }
+
+    // The arrival time of the message that brought the number of unique signers of the given
+    // message type up to `quorum`
+    fn get_quorum_time(
+        &self,
+        msg_type: &PbftMessageType,
+        sequence_number: u64,
+        view: u64,
+        quorum: u64,
+    ) -> Option<Instant> {
+        if quorum == 0 {
+            return None;
+        }
+        let mut arrivals: Vec<Instant> = self
+            .get_first_arrivals(msg_type, sequence_number, view)
+            .into_iter()
+            .map(|(_, arrived)| arrived)
+            .collect();
+        arrivals.sort();
+        arrivals.get(quorum as usize - 1).cloned()
+    }
+
+    // The earliest arrival time of a message of the given type from each signer
+    fn get_first_arrivals(
+        &self,
+        msg_type: &PbftMessageType,
+        sequence_number: u64,
+        view: u64,
+    ) -> HashMap<&[u8], Instant> {
+        let mut arrivals: HashMap<&[u8], Instant> = HashMap::new();
+        for msg in self.get_messages_of_type(msg_type, sequence_number, view) {
+            let arrived = self.messages[msg];
+            let earliest = arrivals
+                .entry(msg.get_info().get_signer_id())
+                .or_insert(arrived);
+            if arrived < *earliest {
+                *earliest = arrived;
+            }
+        }
+        arrivals
+    }
+
+    /// Get a prepared certificate (the `PrePrepare` and `quorum` matching `Prepare` messages from
+    /// different nodes) for every sequence number after `low_seq_num` that this node has prepared,
+    /// keeping only the one from the highest view for each sequence number. These are sent with
+    /// `ViewChange` messages, so that prepared blocks aren't lost in the new view.
+    pub fn get_prepared_certificates(
+        &self,
+        low_seq_num: u64,
+        quorum: u64,
+    ) -> Vec<PbftPreparedCertificate> {
+        let mut certificates: HashMap<u64, PbftPreparedCertificate> = HashMap::new();
+
+        for pre_prepare in self.messages_of_type(&PbftMessageType::PrePrepare) {
+            let info = pre_prepare.get_info();
+            if info.get_seq_num() <= low_seq_num {
+                continue;
+            }
+            if let Some(cert) = certificates.get(&info.get_seq_num()) {
+                if cert.get_pre_prepare().get_info().get_view() >= info.get_view() {
+                    continue;
+                }
+            }
+
+            let mut signers: HashSet<&[u8]> = HashSet::new();
+            let prepares: Vec<PbftMessage> = self
+                .messages_of_type(&PbftMessageType::Prepare)
+                .filter(|msg| {
+                    infos_match(msg.get_info(), info)
+                        && msg.get_block().get_block_id() == pre_prepare.get_block().get_block_id()
+                        && signers.insert(msg.get_info().get_signer_id())
+                })
+                .cloned()
+                .collect();
+            if (prepares.len() as u64) < quorum {
+                continue;
+            }
+
+            let mut cert = PbftPreparedCertificate::new();
+            cert.set_pre_prepare(pre_prepare.clone());
+            cert.set_prepare_messages(RepeatedField::from_vec(prepares));
+            certificates.insert(info.get_seq_num(), cert);
+        }
+
+        let mut certificates: Vec<PbftPreparedCertificate> =
+            certificates.into_iter().map(|(_, cert)| cert).collect();
+        certificates.sort_by_key(|cert| cert.get_pre_prepare().get_info().get_seq_num());
+        certificates
+    }
+
+    /// Get a seal (the block and `quorum` matching `Commit` messages from different nodes) for the
+    /// block committed at the given sequence number, if there are enough `Commit`s in the log.
+    /// The signed copies of the `Commit`s are included if the log has all of them.
+    /// Seals of committed blocks are taken from `seal_history`, which serves as a cache of them;
+    /// since a committed block's seal never changes, nothing there needs to be invalidated.
+    pub fn get_seal(&self, seq_num: u64, quorum: u64) -> Option<PbftSeal> {
+        if let Some(seal) = self.seal_history.get(&seq_num) {
+            return Some(seal.clone());
+        }
+
+        // The messages for older blocks have been garbage collected, so there's no need to look
+        if seq_num < self.low_water_mark {
+            return None;
+        }
+
+        let mut commits: HashMap<(&[u8], u64), Vec<&PbftMessage>> = HashMap::new();
+        for msg in self.messages_of_type(&PbftMessageType::Commit) {
+            if msg.get_info().get_seq_num() != seq_num {
+                continue;
+            }
+            let same_block = commits
+                .entry((msg.get_block().get_block_id(), msg.get_info().get_view()))
+                .or_insert_with(Vec::new);
+            if same_block
+                .iter()
+                .all(|other| other.get_info().get_signer_id() != msg.get_info().get_signer_id())
+            {
+                same_block.push(msg);
+            }
+        }
+
+        commits
+            .values()
+            .find(|same_block| same_block.len() as u64 >= quorum)
+            .map(|same_block| {
+                let mut seal = PbftSeal::new();
+                seal.set_version(SEAL_VERSION);
+                seal.set_seq_num(seq_num);
+                seal.set_block(same_block[0].get_block().clone());
+                seal.set_commit_messages(RepeatedField::from_vec(
+                    same_block.iter().map(|&msg| msg.clone()).collect(),
+                ));
+                let signed: Option<Vec<PbftSignedMessage>> = same_block
+                    .iter()
+                    .map(|msg| {
+                        msg.write_to_bytes()
+                            .ok()
+                            .and_then(|bytes| self.get_signed_vote(&bytes).cloned())
+                    })
+                    .collect();
+                if let Some(signed) = signed {
+                    seal.set_signed_commit_messages(RepeatedField::from_vec(signed));
+                }
+                seal
+            })
+    }
+
+    /// Keep the seal for a block that was committed, so that it can be sent to nodes that are
+    /// behind even after the block's messages have been garbage collected. Only the most recent
+    /// `seal_history_size` seals are kept.
+    pub fn archive_seal(&mut self, seal: PbftSeal) {
+        self.seal_history.insert(seal.get_seq_num(), seal);
+        while self.seal_history.len() > self.seal_history_size {
+            let oldest = *self
+                .seal_history
+                .keys()
+                .next()
+                .expect("Seal history is empty");
+            self.seal_history.remove(&oldest);
+        }
+    }
+
+    /// Keep a verified seal until its block is committed
+    pub fn add_seal(&mut self, seal: PbftSeal) {
+        self.seals
+            .insert(seal.get_block().get_block_id().to_vec(), seal);
+    }
+
+    /// Tell if there's a verified seal for the given block
+    pub fn has_seal(&self, block_id: &[u8]) -> bool {
+        self.seals.contains_key(block_id)
+    }
+
+    /// Get the highest sequence number that there's a verified seal for
+    pub fn get_highest_seal_seq_num(&self) -> Option<u64> {
+        self.seals.values().map(|seal| seal.get_seq_num()).max()
+    }
+
+    /// Remember that the validator found the given sealed block to be valid
+    pub fn mark_seal_valid(&mut self, block_id: &[u8]) {
+        if self.seals.contains_key(block_id) {
+            self.valid_sealed_blocks.insert(block_id.to_vec());
+        }
+    }
+
+    /// Remove and return the seal for the block with the given block number, if there is one and
+    /// the validator has found the block to be valid
+    pub fn take_valid_seal(&mut self, block_num: u64) -> Option<PbftSeal> {
+        let block_id = self
+            .seals
+            .values()
+            .find(|seal| {
+                seal.get_block().get_block_num() == block_num
+                    && self
+                        .valid_sealed_blocks
+                        .contains(seal.get_block().get_block_id())
+            })?
+            .get_block()
+            .get_block_id()
+            .to_vec();
+
+        self.valid_sealed_blocks.remove(&block_id);
+        self.seals.remove(&block_id)
+    }
+
+    /// Remove and return the IDs of the blocks in the block backlog, or deferred from it, that
+    /// there are verified seals for
+    pub fn take_sealed_blocks(&mut self) -> Vec<BlockId> {
+        let seals = &self.seals;
+        let is_sealed = |block_id: &BlockId| seals.contains_key(&Vec::<u8>::from(block_id.clone()));
+
+        let (sealed, unsealed): (Vec<Block>, Vec<Block>) = self
+            .block_backlog
+            .drain(..)
+            .partition(|block| is_sealed(&block.block_id));
+        self.block_backlog = unsealed.into_iter().collect();
+
+        let (sealed_deferred, unsealed_deferred): (Vec<BlockId>, Vec<BlockId>) =
+            self.deferred_blocks.drain(..).partition(is_sealed);
+        self.deferred_blocks = unsealed_deferred.into_iter().collect();
+
+        sealed
+            .into_iter()
+            .map(|block| block.block_id)
+            .chain(sealed_deferred)
+            .collect()
+    }
+
     /// Add a `ViewChange` message to the log
     pub fn add_view_change(&mut self, vc: PbftViewChange) {
         self.view_changes.insert(vc);
     }
 
+    /// Keep the signed copy of a `Commit` or `ViewChange` message with the given sequence number,
+    /// so that it can be passed on as proof of what its signer sent
+    pub fn add_signed_vote(&mut self, seq_num: u64, signed: PbftSignedMessage) {
+        self.signed_votes
+            .insert(signed.get_message().to_vec(), (seq_num, signed));
+    }
+
+    /// Get the signed copy of the message with the given encoding, if there is one
+    pub fn get_signed_vote(&self, msg_bytes: &[u8]) -> Option<&PbftSignedMessage> {
+        self.signed_votes
+            .get(msg_bytes)
+            .map(|&(_, ref signed)| signed)
+    }
+
     /// Get the latest stable checkpoint
     pub fn get_latest_checkpoint(&self) -> u64 {
         if let Some(ref cp) = self.latest_stable_checkpoint {
@@ -361,50 +1157,206 @@ impl PbftLog {
         }
     }
 
-    /// Is this node ready for a checkpoint?
-    pub fn at_checkpoint(&self) -> bool {
-        self.cycles >= self.checkpoint_period
+    /// Get the low water mark; messages with sequence numbers below it are not accepted
+    pub fn get_low_water_mark(&self) -> u64 {
+        self.low_water_mark
     }
 
-    /// Garbage collect the log, and create a stable checkpoint
-    pub fn garbage_collect(&mut self, stable_checkpoint: u64, view: u64) {
-        self.low_water_mark = stable_checkpoint;
-        self.high_water_mark = self.low_water_mark + self.max_log_size;
-        self.cycles = 0;
+    /// Get the high water mark; messages with sequence numbers at or above it are not accepted
+    pub fn get_high_water_mark(&self) -> u64 {
+        self.high_water_mark
+    }
 
-        // Update the stable checkpoint
-        #[allow(map_clone)]
-        let cp_msgs: Vec<PbftMessage> = self
-            .get_messages_of_type(&PbftMessageType::Checkpoint, stable_checkpoint, view)
-            .iter()
-            .map(|&cp| cp.clone())
-            .collect();
-        let cp = PbftStableCheckpoint {
-            seq_num: stable_checkpoint,
-            checkpoint_messages: cp_msgs,
-        };
-        self.latest_stable_checkpoint = Some(cp);
+    /// Is the given sequence number between the low and high water marks?
+    pub fn within_water_marks(&self, seq_num: u64) -> bool {
+        seq_num >= self.low_water_mark && seq_num < self.high_water_mark
+    }
 
-        // Garbage collect logs, filter out all old messages (up to but not including the
-        // checkpoint)
-        self.messages = self
-            .messages
-            .iter()
-            .filter(|ref msg| {
-                let seq_num = msg.get_info().get_seq_num();
-                seq_num >= self.get_latest_checkpoint() && seq_num > 0
+    /// Should a checkpoint be taken after committing the block with this sequence number?
+    pub fn at_checkpoint(&self, seq_num: u64) -> bool {
+        seq_num > 0 && seq_num % self.checkpoint_period == 0
+    }
+
+    /// Get the proof that the checkpoint at the given sequence number and block is stable: `quorum`
+    /// `Checkpoint` messages for it from different nodes, one of which must be this node's own.
+    /// Nodes that haven't reached the checkpoint yet will still need the messages it would collect.
+    pub fn get_checkpoint_proof(
+        &self,
+        seq_num: u64,
+        block_id: &[u8],
+        own_id: &[u8],
+        quorum: u64,
+    ) -> Option<PbftStableCheckpoint> {
+        let mut signers: HashSet<&[u8]> = HashSet::new();
+        let checkpoint_messages: Vec<PbftMessage> = self
+            .messages_of_type(&PbftMessageType::Checkpoint)
+            .filter(|msg| {
+                msg.get_info().get_seq_num() == seq_num
+                    && msg.get_block().get_block_id() == block_id
+                    && signers.insert(msg.get_info().get_signer_id())
             })
             .cloned()
             .collect();
+
+        if !signers.contains(own_id) || (checkpoint_messages.len() as u64) < quorum {
+            return None;
+        }
+
+        Some(PbftStableCheckpoint {
+            seq_num,
+            checkpoint_messages,
+        })
+    }
+
+    /// Keep a `NewView` message that has been verified, so that it can be included in seals as
+    /// evidence of its view change
+    pub fn set_new_view(&mut self, new_view: PbftNewView) {
+        self.new_view = Some(new_view);
+    }
+
+    /// Get the `NewView` message for the view that a seal's `Commit` messages are from, if the
+    /// sealed block was proposed in an earlier view: its oldest `BlockNew` or `PrePrepare` in the
+    /// log is from an earlier view than its `Commit`s
+    pub fn view_change_evidence(&self, seal: &PbftSeal) -> Option<PbftNewView> {
+        let commit_view = seal.get_commit_messages().first()?.get_info().get_view();
+        let block_id = seal.get_block().get_block_id();
+
+        let proposal_view = self
+            .messages_of_type(&PbftMessageType::BlockNew)
+            .chain(self.messages_of_type(&PbftMessageType::PrePrepare))
+            .filter(|msg| msg.get_block().get_block_id() == block_id)
+            .map(|msg| msg.get_info().get_view())
+            .min()?;
+        if proposal_view >= commit_view {
+            return None;
+        }
+
+        self.new_view
+            .as_ref()
+            .filter(|new_view| new_view.get_info().get_view() == commit_view)
+            .cloned()
+    }
+
+    /// Make the given checkpoint stable, advance the water marks to it, and garbage collect the
+    /// log
+    pub fn garbage_collect(&mut self, stable_checkpoint: PbftStableCheckpoint) {
+        let seq_num = stable_checkpoint.seq_num;
+        self.latest_stable_checkpoint = Some(stable_checkpoint);
+        self.move_water_marks(seq_num);
+    }
+
+    /// Move the water marks up to the given sequence number without a stable checkpoint, for a
+    /// node that committed the blocks before it from seals instead of going through consensus for
+    /// them. Older messages are dropped, just like when garbage collecting.
+    pub fn move_water_marks(&mut self, seq_num: u64) {
+        self.low_water_mark = seq_num;
+        self.high_water_mark = self.low_water_mark + self.max_log_size;
+        let size_before = self.messages.len() + self.view_changes.len();
+
+        // Garbage collect logs, filter out all old messages (up to but not including the
+        // checkpoint). `BlockNew`s that haven't been assigned a sequence number yet are kept.
+        self.messages.retain(|msg, _| {
+            let msg_seq_num = msg.get_info().get_seq_num();
+            if msg_seq_num == 0 {
+                PbftMessageType::from(msg.get_info().get_msg_type()) == PbftMessageType::BlockNew
+            } else {
+                msg_seq_num >= seq_num
+            }
+        });
         self.view_changes = self
             .view_changes
             .iter()
             .filter(|ref msg| {
-                let seq_num = msg.get_info().get_seq_num();
-                seq_num >= self.get_latest_checkpoint() && seq_num > 0
+                let msg_seq_num = msg.get_info().get_seq_num();
+                msg_seq_num >= seq_num && msg_seq_num > 0
             })
             .cloned()
             .collect();
+        self.slots
+            .retain(|&(_, _, msg_seq_num, _), _| msg_seq_num >= seq_num && msg_seq_num > 0);
+        self.signed_votes
+            .retain(|_, &mut (msg_seq_num, _)| msg_seq_num >= seq_num && msg_seq_num > 0);
+
+        // Evidence of equivocations is only kept for as long as the messages it's about
+        self.equivocations
+            .retain(|&(_, msg_seq_num, _), _| msg_seq_num >= seq_num);
+        self.equivocations_per_signer.clear();
+        for (&(_, _, ref signer_id), slot) in &self.equivocations {
+            *self
+                .equivocations_per_signer
+                .entry(signer_id.clone())
+                .or_insert(0) += slot.len();
+        }
+
+        self.last_pruned = (size_before - self.messages.len() - self.view_changes.len()) as u64;
+        self.pruned_total += self.last_pruned;
+        self.gc_passes += 1;
+        debug!(
+            "Garbage collected {} messages below sequence number {}",
+            self.last_pruned, seq_num
+        );
+    }
+
+    /// Measure the log's size, for metrics
+    pub fn stats(&self) -> LogStats {
+        let seq_nums = self
+            .messages
+            .keys()
+            .map(|msg| msg.get_info().get_seq_num())
+            .chain(
+                self.view_changes
+                    .iter()
+                    .map(|vc| vc.get_info().get_seq_num()),
+            )
+            .filter(|&seq_num| seq_num > 0);
+        let seq_num_range = seq_nums.fold(None, |range, seq_num| match range {
+            None => Some((seq_num, seq_num)),
+            Some((low, high)) => Some((low.min(seq_num), high.max(seq_num))),
+        });
+
+        let bytes = self
+            .messages
+            .keys()
+            .map(|msg| u64::from(msg.compute_size()))
+            .chain(
+                self.view_changes
+                    .iter()
+                    .map(|vc| u64::from(vc.compute_size())),
+            )
+            .chain(
+                self.signed_votes
+                    .values()
+                    .map(|&(_, ref signed)| u64::from(signed.compute_size())),
+            )
+            .chain(self.backlog.iter().map(|msg| msg.content.len() as u64))
+            .chain(self.block_backlog.iter().map(|block| {
+                (block.block_id.len()
+                    + block.previous_id.len()
+                    + block.signer_id.len()
+                    + block.payload.len()
+                    + block.summary.len()) as u64
+            }))
+            .chain(
+                self.seals
+                    .values()
+                    .chain(self.seal_history.values())
+                    .map(|seal| u64::from(seal.compute_size())),
+            )
+            .sum();
+
+        LogStats {
+            messages: (self.messages.len() + self.view_changes.len()) as u64,
+            backlog: self.backlog.len() as u64,
+            block_backlog: self.block_backlog.len() as u64,
+            bytes,
+            seq_num_range,
+            low_water_mark: self.low_water_mark,
+            high_water_mark: self.high_water_mark,
+            last_pruned: self.last_pruned,
+            pruned_total: self.pruned_total,
+            gc_passes: self.gc_passes,
+            cycles: self.cycles,
+        }
     }
 
     pub fn push_backlog(&mut self, msg: PeerMessage) {
@@ -415,13 +1367,32 @@ impl PbftLog {
         self.backlog.pop_front()
     }
 
-    pub fn push_block_backlog(&mut self, msg: Block) {
+    /// Add a block to the block backlog. If the backlog is full, only the block's ID is kept, so
+    /// that blocks arriving faster than they can be committed don't use up unbounded memory; the
+    /// block can be fetched again once there's room (see `take_deferred_blocks`). Returns whether
+    /// the block itself was kept.
+    pub fn push_block_backlog(&mut self, msg: Block) -> bool {
+        if self.block_backlog.len() >= self.max_block_backlog {
+            self.deferred_blocks.push_back(msg.block_id);
+            return false;
+        }
         self.block_backlog.push_back(msg);
+        true
     }
 
     pub fn pop_block_backlog(&mut self) -> Option<Block> {
         self.block_backlog.pop_front()
     }
+
+    /// Remove and return the IDs of as many deferred blocks as there's room for in the block
+    /// backlog, oldest first
+    pub fn take_deferred_blocks(&mut self) -> Vec<BlockId> {
+        let room = self
+            .max_block_backlog
+            .saturating_sub(self.block_backlog.len())
+            .min(self.deferred_blocks.len());
+        self.deferred_blocks.drain(..room).collect()
+    }
 }
 
 // Make sure messages are all from different nodes
@@ -437,6 +1408,26 @@ fn num_unique_signers(msg_info_list: &[&PbftMessageInfo]) -> u64 {
     diff_msgs as u64
 }
 
+// Time elapsed between two instants, or zero if `end` is before `start`
+fn duration_between(start: Instant, end: Instant) -> Duration {
+    if end > start {
+        end - start
+    } else {
+        Duration::from_millis(0)
+    }
+}
+
+// Get the type, view, sequence number, and signer of a message
+fn slot_key(msg: &PbftMessage) -> SlotKey {
+    let info = msg.get_info();
+    (
+        info.get_msg_type().to_string(),
+        info.get_view(),
+        info.get_seq_num(),
+        info.get_signer_id().to_vec(),
+    )
+}
+
 // Check that the views and sequence numbers of two messages match
 fn infos_match(m1: &PbftMessageInfo, m2: &PbftMessageInfo) -> bool {
     m1.get_view() == m2.get_view() && m1.get_seq_num() == m2.get_seq_num()
@@ -541,6 +1532,250 @@ mod tests {
         }
     }
 
+    /// Make sure that a quorum larger than `2f + 1`, for an `f` configured lower than the network
+    /// could tolerate, needs that many `Prepare` and `Commit` messages
+    #[test]
+    fn prepared_committed_with_quorum() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 1, get_peer_id(&cfg, 1));
+        log.add_message(msg);
+        let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        log.add_message(msg);
+
+        for peer in 0..4 {
+            let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, peer));
+            log.add_message(msg.clone());
+            if peer < 3 {
+                assert!(log.prepared_with_quorum(&msg, 4).is_err());
+            } else {
+                assert!(log.prepared_with_quorum(&msg, 4).is_ok());
+            }
+        }
+
+        for peer in 0..4 {
+            let msg = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, peer));
+            log.add_message(msg.clone());
+            if peer < 3 {
+                assert!(log.committed_with_quorum(&msg, 4).is_err());
+            } else {
+                assert!(log.committed_with_quorum(&msg, 4).is_ok());
+            }
+        }
+        assert_eq!(log.stats().cycles, 1);
+    }
+
+    /// Make sure that the fast path predicate requires `Prepare` messages from every node, and
+    /// doesn't need any `Commit` messages
+    #[test]
+    fn prepared_by_all() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 1, get_peer_id(&cfg, 1));
+        log.add_message(msg);
+        let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        log.add_message(msg);
+
+        let commit = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, 1));
+        for peer in 0..4 {
+            let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, peer));
+            log.add_message(msg);
+            if peer < 3 {
+                assert!(log.prepared_by_all(&commit, 3, 4).is_err());
+            } else {
+                assert!(log.prepared_by_all(&commit, 3, 4).is_ok());
+            }
+        }
+        assert!(log.committed(&commit, 3).is_err());
+    }
+
+    /// Make sure that prepared certificates are only made for blocks with a `PrePrepare` and
+    /// `2f + 1` matching `Prepare`s after the given sequence number, and that the highest view wins
+    #[test]
+    fn prepared_certificates() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for &(view, seq, prepares) in &[(0, 1, 4), (0, 2, 3), (0, 3, 2), (1, 2, 3)] {
+            let msg = make_msg(
+                &PbftMessageType::PrePrepare,
+                view,
+                seq,
+                get_peer_id(&cfg, 0),
+            );
+            log.add_message(msg);
+            for peer in 0..prepares {
+                let msg = make_msg(
+                    &PbftMessageType::Prepare,
+                    view,
+                    seq,
+                    get_peer_id(&cfg, peer),
+                );
+                log.add_message(msg);
+            }
+        }
+
+        let certs = log.get_prepared_certificates(1, 3);
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].get_pre_prepare().get_info().get_seq_num(), 2);
+        assert_eq!(certs[0].get_pre_prepare().get_info().get_view(), 1);
+        assert_eq!(certs[0].get_prepare_messages().len(), 3);
+
+        assert_eq!(log.get_prepared_certificates(0, 3).len(), 2);
+    }
+
+    /// Make sure that a seal is only made once there are `2f + 1` matching `Commit`s from
+    /// different nodes, and that verified seals are only taken once their block is valid
+    #[test]
+    fn seals() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for peer in 0..2 {
+            log.add_message(make_msg(
+                &PbftMessageType::Commit,
+                0,
+                5,
+                get_peer_id(&cfg, peer),
+            ));
+        }
+        assert!(log.get_seal(5, 3).is_none());
+
+        log.add_message(make_msg(
+            &PbftMessageType::Commit,
+            0,
+            5,
+            get_peer_id(&cfg, 3),
+        ));
+        let seal = log.get_seal(5, 3).unwrap();
+        assert_eq!(seal.get_version(), SEAL_VERSION);
+        assert_eq!(seal.get_seq_num(), 5);
+        assert_eq!(seal.get_commit_messages().len(), 3);
+        assert!(log.get_seal(4, 3).is_none());
+
+        let block_id = seal.get_block().get_block_id().to_vec();
+        assert!(!log.has_seal(&block_id));
+        log.add_seal(seal.clone());
+        assert!(log.has_seal(&block_id));
+        assert_eq!(log.get_highest_seal_seq_num(), Some(5));
+
+        assert!(log.take_valid_seal(5).is_none());
+        log.mark_seal_valid(&block_id);
+        assert_eq!(log.take_valid_seal(5), Some(seal));
+        assert!(!log.has_seal(&block_id));
+        assert_eq!(log.get_highest_seal_seq_num(), None);
+    }
+
+    /// Make sure that archived seals outlive garbage collection, and that only the most recent
+    /// `seal_history_size` of them are kept
+    #[test]
+    fn seal_history() {
+        let mut cfg = config::mock_config(4);
+        cfg.seal_history_size = 2;
+        let mut log = PbftLog::new(&cfg);
+
+        for seq_num in 1..4 {
+            for peer in 0..3 {
+                log.add_message(make_msg(
+                    &PbftMessageType::Commit,
+                    0,
+                    seq_num,
+                    get_peer_id(&cfg, peer),
+                ));
+            }
+            let seal = log.get_seal(seq_num, 3).unwrap();
+            log.archive_seal(seal);
+        }
+
+        log.move_water_marks(10);
+        assert_eq!(log.get_low_water_mark(), 10);
+        assert!(log.get_seal(1, 3).is_none());
+        assert_eq!(log.get_seal(2, 3).unwrap().get_seq_num(), 2);
+        assert_eq!(log.get_seal(3, 3).unwrap().get_seq_num(), 3);
+    }
+
+    /// Make sure that the `NewView` for a seal's view is only given as evidence if the sealed block
+    /// was proposed in an earlier view
+    #[test]
+    fn view_change_evidence() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for peer in 0..3 {
+            log.add_message(make_msg(
+                &PbftMessageType::Commit,
+                1,
+                5,
+                get_peer_id(&cfg, peer),
+            ));
+        }
+        let seal = log.get_seal(5, 3).unwrap();
+        assert!(log.view_change_evidence(&seal).is_none());
+
+        let new_view = |view, peer| {
+            let mut new_view = PbftNewView::new();
+            new_view.set_info(
+                make_msg(&PbftMessageType::NewView, view, 5, get_peer_id(&cfg, peer)).take_info(),
+            );
+            new_view
+        };
+        log.set_new_view(new_view(1, 1));
+
+        // Proposed in the same view it was committed in
+        log.add_message(make_msg(
+            &PbftMessageType::PrePrepare,
+            1,
+            5,
+            get_peer_id(&cfg, 1),
+        ));
+        assert!(log.view_change_evidence(&seal).is_none());
+
+        // Proposed in an earlier view
+        log.add_message(make_msg(
+            &PbftMessageType::BlockNew,
+            0,
+            5,
+            get_peer_id(&cfg, 0),
+        ));
+        assert_eq!(log.view_change_evidence(&seal), Some(new_view(1, 1)));
+
+        // Only the `NewView` for the seal's own view counts
+        log.set_new_view(new_view(2, 2));
+        assert!(log.view_change_evidence(&seal).is_none());
+    }
+
+    /// Make sure that `ViewChange`s for a view count towards a quorum together, whichever stable
+    /// checkpoints their senders are at, but only once per sender
+    #[test]
+    fn check_view_changes() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let view_change = |view, checkpoint, peer| {
+            let mut vc = PbftViewChange::new();
+            vc.set_info(
+                make_msg(
+                    &PbftMessageType::ViewChange,
+                    view,
+                    checkpoint,
+                    get_peer_id(&cfg, peer),
+                )
+                .take_info(),
+            );
+            vc
+        };
+        log.add_view_change(view_change(1, 100, 0));
+        log.add_view_change(view_change(1, 200, 1));
+        log.add_view_change(view_change(1, 100, 1));
+        log.add_view_change(view_change(2, 200, 2));
+
+        assert!(log.check_view_changes(1, 2).is_ok());
+        assert!(log.check_view_changes(1, 3).is_err());
+        assert!(log.check_view_changes(2, 2).is_err());
+    }
+
     /// Test that sequence number adjustments work as expected
     /// (This is used by secondary nodes to adjust the sequence number of their `BlockNew`, when
     /// they receive a `PrePrepare` from the primary)
@@ -558,6 +1793,248 @@ mod tests {
         let num_updated = log.fix_seq_nums(&PbftMessageType::BlockNew, 1, 0, msg0.get_block());
 
         assert_eq!(num_updated, 1);
+
+        // A BlockNew for a different block is left alone
+        let mut other = make_msg(&PbftMessageType::BlockNew, 0, 0, get_peer_id(&cfg, 1));
+        let mut block = other.get_block().clone();
+        block.set_block_id(b"other".to_vec());
+        other.set_block(block);
+        log.add_message(other.clone());
+
+        let num_updated = log.fix_seq_nums(&PbftMessageType::BlockNew, 2, 0, msg0.get_block());
+        assert_eq!(num_updated, 0);
+        assert_eq!(
+            log.get_messages_of_type(&PbftMessageType::BlockNew, 0, 0),
+            vec![&other]
+        );
+    }
+
+    /// Make sure that the log iterators only yield the messages and blocks they filter for
+    #[test]
+    fn iterators() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for seq in 1..4 {
+            let msg = make_msg(&PbftMessageType::PrePrepare, 0, seq, get_peer_id(&cfg, 0));
+            log.add_message(msg);
+            for peer in 0..4 {
+                let msg = make_msg(&PbftMessageType::Prepare, 1, seq, get_peer_id(&cfg, peer));
+                log.add_message(msg);
+            }
+        }
+
+        assert_eq!(log.messages().count(), 15);
+        assert_eq!(
+            log.messages_of_type(&PbftMessageType::PrePrepare).count(),
+            3
+        );
+        assert_eq!(log.messages_of_type(&PbftMessageType::Commit).count(), 0);
+        assert_eq!(log.messages_in_view(1).count(), 12);
+        assert_eq!(log.messages_in_seq_range(2, 4).count(), 10);
+        assert_eq!(log.messages_in_seq_range(4, 10).count(), 0);
+        assert_eq!(log.blocks().count(), 3);
+        assert_eq!(log.view_changes().count(), 0);
+        assert_eq!(log.backlog().count(), 0);
+        assert_eq!(log.block_backlog().count(), 0);
+    }
+
+    /// Make sure that arrival times are recorded and that phase latencies are only available once
+    /// the corresponding quorums have been reached
+    #[test]
+    fn phase_latencies() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        log.add_message(msg.clone());
+        assert!(log.get_arrival_time(&msg).is_some());
+        assert!(log.get_pre_prepare_to_prepared_latency(1, 0, 3).is_none());
+
+        for peer in 0..3 {
+            let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, peer));
+            log.add_message(msg);
+        }
+        assert!(log.get_pre_prepare_to_prepared_latency(1, 0, 3).is_some());
+        assert!(log.get_prepared_to_committed_latency(1, 0, 3).is_none());
+        assert_eq!(
+            log.get_arrival_offsets(&PbftMessageType::Prepare, 1, 0)
+                .len(),
+            3
+        );
+
+        for peer in 0..3 {
+            let msg = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, peer));
+            log.add_message(msg);
+        }
+        assert!(log.get_prepared_to_committed_latency(1, 0, 3).is_some());
+    }
+
+    /// Make sure that a conflicting message from the same signer is not stored, but is recorded as
+    /// evidence instead
+    #[test]
+    fn equivocation() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let original = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 2));
+        log.add_message(original.clone());
+
+        let mut conflicting = original.clone();
+        conflicting.mut_block().set_block_num(2);
+        log.add_message(conflicting.clone());
+        log.add_message(conflicting.clone());
+
+        assert_eq!(
+            log.get_messages_of_type(&PbftMessageType::Prepare, 1, 0)
+                .len(),
+            1
+        );
+        assert_eq!(log.get_equivocations().len(), 1);
+        assert_eq!(log.get_equivocations()[0].original, original);
+        assert_eq!(log.get_equivocations()[0].conflicting, conflicting);
+
+        // A different trace ID doesn't hide a conflict, and doesn't make a copy a conflict
+        let mut traced = conflicting.clone();
+        traced.mut_info().set_trace_id(vec![1; 16]);
+        log.add_message(traced);
+        let mut traced = original.clone();
+        traced.mut_info().set_trace_id(vec![1; 16]);
+        log.add_message(traced);
+        assert_eq!(
+            log.get_messages_of_type(&PbftMessageType::Prepare, 1, 0)
+                .len(),
+            1
+        );
+        assert_eq!(log.get_equivocations().len(), 2);
+    }
+
+    /// Make sure that the log keeps a limited amount of evidence for each signer, and prunes
+    /// evidence along with the messages it's about
+    #[test]
+    fn equivocation_bounds() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for seq_num in 1..(MAX_EQUIVOCATIONS_PER_SIGNER as u64 + 5) {
+            let original = make_msg(&PbftMessageType::Prepare, 0, seq_num, get_peer_id(&cfg, 2));
+            log.add_message(original.clone());
+            let mut conflicting = original.clone();
+            conflicting.mut_block().set_block_id(vec![0xff]);
+            log.add_message(conflicting);
+        }
+        assert_eq!(log.get_equivocations().len(), MAX_EQUIVOCATIONS_PER_SIGNER);
+        assert_eq!(
+            log.equivocation_count(),
+            MAX_EQUIVOCATIONS_PER_SIGNER as u64
+        );
+        assert_eq!(
+            log.get_equivocations()[0].original.get_info().get_seq_num(),
+            1
+        );
+
+        // Another signer still has room for evidence
+        let original = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, 3));
+        log.add_message(original.clone());
+        let mut conflicting = original.clone();
+        conflicting.mut_block().set_block_id(vec![0xff]);
+        log.add_message(conflicting);
+        let new = log.equivocations_since(MAX_EQUIVOCATIONS_PER_SIGNER as u64);
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].original, original);
+
+        // Evidence below the low water mark is pruned, making room for more
+        log.move_water_marks(5);
+        assert_eq!(
+            log.get_equivocations().len(),
+            MAX_EQUIVOCATIONS_PER_SIGNER - 4
+        );
+        assert!(log.get_equivocations().iter().all(|evidence| evidence
+            .original
+            .get_info()
+            .get_seq_num()
+            >= 5));
+        let seq_num = MAX_EQUIVOCATIONS_PER_SIGNER as u64 + 5;
+        let original = make_msg(&PbftMessageType::Prepare, 0, seq_num, get_peer_id(&cfg, 2));
+        log.add_message(original.clone());
+        let mut conflicting = original.clone();
+        conflicting.mut_block().set_block_id(vec![0xff]);
+        log.add_message(conflicting);
+        assert_eq!(
+            log.get_equivocations().len(),
+            MAX_EQUIVOCATIONS_PER_SIGNER - 3
+        );
+        assert_eq!(
+            log.equivocation_count(),
+            MAX_EQUIVOCATIONS_PER_SIGNER as u64 + 2
+        );
+    }
+
+    /// Make sure that a block's trace ID is taken from its most recent `PrePrepare`
+    #[test]
+    fn trace_ids() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let mut pre_prepare = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        let block_id = pre_prepare.get_block().get_block_id().to_vec();
+        assert_eq!(log.get_trace_id(1, &block_id), None);
+
+        log.add_message(pre_prepare.clone());
+        assert_eq!(log.get_trace_id(1, &block_id), None);
+
+        pre_prepare.mut_info().set_view(1);
+        pre_prepare.mut_info().set_trace_id(vec![1; 16]);
+        log.add_message(pre_prepare.clone());
+        pre_prepare.mut_info().set_view(2);
+        pre_prepare.mut_info().set_trace_id(vec![2; 16]);
+        log.add_message(pre_prepare);
+        assert_eq!(log.get_trace_id(1, &block_id), Some(&[2; 16][..]));
+        assert_eq!(log.get_trace_id(2, &block_id), None);
+        assert_eq!(log.get_trace_id(1, b"another block"), None);
+    }
+
+    /// Make sure that a second block at the same height on top of the same block is recorded as
+    /// a conflict once, and that committing one candidate returns the others
+    #[test]
+    fn block_conflicts() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+        let block = |n: u8| Block {
+            block_id: BlockId::from(vec![n]),
+            previous_id: BlockId::from(vec![n - 1]),
+            block_num: u64::from(n),
+            ..Default::default()
+        };
+
+        let original = block(1);
+        let mut conflicting = block(1);
+        conflicting.block_id = BlockId::from(vec![0xff]);
+
+        assert_eq!(log.add_block_candidate(&original), None);
+        assert_eq!(log.add_block_candidate(&block(2)), None);
+        assert_eq!(
+            log.add_block_candidate(&conflicting),
+            Some(original.block_id.clone())
+        );
+        assert_eq!(
+            log.add_block_candidate(&conflicting),
+            Some(original.block_id.clone())
+        );
+
+        assert_eq!(log.get_block_conflicts().len(), 1);
+        assert_eq!(log.get_block_conflicts()[0].block_num, 1);
+        assert_eq!(log.get_block_conflicts()[0].original, original.block_id);
+        assert_eq!(
+            log.get_block_conflicts()[0].conflicting,
+            conflicting.block_id
+        );
+
+        assert_eq!(
+            log.remove_block_candidates(&conflicting.block_id, 1),
+            vec![original.block_id.clone()]
+        );
+        assert_eq!(log.remove_block_candidates(&block(2).block_id, 2), vec![]);
     }
 
     /// Make sure that the log doesn't start out checkpointing
@@ -567,7 +2044,74 @@ mod tests {
         let log = PbftLog::new(&cfg);
 
         assert_eq!(log.get_latest_checkpoint(), 0);
-        assert!(!log.at_checkpoint());
+        assert!(!log.at_checkpoint(0));
+        assert!(!log.at_checkpoint(cfg.checkpoint_period - 1));
+        assert!(log.at_checkpoint(cfg.checkpoint_period));
+    }
+
+    /// Make sure that a checkpoint only becomes stable with `2f + 1` matching `Checkpoint`
+    /// messages, including this node's own
+    #[test]
+    fn checkpoint_proof() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+        let own_id = Vec::<u8>::from(get_peer_id(&cfg, 3));
+        let block_id = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, 0))
+            .get_block()
+            .get_block_id()
+            .to_vec();
+
+        for peer in 0..3 {
+            let msg = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, peer));
+            log.add_message(msg);
+        }
+
+        // Enough messages, but this node hasn't reached the checkpoint itself
+        assert!(log.get_checkpoint_proof(4, &block_id, &own_id, 3).is_none());
+
+        let msg = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, 3));
+        log.add_message(msg);
+        let proof = log.get_checkpoint_proof(4, &block_id, &own_id, 3).unwrap();
+        assert_eq!(proof.seq_num, 4);
+        assert_eq!(proof.checkpoint_messages.len(), 4);
+
+        // Checkpoints for a different block don't count
+        assert!(log.get_checkpoint_proof(4, b"other", &own_id, 3).is_none());
+    }
+
+    /// Make sure that messages outside of the water marks are not accepted, and that the water
+    /// marks advance with the stable checkpoint
+    #[test]
+    fn water_marks() {
+        let mut cfg = config::mock_config(4);
+        cfg.max_log_size = 10;
+        let mut log = PbftLog::new(&cfg);
+
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 10, get_peer_id(&cfg, 0));
+        log.add_message(msg);
+        assert_eq!(log.messages().count(), 0);
+
+        let msg = make_msg(&PbftMessageType::Checkpoint, 0, 5, get_peer_id(&cfg, 0));
+        log.add_message(msg.clone());
+        log.garbage_collect(PbftStableCheckpoint {
+            seq_num: 5,
+            checkpoint_messages: vec![msg],
+        });
+        assert_eq!(log.get_low_water_mark(), 5);
+        assert_eq!(log.get_high_water_mark(), 15);
+
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 4, get_peer_id(&cfg, 0));
+        log.add_message(msg);
+        assert_eq!(log.messages_of_type(&PbftMessageType::Prepare).count(), 0);
+
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 10, get_peer_id(&cfg, 0));
+        log.add_message(msg);
+        assert_eq!(log.messages_of_type(&PbftMessageType::Prepare).count(), 1);
+
+        // BlockNews that haven't been assigned a sequence number yet are always accepted
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 0, get_peer_id(&cfg, 0));
+        log.add_message(msg);
+        assert_eq!(log.messages_of_type(&PbftMessageType::BlockNew).count(), 1);
     }
 
     /// Make sure that log garbage collection works as expected
@@ -603,7 +2147,14 @@ mod tests {
             log.add_message(msg.clone());
         }
 
-        log.garbage_collect(4, 0);
+        let block_id = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, 0))
+            .get_block()
+            .get_block_id()
+            .to_vec();
+        let own_id = Vec::<u8>::from(get_peer_id(&cfg, 0));
+        let proof = log.get_checkpoint_proof(4, &block_id, &own_id, 3).unwrap();
+        log.garbage_collect(proof);
+        assert_eq!(log.get_latest_checkpoint(), 4);
 
         for old in 1..3 {
             for msg_type in &[
@@ -624,4 +2175,75 @@ mod tests {
             assert_eq!(log.get_messages_of_type(&msg_type, 4, 0).len(), 4);
         }
     }
+
+    /// Make sure that the log's stats show what's left after garbage collection, and count what
+    /// was pruned
+    #[test]
+    fn garbage_collection_stats() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for seq in 1..5 {
+            let mut senders = vec![
+                (PbftMessageType::BlockNew, 1),
+                (PbftMessageType::PrePrepare, 0),
+            ];
+            for peer in 0..4 {
+                senders.push((PbftMessageType::Prepare, peer));
+                senders.push((PbftMessageType::Commit, peer));
+            }
+            for (msg_type, peer) in senders {
+                log.add_message(make_msg(&msg_type, 0, seq, get_peer_id(&cfg, peer)));
+            }
+        }
+        for peer in 0..4 {
+            let msg = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, peer));
+            log.add_message(msg);
+        }
+
+        let block_id = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, 0))
+            .get_block()
+            .get_block_id()
+            .to_vec();
+        let own_id = Vec::<u8>::from(get_peer_id(&cfg, 0));
+        let proof = log.get_checkpoint_proof(4, &block_id, &own_id, 3).unwrap();
+        log.garbage_collect(proof);
+
+        // Only the messages for sequence number 4 are left, and the rest were counted as pruned
+        let stats = log.stats();
+        assert_eq!(stats.messages, 14);
+        assert_eq!(stats.seq_num_range, Some((4, 4)));
+        assert_eq!((stats.low_water_mark, stats.gc_passes), (4, 1));
+        assert_eq!((stats.last_pruned, stats.pruned_total), (30, 30));
+        assert!(stats.bytes > 0);
+    }
+
+    /// Make sure that once the block backlog is full, only the IDs of new blocks are kept, and
+    /// that they are handed back in order as room frees up
+    #[test]
+    fn block_backlog_limit() {
+        let mut cfg = config::mock_config(4);
+        cfg.max_block_backlog = 2;
+        let mut log = PbftLog::new(&cfg);
+        let block = |n: u8| Block {
+            block_id: BlockId::from(vec![n]),
+            ..Default::default()
+        };
+
+        assert!(log.push_block_backlog(block(1)));
+        assert!(log.push_block_backlog(block(2)));
+        assert!(!log.push_block_backlog(block(3)));
+        assert!(!log.push_block_backlog(block(4)));
+        assert_eq!(log.block_backlog().count(), 2);
+        assert!(log.take_deferred_blocks().is_empty());
+
+        assert_eq!(log.pop_block_backlog(), Some(block(1)));
+        assert_eq!(log.take_deferred_blocks(), vec![BlockId::from(vec![3])]);
+        assert!(log.push_block_backlog(block(3)));
+        assert!(log.take_deferred_blocks().is_empty());
+
+        log.pop_block_backlog();
+        log.pop_block_backlog();
+        assert_eq!(log.take_deferred_blocks(), vec![BlockId::from(vec![4])]);
+    }
 }
@@ -16,64 +16,11 @@
  */
 
 //! Message types for PeerMessages
+//!
+//! The types themselves are part of the protocol core (see `protocol`); this module converts them
+//! to and from the names they have on the wire.
 
-use std::fmt;
-
-/// Enum for showing the difference between future messages, present messages, and past messages.
-#[derive(Debug, PartialEq)]
-pub enum PbftHint {
-    /// A future message. The node is not ready to process it yet.
-    FutureMessage,
-
-    /// A past message. It's possible the node may still need it though, so it is added to the log.
-    PastMessage,
-
-    /// A present message. The node is ready to process this message immediately.
-    PresentMessage,
-}
-
-// Messages related to PBFT consensus
-#[derive(Debug, PartialEq, PartialOrd)]
-pub enum PbftMessageType {
-    /// Basic message types for the multicast protocol
-    PrePrepare,
-    Prepare,
-    Commit,
-
-    /// Auxiliary PBFT messages
-    BlockNew,
-    Checkpoint,
-    ViewChange,
-
-    Unset,
-}
-
-impl fmt::Display for PbftMessageType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let txt = match self {
-            PbftMessageType::PrePrepare => "PP",
-            PbftMessageType::Prepare => "Pr",
-            PbftMessageType::Commit => "Co",
-            PbftMessageType::BlockNew => "BN",
-            PbftMessageType::Checkpoint => "CP",
-            PbftMessageType::ViewChange => "VC",
-            PbftMessageType::Unset => "Un",
-        };
-        write!(f, "{}", txt)
-    }
-}
-
-impl PbftMessageType {
-    /// Is the message type a multicast message (`PrePrepare`, `Prepare`, or `Commit`)?
-    pub fn is_multicast(&self) -> bool {
-        match self {
-            PbftMessageType::PrePrepare | PbftMessageType::Prepare | PbftMessageType::Commit => {
-                true
-            }
-            _ => false,
-        }
-    }
-}
+pub use protocol::{PbftHint, PbftMessageType};
 
 impl<'a> From<&'a str> for PbftMessageType {
     fn from(s: &'a str) -> Self {
@@ -83,7 +30,17 @@ impl<'a> From<&'a str> for PbftMessageType {
             "Commit" => PbftMessageType::Commit,
             "BlockNew" => PbftMessageType::BlockNew,
             "ViewChange" => PbftMessageType::ViewChange,
+            "NewView" => PbftMessageType::NewView,
             "Checkpoint" => PbftMessageType::Checkpoint,
+            "Probe" => PbftMessageType::Probe,
+            "ProbeResponse" => PbftMessageType::ProbeResponse,
+            "StateRequest" => PbftMessageType::StateRequest,
+            "StateResponse" => PbftMessageType::StateResponse,
+            "Handoff" => PbftMessageType::Handoff,
+            "Heartbeat" => PbftMessageType::Heartbeat,
+            "RetransmitRequest" => PbftMessageType::RetransmitRequest,
+            "ClockSync" => PbftMessageType::ClockSync,
+            "ClockSyncResponse" => PbftMessageType::ClockSyncResponse,
             _ => {
                 warn!("Unhandled PBFT message type: {}", s);
                 PbftMessageType::Unset
@@ -0,0 +1,1166 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Metrics about the health of consensus, in the Prometheus text format
+//!
+//! The node counts the messages it receives and the view changes it starts. Along with its view,
+//! sequence number, phase, mode, and the size of the network, these are written in the Prometheus
+//! text exposition format. If the engine is given a metrics address, a `MetricsServer` serves them
+//! at `/metrics` (and the node's `NodeStatus` at `/status`, and when recent blocks became final at
+//! `/finality`, or `/finality/<seq_num>` for one block). The server answers requests on a thread
+//! of its own, from a snapshot that the engine's event loop updates every `SNAPSHOT_INTERVAL`, so
+//! a slow client can't hold up consensus. The same metrics can also be sent to InfluxDB by an
+//! `InfluxReporter`, like the validator's own metrics.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use hex;
+use serde_json::Value;
+
+use error::{ErrorCategory, PbftError};
+use finality::FinalityRecord;
+use message_log::{LogStats, PbftLog};
+use peer_stats::PeerStats;
+use state::{PbftMode, PbftPhase, PbftState};
+use status::NodeStatus;
+use timing;
+use view_stats::ViewChangeReason;
+
+const PHASES: [PbftPhase; 6] = [
+    PbftPhase::NotStarted,
+    PbftPhase::PrePreparing,
+    PbftPhase::Preparing,
+    PbftPhase::Checking,
+    PbftPhase::Committing,
+    PbftPhase::Finished,
+];
+
+const MODES: [PbftMode; 4] = [
+    PbftMode::Normal,
+    PbftMode::ViewChanging,
+    PbftMode::Recovering,
+    PbftMode::NonVoting,
+];
+
+/// How long to wait for a client that is slow to send its request or to read the response
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How often the `MetricsServer`'s snapshot of the node is brought up to date
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for a server to accept a POST (a report to InfluxDB, or an alert)
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the commit latency histogram's buckets, in milliseconds
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000, 60000,
+];
+
+/// Counts of what has happened to the node since it started
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Messages received from other nodes, by type
+    pub messages_received: BTreeMap<String, u64>,
+
+    /// View changes this node started, by reason
+    pub view_changes: BTreeMap<String, u64>,
+
+    /// Errors the node ran into, by category and code
+    pub errors: BTreeMap<(ErrorCategory, &'static str), u64>,
+
+    /// How long blocks took to commit, by phase (`total` for the whole time)
+    pub commit_latency: BTreeMap<String, Histogram>,
+
+    /// How many updates from the validator were waiting to be handled, as of the last one taken
+    pub update_queue_depth: u64,
+
+    /// How long updates from the validator waited before the engine took them
+    pub update_queue_time: Histogram,
+
+    /// How many times the event loop stalled, according to the watchdog
+    pub engine_stalls: u64,
+
+    /// How long the event loop's stalls lasted, in all
+    pub engine_stall_time: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Count a message received from another node, whether or not it was handled
+    pub fn count_message(&mut self, msg_type: &str) {
+        *self
+            .messages_received
+            .entry(msg_type.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Count a view change that this node started
+    pub fn count_view_change(&mut self, reason: ViewChangeReason) {
+        *self
+            .view_changes
+            .entry(format!("{:?}", reason))
+            .or_insert(0) += 1;
+    }
+
+    /// Count an error the node ran into
+    pub fn count_error(&mut self, err: &PbftError) {
+        *self.errors.entry((err.category(), err.code())).or_insert(0) += 1;
+    }
+
+    /// Record how long a block took to get through the given phase, or to commit (`total`)
+    pub fn record_latency(&mut self, phase: &str, latency: Duration) {
+        self.commit_latency
+            .entry(phase.to_string())
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Record how many updates from the validator are waiting, and how long the one just taken
+    /// waited (`None` if no update was taken)
+    pub fn record_update_queue(&mut self, depth: usize, queued: Option<Duration>) {
+        self.update_queue_depth = depth as u64;
+        if let Some(queued) = queued {
+            self.update_queue_time.observe(queued);
+        }
+    }
+
+    /// Count a stall of the event loop that the watchdog reported, now that it's over
+    pub fn record_engine_stall(&mut self, stall: Duration) {
+        self.engine_stalls += 1;
+        self.engine_stall_time += stall;
+    }
+}
+
+/// Counts of how many durations fell into each of the `LATENCY_BUCKETS_MS`, from which
+/// percentiles can be estimated
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    /// How many durations fell into each bucket; the last is for durations beyond every bound
+    pub buckets: [u64; 13],
+    pub count: u64,
+    pub sum: Duration,
+    pub max: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let millis = timing::millis(duration);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+        self.max = self.max.max(duration);
+    }
+
+    /// Estimate the given quantile (between 0 and 1) as the upper bound of the bucket it falls in,
+    /// but no more than the longest duration seen; `None` if nothing was observed
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(match LATENCY_BUCKETS_MS.get(bucket) {
+                    Some(&bound) => Duration::from_millis(bound).min(self.max),
+                    None => self.max,
+                });
+            }
+        }
+        Some(self.max)
+    }
+}
+
+// A duration in seconds, as written in metrics
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Write the node's metrics in the Prometheus text exposition format
+pub fn write_metrics<W: Write>(
+    out: &mut W,
+    state: &PbftState,
+    msg_log: &PbftLog,
+) -> io::Result<()> {
+    write_gauge(out, "pbft_view", "The view this node is in", state.view)?;
+    write_gauge(
+        out,
+        "pbft_seq_num",
+        "The sequence number of the block this node is working on",
+        state.seq_num,
+    )?;
+    write_gauge(
+        out,
+        "pbft_is_primary",
+        "Whether this node is the primary of its view",
+        state.is_primary() as u64,
+    )?;
+    write_gauge(
+        out,
+        "pbft_members",
+        "How many nodes are in the network",
+        state.peers().len() as u64,
+    )?;
+    write_gauge(
+        out,
+        "pbft_max_faulty",
+        "How many faulty nodes the network can tolerate",
+        state.f,
+    )?;
+
+    write_header(out, "pbft_phase", "gauge", "The phase this node is in")?;
+    for phase in PHASES.iter() {
+        writeln!(
+            out,
+            "pbft_phase{{phase=\"{:?}\"}} {}",
+            phase,
+            (*phase == state.phase) as u64
+        )?;
+    }
+
+    write_header(out, "pbft_mode", "gauge", "The mode this node is in")?;
+    for mode in MODES.iter() {
+        writeln!(
+            out,
+            "pbft_mode{{mode=\"{:?}\"}} {}",
+            mode,
+            (*mode == state.mode) as u64
+        )?;
+    }
+
+    write_header(
+        out,
+        "pbft_messages_received_total",
+        "counter",
+        "Messages received from other nodes, by type",
+    )?;
+    for (msg_type, count) in &state.metrics.messages_received {
+        writeln!(
+            out,
+            "pbft_messages_received_total{{type=\"{}\"}} {}",
+            msg_type, count
+        )?;
+    }
+
+    write_header(
+        out,
+        "pbft_view_changes_total",
+        "counter",
+        "View changes this node started, by reason",
+    )?;
+    for (reason, count) in &state.metrics.view_changes {
+        writeln!(
+            out,
+            "pbft_view_changes_total{{reason=\"{}\"}} {}",
+            reason, count
+        )?;
+    }
+
+    write_header(
+        out,
+        "pbft_errors_total",
+        "counter",
+        "Errors this node ran into, by category and code",
+    )?;
+    for ((category, code), count) in &state.metrics.errors {
+        writeln!(
+            out,
+            "pbft_errors_total{{category=\"{}\",code=\"{}\"}} {}",
+            category, code, count
+        )?;
+    }
+
+    write_peer_metrics(out, state)?;
+    write_log_metrics(out, &msg_log.stats())?;
+
+    write_header(
+        out,
+        "pbft_commit_latency_seconds",
+        "histogram",
+        "How long blocks took to commit after they were received (phase total), and how long \
+         they spent in each phase",
+    )?;
+    for (phase, histogram) in &state.metrics.commit_latency {
+        write_histogram(
+            out,
+            "pbft_commit_latency_seconds",
+            &format!("phase=\"{}\"", phase),
+            histogram,
+        )?;
+    }
+
+    write_gauge(
+        out,
+        "pbft_update_queue_depth",
+        "Updates from the validator waiting to be handled",
+        state.metrics.update_queue_depth,
+    )?;
+    write_header(
+        out,
+        "pbft_update_queue_seconds",
+        "histogram",
+        "How long updates from the validator waited before they were handled",
+    )?;
+    write_histogram(
+        out,
+        "pbft_update_queue_seconds",
+        "",
+        &state.metrics.update_queue_time,
+    )?;
+
+    write_header(
+        out,
+        "pbft_engine_stalls_total",
+        "counter",
+        "How many times the event loop went longer than the watchdog interval without making \
+         progress",
+    )?;
+    writeln!(
+        out,
+        "pbft_engine_stalls_total {}",
+        state.metrics.engine_stalls
+    )?;
+    write_header(
+        out,
+        "pbft_engine_stall_seconds_total",
+        "counter",
+        "How long the event loop's stalls lasted, in all",
+    )?;
+    writeln!(
+        out,
+        "pbft_engine_stall_seconds_total {}",
+        seconds(state.metrics.engine_stall_time)
+    )?;
+
+    Ok(())
+}
+
+// Write a histogram's cumulative buckets, sum, and count, with the given labels (if any)
+fn write_histogram<W: Write>(
+    out: &mut W,
+    name: &str,
+    labels: &str,
+    histogram: &Histogram,
+) -> io::Result<()> {
+    let separator = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    for (bucket, count) in histogram.buckets.iter().enumerate() {
+        cumulative += count;
+        let bound = match LATENCY_BUCKETS_MS.get(bucket) {
+            Some(&bound) => format!("{}", bound as f64 / 1000.0),
+            None => String::from("+Inf"),
+        };
+        writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"{}\"}} {}",
+            name, labels, separator, bound, cumulative
+        )?;
+    }
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    writeln!(out, "{}_sum{} {}", name, labels, seconds(histogram.sum))?;
+    writeln!(out, "{}_count{} {}", name, labels, histogram.count)
+}
+
+// Write the messages exchanged with each other node, and how long it's been since each was heard
+// from; every member is included, so a node that was never heard from stands out
+fn write_peer_metrics<W: Write>(out: &mut W, state: &PbftState) -> io::Result<()> {
+    let own_peer_id = state.get_own_peer_id();
+    let peers: Vec<(String, Option<&PeerStats>)> = state
+        .peers()
+        .iter()
+        .filter(|peer_id| **peer_id != own_peer_id)
+        .map(|peer_id| {
+            (
+                hex::encode(Vec::<u8>::from(peer_id.clone())),
+                state.peer_stats.get(peer_id),
+            )
+        })
+        .collect();
+
+    let counters: [(&str, &str, fn(&PeerStats) -> &BTreeMap<String, u64>); 3] = [
+        (
+            "pbft_peer_messages_sent_total",
+            "Messages sent to each other node, by type",
+            |stats| &stats.sent,
+        ),
+        (
+            "pbft_peer_messages_received_total",
+            "Messages received from each other node, by type",
+            |stats| &stats.received,
+        ),
+        (
+            "pbft_peer_messages_rejected_total",
+            "Messages from each other node that were dropped before being handled, by type",
+            |stats| &stats.rejected,
+        ),
+    ];
+    for &(name, help, counts) in counters.iter() {
+        write_header(out, name, "counter", help)?;
+        for (peer, stats) in &peers {
+            if let Some(stats) = stats {
+                for (msg_type, count) in counts(stats) {
+                    writeln!(
+                        out,
+                        "{}{{peer=\"{}\",type=\"{}\"}} {}",
+                        name, peer, msg_type, count
+                    )?;
+                }
+            }
+        }
+    }
+
+    write_header(
+        out,
+        "pbft_peer_last_seen_seconds",
+        "gauge",
+        "How long it's been since each other node was heard from (+Inf if never)",
+    )?;
+    for (peer, stats) in &peers {
+        let since = match stats.and_then(PeerStats::since_last_seen) {
+            Some(since) => format!("{}", seconds(since)),
+            None => String::from("+Inf"),
+        };
+        writeln!(
+            out,
+            "pbft_peer_last_seen_seconds{{peer=\"{}\"}} {}",
+            peer, since
+        )?;
+    }
+
+    write_header(
+        out,
+        "pbft_peer_arrival_lag_seconds",
+        "histogram",
+        "How long after the PrePrepare each other node's votes arrived, by type, for committed \
+         blocks",
+    )?;
+    for (peer, stats) in &peers {
+        if let Some(stats) = stats {
+            for (msg_type, histogram) in &stats.arrival_lag {
+                write_histogram(
+                    out,
+                    "pbft_peer_arrival_lag_seconds",
+                    &format!("peer=\"{}\",type=\"{}\"", peer, msg_type),
+                    histogram,
+                )?;
+            }
+        }
+    }
+
+    write_header(
+        out,
+        "pbft_peer_clock_skew_seconds",
+        "gauge",
+        "How far ahead of this node's clock each other node's clock is, as of the last ClockSync",
+    )?;
+    for (peer, stats) in &peers {
+        if let Some(skew) = stats.and_then(|stats| stats.clock_skew) {
+            writeln!(
+                out,
+                "pbft_peer_clock_skew_seconds{{peer=\"{}\"}} {}",
+                peer,
+                skew.offset_ms as f64 / 1000.0
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// Write the size of the message log and how much garbage collection has removed from it
+fn write_log_metrics<W: Write>(out: &mut W, stats: &LogStats) -> io::Result<()> {
+    write_header(
+        out,
+        "pbft_log_entries",
+        "gauge",
+        "Entries in the message log: messages, backlogged messages, and backlogged blocks",
+    )?;
+    let entries = [
+        ("messages", stats.messages),
+        ("backlog", stats.backlog),
+        ("block_backlog", stats.block_backlog),
+    ];
+    for &(kind, count) in entries.iter() {
+        writeln!(out, "pbft_log_entries{{kind=\"{}\"}} {}", kind, count)?;
+    }
+    write_gauge(
+        out,
+        "pbft_log_bytes",
+        "Estimated size of the message log, going by the encoded size of its contents",
+        stats.bytes,
+    )?;
+
+    write_header(
+        out,
+        "pbft_log_seq_num",
+        "gauge",
+        "The lowest and highest sequence numbers of messages in the log, and its water marks",
+    )?;
+    if let Some((lowest, highest)) = stats.seq_num_range {
+        writeln!(out, "pbft_log_seq_num{{bound=\"lowest\"}} {}", lowest)?;
+        writeln!(out, "pbft_log_seq_num{{bound=\"highest\"}} {}", highest)?;
+    }
+    writeln!(
+        out,
+        "pbft_log_seq_num{{bound=\"low_water_mark\"}} {}",
+        stats.low_water_mark
+    )?;
+    writeln!(
+        out,
+        "pbft_log_seq_num{{bound=\"high_water_mark\"}} {}",
+        stats.high_water_mark
+    )?;
+
+    write_gauge(
+        out,
+        "pbft_log_last_pruned",
+        "Messages removed from the log by the most recent garbage collection",
+        stats.last_pruned,
+    )?;
+    write_header(
+        out,
+        "pbft_log_pruned_total",
+        "counter",
+        "Messages removed from the log by garbage collection",
+    )?;
+    writeln!(out, "pbft_log_pruned_total {}", stats.pruned_total)?;
+    write_header(
+        out,
+        "pbft_log_gc_passes_total",
+        "counter",
+        "Times the message log has been garbage collected",
+    )?;
+    writeln!(out, "pbft_log_gc_passes_total {}", stats.gc_passes)
+}
+
+fn write_header<W: Write>(out: &mut W, name: &str, kind: &str, help: &str) -> io::Result<()> {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+fn write_gauge<W: Write>(out: &mut W, name: &str, help: &str, value: u64) -> io::Result<()> {
+    write_header(out, name, "gauge", help)?;
+    writeln!(out, "{} {}", name, value)
+}
+
+/// Write the node's metrics in the InfluxDB line protocol, tagged with the node's public key. The
+/// points have no timestamps, so InfluxDB uses the time it receives them.
+pub fn write_line_protocol<W: Write>(
+    out: &mut W,
+    state: &PbftState,
+    msg_log: &PbftLog,
+) -> io::Result<()> {
+    let node = hex::encode(Vec::<u8>::from(state.get_own_peer_id()));
+
+    writeln!(
+        out,
+        "pbft,node={} view={}i,seq_num={}i,is_primary={},members={}i,max_faulty={}i,\
+         phase=\"{:?}\",mode=\"{:?}\"",
+        node,
+        state.view,
+        state.seq_num,
+        state.is_primary(),
+        state.peers().len(),
+        state.f,
+        state.phase,
+        state.mode
+    )?;
+    for (msg_type, count) in &state.metrics.messages_received {
+        writeln!(
+            out,
+            "pbft_messages_received,node={},type={} count={}i",
+            node, msg_type, count
+        )?;
+    }
+    for (reason, count) in &state.metrics.view_changes {
+        writeln!(
+            out,
+            "pbft_view_changes,node={},reason={} count={}i",
+            node, reason, count
+        )?;
+    }
+    for ((category, code), count) in &state.metrics.errors {
+        writeln!(
+            out,
+            "pbft_errors,node={},category={},code={} count={}i",
+            node, category, code, count
+        )?;
+    }
+    for (phase, histogram) in &state.metrics.commit_latency {
+        let quantile = |q| histogram.quantile(q).map_or(0.0, seconds);
+        writeln!(
+            out,
+            "pbft_commit_latency,node={},phase={} count={}i,sum={},p50={},p90={},p99={},max={}",
+            node,
+            phase,
+            histogram.count,
+            seconds(histogram.sum),
+            quantile(0.5),
+            quantile(0.9),
+            quantile(0.99),
+            seconds(histogram.max)
+        )?;
+    }
+    let queued = &state.metrics.update_queue_time;
+    writeln!(
+        out,
+        "pbft_update_queue,node={} depth={}i,count={}i,p50={},p99={},max={}",
+        node,
+        state.metrics.update_queue_depth,
+        queued.count,
+        queued.quantile(0.5).map_or(0.0, seconds),
+        queued.quantile(0.99).map_or(0.0, seconds),
+        seconds(queued.max)
+    )?;
+    writeln!(
+        out,
+        "pbft_engine_stalls,node={} count={}i,seconds={}",
+        node,
+        state.metrics.engine_stalls,
+        seconds(state.metrics.engine_stall_time)
+    )?;
+    for peer_id in state.peers() {
+        let stats = match state.peer_stats.get(peer_id) {
+            Some(stats) => stats,
+            None => continue,
+        };
+        let peer = hex::encode(Vec::<u8>::from(peer_id.clone()));
+        for msg_type in stats.msg_types() {
+            let count = |counts: &BTreeMap<String, u64>| counts.get(msg_type).cloned().unwrap_or(0);
+            writeln!(
+                out,
+                "pbft_peer_messages,node={},peer={},type={} sent={}i,received={}i,rejected={}i",
+                node,
+                peer,
+                msg_type,
+                count(&stats.sent),
+                count(&stats.received),
+                count(&stats.rejected)
+            )?;
+        }
+        if let Some(since) = stats.since_last_seen() {
+            writeln!(
+                out,
+                "pbft_peer,node={},peer={} last_seen_seconds={}",
+                node,
+                peer,
+                seconds(since)
+            )?;
+        }
+    }
+
+    let log = msg_log.stats();
+    write!(
+        out,
+        "pbft_log,node={} messages={}i,backlog={}i,block_backlog={}i,bytes={}i,\
+         low_water_mark={}i,high_water_mark={}i,last_pruned={}i,pruned_total={}i,gc_passes={}i",
+        node,
+        log.messages,
+        log.backlog,
+        log.block_backlog,
+        log.bytes,
+        log.low_water_mark,
+        log.high_water_mark,
+        log.last_pruned,
+        log.pruned_total,
+        log.gc_passes
+    )?;
+    if let Some((lowest, highest)) = log.seq_num_range {
+        write!(
+            out,
+            ",lowest_seq_num={}i,highest_seq_num={}i",
+            lowest, highest
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Sends the node's metrics to an InfluxDB database over HTTP
+pub struct InfluxReporter {
+    /// The `host:port` of the InfluxDB server
+    address: String,
+
+    /// The database to write to
+    database: String,
+}
+
+impl InfluxReporter {
+    pub fn new(address: String, database: String) -> Self {
+        InfluxReporter { address, database }
+    }
+
+    /// Send the node's current metrics. The request is made from a separate thread, so that a
+    /// slow or unreachable server doesn't hold up the node; failures are only logged.
+    pub fn report(&self, state: &PbftState, msg_log: &PbftLog) {
+        let mut body = Vec::new();
+        if let Err(err) = write_line_protocol(&mut body, state, msg_log) {
+            warn!("Couldn't write metrics for InfluxDB: {}", err);
+            return;
+        }
+
+        let address = self.address.clone();
+        let database = self.database.clone();
+        thread::spawn(move || {
+            let path = format!("/write?db={}", database);
+            if let Err(err) = http_post(&address, &path, "text/plain", &body) {
+                warn!("Couldn't send metrics to InfluxDB at {}: {}", address, err);
+            }
+        });
+    }
+}
+
+/// Send the body to the given path of the HTTP server at `address` (`host:port`), and wait for it
+/// to be accepted; any response other than a 2xx is an error
+pub fn http_post(address: &str, path: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address found"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, POST_TIMEOUT)?;
+    stream.set_read_timeout(Some(POST_TIMEOUT))?;
+    stream.set_write_timeout(Some(POST_TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: {}\r\n\
+         Content-Length: {}\r\n\r\n",
+        path,
+        address,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or("");
+    if status
+        .split_whitespace()
+        .nth(1)
+        .map_or(false, |code| code.starts_with('2'))
+    {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unexpected response: {}", status),
+        ))
+    }
+}
+
+/// Serves the node's metrics over HTTP at `/metrics`, its health at `/status`, and when recent
+/// blocks became final at `/finality`
+pub struct MetricsServer {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+
+    /// When the snapshot was last brought up to date (`None` if it never was)
+    last_update: Option<Instant>,
+}
+
+// What the engine and the server's thread share
+#[derive(Default)]
+struct Shared {
+    /// The responses to give, as of the last update
+    snapshot: Mutex<Snapshot>,
+
+    /// Set to stop the server's thread
+    stop: AtomicBool,
+}
+
+// The node as it was when the snapshot was taken, ready to be served
+#[derive(Default)]
+struct Snapshot {
+    metrics: Vec<u8>,
+    status: String,
+    finality: Vec<FinalityRecord>,
+}
+
+impl MetricsServer {
+    /// Start listening for metrics requests on the given address, in a thread of its own, so that
+    /// a slow client never holds up the engine. Until the first call to `update`, there's nothing
+    /// to serve.
+    pub fn bind(addr: &SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+
+        let served = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name(String::from("pbft-metrics"))
+            .spawn(move || serve(&listener, &served))?;
+
+        Ok(MetricsServer {
+            shared,
+            local_addr,
+            thread: Some(thread),
+            last_update: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Take a new snapshot of the node for the server to answer requests from, if the last one is
+    /// more than `SNAPSHOT_INTERVAL` old
+    pub fn update(&mut self, state: &PbftState, msg_log: &PbftLog) {
+        let now = timing::now();
+        if self
+            .last_update
+            .map_or(false, |last| now - last < SNAPSHOT_INTERVAL)
+        {
+            return;
+        }
+        self.last_update = Some(now);
+
+        let mut metrics = Vec::new();
+        if let Err(err) = write_metrics(&mut metrics, state, msg_log) {
+            warn!("Couldn't write metrics: {}", err);
+            return;
+        }
+        let snapshot = Snapshot {
+            metrics,
+            status: NodeStatus::new(state).to_json(),
+            finality: state.finality.recent().cloned().collect(),
+        };
+        *self
+            .shared
+            .snapshot
+            .lock()
+            .expect("Metrics snapshot lock poisoned") = snapshot;
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            // The thread is waiting for a connection, so give it one to notice it should stop
+            let _ = TcpStream::connect_timeout(&self.local_addr, CLIENT_TIMEOUT);
+            let _ = thread.join();
+        }
+    }
+}
+
+// Answer metrics requests, one at a time, until told to stop
+fn serve(listener: &TcpListener, shared: &Shared) {
+    for stream in listener.incoming() {
+        if shared.stop.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = respond(stream, shared) {
+                    debug!("Couldn't answer metrics request: {}", err);
+                }
+            }
+            Err(err) => warn!("Couldn't accept metrics connection: {}", err),
+        }
+    }
+}
+
+// Answer a single request for the node's metrics (`/metrics`), health (`/status`), or blocks'
+// finality (`/finality`), or with an error for anything else. The node's full state isn't served
+// here, since anyone who can reach the port can ask; it's only given to admin clients.
+fn respond(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // The request may come in pieces; only its first line matters, but it's all read before
+    // answering, so the client isn't cut off while it's still sending
+    let mut request = [0; 1024];
+    let mut len = 0;
+    while len < request.len() && !request[..len].windows(4).any(|end| end == b"\r\n\r\n") {
+        match stream.read(&mut request[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let request = String::from_utf8_lossy(&request[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let mut body = Vec::new();
+    let (status, content_type) = {
+        let snapshot = shared
+            .snapshot
+            .lock()
+            .expect("Metrics snapshot lock poisoned");
+        match path {
+            "/metrics" if request.starts_with("GET ") => {
+                body.extend_from_slice(&snapshot.metrics);
+                ("200 OK", "text/plain; version=0.0.4")
+            }
+            "/status" if request.starts_with("GET ") => {
+                writeln!(body, "{}", snapshot.status)?;
+                ("200 OK", "application/json")
+            }
+            "/finality" if request.starts_with("GET ") => {
+                let records = snapshot
+                    .finality
+                    .iter()
+                    .map(FinalityRecord::to_value)
+                    .collect();
+                writeln!(body, "{}", Value::Array(records))?;
+                ("200 OK", "application/json")
+            }
+            _ if path.starts_with("/finality/") && request.starts_with("GET ") => {
+                let record = path["/finality/".len()..].parse().ok().and_then(|seq_num| {
+                    snapshot
+                        .finality
+                        .iter()
+                        .rev()
+                        .find(|record| record.seq_num == seq_num)
+                });
+                match record {
+                    Some(record) => {
+                        writeln!(body, "{}", record.to_json())?;
+                        ("200 OK", "application/json")
+                    }
+                    None => {
+                        body.extend_from_slice(b"Not found\n");
+                        ("404 Not Found", "text/plain")
+                    }
+                }
+            }
+            _ => {
+                body.extend_from_slice(b"Not found\n");
+                ("404 Not Found", "text/plain")
+            }
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use sawtooth_sdk::consensus::engine::BlockId;
+
+    /// Make sure that the node's state and counts are written as Prometheus metrics
+    #[test]
+    fn metrics() {
+        let mut state = PbftState::new(1, &mock_config(4));
+        state.view = 5;
+        state.seq_num = 12;
+        state.phase = PbftPhase::Preparing;
+        state.upgrade_role();
+        state.metrics.count_message("Prepare");
+        state.metrics.count_message("Prepare");
+        state
+            .metrics
+            .count_view_change(ViewChangeReason::CommitTimeout);
+        state
+            .metrics
+            .record_latency("total", Duration::from_millis(300));
+        state
+            .metrics
+            .record_update_queue(3, Some(Duration::from_millis(20)));
+        state
+            .metrics
+            .record_engine_stall(Duration::from_millis(1500));
+        let peer_id = state.peers()[3].clone();
+        state
+            .metrics
+            .count_error(&PbftError::InvalidSignature.with_context(Some(5), Some(12), &peer_id));
+        let peer_id = state.peers()[2].clone();
+        let peer = hex::encode(Vec::<u8>::from(peer_id.clone()));
+        let mut peer_stats = PeerStats::default();
+        peer_stats.count_sent("Commit");
+        peer_stats.count_rejected("Prepare");
+        peer_stats.record_arrival_lag("Commit", Duration::from_millis(40));
+        state.peer_stats.insert(peer_id, peer_stats);
+        let mut msg_log = PbftLog::new(&mock_config(4));
+        msg_log.move_water_marks(10);
+
+        let mut out = Vec::new();
+        write_metrics(&mut out, &state, &msg_log).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("# TYPE pbft_view gauge\npbft_view 5\n"));
+        assert!(out.contains("pbft_seq_num 12\n"));
+        assert!(out.contains("pbft_is_primary 1\n"));
+        assert!(out.contains("pbft_members 4\n"));
+        assert!(out.contains("pbft_max_faulty 1\n"));
+        assert!(out.contains("pbft_phase{phase=\"Preparing\"} 1\n"));
+        assert!(out.contains("pbft_phase{phase=\"Committing\"} 0\n"));
+        assert!(out.contains("pbft_mode{mode=\"Normal\"} 1\n"));
+        assert!(out.contains("pbft_messages_received_total{type=\"Prepare\"} 2\n"));
+        assert!(out.contains("pbft_view_changes_total{reason=\"CommitTimeout\"} 1\n"));
+        assert!(out.contains("pbft_errors_total{category=\"message\",code=\"E103\"} 1\n"));
+        assert!(out.contains("pbft_commit_latency_seconds_bucket{phase=\"total\",le=\"0.25\"} 0\n"));
+        assert!(out.contains("pbft_commit_latency_seconds_bucket{phase=\"total\",le=\"0.5\"} 1\n"));
+        assert!(out.contains("pbft_commit_latency_seconds_bucket{phase=\"total\",le=\"+Inf\"} 1\n"));
+        assert!(out.contains("pbft_commit_latency_seconds_sum{phase=\"total\"} 0.3\n"));
+        assert!(out.contains("pbft_commit_latency_seconds_count{phase=\"total\"} 1\n"));
+        assert!(out.contains(&format!(
+            "pbft_peer_messages_sent_total{{peer=\"{}\",type=\"Commit\"}} 1\n",
+            peer
+        )));
+        assert!(out.contains(&format!(
+            "pbft_peer_messages_rejected_total{{peer=\"{}\",type=\"Prepare\"}} 1\n",
+            peer
+        )));
+        assert!(out.contains(&format!(
+            "pbft_peer_last_seen_seconds{{peer=\"{}\"}} +Inf\n",
+            peer
+        )));
+        assert!(out.contains(&format!(
+            "pbft_peer_arrival_lag_seconds_bucket{{peer=\"{}\",type=\"Commit\",le=\"0.05\"}} 1\n",
+            peer
+        )));
+        assert!(out.contains("pbft_log_entries{kind=\"messages\"} 0\n"));
+        assert!(out.contains("pbft_log_seq_num{bound=\"low_water_mark\"} 10\n"));
+        assert!(!out.contains("pbft_log_seq_num{bound=\"lowest\"}"));
+        assert!(out.contains("pbft_log_gc_passes_total 1\n"));
+        assert!(out.contains("pbft_update_queue_depth 3\n"));
+        assert!(out.contains("pbft_update_queue_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("pbft_update_queue_seconds_count 1\n"));
+        assert!(out.contains("pbft_engine_stalls_total 1\n"));
+        assert!(out.contains("pbft_engine_stall_seconds_total 1.5\n"));
+        // This node isn't one of its own peers
+        let own_peer = hex::encode(Vec::<u8>::from(state.get_own_peer_id()));
+        assert!(!out.contains(&format!("{{peer=\"{}\"", own_peer)));
+    }
+
+    /// Make sure that quantiles are estimated from the bucket they fall in, and don't exceed the
+    /// longest duration seen
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for _ in 0..90 {
+            histogram.observe(Duration::from_millis(80));
+        }
+        for _ in 0..9 {
+            histogram.observe(Duration::from_millis(700));
+        }
+        histogram.observe(Duration::from_secs(90));
+
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.quantile(0.9), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_millis(1000)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(90)));
+
+        // A quantile in a bucket's range is no more than the longest duration
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3000));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(3000)));
+    }
+
+    /// Make sure that the server answers requests for `/metrics`, `/status`, and `/finality` from
+    /// the latest snapshot, and only those; the node's full state is only for admin clients
+    #[test]
+    fn metrics_server() {
+        let mut state = PbftState::new(0, &mock_config(4));
+        state
+            .finality
+            .record(1, BlockId::from(vec![1]), 0, None, false, vec![]);
+        let msg_log = PbftLog::new(&mock_config(4));
+        let mut server = MetricsServer::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        server.update(&state, &msg_log);
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("pbft_view 0\n"));
+        let response = get("/status");
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.contains("\"health\":\"degraded\""));
+        assert!(get("/state").starts_with("HTTP/1.0 404 Not Found\r\n"));
+        assert!(get("/finality").contains("[{\"block_id\":\"01\""));
+        assert!(get("/finality/1").contains("\"seq_num\":1"));
+        assert!(get("/finality/2").starts_with("HTTP/1.0 404 Not Found\r\n"));
+        assert!(get("/other").starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        // A client that never sends its request doesn't keep others from being answered
+        let _idle = TcpStream::connect(addr).unwrap();
+        assert!(get("/metrics").starts_with("HTTP/1.0 200 OK\r\n"));
+
+        // Updates within the snapshot interval are skipped
+        state.view = 3;
+        server.update(&state, &msg_log);
+        assert!(get("/metrics").contains("pbft_view 0\n"));
+    }
+
+    /// Make sure that metrics are sent to InfluxDB's write endpoint in the line protocol
+    #[test]
+    fn influx_reporter() {
+        let mut state = PbftState::new(0, &mock_config(4));
+        state.metrics.count_message("Commit");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reporter = InfluxReporter::new(
+            listener.local_addr().unwrap().to_string(),
+            String::from("metrics"),
+        );
+
+        reporter.report(&state, &PbftLog::new(&mock_config(4)));
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![0; 4096];
+        let mut len = 0;
+        while !String::from_utf8_lossy(&request[..len]).contains("count=1i") {
+            len += stream.read(&mut request[len..]).unwrap();
+        }
+        stream
+            .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+            .unwrap();
+
+        let request = String::from_utf8_lossy(&request[..len]);
+        assert!(request.starts_with("POST /write?db=metrics HTTP/1.0\r\n"));
+        assert!(request.contains(" view=0i,seq_num=0i,is_primary=true,members=4i,max_faulty=1i,"));
+        assert!(request.contains(",type=Commit count=1i\n"));
+    }
+}
@@ -0,0 +1,293 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Running consensus for more than one network in the same process
+//!
+//! Each network (a chain, or anything else the engine orders blocks for) gets an engine of its
+//! own, with its own state, message log, and on-chain settings, on a thread of its own. `Networks`
+//! keeps track of them by network ID, so a process that gets the updates for all of its networks
+//! on one connection can register an engine for each network and route each update to the right
+//! one. The `sawtooth-pbft` binary connects to each network's validator separately instead (see
+//! `--network`), so each network's updates already come to its own engine.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{self, Sender};
+
+use sawtooth_sdk::consensus::engine::{StartupState, Update};
+use sawtooth_sdk::consensus::service::Service;
+
+use engine::{EngineHandle, PbftEngine};
+
+/// Errors in registering networks and routing updates to them
+#[derive(Debug)]
+pub enum NetworkError {
+    /// There's already an engine for the network (ID)
+    AlreadyRegistered(String),
+
+    /// There's no engine for the network (ID)
+    Unknown(String),
+
+    /// The network's engine stopped, so it can't take updates (ID)
+    Stopped(String),
+
+    /// The network's engine couldn't be started (ID, error)
+    Spawn(String, io::Error),
+}
+
+impl Error for NetworkError {
+    fn description(&self) -> &str {
+        use self::NetworkError::*;
+        match self {
+            AlreadyRegistered(_) => "AlreadyRegistered",
+            Unknown(_) => "Unknown",
+            Stopped(_) => "Stopped",
+            Spawn(_, _) => "Spawn",
+        }
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::AlreadyRegistered(id) => {
+                write!(f, "Network {} already has an engine", id)
+            }
+            NetworkError::Unknown(id) => write!(f, "No engine for network {}", id),
+            NetworkError::Stopped(id) => write!(f, "The engine for network {} stopped", id),
+            NetworkError::Spawn(id, err) => {
+                write!(f, "Couldn't start the engine for network {}: {}", id, err)
+            }
+        }
+    }
+}
+
+// A registered network's engine, and where to send its updates
+struct Network {
+    updates: Sender<Update>,
+    handle: EngineHandle,
+}
+
+/// The engines for each of the networks a process runs consensus for, by network ID
+#[derive(Default)]
+pub struct Networks {
+    networks: HashMap<String, Network>,
+}
+
+impl Networks {
+    pub fn new() -> Self {
+        Networks::default()
+    }
+
+    /// Start the given engine for the network with the given ID, with a `service` that makes its
+    /// calls to that network's validator, and the `startup_state` that validator reported
+    pub fn register(
+        &mut self,
+        id: &str,
+        engine: PbftEngine,
+        service: Box<Service + Send>,
+        startup_state: StartupState,
+    ) -> Result<(), NetworkError> {
+        if self.networks.contains_key(id) {
+            return Err(NetworkError::AlreadyRegistered(String::from(id)));
+        }
+
+        let (updates, receiver) = mpsc::channel();
+        let handle = engine
+            .with_network(String::from(id))
+            .spawn(receiver, service, startup_state)
+            .map_err(|err| NetworkError::Spawn(String::from(id), err))?;
+        info!("Registered network {}", id);
+        self.networks
+            .insert(String::from(id), Network { updates, handle });
+        Ok(())
+    }
+
+    /// Send an update from a network's validator to that network's engine
+    pub fn route(&self, id: &str, update: Update) -> Result<(), NetworkError> {
+        let network = self
+            .networks
+            .get(id)
+            .ok_or_else(|| NetworkError::Unknown(String::from(id)))?;
+        network
+            .updates
+            .send(update)
+            .map_err(|_| NetworkError::Stopped(String::from(id)))
+    }
+
+    /// Shut down the engine for the network with the given ID, and wait for it to stop
+    pub fn deregister(&mut self, id: &str) -> Result<(), NetworkError> {
+        let network = self
+            .networks
+            .remove(id)
+            .ok_or_else(|| NetworkError::Unknown(String::from(id)))?;
+        if network.handle.shutdown().is_err() {
+            error!("The engine for network {} panicked", id);
+        }
+        info!("Deregistered network {}", id);
+        Ok(())
+    }
+
+    /// The IDs of the registered networks, in order
+    pub fn ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.networks.keys().map(String::as_str).collect();
+        ids.sort();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sawtooth_sdk::consensus::engine::{
+        Block, BlockId, Error as ServiceError, PeerId, PeerInfo,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A validator for a network of four nodes, that doesn't have any blocks to publish
+    struct NetworkService {
+        head: Block,
+    }
+
+    impl Service for NetworkService {
+        fn send_to(&mut self, _: &PeerId, _: &str, _: Vec<u8>) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn broadcast(&mut self, _: &str, _: Vec<u8>) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn initialize_block(&mut self, _: Option<BlockId>) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn summarize_block(&mut self) -> Result<Vec<u8>, ServiceError> {
+            Err(ServiceError::BlockNotReady)
+        }
+        fn finalize_block(&mut self, _: Vec<u8>) -> Result<BlockId, ServiceError> {
+            Err(ServiceError::BlockNotReady)
+        }
+        fn cancel_block(&mut self) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn check_blocks(&mut self, _: Vec<BlockId>) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn commit_block(&mut self, _: BlockId) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn ignore_block(&mut self, _: BlockId) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn fail_block(&mut self, _: BlockId) -> Result<(), ServiceError> {
+            Ok(())
+        }
+        fn get_blocks(&mut self, _: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, ServiceError> {
+            Ok(HashMap::new())
+        }
+        fn get_chain_head(&mut self) -> Result<Block, ServiceError> {
+            Ok(self.head.clone())
+        }
+        fn get_settings(
+            &mut self,
+            _: BlockId,
+            _: Vec<String>,
+        ) -> Result<HashMap<String, String>, ServiceError> {
+            let mut settings = HashMap::new();
+            settings.insert(
+                String::from("sawtooth.consensus.pbft.peers"),
+                String::from(r#"["aa", "bb", "cc", "dd"]"#),
+            );
+            Ok(settings)
+        }
+        fn get_state(
+            &mut self,
+            _: BlockId,
+            _: Vec<String>,
+        ) -> Result<HashMap<String, Vec<u8>>, ServiceError> {
+            Ok(HashMap::new())
+        }
+    }
+
+    fn register(networks: &mut Networks, id: &str) -> Result<(), NetworkError> {
+        let head = Block {
+            block_id: BlockId::from(vec![1]),
+            previous_id: BlockId::from(vec![0]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 1,
+            payload: vec![],
+            summary: vec![],
+        };
+        let startup_state = StartupState {
+            chain_head: head.clone(),
+            peers: vec![],
+            local_peer_info: PeerInfo {
+                peer_id: PeerId::from(vec![0xaa]),
+            },
+        };
+        networks.register(
+            id,
+            PbftEngine::new(None, None),
+            Box::new(NetworkService { head }),
+            startup_state,
+        )
+    }
+
+    /// Make sure that each network gets an engine of its own, that updates only go to the
+    /// network they're for, and that networks can come and go without affecting each other
+    #[test]
+    fn networks() {
+        let mut networks = Networks::new();
+        register(&mut networks, "beta").unwrap();
+        register(&mut networks, "alpha").unwrap();
+        match register(&mut networks, "alpha") {
+            Err(NetworkError::AlreadyRegistered(ref id)) if id == "alpha" => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(networks.ids(), vec!["alpha", "beta"]);
+
+        match networks.route("gamma", Update::Shutdown) {
+            Err(NetworkError::Unknown(ref id)) if id == "gamma" => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Shutting down one network's engine leaves the other one running
+        networks.route("alpha", Update::Shutdown).unwrap();
+        let start = Instant::now();
+        let peer = || {
+            Update::PeerConnected(PeerInfo {
+                peer_id: PeerId::from(vec![0xbb]),
+            })
+        };
+        loop {
+            match networks.route("alpha", peer()) {
+                Ok(()) if start.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(NetworkError::Stopped(ref id)) if id == "alpha" => break,
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+        networks.route("beta", peer()).unwrap();
+
+        networks.deregister("alpha").unwrap();
+        networks.deregister("beta").unwrap();
+        assert!(networks.ids().is_empty());
+        assert!(networks.deregister("beta").is_err());
+    }
+}
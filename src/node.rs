@@ -25,41 +25,127 @@ use protobuf::{Message, ProtobufError};
 
 use std::convert::From;
 use std::error::Error;
+use std::io;
+use std::mem;
 
-use sawtooth_sdk::consensus::engine::{Block, BlockId, Error as EngineError, PeerMessage};
-use sawtooth_sdk::consensus::service::Service;
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error as EngineError, PeerId, PeerMessage};
+use tracing;
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protos::pbft_message::{
+    PbftBlock, PbftClockSync, PbftMessage, PbftMessageInfo, PbftNewView, PbftRetransmitRequest,
+    PbftSeal, PbftSignedMessage, PbftStateRequest, PbftStateResponse, PbftViewChange,
+    PbftViewChangeProgress,
+};
 
-use config::PbftConfig;
+use authentication::MessageSigner;
+use config::{self, PbftConfig};
+use consensus_service::ConsensusService;
+use crash_dump;
 use error::PbftError;
+use events::ConsensusEvent;
+#[cfg(feature = "test-faults")]
+use faults::{DelayedMessage, FaultInjector};
 use handlers;
+use incidents::Incident;
+use log_throttle::LogThrottle;
+use message_extensions::parse_msg_info;
 use message_log::{PbftLog, PbftStableCheckpoint};
 use message_type::{PbftHint, PbftMessageType};
+use peer_stats::{ClockSkew, PeerStats};
+use pipeline::{self, DecodeError, DecodePool, DecodedMessage};
+use quarantine;
 use state::{PbftMode, PbftPhase, PbftState, WorkingBlockOption};
+use status::{self, Health, NodeStatus};
+use storage::ViewChangeStorage;
+use timing::{self, Timeout};
+use validation::Rejection;
+use view_stats::ViewChangeReason;
+use votes::SentVotes;
 
-/// Contains all of the components for operating a PBFT node.
-pub struct PbftNode {
+/// The most seals that are sent in one `StateResponse`
+const MAX_SEALS_PER_RESPONSE: u64 = 100;
+
+/// Contains all of the components for operating a PBFT node. The node works with any
+/// `ConsensusService`; unless it's given a particular one, it's a `PbftNode<ConsensusService>`.
+pub struct PbftNode<S: ConsensusService + ?Sized = ConsensusService> {
     /// Used for interactions with the validator
-    pub service: Box<Service>,
+    pub service: Box<S>,
 
     /// Storage of state information
     pub state: PbftState,
 
     /// Messages this node has received
     pub msg_log: PbftLog,
+
+    /// Signs the messages this node sends, if message authentication is enabled
+    pub signer: Option<MessageSigner>,
+
+    /// Where this node saves its view change progress, so it can resume after a restart
+    pub storage: Option<ViewChangeStorage>,
+
+    /// Keeps warnings that can repeat for each peer, like failures to send to it, from flooding
+    /// the log
+    log_throttle: LogThrottle<(&'static str, PeerId)>,
+
+    /// Makes this node misbehave on purpose, for testing
+    #[cfg(feature = "test-faults")]
+    pub faults: Option<FaultInjector>,
+
+    /// Whether this node is part of a simulated network (see `simulation`), so its messages should
+    /// actually be sent
+    #[cfg(test)]
+    pub simulated: bool,
 }
 
-impl PbftNode {
+impl<S: ConsensusService + ?Sized> PbftNode<S> {
     /// Construct a new PBFT node.
     /// After the node is created, if the node is primary, it initializes a new block on the chain.
-    pub fn new(id: u64, config: &PbftConfig, service: Box<Service>) -> Self {
+    pub fn new(id: u64, config: &PbftConfig, service: Box<S>) -> Self {
+        PbftNode::with_state(PbftState::new(id, config), config, service)
+    }
+
+    /// Construct a node that isn't a member of the network. It verifies the members' seals and
+    /// commits the blocks they agree on, and follows them into new views, but doesn't vote,
+    /// publish blocks, or take part in view changes until it's added to the membership.
+    pub fn new_observer(
+        own_peer_id: PeerId,
+        config: &PbftConfig,
+        service: Box<S>,
+    ) -> Result<Self, PbftError> {
+        let state = PbftState::new_observer(own_peer_id, config)?;
+        Ok(PbftNode::with_state(state, config, service))
+    }
+
+    fn with_state(state: PbftState, config: &PbftConfig, service: Box<S>) -> Self {
         let mut n = PbftNode {
-            state: PbftState::new(id, config),
+            state,
             service,
             msg_log: PbftLog::new(config),
+            signer: None,
+            storage: None,
+            log_throttle: LogThrottle::new(),
+            #[cfg(feature = "test-faults")]
+            faults: None,
+            #[cfg(test)]
+            simulated: false,
         };
 
+        match n.service.get_chain_head() {
+            Ok(head) => {
+                n.state.chain_head = head.block_id;
+                n.state.chain_head_num = head.block_num;
+            }
+            Err(err) => error!("Couldn't get chain head: {}", err),
+        }
+
+        if n.state.single_node {
+            warn!(
+                "{}: This is the only node in the network; blocks will be committed without \
+                 consensus",
+                n.state
+            );
+        }
+
         // Primary initializes a block
         if n.state.is_primary() {
             debug!("{}: Initializing block", n.state);
@@ -72,6 +158,330 @@ impl PbftNode {
 
     // ---------- Methods for handling Updates from the validator ----------
 
+    /// Handle a peer message as it arrives from the validator. Before the message is handled, it
+    /// is dropped if the node that sent it is over its rate limit for the message's type, if it's
+    /// too large or of an unknown type, or if it's missing required fields. If message
+    /// authentication is enabled, the message is unwrapped and its signature checked, and it is
+    /// dropped if it wasn't signed by the node it says it's from. Stale and replayed messages are
+    /// dropped too. Dropped messages are counted against the node that sent them.
+    pub fn on_network_message(
+        &mut self,
+        msg: &PeerMessage,
+        sender_id: &PeerId,
+    ) -> Result<(), PbftError> {
+        if !self.admit_message(msg, sender_id) {
+            return Ok(());
+        }
+        let decoded = pipeline::decode(msg, self.state.max_message_size, self.signer.is_some());
+        self.on_decoded_message(msg, decoded, sender_id)
+    }
+
+    /// Handle a batch of peer messages, in the order they arrived from the validator, decoding
+    /// them on the given pool. The messages are handled as if each one had been passed to
+    /// `on_network_message` in turn, except that they're all admitted before any of them is
+    /// handled; a node that's quarantined partway through the batch still has the rest of its
+    /// messages dropped. Returns the result of handling each message, with the node that sent it.
+    pub fn on_network_messages(
+        &mut self,
+        messages: Vec<(PeerMessage, PeerId)>,
+        pool: &DecodePool,
+    ) -> Vec<(PeerId, Result<(), PbftError>)> {
+        let mut senders = Vec::with_capacity(messages.len());
+        let mut admitted = Vec::with_capacity(messages.len());
+        for (msg, sender_id) in messages {
+            if self.admit_message(&msg, &sender_id) {
+                admitted.push(msg);
+                senders.push(sender_id);
+            }
+        }
+
+        let decoded = pool.decode_all(admitted, self.state.max_message_size, self.signer.is_some());
+
+        senders
+            .into_iter()
+            .zip(decoded)
+            .map(|(sender_id, (msg, decoded))| {
+                let res = if self.state.quarantine.is_quarantined(&sender_id) {
+                    self.drop_quarantined(&msg, &sender_id);
+                    Ok(())
+                } else {
+                    self.on_decoded_message(&msg, decoded, &sender_id)
+                };
+                (sender_id, res)
+            })
+            .collect()
+    }
+
+    // Count a message as it arrives, and decide whether it's worth decoding: it isn't if the node
+    // that sent it is quarantined, or over its rate limit
+    fn admit_message(&mut self, msg: &PeerMessage, sender_id: &PeerId) -> bool {
+        self.state.metrics.count_message(&msg.message_type);
+        self.state
+            .peer_stats
+            .entry(sender_id.clone())
+            .or_default()
+            .count_received(&msg.message_type);
+
+        if self.state.quarantine.is_quarantined(sender_id) {
+            self.drop_quarantined(msg, sender_id);
+            return false;
+        }
+
+        if !self.state.rate_limiter.allow(sender_id, &msg.message_type) {
+            debug!(
+                "{}: Dropping {} from {:?}; over rate limit",
+                self.state, msg.message_type, sender_id
+            );
+            self.count_rejection(sender_id, &msg.message_type, Rejection::RateLimited);
+            return false;
+        }
+
+        true
+    }
+
+    fn drop_quarantined(&mut self, msg: &PeerMessage, sender_id: &PeerId) {
+        trace!(
+            "{}: Dropping {} from {:?}; quarantined",
+            self.state,
+            msg.message_type,
+            sender_id
+        );
+        self.count_rejection(sender_id, &msg.message_type, Rejection::Quarantined);
+    }
+
+    // A message for more than one sequence number past the last block this node committed means
+    // that the sender committed blocks that this node hasn't. The peers' messages for the first
+    // one are asked for again, since the `Commit`s are enough to commit it, whatever view they
+    // were sent in; if more than one was missed, or the messages don't arrive in time, the sender
+    // is also asked for seals for the missed blocks. Returns whether this node is recovering,
+    // because the message was past the high water mark.
+    fn catch_up_to(&mut self, info: &PbftMessageInfo) -> Result<bool, PbftError> {
+        let seq_num = info.get_seq_num();
+        let committed = self.state.committed_seq_num();
+        if seq_num > committed + 1 {
+            // The `Commit`s may have arrived already, before the node had prepared the block
+            if self.commit_from_log(committed + 1)? && seq_num == committed + 2 {
+                return Ok(false);
+            }
+            let repairing = self.request_retransmission(committed + 1)? && seq_num == committed + 2;
+            if !repairing {
+                self.request_state(seq_num - 1, info.get_signer_id())?;
+            }
+        }
+
+        // A message past the high water mark means that the rest of the network may have garbage
+        // collected the messages for the blocks this node is missing. One node could be lying
+        // about how far along it is, though, so recovery waits until `f + 1` members are past the
+        // high water mark, and only goes as far as all of them have.
+        let high_water_mark = self.msg_log.get_high_water_mark();
+        if seq_num >= high_water_mark {
+            let network_seq_num = status::network_seq_num(&self.state);
+            if network_seq_num >= high_water_mark {
+                return self.start_recovery(network_seq_num - 1, info.get_signer_id());
+            }
+            debug!(
+                "{}: Waiting for f + 1 nodes to be past sequence number {} before recovering",
+                self.state, high_water_mark
+            );
+        }
+
+        Ok(false)
+    }
+
+    // A node that's still working on a block that this node committed in an earlier view can't get
+    // it committed in its own view, since the nodes that committed it have moved on; this node's
+    // `Commit` for the block is sent to it instead, so it can commit the block from a quorum of
+    // them (see `follow_commit`)
+    fn help_catch_up(&mut self, msg: &PbftMessage) -> Result<(), PbftError> {
+        let info = msg.get_info();
+        if info.get_seq_num() > self.state.committed_seq_num() {
+            return Ok(());
+        }
+
+        let own_id = self.state.get_own_peer_id();
+        let commits: Vec<PbftMessage> = self
+            .msg_log
+            .messages_of_type(&PbftMessageType::Commit)
+            .filter(|commit| {
+                commit.get_info().get_seq_num() == info.get_seq_num()
+                    && commit.get_info().get_view() < info.get_view()
+                    && commit.get_info().get_signer_id() == own_id.as_slice()
+            })
+            .cloned()
+            .collect();
+        let sender = PeerId::from(info.get_signer_id().to_vec());
+        for commit in commits {
+            debug!(
+                "{}: {:?} is still working on sequence number {}; sending it this node's Commit",
+                self.state,
+                sender,
+                info.get_seq_num()
+            );
+            let msg_bytes = commit
+                .write_to_bytes()
+                .map_err(PbftError::SerializationError)?;
+            self.send_to(&sender, &PbftMessageType::Commit, msg_bytes)?;
+        }
+        Ok(())
+    }
+
+    // Handle a message that has been decoded: count it against its sender if it couldn't be,
+    // drop it if it's stale or replayed, and otherwise pass it on
+    fn on_decoded_message(
+        &mut self,
+        msg: &PeerMessage,
+        decoded: Result<DecodedMessage, DecodeError>,
+        sender_id: &PeerId,
+    ) -> Result<(), PbftError> {
+        let DecodedMessage {
+            msg_type,
+            unwrapped,
+            info,
+        } = match decoded {
+            Ok(decoded) => decoded,
+            Err(DecodeError::Malformed(err)) => {
+                self.count_rejection(sender_id, &msg.message_type, Rejection::Malformed);
+                self.count_bad_message(sender_id);
+                return Err(err.with_context(None, None, sender_id));
+            }
+            Err(DecodeError::Unauthenticated(err)) => {
+                self.count_bad_message(sender_id);
+                return Err(err.with_context(None, None, sender_id));
+            }
+        };
+        let signed_msg = msg;
+        let msg = unwrapped.as_ref().unwrap_or(msg);
+
+        if let Err(rejection) = self.state.replay_filter.check(
+            &msg_type,
+            &info,
+            &msg.content,
+            self.state.view,
+            self.state.seq_num,
+        ) {
+            debug!(
+                "{}: Dropping {:?} {} (v {}, seq {}) from {:?}",
+                self.state,
+                rejection,
+                msg_type,
+                info.get_view(),
+                info.get_seq_num(),
+                sender_id,
+            );
+            self.count_rejection(sender_id, &msg.message_type, rejection);
+
+            // A consensus message from a member that's too far ahead to handle still shows that
+            // this node has fallen behind
+            if rejection == Rejection::Ahead
+                && msg_type.is_multicast()
+                && self.state.mode != PbftMode::NonVoting
+                && self
+                    .state
+                    .get_node_id_from_bytes(info.get_signer_id())
+                    .is_ok()
+            {
+                self.state
+                    .peer_stats
+                    .entry(sender_id.clone())
+                    .or_default()
+                    .record_seq_num(info.get_seq_num());
+                self.catch_up_to(&info)?;
+            }
+            return Ok(());
+        }
+        self.state
+            .peer_stats
+            .entry(sender_id.clone())
+            .or_default()
+            .record_seq_num(info.get_seq_num());
+        if unwrapped.is_some() {
+            self.keep_signed_vote(&msg_type, &signed_msg.content);
+        }
+
+        self.on_peer_message(msg).map_err(|err| {
+            err.with_context(Some(info.get_view()), Some(info.get_seq_num()), sender_id)
+        })
+    }
+
+    /// Handle a `PeerConnected` update: messages held for the peer while it was disconnected are
+    /// sent right away
+    pub fn on_peer_connected(&mut self, peer_id: PeerId) -> Result<(), PbftError> {
+        if self.state.peers().contains(&peer_id) {
+            info!("{}: Peer {:?} connected", self.state, peer_id);
+        } else {
+            // Validators connect to each other whether or not they take part in consensus
+            debug!(
+                "{}: Peer {:?} connected; it isn't a member of the network",
+                self.state, peer_id
+            );
+        }
+        self.state.outbox.connect(&peer_id);
+        self.retransmit()
+    }
+
+    /// Handle a `PeerDisconnected` update: messages for the peer are held until it reconnects,
+    /// instead of being lost. If the peer was asked for seals and hasn't answered, it won't, so
+    /// another node is asked instead of waiting for the request to time out.
+    pub fn on_peer_disconnected(&mut self, peer_id: PeerId) -> Result<(), PbftError> {
+        warn!("{}: Peer {:?} disconnected", self.state, peer_id);
+        self.state.outbox.disconnect(peer_id.clone());
+
+        if self.state.state_request_timeout.is_active()
+            && self.state.state_request_peer.as_ref() == Some(&peer_id)
+        {
+            info!(
+                "{}: Peer {:?} disconnected before answering StateRequest",
+                self.state, peer_id
+            );
+            self.state.state_request_timeout.stop();
+            return self.continue_recovery();
+        }
+        Ok(())
+    }
+
+    // Count a message from the given node that was dropped before being handled
+    fn count_rejection(&mut self, sender_id: &PeerId, msg_type: &str, rejection: Rejection) {
+        self.state
+            .rejected_messages
+            .entry(sender_id.clone())
+            .or_default()
+            .count(rejection);
+        self.state
+            .peer_stats
+            .entry(sender_id.clone())
+            .or_default()
+            .count_rejected(msg_type);
+    }
+
+    // Count a message from the given node that couldn't be decoded or validated, and quarantine
+    // the node if it has sent too many
+    fn count_bad_message(&mut self, sender_id: &PeerId) {
+        if !self.state.quarantine.record_failure(sender_id) {
+            return;
+        }
+        warn!(
+            "{}: Quarantining {:?} for {:?}; it sent {} messages that couldn't be used within {:?}",
+            self.state,
+            sender_id,
+            quarantine::QUARANTINE_DURATION,
+            quarantine::MAX_FAILURES,
+            quarantine::FAILURE_WINDOW
+        );
+        self.state.events.push(ConsensusEvent::PeerQuarantined {
+            peer_id: sender_id.clone(),
+            seconds: quarantine::QUARANTINE_DURATION.as_secs(),
+        });
+    }
+
+    // Count a message handed to the validator for the given node
+    fn count_sent(&mut self, peer_id: &PeerId, msg_type: &str) {
+        self.state
+            .peer_stats
+            .entry(peer_id.clone())
+            .or_default()
+            .count_sent(msg_type);
+    }
+
     /// Handle a peer message from another PbftNode
     /// This method handles all messages from other nodes. Such messages may include `PrePrepare`,
     /// `Prepare`, `Commit`, `Checkpoint`, or `ViewChange`. If a node receives a type of message
@@ -80,13 +490,22 @@ impl PbftNode {
         let msg_type = msg.message_type.clone();
         let msg_type = PbftMessageType::from(msg_type.as_str());
 
-        // Handle a multicast protocol message
-        let multicast_hint = if msg_type.is_multicast() {
+        let span = tracing::debug_span!(
+            "peer_message",
+            msg_type = %msg_type,
+            trace_id = tracing::field::Empty,
+        )
+        .entered();
+
+        // Handle a multicast protocol message; it's only parsed once, here
+        let (multicast_message, multicast_hint) = if msg_type.is_multicast() {
             let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
                 .map_err(PbftError::SerializationError)?;
 
+            let trace_id = handlers::display_trace_id(pbft_message.get_info().get_trace_id());
+            span.record("trace_id", &trace_id.as_str());
             debug!(
-                "{}: <<<<<< {} [Node {:02}] (v {}, seq {}, b {})",
+                "{}: <<<<<< {} [Node {:02}] (v {}, seq {}, b {}, trace {})",
                 self.state,
                 msg_type,
                 self.state
@@ -94,25 +513,74 @@ impl PbftNode {
                 pbft_message.get_info().get_view(),
                 pbft_message.get_info().get_seq_num(),
                 &hex::encode(pbft_message.get_block().get_block_id())[..6],
+                trace_id,
             );
 
-            handlers::multicast_hint(&self.state, &pbft_message)
+            // A node that was removed from the network doesn't take part in consensus, so all it
+            // needs from the members are their `Commit`s, to follow the chain with
+            if self.state.mode == PbftMode::NonVoting {
+                if msg_type == PbftMessageType::Commit {
+                    return self.follow_commit(pbft_message);
+                }
+                return Ok(());
+            }
+
+            if self.catch_up_to(pbft_message.get_info())? {
+                return Ok(());
+            }
+            self.help_catch_up(&pbft_message)?;
+
+            if !self
+                .msg_log
+                .within_water_marks(pbft_message.get_info().get_seq_num())
+            {
+                debug!(
+                    "{}: Ignoring message outside of water marks ({}, {})",
+                    self.state,
+                    self.msg_log.get_low_water_mark(),
+                    self.msg_log.get_high_water_mark(),
+                );
+                return Ok(());
+            }
+
+            let hint = handlers::multicast_hint(&self.state, &pbft_message);
+            (Some(pbft_message), hint)
         } else {
-            PbftHint::PresentMessage
+            (None, PbftHint::PresentMessage)
         };
 
+        if self.state.mode == PbftMode::NonVoting && msg_type == PbftMessageType::NewView {
+            return self.follow_new_view(msg);
+        }
+
+        // A recovering node can't take part in consensus or view changes, and neither can one
+        // that was removed from the network; they only trade seals
+        if (self.state.mode == PbftMode::Recovering || self.state.mode == PbftMode::NonVoting)
+            && msg_type != PbftMessageType::StateRequest
+            && msg_type != PbftMessageType::StateResponse
+        {
+            return Ok(());
+        }
+
         match msg_type {
             PbftMessageType::PrePrepare => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
-                    .map_err(PbftError::SerializationError)?;
+                let pbft_message = multicast_message.expect("Multicast message wasn't parsed");
 
                 // If we've got a BlockNew ready and the sequence number is our current plus one,
-                // then ignore whatever multicast_hint tells us to do.
+                // then ignore whatever multicast_hint tells us to do. The block can also be one
+                // that conflicts with the working block, if the primary chose that one instead.
                 let mut ignore_hint = false;
                 if let WorkingBlockOption::TentativeWorkingBlock(ref block_id) =
                     self.state.working_block
                 {
-                    if block_id == &BlockId::from(pbft_message.get_block().get_block_id().to_vec())
+                    let conflicting_candidate = self
+                        .msg_log
+                        .get_messages_of_type(&PbftMessageType::BlockNew, 0, self.state.view)
+                        .iter()
+                        .any(|msg| msg.get_block() == pbft_message.get_block());
+                    if (block_id
+                        == &BlockId::from(pbft_message.get_block().get_block_id().to_vec())
+                        || conflicting_candidate)
                         && pbft_message.get_info().get_seq_num() == self.state.seq_num + 1
                     {
                         debug!("{}: Ignoring not ready and starting multicast", self.state);
@@ -134,7 +602,7 @@ impl PbftNode {
                         &mut self.msg_log,
                         &multicast_hint,
                         &pbft_message,
-                        msg.content.clone(),
+                        &msg.content,
                     )?;
                 }
 
@@ -159,55 +627,77 @@ impl PbftNode {
             }
 
             PbftMessageType::Prepare => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
-                    .map_err(PbftError::SerializationError)?;
+                let pbft_message = multicast_message.expect("Multicast message wasn't parsed");
+
+                // While this node holds back its `Commit` on the fast path, a `Prepare` for the
+                // working block may be the last one it was waiting for
+                if self.state.fast_path_timeout.remaining().is_some()
+                    && self.state.phase == PbftPhase::Committing
+                    && pbft_message.get_info().get_seq_num() == self.state.seq_num
+                    && pbft_message.get_info().get_view() == self.state.view
+                {
+                    self.msg_log.add_message(pbft_message.clone());
+                    self.try_fast_commit()?;
+                    return Ok(());
+                }
 
                 handlers::action_from_hint(
                     &mut self.msg_log,
                     &multicast_hint,
                     &pbft_message,
-                    msg.content.clone(),
+                    &msg.content,
                 )?;
 
                 self.msg_log.add_message(pbft_message.clone());
 
-                self.msg_log.prepared(&pbft_message, self.state.f)?;
+                self.msg_log
+                    .prepared_with_quorum(&pbft_message, self.state.quorum())?;
 
                 if self.state.phase != PbftPhase::Checking {
                     self.state.switch_phase(PbftPhase::Checking);
                     debug!("{}: Checking blocks", self.state);
-                    self.service
-                        .check_blocks(vec![BlockId::from(
-                            pbft_message.get_block().clone().block_id,
-                        )])
-                        .map_err(|_| {
-                            PbftError::InternalError(String::from("Failed to check blocks"))
-                        })?;
+                    let block_id = BlockId::from(pbft_message.get_block().clone().block_id);
+
+                    // If the block was checked ahead of time, the answer to that check counts
+                    self.state.speculative_blocks.remove(&block_id);
+                    self.service.check_blocks(vec![block_id]).map_err(|err| {
+                        PbftError::ServiceError(String::from("Failed to check blocks"), err)
+                    })?;
                 }
             }
 
             PbftMessageType::Commit => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
-                    .map_err(PbftError::SerializationError)?;
+                let pbft_message = multicast_message.expect("Multicast message wasn't parsed");
+
+                // A node that's trying to leave the view can't vote in it anymore, but it can still
+                // commit the blocks that the rest of the network commits without it
+                if pbft_message.get_info().get_view() < self.state.view
+                    || self.state.mode == PbftMode::ViewChanging
+                {
+                    return self.follow_commit(pbft_message);
+                }
 
                 handlers::action_from_hint(
                     &mut self.msg_log,
                     &multicast_hint,
                     &pbft_message,
-                    msg.content.clone(),
+                    &msg.content,
                 )?;
 
                 self.msg_log.add_message(pbft_message.clone());
 
-                self.msg_log.committed(&pbft_message, self.state.f)?;
+                self.msg_log
+                    .committed_with_quorum(&pbft_message, self.state.quorum())?;
 
                 if self.state.phase == PbftPhase::Committing {
+                    // Nodes that are missing a `Prepare` may still need this node's `Commit`
+                    self.send_held_commit()?;
                     handlers::commit(
                         &mut self.state,
                         &mut self.msg_log,
                         &mut *self.service,
                         &pbft_message,
-                        msg.content.clone(),
+                        &msg.content,
                     )?;
                 } else {
                     debug!(
@@ -237,45 +727,11 @@ impl PbftNode {
                     return Ok(());
                 }
 
-                // Not ready to receive checkpoint yet; only acceptable in NotStarted
-                if self.state.phase != PbftPhase::NotStarted {
-                    self.msg_log.push_backlog(PeerMessage {
-                        message_type: msg.message_type.clone(),
-                        content: msg.content.clone(),
-                    });
-                    debug!("{}: Not in NotStarted; not handling checkpoint yet", self.state);
-                    return Ok(());
-                }
-
-                // Add message to the log
                 self.msg_log.add_message(pbft_message.clone());
-
-                // If we're a secondary, forward the message to everyone else in the network (resign it)
-                if !self.state.is_primary() && self.state.mode != PbftMode::Checkpointing {
-                    self.state.pre_checkpoint_mode = self.state.mode;
-                    self.state.mode = PbftMode::Checkpointing;
-                    self._broadcast_pbft_message(
-                        pbft_message.get_info().get_seq_num(),
-                        &PbftMessageType::Checkpoint,
-                        PbftBlock::new(),
-                    )?;
-                }
-
-                if self.state.mode == PbftMode::Checkpointing {
-                    self.msg_log
-                        .check_msg_against_log(&&pbft_message, true, 2 * self.state.f + 1)?;
-                    warn!(
-                        "{}: Reached stable checkpoint (seq num {}); garbage collecting logs",
-                        self.state,
-                        pbft_message.get_info().get_seq_num()
-                    );
-                    self.msg_log.garbage_collect(
-                        pbft_message.get_info().get_seq_num(),
-                        pbft_message.get_info().get_view(),
-                    );
-
-                    self.state.mode = self.state.pre_checkpoint_mode;
-                }
+                self.try_stabilize_checkpoint(
+                    pbft_message.get_info().get_seq_num(),
+                    pbft_message.get_block().get_block_id(),
+                );
             }
 
             PbftMessageType::ViewChange => {
@@ -293,12 +749,43 @@ impl PbftNode {
 
                 self.msg_log.add_view_change(vc_message.clone());
 
+                if vc_message.get_info().get_view() <= self.state.view {
+                    debug!("{}: Ignoring ViewChange for an old view", self.state);
+                    return Ok(());
+                }
+
+                if self.state.mode == PbftMode::ViewChanging
+                    && vc_message.get_info().get_view() > self.view_change_target()
+                    && self
+                        .msg_log
+                        .check_view_changes(
+                            vc_message.get_info().get_view(),
+                            self.state.weak_quorum(),
+                        )
+                        .is_ok()
+                {
+                    // Enough other nodes have given up on the view this node is trying to reach
+                    // that it won't be reached; move on to their view instead
+                    while self.view_change_target() < vc_message.get_info().get_view() {
+                        self.state.view_change_backoff.fail();
+                    }
+                    warn!(
+                        "{}: Joining view change to view {}",
+                        self.state,
+                        self.view_change_target()
+                    );
+                    self.broadcast_view_change()?;
+                }
+
                 if self.state.mode != PbftMode::ViewChanging {
                     // Even if our own timer hasn't expired, still do a ViewChange if we've received
                     // f + 1 VC messages to prevent being late to the new view party
                     if self
                         .msg_log
-                        .check_msg_against_log(&&vc_message, true, self.state.f + 1)
+                        .check_view_changes(
+                            vc_message.get_info().get_view(),
+                            self.state.weak_quorum(),
+                        )
                         .is_ok()
                         && vc_message.get_info().get_view() > self.state.view
                     {
@@ -306,7 +793,7 @@ impl PbftNode {
                             "{}: Starting ViewChange from a ViewChange message",
                             self.state
                         );
-                        self.start_view_change()?;
+                        self.start_view_change(ViewChangeReason::OtherNodes)?;
                     } else {
                         return Ok(());
                     }
@@ -318,95 +805,558 @@ impl PbftNode {
                     &mut *self.service,
                     &vc_message,
                 )?;
+
+                // The new primary tells everyone which blocks from earlier views to re-propose
+                if self.state.is_primary() {
+                    self.broadcast_new_view()?;
+                    self.propose_carried_block()?;
+                }
             }
 
-            _ => warn!("Message type not implemented"),
-        }
-        Ok(())
-    }
+            PbftMessageType::NewView => {
+                let new_view = protobuf::parse_from_bytes::<PbftNewView>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
 
-    /// Creates a new working block on the working block queue and kicks off the consensus algorithm
-    /// by broadcasting a `PrePrepare` message to peers. Starts a view change timer, just in case
-    /// the primary decides not to commit this block. If a `BlockCommit` update doesn't happen in a
-    /// timely fashion, then the primary can be considered faulty and a view change should happen.
-    pub fn on_block_new(&mut self, block: Block) -> Result<(), PbftError> {
-        info!("{}: Got BlockNew: {:?}", self.state, block.block_id);
+                debug!(
+                    "{}: Received NewView message from Node {:02} (v {})",
+                    self.state,
+                    self.state
+                        .get_node_id_from_bytes(new_view.get_info().get_signer_id())?,
+                    new_view.get_info().get_view(),
+                );
 
-        let pbft_block = pbft_block_from_block(block.clone());
+                if new_view.get_info().get_view() < self.state.view {
+                    debug!("{}: Ignoring NewView for an old view", self.state);
+                    return Ok(());
+                }
 
-        let mut msg = PbftMessage::new();
-        if self.state.is_primary() {
-            self.state.seq_num += 1;
-            msg.set_info(handlers::make_msg_info(
-                &PbftMessageType::BlockNew,
-                self.state.view,
-                self.state.seq_num, // primary knows the proper sequence number
-                self.state.get_own_peer_id(),
-            ));
-        } else {
-            msg.set_info(handlers::make_msg_info(
-                &PbftMessageType::BlockNew,
-                self.state.view,
-                0, // default to unset; change it later when we receive PrePrepare
-                self.state.get_own_peer_id(),
-            ));
-        }
+                // A valid NewView proves that the view change happened, so this node can enter the
+                // new view even if it hasn't received enough ViewChange messages itself
+                if new_view.get_info().get_view() > self.state.view {
+                    handlers::verify_new_view(&self.state, &new_view)?;
+                    warn!(
+                        "{}: Entering view {} from NewView message",
+                        self.state,
+                        new_view.get_info().get_view()
+                    );
+                    for vc in new_view.get_view_changes() {
+                        self.msg_log.add_view_change(vc.clone());
+                    }
+                    handlers::enter_view(
+                        &mut self.state,
+                        &mut self.msg_log,
+                        &mut *self.service,
+                        new_view.get_info().get_view(),
+                    )?;
+                }
 
-        msg.set_block(pbft_block.clone());
+                let reproposal = handlers::new_view(&self.state, &mut *self.service, &new_view)?;
+                self.msg_log.set_new_view(new_view);
+                if let Some(pre_prepare) = reproposal {
+                    self.repropose(pre_prepare)?;
+                }
+            }
 
-        let head = self
-            .service
-            .get_chain_head()
-            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+            PbftMessageType::Probe => {
+                let probe = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+                self.state
+                    .get_node_id_from_bytes(probe.get_info().get_signer_id())?;
 
-        if block.block_num > head.block_num + 1
-            || self.state.switch_phase(PbftPhase::PrePreparing).is_none()
-        {
-            debug!(
-                "{}: Not ready for block {}, pushing to backlog",
-                self.state,
-                &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
-            );
-            self.msg_log.push_block_backlog(block.clone());
-            return Ok(());
-        }
+                if self.state.is_primary() {
+                    let msg_bytes = make_msg_bytes(
+                        handlers::make_msg_info(
+                            &PbftMessageType::ProbeResponse,
+                            self.state.view,
+                            self.state.seq_num,
+                            self.state.get_own_peer_id(),
+                        ),
+                        PbftBlock::new(),
+                    )
+                    .map_err(PbftError::SerializationError)?;
 
-        self.msg_log.add_message(msg);
-        self.state.working_block = WorkingBlockOption::TentativeWorkingBlock(block.block_id);
-        self.state.timeout.start();
+                    self.send_to(
+                        &PeerId::from(probe.get_info().get_signer_id().to_vec()),
+                        &PbftMessageType::ProbeResponse,
+                        msg_bytes,
+                    )?;
+                }
+            }
 
-        if self.state.is_primary() {
-            let s = self.state.seq_num;
-            self._broadcast_pbft_message(s, &PbftMessageType::PrePrepare, pbft_block)?;
-        }
-        Ok(())
-    }
+            PbftMessageType::ProbeResponse => {
+                let response = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
 
-    /// Handle a `BlockCommit` update from the Validator
-    /// Since the block was successfully committed, the primary is not faulty and the view change
-    /// timer can be stopped. If this node is a primary, then initialize a new block. Both node
-    /// roles transition back to the `NotStarted` phase. If this node is at a checkpoint after the
-    /// previously committed block (`checkpoint_period` blocks have been committed since the last
-    /// checkpoint), then start a checkpoint.
-    pub fn on_block_commit(&mut self, block_id: BlockId) -> Result<(), PbftError> {
-        debug!("{}: <<<<<< BlockCommit: {:?}", self.state, block_id);
+                if response.get_info().get_signer_id()
+                    == Vec::<u8>::from(self.state.get_primary_peer_id()).as_slice()
+                {
+                    self.state.probe_outstanding = false;
+                    self.state.failed_probes = 0;
+                }
+            }
 
-        if self.state.phase == PbftPhase::Finished {
-            if self.state.is_primary() {
+            PbftMessageType::Heartbeat => {
+                let heartbeat = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+
+                if heartbeat.get_info().get_signer_id()
+                    == Vec::<u8>::from(self.state.get_primary_peer_id()).as_slice()
+                    && heartbeat.get_info().get_view() == self.state.view
+                {
+                    if let Some(ref mut timeout) = self.state.idle_timeout {
+                        timeout.start();
+                    }
+                }
+            }
+
+            PbftMessageType::Handoff => {
+                let handoff = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+
+                // Only the primary can give up its own view
+                if handoff.get_info().get_signer_id()
+                    != Vec::<u8>::from(self.state.get_primary_peer_id()).as_slice()
+                    || handoff.get_info().get_view() != self.state.view
+                {
+                    debug!(
+                        "{}: Ignoring Handoff for view {} that isn't from the primary",
+                        self.state,
+                        handoff.get_info().get_view()
+                    );
+                    return Ok(());
+                }
+
+                warn!(
+                    "{}: Primary is handing off, starting view change",
+                    self.state
+                );
+                self.start_view_change(ViewChangeReason::Handoff)?;
+            }
+
+            PbftMessageType::StateRequest => {
+                let request = protobuf::parse_from_bytes::<PbftStateRequest>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+                self.state
+                    .get_node_id_from_bytes(request.get_info().get_signer_id())?;
+
+                let start = request.get_info().get_seq_num();
+                let end = request
+                    .get_end_seq_num()
+                    .min(start + MAX_SEALS_PER_RESPONSE - 1);
+                let seals: Vec<PbftSeal> = (start..=end)
+                    .filter_map(|seq_num| self.msg_log.get_seal(seq_num, self.state.quorum()))
+                    .collect();
+
+                debug!(
+                    "{}: Sending {} seals for sequence numbers {} to {}",
+                    self.state,
+                    seals.len(),
+                    start,
+                    end
+                );
+                if seals.is_empty() {
+                    return Ok(());
+                }
+
+                let mut response = PbftStateResponse::new();
+                response.set_info(handlers::make_msg_info(
+                    &PbftMessageType::StateResponse,
+                    self.state.view,
+                    self.state.seq_num,
+                    self.state.get_own_peer_id(),
+                ));
+                response.set_seals(RepeatedField::from_vec(seals));
+                let msg_bytes = response
+                    .write_to_bytes()
+                    .map_err(PbftError::SerializationError)?;
+
+                self.send_to(
+                    &PeerId::from(request.get_info().get_signer_id().to_vec()),
+                    &PbftMessageType::StateResponse,
+                    msg_bytes,
+                )?;
+            }
+
+            PbftMessageType::RetransmitRequest => {
+                let request = protobuf::parse_from_bytes::<PbftRetransmitRequest>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+                self.state
+                    .get_node_id_from_bytes(request.get_info().get_signer_id())?;
+
+                // Only this node's own messages are sent again, since those are the only ones
+                // it can vouch for. A `Commit` is sent from whichever view it was sent in, since
+                // the requester can commit the block from a quorum of them even if it has moved
+                // on to a later view since (see `follow_commit`).
+                let own_id = self.state.get_own_peer_id();
+                let seq_num = request.get_info().get_seq_num();
+                let view = request.get_info().get_view();
+                let mut own_messages = vec![];
+                for msg_type in request.get_message_types() {
+                    let msg_type = PbftMessageType::from(msg_type.as_str());
+                    if !msg_type.is_multicast() {
+                        continue;
+                    }
+                    let any_view = msg_type == PbftMessageType::Commit;
+                    own_messages.extend(
+                        self.msg_log
+                            .messages_of_type(&msg_type)
+                            .filter(|msg| {
+                                let info = msg.get_info();
+                                info.get_seq_num() == seq_num
+                                    && (any_view || info.get_view() == view)
+                                    && info.get_signer_id() == own_id.as_slice()
+                            })
+                            .cloned(),
+                    );
+                }
+
+                debug!(
+                    "{}: Retransmitting {} messages for sequence number {}",
+                    self.state,
+                    own_messages.len(),
+                    seq_num
+                );
+                let requester = PeerId::from(request.get_info().get_signer_id().to_vec());
+                for own_message in own_messages {
+                    let msg_type = PbftMessageType::from(own_message.get_info().get_msg_type());
+                    let msg_bytes = own_message
+                        .write_to_bytes()
+                        .map_err(PbftError::SerializationError)?;
+                    self.send_to(&requester, &msg_type, msg_bytes)?;
+                }
+            }
+
+            PbftMessageType::ClockSync => {
+                let mut sync = protobuf::parse_from_bytes::<PbftClockSync>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+                self.state
+                    .get_node_id_from_bytes(sync.get_info().get_signer_id())?;
+
+                let requester = PeerId::from(sync.get_info().get_signer_id().to_vec());
+                sync.set_info(handlers::make_msg_info(
+                    &PbftMessageType::ClockSyncResponse,
+                    self.state.view,
+                    self.state.seq_num,
+                    self.state.get_own_peer_id(),
+                ));
+                sync.set_receive_time(timing::unix_millis());
+                let msg_bytes = sync
+                    .write_to_bytes()
+                    .map_err(PbftError::SerializationError)?;
+                self.send_to(&requester, &PbftMessageType::ClockSyncResponse, msg_bytes)?;
+            }
+
+            PbftMessageType::ClockSyncResponse => {
+                let response = protobuf::parse_from_bytes::<PbftClockSync>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+                self.state
+                    .get_node_id_from_bytes(response.get_info().get_signer_id())?;
+
+                self.record_clock_skew(
+                    PeerId::from(response.get_info().get_signer_id().to_vec()),
+                    response.get_origin_time(),
+                    response.get_receive_time(),
+                    timing::unix_millis(),
+                );
+            }
+
+            PbftMessageType::StateResponse => {
+                let response = protobuf::parse_from_bytes::<PbftStateResponse>(&msg.content)
+                    .map_err(PbftError::SerializationError)?;
+
+                // Without signatures, any node could have made up the Commits in its seals
+                if !self.state.authenticate_messages {
+                    debug!(
+                        "{}: Ignoring StateResponse, since seals can't be checked",
+                        self.state
+                    );
+                    return Ok(());
+                }
+
+                let mut highest_sealed = 0;
+                for seal in response.get_seals() {
+                    if seal.get_seq_num() <= self.state.committed_seq_num() {
+                        continue;
+                    }
+                    match handlers::verify_seal(&self.state, seal) {
+                        Ok(()) => {
+                            self.seal_verified();
+                            self.msg_log.add_seal(seal.clone());
+                            highest_sealed = highest_sealed.max(seal.get_seq_num());
+                        }
+                        Err(err) => {
+                            self.seal_failed(&err);
+                            let sender_id =
+                                PeerId::from(response.get_info().get_signer_id().to_vec());
+                            if let Some(suppressed) =
+                                self.log_throttle.check(("invalid seal", sender_id))
+                            {
+                                warn!(
+                                    "{}: Ignoring invalid seal for sequence number {}: {}{}",
+                                    self.state,
+                                    seal.get_seq_num(),
+                                    err,
+                                    suppressed
+                                );
+                            }
+                        }
+                    }
+                }
+
+                self.state.state_request_timeout.stop();
+
+                // A verified seal proves how far the network has gotten, so one past the high
+                // water mark is enough to start recovering
+                if highest_sealed >= self.msg_log.get_high_water_mark()
+                    && self.state.mode != PbftMode::NonVoting
+                {
+                    self.start_recovery(highest_sealed, response.get_info().get_signer_id())?;
+                }
+                self.check_sealed_blocks()?;
+
+                // A full response means the peer may have more seals, so ask for the rest
+                if response.get_seals().len() as u64 == MAX_SEALS_PER_RESPONSE {
+                    self.request_state(
+                        response.get_info().get_seq_num(),
+                        response.get_info().get_signer_id(),
+                    )?;
+                }
+            }
+
+            _ => warn!("Message type not implemented"),
+        }
+        Ok(())
+    }
+
+    /// Creates a new working block on the working block queue and kicks off the consensus algorithm
+    /// by broadcasting a `PrePrepare` message to peers. Starts a view change timer, just in case
+    /// the primary decides not to commit this block. If a `BlockCommit` update doesn't happen in a
+    /// timely fashion, then the primary can be considered faulty and a view change should happen.
+    pub fn on_block_new(&mut self, block: Block) -> Result<(), PbftError> {
+        let _span = tracing::debug_span!("block_new", block_id = ?block.block_id).entered();
+        info!("{}: Got BlockNew: {:?}", self.state, block.block_id);
+
+        // A block that this node has a seal for was already committed by the network, so it only
+        // needs to be checked by the validator; it is committed once it's valid and the blocks
+        // before it have been committed
+        if self
+            .msg_log
+            .has_seal(&Vec::<u8>::from(block.block_id.clone()))
+        {
+            debug!("{}: Checking sealed block", self.state);
+            return self
+                .service
+                .check_blocks(vec![block.block_id])
+                .map_err(|err| {
+                    PbftError::ServiceError(String::from("Failed to check blocks"), err)
+                });
+        }
+
+        // Only one block can be committed at each height on top of the chain head
+        if self.msg_log.add_block_candidate(&block).is_some() {
+            if self.state.is_primary() || !self.state.working_block.is_none() {
+                return self.on_conflicting_block(block);
+            }
+        }
+
+        let pbft_block = pbft_block_from_block(block.clone());
+
+        let mut msg = PbftMessage::new();
+        if self.state.is_primary() {
+            msg.set_info(handlers::make_msg_info(
+                &PbftMessageType::BlockNew,
+                self.state.view,
+                self.state.seq_num + 1, // primary knows the proper sequence number
+                self.state.get_own_peer_id(),
+            ));
+        } else {
+            msg.set_info(handlers::make_msg_info(
+                &PbftMessageType::BlockNew,
+                self.state.view,
+                0, // default to unset; change it later when we receive PrePrepare
+                self.state.get_own_peer_id(),
+            ));
+        }
+
+        msg.set_block(pbft_block.clone());
+
+        let head = self.service.get_chain_head().map_err(|err| {
+            PbftError::ServiceError(String::from("Failed to get chain head"), err)
+        })?;
+
+        if block.block_num > head.block_num + 1
+            || self.state.mode == PbftMode::Recovering
+            || self.state.mode == PbftMode::NonVoting
+            || self.state.switch_phase(PbftPhase::PrePreparing).is_none()
+        {
+            debug!(
+                "{}: Not ready for block {}, pushing to backlog",
+                self.state,
+                &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
+            );
+            if !self.msg_log.push_block_backlog(block.clone()) {
+                warn!(
+                    "{}: Block backlog is full; deferring block {}",
+                    self.state,
+                    &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
+                );
+            }
+            return self.check_next_block();
+        }
+
+        // A block that has to wait in the backlog doesn't take up a sequence number yet
+        if self.state.is_primary() {
+            self.state.seq_num += 1;
+        }
+        self.msg_log.add_message(msg);
+        self.state.working_block =
+            WorkingBlockOption::TentativeWorkingBlock(block.block_id.clone());
+        self.state.timeout.start();
+
+        // With nobody else to agree with, the block only needs to be checked by the validator
+        if self.state.single_node {
+            self.state.working_block = WorkingBlockOption::WorkingBlock(pbft_block);
+            self.state.phase = PbftPhase::Checking;
+            return self
+                .service
+                .check_blocks(vec![block.block_id])
+                .map_err(|err| {
+                    PbftError::ServiceError(String::from("Failed to check blocks"), err)
+                });
+        }
+
+        if self.state.is_primary() && !self.is_silent_primary() {
+            let s = self.state.seq_num;
+            self._broadcast_pbft_message(s, &PbftMessageType::PrePrepare, pbft_block)?;
+        }
+        Ok(())
+    }
+
+    // Handle a block that conflicts with one that arrived earlier. The primary keeps the one it
+    // got first, so it fails the new one right away. A secondary can't tell which one the primary
+    // will choose, so it keeps the new one in its log next to its working block, ready for a
+    // `PrePrepare` of either; whichever isn't committed is failed once the other one is (see
+    // `on_block_commit`).
+    fn on_conflicting_block(&mut self, block: Block) -> Result<(), PbftError> {
+        if self.state.is_primary() {
+            warn!(
+                "{}: Failing block {}, which conflicts with a block this primary already has",
+                self.state,
+                &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
+            );
+            return self
+                .service
+                .fail_block(block.block_id)
+                .map_err(|err| PbftError::ServiceError(String::from("Failed to fail block"), err));
+        }
+
+        let mut msg = PbftMessage::new();
+        msg.set_info(handlers::make_msg_info(
+            &PbftMessageType::BlockNew,
+            self.state.view,
+            0,
+            self.state.get_own_peer_id(),
+        ));
+        msg.set_block(pbft_block_from_block(block));
+        self.msg_log.add_message(msg);
+        Ok(())
+    }
+
+    /// Handle a `BlockCommit` update from the Validator
+    /// Since the block was successfully committed, the primary is not faulty and the view change
+    /// timer can be stopped. If this node is a primary, then initialize a new block. Both node
+    /// roles transition back to the `NotStarted` phase. If the committed block's sequence number is
+    /// a multiple of `checkpoint_period`, then send a checkpoint for it.
+    ///
+    /// If the committed block changed the network's membership, the new membership takes effect
+    /// here, before the next block is started.
+    pub fn on_block_commit(&mut self, block_id: BlockId) -> Result<(), PbftError> {
+        let _span = tracing::debug_span!("block_commit", block_id = ?block_id).entered();
+        debug!("{}: <<<<<< BlockCommit: {:?}", self.state, block_id);
+
+        self.state.chain_head = block_id.clone();
+        self.state.chain_head_num += 1;
+        self.state.last_commit = Some(timing::now());
+        self.state.view_history.record_commit();
+        self.state.events.push(ConsensusEvent::BlockCommitted {
+            block_num: self.state.chain_head_num,
+            block_id: block_id.clone(),
+        });
+
+        // Other candidates for this height can't be committed anymore
+        for candidate in self
+            .msg_log
+            .remove_block_candidates(&block_id, self.state.chain_head_num)
+        {
+            info!(
+                "{}: Failing block {}, which lost to block {}",
+                self.state,
+                &hex::encode(Vec::<u8>::from(candidate.clone()))[..6],
+                &hex::encode(Vec::<u8>::from(block_id.clone()))[..6]
+            );
+            self.service
+                .fail_block(candidate)
+                .unwrap_or_else(|err| error!("Couldn't fail block: {}", err));
+        }
+        if let Some(ref mut timeout) = self.state.block_interval_timeout {
+            timeout.start();
+        }
+
+        match self.update_membership(block_id.clone()) {
+            Ok(()) => self.end_incident(Incident::InvalidSettings),
+            Err(err) => {
+                error!("{}: Couldn't update membership: {}", self.state, err);
+                self.start_incident(
+                    Incident::InvalidSettings,
+                    format!("couldn't update membership: {}", err),
+                );
+            }
+        }
+
+        if self.state.phase == PbftPhase::Finished {
+            if self.state.is_primary() {
                 info!(
                     "{}: Initializing block with previous ID {:?}",
                     self.state, block_id
                 );
                 self.service
-                    .initialize_block(Some(block_id))
+                    .initialize_block(Some(block_id.clone()))
                     .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
             }
 
             self.state.switch_phase(PbftPhase::NotStarted);
 
-            // Start a checkpoint in NotStarted, if we're at one
-            if self.msg_log.at_checkpoint() {
-                self.start_checkpoint()?;
+            // Keep the block's seal, so that nodes that fall behind can still get it after the
+            // block's messages are garbage collected
+            if let Some(mut seal) = self
+                .msg_log
+                .get_seal(self.state.seq_num, self.state.quorum())
+            {
+                if self.state.seal_view_change_evidence {
+                    if let Some(new_view) = self.msg_log.view_change_evidence(&seal) {
+                        seal.set_new_view(new_view);
+                    }
+                }
+                self.msg_log.archive_seal(seal);
+            }
+
+            // The timeout was started when the block was received, so it has been running for as
+            // long as the block took to commit (blocks committed from seals don't count)
+            if self.state.timeout.is_active() {
+                let latency = self.state.timeout.elapsed();
+                self.state.metrics.record_latency("total", latency);
+                if let Some(ref mut adaptive_timeout) = self.state.adaptive_timeout {
+                    adaptive_timeout.record(latency);
+                    self.state.timeout.set_duration(adaptive_timeout.duration());
+                }
+            }
+
+            if self.state.mode == PbftMode::Recovering {
+                self.check_recovered()?;
+            } else if self.state.mode != PbftMode::NonVoting
+                && self.msg_log.at_checkpoint(self.state.seq_num)
+            {
+                self.start_checkpoint(block_id)?;
             }
         } else {
             debug!("{}: Not doing anything with BlockCommit", self.state);
@@ -415,6 +1365,139 @@ impl PbftNode {
         // The primary processessed this block in a timely manner, so stop the timeout.
         self.state.timeout.stop();
 
+        // If this node is catching up, the next block may be ready to commit already
+        self.commit_next_sealed()
+    }
+
+    /// Check whether the given block changed the `sawtooth.consensus.pbft.peers` setting, and if
+    /// so, switch to the new membership. Since every node does this after committing the same
+    /// block, the change takes effect at the same sequence number on every node. If the primary
+    /// was removed, every remaining node moves to the next view at that point. If this node was
+    /// removed, it stops taking part in consensus and only follows the chain, until it's added
+    /// back.
+    fn update_membership(&mut self, block_id: BlockId) -> Result<(), PbftError> {
+        let peers = config::load_peers(block_id, &mut *self.service)?;
+        if peers.as_slice() == self.state.peers() {
+            return Ok(());
+        }
+
+        if !peers.contains(&self.state.get_own_peer_id()) {
+            return self.leave_network(peers);
+        }
+
+        if self.state.mode == PbftMode::NonVoting {
+            self.state.set_peers(peers)?;
+            self.state.mode = PbftMode::Normal;
+            warn!(
+                "{}: This node was added back to the network after sequence number {}; taking \
+                 part in consensus again",
+                self.state, self.state.seq_num
+            );
+            return self.enter_committed_view();
+        }
+
+        let view = self.state.view;
+        self.state.set_peers(peers)?;
+        warn!(
+            "{}: Membership changed after sequence number {}; now {} nodes (f = {})",
+            self.state,
+            self.state.seq_num,
+            self.state.num_nodes(),
+            self.state.f
+        );
+        if self.state.view != view {
+            warn!(
+                "{}: Primary was removed; moved from view {} to view {}",
+                self.state, view, self.state.view
+            );
+        }
+
+        Ok(())
+    }
+
+    // Stop publishing blocks, voting, and taking part in view changes, since this node isn't in
+    // the given membership; it keeps following the chain from the members' `Commit`s instead (see
+    // `follow_commit`)
+    fn leave_network(&mut self, peers: Vec<PeerId>) -> Result<(), PbftError> {
+        if self.state.mode == PbftMode::NonVoting {
+            return self.state.leave(peers);
+        }
+
+        // A block that was started but not published yet isn't this node's to publish anymore
+        if self.state.is_primary() && self.state.phase == PbftPhase::NotStarted {
+            self.service
+                .cancel_block()
+                .unwrap_or_else(|err| error!("Couldn't cancel block: {}", err));
+        }
+        self.state.leave(peers)?;
+        warn!(
+            "{}: This node was removed from the network after sequence number {}; following the \
+             chain without voting",
+            self.state, self.state.seq_num
+        );
+        Ok(())
+    }
+
+    // Once a quorum of the members' `Commit`s for a block have arrived, they're a seal for it, so
+    // a node that isn't a member commits the block from that seal, like a node that's recovering.
+    // So does a member that's still working on a block the others committed in an earlier view,
+    // since their `Commit`s can't count towards the current view's consensus, and one that's
+    // changing views on its own while the others carry on in the view it's leaving.
+    fn follow_commit(&mut self, commit: PbftMessage) -> Result<(), PbftError> {
+        let seq_num = commit.get_info().get_seq_num();
+        if seq_num <= self.state.committed_seq_num()
+            || self.msg_log.has_seal(commit.get_block().get_block_id())
+        {
+            return Ok(());
+        }
+
+        self.msg_log.add_message(commit);
+        self.commit_from_log(seq_num).map(|_| ())
+    }
+
+    // Commits the block at this sequence number from a quorum of `Commit`s for it in the log, if
+    // there is one; returns whether there was
+    fn commit_from_log(&mut self, seq_num: u64) -> Result<bool, PbftError> {
+        let seal = match self.msg_log.get_seal(seq_num, self.state.quorum()) {
+            Some(seal) => seal,
+            None => return Ok(false),
+        };
+        if self.msg_log.has_seal(seal.get_block().get_block_id()) {
+            return Ok(true);
+        }
+        if let Err(err) = handlers::verify_seal(&self.state, &seal) {
+            self.seal_failed(&err);
+            return Err(err);
+        }
+        self.seal_verified();
+        self.msg_log.add_seal(seal);
+        self.check_sealed_blocks()?;
+        Ok(true)
+    }
+
+    // A node that isn't a member moves to the members' new view once a valid `NewView` shows that
+    // they entered it, so it's in the right view if it's added; it still doesn't take part in it
+    fn follow_new_view(&mut self, msg: &PeerMessage) -> Result<(), PbftError> {
+        let new_view = protobuf::parse_from_bytes::<PbftNewView>(&msg.content)
+            .map_err(PbftError::SerializationError)?;
+        let view = new_view.get_info().get_view();
+        if view <= self.state.view {
+            return Ok(());
+        }
+
+        handlers::verify_new_view(&self.state, &new_view)?;
+        info!("{}: Members entered view {}; following", self.state, view);
+        for failed_view in self.state.view..view {
+            self.state.record_primary_failure(failed_view);
+        }
+        self.state.view = view;
+        let ended = self.state.view_history.enter_view(view);
+        let voters = new_view
+            .get_view_changes()
+            .iter()
+            .map(|vc| PeerId::from(vc.get_info().get_signer_id().to_vec()))
+            .collect();
+        self.state.record_view_change(&ended, voters);
         Ok(())
     }
 
@@ -423,7 +1506,26 @@ impl PbftNode {
     /// successfully checked a block with this `BlockId`.
     /// Once a `BlockValid` is received, transition to committing blocks.
     pub fn on_block_valid(&mut self, block_id: BlockId) -> Result<(), PbftError> {
+        let _span = tracing::debug_span!("block_valid", block_id = ?block_id).entered();
         debug!("{}: <<<<<< BlockValid: {:?}", self.state, block_id);
+
+        if self.state.speculative_blocks.remove(&block_id) {
+            debug!("{}: Block was checked ahead of time", self.state);
+            return Ok(());
+        }
+
+        let sealed_block_id = Vec::<u8>::from(block_id.clone());
+        if self.msg_log.has_seal(&sealed_block_id) {
+            self.msg_log.mark_seal_valid(&sealed_block_id);
+            return self.commit_next_sealed();
+        }
+
+        // The block may have been checked both ahead of time and in its own turn
+        if self.state.phase != PbftPhase::Checking {
+            debug!("{}: Block was already found valid", self.state);
+            return Ok(());
+        }
+
         self.state.switch_phase(PbftPhase::Committing);
 
         debug!("{}: Getting blocks", self.state);
@@ -439,27 +1541,229 @@ impl PbftNode {
             return Err(PbftError::WrongNumBlocks);
         }
 
+        if self.state.single_node {
+            return self.commit_own_block(handlers::pbft_block_from_block(valid_blocks[0].clone()));
+        }
+
+        // On the fast path, this node's `Commit` is held back for a moment, in case the rest of
+        // the `Prepare`s arrive and the block can be committed without waiting for `Commit`s
+        if self.state.fast_path {
+            if !self.try_fast_commit()? {
+                debug!(
+                    "{}: Waiting {:?} for Prepares from every node",
+                    self.state,
+                    self.state.fast_path_timeout.duration()
+                );
+                self.state.fast_path_timeout.start();
+            }
+            return self.check_next_block();
+        }
+
         let s = self.state.seq_num; // By now, secondaries have the proper seq number
         self._broadcast_pbft_message(
             s,
             &PbftMessageType::Commit,
             handlers::pbft_block_from_block(valid_blocks[0].clone()),
         )?;
-        Ok(())
+
+        self.check_next_block()
     }
 
-    // ---------- Methods for periodically checking on and updating the state, called by the engine ----------
+    // Commit a block as the only node in the network. This node's own `Commit` is recorded, so
+    // the block has a seal (with just the one `Commit`) like any other block.
+    fn commit_own_block(&mut self, block: PbftBlock) -> Result<(), PbftError> {
+        let commit = self.own_commit(block);
+        let msg_bytes = commit
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+        self.msg_log.add_message(commit.clone());
 
-    /// The primary tries to finalize a block every so often
-    /// # Panics
-    /// Panics if `finalize_block` fails. This is necessary because it means the validator wasn't
-    /// able to publish the new block.
-    pub fn try_publish(&mut self) -> Result<(), PbftError> {
-        // Try to finalize a block
-        if self.state.is_primary() && self.state.phase == PbftPhase::NotStarted {
+        handlers::commit(
+            &mut self.state,
+            &mut self.msg_log,
+            &mut *self.service,
+            &commit,
+            &msg_bytes,
+        )
+    }
+
+    // This node's own `Commit` for the given block at the current view and sequence number
+    fn own_commit(&self, block: PbftBlock) -> PbftMessage {
+        let mut info = handlers::make_msg_info(
+            &PbftMessageType::Commit,
+            self.state.view,
+            self.state.seq_num,
+            self.state.get_own_peer_id(),
+        );
+        if let Some(trace_id) = self
+            .msg_log
+            .get_trace_id(self.state.seq_num, block.get_block_id())
+        {
+            info.set_trace_id(trace_id.to_vec());
+        }
+        let mut commit = PbftMessage::new();
+        commit.set_info(info);
+        commit.set_block(block);
+        commit
+    }
+
+    // Commit the working block on the fast path, if this node has matching `Prepare`s for it from
+    // every node. Its `Commit` is sent first, so that nodes that are missing a `Prepare` can still
+    // commit the block in the normal `Commit` phase, and the block gets a seal. Returns whether
+    // the block was committed.
+    fn try_fast_commit(&mut self) -> Result<bool, PbftError> {
+        let block = match self.state.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => block.clone(),
+            _ => return Ok(false),
+        };
+
+        let commit = self.own_commit(block.clone());
+        if self
+            .msg_log
+            .prepared_by_all(&commit, self.state.quorum(), self.state.num_nodes())
+            .is_err()
+        {
+            return Ok(false);
+        }
+        info!(
+            "{}: Prepared by all nodes; committing on fast path",
+            self.state
+        );
+
+        self.state.fast_path_timeout.stop();
+        let seq_num = self.state.seq_num;
+        self._broadcast_pbft_message(seq_num, &PbftMessageType::Commit, block)?;
+
+        let msg_bytes = commit
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+        self.msg_log.add_message(commit.clone());
+        handlers::commit(
+            &mut self.state,
+            &mut self.msg_log,
+            &mut *self.service,
+            &commit,
+            &msg_bytes,
+        )?;
+        Ok(true)
+    }
+
+    // Send this node's `Commit` for the working block, if it was held back on the fast path. The
+    // timer is only stopped once the `Commit` is sent, so an expired one still holds it back.
+    fn send_held_commit(&mut self) -> Result<(), PbftError> {
+        if self.state.fast_path_timeout.remaining().is_none() {
+            return Ok(());
+        }
+        self.state.fast_path_timeout.stop();
+
+        let block = match self.state.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => block.clone(),
+            _ => return Ok(()),
+        };
+        let seq_num = self.state.seq_num;
+        self._broadcast_pbft_message(seq_num, &PbftMessageType::Commit, block)
+    }
+
+    /// On the fast path, once the working block has waited long enough for `Prepare`s from every
+    /// node, send this node's `Commit` for it, falling back to the normal `Commit` phase
+    pub fn check_fast_path_timeout(&mut self) -> Result<(), PbftError> {
+        if !self.state.fast_path_timeout.check_expired() {
+            return Ok(());
+        }
+        if self.state.phase != PbftPhase::Committing || self.state.mode != PbftMode::Normal {
+            self.state.fast_path_timeout.stop();
+            return Ok(());
+        }
+        debug!(
+            "{}: Not every node has prepared; falling back to the Commit phase",
+            self.state
+        );
+        self.send_held_commit()
+    }
+
+    /// Handle a `BlockInvalid` update
+    /// The working block is invalid, so the primary is considered faulty and a view change is
+    /// started. A block that was only checked ahead of time is left alone; it's checked again
+    /// when its turn comes, and the view change happens then.
+    pub fn on_block_invalid(&mut self, block_id: BlockId) -> Result<(), PbftError> {
+        let _span = tracing::debug_span!("block_invalid", block_id = ?block_id).entered();
+        if self.state.speculative_blocks.remove(&block_id) {
+            warn!(
+                "{}: Block {:?} failed when checked ahead of time",
+                self.state, block_id
+            );
+            return Ok(());
+        }
+
+        warn!(
+            "{}: BlockInvalid received, starting view change",
+            self.state
+        );
+        self.state.view_history.record_failed_proposal();
+        self.start_view_change(ViewChangeReason::InvalidBlock)
+    }
+
+    // Once the working block is valid and only needs to be committed, ask the validator to check
+    // the block after it, if that block has arrived already. Validation of the next block then
+    // overlaps with consensus on this one, and when the next block reaches the `Checking` phase,
+    // the validator already knows whether it's valid. Results for blocks checked this way are
+    // ignored, since the block is checked again (and answered right away) in its own turn.
+    fn check_next_block(&mut self) -> Result<(), PbftError> {
+        if self.state.phase != PbftPhase::Committing && self.state.phase != PbftPhase::Finished {
+            return Ok(());
+        }
+
+        let working_block_id = match self.state.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => {
+                BlockId::from(block.get_block_id().to_vec())
+            }
+            _ => return Ok(()),
+        };
+
+        let next_block_id = self
+            .msg_log
+            .block_backlog()
+            .find(|block| {
+                block.previous_id == working_block_id
+                    && !self.state.speculative_blocks.contains(&block.block_id)
+            })
+            .map(|block| block.block_id.clone());
+
+        if let Some(block_id) = next_block_id {
+            debug!(
+                "{}: Checking next block {:?} ahead of time",
+                self.state, block_id
+            );
+            self.service
+                .check_blocks(vec![block_id.clone()])
+                .map_err(|err| {
+                    PbftError::ServiceError(String::from("Failed to check blocks"), err)
+                })?;
+            self.state.speculative_blocks.insert(block_id);
+        }
+
+        Ok(())
+    }
+
+    // ---------- Methods for periodically checking on and updating the state, called by the engine ----------
+
+    /// The primary tries to finalize a block every so often
+    /// # Panics
+    /// Panics if `finalize_block` fails. This is necessary because it means the validator wasn't
+    /// able to publish the new block.
+    pub fn try_publish(&mut self) -> Result<(), PbftError> {
+        if self.state.maintenance {
+            return self.hand_off_for_maintenance();
+        }
+
+        // Try to finalize a block
+        if self.state.is_primary()
+            && self.state.phase == PbftPhase::NotStarted
+            && !self.is_silent_primary()
+        {
             debug!("{}: Summarizing block", self.state);
             if let Err(e) = self.service.summarize_block() {
-                info!(
+                debug!(
                     "{}: Couldn't summarize, so not finalizing: {}",
                     self.state,
                     e.description().to_string()
@@ -469,554 +1773,3560 @@ impl PbftNode {
                 match self.service.finalize_block(vec![]) {
                     Ok(block_id) => {
                         info!("{}: Publishing block {:?}", self.state, block_id);
+                        if let Some(ref mut timeout) = self.state.block_interval_timeout {
+                            timeout.stop();
+                        }
                     }
                     Err(EngineError::BlockNotReady) => {
                         debug!("{}: Block not ready", self.state);
                     }
-                    Err(err) => panic!("Failed to finalize block: {:?}", err),
+                    Err(err) => {
+                        return Err(PbftError::ServiceError(
+                            String::from("Failed to finalize block"),
+                            err,
+                        ));
+                    }
                 }
             }
         }
         Ok(())
     }
 
-    /// Check to see if the view change timeout has expired
+    /// If `min_block_interval` is set, the primary tries to publish a block as soon as that long
+    /// has passed since the last block was committed, rather than waiting for `block_duration`.
+    /// This is called on every pass through the event loop, so a block goes out shortly after
+    /// the validator has batches for it.
+    pub fn try_publish_early(&mut self) -> Result<(), PbftError> {
+        let interval_elapsed = match self.state.block_interval_timeout {
+            Some(ref mut timeout) => timeout.check_expired(),
+            None => false,
+        };
+        if interval_elapsed {
+            self.try_publish()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check to see if the view change timeout has expired, or, if a view change is in progress,
+    /// if it has taken too long
     pub fn check_timeout_expired(&mut self) -> bool {
-        self.state.timeout.check_expired()
+        if self.state.mode == PbftMode::ViewChanging {
+            self.state.view_change_timeout.check_expired()
+        } else {
+            self.state.timeout.check_expired()
+        }
     }
 
-    /// Start the checkpoint process
-    /// Primaries start the checkpoint to ensure sequence number correctness
-    pub fn start_checkpoint(&mut self) -> Result<(), PbftError> {
-        if !self.state.is_primary() {
+    /// If the block in progress is about to time out, but some of the messages this node is
+    /// waiting for have arrived, send this node's own messages for the block again, once. A single
+    /// dropped message can hold up a block that most nodes agree on, and sending it again is much
+    /// cheaper than a view change; nodes that already have the messages drop the copies. The peers'
+    /// messages are asked for again until they arrive, however many are missing.
+    pub fn rebroadcast_if_stalled(&mut self) -> Result<(), PbftError> {
+        let waiting_for = match self.state.phase {
+            PbftPhase::Preparing => PbftMessageType::Prepare,
+            PbftPhase::Committing => PbftMessageType::Commit,
+            _ => return Ok(()),
+        };
+        let view = self.state.view;
+        let seq_num = self.state.seq_num;
+        if self.state.mode != PbftMode::Normal
+            || !self.state.timeout.is_active()
+            || self.state.timeout.elapsed() * 4 < self.state.timeout.duration() * 3
+        {
             return Ok(());
         }
-        if self.state.mode == PbftMode::Checkpointing {
+
+        let received = self
+            .msg_log
+            .get_messages_of_type(&waiting_for, seq_num, view)
+            .len() as u64;
+        if received >= self.state.quorum() {
             return Ok(());
         }
 
-        self.state.pre_checkpoint_mode = self.state.mode;
-        self.state.mode = PbftMode::Checkpointing;
-        info!("{}: Starting checkpoint", self.state);
-        let s = self.state.seq_num;
-        self._broadcast_pbft_message(s, &PbftMessageType::Checkpoint, PbftBlock::new())
-    }
+        // The peers may have committed the block and moved on, so they won't send anything else
+        // unless they're asked; the requests are repeated, since they can be lost too
+        self.request_retransmission(seq_num)?;
 
-    /// Retry messages from the backlog queue
-    pub fn retry_backlog(&mut self) -> Result<(), PbftError> {
-        let mut peer_res = Ok(());
-        if let Some(msg) = self.msg_log.pop_backlog() {
-            debug!("{}: Popping from backlog {}", self.state, msg.message_type);
-            peer_res = self.on_peer_message(&msg);
+        // With fewer than f + 1 of the messages, something more than a dropped message is wrong
+        if received < self.state.weak_quorum()
+            || self.state.last_rebroadcast == Some((view, seq_num))
+        {
+            return Ok(());
         }
-        if self.state.mode == PbftMode::Normal && self.state.phase == PbftPhase::NotStarted {
-            if let Some(msg) = self.msg_log.pop_block_backlog() {
-                debug!("{}: Popping BlockNew from backlog", self.state);
-                self.on_block_new(msg)?;
+
+        // A primary's own messages include its `PrePrepare`, without which nobody can prepare
+        let own_id = self.state.get_own_peer_id();
+        let own_types = [
+            PbftMessageType::PrePrepare,
+            PbftMessageType::Prepare,
+            PbftMessageType::Commit,
+        ];
+        let own_messages: Vec<PbftMessage> = own_types
+            .iter()
+            .flat_map(|msg_type| self.msg_log.get_messages_of_type(msg_type, seq_num, view))
+            .filter(|msg| msg.get_info().get_signer_id() == own_id.as_slice())
+            .cloned()
+            .collect();
+
+        info!(
+            "{}: Block is about to time out with {} {}s; sending {} own messages again",
+            self.state,
+            received,
+            String::from(&waiting_for),
+            own_messages.len()
+        );
+        self.state.last_rebroadcast = Some((view, seq_num));
+
+        // This node already has its own messages; handing it another copy would fail as a past
+        // message and stop the rest from going out
+        let peers: Vec<PeerId> = self
+            .state
+            .peers()
+            .iter()
+            .filter(|&peer| peer != &own_id)
+            .cloned()
+            .collect();
+        for own_message in own_messages {
+            let msg_type = PbftMessageType::from(own_message.get_info().get_msg_type());
+            let msg_bytes = own_message
+                .write_to_bytes()
+                .map_err(PbftError::SerializationError)?;
+            for peer in &peers {
+                self.send_to(peer, &msg_type, msg_bytes.clone())?;
             }
         }
-        peer_res
+
+        Ok(())
     }
 
-    /// Initiate a view change (this node suspects that the primary is faulty)
-    /// Nodes drop everything when they're doing a view change - will not process any peer messages
-    /// other than `ViewChanges` until the view change is complete.
-    pub fn start_view_change(&mut self) -> Result<(), PbftError> {
-        if self.state.mode == PbftMode::ViewChanging {
+    /// Make sure that the validator's chain head is the block this node last saw committed. If it
+    /// isn't (for instance, because the validator restarted and lost or synced blocks in the
+    /// meantime), reload the on-chain settings as of the new chain head and rebuild this node's
+    /// state and log, instead of waiting for blocks that will never arrive.
+    pub fn check_chain_head(&mut self) -> Result<(), PbftError> {
+        if (self.state.mode != PbftMode::Normal && self.state.mode != PbftMode::NonVoting)
+            || self.state.phase != PbftPhase::NotStarted
+        {
             return Ok(());
         }
-        warn!("{}: Starting view change", self.state);
-        self.state.mode = PbftMode::ViewChanging;
 
-        let PbftStableCheckpoint {
-            seq_num: stable_seq_num,
-            checkpoint_messages,
-        } = if let Some(ref cp) = self.msg_log.latest_stable_checkpoint {
-            debug!("{}: No stable checkpoint", self.state);
-            cp.clone()
-        } else {
-            PbftStableCheckpoint {
-                seq_num: 0,
-                checkpoint_messages: vec![],
+        let head = self.service.get_chain_head().map_err(|err| {
+            PbftError::ServiceError(String::from("Failed to get chain head"), err)
+        })?;
+        if head.block_id == self.state.chain_head {
+            return Ok(());
+        }
+
+        warn!(
+            "{}: Chain head moved from block {} to block {} unexpectedly; resynchronizing",
+            self.state, self.state.chain_head_num, head.block_num
+        );
+        let config = match config::load_pbft_config(head.block_id.clone(), &mut *self.service) {
+            Ok(config) => config,
+            Err(err) => {
+                self.start_incident(
+                    Incident::InvalidSettings,
+                    format!("couldn't load settings to resynchronize: {}", err),
+                );
+                return Err(err);
             }
         };
+        self.end_incident(Incident::InvalidSettings);
+        self.resync(head, &config)
+    }
 
-        let info = handlers::make_msg_info(
-            &PbftMessageType::ViewChange,
-            self.state.view + 1,
-            stable_seq_num,
-            self.state.get_own_peer_id(),
-        );
+    // Rebuild this node's state and log for the given chain head and settings. Every sequence
+    // number commits one block, so the sequence number moves as far as the chain head did. The
+    // log keeps its stable checkpoint, so the water marks stay where the rest of the network has
+    // them.
+    fn resync(&mut self, head: Block, config: &PbftConfig) -> Result<(), PbftError> {
+        let seq_num = if head.block_num >= self.state.chain_head_num {
+            self.state.seq_num + (head.block_num - self.state.chain_head_num)
+        } else {
+            self.state
+                .seq_num
+                .saturating_sub(self.state.chain_head_num - head.block_num)
+        };
 
-        let mut vc_msg = PbftViewChange::new();
-        vc_msg.set_info(info);
-        vc_msg.set_checkpoint_messages(RepeatedField::from_vec(checkpoint_messages.to_vec()));
+        match self.state.resync(config) {
+            Ok(()) => {}
+            Err(PbftError::NodeNotFound) => self.leave_network(config.peers.clone())?,
+            Err(err) => return Err(err),
+        }
+        self.state.seq_num = seq_num;
+        self.state.chain_head = head.block_id.clone();
+        self.state.chain_head_num = head.block_num;
 
-        let msg_bytes = vc_msg
-            .write_to_bytes()
-            .map_err(PbftError::SerializationError)?;
+        let checkpoint = self.msg_log.latest_stable_checkpoint.take();
+        self.msg_log = PbftLog::new(config);
+        if let Some(checkpoint) = checkpoint {
+            self.msg_log.garbage_collect(checkpoint);
+        }
 
-        self._broadcast_message(&PbftMessageType::ViewChange, &msg_bytes)
+        info!("{}: Resynchronized at block {}", self.state, head.block_num);
+
+        if self.state.is_primary() {
+            self.service
+                .initialize_block(Some(head.block_id))
+                .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
+        }
+
+        Ok(())
     }
 
-    // ---------- Methods for communication between nodes ----------
+    /// Ask each peer to send its messages for the given sequence number in the current view again,
+    /// if this node is missing any of them, instead of catching up from seals for a single missed
+    /// block. Returns whether the messages are still expected to arrive: `false` if they were
+    /// already asked for and didn't arrive in time, in which case they're asked for again, since
+    /// the requests may have been lost as well.
+    fn request_retransmission(&mut self, seq_num: u64) -> Result<bool, PbftError> {
+        let asked_before = self.state.retransmit_seq_num == seq_num;
+        if asked_before && !self.state.retransmit_timeout.check_expired() {
+            return Ok(true);
+        }
+        self.state.retransmit_seq_num = seq_num;
+        self.state.retransmit_timeout.start();
 
-    // Broadcast a message to this node's peers, and itself
-    fn _broadcast_pbft_message(
-        &mut self,
-        seq_num: u64,
-        msg_type: &PbftMessageType,
-        block: PbftBlock,
-    ) -> Result<(), PbftError> {
-        let expected_type = self.state.check_msg_type();
-        // Make sure that we should be sending messages of this type
-        if msg_type.is_multicast() && msg_type != &expected_type {
+        let view = self.state.view;
+        let primary = self.state.get_primary_peer_id();
+        let own_id = self.state.get_own_peer_id();
+        let peers: Vec<PeerId> = self
+            .state
+            .peers()
+            .iter()
+            .filter(|&peer| peer != &own_id)
+            .cloned()
+            .collect();
+
+        for peer in peers {
+            let mut expected = vec![PbftMessageType::Prepare, PbftMessageType::Commit];
+            if peer == primary {
+                expected.insert(0, PbftMessageType::PrePrepare);
+            }
+            let missing: Vec<String> = expected
+                .iter()
+                .filter(|msg_type| {
+                    !self
+                        .msg_log
+                        .has_message_from(msg_type, seq_num, view, peer.as_slice())
+                })
+                .map(String::from)
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+
+            info!(
+                "{}: Asking {:?} to retransmit {:?} for sequence number {}",
+                self.state, peer, missing, seq_num
+            );
+            let mut request = PbftRetransmitRequest::new();
+            request.set_info(handlers::make_msg_info(
+                &PbftMessageType::RetransmitRequest,
+                view,
+                seq_num,
+                own_id.clone(),
+            ));
+            request.set_message_types(RepeatedField::from_vec(missing));
+            let msg_bytes = request
+                .write_to_bytes()
+                .map_err(PbftError::SerializationError)?;
+            self.send_to(&peer, &PbftMessageType::RetransmitRequest, msg_bytes)?;
+        }
+
+        Ok(!asked_before)
+    }
+
+    /// Ask the given peer for seals for the blocks this node missed, up to `end_seq_num`, unless
+    /// this node is still waiting for a response to an earlier request. Sequence numbers that this
+    /// node already has seals for aren't asked for again. Nothing is asked for if messages aren't
+    /// authenticated, since a peer's seals can't be checked then.
+    fn request_state(&mut self, end_seq_num: u64, peer_id: &[u8]) -> Result<(), PbftError> {
+        if self.state.state_request_timeout.is_active() {
             return Ok(());
         }
 
-        let msg_bytes = make_msg_bytes(
-            handlers::make_msg_info(
-                &msg_type,
-                self.state.view,
-                seq_num,
-                self.state.get_own_peer_id(),
-            ),
-            block,
-        ).unwrap_or_default();
+        if !self.state.authenticate_messages {
+            let own_id = self.state.get_own_peer_id();
+            if let Some(suppressed) = self.log_throttle.check(("unsigned seals", own_id)) {
+                warn!(
+                    "{}: Can't catch up to sequence number {} from seals, because messages aren't \
+                     authenticated{}",
+                    self.state, end_seq_num, suppressed
+                );
+            }
+            return Ok(());
+        }
 
-        self._broadcast_message(&msg_type, &msg_bytes)
+        let start_seq_num = self
+            .msg_log
+            .get_highest_seal_seq_num()
+            .unwrap_or(0)
+            .max(self.state.committed_seq_num())
+            + 1;
+        if start_seq_num > end_seq_num {
+            return Ok(());
+        }
+
+        info!(
+            "{}: Requesting seals for sequence numbers {} to {}",
+            self.state, start_seq_num, end_seq_num
+        );
+
+        let mut request = PbftStateRequest::new();
+        request.set_info(handlers::make_msg_info(
+            &PbftMessageType::StateRequest,
+            self.state.view,
+            start_seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        request.set_end_seq_num(end_seq_num);
+        let msg_bytes = request
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+
+        self.state.state_request_timeout.start();
+        self.state.state_request_peer = Some(PeerId::from(peer_id.to_vec()));
+        self.send_to(
+            &PeerId::from(peer_id.to_vec()),
+            &PbftMessageType::StateRequest,
+            msg_bytes,
+        )?;
+
+        Ok(())
     }
 
-    #[cfg(not(test))]
-    fn _broadcast_message(
-        &mut self,
-        msg_type: &PbftMessageType,
-        msg_bytes: &[u8],
-    ) -> Result<(), PbftError> {
-        // Broadcast to peers
-        debug!("{}: Broadcasting {:?}", self.state, msg_type);
-        self.service
-            .broadcast(String::from(msg_type).as_str(), msg_bytes.to_vec())
-            .unwrap_or_else(|err| error!("Couldn't broadcast: {}", err));
+    /// Ask the validator to check all of the backlogged blocks that this node has seals for at
+    /// once, along with the block in progress if it's sealed, so they can be committed one after
+    /// another as soon as each one is valid
+    fn check_sealed_blocks(&mut self) -> Result<(), PbftError> {
+        let mut block_ids = self.msg_log.take_sealed_blocks();
 
-        // Send to self
-        let peer_msg = PeerMessage {
-            message_type: String::from(msg_type),
-            content: msg_bytes.to_vec(),
+        // The block this node is working on isn't in the backlog, but the rest of the network may
+        // have committed it without this node
+        let working_block_id = match self.state.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => {
+                Some(BlockId::from(block.get_block_id().to_vec()))
+            }
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id) => Some(block_id.clone()),
+            WorkingBlockOption::NoWorkingBlock => None,
         };
-        self.on_peer_message(&peer_msg)
-    }
+        if let Some(block_id) = working_block_id {
+            if self.msg_log.has_seal(&Vec::<u8>::from(block_id.clone()))
+                && !block_ids.contains(&block_id)
+            {
+                block_ids.push(block_id);
+            }
+        }
 
-    /// NOTE: Disabling self-sending for testing purposes
-    #[cfg(test)]
-    fn _broadcast_message(
-        &mut self,
-        _msg_type: &PbftMessageType,
-        _msg_bytes: &[u8],
-    ) -> Result<(), PbftError> {
-        return Ok(());
+        if block_ids.is_empty() {
+            return Ok(());
+        }
+
+        debug!("{}: Checking {} sealed blocks", self.state, block_ids.len());
+        self.service
+            .check_blocks(block_ids)
+            .map_err(|err| PbftError::ServiceError(String::from("Failed to check blocks"), err))
     }
-}
 
-/// Create a Protobuf binary representation of a PbftMessage from its info and corresponding Block
-fn make_msg_bytes(info: PbftMessageInfo, block: PbftBlock) -> Result<Vec<u8>, ProtobufError> {
-    let mut msg = PbftMessage::new();
-    msg.set_info(info);
-    msg.set_block(block);
-    msg.write_to_bytes()
-}
+    /// Commit the block after the chain head from its seal, if the validator has found it to be
+    /// valid and this node isn't already waiting for a block to be committed
+    fn commit_next_sealed(&mut self) -> Result<(), PbftError> {
+        if self.state.phase == PbftPhase::Finished {
+            return Ok(());
+        }
 
-// Make a PbftBlock out of a consensus Block (PBFT doesn't need to use all the information about
-// the block - this keeps blocks lighter weight)
-fn pbft_block_from_block(block: Block) -> PbftBlock {
-    let mut pbft_block = PbftBlock::new();
-    pbft_block.set_block_id(Vec::<u8>::from(block.block_id));
-    pbft_block.set_signer_id(Vec::<u8>::from(block.signer_id));
-    pbft_block.set_block_num(block.block_num);
-    pbft_block.set_summary(block.summary);
-    pbft_block
-}
+        let head = self.service.get_chain_head().map_err(|err| {
+            PbftError::ServiceError(String::from("Failed to get chain head"), err)
+        })?;
+        match self.msg_log.take_valid_seal(head.block_num + 1) {
+            Some(seal) => self.commit_sealed(seal),
+            None => Ok(()),
+        }
+    }
 
-/// NOTE: Testing the PbftNode is a bit strange. Due to missing functionality in the Service,
-/// a node calling `broadcast()` doesn't include sending a message to itself. In order to get around
-/// this, `on_peer_message()` is called, which sometimes causes unintended side effects when
-/// testing. Self-sending has been disabled (see `broadcast()` method) for testing purposes.
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use config::mock_config;
-    use crypto::digest::Digest;
-    use crypto::sha2::Sha256;
-    use handlers::make_msg_info;
-    use sawtooth_sdk::consensus::engine::{Error, PeerId};
-    use serde_json;
-    use std::collections::HashMap;
-    use std::default::Default;
-    use std::fs::{remove_file, File};
-    use std::io::prelude::*;
+    /// Commit a block that this node has a verified seal for, without going through consensus for
+    /// it again. The seal's `Commit` messages and their signed copies are added to the log, so this
+    /// node can pass the seal on to other nodes that are behind.
+    fn commit_sealed(&mut self, seal: PbftSeal) -> Result<(), PbftError> {
+        info!(
+            "{}: Committing block {} from seal for sequence number {}",
+            self.state,
+            &hex::encode(seal.get_block().get_block_id())[..6],
+            seal.get_seq_num()
+        );
 
-    const BLOCK_FILE: &str = "blocks.txt";
+        // Nobody else has the messages for these blocks anymore, so the log moves along with them
+        if self.state.mode == PbftMode::Recovering || self.state.mode == PbftMode::NonVoting {
+            self.msg_log.move_water_marks(seal.get_seq_num());
+        }
+        for commit in seal.get_commit_messages() {
+            self.msg_log.add_message(commit.clone());
+        }
+        for signed in seal.get_signed_commit_messages() {
+            self.msg_log
+                .add_signed_vote(seal.get_seq_num(), signed.clone());
+        }
+        self.msg_log.archive_seal(seal.clone());
+        self.state.seq_num = seal.get_seq_num();
+        self.state.working_block = WorkingBlockOption::WorkingBlock(seal.get_block().clone());
+        self.state.phase = PbftPhase::Finished;
+        self.state.timeout.stop();
 
-    /// Mock service to roughly keep track of the blockchain
-    pub struct MockService {
-        pub chain: Vec<BlockId>,
+        let block_id = BlockId::from(seal.get_block().get_block_id().to_vec());
+        self.service
+            .commit_block(block_id.clone())
+            .map_err(|err| PbftError::ServiceError(String::from("Failed to commit block"), err))?;
+        let view = seal
+            .get_commit_messages()
+            .first()
+            .map_or(self.state.view, |commit| commit.get_info().get_view());
+        let seal_signers = seal
+            .get_commit_messages()
+            .iter()
+            .map(|commit| PeerId::from(commit.get_info().get_signer_id().to_vec()))
+            .collect();
+        let record = self.state.finality.record(
+            seal.get_seq_num(),
+            block_id,
+            view,
+            None,
+            true,
+            seal_signers,
+        );
+        self.state
+            .events
+            .push(ConsensusEvent::BlockFinalized { record });
+        Ok(())
     }
 
-    impl MockService {
-        /// Serialize the chain into JSON, and write to a file
-        fn write_chain(&self) {
-            let mut block_file = File::create(BLOCK_FILE).unwrap();
-            let block_bytes: Vec<Vec<u8>> = self
-                .chain
-                .iter()
-                .map(|block: &BlockId| -> Vec<u8> { Vec::<u8>::from(block.clone()) })
-                .collect();
+    // Stop taking part in consensus and view changes, and commit the missed blocks from seals
+    // instead, until this node is close enough to the given sequence number that the rest of the
+    // network still has the messages for the blocks in between. Returns whether this node is
+    // recovering; see `can_recover`.
+    fn start_recovery(&mut self, target: u64, peer_id: &[u8]) -> Result<bool, PbftError> {
+        if !self.can_recover(target) {
+            return Ok(false);
+        }
 
-            let ser_blocks = serde_json::to_string(&block_bytes).unwrap();
-            block_file.write_all(&ser_blocks.into_bytes()).unwrap();
+        if self.state.mode != PbftMode::Recovering {
+            warn!(
+                "{}: Fell too far behind to catch up normally (network is at sequence number {}); \
+                 recovering",
+                self.state, target
+            );
+            // The block in progress is one of the blocks to recover, so it waits for its seal
+            let working_block_id = match self.state.working_block {
+                WorkingBlockOption::WorkingBlock(ref block) => {
+                    Some(BlockId::from(block.get_block_id().to_vec()))
+                }
+                WorkingBlockOption::TentativeWorkingBlock(ref block_id) => Some(block_id.clone()),
+                WorkingBlockOption::NoWorkingBlock => None,
+            };
+            if let Some(block_id) = working_block_id {
+                match self.service.get_blocks(vec![block_id.clone()]) {
+                    Ok(mut blocks) => {
+                        if let Some(block) = blocks.remove(&block_id) {
+                            self.msg_log.push_block_backlog(block);
+                        }
+                    }
+                    Err(err) => error!("Couldn't get block: {}", err),
+                }
+            }
+            self.state.mode = PbftMode::Recovering;
+            self.state.events.push(ConsensusEvent::CatchUpStarted {
+                target_seq_num: target,
+            });
+            self.state.working_block = WorkingBlockOption::NoWorkingBlock;
+            self.state.phase = PbftPhase::NotStarted;
+            self.state.timeout.stop();
+            self.state.view_change_timeout.stop();
         }
+
+        self.state.recovery_target = self.state.recovery_target.max(target);
+        self.request_state(self.state.recovery_target, peer_id)?;
+        Ok(true)
     }
 
-    impl Service for MockService {
-        fn send_to(
-            &mut self,
-            _peer: &PeerId,
-            _message_type: &str,
-            _payload: Vec<u8>,
-        ) -> Result<(), Error> {
-            Ok(())
-        }
-        fn broadcast(&mut self, _message_type: &str, _payload: Vec<u8>) -> Result<(), Error> {
-            Ok(())
+    // Seals are only asked for and trusted when messages are authenticated, so without that, a node
+    // that would recover up to the given sequence number stays in normal operation instead, rather
+    // than wait for seals that will never come
+    fn can_recover(&mut self, target: u64) -> bool {
+        if self.state.authenticate_messages {
+            return true;
         }
-        fn initialize_block(&mut self, _previous_id: Option<BlockId>) -> Result<(), Error> {
-            Ok(())
+        let own_id = self.state.get_own_peer_id();
+        if let Some(suppressed) = self.log_throttle.check(("no recovery", own_id)) {
+            warn!(
+                "{}: Can't recover from seals up to sequence number {}, because messages aren't \
+                 authenticated; staying in normal operation{}",
+                self.state, target, suppressed
+            );
         }
-        fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
-            Ok(Default::default())
+        false
+    }
+
+    /// While recovering, ask the next node for seals if the last `StateRequest` went unanswered,
+    /// so that one node that doesn't respond can't hold recovery up. Nodes that the validator
+    /// reports as disconnected are skipped, unless none are connected.
+    pub fn continue_recovery(&mut self) -> Result<(), PbftError> {
+        if self.state.mode != PbftMode::Recovering || self.state.state_request_timeout.is_active() {
+            return Ok(());
         }
-        fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
-            Ok(Default::default())
+
+        let num_nodes = self.state.num_nodes() as usize;
+        let others: Vec<usize> = (1..=num_nodes)
+            .map(|i| (self.state.recovery_peer + i) % num_nodes)
+            .filter(|i| *i != self.state.id as usize)
+            .collect();
+        let next = others
+            .iter()
+            .cloned()
+            .find(|i| !self.state.outbox.is_disconnected(&self.state.peers()[*i]))
+            .or_else(|| others.first().cloned());
+        self.state.recovery_peer = next.unwrap_or(self.state.recovery_peer);
+        let peer_id = self.state.peers()[self.state.recovery_peer].clone();
+        self.request_state(self.state.recovery_target, &peer_id)
+    }
+
+    // Go back to normal operation once the rest of the network is within this node's log window
+    // again, in the view that the last block was committed in; normal catch-up takes care of any
+    // blocks still missing
+    fn check_recovered(&mut self) -> Result<(), PbftError> {
+        if self.state.recovery_target >= self.msg_log.get_high_water_mark() {
+            return Ok(());
         }
-        fn cancel_block(&mut self) -> Result<(), Error> {
-            Ok(())
+
+        info!(
+            "{}: Recovered at sequence number {}",
+            self.state, self.state.seq_num
+        );
+        self.state.mode = PbftMode::Normal;
+        self.state.recovery_target = 0;
+        self.state.events.push(ConsensusEvent::CatchUpFinished {
+            seq_num: self.state.seq_num,
+        });
+        self.enter_committed_view()
+    }
+
+    // Move to the view that the last block was committed in, if this node is behind it
+    fn enter_committed_view(&mut self) -> Result<(), PbftError> {
+        let view = self
+            .msg_log
+            .get_seal(self.state.seq_num, self.state.quorum())
+            .and_then(|seal| {
+                seal.get_commit_messages()
+                    .first()
+                    .map(|commit| commit.get_info().get_view())
+            })
+            .unwrap_or(self.state.view);
+        if view > self.state.view {
+            handlers::enter_view(&mut self.state, &mut self.msg_log, &mut *self.service, view)?;
         }
-        fn check_blocks(&mut self, _priority: Vec<BlockId>) -> Result<(), Error> {
-            Ok(())
+        Ok(())
+    }
+
+    /// Check that the primary is still responding by sending it a `Probe`, if no block is in
+    /// progress (otherwise, the view change timeout is what catches a faulty primary). If the
+    /// primary hasn't responded to `max_probe_failures` `Probe`s in a row, start a view change
+    /// instead of waiting for the view change timeout.
+    pub fn probe_primary(&mut self) -> Result<(), PbftError> {
+        if self.state.is_primary()
+            || self.state.mode != PbftMode::Normal
+            || self.state.phase != PbftPhase::NotStarted
+        {
+            self.state.probe_outstanding = false;
+            self.state.failed_probes = 0;
+            return Ok(());
         }
-        fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
-            self.chain.push(block_id);
-            self.write_chain();
-            Ok(())
+
+        if self.state.probe_outstanding {
+            self.state.failed_probes += 1;
+            if self.state.failed_probes >= self.state.max_probe_failures {
+                warn!(
+                    "{}: Primary didn't respond to {} probes, starting view change",
+                    self.state, self.state.failed_probes
+                );
+                self.state.probe_outstanding = false;
+                self.state.failed_probes = 0;
+                return self.start_view_change(ViewChangeReason::ProbeFailures);
+            }
         }
-        fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
-            Ok(())
+
+        let msg_bytes = make_msg_bytes(
+            handlers::make_msg_info(
+                &PbftMessageType::Probe,
+                self.state.view,
+                self.state.seq_num,
+                self.state.get_own_peer_id(),
+            ),
+            PbftBlock::new(),
+        )
+        .map_err(PbftError::SerializationError)?;
+
+        self.state.probe_outstanding = true;
+        let primary = self.state.get_primary_peer_id();
+        self.send_to(&primary, &PbftMessageType::Probe, msg_bytes)?;
+
+        Ok(())
+    }
+
+    /// Send every other node a `ClockSync` with this node's current time, so that how far their
+    /// clocks are from this node's can be estimated from their answers
+    pub fn sync_clocks(&mut self) -> Result<(), PbftError> {
+        if self.state.mode == PbftMode::NonVoting {
+            return Ok(());
         }
-        fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
-            Ok(())
+
+        let mut sync = PbftClockSync::new();
+        sync.set_info(handlers::make_msg_info(
+            &PbftMessageType::ClockSync,
+            self.state.view,
+            self.state.seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        sync.set_origin_time(timing::unix_millis());
+        let msg_bytes = sync
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+        self._broadcast_message(&PbftMessageType::ClockSync, &msg_bytes)
+    }
+
+    // Estimate how far the given node's clock is from this node's, from its answer to a
+    // `ClockSync`, and warn if it's further than the liveness timeouts can tolerate
+    fn record_clock_skew(
+        &mut self,
+        peer_id: PeerId,
+        origin_time: u64,
+        receive_time: u64,
+        now: u64,
+    ) {
+        let skew = match ClockSkew::estimate(origin_time, receive_time, now) {
+            Some(skew) => skew,
+            None => return,
+        };
+        if skew.exceeds(self.state.max_clock_skew) {
+            if let Some(suppressed) = self.log_throttle.check(("clock skew", peer_id.clone())) {
+                warn!(
+                    "{}: Clock of {:?} is {} from this node's, more than the allowed {:?}{}",
+                    self.state, peer_id, skew, self.state.max_clock_skew, suppressed
+                );
+            }
         }
-        fn get_blocks(
-            &mut self,
-            block_ids: Vec<BlockId>,
-        ) -> Result<HashMap<BlockId, Block>, Error> {
-            let mut res = HashMap::new();
-            for id in &block_ids {
-                let index = self
-                    .chain
-                    .iter()
-                    .position(|val| val == id)
-                    .unwrap_or(self.chain.len());
-                res.insert(id.clone(), mock_block(index as u64));
+        self.state
+            .peer_stats
+            .entry(peer_id)
+            .or_insert_with(PeerStats::default)
+            .clock_skew = Some(skew);
+    }
+
+    /// While no block is in progress, the primary sends a `Heartbeat` so the other nodes know
+    /// it's still running, and the other nodes start a view change if they haven't heard from the
+    /// primary for `idle_timeout`. While a block is in progress, the view change timeout watches
+    /// the primary instead.
+    pub fn heartbeat(&mut self) -> Result<(), PbftError> {
+        if self.state.mode != PbftMode::Normal || self.state.phase != PbftPhase::NotStarted {
+            if let Some(ref mut timeout) = self.state.idle_timeout {
+                timeout.start();
             }
-            Ok(res)
+            return Ok(());
         }
-        fn get_chain_head(&mut self) -> Result<Block, Error> {
-            let prev_num = ::std::panic::catch_unwind(|| self.chain.len() - 2).unwrap_or(0);
-            Ok(Block {
-                block_id: self.chain.last().unwrap().clone(),
-                previous_id: self.chain.get(prev_num).unwrap().clone(),
-                signer_id: PeerId::from(vec![]),
-                block_num: self.chain.len() as u64,
-                payload: vec![],
-                summary: vec![],
-            })
+
+        if self.state.is_primary() {
+            let msg_bytes = make_msg_bytes(
+                handlers::make_msg_info(
+                    &PbftMessageType::Heartbeat,
+                    self.state.view,
+                    self.state.seq_num,
+                    self.state.get_own_peer_id(),
+                ),
+                PbftBlock::new(),
+            )
+            .map_err(PbftError::SerializationError)?;
+            return self._broadcast_message(&PbftMessageType::Heartbeat, &msg_bytes);
         }
-        fn get_settings(
-            &mut self,
-            _block_id: BlockId,
-            _settings: Vec<String>,
-        ) -> Result<HashMap<String, String>, Error> {
-            Ok(Default::default())
+
+        if self
+            .state
+            .idle_timeout
+            .as_mut()
+            .map_or(false, Timeout::check_expired)
+        {
+            warn!(
+                "{}: Haven't heard from the primary while idle, starting view change",
+                self.state
+            );
+            return self.start_view_change(ViewChangeReason::IdleTimeout);
         }
-        fn get_state(
-            &mut self,
-            _block_id: BlockId,
-            _addresses: Vec<String>,
-        ) -> Result<HashMap<String, Vec<u8>>, Error> {
-            Ok(Default::default())
+
+        Ok(())
+    }
+
+    /// If this node is the primary, tell the other nodes that it's about to stop, so they can move
+    /// to the next view right away instead of waiting to notice that it's gone. The node handles
+    /// its own `Handoff` too, which sends its `ViewChange` along with theirs.
+    pub fn hand_off(&mut self) -> Result<(), PbftError> {
+        if !self.state.is_primary() || self.state.mode != PbftMode::Normal {
+            return Ok(());
         }
+
+        info!("{}: Handing off to the next primary", self.state);
+        let msg_bytes = make_msg_bytes(
+            handlers::make_msg_info(
+                &PbftMessageType::Handoff,
+                self.state.view,
+                self.state.seq_num,
+                self.state.get_own_peer_id(),
+            ),
+            PbftBlock::new(),
+        )
+        .map_err(PbftError::SerializationError)?;
+
+        self._broadcast_message(&PbftMessageType::Handoff, &msg_bytes)
     }
 
-    /// Create a node, based on a given ID
-    fn mock_node(node_id: usize) -> PbftNode {
-        let service: Box<MockService> = Box::new(MockService {
-            // Create genesis block (but with actual ID)
-            chain: vec![mock_block_id(0)],
-        });
-        let cfg = mock_config(4);
-        PbftNode::new(node_id as u64, &cfg, service)
+    /// Put this node in maintenance mode, or take it out. In maintenance mode, the node keeps
+    /// voting, so the network doesn't lose a member, but doesn't publish blocks, and hands off to
+    /// the next primary whenever it's the primary.
+    pub fn set_maintenance(&mut self, maintenance: bool) -> Result<(), PbftError> {
+        if maintenance == self.state.maintenance {
+            return Ok(());
+        }
+        if maintenance {
+            warn!("{}: Entering maintenance mode", self.state);
+        } else {
+            warn!("{}: Leaving maintenance mode", self.state);
+        }
+        self.state.maintenance = maintenance;
+        self.state.maintenance_handoff = None;
+        self.hand_off_for_maintenance()
     }
 
-    /// Create a deterministic BlockId hash based on a block number
-    fn mock_block_id(num: u64) -> BlockId {
-        let mut sha = Sha256::new();
-        sha.input_str(format!("I'm a block with block num {}", num).as_str());
-        BlockId::from(sha.result_str().as_bytes().to_vec())
+    // Hand off to the next primary if this node is the primary in maintenance mode, once per view
+    fn hand_off_for_maintenance(&mut self) -> Result<(), PbftError> {
+        if !self.state.maintenance
+            || !self.state.is_primary()
+            || self.state.mode != PbftMode::Normal
+            || self.state.maintenance_handoff == Some(self.state.view)
+        {
+            return Ok(());
+        }
+        self.state.maintenance_handoff = Some(self.state.view);
+        self.hand_off()
     }
 
-    /// Create a deterministic PeerId hash based on a peer number
-    fn mock_peer_id(num: u64) -> PeerId {
-        let mut sha = Sha256::new();
-        sha.input_str(format!("I'm a peer (number {})", num).as_str());
-        PeerId::from(sha.result_str().as_bytes().to_vec())
+    /// Ask the node that's furthest along for the seals of the blocks this node is missing, even
+    /// if an earlier request hasn't been answered yet. Returns the sequence number this node is
+    /// catching up to, or `None` if no other node is known to be ahead of it.
+    pub fn force_catch_up(&mut self) -> Result<Option<u64>, PbftError> {
+        let own_peer_id = self.state.get_own_peer_id();
+        let furthest = self
+            .state
+            .peers()
+            .iter()
+            .filter(|peer_id| **peer_id != own_peer_id)
+            .filter_map(|peer_id| {
+                self.state
+                    .peer_stats
+                    .get(peer_id)
+                    .map(|stats| (stats.highest_seq_num, peer_id.clone()))
+            })
+            .max_by_key(|&(seq_num, _)| seq_num);
+
+        match furthest {
+            Some((target, peer_id)) if target > self.state.seq_num => {
+                warn!(
+                    "{}: Catching up to sequence number {} from {:?}",
+                    self.state, target, peer_id
+                );
+                self.state.state_request_timeout.stop();
+                self.request_state(target, &Vec::<u8>::from(peer_id))?;
+                Ok(Some(target))
+            }
+            _ => Ok(None),
+        }
     }
 
-    /// Create a mock Block, including only the BlockId, the BlockId of the previous block, and the
-    /// block number
-    fn mock_block(num: u64) -> Block {
-        Block {
-            block_id: mock_block_id(num),
-            previous_id: mock_block_id(num - 1),
-            signer_id: PeerId::from(vec![]),
-            block_num: num,
-            payload: vec![],
-            summary: vec![],
+    /// Save this node's view, the view change it's doing (if any), and the `ViewChange`s it has
+    /// collected for later views, if it has somewhere to save them, and add the view changes it
+    /// has made and the blocks that became final since the last save to its audit trail. Errors
+    /// are logged rather than returned, since the node can keep going without its progress saved.
+    pub fn save_view_change_progress(&mut self) {
+        let records = mem::replace(&mut self.state.view_change_records, vec![]);
+        let finality_records = self.state.finality.take_unsaved();
+        if self.storage.is_none() {
+            return;
+        }
+
+        let progress = self.view_change_progress();
+        if let Some(ref mut storage) = self.storage {
+            if let Err(err) = storage.append_to_audit_trail(&records) {
+                error!(
+                    "{}: Couldn't add view changes to the audit trail: {}",
+                    self.state, err
+                );
+            }
+            if let Err(err) = storage.append_finality_records(&finality_records) {
+                error!(
+                    "{}: Couldn't add finality records to the audit trail: {}",
+                    self.state, err
+                );
+            }
+            if let Err(err) = storage.save(&progress) {
+                error!(
+                    "{}: Couldn't save view change progress: {}",
+                    self.state, err
+                );
+            }
         }
     }
 
-    /// Create a mock PeerMessage
-    fn mock_msg(
-        msg_type: &PbftMessageType,
-        view: u64,
-        seq_num: u64,
-        block: Block,
-        from: u64,
-    ) -> PeerMessage {
-        let info = make_msg_info(&msg_type, view, seq_num, mock_peer_id(from));
+    /// Save this node's progress before it does something the other nodes will see, such as
+    /// sending a vote, so that if it crashes right after, it comes back knowing what it did.
+    /// Nothing is saved if the node has nowhere to save it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InternalError` if the progress couldn't be saved; the action it was saved for
+    /// shouldn't be taken.
+    fn persist_progress(&mut self) -> Result<(), PbftError> {
+        if self.storage.is_none() {
+            return Ok(());
+        }
 
-        let mut pbft_msg = PbftMessage::new();
-        pbft_msg.set_info(info);
-        pbft_msg.set_block(pbft_block_from_block(block.clone()));
+        let progress = self.view_change_progress();
+        if let Some(ref mut storage) = self.storage {
+            storage.save(&progress).map_err(|err| {
+                PbftError::InternalError(format!("Couldn't save progress: {}", err))
+            })?;
+        }
+        Ok(())
+    }
+
+    // This node's view, the view change it's doing (if any), the `ViewChange`s it has collected
+    // for later views, and the votes it has sent for blocks it hasn't committed yet
+    fn view_change_progress(&mut self) -> PbftViewChangeProgress {
+        let mut progress = PbftViewChangeProgress::new();
+        progress.set_view(self.state.view);
+        if self.state.mode == PbftMode::ViewChanging {
+            progress.set_target_view(self.view_change_target());
+        }
+        let view = self.state.view;
+        progress.set_view_changes(RepeatedField::from_vec(
+            self.msg_log
+                .view_changes()
+                .filter(|vc| vc.get_info().get_view() > view)
+                .cloned()
+                .collect(),
+        ));
+        self.state.sent_votes.prune(self.state.seq_num);
+        progress.set_votes(self.state.sent_votes.to_protos());
+        progress
+    }
+
+    /// Pick up where this node left off before it restarted: move to the view it was in, put the
+    /// `ViewChange`s it had collected back in the log, and if it was in the middle of a view
+    /// change, resume it with the same target view, sending its `ViewChange` again in case the
+    /// other nodes never got it. If the saved progress can't be loaded or is corrupt, the node
+    /// starts from the chain head instead; see `rebuild_state`.
+    pub fn restore_view_change_progress(&mut self) -> Result<(), PbftError> {
+        let progress = match self.storage.as_mut().map(|storage| storage.load()) {
+            Some(Ok(Some(progress))) => progress,
+            Some(Err(err)) => return self.rebuild_state(&err),
+            _ => return Ok(()),
+        };
+
+        info!(
+            "{}: Restoring view {} (view change target {})",
+            self.state,
+            progress.get_view(),
+            progress.get_target_view()
+        );
+
+        if progress.get_view() > self.state.view {
+            let was_primary = self.state.is_primary();
+            handlers::enter_view(
+                &mut self.state,
+                &mut self.msg_log,
+                &mut *self.service,
+                progress.get_view(),
+            )?;
+            // The block this node started when it thought it was the primary isn't its to publish
+            if was_primary && !self.state.is_primary() {
+                self.service
+                    .cancel_block()
+                    .unwrap_or_else(|err| error!("Couldn't cancel block: {}", err));
+            }
+        }
+
+        for vc in progress.get_view_changes() {
+            self.msg_log.add_view_change(vc.clone());
+        }
+        self.state.sent_votes = SentVotes::from_protos(progress.get_votes());
+
+        if progress.get_target_view() > self.state.view {
+            self.state.mode = PbftMode::ViewChanging;
+            self.state.view_change_backoff.reset();
+            while self.view_change_target() < progress.get_target_view() {
+                self.state.view_change_backoff.fail();
+            }
+            warn!(
+                "{}: Resuming view change to view {}",
+                self.state,
+                self.view_change_target()
+            );
+            self.broadcast_view_change()?;
+        }
+
+        Ok(())
+    }
+
+    // Start over from the chain head and on-chain settings, because the saved progress couldn't be
+    // loaded. The node's state was just built from them, so it's fresh already, but the node has
+    // lost its record of the votes it sent. Rather than vote again and risk contradicting itself,
+    // it catches up from the other nodes' seals until the block after the chain head is committed,
+    // if it can (see `can_recover`); otherwise it carries on from the chain head as it is. The
+    // unusable file is set aside for inspection, and the incident is added to the audit trail.
+    fn rebuild_state(&mut self, cause: &io::Error) -> Result<(), PbftError> {
+        error!(
+            "{}: Couldn't load view change progress ({}); rebuilding state from the chain head",
+            self.state, cause
+        );
+        if let Some(ref mut storage) = self.storage {
+            match storage.set_aside() {
+                Ok(path) => warn!("Set the unusable progress aside as {:?}", path),
+                Err(err) => error!("Couldn't set the unusable progress aside: {}", err),
+            }
+            storage
+                .append_incident("state_rebuilt", &cause.to_string())
+                .unwrap_or_else(|err| error!("Couldn't add incident to the audit trail: {}", err));
+        }
+
+        // A lone node has no one to contradict, or to catch up from
+        if self.state.single_node {
+            return Ok(());
+        }
+
+        let target = self.state.chain_head_num + 1;
+        if !self.can_recover(target) {
+            return Ok(());
+        }
+        self.state.seq_num = self.state.chain_head_num;
+        self.state.mode = PbftMode::Recovering;
+        self.state.recovery_target = target;
+        self.state.events.push(ConsensusEvent::CatchUpStarted {
+            target_seq_num: target,
+        });
+        self.continue_recovery()
+    }
+
+    /// Check whether this node has stopped making progress, which it can't get out of by itself,
+    /// so an operator hears about it once when it happens (and once when it's over)
+    pub fn check_progress(&mut self) {
+        let status = NodeStatus::new(&self.state);
+        if status.health == Health::Stuck {
+            self.start_incident(Incident::Stuck, status.problems.join("; "));
+        } else {
+            self.end_incident(Incident::Stuck);
+        }
+    }
+
+    // Report a problem that needs an operator's attention through everything that operational
+    // tooling can watch (see `incidents`), if it wasn't going on already
+    fn start_incident(&mut self, incident: Incident, detail: String) {
+        if !self.state.incidents.start(incident, detail.clone()) {
+            return;
+        }
+
+        error!(
+            "{}: Needs an operator: {} ({})",
+            self.state, incident, detail
+        );
+        if let Some(ref mut storage) = self.storage {
+            storage
+                .append_incident(incident.name(), &detail)
+                .unwrap_or_else(|err| error!("Couldn't add incident to the audit trail: {}", err));
+        }
+        self.state
+            .events
+            .push(ConsensusEvent::IncidentStarted { incident, detail });
+    }
+
+    // Report that a problem that needed an operator's attention is over, if it was going on
+    fn end_incident(&mut self, incident: Incident) {
+        if !self.state.incidents.end(incident) {
+            return;
+        }
+
+        info!("{}: No longer {}", self.state, incident);
+        self.state
+            .events
+            .push(ConsensusEvent::IncidentEnded { incident });
+    }
+
+    // Count a seal from another node that failed verification; a run of them is an incident
+    fn seal_failed(&mut self, err: &PbftError) {
+        if self.state.incidents.count_invalid_seal() {
+            self.start_incident(Incident::InvalidSeals, format!("latest failure: {}", err));
+        }
+    }
+
+    fn seal_verified(&mut self) {
+        self.state.incidents.count_valid_seal();
+        self.end_incident(Incident::InvalidSeals);
+    }
+
+    /// Start the checkpoint process
+    /// Every node broadcasts a `Checkpoint` for the block it just committed; the checkpoint
+    /// becomes stable once a quorum of nodes agree on it.
+    pub fn start_checkpoint(&mut self, block_id: BlockId) -> Result<(), PbftError> {
+        let blocks: Vec<Block> = self
+            .service
+            .get_blocks(vec![block_id])
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_block_id, block)| block)
+            .collect();
+
+        if blocks.is_empty() {
+            return Err(PbftError::WrongNumBlocks);
+        }
+
+        info!("{}: Starting checkpoint", self.state);
+        let s = self.state.seq_num;
+        self._broadcast_pbft_message(
+            s,
+            &PbftMessageType::Checkpoint,
+            handlers::pbft_block_from_block(blocks[0].clone()),
+        )
+    }
+
+    /// If there's proof that the checkpoint at this sequence number and block is stable, move
+    /// the log's water marks up to it and garbage collect
+    fn try_stabilize_checkpoint(&mut self, seq_num: u64, block_id: &[u8]) {
+        let own_id = Vec::<u8>::from(self.state.get_own_peer_id());
+        if let Some(checkpoint) =
+            self.msg_log
+                .get_checkpoint_proof(seq_num, block_id, &own_id, self.state.quorum())
+        {
+            info!(
+                "{}: Reached stable checkpoint (seq num {}); garbage collecting logs",
+                self.state, seq_num
+            );
+            self.msg_log.garbage_collect(checkpoint);
+        }
+    }
+
+    /// Retry messages from the backlog queue
+    pub fn retry_backlog(&mut self) -> Result<(), PbftError> {
+        let mut peer_res = Ok(());
+        if let Some(msg) = self.msg_log.pop_backlog() {
+            debug!("{}: Popping from backlog {}", self.state, msg.message_type);
+            peer_res = self.on_peer_message(&msg);
+        }
+        if self.state.mode == PbftMode::Normal && self.state.phase == PbftPhase::NotStarted {
+            if let Some(msg) = self.msg_log.pop_block_backlog() {
+                debug!("{}: Popping BlockNew from backlog", self.state);
+                self.refill_block_backlog();
+                self.on_block_new(msg)?;
+            }
+        }
+        peer_res
+    }
+
+    // Fetch blocks that were deferred because the block backlog was full, now that there's room
+    // for them
+    fn refill_block_backlog(&mut self) {
+        let block_ids = self.msg_log.take_deferred_blocks();
+        if block_ids.is_empty() {
+            return;
+        }
+
+        let mut blocks = self
+            .service
+            .get_blocks(block_ids.clone())
+            .unwrap_or_else(|err| {
+                error!("Couldn't get deferred blocks: {}", err);
+                Default::default()
+            });
+        for block_id in block_ids {
+            match blocks.remove(&block_id) {
+                Some(block) => {
+                    self.msg_log.push_block_backlog(block);
+                }
+                None => warn!(
+                    "{}: Deferred block {:?} is no longer available",
+                    self.state, block_id
+                ),
+            }
+        }
+    }
+
+    /// Initiate a view change (this node suspects that the primary is faulty)
+    /// Nodes drop everything when they're doing a view change - will not process any peer messages
+    /// other than `ViewChanges` until the view change is complete.
+    ///
+    /// If a view change is already in progress and hasn't completed in time, this starts a view
+    /// change to the view after the one it was trying to reach, waiting longer for it to complete
+    /// (see `ViewChangeBackoff`). The reason is recorded in the view statistics as why the current
+    /// view ended.
+    pub fn start_view_change(&mut self, reason: ViewChangeReason) -> Result<(), PbftError> {
+        if self.state.mode == PbftMode::NonVoting {
+            return Ok(());
+        }
+        self.state.view_history.record_end_reason(reason);
+
+        if self.state.mode == PbftMode::ViewChanging {
+            if !self.state.view_change_timeout.check_expired() {
+                return Ok(());
+            }
+            self.state.view_change_backoff.fail();
+            warn!(
+                "{}: View change timed out, trying view {}",
+                self.state,
+                self.view_change_target()
+            );
+        } else {
+            warn!("{}: Starting view change", self.state);
+            self.state.mode = PbftMode::ViewChanging;
+            self.state.metrics.count_view_change(reason);
+        }
+
+        self.broadcast_view_change()
+    }
+
+    /// Send a `ViewChange` message for the view this node is trying to reach, and start the timer
+    /// for that view change
+    fn broadcast_view_change(&mut self) -> Result<(), PbftError> {
+        let msg_bytes = self
+            .view_change_message()
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+
+        let duration = self.state.view_change_backoff.duration();
+        self.state.view_change_timeout.set_duration(duration);
+        self.state.view_change_timeout.start();
+
+        self.persist_progress()?;
+        self._broadcast_message(&PbftMessageType::ViewChange, &msg_bytes)
+    }
+
+    // This node's `ViewChange` message for the view it's trying to reach, with proof of its
+    // stable checkpoint and the blocks it has prepared since then
+    fn view_change_message(&self) -> PbftViewChange {
+        let PbftStableCheckpoint {
+            seq_num: stable_seq_num,
+            checkpoint_messages,
+        } = if let Some(ref cp) = self.msg_log.latest_stable_checkpoint {
+            debug!("{}: No stable checkpoint", self.state);
+            cp.clone()
+        } else {
+            PbftStableCheckpoint {
+                seq_num: 0,
+                checkpoint_messages: vec![],
+            }
+        };
+
+        let info = handlers::make_msg_info(
+            &PbftMessageType::ViewChange,
+            self.view_change_target(),
+            stable_seq_num,
+            self.state.get_own_peer_id(),
+        );
+
+        let mut vc_msg = PbftViewChange::new();
+        vc_msg.set_info(info);
+        vc_msg.set_checkpoint_messages(RepeatedField::from_vec(checkpoint_messages.to_vec()));
+        vc_msg.set_prepared_certificates(RepeatedField::from_vec(
+            self.msg_log
+                .get_prepared_certificates(stable_seq_num, self.state.quorum()),
+        ));
+        if self.state.fast_path {
+            vc_msg.set_accepted_pre_prepares(RepeatedField::from_vec(
+                self.accepted_pre_prepares(stable_seq_num),
+            ));
+        }
+        vc_msg
+    }
+
+    // The `PrePrepare`s for the blocks after the given sequence number that this node sent a
+    // `Prepare` for, from the highest view it sent one in. A block that was committed on the fast
+    // path may not have a prepared certificate anywhere but on the nodes that committed it, so the
+    // nodes that prepared it vouch for it instead (see `handlers::new_view_pre_prepares`).
+    fn accepted_pre_prepares(&self, stable_seq_num: u64) -> Vec<PbftMessage> {
+        self.state
+            .sent_votes
+            .latest_votes(&PbftMessageType::Prepare, stable_seq_num)
+            .into_iter()
+            .filter_map(|(seq_num, view, block_id)| {
+                self.msg_log
+                    .get_messages_of_type(&PbftMessageType::PrePrepare, seq_num, view)
+                    .into_iter()
+                    .find(|pre_prepare| pre_prepare.get_block().get_block_id() == block_id)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    // The view that this node's current view change is trying to reach; each view change that
+    // doesn't complete in time moves on to the next view
+    fn view_change_target(&self) -> u64 {
+        self.state.view + 1 + u64::from(self.state.view_change_backoff.attempts())
+    }
+
+    /// Send a `NewView` message for the current view, containing the `ViewChange` messages for it
+    /// and `PrePrepare`s for the blocks that were prepared in earlier views
+    fn broadcast_new_view(&mut self) -> Result<(), PbftError> {
+        let view_changes: Vec<PbftViewChange> = self
+            .msg_log
+            .view_changes()
+            .filter(|vc| vc.get_info().get_view() == self.state.view)
+            .cloned()
+            .collect();
+        let pre_prepares = handlers::new_view_pre_prepares(&self.state, &view_changes);
+
+        let mut new_view = PbftNewView::new();
+        new_view.set_info(handlers::make_msg_info(
+            &PbftMessageType::NewView,
+            self.state.view,
+            self.state.seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        // Other nodes only count `ViewChange`s that come with their senders' signatures
+        if self.signer.is_some() {
+            let mut signed_view_changes = Vec::with_capacity(view_changes.len());
+            for vc in &view_changes {
+                let vc_bytes = vc.write_to_bytes().map_err(PbftError::SerializationError)?;
+                let signed = self.msg_log.get_signed_vote(&vc_bytes).ok_or_else(|| {
+                    PbftError::InternalError(format!(
+                        "Missing the signed ViewChange from {}",
+                        hex::encode(vc.get_info().get_signer_id())
+                    ))
+                })?;
+                signed_view_changes.push(signed.clone());
+            }
+            new_view.set_signed_view_changes(RepeatedField::from_vec(signed_view_changes));
+        }
+        new_view.set_view_changes(RepeatedField::from_vec(view_changes));
+        new_view.set_pre_prepares(RepeatedField::from_vec(pre_prepares));
+
+        let msg_bytes = new_view
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+
+        self._broadcast_message(&PbftMessageType::NewView, &msg_bytes)
+    }
+
+    /// Send a `PrePrepare` for the block that was in progress in the last view, if nothing
+    /// prepared had to be re-proposed instead and `handlers::enter_view` kept it for this view
+    fn propose_carried_block(&mut self) -> Result<(), PbftError> {
+        let block_id = match self.state.working_block {
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id)
+                if self.state.phase == PbftPhase::PrePreparing =>
+            {
+                Vec::<u8>::from(block_id.clone())
+            }
+            _ => return Ok(()),
+        };
+
+        let block = self
+            .msg_log
+            .get_messages_of_type(
+                &PbftMessageType::BlockNew,
+                self.state.seq_num,
+                self.state.view,
+            )
+            .iter()
+            .find(|msg| msg.get_block().get_block_id() == block_id.as_slice())
+            .map(|msg| msg.get_block().clone());
+
+        match block {
+            Some(block) if !self.is_silent_primary() => {
+                let seq_num = self.state.seq_num;
+                self._broadcast_pbft_message(seq_num, &PbftMessageType::PrePrepare, block)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Start consensus in the current view on a block that was prepared in an earlier view, using
+    /// the `PrePrepare` from the `NewView` message
+    fn repropose(&mut self, pre_prepare: PbftMessage) -> Result<(), PbftError> {
+        let seq_num = pre_prepare.get_info().get_seq_num();
+        let block = pre_prepare.get_block().clone();
+        info!(
+            "{}: Re-proposing block {} at sequence number {}",
+            self.state,
+            &hex::encode(block.get_block_id())[..6],
+            seq_num
+        );
+
+        // Record the block as if it had just arrived, so this node can consider it prepared
+        let mut block_new = pre_prepare.clone();
+        block_new.set_info(handlers::make_msg_info(
+            &PbftMessageType::BlockNew,
+            self.state.view,
+            seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        self.msg_log.add_message(block_new);
+        self.msg_log.add_message(pre_prepare);
+
+        self.state.seq_num = seq_num;
+        self.state.working_block = WorkingBlockOption::WorkingBlock(block.clone());
+        self.state.phase = PbftPhase::Preparing;
+        self.state.timeout.start();
+
+        self._broadcast_pbft_message(seq_num, &PbftMessageType::Prepare, block)
+    }
+
+    // ---------- Methods for communication between nodes ----------
+
+    // Broadcast a message to this node's peers, and itself
+    fn _broadcast_pbft_message(
+        &mut self,
+        seq_num: u64,
+        msg_type: &PbftMessageType,
+        block: PbftBlock,
+    ) -> Result<(), PbftError> {
+        let expected_type = self.state.check_msg_type();
+        // Make sure that we should be sending messages of this type
+        if msg_type.is_multicast() && msg_type != &expected_type {
+            return Ok(());
+        }
+
+        // A vote is saved before it's sent, so this node can't contradict it after a crash
+        if msg_type.is_multicast()
+            && self.state.sent_votes.record(
+                msg_type,
+                self.state.view,
+                seq_num,
+                block.get_block_id(),
+            )?
+        {
+            self.persist_progress()?;
+        }
+
+        // Messages about a block carry the trace ID the primary gave it; a primary proposing a
+        // block that doesn't have one yet makes one
+        let mut info = handlers::make_msg_info(
+            &msg_type,
+            self.state.view,
+            seq_num,
+            self.state.get_own_peer_id(),
+        );
+        match self.msg_log.get_trace_id(seq_num, block.get_block_id()) {
+            Some(trace_id) => info.set_trace_id(trace_id.to_vec()),
+            None if msg_type == &PbftMessageType::PrePrepare => {
+                info.set_trace_id(handlers::new_trace_id(
+                    block.get_block_id(),
+                    &self.state.get_own_peer_id(),
+                    self.state.view,
+                    seq_num,
+                ))
+            }
+            None => (),
+        }
+        debug!(
+            "{}: >>>>>> {} (v {}, seq {}, b {}, trace {})",
+            self.state,
+            msg_type,
+            self.state.view,
+            seq_num,
+            &hex::encode(block.get_block_id())[..6],
+            handlers::display_trace_id(info.get_trace_id()),
+        );
+
+        let msg_bytes = make_msg_bytes(info, block).unwrap_or_default();
+
+        self._broadcast_message(&msg_type, &msg_bytes)
+    }
+
+    fn _broadcast_message(
+        &mut self,
+        msg_type: &PbftMessageType,
+        msg_bytes: &[u8],
+    ) -> Result<(), PbftError> {
+        // NOTE: Disabling self-sending for testing purposes, except in simulations
+        #[cfg(test)]
+        {
+            if !self.simulated {
+                return Ok(());
+            }
+        }
+
+        let signed_bytes = self.sign(msg_bytes.to_vec())?;
+        if self.signer.is_some() {
+            self.keep_signed_vote(msg_type, &signed_bytes);
+        }
+
+        // Broadcast to peers
+        debug!("{}: Broadcasting {:?}", self.state, msg_type);
+
+        // When faults are injected, each peer's copy may be different, so it's sent separately
+        #[cfg(feature = "test-faults")]
+        let send_separately = self.faults.is_some();
+        #[cfg(not(feature = "test-faults"))]
+        let send_separately = false;
+
+        if send_separately {
+            let own_peer_id = self.state.get_own_peer_id();
+            let peer_ids: Vec<PeerId> = self
+                .state
+                .peers()
+                .iter()
+                .filter(|peer_id| **peer_id != own_peer_id)
+                .cloned()
+                .collect();
+            for peer_id in peer_ids {
+                self.send_to(&peer_id, msg_type, msg_bytes.to_vec())?;
+            }
+        } else {
+            // Peers that don't get the message are sent it later, once they can be
+            let unreached: Vec<PeerId> = match self
+                .service
+                .broadcast(String::from(msg_type).as_str(), signed_bytes.clone())
+            {
+                Ok(()) => self
+                    .state
+                    .peers()
+                    .iter()
+                    .filter(|peer_id| self.state.outbox.is_disconnected(peer_id))
+                    .cloned()
+                    .collect(),
+                Err(err) => {
+                    warn!(
+                        "{}: Couldn't broadcast {:?}, will retry: {}",
+                        self.state, msg_type, err
+                    );
+                    let own_peer_id = self.state.get_own_peer_id();
+                    self.state
+                        .peers()
+                        .iter()
+                        .filter(|peer_id| **peer_id != own_peer_id)
+                        .cloned()
+                        .collect()
+                }
+            };
+            let own_peer_id = self.state.get_own_peer_id();
+            let reached: Vec<PeerId> = self
+                .state
+                .peers()
+                .iter()
+                .filter(|peer_id| **peer_id != own_peer_id && !unreached.contains(peer_id))
+                .cloned()
+                .collect();
+            for peer_id in reached {
+                self.count_sent(&peer_id, String::from(msg_type).as_str());
+            }
+            for peer_id in unreached {
+                self.state
+                    .outbox
+                    .push(&peer_id, String::from(msg_type), signed_bytes.clone());
+            }
+        }
+
+        // Send to self
+        let peer_msg = PeerMessage {
+            message_type: String::from(msg_type),
+            content: msg_bytes.to_vec(),
+        };
+        self.on_peer_message(&peer_msg)
+    }
+
+    // Sign a message and send it to a single peer
+    fn send_to(
+        &mut self,
+        peer_id: &PeerId,
+        msg_type: &PbftMessageType,
+        msg_bytes: Vec<u8>,
+    ) -> Result<(), PbftError> {
+        #[cfg(feature = "test-faults")]
+        let msg_bytes = match self.faults {
+            Some(ref faults) => {
+                let peer_index = self
+                    .state
+                    .peers()
+                    .iter()
+                    .position(|id| id == peer_id)
+                    .unwrap_or(0);
+                faults.tamper(msg_type, msg_bytes, peer_index, self.state.seq_num)
+            }
+            None => msg_bytes,
+        };
+
+        let signed_bytes = self.sign(msg_bytes)?;
+
+        #[cfg(feature = "test-faults")]
+        let signed_bytes = match self.faults {
+            Some(ref mut faults) => {
+                match faults.delay(peer_id, msg_type, signed_bytes, self.state.seq_num) {
+                    Some(signed_bytes) => signed_bytes,
+                    None => return Ok(()),
+                }
+            }
+            None => signed_bytes,
+        };
+
+        // Messages for a peer that is disconnected, or that already has messages waiting, are
+        // held and sent in order later
+        if !self.state.outbox.can_send(peer_id) {
+            self.state
+                .outbox
+                .push(peer_id, String::from(msg_type), signed_bytes);
+            return Ok(());
+        }
+
+        match self.service.send_to(
+            peer_id,
+            String::from(msg_type).as_str(),
+            signed_bytes.clone(),
+        ) {
+            Ok(()) => self.count_sent(peer_id, String::from(msg_type).as_str()),
+            Err(err) => {
+                if let Some(suppressed) = self.log_throttle.check(("send", peer_id.clone())) {
+                    warn!(
+                        "{}: Couldn't send {:?} to {:?}, will retry: {}{}",
+                        self.state, msg_type, peer_id, err, suppressed
+                    );
+                }
+                self.state
+                    .outbox
+                    .push(peer_id, String::from(msg_type), signed_bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Try again to send the messages that couldn't be sent before, for each peer that is
+    /// connected and due for another try. If a message fails again, it and the messages after it
+    /// are held for the next try.
+    pub fn retransmit(&mut self) -> Result<(), PbftError> {
+        for (peer_id, mut messages) in self.state.outbox.take_ready() {
+            debug!(
+                "{}: Resending {} messages to {:?}",
+                self.state,
+                messages.len(),
+                peer_id
+            );
+            while let Some((msg_type, payload)) = messages.pop_front() {
+                if let Err(err) = self.service.send_to(&peer_id, &msg_type, payload.clone()) {
+                    if let Some(suppressed) = self.log_throttle.check(("resend", peer_id.clone())) {
+                        warn!(
+                            "{}: Couldn't resend {} to {:?}: {}{}",
+                            self.state, msg_type, peer_id, err, suppressed
+                        );
+                    }
+                    messages.push_front((msg_type, payload));
+                    break;
+                }
+                self.count_sent(&peer_id, &msg_type);
+            }
+            self.state.outbox.retry_failed(&peer_id, messages);
+        }
+        Ok(())
+    }
+
+    /// Send the messages that a fault scenario held back, once their delay is over
+    #[cfg(feature = "test-faults")]
+    pub fn send_delayed_messages(&mut self) {
+        let ready = match self.faults {
+            Some(ref mut faults) => faults.take_ready(),
+            None => return,
+        };
+        for msg in ready {
+            let DelayedMessage {
+                peer_id,
+                msg_type,
+                payload,
+                ..
+            } = msg;
+            self.service
+                .send_to(&peer_id, &msg_type, payload)
+                .unwrap_or_else(|err| error!("Couldn't send {}: {}", msg_type, err));
+        }
+    }
+
+    // Whether this node is primary, but a fault scenario says it should stay silent
+    #[cfg(feature = "test-faults")]
+    fn is_silent_primary(&self) -> bool {
+        self.state.is_primary()
+            && self
+                .faults
+                .as_ref()
+                .map_or(false, |faults| faults.is_silent_primary(self.state.seq_num))
+    }
+
+    #[cfg(not(feature = "test-faults"))]
+    fn is_silent_primary(&self) -> bool {
+        false
+    }
+
+    // Wrap a serialized message in a `PbftSignedMessage`, if message authentication is enabled
+    fn sign(&self, msg_bytes: Vec<u8>) -> Result<Vec<u8>, PbftError> {
+        match self.signer {
+            Some(ref signer) => signer.sign(msg_bytes),
+            None => Ok(msg_bytes),
+        }
+    }
+
+    // Keep the signed copy of a `Commit` or `ViewChange`, sent by this node or another one, so
+    // that this node can pass it on as proof of what its signer sent
+    fn keep_signed_vote(&mut self, msg_type: &PbftMessageType, signed_bytes: &[u8]) {
+        if msg_type != &PbftMessageType::Commit && msg_type != &PbftMessageType::ViewChange {
+            return;
+        }
+        if let Ok(signed) = protobuf::parse_from_bytes::<PbftSignedMessage>(signed_bytes) {
+            if let Ok(info) = parse_msg_info(msg_type, signed.get_message()) {
+                self.msg_log.add_signed_vote(info.get_seq_num(), signed);
+            }
+        }
+    }
+}
+
+impl<S: ConsensusService + ?Sized> Drop for PbftNode<S> {
+    // A node dropped while its thread unwinds from a panic has the last word on what it was doing
+    // when it panicked
+    fn drop(&mut self) {
+        crash_dump::add_state_on_panic(&self.state, &self.msg_log);
+    }
+}
+
+/// Create a Protobuf binary representation of a PbftMessage from its info and corresponding Block
+fn make_msg_bytes(info: PbftMessageInfo, block: PbftBlock) -> Result<Vec<u8>, ProtobufError> {
+    let mut msg = PbftMessage::new();
+    msg.set_info(info);
+    msg.set_block(block);
+    msg.write_to_bytes()
+}
+
+// Make a PbftBlock out of a consensus Block (PBFT doesn't need to use all the information about
+// the block - this keeps blocks lighter weight)
+fn pbft_block_from_block(block: Block) -> PbftBlock {
+    let mut pbft_block = PbftBlock::new();
+    pbft_block.set_block_id(Vec::<u8>::from(block.block_id));
+    pbft_block.set_signer_id(Vec::<u8>::from(block.signer_id));
+    pbft_block.set_block_num(block.block_num);
+    pbft_block.set_summary(block.summary);
+    pbft_block
+}
+
+/// NOTE: Testing the PbftNode is a bit strange. Due to missing functionality in the Service,
+/// a node calling `broadcast()` doesn't include sending a message to itself. In order to get around
+/// this, `on_peer_message()` is called, which sometimes causes unintended side effects when
+/// testing. Self-sending has been disabled (see `_broadcast_message()` method) for testing
+/// purposes, except for nodes in a simulated network (see `simulation`).
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use handlers::make_msg_info;
+    use incidents::INVALID_SEALS_BEFORE_INCIDENT;
+    use sawtooth_sdk::consensus::engine::{Error, PeerId};
+    use sawtooth_sdk::consensus::service::Service;
+    use serde_json;
+    use std::collections::HashMap;
+    use std::default::Default;
+    use std::fs::{remove_file, File};
+    use std::io::prelude::*;
+    use std::time::Duration;
+    use timing;
+
+    const BLOCK_FILE: &str = "blocks.txt";
+
+    /// Mock service to roughly keep track of the blockchain
+    pub struct MockService {
+        pub chain: Vec<BlockId>,
+    }
+
+    impl MockService {
+        /// Serialize the chain into JSON, and write to a file
+        fn write_chain(&self) {
+            let mut block_file = File::create(BLOCK_FILE).unwrap();
+            let block_bytes: Vec<Vec<u8>> = self
+                .chain
+                .iter()
+                .map(|block: &BlockId| -> Vec<u8> { Vec::<u8>::from(block.clone()) })
+                .collect();
+
+            let ser_blocks = serde_json::to_string(&block_bytes).unwrap();
+            block_file.write_all(&ser_blocks.into_bytes()).unwrap();
+        }
+    }
+
+    impl Service for MockService {
+        fn send_to(
+            &mut self,
+            _peer: &PeerId,
+            _message_type: &str,
+            _payload: Vec<u8>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+        fn broadcast(&mut self, _message_type: &str, _payload: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn initialize_block(&mut self, _previous_id: Option<BlockId>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+            Ok(Default::default())
+        }
+        fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
+            Ok(Default::default())
+        }
+        fn cancel_block(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn check_blocks(&mut self, _priority: Vec<BlockId>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+            self.chain.push(block_id);
+            self.write_chain();
+            Ok(())
+        }
+        fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_blocks(
+            &mut self,
+            block_ids: Vec<BlockId>,
+        ) -> Result<HashMap<BlockId, Block>, Error> {
+            let mut res = HashMap::new();
+            for id in &block_ids {
+                let index = self
+                    .chain
+                    .iter()
+                    .position(|val| val == id)
+                    .unwrap_or(self.chain.len());
+                res.insert(id.clone(), mock_block(index as u64));
+            }
+            Ok(res)
+        }
+        fn get_chain_head(&mut self) -> Result<Block, Error> {
+            let prev_num = ::std::panic::catch_unwind(|| self.chain.len() - 2).unwrap_or(0);
+            Ok(Block {
+                block_id: self.chain.last().unwrap().clone(),
+                previous_id: self.chain.get(prev_num).unwrap().clone(),
+                signer_id: PeerId::from(vec![]),
+                block_num: self.chain.len() as u64,
+                payload: vec![],
+                summary: vec![],
+            })
+        }
+        fn get_settings(
+            &mut self,
+            _block_id: BlockId,
+            _settings: Vec<String>,
+        ) -> Result<HashMap<String, String>, Error> {
+            Ok(Default::default())
+        }
+        fn get_state(
+            &mut self,
+            _block_id: BlockId,
+            _addresses: Vec<String>,
+        ) -> Result<HashMap<String, Vec<u8>>, Error> {
+            Ok(Default::default())
+        }
+    }
+
+    /// Create a node, based on a given ID
+    fn mock_node(node_id: usize) -> PbftNode {
+        let service: Box<MockService> = Box::new(MockService {
+            // Create genesis block (but with actual ID)
+            chain: vec![mock_block_id(0)],
+        });
+        let cfg = mock_config(4);
+        PbftNode::new(node_id as u64, &cfg, service)
+    }
+
+    /// The signer of the node with the given ID in a network whose messages are authenticated
+    fn mock_signer(node_id: usize) -> MessageSigner {
+        MessageSigner::from_hex(&format!("{:064x}", node_id + 1)).unwrap()
+    }
+
+    /// Create a node, based on a given ID, in a network whose messages are authenticated; the
+    /// nodes' IDs are their signers' public keys
+    fn mock_signed_node(node_id: usize) -> PbftNode {
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let mut cfg = mock_config(4);
+        cfg.peers = (0..4).map(|i| mock_signer(i).get_signer_id()).collect();
+        cfg.authenticate_messages = true;
+        let mut node: PbftNode = PbftNode::new(node_id as u64, &cfg, service);
+        node.signer = Some(mock_signer(node_id));
+        node
+    }
+
+    /// Create a seal for block `seq_num + 1` at the given sequence number, with `Commit`s from the
+    /// first three nodes of `mock_signed_node`'s network and, if `signed`, their signed copies
+    fn mock_signed_seal(seq_num: u64, signed: bool) -> PbftSeal {
+        let mut seal = PbftSeal::new();
+        seal.set_seq_num(seq_num);
+        seal.set_block(pbft_block_from_block(mock_block(seq_num + 1)));
+        for peer in 0..3 {
+            let signer = mock_signer(peer);
+            let mut commit = PbftMessage::new();
+            commit.set_info(make_msg_info(
+                &PbftMessageType::Commit,
+                0,
+                seq_num,
+                signer.get_signer_id(),
+            ));
+            commit.set_block(pbft_block_from_block(mock_block(seq_num + 1)));
+            if signed {
+                let signed_bytes = signer.sign(commit.write_to_bytes().unwrap()).unwrap();
+                seal.mut_signed_commit_messages()
+                    .push(protobuf::parse_from_bytes(&signed_bytes).unwrap());
+            }
+            seal.mut_commit_messages().push(commit);
+        }
+        seal
+    }
+
+    /// Create a node with the fast path enabled, based on a given ID
+    fn mock_fast_path_node(node_id: usize) -> PbftNode {
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let mut cfg = mock_config(4);
+        cfg.fast_path = true;
+        PbftNode::new(node_id as u64, &cfg, service)
+    }
+
+    /// Give a node block 2, the primary's `PrePrepare` for it at sequence number 1 in view 0, and
+    /// `Prepare`s for it from the given nodes
+    fn prepare_block(node: &mut PbftNode, from: &[u64]) {
+        node.on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        node.on_peer_message(&mock_msg(
+            &PbftMessageType::PrePrepare,
+            0,
+            1,
+            mock_block(2),
+            0,
+        ))
+        .unwrap_or_else(handle_pbft_err);
+        for &peer in from {
+            node.on_peer_message(&mock_msg(
+                &PbftMessageType::Prepare,
+                0,
+                1,
+                mock_block(2),
+                peer,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        }
+    }
+
+    /// Create a deterministic BlockId hash based on a block number
+    fn mock_block_id(num: u64) -> BlockId {
+        let mut sha = Sha256::new();
+        sha.input_str(format!("I'm a block with block num {}", num).as_str());
+        BlockId::from(sha.result_str().as_bytes().to_vec())
+    }
+
+    /// Create a deterministic PeerId hash based on a peer number
+    fn mock_peer_id(num: u64) -> PeerId {
+        let mut sha = Sha256::new();
+        sha.input_str(format!("I'm a node with ID {}", num).as_str());
+        PeerId::from(sha.result_str().as_bytes().to_vec())
+    }
+
+    /// Create a mock Block, including only the BlockId, the BlockId of the previous block, and the
+    /// block number
+    fn mock_block(num: u64) -> Block {
+        Block {
+            block_id: mock_block_id(num),
+            previous_id: mock_block_id(num - 1),
+            signer_id: PeerId::from(vec![]),
+            block_num: num,
+            payload: vec![],
+            summary: vec![],
+        }
+    }
+
+    /// Create a mock PeerMessage
+    fn mock_msg(
+        msg_type: &PbftMessageType,
+        view: u64,
+        seq_num: u64,
+        block: Block,
+        from: u64,
+    ) -> PeerMessage {
+        let info = make_msg_info(&msg_type, view, seq_num, mock_peer_id(from));
+
+        let mut pbft_msg = PbftMessage::new();
+        pbft_msg.set_info(info);
+        pbft_msg.set_block(pbft_block_from_block(block.clone()));
+
+        let content = pbft_msg.write_to_bytes().expect("SerializationError");
+        PeerMessage {
+            message_type: String::from(msg_type),
+            content,
+        }
+    }
+
+    fn handle_pbft_err(e: PbftError) {
+        match e {
+            PbftError::Timeout => (),
+            PbftError::WrongNumMessages(_, _, _) | PbftError::NotReadyForMessage => {
+                println!("{}", e)
+            }
+            _ => panic!("{}", e),
+        }
+    }
+
+    /// Make sure that receiving a `BlockNew` update works as expected
+    #[test]
+    fn block_new() {
+        // NOTE: Special case for primary node
+        let mut node0 = mock_node(0);
+        node0
+            .on_block_new(mock_block(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.phase, PbftPhase::PrePreparing);
+        assert_eq!(node0.state.seq_num, 1);
+        assert_eq!(
+            node0.state.working_block,
+            WorkingBlockOption::TentativeWorkingBlock(mock_block_id(1))
+        );
+
+        // Try the next block
+        let mut node1 = mock_node(1);
+        node1
+            .on_block_new(mock_block(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
+        assert_eq!(
+            node1.state.working_block,
+            WorkingBlockOption::TentativeWorkingBlock(mock_block_id(1))
+        );
+        assert_eq!(node1.state.seq_num, 0);
+
+        // Try a block way in the future (push to backlog)
+        let mut node1 = mock_node(1);
+        node1
+            .on_block_new(mock_block(7))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+        assert_eq!(
+            node1.state.working_block,
+            WorkingBlockOption::NoWorkingBlock
+        );
+        assert_eq!(node1.state.seq_num, 0);
+    }
+
+    /// Make sure that receiving a `BlockValid` update works as expected
+    #[test]
+    fn block_valid() {
+        let mut node = mock_node(0);
+        node.state.phase = PbftPhase::Checking;
+        node.on_block_valid(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node.state.phase == PbftPhase::Committing);
+    }
+
+    /// Make sure that receiving a `BlockCommit` update works as expected
+    #[test]
+    fn block_commit() {
+        let mut node = mock_node(0);
+        node.state.phase = PbftPhase::Finished;
+        node.on_block_commit(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node.state.phase == PbftPhase::NotStarted);
+    }
+
+    /// Make sure that the block after the working block is checked ahead of time once the working
+    /// block only needs to be committed, and that the results of that check don't affect the
+    /// working block
+    #[test]
+    fn check_next_block() {
+        let mut node = mock_node(1);
+        node.state.phase = PbftPhase::Committing;
+        node.state.working_block =
+            WorkingBlockOption::WorkingBlock(pbft_block_from_block(mock_block(2)));
+
+        node.on_block_new(mock_block(3))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node.state.speculative_blocks.contains(&mock_block_id(3)));
+
+        node.on_block_valid(mock_block_id(3))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.phase, PbftPhase::Committing);
+        assert!(node.state.speculative_blocks.is_empty());
+
+        // A block that fails its early check doesn't cause a view change
+        node.state.speculative_blocks.insert(mock_block_id(3));
+        node.on_block_invalid(mock_block_id(3))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.mode, PbftMode::Normal);
+    }
+
+    /// Test the multicast protocol (`PrePrepare` => `Prepare` => `Commit`)
+    #[test]
+    fn multicast_protocol() {
+        let mut node = mock_node(0);
+        let garbage_msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::PrePrepare),
+            content: b"this message will result in an error".to_vec(),
+        };
+        assert!(node.on_peer_message(&garbage_msg).is_err());
+
+        // Make sure BlockNew is in the log
+        let mut node1 = mock_node(1);
+        let block = mock_block(1);
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+
+        // Receive a PrePrepare
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block.clone(), 0);
+        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+        assert_eq!(node1.state.seq_num, 1);
+        if let WorkingBlockOption::WorkingBlock(ref blk) = node1.state.working_block {
+            assert_eq!(BlockId::from(blk.clone().block_id), mock_block_id(1));
+        } else {
+            panic!("Wrong WorkingBlockOption");
+        }
+
+        // Receive 3 `Prepare` messages
+        for peer in 0..3 {
+            assert_eq!(node1.state.phase, PbftPhase::Preparing);
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.phase, PbftPhase::Checking);
+
+        // Spoof the `check_blocks()` call
+        assert!(node1.on_block_valid(mock_block_id(1)).is_ok());
+
+        // Receive 3 `Commit` messages
+        for peer in 0..3 {
+            assert_eq!(node1.state.phase, PbftPhase::Committing);
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+
+        // Spoof the `commit_blocks()` call
+        assert!(node1.on_block_commit(mock_block_id(1)).is_ok());
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+
+        // Make sure the block was actually committed
+        let mut f = File::open(BLOCK_FILE).unwrap();
+        let mut buffer = String::new();
+        f.read_to_string(&mut buffer).unwrap();
+        let deser: Vec<Vec<u8>> = serde_json::from_str(&buffer).unwrap();
+        let blocks: Vec<BlockId> = deser
+            .iter()
+            .filter(|&block| !block.is_empty())
+            .map(|ref block| BlockId::from(block.clone().clone()))
+            .collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1], mock_block_id(1));
+
+        remove_file(BLOCK_FILE).unwrap();
+    }
+
+    /// Make sure that on the fast path, a node holds back its `Commit` until it has `Prepare`s from
+    /// every node, then commits the block right away (still sending its `Commit`), and that it
+    /// falls back to the `Commit` phase if the last `Prepare` doesn't arrive in time
+    #[test]
+    fn fast_path() {
+        timing::start_virtual_time(1);
+        let block_id = Vec::<u8>::from(mock_block_id(2));
+
+        let mut node1 = mock_fast_path_node(1);
+        prepare_block(&mut node1, &[0, 1, 2]);
+        assert_eq!(node1.state.phase, PbftPhase::Checking);
+        node1
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Committing);
+        assert!(node1.state.fast_path_timeout.is_active());
+        assert!(node1
+            .state
+            .sent_votes
+            .latest_votes(&PbftMessageType::Commit, 0)
+            .is_empty());
+
+        node1
+            .on_peer_message(&mock_msg(&PbftMessageType::Prepare, 0, 1, mock_block(2), 3))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        assert!(!node1.state.fast_path_timeout.is_active());
+        assert_eq!(
+            node1
+                .state
+                .sent_votes
+                .latest_votes(&PbftMessageType::Commit, 0),
+            vec![(1, 0, block_id.as_slice())]
+        );
+
+        // Without the last `Prepare`, the `Commit` goes out once the timeout expires, and the
+        // block needs 2f + 1 `Commit`s like any other
+        let mut node2 = mock_fast_path_node(2);
+        prepare_block(&mut node2, &[0, 1, 2]);
+        node2
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        node2.check_fast_path_timeout().unwrap();
+        assert!(node2
+            .state
+            .sent_votes
+            .latest_votes(&PbftMessageType::Commit, 0)
+            .is_empty());
+
+        timing::set_virtual_time(timing::now() + Duration::from_secs(1));
+        node2.check_fast_path_timeout().unwrap();
+        assert_eq!(
+            node2
+                .state
+                .sent_votes
+                .latest_votes(&PbftMessageType::Commit, 0),
+            vec![(1, 0, block_id.as_slice())]
+        );
+        for peer in 0..3 {
+            assert_eq!(node2.state.phase, PbftPhase::Committing);
+            node2
+                .on_peer_message(&mock_msg(
+                    &PbftMessageType::Commit,
+                    0,
+                    1,
+                    mock_block(2),
+                    peer,
+                ))
+                .unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node2.state.phase, PbftPhase::Finished);
+
+        timing::stop_virtual_time();
+    }
+
+    /// Make sure that a block committed on the fast path is re-proposed after a view change, even
+    /// though only the node that committed it has a prepared certificate for it: the nodes that
+    /// sent `Prepare`s for it vouch for it in their `ViewChange`s, and a `NewView` that leaves it
+    /// out is rejected
+    #[test]
+    fn fast_path_view_change() {
+        // Node 2 commits block 2 on the fast path, then drops out
+        let mut node2 = mock_fast_path_node(2);
+        prepare_block(&mut node2, &[0, 1, 2, 3]);
+        node2
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node2.state.phase, PbftPhase::Finished);
+
+        // Nodes 1 and 3 only got their own `Prepare`s and the primary's before the view changed,
+        // so neither is prepared; node 0 is faulty, and doesn't vouch for anything
+        let mut view_changes = vec![];
+        for &id in &[1, 3] {
+            let mut node = mock_fast_path_node(id);
+            prepare_block(&mut node, &[0, id as u64]);
+            assert_eq!(node.state.phase, PbftPhase::Preparing);
+            node.start_view_change(ViewChangeReason::CommitTimeout)
+                .unwrap_or_else(handle_pbft_err);
+            let vc = node.view_change_message();
+            assert!(vc.get_prepared_certificates().is_empty());
+            assert_eq!(vc.get_accepted_pre_prepares().len(), 1);
+            view_changes.push(vc);
+        }
+        let mut faulty_vc = PbftViewChange::new();
+        faulty_vc.set_info(make_msg_info(
+            &PbftMessageType::ViewChange,
+            1,
+            0,
+            mock_peer_id(0),
+        ));
+        view_changes.push(faulty_vc);
+
+        let new_view_msg = |pre_prepares: Vec<PbftMessage>| {
+            let mut new_view = PbftNewView::new();
+            new_view.set_info(make_msg_info(
+                &PbftMessageType::NewView,
+                1,
+                0,
+                mock_peer_id(1),
+            ));
+            new_view.set_view_changes(RepeatedField::from_vec(view_changes.clone()));
+            new_view.set_pre_prepares(RepeatedField::from_vec(pre_prepares));
+            PeerMessage {
+                message_type: String::from(&PbftMessageType::NewView),
+                content: new_view.write_to_bytes().unwrap(),
+            }
+        };
+
+        // A new primary that proposes a different block at sequence number 1 is caught
+        let mut node3 = mock_fast_path_node(3);
+        prepare_block(&mut node3, &[0, 3]);
+        assert!(node3.on_peer_message(&new_view_msg(vec![])).is_err());
+
+        let mut pre_prepare = PbftMessage::new();
+        pre_prepare.set_info(make_msg_info(
+            &PbftMessageType::PrePrepare,
+            1,
+            1,
+            mock_peer_id(1),
+        ));
+        pre_prepare.set_block(pbft_block_from_block(mock_block(2)));
+        node3
+            .on_peer_message(&new_view_msg(vec![pre_prepare]))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node3.state.view, 1);
+        assert_eq!(node3.state.phase, PbftPhase::Preparing);
+        assert_eq!(
+            node3.state.working_block,
+            WorkingBlockOption::WorkingBlock(pbft_block_from_block(mock_block(2)))
+        );
+    }
+
+    /// Make sure that subscribers are told about a block as soon as it's final, with the nodes
+    /// whose `Commit`s made it final, before the validator has committed it
+    #[test]
+    fn finality_events() {
+        let mut node1 = mock_node(1);
+        let block = mock_block(1);
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                block.clone(),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        node1
+            .on_block_valid(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+
+        let record = node1
+            .state
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                ConsensusEvent::BlockFinalized { record } => Some(record.clone()),
+                _ => None,
+            })
+            .next()
+            .expect("No BlockFinalized event");
+        assert_eq!(record.seq_num, 1);
+        assert_eq!(record.block_id, mock_block_id(1));
+        assert_eq!(record.view, 0);
+        assert!(!record.from_seal);
+        let mut signers = record.seal_signers.clone();
+        signers.sort();
+        let mut expected: Vec<PeerId> = (0..3).map(mock_peer_id).collect();
+        expected.sort();
+        assert_eq!(signers, expected);
+        assert!(ConsensusEvent::BlockFinalized { record }
+            .to_json()
+            .contains("\"event\":\"block_finalized\""));
+    }
+
+    /// Make sure that how late each other node's votes arrived is recorded when a block commits,
+    /// so that a slow node can be told apart from the others
+    #[test]
+    fn vote_arrival_lag() {
+        let mut node1 = mock_node(1);
+        let block = mock_block(1);
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                block.clone(),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        node1
+            .on_block_valid(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let lag = |node: &PbftNode| {
+                node.state
+                    .peer_stats
+                    .get(&mock_peer_id(peer))
+                    .map_or(0, |stats| stats.arrival_lag.len())
+            };
+            assert_eq!(lag(&node1), 0);
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+
+        for peer in &[0, 2] {
+            let stats = &node1.state.peer_stats[&mock_peer_id(*peer)];
+            assert_eq!(stats.arrival_lag["Prepare"].count, 1);
+            assert_eq!(stats.arrival_lag["Commit"].count, 1);
+        }
+        // This node's own votes aren't counted against it
+        assert!(node1
+            .state
+            .peer_stats
+            .get(&mock_peer_id(1))
+            .map_or(true, |stats| stats.arrival_lag.is_empty()));
+    }
+
+    /// Make sure that checkpointing works as expected:
+    /// + Node enters Normal mode again after checkpoint
+    /// + A stable checkpoint is created
+    #[test]
+    fn checkpoint() {
+        let mut node1 = mock_node(1);
+        // Pretend that the node just finished block 10
+        node1.state.seq_num = 10;
+        let block = mock_block(10);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert!(node1.msg_log.latest_stable_checkpoint.is_none());
+
+        // Receive 3 `Checkpoint` messages
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Checkpoint, 0, 10, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert!(node1.msg_log.latest_stable_checkpoint.is_some());
+    }
+
+    /// Test that view changes work as expected, and that nodes take the proper roles after a view
+    /// change
+    #[test]
+    fn view_change() {
+        let mut node1 = mock_node(1);
+
+        assert!(!node1.state.is_primary());
+
+        // Receive 3 `ViewChange` messages
+        for peer in 0..3 {
+            // It takes f + 1 `ViewChange` messages to trigger a view change, if it wasn't started
+            // by `start_view_change()`
+            if peer < 2 {
+                assert_eq!(node1.state.mode, PbftMode::Normal);
+            } else {
+                assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+            }
+            let info = make_msg_info(&PbftMessageType::ViewChange, 1, 1, mock_peer_id(peer));
+            let mut vc_msg = PbftViewChange::new();
+            vc_msg.set_info(info);
+            vc_msg.set_checkpoint_messages(RepeatedField::default());
+
+            let msg_bytes = vc_msg.write_to_bytes().unwrap();
+            let msg = PeerMessage {
+                message_type: String::from(&PbftMessageType::ViewChange),
+                content: msg_bytes,
+            };
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+
+        assert!(node1.state.is_primary());
+        assert_eq!(node1.state.view, 1);
+    }
+
+    /// Make sure that a completed view change is queued for the audit trail, with the nodes whose
+    /// votes counted
+    #[test]
+    fn view_change_audit_record() {
+        let mut node1 = mock_node(1);
+        for peer in 0..3 {
+            let mut vc_msg = PbftViewChange::new();
+            vc_msg.set_info(make_msg_info(
+                &PbftMessageType::ViewChange,
+                1,
+                1,
+                mock_peer_id(peer),
+            ));
+            let msg = PeerMessage {
+                message_type: String::from(&PbftMessageType::ViewChange),
+                content: vc_msg.write_to_bytes().unwrap(),
+            };
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.view, 1);
+
+        assert_eq!(node1.state.view_change_records.len(), 1);
+        let record = &node1.state.view_change_records[0];
+        assert_eq!((record.from_view, record.view), (0, 1));
+        assert_eq!(record.reason, Some(ViewChangeReason::OtherNodes));
+        let mut voters: Vec<PeerId> = (0..3).map(mock_peer_id).collect();
+        voters.sort();
+        assert_eq!(record.voters, voters);
+    }
+
+    /// Make sure that a `NewView` for a later view that wasn't sent by that view's primary is
+    /// rejected before the node (voting or not) moves to the view
+    #[test]
+    fn new_view_from_wrong_node() {
+        let mut new_view = PbftNewView::new();
+        new_view.set_info(make_msg_info(
+            &PbftMessageType::NewView,
+            1,
+            0,
+            mock_peer_id(2),
+        ));
+        new_view.set_view_changes(
+            (0..3)
+                .map(|voter| {
+                    let mut vc = PbftViewChange::new();
+                    vc.set_info(make_msg_info(
+                        &PbftMessageType::ViewChange,
+                        1,
+                        0,
+                        mock_peer_id(voter),
+                    ));
+                    vc
+                })
+                .collect(),
+        );
+        let msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::NewView),
+            content: new_view.write_to_bytes().unwrap(),
+        };
+
+        let mut node3 = mock_node(3);
+        assert!(node3.on_peer_message(&msg).is_err());
+        assert_eq!(node3.state.view, 0);
+        assert!(node3.msg_log.view_changes().next().is_none());
+
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let mut observer = PbftNode::new_observer(mock_peer_id(4), &mock_config(4), service)
+            .unwrap_or_else(|err| panic!("{}", err));
+        assert!(observer.on_peer_message(&msg).is_err());
+        assert_eq!(observer.state.view, 0);
+    }
+
+    /// Make sure that a new primary proposes the block that was in progress when the view
+    /// changed, instead of throwing it away and waiting for a new one
+    #[test]
+    fn view_change_keeps_in_flight_block() {
+        let mut node1 = mock_node(1);
+        node1
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                mock_block(2),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+
+        node1
+            .start_view_change(ViewChangeReason::CommitTimeout)
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let mut vc_msg = PbftViewChange::new();
+            vc_msg.set_info(make_msg_info(
+                &PbftMessageType::ViewChange,
+                1,
+                1,
+                mock_peer_id(peer),
+            ));
+            let msg = PeerMessage {
+                message_type: String::from(&PbftMessageType::ViewChange),
+                content: vc_msg.write_to_bytes().unwrap(),
+            };
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+
+        assert!(node1.state.is_primary());
+        assert_eq!(node1.state.seq_num, 1);
+        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
+        assert_eq!(
+            node1.state.working_block,
+            WorkingBlockOption::TentativeWorkingBlock(mock_block_id(2))
+        );
+        assert_eq!(
+            node1
+                .msg_log
+                .get_messages_of_type(&PbftMessageType::BlockNew, 1, 1)
+                .len(),
+            1
+        );
+    }
+
+    /// Make sure that a block conflicting with one that arrived earlier is recorded, that the
+    /// primary keeps the first one, and that a secondary can accept a `PrePrepare` for either
+    #[test]
+    fn conflicting_blocks() {
+        let mut conflicting = mock_block(2);
+        conflicting.block_id = mock_block_id(20);
+
+        let mut node0 = mock_node(0);
+        node0
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        node0
+            .on_block_new(conflicting.clone())
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.msg_log.get_block_conflicts().len(), 1);
+        assert_eq!(node0.state.seq_num, 1);
+        assert_eq!(
+            node0.state.working_block,
+            WorkingBlockOption::TentativeWorkingBlock(mock_block_id(2))
+        );
+
+        let mut node1 = mock_node(1);
+        node1
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_block_new(conflicting.clone())
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.msg_log.get_block_conflicts().len(), 1);
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                conflicting.clone(),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+        assert_eq!(
+            node1.state.working_block,
+            WorkingBlockOption::WorkingBlock(pbft_block_from_block(conflicting))
+        );
+    }
+
+    /// Make sure that view changes start correctly
+    #[test]
+    fn start_view_change() {
+        let mut node1 = mock_node(1);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+
+        node1
+            .start_view_change(ViewChangeReason::CommitTimeout)
+            .unwrap_or_else(handle_pbft_err);
+
+        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+    }
+
+    /// Make sure that a node that gets messages for the sequence number after the one it's missing
+    /// messages for asks for those messages again, and only catches up from seals if they don't
+    /// arrive in time
+    #[test]
+    fn retransmission_requests() {
+        timing::start_virtual_time(1);
+        let mut node1 = mock_node(1);
+        // Seals are only asked for if they can be checked
+        node1.state.authenticate_messages = true;
+
+        // The primary's PrePrepare arrived before its block, so it isn't missing
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                mock_block(1),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node1.msg_log.has_message_from(
+            &PbftMessageType::PrePrepare,
+            1,
+            0,
+            &mock_peer_id(0)
+        ));
+        assert!(!node1
+            .msg_log
+            .has_message_from(&PbftMessageType::Prepare, 1, 0, &mock_peer_id(0)));
+
+        node1
+            .on_peer_message(&mock_msg(&PbftMessageType::Commit, 0, 2, mock_block(2), 2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.retransmit_seq_num, 1);
+        assert!(!node1.state.state_request_timeout.is_active());
+
+        // The missing messages didn't arrive in time
+        timing::set_virtual_time(timing::now() + Duration::from_secs(1));
+        node1
+            .on_peer_message(&mock_msg(&PbftMessageType::Commit, 0, 2, mock_block(2), 3))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node1.state.state_request_timeout.is_active());
+
+        timing::stop_virtual_time();
+    }
+
+    /// Make sure that a node sends its own messages for a block again once, when the block is
+    /// about to time out with some but not all of the messages it needs
+    #[test]
+    fn rebroadcast_if_stalled() {
+        timing::start_virtual_time(1);
+        let mut node1 = mock_node(1);
+        node1.state.seq_num = 1;
+        node1.state.phase = PbftPhase::Preparing;
+        node1.state.timeout.start();
+        for from in 1..3 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, mock_block(1), from);
+            node1
+                .msg_log
+                .add_message(protobuf::parse_from_bytes(&msg.content).unwrap());
+        }
+
+        // Not close enough to timing out yet
+        node1
+            .rebroadcast_if_stalled()
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.last_rebroadcast, None);
+
+        let duration = node1.state.timeout.duration();
+        timing::set_virtual_time(timing::now() + duration * 4 / 5);
+        node1
+            .rebroadcast_if_stalled()
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.last_rebroadcast, Some((0, 1)));
+
+        // The missing `Prepare` is asked for as well
+        assert_eq!(node1.state.retransmit_seq_num, 1);
+        assert!(node1.state.retransmit_timeout.is_active());
+
+        // With a quorum, the block isn't stalled
+        node1.state.last_rebroadcast = None;
+        let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, mock_block(1), 3);
+        node1
+            .msg_log
+            .add_message(protobuf::parse_from_bytes(&msg.content).unwrap());
+        node1
+            .rebroadcast_if_stalled()
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.last_rebroadcast, None);
+
+        timing::stop_virtual_time();
+    }
+
+    /// Make sure that a node that changed views before it got the `Commit`s for a block, which the
+    /// other nodes committed in the earlier view, commits the block from them once they arrive
+    #[test]
+    fn commit_from_earlier_view() {
+        let mut node1 = mock_node(1);
+        node1
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        node1.state.view = 1;
+
+        for &from in &[0, 2, 3] {
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, mock_block(2), from);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert!(node1.msg_log.has_seal(&Vec::<u8>::from(mock_block_id(2))));
+
+        node1
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+    }
+
+    /// Make sure that a node that's changing views on its own still commits the blocks that the
+    /// other nodes commit in the view it's leaving, without voting on them
+    #[test]
+    fn commit_while_view_changing() {
+        let mut node1 = mock_node(1);
+        node1
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        node1.state.mode = PbftMode::ViewChanging;
+
+        for &from in &[0, 2, 3] {
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, mock_block(2), from);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert!(node1.msg_log.has_seal(&Vec::<u8>::from(mock_block_id(2))));
+        assert!(!node1
+            .msg_log
+            .has_message_from(&PbftMessageType::Commit, 1, 0, &mock_peer_id(1)));
+
+        node1
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+    }
+
+    /// Make sure that a node that restarts in the middle of a view change resumes it with the
+    /// same target view and the `ViewChange`s it had collected
+    #[test]
+    fn restore_view_change_progress() {
+        let dir = ::std::env::temp_dir().join("pbft-restore-view-change-test");
+        let _ = ::std::fs::remove_dir_all(&dir);
+
+        let mut node1 = mock_node(1);
+        node1.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        handlers::enter_view(&mut node1.state, &mut node1.msg_log, &mut *node1.service, 1)
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .start_view_change(ViewChangeReason::CommitTimeout)
+            .unwrap_or_else(handle_pbft_err);
+        node1.state.view_change_backoff.fail();
+        let target = node1.view_change_target();
+        assert_eq!(target, 3);
+
+        let mut vc = PbftViewChange::new();
+        vc.set_info(make_msg_info(
+            &PbftMessageType::ViewChange,
+            target,
+            0,
+            mock_peer_id(2),
+        ));
+        node1.msg_log.add_view_change(vc.clone());
+        node1.save_view_change_progress();
+
+        // The restarted node starts over in view 0, until it restores its progress
+        let mut restarted = mock_node(1);
+        restarted.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        restarted
+            .restore_view_change_progress()
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(restarted.state.view, 1);
+        assert_eq!(restarted.state.mode, PbftMode::ViewChanging);
+        assert_eq!(restarted.view_change_target(), target);
+        assert!(restarted.msg_log.view_changes().any(|logged| logged == &vc));
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Make sure that a primary in maintenance mode hands off, and that a node in maintenance mode
+    /// keeps its place in the network but shows that it's in maintenance
+    #[test]
+    fn maintenance_mode() {
+        let mut node0 = mock_node(0);
+        assert!(node0.state.is_primary());
+        node0.set_maintenance(true).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.maintenance_handoff, Some(0));
+
+        // Trying to publish hands off instead, if it hasn't handed off in this view yet
+        node0.state.maintenance_handoff = Some(1);
+        node0.try_publish().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.maintenance_handoff, Some(0));
+        node0.try_publish().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.maintenance_handoff, Some(0));
+        node0.set_maintenance(false).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.maintenance_handoff, None);
+
+        let mut node1 = mock_node(1);
+        node1.set_maintenance(true).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert!(NodeStatus::new(&node1.state)
+            .problems
+            .contains(&String::from("in maintenance mode; not publishing blocks")));
+        node1.set_maintenance(false).unwrap_or_else(handle_pbft_err);
+        assert!(!node1.state.maintenance);
+    }
+
+    /// Make sure that catching up on an operator's request asks the node that's furthest along,
+    /// even if an earlier request is still outstanding
+    #[test]
+    fn force_catch_up() {
+        let mut node1 = mock_node(1);
+        // Seals are only asked for if they can be checked
+        node1.state.authenticate_messages = true;
+        assert_eq!(node1.force_catch_up().unwrap(), None);
+
+        for (peer, seq_num) in [(0, 3), (2, 7), (3, 5)].iter() {
+            let mut stats = PeerStats::default();
+            stats.record_seq_num(*seq_num);
+            node1.state.peer_stats.insert(mock_peer_id(*peer), stats);
+        }
+        node1.state.state_request_timeout.start();
+        assert_eq!(node1.force_catch_up().unwrap(), Some(7));
+        assert_eq!(node1.state.state_request_peer, Some(mock_peer_id(2)));
+    }
+
+    /// Make sure that a node that was alone in its network goes through consensus once other
+    /// nodes join, so a block isn't committed until it has `Commit`s from 2f + 1 nodes
+    #[test]
+    fn grow_from_single_node() {
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let mut node0: PbftNode = PbftNode::new(0, &mock_config(1), service);
+        assert!(node0.state.single_node);
+
+        node0
+            .state
+            .set_peers(mock_config(4).peers)
+            .unwrap_or_else(handle_pbft_err);
+        assert!(!node0.state.single_node);
+        assert_eq!((node0.state.f, node0.state.quorum()), (1, 3));
+
+        prepare_block(&mut node0, &[0, 1, 2]);
+        node0
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.phase, PbftPhase::Committing);
+
+        for peer in 0..2 {
+            node0
+                .on_peer_message(&mock_msg(
+                    &PbftMessageType::Commit,
+                    0,
+                    1,
+                    mock_block(2),
+                    peer,
+                ))
+                .unwrap_or_else(handle_pbft_err);
+            assert_eq!(node0.state.phase, PbftPhase::Committing);
+        }
+        node0
+            .on_peer_message(&mock_msg(&PbftMessageType::Commit, 0, 1, mock_block(2), 2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.phase, PbftPhase::Finished);
+    }
+
+    /// Make sure that a message too far ahead to be handled still shows a node that it has fallen
+    /// behind
+    #[test]
+    fn catch_up_from_dropped_message() {
+        let mut node1 = mock_node(1);
+        // Seals are only asked for if they can be checked
+        node1.state.authenticate_messages = true;
+        let far_ahead = node1.msg_log.get_high_water_mark() + 1;
+        node1
+            .on_network_message(
+                &mock_msg(&PbftMessageType::Commit, 0, far_ahead, mock_block(2), 2),
+                &mock_peer_id(2),
+            )
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.rejected_messages[&mock_peer_id(2)].ahead, 1);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+
+        // A second node that's even further ahead vouches for the first one's position
+        node1
+            .on_network_message(
+                &mock_msg(&PbftMessageType::Commit, 0, far_ahead + 5, mock_block(2), 3),
+                &mock_peer_id(3),
+            )
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::Recovering);
+        assert_eq!(node1.state.recovery_target, far_ahead - 1);
+    }
+
+    /// Make sure that a node that falls past the high water mark while messages aren't
+    /// authenticated doesn't start recovering, since it couldn't get any seals it could trust, but
+    /// carries on with consensus instead
+    #[test]
+    fn catch_up_without_authentication() {
+        let mut node1 = mock_node(1);
+        assert!(!node1.state.authenticate_messages);
+        let far_ahead = node1.msg_log.get_high_water_mark() + 1;
+        for peer in 2..4 {
+            node1
+                .on_network_message(
+                    &mock_msg(&PbftMessageType::Commit, 0, far_ahead, mock_block(2), peer),
+                    &mock_peer_id(peer),
+                )
+                .unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert_eq!(node1.state.recovery_target, 0);
+        assert!(!node1.state.events.iter().any(|event| match event {
+            ConsensusEvent::CatchUpStarted { .. } => true,
+            _ => false,
+        }));
+
+        // The next block still goes through consensus
+        let block = mock_block(1);
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                block.clone(),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        node1
+            .on_block_valid(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, block.clone(), peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        node1
+            .on_block_commit(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.seq_num, 1);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+    }
+
+    /// Make sure that one node claiming to be far ahead doesn't start recovery, no matter how many
+    /// messages it sends
+    #[test]
+    fn recovery_needs_f_plus_one_nodes() {
+        let mut node1 = mock_node(1);
+        node1.state.authenticate_messages = true;
+        let high_water_mark = node1.msg_log.get_high_water_mark();
+        for seq_num in high_water_mark..high_water_mark + 3 {
+            node1
+                .on_network_message(
+                    &mock_msg(&PbftMessageType::Commit, 0, seq_num, mock_block(2), 2),
+                    &mock_peer_id(2),
+                )
+                .unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert_eq!(node1.state.recovery_target, 0);
+    }
+
+    /// Make sure that a verified seal past the high water mark starts recovery on its own, since
+    /// it proves that the network got that far
+    #[test]
+    fn recovery_from_verified_seal() {
+        let mut node1 = mock_signed_node(1);
+        let high_water_mark = node1.msg_log.get_high_water_mark();
+
+        let mut response = PbftStateResponse::new();
+        response.set_info(make_msg_info(
+            &PbftMessageType::StateResponse,
+            0,
+            high_water_mark,
+            mock_signer(0).get_signer_id(),
+        ));
+        response
+            .mut_seals()
+            .push(mock_signed_seal(high_water_mark, true));
+        let msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::StateResponse),
+            content: response.write_to_bytes().unwrap(),
+        };
+        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::Recovering);
+        assert_eq!(node1.state.recovery_target, high_water_mark);
+    }
+
+    /// Make sure that a recovering node only asks connected nodes for seals, and asks another node
+    /// right away if the one it asked disconnects
+    #[test]
+    fn recovery_follows_connections() {
+        let mut node1 = mock_node(1);
+        // Seals are only asked for if they can be checked
+        node1.state.authenticate_messages = true;
+        node1.state.mode = PbftMode::Recovering;
+        node1.state.recovery_target = 100;
+        node1
+            .on_peer_disconnected(mock_peer_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        node1.continue_recovery().unwrap_or_else(handle_pbft_err);
+        assert!(node1.state.state_request_timeout.is_active());
+        assert_eq!(node1.state.state_request_peer, Some(mock_peer_id(3)));
+
+        // Another node disconnecting doesn't matter
+        node1
+            .on_peer_disconnected(mock_peer_id(0))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.state_request_peer, Some(mock_peer_id(3)));
+
+        // The node that was asked won't answer; the only connected one left is asked instead
+        node1
+            .on_peer_connected(mock_peer_id(0))
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_disconnected(mock_peer_id(3))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node1.state.state_request_timeout.is_active());
+        assert_eq!(node1.state.state_request_peer, Some(mock_peer_id(0)));
+    }
+
+    /// Make sure that a node whose saved progress is corrupt starts from the chain head instead,
+    /// catching up from seals rather than voting for the block after it, and that the incident is
+    /// audited
+    #[test]
+    fn rebuild_state_from_corrupt_progress() {
+        let dir = ::std::env::temp_dir().join("pbft-rebuild-state-test");
+        let _ = ::std::fs::remove_dir_all(&dir);
+        ::std::fs::create_dir_all(&dir).unwrap();
+        ::std::fs::write(dir.join("pbft-view-change-progress"), b"\xffcorrupt").unwrap();
+
+        let mut node1 = mock_node(1);
+        node1.state.authenticate_messages = true;
+        node1.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        node1
+            .restore_view_change_progress()
+            .unwrap_or_else(handle_pbft_err);
+
+        let head_num = node1.state.chain_head_num;
+        assert_eq!(node1.state.view, 0);
+        assert_eq!(node1.state.mode, PbftMode::Recovering);
+        assert_eq!(node1.state.recovery_target, head_num + 1);
+        assert_eq!(node1.state.seq_num, head_num);
+        assert!(dir.join("pbft-view-change-progress.unusable").exists());
+        assert!(!dir.join("pbft-view-change-progress").exists());
+        assert!(
+            ::std::fs::read_to_string(dir.join("pbft-view-change-audit"))
+                .unwrap()
+                .contains("\"incident\":\"state_rebuilt\"")
+        );
+
+        // Progress saved from here on is usable again
+        node1.save_view_change_progress();
+        let mut restarted = mock_node(1);
+        restarted.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        restarted
+            .restore_view_change_progress()
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(restarted.state.mode, PbftMode::Normal);
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Make sure that a node whose saved progress is corrupt while messages aren't authenticated
+    /// carries on from the chain head in normal operation, rather than start recovering from seals
+    /// it couldn't get
+    #[test]
+    fn rebuild_state_without_authentication() {
+        let dir = ::std::env::temp_dir().join("pbft-rebuild-state-no-auth-test");
+        let _ = ::std::fs::remove_dir_all(&dir);
+        ::std::fs::create_dir_all(&dir).unwrap();
+        ::std::fs::write(dir.join("pbft-view-change-progress"), b"\xffcorrupt").unwrap();
+
+        let mut node1 = mock_node(1);
+        assert!(!node1.state.authenticate_messages);
+        node1.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        node1
+            .restore_view_change_progress()
+            .unwrap_or_else(handle_pbft_err);
+
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert_eq!(node1.state.recovery_target, 0);
+        assert_eq!(node1.state.seq_num, mock_node(1).state.seq_num);
+        assert!(!node1.state.state_request_timeout.is_active());
+        assert!(!node1.state.events.iter().any(|event| match event {
+            ConsensusEvent::CatchUpStarted { .. } => true,
+            _ => false,
+        }));
+        assert!(dir.join("pbft-view-change-progress.unusable").exists());
+
+        // The next block still goes through consensus
+        let block = mock_block(1);
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_message(&mock_msg(&PbftMessageType::PrePrepare, 0, 1, block, 0))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
 
-        let content = pbft_msg.write_to_bytes().expect("SerializationError");
-        PeerMessage {
-            message_type: String::from(msg_type),
-            content,
-        }
+        ::std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn handle_pbft_err(e: PbftError) {
-        match e {
-            PbftError::Timeout => (),
-            PbftError::WrongNumMessages(_, _, _) | PbftError::NotReadyForMessage => {
-                println!("{}", e)
-            }
-            _ => panic!("{}", e),
-        }
+    /// Make sure that a node's votes are saved before they're sent, so that after a crash it
+    /// refuses to vote for a different block in the same view and sequence number
+    #[test]
+    fn sent_votes_survive_restart() {
+        let dir = ::std::env::temp_dir().join("pbft-sent-votes-test");
+        let _ = ::std::fs::remove_dir_all(&dir);
+
+        let mut node1 = mock_node(1);
+        node1.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        node1.state.seq_num = 1;
+        node1.state.phase = PbftPhase::Preparing;
+        node1
+            ._broadcast_pbft_message(
+                1,
+                &PbftMessageType::Prepare,
+                handlers::pbft_block_from_block(mock_block(1)),
+            )
+            .unwrap_or_else(handle_pbft_err);
+
+        // Crashing before the end of the update means nothing else was saved
+        let mut restarted = mock_node(1);
+        restarted.storage = Some(ViewChangeStorage::new(&dir).unwrap());
+        restarted
+            .restore_view_change_progress()
+            .unwrap_or_else(handle_pbft_err);
+        restarted.state.seq_num = 1;
+        restarted.state.phase = PbftPhase::Preparing;
+        assert!(restarted
+            ._broadcast_pbft_message(
+                1,
+                &PbftMessageType::Prepare,
+                handlers::pbft_block_from_block(mock_block(2))
+            )
+            .is_err());
+        restarted
+            ._broadcast_pbft_message(
+                1,
+                &PbftMessageType::Prepare,
+                handlers::pbft_block_from_block(mock_block(1)),
+            )
+            .unwrap_or_else(handle_pbft_err);
+
+        // Once the block is committed, its votes are forgotten
+        restarted.state.seq_num = 2;
+        restarted.save_view_change_progress();
+        assert!(restarted.state.sent_votes.to_protos().is_empty());
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    /// Make sure that receiving a `BlockNew` update works as expected
+    /// Make sure that a node that missed blocks commits them from seals in order, without going
+    /// through consensus for them, even if the validator finds them valid out of order
     #[test]
-    fn block_new() {
-        // NOTE: Special case for primary node
-        let mut node0 = mock_node(0);
-        node0
-            .on_block_new(mock_block(1))
+    fn commit_from_seals() {
+        let mut node1 = mock_signed_node(1);
+
+        // Block 3 arrives before this node knows it missed anything
+        node1
+            .on_block_new(mock_block(3))
             .unwrap_or_else(handle_pbft_err);
-        assert_eq!(node0.state.phase, PbftPhase::PrePreparing);
-        assert_eq!(node0.state.seq_num, 1);
-        assert_eq!(
-            node0.state.working_block,
-            WorkingBlockOption::TentativeWorkingBlock(mock_block_id(1))
-        );
+        assert_eq!(node1.msg_log.block_backlog().count(), 1);
 
-        // Try the next block
-        let mut node1 = mock_node(1);
+        let mut response = PbftStateResponse::new();
+        response.set_info(make_msg_info(
+            &PbftMessageType::StateResponse,
+            0,
+            2,
+            mock_signer(0).get_signer_id(),
+        ));
+        for seq_num in 1..3 {
+            response.mut_seals().push(mock_signed_seal(seq_num, true));
+        }
+        let msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::StateResponse),
+            content: response.write_to_bytes().unwrap(),
+        };
+        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.msg_log.block_backlog().count(), 0);
+
+        // Block 3 can't be committed before block 2
         node1
-            .on_block_new(mock_block(1))
+            .on_block_valid(mock_block_id(3))
             .unwrap_or_else(handle_pbft_err);
-        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
-        assert_eq!(
-            node1.state.working_block,
-            WorkingBlockOption::TentativeWorkingBlock(mock_block_id(1))
-        );
-        assert_eq!(node1.state.seq_num, 0);
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
 
-        // Try a block way in the future (push to backlog)
-        let mut node1 = mock_node(1);
         node1
-            .on_block_new(mock_block(7))
+            .on_block_new(mock_block(2))
             .unwrap_or_else(handle_pbft_err);
         assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+        node1
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        assert_eq!(node1.state.seq_num, 1);
+
+        node1
+            .on_block_commit(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        assert_eq!(node1.state.seq_num, 2);
+        assert!(node1.msg_log.get_seal(1, node1.state.quorum()).is_some());
         assert_eq!(
-            node1.state.working_block,
-            WorkingBlockOption::NoWorkingBlock
+            node1
+                .msg_log
+                .get_seal(2, node1.state.quorum())
+                .map(|seal| seal.get_signed_commit_messages().len()),
+            Some(3)
         );
-        assert_eq!(node1.state.seq_num, 0);
     }
 
-    /// Make sure that receiving a `BlockValid` update works as expected
+    /// Make sure that blocks aren't committed from a peer's seals that can't be checked: ones
+    /// whose `Commit`s aren't signed, or any seals if messages aren't authenticated at all
     #[test]
-    fn block_valid() {
-        let mut node = mock_node(0);
-        node.state.phase = PbftPhase::Checking;
-        node.on_block_valid(mock_block_id(1))
+    fn unsigned_seals() {
+        let response = |signer_id: PeerId, signed: bool| {
+            let mut response = PbftStateResponse::new();
+            response.set_info(make_msg_info(
+                &PbftMessageType::StateResponse,
+                0,
+                1,
+                signer_id,
+            ));
+            response.mut_seals().push(mock_signed_seal(1, signed));
+            PeerMessage {
+                message_type: String::from(&PbftMessageType::StateResponse),
+                content: response.write_to_bytes().unwrap(),
+            }
+        };
+
+        let mut node1 = mock_signed_node(1);
+        node1
+            .on_peer_message(&response(mock_signer(0).get_signer_id(), false))
             .unwrap_or_else(handle_pbft_err);
-        assert!(node.state.phase == PbftPhase::Committing);
-    }
+        assert!(!node1.msg_log.has_seal(&Vec::<u8>::from(mock_block_id(2))));
 
-    /// Make sure that receiving a `BlockCommit` update works as expected
-    #[test]
-    fn block_commit() {
-        let mut node = mock_node(0);
-        node.state.phase = PbftPhase::Finished;
-        node.on_block_commit(mock_block_id(1))
+        // The same seal would be valid if messages weren't authenticated, but any node could have
+        // made up its Commits then
+        let mut cfg = mock_config(4);
+        cfg.peers = (0..4).map(|i| mock_signer(i).get_signer_id()).collect();
+        let mut node2 = PbftNode::new(
+            2,
+            &cfg,
+            Box::new(MockService {
+                chain: vec![mock_block_id(0)],
+            }),
+        );
+        node2
+            .on_peer_message(&response(mock_signer(0).get_signer_id(), false))
             .unwrap_or_else(handle_pbft_err);
-        assert!(node.state.phase == PbftPhase::NotStarted);
+        assert!(!node2.msg_log.has_seal(&Vec::<u8>::from(mock_block_id(2))));
+        assert!(handlers::verify_seal(&node2.state, &mock_signed_seal(1, false)).is_ok());
     }
 
-    /// Test the multicast protocol (`PrePrepare` => `Prepare` => `Commit`)
+    /// Make sure that a run of seals that fail verification is reported once, as an incident, and
+    /// that a valid seal ends it
     #[test]
-    fn multicast_protocol() {
-        let mut node = mock_node(0);
-        let garbage_msg = PeerMessage {
-            message_type: String::from(&PbftMessageType::PrePrepare),
-            content: b"this message will result in an error".to_vec(),
+    fn invalid_seals_incident() {
+        let mut node1 = mock_signed_node(1);
+        let mut response = PbftStateResponse::new();
+        response.set_info(make_msg_info(
+            &PbftMessageType::StateResponse,
+            0,
+            1,
+            mock_signer(0).get_signer_id(),
+        ));
+        for seq_num in 1..=u64::from(INVALID_SEALS_BEFORE_INCIDENT) {
+            let mut seal = PbftSeal::new();
+            seal.set_seq_num(seq_num);
+            seal.set_block(pbft_block_from_block(mock_block(seq_num + 1)));
+            response.mut_seals().push(seal);
+        }
+        let msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::StateResponse),
+            content: response.write_to_bytes().unwrap(),
         };
-        assert!(node.on_peer_message(&garbage_msg).is_err());
+        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert!(node1.state.incidents.is_ongoing(Incident::InvalidSeals));
+        let started = |event: &ConsensusEvent| match event {
+            ConsensusEvent::IncidentStarted { incident, .. } => *incident == Incident::InvalidSeals,
+            _ => false,
+        };
+        assert_eq!(node1.state.events.iter().filter(|e| started(e)).count(), 1);
 
-        // Make sure BlockNew is in the log
+        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.events.iter().filter(|e| started(e)).count(), 1);
+
+        node1.seal_verified();
+        assert!(!node1.state.incidents.is_ongoing(Incident::InvalidSeals));
+        assert_eq!(
+            node1.state.events.last(),
+            Some(&ConsensusEvent::IncidentEnded {
+                incident: Incident::InvalidSeals
+            })
+        );
+    }
+
+    /// Make sure that a node whose validator's chain head moved without it rebuilds its state and
+    /// log, keeping up with the blocks that were committed in the meantime
+    #[test]
+    fn resync() {
         let mut node1 = mock_node(1);
-        let block = mock_block(1);
+        node1.check_chain_head().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.chain_head, mock_block_id(0));
+
+        // Settings that can't be loaded (the mock service has none) leave the node as it was,
+        // rather than stopping it
+        let seq_num = node1.state.seq_num;
+        node1.service.commit_block(mock_block_id(1)).unwrap();
+        assert!(node1.check_chain_head().is_err());
+        assert_eq!(node1.state.chain_head, mock_block_id(0));
+        assert_eq!(node1.state.seq_num, seq_num);
+
+        node1.state.seq_num = 3;
+        let mut prepare = PbftMessage::new();
+        prepare.set_info(make_msg_info(
+            &PbftMessageType::Prepare,
+            0,
+            4,
+            mock_peer_id(0),
+        ));
+        node1.msg_log.add_message(prepare);
+
+        // The validator commits two blocks that this node doesn't hear about
+        node1.service.commit_block(mock_block_id(2)).unwrap();
+        let head = node1.service.get_chain_head().unwrap();
         node1
-            .on_block_new(block.clone())
+            .resync(head, &mock_config(4))
             .unwrap_or_else(handle_pbft_err);
 
-        // Receive a PrePrepare
-        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block.clone(), 0);
-        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.seq_num, 5);
+        assert_eq!(node1.state.chain_head, mock_block_id(2));
+        assert_eq!(node1.state.chain_head_num, 3);
+        assert_eq!(node1.msg_log.messages().count(), 0);
+        assert!(!node1.state.is_primary());
+    }
 
-        assert_eq!(node1.state.phase, PbftPhase::Preparing);
-        assert_eq!(node1.state.seq_num, 1);
-        if let WorkingBlockOption::WorkingBlock(ref blk) = node1.state.working_block {
-            assert_eq!(BlockId::from(blk.clone().block_id), mock_block_id(1));
-        } else {
-            panic!("Wrong WorkingBlockOption");
-        }
+    /// Make sure that a node that was removed from the network stops voting and taking part in
+    /// view changes, but keeps committing the blocks that the remaining members agree on
+    #[test]
+    fn follow_chain_after_removal() {
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let mut node1 = PbftNode::new(1, &mock_config(5), service);
+        let members: Vec<PeerId> = [0, 2, 3, 4].iter().map(|&i| mock_peer_id(i)).collect();
+        node1.leave_network(members).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::NonVoting);
+        assert!(!node1.state.is_primary());
 
-        // Receive 3 `Prepare` messages
-        for peer in 0..3 {
-            assert_eq!(node1.state.phase, PbftPhase::Preparing);
-            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block.clone(), peer);
-            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
-        }
-        assert_eq!(node1.state.phase, PbftPhase::Checking);
+        node1
+            .start_view_change(ViewChangeReason::CommitTimeout)
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::NonVoting);
 
-        // Spoof the `check_blocks()` call
-        assert!(node1.on_block_valid(mock_block_id(1)).is_ok());
+        node1
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.msg_log.block_backlog().count(), 1);
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
 
-        // Receive 3 `Commit` messages
-        for peer in 0..3 {
-            assert_eq!(node1.state.phase, PbftPhase::Committing);
-            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, block.clone(), peer);
-            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        // Once a quorum of the members have committed the block, it's checked and committed
+        let prepare = mock_msg(&PbftMessageType::Prepare, 0, 1, mock_block(2), 0);
+        node1
+            .on_peer_message(&prepare)
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.msg_log.messages().count(), 0);
+        for (i, &member) in [0, 2, 3].iter().enumerate() {
+            let commit = mock_msg(&PbftMessageType::Commit, 0, 1, mock_block(2), member);
+            node1
+                .on_peer_message(&commit)
+                .unwrap_or_else(handle_pbft_err);
+            assert_eq!(
+                node1.msg_log.has_seal(&Vec::<u8>::from(mock_block_id(2))),
+                i == 2
+            );
         }
-        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        assert_eq!(node1.msg_log.block_backlog().count(), 0);
 
-        // Spoof the `commit_blocks()` call
-        assert!(node1.on_block_commit(mock_block_id(1)).is_ok());
+        node1
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Finished);
+        assert_eq!(node1.state.seq_num, 1);
+        node1
+            .on_block_commit(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
         assert_eq!(node1.state.phase, PbftPhase::NotStarted);
-
-        // Make sure the block was actually committed
-        let mut f = File::open(BLOCK_FILE).unwrap();
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer).unwrap();
-        let deser: Vec<Vec<u8>> = serde_json::from_str(&buffer).unwrap();
-        let blocks: Vec<BlockId> = deser
-            .iter()
-            .filter(|&block| !block.is_empty())
-            .map(|ref block| BlockId::from(block.clone().clone()))
-            .collect();
-        assert_eq!(blocks.len(), 2);
-        assert_eq!(blocks[1], mock_block_id(1));
-
-        remove_file(BLOCK_FILE).unwrap();
+        assert_eq!(node1.state.mode, PbftMode::NonVoting);
+        assert_eq!(node1.state.chain_head, mock_block_id(2));
     }
 
-    /// Make sure that checkpointing works as expected:
-    /// + Node enters Normal mode again after checkpoint
-    /// + A stable checkpoint is created
+    /// Make sure that a node that isn't in the membership starts out following the network without
+    /// voting: it commits the blocks the members seal and follows them into new views, so it's
+    /// caught up as soon as it's added
     #[test]
-    fn checkpoint() {
-        let mut node1 = mock_node(1);
-        // Pretend that the node just finished block 10
-        node1.state.seq_num = 10;
-        let block = mock_block(10);
-        assert_eq!(node1.state.mode, PbftMode::Normal);
-        assert!(node1.msg_log.latest_stable_checkpoint.is_none());
+    fn observer() {
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let cfg = mock_config(4);
+        let mut observer = PbftNode::new_observer(mock_peer_id(4), &cfg, service)
+            .unwrap_or_else(|err| panic!("{}", err));
+        assert_eq!(observer.state.mode, PbftMode::NonVoting);
+        assert_eq!(observer.state.get_own_peer_id(), mock_peer_id(4));
+        assert_eq!(observer.state.id, 4);
+        assert!(!observer.state.is_primary());
 
-        // Receive 3 `Checkpoint` messages
-        for peer in 0..3 {
-            let msg = mock_msg(&PbftMessageType::Checkpoint, 0, 10, block.clone(), peer);
-            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        // There's no following a network of one node, since it doesn't send any messages
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        assert!(PbftNode::new_observer(mock_peer_id(4), &mock_config(1), service).is_err());
+
+        observer
+            .on_block_new(mock_block(2))
+            .unwrap_or_else(handle_pbft_err);
+        for &member in &[0, 1, 2] {
+            let commit = mock_msg(&PbftMessageType::Commit, 0, 1, mock_block(2), member);
+            observer
+                .on_peer_message(&commit)
+                .unwrap_or_else(handle_pbft_err);
         }
+        observer
+            .on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        observer
+            .on_block_commit(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(observer.state.seq_num, 1);
+        assert_eq!(observer.state.chain_head, mock_block_id(2));
 
-        assert_eq!(node1.state.mode, PbftMode::Normal);
-        assert!(node1.msg_log.latest_stable_checkpoint.is_some());
+        // A `NewView` without enough `ViewChange`s doesn't move it; one with a quorum does
+        let new_view = |voters: &[u64]| {
+            let mut new_view = PbftNewView::new();
+            new_view.set_info(make_msg_info(
+                &PbftMessageType::NewView,
+                1,
+                1,
+                mock_peer_id(1),
+            ));
+            new_view.set_view_changes(
+                voters
+                    .iter()
+                    .map(|&voter| {
+                        let mut vc = PbftViewChange::new();
+                        vc.set_info(make_msg_info(
+                            &PbftMessageType::ViewChange,
+                            1,
+                            1,
+                            mock_peer_id(voter),
+                        ));
+                        vc
+                    })
+                    .collect(),
+            );
+            PeerMessage {
+                message_type: String::from(&PbftMessageType::NewView),
+                content: new_view.write_to_bytes().unwrap(),
+            }
+        };
+        assert!(observer.on_peer_message(&new_view(&[1, 2])).is_err());
+        assert_eq!(observer.state.view, 0);
+        observer
+            .on_peer_message(&new_view(&[1, 2, 3]))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(observer.state.view, 1);
+        assert_eq!(observer.state.mode, PbftMode::NonVoting);
+        assert_eq!(observer.state.view_change_records.len(), 1);
+
+        // Once it's added, it's in the members' view and at their sequence number right away
+        let mut peers = cfg.peers.clone();
+        peers.push(mock_peer_id(4));
+        observer.state.set_peers(peers).unwrap();
+        assert_eq!(observer.state.id, 4);
+        assert_eq!(observer.state.view, 1);
+        assert_eq!(observer.state.seq_num, 1);
     }
 
-    /// Test that view changes work as expected, and that nodes take the proper roles after a view
-    /// change
+    /// Make sure that, with `min_block_interval` set, the primary publishes once the interval has
+    /// passed, and then waits for the next block to be committed
     #[test]
-    fn view_change() {
-        let mut node1 = mock_node(1);
+    fn publish_early() {
+        let service: Box<MockService> = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+        });
+        let mut cfg = mock_config(4);
+        cfg.min_block_interval = Some(Duration::from_millis(0));
+        let mut node0 = PbftNode::new(0, &cfg, service);
+        assert!(node0.state.is_primary());
 
-        assert!(!node1.state.is_primary());
+        ::std::thread::sleep(Duration::from_millis(1));
+        assert!(node0
+            .state
+            .block_interval_timeout
+            .as_mut()
+            .unwrap()
+            .check_expired());
 
-        // Receive 3 `ViewChange` messages
-        for peer in 0..3 {
-            // It takes f + 1 `ViewChange` messages to trigger a view change, if it wasn't started
-            // by `start_view_change()`
-            if peer < 2 {
-                assert_eq!(node1.state.mode, PbftMode::Normal);
-            } else {
-                assert_eq!(node1.state.mode, PbftMode::ViewChanging);
-            }
-            let info = make_msg_info(&PbftMessageType::ViewChange, 1, 1, mock_peer_id(peer));
-            let mut vc_msg = PbftViewChange::new();
-            vc_msg.set_info(info);
-            vc_msg.set_checkpoint_messages(RepeatedField::default());
+        // Once the block is published, the timer waits for the next block to be committed
+        node0.try_publish_early().unwrap_or_else(handle_pbft_err);
+        assert!(!node0
+            .state
+            .block_interval_timeout
+            .as_mut()
+            .unwrap()
+            .check_expired());
+    }
 
-            let msg_bytes = vc_msg.write_to_bytes().unwrap();
-            let msg = PeerMessage {
-                message_type: String::from(&PbftMessageType::ViewChange),
-                content: msg_bytes,
-            };
-            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+    /// Make sure that a primary that answers `Probe`s is left alone, and a view change starts once
+    /// it stops answering
+    #[test]
+    fn probe_primary() {
+        let mut node1 = mock_node(1);
+        let response = mock_msg(&PbftMessageType::ProbeResponse, 0, 0, mock_block(1), 0);
+
+        for _ in 0..5 {
+            node1.probe_primary().unwrap_or_else(handle_pbft_err);
+            assert!(node1.state.probe_outstanding);
+            node1
+                .on_peer_message(&response)
+                .unwrap_or_else(handle_pbft_err);
+            assert!(!node1.state.probe_outstanding);
         }
 
-        assert!(node1.state.is_primary());
-        assert_eq!(node1.state.view, 1);
+        // The first probe, plus max_probe_failures - 1 more that go unanswered
+        for _ in 0..node1.state.max_probe_failures {
+            node1.probe_primary().unwrap_or_else(handle_pbft_err);
+            assert_eq!(node1.state.mode, PbftMode::Normal);
+        }
+        node1.probe_primary().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
     }
 
-    /// Make sure that view changes start correctly
+    /// Make sure that a node's answer to a `ClockSync` is used to estimate how far its clock is
+    /// from this node's, unless this node's clock went backwards in the meantime
     #[test]
-    fn start_view_change() {
+    fn clock_sync() {
         let mut node1 = mock_node(1);
-        assert_eq!(node1.state.mode, PbftMode::Normal);
+        let response = |origin_time: u64, receive_time: u64| {
+            let mut response = PbftClockSync::new();
+            response.set_info(make_msg_info(
+                &PbftMessageType::ClockSyncResponse,
+                0,
+                0,
+                mock_peer_id(2),
+            ));
+            response.set_origin_time(origin_time);
+            response.set_receive_time(receive_time);
+            PeerMessage {
+                message_type: String::from(&PbftMessageType::ClockSyncResponse),
+                content: response.write_to_bytes().unwrap(),
+            }
+        };
+        let skew = |node: &PbftNode| {
+            node.state
+                .peer_stats
+                .get(&mock_peer_id(2))
+                .and_then(|stats| stats.clock_skew)
+        };
 
-        node1.start_view_change().unwrap_or_else(handle_pbft_err);
+        let future = timing::unix_millis() + 60_000;
+        node1
+            .on_peer_message(&response(future, future))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(skew(&node1), None);
 
-        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+        // Sent 100ms ago, and answered by a clock that's about 5s ahead
+        let origin_time = timing::unix_millis() - 100;
+        node1
+            .on_peer_message(&response(origin_time, origin_time + 5050))
+            .unwrap_or_else(handle_pbft_err);
+        let skew = skew(&node1).unwrap();
+        assert!(skew.offset_ms > 4900 && skew.offset_ms <= 5000);
+        assert!(skew.exceeds(node1.state.max_clock_skew));
     }
 }
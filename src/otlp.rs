@@ -0,0 +1,633 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Exporting metrics and traces with the OpenTelemetry protocol (OTLP)
+//!
+//! An `OtlpExporter` sends the node's metrics, and the tracing spans recorded by its
+//! `OtlpSpanLayer`, to an OTLP receiver (such as an OpenTelemetry Collector, Grafana Tempo, or
+//! Honeycomb) using OTLP over HTTP with JSON encoding. Metrics go to `/v1/metrics` and spans to
+//! `/v1/traces`, every time the engine reports its metrics. Spans that close between reports are
+//! buffered, up to `MAX_BUFFERED_SPANS`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hex;
+use serde_json::{self, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use message_log::PbftLog;
+use metrics::{self, Histogram, LATENCY_BUCKETS_MS};
+use state::PbftState;
+use timing;
+
+/// How many closed spans are kept for the next export; the oldest are dropped beyond this
+const MAX_BUFFERED_SPANS: usize = 10_000;
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`, since the node's counters count from when it started
+const CUMULATIVE: u64 = 2;
+
+/// A span that has closed, waiting to be exported
+#[derive(Clone, Debug)]
+pub struct FinishedSpan {
+    pub trace_id: [u64; 2],
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, String)>,
+}
+
+// What the layer keeps with each open span
+struct SpanTiming {
+    trace_id: [u64; 2],
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+/// Sends the node's metrics and spans to an OTLP receiver over HTTP
+#[derive(Clone)]
+pub struct OtlpExporter {
+    /// The `host:port` of the receiver
+    address: String,
+
+    /// When the node started, which is when its counters started counting
+    started: SystemTime,
+
+    spans: Arc<Mutex<VecDeque<FinishedSpan>>>,
+}
+
+impl OtlpExporter {
+    pub fn new(address: String) -> Self {
+        OtlpExporter {
+            address,
+            started: SystemTime::now(),
+            spans: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// A tracing layer that records spans for this exporter to send
+    pub fn span_layer(&self) -> OtlpSpanLayer {
+        OtlpSpanLayer {
+            spans: Arc::clone(&self.spans),
+        }
+    }
+
+    /// Send the node's current metrics, and the spans that closed since the last export. The
+    /// requests are made from a separate thread, so that a slow or unreachable receiver doesn't
+    /// hold up the node; failures are only logged.
+    pub fn export(&self, state: &PbftState, msg_log: &PbftLog) {
+        let node = hex::encode(Vec::<u8>::from(state.get_own_peer_id()));
+        let metrics = metrics_json(&node, state, msg_log, self.started, SystemTime::now());
+        let spans: Vec<FinishedSpan> = self
+            .spans
+            .lock()
+            .expect("Span buffer lock poisoned")
+            .drain(..)
+            .collect();
+        let traces = if spans.is_empty() {
+            None
+        } else {
+            Some(spans_json(&node, &spans))
+        };
+
+        let address = self.address.clone();
+        thread::spawn(move || {
+            let requests = Some(("/v1/metrics", metrics))
+                .into_iter()
+                .chain(traces.map(|traces| ("/v1/traces", traces)));
+            for (path, body) in requests {
+                let body = serde_json::to_string(&body).expect("Couldn't write OTLP request");
+                if let Err(err) =
+                    metrics::http_post(&address, path, "application/json", body.as_bytes())
+                {
+                    warn!(
+                        "Couldn't export to OTLP receiver at {}{}: {}",
+                        address, path, err
+                    );
+                }
+            }
+        });
+    }
+}
+
+/// Records each span's timing and fields, and hands closed spans to an `OtlpExporter`
+pub struct OtlpSpanLayer {
+    spans: Arc<Mutex<VecDeque<FinishedSpan>>>,
+}
+
+impl<S> Layer<S> for OtlpSpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        // A span belongs to the same trace as its parent; one without a parent starts a new trace
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let ids = extensions
+                .get::<SpanTiming>()
+                .map(|timing| (timing.trace_id, timing.span_id));
+            ids
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => ([timing::random_u64(), timing::random_u64()], None),
+        };
+
+        let mut visitor = AttributeVisitor(Vec::new());
+        attrs.record(&mut visitor);
+
+        span.extensions_mut().insert(SpanTiming {
+            trace_id,
+            span_id: timing::random_u64(),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes: visitor.0,
+        });
+    }
+
+    // Fields given values after the span was made, like the trace ID of a peer message once it's
+    // been parsed
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut visitor = AttributeVisitor(Vec::new());
+        values.record(&mut visitor);
+        if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+            timing.attributes.extend(visitor.0);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let extensions = span.extensions();
+        let timing = match extensions.get::<SpanTiming>() {
+            Some(timing) => timing,
+            None => return,
+        };
+
+        let finished = FinishedSpan {
+            trace_id: timing.trace_id,
+            span_id: timing.span_id,
+            parent_span_id: timing.parent_span_id,
+            name: span.name(),
+            start: timing.start,
+            end: SystemTime::now(),
+            attributes: timing.attributes.clone(),
+        };
+        let mut spans = self.spans.lock().expect("Span buffer lock poisoned");
+        if spans.len() >= MAX_BUFFERED_SPANS {
+            spans.pop_front();
+        }
+        spans.push_back(finished);
+    }
+}
+
+// Collects a span's fields as strings
+struct AttributeVisitor(Vec<(String, String)>);
+
+impl Visit for AttributeVisitor {
+    fn record_debug(&mut self, field: &Field, value: &fmt::Debug) {
+        self.0
+            .push((String::from(field.name()), format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0
+            .push((String::from(field.name()), String::from(value)));
+    }
+}
+
+/// Describe the node's metrics as an OTLP `ExportMetricsServiceRequest`
+pub fn metrics_json(
+    node: &str,
+    state: &PbftState,
+    msg_log: &PbftLog,
+    started: SystemTime,
+    now: SystemTime,
+) -> Value {
+    let point = |attributes: Vec<Value>, value: u64| {
+        object(vec![
+            ("attributes", Value::Array(attributes)),
+            ("startTimeUnixNano", unix_nanos(started)),
+            ("timeUnixNano", unix_nanos(now)),
+            ("asInt", Value::from(value.to_string())),
+        ])
+    };
+    let gauge = |name: &str, description: &str, value: u64| {
+        object(vec![
+            ("name", Value::from(name)),
+            ("description", Value::from(description)),
+            (
+                "gauge",
+                object(vec![(
+                    "dataPoints",
+                    Value::Array(vec![point(vec![], value)]),
+                )]),
+            ),
+        ])
+    };
+    let counter = |name: &str, description: &str, label: &str, counts: &BTreeMap<String, u64>| {
+        let points = counts
+            .iter()
+            .map(|(key, count)| point(vec![attribute(label, key)], *count))
+            .collect();
+        object(vec![
+            ("name", Value::from(name)),
+            ("description", Value::from(description)),
+            (
+                "sum",
+                object(vec![
+                    ("dataPoints", Value::Array(points)),
+                    ("aggregationTemporality", Value::from(CUMULATIVE)),
+                    ("isMonotonic", Value::Bool(true)),
+                ]),
+            ),
+        ])
+    };
+
+    let log = msg_log.stats();
+    let mut metrics = vec![
+        gauge("pbft_view", "The view this node is in", state.view),
+        gauge(
+            "pbft_seq_num",
+            "The sequence number of the block this node is working on",
+            state.seq_num,
+        ),
+        gauge(
+            "pbft_is_primary",
+            "Whether this node is the primary of its view",
+            state.is_primary() as u64,
+        ),
+        gauge(
+            "pbft_members",
+            "How many nodes are in the network",
+            state.peers().len() as u64,
+        ),
+        gauge(
+            "pbft_max_faulty",
+            "How many faulty nodes the network can tolerate",
+            state.f,
+        ),
+        gauge(
+            "pbft_log_entries",
+            "Messages in the message log",
+            log.messages,
+        ),
+        gauge(
+            "pbft_log_bytes",
+            "Estimated size of the message log",
+            log.bytes,
+        ),
+        gauge(
+            "pbft_update_queue_depth",
+            "Updates from the validator waiting to be handled",
+            state.metrics.update_queue_depth,
+        ),
+        counter(
+            "pbft_messages_received",
+            "Messages received from other nodes, by type",
+            "type",
+            &state.metrics.messages_received,
+        ),
+        counter(
+            "pbft_view_changes",
+            "View changes this node started, by reason",
+            "reason",
+            &state.metrics.view_changes,
+        ),
+        counter(
+            "pbft_errors",
+            "Errors this node ran into, by code",
+            "code",
+            &state
+                .metrics
+                .errors
+                .iter()
+                .map(|(&(_, code), &count)| (String::from(code), count))
+                .collect(),
+        ),
+    ];
+
+    let latency_points = state
+        .metrics
+        .commit_latency
+        .iter()
+        .map(|(phase, histogram)| histogram_point(phase, histogram, started, now))
+        .collect();
+    metrics.push(object(vec![
+        ("name", Value::from("pbft_commit_latency")),
+        (
+            "description",
+            Value::from(
+                "How long blocks took to commit (phase total), and to get through each phase",
+            ),
+        ),
+        ("unit", Value::from("s")),
+        (
+            "histogram",
+            object(vec![
+                ("dataPoints", Value::Array(latency_points)),
+                ("aggregationTemporality", Value::from(CUMULATIVE)),
+            ]),
+        ),
+    ]));
+
+    object(vec![(
+        "resourceMetrics",
+        Value::Array(vec![object(vec![
+            ("resource", resource(node)),
+            (
+                "scopeMetrics",
+                Value::Array(vec![object(vec![
+                    ("scope", scope()),
+                    ("metrics", Value::Array(metrics)),
+                ])]),
+            ),
+        ])]),
+    )])
+}
+
+fn histogram_point(
+    phase: &str,
+    histogram: &Histogram,
+    started: SystemTime,
+    now: SystemTime,
+) -> Value {
+    object(vec![
+        ("attributes", Value::Array(vec![attribute("phase", phase)])),
+        ("startTimeUnixNano", unix_nanos(started)),
+        ("timeUnixNano", unix_nanos(now)),
+        ("count", Value::from(histogram.count.to_string())),
+        ("sum", Value::from(metrics::seconds(histogram.sum))),
+        (
+            "bucketCounts",
+            Value::Array(
+                histogram
+                    .buckets
+                    .iter()
+                    .map(|count| Value::from(count.to_string()))
+                    .collect(),
+            ),
+        ),
+        (
+            "explicitBounds",
+            Value::Array(
+                LATENCY_BUCKETS_MS
+                    .iter()
+                    .map(|&bound| Value::from(bound as f64 / 1000.0))
+                    .collect(),
+            ),
+        ),
+        ("max", Value::from(metrics::seconds(histogram.max))),
+    ])
+}
+
+/// Describe the given spans as an OTLP `ExportTraceServiceRequest`
+pub fn spans_json(node: &str, spans: &[FinishedSpan]) -> Value {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let mut fields = vec![
+                (
+                    "traceId",
+                    Value::from(format!(
+                        "{:016x}{:016x}",
+                        span.trace_id[0], span.trace_id[1]
+                    )),
+                ),
+                ("spanId", Value::from(format!("{:016x}", span.span_id))),
+                ("name", Value::from(span.name)),
+                // SPAN_KIND_INTERNAL
+                ("kind", Value::from(1u64)),
+                ("startTimeUnixNano", unix_nanos(span.start)),
+                ("endTimeUnixNano", unix_nanos(span.end)),
+                (
+                    "attributes",
+                    Value::Array(
+                        span.attributes
+                            .iter()
+                            .map(|(key, value)| attribute(key, value))
+                            .collect(),
+                    ),
+                ),
+            ];
+            if let Some(parent_span_id) = span.parent_span_id {
+                fields.push((
+                    "parentSpanId",
+                    Value::from(format!("{:016x}", parent_span_id)),
+                ));
+            }
+            object(fields)
+        })
+        .collect();
+
+    object(vec![(
+        "resourceSpans",
+        Value::Array(vec![object(vec![
+            ("resource", resource(node)),
+            (
+                "scopeSpans",
+                Value::Array(vec![object(vec![
+                    ("scope", scope()),
+                    ("spans", Value::Array(spans)),
+                ])]),
+            ),
+        ])]),
+    )])
+}
+
+// The resource that everything is reported for: this node, identified by its public key
+fn resource(node: &str) -> Value {
+    object(vec![(
+        "attributes",
+        Value::Array(vec![
+            attribute("service.name", env!("CARGO_PKG_NAME")),
+            attribute("service.version", env!("CARGO_PKG_VERSION")),
+            attribute("service.instance.id", node),
+        ]),
+    )])
+}
+
+fn scope() -> Value {
+    object(vec![
+        ("name", Value::from(env!("CARGO_PKG_NAME"))),
+        ("version", Value::from(env!("CARGO_PKG_VERSION"))),
+    ])
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    object(vec![
+        ("key", Value::from(key)),
+        ("value", object(vec![("stringValue", Value::from(value))])),
+    ])
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (String::from(key), value))
+            .collect(),
+    )
+}
+
+// OTLP times are 64-bit nanoseconds since the Unix epoch, which JSON carries as strings
+fn unix_nanos(time: SystemTime) -> Value {
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    let nanos =
+        u128::from(since_epoch.as_secs()) * 1_000_000_000 + u128::from(since_epoch.subsec_nanos());
+    Value::from(nanos.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+
+    /// Make sure that metrics and spans are described the way OTLP/JSON expects: 64-bit numbers
+    /// as strings, IDs in hex, and everything tagged with the node that reported it
+    #[test]
+    fn otlp_json() {
+        let mut state = PbftState::new(0, &mock_config(4));
+        state.view = 3;
+        state.metrics.count_message("Commit");
+        state
+            .metrics
+            .record_latency("total", Duration::from_millis(40));
+        let msg_log = PbftLog::new(&mock_config(4));
+        let started = UNIX_EPOCH + Duration::from_secs(1);
+        let now = UNIX_EPOCH + Duration::from_secs(2);
+
+        let request = metrics_json("abcd", &state, &msg_log, started, now);
+        let resource_metrics = &request.get("resourceMetrics").unwrap().as_array().unwrap()[0];
+        let attributes = resource_metrics
+            .get("resource")
+            .and_then(|resource| resource.get("attributes"))
+            .and_then(Value::as_array)
+            .unwrap();
+        assert!(attributes.contains(&attribute("service.instance.id", "abcd")));
+        let metrics = resource_metrics
+            .get("scopeMetrics")
+            .unwrap()
+            .as_array()
+            .unwrap()[0]
+            .get("metrics")
+            .and_then(Value::as_array)
+            .unwrap();
+        let metric = |name: &str| {
+            metrics
+                .iter()
+                .find(|metric| metric.get("name").and_then(Value::as_str) == Some(name))
+                .unwrap()
+        };
+
+        let view = &metric("pbft_view")
+            .get("gauge")
+            .unwrap()
+            .get("dataPoints")
+            .unwrap();
+        assert_eq!(
+            view.as_array().unwrap()[0]
+                .get("asInt")
+                .and_then(Value::as_str),
+            Some("3")
+        );
+        assert_eq!(
+            view.as_array().unwrap()[0]
+                .get("timeUnixNano")
+                .and_then(Value::as_str),
+            Some("2000000000")
+        );
+        let received = &metric("pbft_messages_received").get("sum").unwrap();
+        assert_eq!(
+            received.get("isMonotonic").and_then(Value::as_bool),
+            Some(true)
+        );
+        let latency = &metric("pbft_commit_latency")
+            .get("histogram")
+            .and_then(|histogram| histogram.get("dataPoints"))
+            .and_then(Value::as_array)
+            .unwrap()[0];
+        assert_eq!(latency.get("count").and_then(Value::as_str), Some("1"));
+        assert_eq!(
+            latency
+                .get("bucketCounts")
+                .and_then(Value::as_array)
+                .map(Vec::len),
+            Some(LATENCY_BUCKETS_MS.len() + 1)
+        );
+
+        let span = FinishedSpan {
+            trace_id: [1, 2],
+            span_id: 3,
+            parent_span_id: Some(4),
+            name: "update",
+            start: started,
+            end: now,
+            attributes: vec![(String::from("call"), String::from("send_to"))],
+        };
+        let request = spans_json("abcd", &[span]);
+        let span = &request.get("resourceSpans").unwrap().as_array().unwrap()[0]
+            .get("scopeSpans")
+            .unwrap()
+            .as_array()
+            .unwrap()[0]
+            .get("spans")
+            .and_then(Value::as_array)
+            .unwrap()[0];
+        assert_eq!(
+            span.get("traceId").and_then(Value::as_str),
+            Some("00000000000000010000000000000002")
+        );
+        assert_eq!(
+            span.get("parentSpanId").and_then(Value::as_str),
+            Some("0000000000000004")
+        );
+        assert_eq!(
+            span.get("endTimeUnixNano").and_then(Value::as_str),
+            Some("2000000000")
+        );
+        assert!(span
+            .get("attributes")
+            .and_then(Value::as_array)
+            .unwrap()
+            .contains(&attribute("call", "send_to")));
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Messages to other nodes that couldn't be sent yet
+//!
+//! If a message can't be sent to a node, because the validator reported that node as
+//! disconnected or because sending it failed, it is kept here instead of being dropped. Messages
+//! for a disconnected node are sent as soon as it reconnects; messages that failed to send to a
+//! connected node are retried, waiting twice as long after each failure. Each node's queue only
+//! holds so many messages, and the oldest are dropped first, since old votes soon stop mattering.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use retry::RetryStrategy;
+use timing;
+
+/// The most messages held for any one node
+const MAX_QUEUED_MESSAGES: usize = 1000;
+
+/// How long to wait before each retry; the delay doubles from 100ms, up to 6.4s
+const RETRY_STRATEGY: RetryStrategy = RetryStrategy::Exponential {
+    initial: Duration::from_millis(100),
+    max: Duration::from_millis(6400),
+};
+
+/// A message that is waiting to be sent: its type and its (signed) contents
+pub type QueuedMessage = (String, Vec<u8>);
+
+#[derive(Debug)]
+struct PeerQueue {
+    messages: VecDeque<QueuedMessage>,
+
+    /// How many retries in a row have failed
+    failures: u32,
+
+    /// When to try sending again
+    retry_at: Instant,
+}
+
+/// Holds messages for nodes that they couldn't be sent to yet
+#[derive(Debug, Default)]
+pub struct Outbox {
+    /// Nodes that the validator has reported as disconnected, and hasn't reported as reconnected
+    disconnected: HashSet<PeerId>,
+
+    queues: HashMap<PeerId, PeerQueue>,
+
+    /// How many messages have been dropped because a node's queue was full
+    pub dropped: u64,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox::default()
+    }
+
+    /// Whether a message to the given node can be sent right away: the node is connected, and
+    /// there aren't any older messages for it waiting to be sent first
+    pub fn can_send(&self, peer_id: &PeerId) -> bool {
+        !self.disconnected.contains(peer_id)
+            && self
+                .queues
+                .get(peer_id)
+                .map_or(true, |queue| queue.messages.is_empty())
+    }
+
+    /// Whether the validator has reported the given node as disconnected
+    pub fn is_disconnected(&self, peer_id: &PeerId) -> bool {
+        self.disconnected.contains(peer_id)
+    }
+
+    /// Hold messages for the given node until it reconnects
+    pub fn disconnect(&mut self, peer_id: PeerId) {
+        self.disconnected.insert(peer_id);
+    }
+
+    /// Send the messages held for the given node the next time `take_ready` is called
+    pub fn connect(&mut self, peer_id: &PeerId) {
+        self.disconnected.remove(peer_id);
+        if let Some(queue) = self.queues.get_mut(peer_id) {
+            queue.failures = 0;
+            queue.retry_at = timing::now();
+        }
+    }
+
+    /// Hold a message for the given node, after any others that are waiting for it
+    pub fn push(&mut self, peer_id: &PeerId, msg_type: String, payload: Vec<u8>) {
+        let queue = self
+            .queues
+            .entry(peer_id.clone())
+            .or_insert_with(|| PeerQueue {
+                messages: VecDeque::new(),
+                failures: 0,
+                retry_at: timing::now(),
+            });
+
+        if queue.messages.is_empty() {
+            queue.retry_at = timing::now() + RETRY_STRATEGY.delay(queue.failures);
+        }
+        queue.messages.push_back((msg_type, payload));
+
+        if queue.messages.len() > MAX_QUEUED_MESSAGES {
+            queue.messages.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    /// Take the messages for each connected node that are due to be sent again
+    pub fn take_ready(&mut self) -> Vec<(PeerId, VecDeque<QueuedMessage>)> {
+        let now = timing::now();
+        let disconnected = &self.disconnected;
+        self.queues
+            .iter_mut()
+            .filter(|(peer_id, queue)| {
+                !disconnected.contains(*peer_id)
+                    && !queue.messages.is_empty()
+                    && queue.retry_at <= now
+            })
+            .map(|(peer_id, queue)| {
+                (
+                    peer_id.clone(),
+                    ::std::mem::replace(&mut queue.messages, VecDeque::new()),
+                )
+            })
+            .collect()
+    }
+
+    /// Put back messages taken by `take_ready` that still couldn't be sent, and wait longer
+    /// before trying again; messages that were successfully sent reset the wait
+    pub fn retry_failed(&mut self, peer_id: &PeerId, mut unsent: VecDeque<QueuedMessage>) {
+        if let Some(queue) = self.queues.get_mut(peer_id) {
+            if unsent.is_empty() {
+                queue.failures = 0;
+                return;
+            }
+
+            queue.failures += 1;
+            queue.retry_at = timing::now() + RETRY_STRATEGY.delay(queue.failures);
+            unsent.extend(queue.messages.drain(..));
+            while unsent.len() > MAX_QUEUED_MESSAGES {
+                unsent.pop_front();
+                self.dropped += 1;
+            }
+            queue.messages = unsent;
+        }
+    }
+
+    /// How many messages are waiting to be sent to the given node
+    pub fn queued(&self, peer_id: &PeerId) -> usize {
+        self.queues
+            .get(peer_id)
+            .map_or(0, |queue| queue.messages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that messages for a disconnected node are held until it reconnects, that failed
+    /// retries wait longer each time, and that messages stay in order
+    #[test]
+    fn outbox() {
+        timing::start_virtual_time(1);
+        let start = timing::now();
+        let peer = PeerId::from(vec![1]);
+        let other = PeerId::from(vec![2]);
+        let mut outbox = Outbox::new();
+        let msg = |n: u8| (String::from("Commit"), vec![n]);
+
+        // Disconnected nodes get nothing until they reconnect
+        outbox.disconnect(peer.clone());
+        assert!(!outbox.can_send(&peer));
+        assert!(outbox.can_send(&other));
+        outbox.push(&peer, String::from("Commit"), vec![1]);
+        outbox.push(&peer, String::from("Commit"), vec![2]);
+        timing::set_virtual_time(start + Duration::from_secs(10));
+        assert!(outbox.take_ready().is_empty());
+
+        outbox.connect(&peer);
+        assert!(!outbox.can_send(&peer));
+        let ready = outbox.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0, peer);
+        assert_eq!(ready[0].1, vec![msg(1), msg(2)]);
+        assert!(outbox.can_send(&peer));
+
+        // A failed retry is put back in front of newer messages, and waits twice as long
+        outbox.push(&peer, String::from("Commit"), vec![3]);
+        timing::set_virtual_time(start + Duration::from_secs(11));
+        let unsent = outbox.take_ready().remove(0).1;
+        assert_eq!(unsent, vec![msg(3)]);
+        outbox.push(&peer, String::from("Commit"), vec![4]);
+        outbox.retry_failed(&peer, unsent);
+        assert_eq!(outbox.queued(&peer), 2);
+
+        timing::set_virtual_time(start + Duration::from_millis(11_150));
+        assert!(outbox.take_ready().is_empty());
+        timing::set_virtual_time(start + Duration::from_millis(11_200));
+        assert_eq!(outbox.take_ready()[0].1, vec![msg(3), msg(4)]);
+
+        timing::stop_virtual_time();
+    }
+}
@@ -0,0 +1,229 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Statistics about the messages exchanged with each other node
+//!
+//! A node that has gone silent shows up as one that hasn't been heard from in a long time, and a
+//! node that is spamming shows up with many more messages received, or rejected, than the others.
+//! The statistics are kept in the node's state, and are written to metrics and crash dumps.
+//!
+//! How late each node's votes arrive, after the `PrePrepare` they follow, is recorded for every
+//! block this node commits, so a node that is slow to vote shows up too.
+//!
+//! They also include how far the node's clock is estimated to be from this node's, from the last
+//! `ClockSync` exchanged with it, since the timeouts the algorithm relies on for liveness assume
+//! that the nodes' clocks run at about the same time.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use metrics::Histogram;
+use timing;
+
+/// Messages sent to and received from a single node, by type
+#[derive(Clone, Debug, Default)]
+pub struct PeerStats {
+    /// Messages handed to the validator for this node
+    pub sent: BTreeMap<String, u64>,
+
+    /// Messages received from this node, including those that were rejected
+    pub received: BTreeMap<String, u64>,
+
+    /// Messages from this node that were dropped before being handled
+    pub rejected: BTreeMap<String, u64>,
+
+    /// When the last message from this node was received
+    pub last_seen: Option<Instant>,
+
+    /// The highest sequence number of the messages from this node that were handled, or that
+    /// were dropped for being too far ahead
+    pub highest_seq_num: u64,
+
+    /// How far this node's clock was from this node's own, as of the last `ClockSync`
+    pub clock_skew: Option<ClockSkew>,
+
+    /// How long after the `PrePrepare` this node's votes arrived, by type, for blocks that were
+    /// committed
+    pub arrival_lag: BTreeMap<String, Histogram>,
+}
+
+/// An estimate of how far another node's clock is from this node's
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockSkew {
+    /// How far ahead the other node's clock is, in milliseconds (negative if it's behind)
+    pub offset_ms: i64,
+
+    /// How long the `ClockSync` took to be answered; the actual offset is within half of this of
+    /// `offset_ms`, since the other node could have answered at any point in between
+    pub round_trip_ms: u64,
+}
+
+impl ClockSkew {
+    /// Estimate the skew from a `ClockSync` that this node sent at `origin_time`, which the other
+    /// node answered at `receive_time` by its clock, and this node got the answer at `now`,
+    /// assuming the answer took as long to come back as the `ClockSync` took to get there. Returns
+    /// `None` if the answer came back before the `ClockSync` was sent, which means this node's
+    /// clock was set back in between.
+    pub fn estimate(origin_time: u64, receive_time: u64, now: u64) -> Option<Self> {
+        if now < origin_time {
+            return None;
+        }
+        let round_trip_ms = now - origin_time;
+        let midpoint = origin_time + round_trip_ms / 2;
+        Some(ClockSkew {
+            offset_ms: receive_time as i64 - midpoint as i64,
+            round_trip_ms,
+        })
+    }
+
+    /// Whether the other node's clock is definitely further than `max` from this node's, even
+    /// allowing for the uncertainty of the estimate
+    pub fn exceeds(&self, max: Duration) -> bool {
+        self.offset_ms.abs() as u64 > timing::millis(max) + self.round_trip_ms / 2
+    }
+}
+
+impl fmt::Display for ClockSkew {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:+}ms (±{}ms)", self.offset_ms, self.round_trip_ms / 2)
+    }
+}
+
+impl PeerStats {
+    pub fn count_sent(&mut self, msg_type: &str) {
+        *self.sent.entry(msg_type.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn count_received(&mut self, msg_type: &str) {
+        *self.received.entry(msg_type.to_string()).or_insert(0) += 1;
+        self.last_seen = Some(timing::now());
+    }
+
+    pub fn count_rejected(&mut self, msg_type: &str) {
+        *self.rejected.entry(msg_type.to_string()).or_insert(0) += 1;
+    }
+
+    /// Record that this node sent a message for the given sequence number, which it must have
+    /// reached
+    pub fn record_seq_num(&mut self, seq_num: u64) {
+        self.highest_seq_num = self.highest_seq_num.max(seq_num);
+    }
+
+    /// Record how long after the `PrePrepare` a vote of the given type from this node arrived
+    pub fn record_arrival_lag(&mut self, msg_type: &str, lag: Duration) {
+        self.arrival_lag
+            .entry(msg_type.to_string())
+            .or_default()
+            .observe(lag);
+    }
+
+    /// How long it's been since a message was received from this node (`None` if none ever was)
+    pub fn since_last_seen(&self) -> Option<Duration> {
+        self.last_seen.map(|last_seen| timing::now() - last_seen)
+    }
+
+    /// Every message type that was sent, received, or rejected, in order
+    pub fn msg_types(&self) -> Vec<&str> {
+        let mut msg_types: Vec<&str> = self
+            .sent
+            .keys()
+            .chain(self.received.keys())
+            .chain(self.rejected.keys())
+            .map(String::as_str)
+            .collect();
+        msg_types.sort();
+        msg_types.dedup();
+        msg_types
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.since_last_seen() {
+            Some(since) => write!(f, "last seen {:?} ago", since)?,
+            None => write!(f, "never seen")?,
+        }
+        for msg_type in self.msg_types() {
+            let count = |counts: &BTreeMap<String, u64>| counts.get(msg_type).cloned().unwrap_or(0);
+            write!(
+                f,
+                "; {}: {} sent, {} received, {} rejected",
+                msg_type,
+                count(&self.sent),
+                count(&self.received),
+                count(&self.rejected)
+            )?;
+        }
+        if let Some(skew) = self.clock_skew {
+            write!(f, "; clock {}", skew)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that messages are counted by type, and that the time since the node was last
+    /// heard from is tracked
+    #[test]
+    fn peer_stats() {
+        timing::start_virtual_time(1);
+        let mut stats = PeerStats::default();
+        assert_eq!(stats.since_last_seen(), None);
+        assert_eq!(stats.to_string(), "never seen");
+
+        stats.count_sent("Prepare");
+        stats.count_received("Commit");
+        stats.count_received("Commit");
+        stats.count_rejected("Commit");
+        timing::set_virtual_time(timing::now() + Duration::from_secs(2));
+
+        assert_eq!(stats.since_last_seen(), Some(Duration::from_secs(2)));
+        assert_eq!(stats.msg_types(), vec!["Commit", "Prepare"]);
+        assert_eq!(
+            stats.to_string(),
+            "last seen 2s ago; Commit: 0 sent, 2 received, 1 rejected; \
+             Prepare: 1 sent, 0 received, 0 rejected"
+        );
+
+        timing::stop_virtual_time();
+    }
+
+    /// Make sure that clock skew is estimated from the midpoint of the round trip, and only counts
+    /// as too large if it is even at the edge of the estimate's uncertainty
+    #[test]
+    fn clock_skew() {
+        // Sent at 1000, answered at 1550 by the other node's clock, and back at 1100: the other
+        // node's clock is about 500ms ahead
+        let skew = ClockSkew::estimate(1000, 1550, 1100).unwrap();
+        assert_eq!(skew.offset_ms, 500);
+        assert_eq!(skew.round_trip_ms, 100);
+        assert_eq!(skew.to_string(), "+500ms (±50ms)");
+        assert!(skew.exceeds(Duration::from_millis(400)));
+        assert!(!skew.exceeds(Duration::from_millis(460)));
+
+        let behind = ClockSkew::estimate(1000, 0, 1000).unwrap();
+        assert_eq!(behind.offset_ms, -1000);
+        assert!(behind.exceeds(Duration::from_millis(999)));
+
+        // This node's clock was set back while waiting for the answer
+        assert_eq!(ClockSkew::estimate(1000, 1000, 900), None);
+    }
+}
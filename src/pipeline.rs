@@ -0,0 +1,261 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Decoding messages from other nodes apart from handling them
+//!
+//! Before a message from another node is handled, its wire format is checked, its signature is
+//! verified (if message authentication is enabled), and its info is parsed. None of that depends
+//! on the node's state, and verifying signatures is the slowest part of handling most messages,
+//! so it's the first of two stages: `decode` does it for one message, and a `DecodePool` does it
+//! for a batch of messages at once, on worker threads. The second stage, applying the decoded
+//! messages to the node's state, stays on the engine's thread, and gets the results in the order
+//! the messages arrived; so the node ends up in the same state as if it had decoded each message
+//! itself.
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use sawtooth_sdk::consensus::engine::PeerMessage;
+
+use authentication;
+use error::PbftError;
+use message_extensions::parse_msg_info;
+use message_type::PbftMessageType;
+use protos::pbft_message::PbftMessageInfo;
+use validation;
+
+/// A message from another node that's ready to be applied to the node's state
+#[derive(Debug)]
+pub struct DecodedMessage {
+    pub msg_type: PbftMessageType,
+
+    /// The message the signed message wrapped, if message authentication is enabled (otherwise,
+    /// the message is used as it arrived)
+    pub unwrapped: Option<PeerMessage>,
+
+    pub info: PbftMessageInfo,
+}
+
+/// Why a message from another node couldn't be decoded
+#[derive(Debug)]
+pub enum DecodeError {
+    /// It's too large, of an unknown type, or missing required fields
+    Malformed(PbftError),
+
+    /// Its signature couldn't be verified
+    Unauthenticated(PbftError),
+}
+
+/// Check a message's wire format, verify its signature if `authenticate` is set, and parse its
+/// info
+pub fn decode(
+    msg: &PeerMessage,
+    max_message_size: u64,
+    authenticate: bool,
+) -> Result<DecodedMessage, DecodeError> {
+    let span = tracing::debug_span!("parse", msg_type = msg.message_type.as_str());
+    let _guard = span.enter();
+
+    let msg_type =
+        validation::check_wire_format(msg, max_message_size).map_err(DecodeError::Malformed)?;
+
+    let unwrapped = if authenticate {
+        Some(authentication::verify(msg).map_err(DecodeError::Unauthenticated)?)
+    } else {
+        None
+    };
+
+    let content = unwrapped.as_ref().map_or(&msg.content, |msg| &msg.content);
+    let info = parse_msg_info(&msg_type, content)
+        .and_then(|info| validation::check_info(&msg_type, &info).map(|_| info))
+        .map_err(DecodeError::Malformed)?;
+
+    Ok(DecodedMessage {
+        msg_type,
+        unwrapped,
+        info,
+    })
+}
+
+// A message to decode, with its place in its batch and how to decode it
+struct Job {
+    index: usize,
+    msg: PeerMessage,
+    max_message_size: u64,
+    authenticate: bool,
+}
+
+type JobResult = (usize, PeerMessage, Result<DecodedMessage, DecodeError>);
+
+/// Worker threads that decode batches of messages
+pub struct DecodePool {
+    jobs: Option<Sender<Job>>,
+    results: Receiver<JobResult>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl DecodePool {
+    /// Start the given number of worker threads
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = mpsc::channel();
+
+        let mut workers = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let job_receiver = Arc::clone(&job_receiver);
+            let result_sender = result_sender.clone();
+            workers.push(
+                thread::Builder::new()
+                    .name(format!("pbft-decode-{}", i))
+                    .spawn(move || work(&job_receiver, &result_sender))?,
+            );
+        }
+
+        Ok(DecodePool {
+            jobs: Some(jobs),
+            results,
+            workers,
+        })
+    }
+
+    /// Decode the given messages, in parallel, and return each one with its result, in the order
+    /// they were given
+    pub fn decode_all(
+        &self,
+        messages: Vec<PeerMessage>,
+        max_message_size: u64,
+        authenticate: bool,
+    ) -> Vec<(PeerMessage, Result<DecodedMessage, DecodeError>)> {
+        let jobs = self.jobs.as_ref().expect("Decode pool was stopped");
+        let count = messages.len();
+        for (index, msg) in messages.into_iter().enumerate() {
+            jobs.send(Job {
+                index,
+                msg,
+                max_message_size,
+                authenticate,
+            })
+            .expect("Decode workers stopped");
+        }
+
+        let mut decoded: Vec<Option<(PeerMessage, Result<DecodedMessage, DecodeError>)>> =
+            (0..count).map(|_| None).collect();
+        for _ in 0..count {
+            let (index, msg, result) = self.results.recv().expect("Decode workers stopped");
+            decoded[index] = Some((msg, result));
+        }
+        decoded
+            .into_iter()
+            .map(|result| result.expect("Message wasn't decoded"))
+            .collect()
+    }
+}
+
+impl Drop for DecodePool {
+    fn drop(&mut self) {
+        // Workers stop once there are no more jobs to wait for
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(jobs: &Mutex<Receiver<Job>>, results: &Sender<JobResult>) {
+    loop {
+        let job = match jobs.lock() {
+            Ok(jobs) => match jobs.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            },
+            Err(_) => return,
+        };
+
+        // A message that makes decoding panic is as good as malformed; the batch it's in still
+        // has to be finished
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            decode(&job.msg, job.max_message_size, job.authenticate)
+        }))
+        .unwrap_or_else(|_| {
+            Err(DecodeError::Malformed(PbftError::InternalError(
+                String::from("Decoding message panicked"),
+            )))
+        });
+
+        if results.send((job.index, job.msg, result)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::make_msg_info;
+    use protobuf::Message;
+    use protos::pbft_message::PbftMessage;
+    use sawtooth_sdk::consensus::engine::PeerId;
+
+    fn prepare(seq_num: u64) -> PeerMessage {
+        let mut msg = PbftMessage::new();
+        msg.set_info(make_msg_info(
+            &PbftMessageType::Prepare,
+            0,
+            seq_num,
+            PeerId::from(vec![1]),
+        ));
+        PeerMessage {
+            message_type: String::from(&PbftMessageType::Prepare),
+            content: msg.write_to_bytes().unwrap(),
+        }
+    }
+
+    /// Make sure that a pool decodes a batch of messages the same way `decode` would, and returns
+    /// them in order
+    #[test]
+    fn decode_pool() {
+        let pool = DecodePool::new(3).unwrap();
+        let mut messages: Vec<PeerMessage> = (1..=20).map(prepare).collect();
+        messages[7].content.truncate(3);
+        messages[9].message_type = String::from("Unknown");
+
+        let decoded = pool.decode_all(messages.clone(), 10_000, false);
+        assert_eq!(decoded.len(), 20);
+        for (i, (msg, result)) in decoded.into_iter().enumerate() {
+            assert_eq!(msg, messages[i]);
+            match (i, result) {
+                (7, Err(DecodeError::Malformed(_))) | (9, Err(DecodeError::Malformed(_))) => (),
+                (_, Ok(decoded)) => {
+                    assert_eq!(decoded.msg_type, PbftMessageType::Prepare);
+                    assert_eq!(decoded.info.get_seq_num(), i as u64 + 1);
+                    assert!(decoded.unwrapped.is_none());
+                }
+                (i, result) => panic!("Unexpected result for message {}: {:?}", i, result),
+            }
+        }
+
+        // Unsigned messages can't be authenticated
+        match pool.decode_all(vec![prepare(1)], 10_000, true).pop() {
+            Some((_, Err(DecodeError::Unauthenticated(_)))) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
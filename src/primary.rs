@@ -0,0 +1,271 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Strategies for choosing which node is the primary in a given view
+//!
+//! Every node must pick the same primary for the same view, so a strategy may only depend on the
+//! view, the list of peers, on-chain settings, and events that every node observes in the same
+//! order (such as completed view changes).
+
+use std::collections::HashMap;
+use std::fmt;
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+/// Which primary selection strategy to use, as set by `sawtooth.consensus.pbft.primary_selection`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrimarySelection {
+    /// Nodes take turns being primary, in the order of the peers list
+    RoundRobin,
+
+    /// Nodes are primary in a number of views proportional to their weight; nodes without a weight
+    /// have a weight of 1
+    Weighted(HashMap<PeerId, u64>),
+
+    /// Nodes take turns being primary, but only nodes that have failed as primary the fewest
+    /// times are chosen
+    Reputation,
+}
+
+/// A view that the network moved past with a view change, because its primary was deemed faulty
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrimaryFailure {
+    pub view: u64,
+    pub peer_id: PeerId,
+}
+
+/// Chooses the primary for each view
+pub trait PrimarySelector: fmt::Debug {
+    /// Get the index (in `peers`) of the primary for the given view. Only `failures` in earlier
+    /// views may be taken into account, so the primary of a past view never changes.
+    fn select_primary(&self, view: u64, peers: &[PeerId], failures: &[PrimaryFailure]) -> usize;
+}
+
+/// Build the selector for the given strategy
+pub fn new_selector(selection: &PrimarySelection) -> Box<PrimarySelector> {
+    match *selection {
+        PrimarySelection::RoundRobin => Box::new(RoundRobin),
+        PrimarySelection::Weighted(ref weights) => Box::new(Weighted::new(weights.clone())),
+        PrimarySelection::Reputation => Box::new(Reputation),
+    }
+}
+
+/// The primary for view `v` is node `v mod n`
+#[derive(Debug)]
+pub struct RoundRobin;
+
+impl PrimarySelector for RoundRobin {
+    fn select_primary(&self, view: u64, peers: &[PeerId], _: &[PrimaryFailure]) -> usize {
+        (view % peers.len() as u64) as usize
+    }
+}
+
+/// Each node is primary for as many consecutive views as its weight, in the order of the peers list
+#[derive(Debug)]
+pub struct Weighted {
+    weights: HashMap<PeerId, u64>,
+}
+
+impl Weighted {
+    pub fn new(weights: HashMap<PeerId, u64>) -> Self {
+        Weighted { weights }
+    }
+
+    fn weight(&self, peer: &PeerId) -> u64 {
+        *self.weights.get(peer).unwrap_or(&1)
+    }
+}
+
+impl PrimarySelector for Weighted {
+    fn select_primary(&self, view: u64, peers: &[PeerId], failures: &[PrimaryFailure]) -> usize {
+        // The weights come from an on-chain setting, so they may be big enough to overflow
+        let total = peers
+            .iter()
+            .fold(0u64, |total, peer| total.saturating_add(self.weight(peer)));
+        if total == 0 {
+            return RoundRobin.select_primary(view, peers, failures);
+        }
+
+        let mut position = view % total;
+        for (i, peer) in peers.iter().enumerate() {
+            let weight = self.weight(peer);
+            if position < weight {
+                return i;
+            }
+            position -= weight;
+        }
+
+        unreachable!()
+    }
+}
+
+/// Round robin among the nodes that have failed as primary the fewest times, so a node that keeps
+/// failing is skipped until the others have failed as often
+#[derive(Debug)]
+pub struct Reputation;
+
+impl PrimarySelector for Reputation {
+    fn select_primary(&self, view: u64, peers: &[PeerId], failures: &[PrimaryFailure]) -> usize {
+        let counts: Vec<usize> = peers
+            .iter()
+            .map(|peer| failures_in(failures, peer, 0, view))
+            .collect();
+        let fewest = counts.iter().cloned().min().unwrap_or(0);
+        let candidates: Vec<usize> = (0..peers.len()).filter(|&i| counts[i] == fewest).collect();
+
+        candidates[(view % candidates.len() as u64) as usize]
+    }
+}
+
+/// Temporarily skips nodes that have failed as primary too often
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blacklist {
+    /// How many failures within the cooldown period get a node skipped (disabled if 0)
+    pub threshold: u64,
+
+    /// How many of the most recent views to count failures in
+    pub cooldown: u64,
+}
+
+impl Blacklist {
+    /// Tell if `peer` is skipped as primary in the given view
+    pub fn is_blacklisted(&self, view: u64, peer: &PeerId, failures: &[PrimaryFailure]) -> bool {
+        self.threshold > 0
+            && failures_in(failures, peer, view.saturating_sub(self.cooldown), view) as u64
+                >= self.threshold
+    }
+
+    /// Starting with the node chosen by a `PrimarySelector`, find the first node in `peers` that
+    /// isn't blacklisted. If every node is blacklisted, the chosen node is kept.
+    pub fn apply(
+        &self,
+        view: u64,
+        selected: usize,
+        peers: &[PeerId],
+        failures: &[PrimaryFailure],
+    ) -> usize {
+        (0..peers.len())
+            .map(|offset| (selected + offset) % peers.len())
+            .find(|&i| !self.is_blacklisted(view, &peers[i], failures))
+            .unwrap_or(selected)
+    }
+}
+
+// Count the failures of the given node as primary in views from `start` up to (not including) `end`
+fn failures_in(failures: &[PrimaryFailure], peer: &PeerId, start: u64, end: u64) -> usize {
+    failures
+        .iter()
+        .filter(|failure| failure.view >= start && failure.view < end && &failure.peer_id == peer)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+
+    fn failure(view: u64, peer_id: &PeerId) -> PrimaryFailure {
+        PrimaryFailure {
+            view,
+            peer_id: peer_id.clone(),
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let peers = mock_config(4).peers;
+        let selector = new_selector(&PrimarySelection::RoundRobin);
+
+        let primaries: Vec<usize> = (0..6)
+            .map(|v| selector.select_primary(v, &peers, &[]))
+            .collect();
+        assert_eq!(primaries, vec![0, 1, 2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn weighted() {
+        let peers = mock_config(4).peers;
+        let mut weights = HashMap::new();
+        weights.insert(peers[0].clone(), 3);
+        weights.insert(peers[2].clone(), 0);
+        let selector = new_selector(&PrimarySelection::Weighted(weights));
+
+        // Node 0 has weight 3, node 2 is never primary, and the others have the default of 1
+        let primaries: Vec<usize> = (0..7)
+            .map(|v| selector.select_primary(v, &peers, &[]))
+            .collect();
+        assert_eq!(primaries, vec![0, 0, 0, 1, 3, 0, 0]);
+
+        // Weights too big to add up still pick a primary
+        let mut weights = HashMap::new();
+        weights.insert(peers[0].clone(), u64::max_value());
+        weights.insert(peers[1].clone(), u64::max_value());
+        let selector = new_selector(&PrimarySelection::Weighted(weights));
+        assert_eq!(
+            selector.select_primary(u64::max_value() - 1, &peers, &[]),
+            0
+        );
+    }
+
+    #[test]
+    fn reputation() {
+        let peers = mock_config(4).peers;
+        let selector = new_selector(&PrimarySelection::Reputation);
+
+        // Node 1 fails in view 1; the other three nodes take turns until they fail as well
+        let mut failures = vec![failure(1, &peers[1])];
+        let primaries: Vec<usize> = (2..6)
+            .map(|v| selector.select_primary(v, &peers, &failures))
+            .collect();
+        assert_eq!(primaries, vec![3, 0, 2, 3]);
+
+        failures.push(failure(2, &peers[3]));
+        failures.push(failure(3, &peers[2]));
+        failures.push(failure(4, &peers[0]));
+        assert_eq!(selector.select_primary(5, &peers, &failures), 1);
+
+        // Past views keep their primaries
+        assert_eq!(selector.select_primary(1, &peers, &failures), 1);
+        assert_eq!(selector.select_primary(3, &peers, &failures), 2);
+    }
+
+    #[test]
+    fn blacklist() {
+        let peers = mock_config(4).peers;
+        let blacklist = Blacklist {
+            threshold: 2,
+            cooldown: 8,
+        };
+        let failures = vec![failure(1, &peers[1]), failure(5, &peers[1])];
+
+        // Node 1 has failed twice in the 8 views before view 9, so node 2 takes its turn
+        assert!(!blacklist.is_blacklisted(5, &peers[1], &failures));
+        assert!(blacklist.is_blacklisted(9, &peers[1], &failures));
+        assert_eq!(blacklist.apply(9, 1, &peers, &failures), 2);
+
+        // The failure in view 1 is too old to count for view 10
+        assert!(!blacklist.is_blacklisted(10, &peers[1], &failures));
+        assert_eq!(blacklist.apply(10, 1, &peers, &failures), 1);
+
+        // Disabled with a threshold of 0
+        let disabled = Blacklist {
+            threshold: 0,
+            cooldown: 8,
+        };
+        assert_eq!(disabled.apply(9, 1, &peers, &failures), 1);
+    }
+}
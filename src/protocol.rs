@@ -0,0 +1,297 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! The PBFT protocol's core rules, apart from any node
+//!
+//! These are the parts of the protocol that only depend on numbers a node already has: how many
+//! nodes a network tolerates being faulty and how many make a quorum, the order of the phases, and
+//! whether a message is for the past, the present, or the future. None of it does I/O, allocates,
+//! logs, or depends on the Sawtooth SDK or the engine's features, and it only uses what's in
+//! `core`; so verification tools, light clients (including ones compiled to WASM), and
+//! property-based tests can use the same rules as the engine, and the module can be moved into a
+//! `no_std` crate as it is.
+
+use std::fmt;
+
+/// Phases of the PBFT algorithm, in `Normal` mode
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum PbftPhase {
+    NotStarted,
+    PrePreparing,
+    Preparing,
+    Checking,
+    Committing,
+    Finished,
+}
+
+/// Modes that the PBFT algorithm can possibly be in
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PbftMode {
+    Normal,
+    ViewChanging,
+
+    /// Fell too far behind the rest of the network to take part in consensus, and is committing
+    /// the missed blocks from seals
+    Recovering,
+
+    /// Isn't in the network's membership, because it was removed or started as an observer, so it
+    /// doesn't publish blocks, vote, or take part in view changes; it only follows the chain,
+    /// committing blocks the members agreed on
+    NonVoting,
+}
+
+/// Enum for showing the difference between future messages, present messages, and past messages.
+#[derive(Debug, PartialEq)]
+pub enum PbftHint {
+    /// A future message. The node is not ready to process it yet.
+    FutureMessage,
+
+    /// A past message. It's possible the node may still need it though, so it is added to the log.
+    PastMessage,
+
+    /// A present message. The node is ready to process this message immediately.
+    PresentMessage,
+}
+
+// Messages related to PBFT consensus
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum PbftMessageType {
+    /// Basic message types for the multicast protocol
+    PrePrepare,
+    Prepare,
+    Commit,
+
+    /// Auxiliary PBFT messages
+    BlockNew,
+    Checkpoint,
+    ViewChange,
+    NewView,
+    Probe,
+    ProbeResponse,
+    StateRequest,
+    StateResponse,
+    Handoff,
+    Heartbeat,
+    RetransmitRequest,
+    ClockSync,
+    ClockSyncResponse,
+
+    Unset,
+}
+
+impl fmt::Display for PbftMessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let txt = match self {
+            PbftMessageType::PrePrepare => "PP",
+            PbftMessageType::Prepare => "Pr",
+            PbftMessageType::Commit => "Co",
+            PbftMessageType::BlockNew => "BN",
+            PbftMessageType::Checkpoint => "CP",
+            PbftMessageType::ViewChange => "VC",
+            PbftMessageType::NewView => "NV",
+            PbftMessageType::Probe => "PB",
+            PbftMessageType::ProbeResponse => "PR",
+            PbftMessageType::StateRequest => "SQ",
+            PbftMessageType::StateResponse => "SR",
+            PbftMessageType::Handoff => "HO",
+            PbftMessageType::Heartbeat => "HB",
+            PbftMessageType::RetransmitRequest => "RR",
+            PbftMessageType::ClockSync => "CS",
+            PbftMessageType::ClockSyncResponse => "CR",
+            PbftMessageType::Unset => "Un",
+        };
+        write!(f, "{}", txt)
+    }
+}
+
+impl PbftMessageType {
+    /// Is the message type a multicast message (`PrePrepare`, `Prepare`, or `Commit`)?
+    pub fn is_multicast(&self) -> bool {
+        match self {
+            PbftMessageType::PrePrepare | PbftMessageType::Prepare | PbftMessageType::Commit => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The most faulty nodes a network of `num_nodes` nodes tolerates: `floor((n - 1) / 3)`, or fewer
+/// if `configured`
+pub fn tolerated_faults(num_nodes: u64, configured: Option<u64>) -> u64 {
+    let max_faulty = num_nodes.saturating_sub(1) / 3;
+    configured.map_or(max_faulty, |f| f.min(max_faulty))
+}
+
+/// How many nodes have to agree for a decision to stand: `2f + 1`, unless `f` is lower than the
+/// network could tolerate. Then it's `n - f`, so that any two quorums still have at least `f + 1`
+/// nodes in common.
+pub fn quorum(num_nodes: u64, f: u64) -> u64 {
+    if f < num_nodes.saturating_sub(1) / 3 {
+        num_nodes - f
+    } else {
+        2 * f + 1
+    }
+}
+
+/// How many nodes have to report the same thing for at least one of them to be honest: `f + 1`
+pub fn weak_quorum(f: u64) -> u64 {
+    f + 1
+}
+
+/// The phase that comes after the given one, in `Normal` mode
+pub fn next_phase(phase: &PbftPhase) -> PbftPhase {
+    match phase {
+        PbftPhase::NotStarted => PbftPhase::PrePreparing,
+        PbftPhase::PrePreparing => PbftPhase::Preparing,
+        PbftPhase::Preparing => PbftPhase::Checking,
+        PbftPhase::Checking => PbftPhase::Committing,
+        PbftPhase::Committing => PbftPhase::Finished,
+        PbftPhase::Finished => PbftPhase::NotStarted,
+    }
+}
+
+/// The type of message a node in the given phase is waiting for (`Unset` if it isn't waiting for
+/// a multicast message)
+pub fn expected_msg_type(phase: &PbftPhase) -> PbftMessageType {
+    match phase {
+        PbftPhase::PrePreparing => PbftMessageType::PrePrepare,
+        PbftPhase::Preparing => PbftMessageType::Prepare,
+        PbftPhase::Checking => PbftMessageType::Prepare,
+        PbftPhase::Committing => PbftMessageType::Commit,
+        _ => PbftMessageType::Unset,
+    }
+}
+
+/// Decide if a multicast message (`PrePrepare`, `Prepare`, or `Commit`) with the given type and
+/// sequence number is for the past, the present, or the future of a node at `seq_num`, in
+/// `phase`. A node without a working block can't use any message for its own sequence number yet,
+/// so those only go in its log.
+pub fn classify_multicast(
+    msg_type: &PbftMessageType,
+    msg_seq_num: u64,
+    seq_num: u64,
+    phase: &PbftPhase,
+    has_working_block: bool,
+) -> PbftHint {
+    if msg_seq_num > seq_num {
+        return PbftHint::FutureMessage;
+    }
+    if msg_seq_num < seq_num || !has_working_block {
+        return PbftHint::PastMessage;
+    }
+
+    let expected = expected_msg_type(phase);
+    if *msg_type < expected {
+        PbftHint::PastMessage
+    } else if *msg_type > expected {
+        PbftHint::FutureMessage
+    } else {
+        PbftHint::PresentMessage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that faulty nodes can't hold up a quorum, and that when `f` is configured lower
+    /// than the network could tolerate, any two quorums still share at least `f + 1` nodes
+    #[test]
+    fn quorum_sizes() {
+        for n in 1..50 {
+            let max_faulty = tolerated_faults(n, None);
+            assert_eq!(max_faulty, (n - 1) / 3);
+            for configured in 0..=max_faulty + 1 {
+                let f = tolerated_faults(n, Some(configured));
+                assert!(f <= max_faulty);
+                let q = quorum(n, f);
+                assert!(q <= n - f, "n = {}, f = {}: faulty nodes can block", n, f);
+                if f < max_faulty {
+                    assert!(2 * q >= n + f + 1, "n = {}, f = {}: too small", n, f);
+                }
+            }
+        }
+        assert_eq!(quorum(4, 1), 3);
+        assert_eq!(quorum(6, 1), 3);
+        assert_eq!(quorum(7, 1), 6);
+        assert_eq!(quorum(7, 2), 5);
+        assert_eq!(weak_quorum(2), 3);
+    }
+
+    /// Make sure that each phase leads to the next, and that the node comes back around to
+    /// `NotStarted` after `Finished`
+    #[test]
+    fn phase_order() {
+        let mut phase = PbftPhase::NotStarted;
+        let mut expected = vec![];
+        for _ in 0..6 {
+            phase = next_phase(&phase);
+            expected.push(expected_msg_type(&phase));
+        }
+        assert_eq!(phase, PbftPhase::NotStarted);
+        assert_eq!(
+            expected,
+            vec![
+                PbftMessageType::PrePrepare,
+                PbftMessageType::Prepare,
+                PbftMessageType::Prepare,
+                PbftMessageType::Commit,
+                PbftMessageType::Unset,
+                PbftMessageType::Unset,
+            ]
+        );
+    }
+
+    /// Make sure that messages are only for the present if they're for the node's sequence number
+    /// and the message type its phase is waiting for
+    #[test]
+    fn multicast_classification() {
+        let classify = |msg_type, msg_seq_num, phase, has_working_block| {
+            classify_multicast(&msg_type, msg_seq_num, 5, &phase, has_working_block)
+        };
+
+        assert_eq!(
+            classify(PbftMessageType::Commit, 6, PbftPhase::Committing, true),
+            PbftHint::FutureMessage
+        );
+        assert_eq!(
+            classify(PbftMessageType::Commit, 4, PbftPhase::Committing, true),
+            PbftHint::PastMessage
+        );
+        assert_eq!(
+            classify(PbftMessageType::Commit, 5, PbftPhase::Committing, false),
+            PbftHint::PastMessage
+        );
+        assert_eq!(
+            classify(PbftMessageType::Prepare, 5, PbftPhase::Committing, true),
+            PbftHint::PastMessage
+        );
+        assert_eq!(
+            classify(PbftMessageType::Commit, 5, PbftPhase::Preparing, true),
+            PbftHint::FutureMessage
+        );
+        assert_eq!(
+            classify(PbftMessageType::Prepare, 5, PbftPhase::Checking, true),
+            PbftHint::PresentMessage
+        );
+        assert_eq!(
+            classify(PbftMessageType::Commit, 5, PbftPhase::Finished, true),
+            PbftHint::PastMessage
+        );
+    }
+}
@@ -15,5 +15,9 @@
  * -----------------------------------------------------------------------------
  */
 
-// Includes the autogenerated protobuf messages
-include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
+// The protobuf messages are generated in their own crate, so tools can parse PBFT payloads
+// without depending on this one
+pub use sawtooth_pbft_protos::pbft_admin;
+#[cfg(feature = "grpc-admin")]
+pub use sawtooth_pbft_protos::pbft_admin_grpc;
+pub use sawtooth_pbft_protos::pbft_message;
@@ -0,0 +1,159 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Ignoring nodes that keep sending messages that can't be used
+//!
+//! A node with a bug, or a malicious one, can send a steady stream of messages that fail to
+//! decode or validate. Each one still costs this node a signature check, a parse, and an error in
+//! the log. The `Quarantine` counts such failures for each node, and once a node has had
+//! `MAX_FAILURES` of them within `FAILURE_WINDOW`, its messages are dropped as soon as they arrive
+//! (though still counted) for `QUARANTINE_DURATION`. After that, the node starts over with a
+//! clean record.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use timing;
+
+/// How many bad messages a node can send within `FAILURE_WINDOW` before it's quarantined
+pub const MAX_FAILURES: usize = 20;
+
+/// How far back bad messages are counted
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a node's messages are ignored once it's quarantined
+pub const QUARANTINE_DURATION: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default)]
+struct PeerRecord {
+    /// When each recent bad message arrived, oldest first
+    failures: VecDeque<Instant>,
+
+    /// Until when the node's messages are ignored, if it's quarantined
+    until: Option<Instant>,
+}
+
+/// Keeps track of which nodes are sending bad messages, and which ones to ignore for it
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    peers: HashMap<PeerId, PeerRecord>,
+}
+
+impl Quarantine {
+    pub fn new() -> Self {
+        Quarantine::default()
+    }
+
+    /// Check whether the given node's messages are being ignored. A node whose quarantine has
+    /// run out is released.
+    pub fn is_quarantined(&mut self, peer_id: &PeerId) -> bool {
+        let now = timing::now();
+        let record = match self.peers.get_mut(peer_id) {
+            Some(record) => record,
+            None => return false,
+        };
+
+        match record.until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                info!("Releasing {:?} from quarantine", peer_id);
+                record.until = None;
+                record.failures.clear();
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record that the given node sent a message that couldn't be decoded or validated. Returns
+    /// `true` if this put the node in quarantine.
+    pub fn record_failure(&mut self, peer_id: &PeerId) -> bool {
+        let now = timing::now();
+        let record = self.peers.entry(peer_id.clone()).or_default();
+        if record.until.is_some() {
+            return false;
+        }
+
+        record.failures.push_back(now);
+        while record
+            .failures
+            .front()
+            .map_or(false, |failed| now - *failed > FAILURE_WINDOW)
+        {
+            record.failures.pop_front();
+        }
+
+        if record.failures.len() < MAX_FAILURES {
+            return false;
+        }
+        record.until = Some(now + QUARANTINE_DURATION);
+        true
+    }
+
+    /// The nodes that are quarantined now
+    pub fn quarantined(&self) -> Vec<&PeerId> {
+        let now = timing::now();
+        let mut peers: Vec<&PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, record)| record.until.map_or(false, |until| now < until))
+            .map(|(peer_id, _)| peer_id)
+            .collect();
+        peers.sort();
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that a node is quarantined once it sends enough bad messages close together, but
+    /// not if they're spread out, and that it's released once its quarantine is over
+    #[test]
+    fn quarantine() {
+        timing::start_virtual_time(1);
+        let faulty = PeerId::from(vec![1]);
+        let sloppy = PeerId::from(vec![2]);
+        let mut quarantine = Quarantine::new();
+
+        // Bad messages spread out over more than the window don't add up
+        for _ in 0..MAX_FAILURES * 2 {
+            assert!(!quarantine.record_failure(&sloppy));
+            timing::set_virtual_time(timing::now() + FAILURE_WINDOW / 10);
+        }
+        assert!(!quarantine.is_quarantined(&sloppy));
+
+        for _ in 0..MAX_FAILURES - 1 {
+            assert!(!quarantine.record_failure(&faulty));
+        }
+        assert!(!quarantine.is_quarantined(&faulty));
+        assert!(quarantine.record_failure(&faulty));
+        assert!(quarantine.is_quarantined(&faulty));
+        assert_eq!(quarantine.quarantined(), vec![&faulty]);
+
+        // More bad messages don't report the quarantine again
+        assert!(!quarantine.record_failure(&faulty));
+
+        timing::set_virtual_time(timing::now() + QUARANTINE_DURATION);
+        assert!(!quarantine.is_quarantined(&faulty));
+        assert!(quarantine.quarantined().is_empty());
+        assert!(!quarantine.record_failure(&faulty));
+    }
+}
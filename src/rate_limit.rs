@@ -0,0 +1,123 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Per-node limits on how fast messages are accepted
+//!
+//! Each node gets a token bucket for each message type that has a limit. The bucket holds up to
+//! one second's worth of messages and refills at the configured rate; a message that arrives when
+//! its bucket is empty is dropped before it is verified or parsed, so a node that floods the
+//! network can't keep this node from making progress on the messages of the others.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use config::PbftConfig;
+use timing;
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Finds messages from nodes that send them faster than the configured rates
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Messages per second allowed from each node, by message type
+    limits: HashMap<String, f64>,
+
+    /// Each node's bucket for each limited message type
+    buckets: HashMap<(PeerId, String), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &PbftConfig) -> Self {
+        RateLimiter {
+            limits: config.rate_limits.clone(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for a message of the given type from the given node. Returns `false` if the
+    /// node is over its limit for that type.
+    pub fn allow(&mut self, sender_id: &PeerId, msg_type: &str) -> bool {
+        self.allow_at(sender_id, msg_type, timing::now())
+    }
+
+    fn allow_at(&mut self, sender_id: &PeerId, msg_type: &str, now: Instant) -> bool {
+        let rate = match self.limits.get(msg_type) {
+            Some(rate) => *rate,
+            None => return true,
+        };
+        let capacity = rate.max(1.0);
+
+        let bucket = self
+            .buckets
+            .entry((sender_id.clone(), msg_type.to_string()))
+            .or_insert_with(|| TokenBucket {
+                tokens: capacity,
+                last_refill: now,
+            });
+
+        let elapsed = now.duration_since(bucket.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use std::time::Duration;
+
+    /// Make sure that each node can only send as many messages of a limited type as its bucket
+    /// allows, that buckets refill over time, and that other nodes and types aren't affected
+    #[test]
+    fn rate_limiter() {
+        let mut cfg = mock_config(4);
+        cfg.rate_limits.insert(String::from("Commit"), 2.0);
+        let mut limiter = RateLimiter::new(&cfg);
+        let start = Instant::now();
+
+        // A full bucket allows a burst of one second's worth of messages
+        assert!(limiter.allow_at(&cfg.peers[1], "Commit", start));
+        assert!(limiter.allow_at(&cfg.peers[1], "Commit", start));
+        assert!(!limiter.allow_at(&cfg.peers[1], "Commit", start));
+
+        // Other nodes and unlimited message types aren't affected
+        assert!(limiter.allow_at(&cfg.peers[2], "Commit", start));
+        for _ in 0..10 {
+            assert!(limiter.allow_at(&cfg.peers[1], "Prepare", start));
+        }
+
+        // Half a second later, one more token has been added
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow_at(&cfg.peers[1], "Commit", later));
+        assert!(!limiter.allow_at(&cfg.peers[1], "Commit", later));
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! The engine's logger, which also keeps the most recent log entries in memory
+//!
+//! Entries are written to standard output, one per line, with the time (in UTC), the level, and
+//! the module they came from. The last `RECENT_ENTRIES` of them are also kept, so that a crash
+//! dump written when the engine panics can show what led up to it, even if the output itself
+//! wasn't kept.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, TryLockError};
+
+use log::{self, Level, Log, Metadata, Record, SetLoggerError};
+
+use timing;
+
+/// How many of the most recent log entries are kept
+pub const RECENT_ENTRIES: usize = 1000;
+
+/// Writes log entries to standard output, keeping the most recent ones
+#[derive(Debug)]
+pub struct RecentLog {
+    level: Level,
+    capacity: usize,
+    entries: Mutex<VecDeque<String>>,
+}
+
+impl RecentLog {
+    pub fn new(level: Level, capacity: usize) -> Self {
+        RecentLog {
+            level,
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Make a `RecentLog` the logger for the whole process, for entries at the given level or
+    /// more severe, and return it so its entries can be read back
+    pub fn init(level: Level) -> Result<&'static RecentLog, SetLoggerError> {
+        let logger: &'static RecentLog = Box::leak(Box::new(RecentLog::new(level, RECENT_ENTRIES)));
+        log::set_logger(logger)?;
+        log::set_max_level(level.to_level_filter());
+        Ok(logger)
+    }
+
+    /// The most recent entries, oldest first. This doesn't wait for an entry that's being added,
+    /// since it's called while panicking, which may be what interrupted that entry.
+    pub fn recent(&self) -> Vec<String> {
+        match self.entries.try_lock() {
+            Ok(entries) => entries.iter().cloned().collect(),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                poisoned.into_inner().iter().cloned().collect()
+            }
+            Err(TryLockError::WouldBlock) => vec![],
+        }
+    }
+
+    fn keep(&self, entry: String) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+}
+
+impl Log for RecentLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let entry = format!(
+            "{} {:<5} [{}] {}",
+            format_timestamp(timing::unix_millis()),
+            record.level().to_string(),
+            record.module_path().unwrap_or_default(),
+            record.args()
+        );
+        println!("{}", entry);
+        if self.capacity > 0 {
+            self.keep(entry);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Write the given time, in milliseconds since the Unix epoch, as a UTC date and time
+fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let secs_of_day = secs % 86_400;
+
+    // Days since the epoch to a date, counting from 0000-03-01 so leap days come last
+    let days = secs / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02},{:03}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that only the most recent entries are kept, oldest first, and that times are
+    /// written as UTC dates, leap days included
+    #[test]
+    fn recent_log() {
+        let log = RecentLog::new(Level::Info, 3);
+        assert!(log.recent().is_empty());
+        for i in 0..5 {
+            log.keep(format!("entry {}", i));
+        }
+        assert_eq!(log.recent(), vec!["entry 2", "entry 3", "entry 4"]);
+
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00,000");
+        assert_eq!(format_timestamp(951_786_123_004), "2000-02-29 01:02:03,004");
+        assert_eq!(
+            format_timestamp(1_538_395_200_123),
+            "2018-10-01 12:00:00,123"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Protection against stale and replayed peer messages
+//!
+//! A message that was valid when it was first sent can be sent again later, either by a faulty
+//! node or by a node that is relaying it. Before a peer message is handled, it is dropped if it's
+//! for a view or sequence number too far behind or ahead of this node's own, or if an identical
+//! copy of it was received recently.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use protos::pbft_message::PbftMessageInfo;
+
+use config::PbftConfig;
+use message_type::PbftMessageType;
+use validation::Rejection;
+
+/// Finds stale and replayed messages
+pub struct ReplayFilter {
+    /// How far behind or ahead of this node's view or sequence number a message can be
+    window: u64,
+
+    /// How many message digests to remember
+    capacity: usize,
+
+    /// Keyed hasher for message digests, so other nodes can't craft messages that collide
+    hasher: RandomState,
+
+    /// Digests of recently received messages
+    digests: HashSet<u64>,
+
+    /// The same digests, oldest first, so the oldest can be forgotten once there are too many
+    order: VecDeque<u64>,
+}
+
+impl fmt::Debug for ReplayFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReplayFilter")
+            .field("window", &self.window)
+            .field("capacity", &self.capacity)
+            .field("remembered", &self.order.len())
+            .finish()
+    }
+}
+
+impl ReplayFilter {
+    pub fn new(config: &PbftConfig) -> Self {
+        ReplayFilter {
+            window: config.message_window,
+            capacity: config.replay_cache_size as usize,
+            hasher: RandomState::new(),
+            digests: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Check a received message against this node's current view and sequence number, and
+    /// against the messages received recently, and remember it so later copies can be dropped.
+    pub fn check(
+        &mut self,
+        msg_type: &PbftMessageType,
+        info: &PbftMessageInfo,
+        msg_bytes: &[u8],
+        view: u64,
+        seq_num: u64,
+    ) -> Result<(), Rejection> {
+        if self.is_stale(msg_type, info, view, seq_num) {
+            return Err(Rejection::Stale);
+        }
+
+        if self.is_ahead(msg_type, info, view, seq_num) {
+            return Err(Rejection::Ahead);
+        }
+
+        if !is_unique(msg_type) {
+            return Ok(());
+        }
+
+        let mut hasher = self.hasher.build_hasher();
+        String::from(msg_type).hash(&mut hasher);
+        msg_bytes.hash(&mut hasher);
+        let digest = hasher.finish();
+
+        if !self.digests.insert(digest) {
+            return Err(Rejection::Replayed);
+        }
+
+        self.order.push_back(digest);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.digests.remove(&oldest);
+            }
+        }
+
+        Ok(())
+    }
+
+    // `StateRequest`s are never stale, since they come from nodes that are behind. A `ViewChange`'s
+    // sequence number is its sender's last stable checkpoint, not the block it's on, so an honest
+    // one can be far behind; only its view is checked.
+    fn is_stale(
+        &self,
+        msg_type: &PbftMessageType,
+        info: &PbftMessageInfo,
+        view: u64,
+        seq_num: u64,
+    ) -> bool {
+        if msg_type == &PbftMessageType::StateRequest {
+            return false;
+        }
+
+        let stale_view = info.get_view().saturating_add(self.window) < view;
+        if msg_type == &PbftMessageType::ViewChange {
+            return stale_view;
+        }
+        stale_view || info.get_seq_num().saturating_add(self.window) < seq_num
+    }
+
+    // `StateResponse`s are never too far ahead, since they're what a node that's behind catches up
+    // with
+    fn is_ahead(
+        &self,
+        msg_type: &PbftMessageType,
+        info: &PbftMessageInfo,
+        view: u64,
+        seq_num: u64,
+    ) -> bool {
+        if msg_type == &PbftMessageType::StateResponse {
+            return false;
+        }
+
+        info.get_view() > view.saturating_add(self.window)
+            || info.get_seq_num() > seq_num.saturating_add(self.window)
+    }
+}
+
+// Whether a node sends each message of this type only once. The other types can legitimately be
+// sent again with the same contents (a `Probe`, `StateRequest` or `RetransmitRequest` that got no
+// answer, the answers to them, and `Heartbeat`s while nothing changes), or are only used for their
+// timing (`ClockSync`s), so they aren't checked for replays.
+fn is_unique(msg_type: &PbftMessageType) -> bool {
+    match msg_type {
+        PbftMessageType::Probe
+        | PbftMessageType::ProbeResponse
+        | PbftMessageType::Heartbeat
+        | PbftMessageType::ClockSync
+        | PbftMessageType::ClockSyncResponse
+        | PbftMessageType::RetransmitRequest
+        | PbftMessageType::StateRequest
+        | PbftMessageType::StateResponse => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use handlers::make_msg_info;
+
+    /// Make sure that messages too far behind or ahead of the current view or sequence number,
+    /// and copies of messages that were already received, are dropped
+    #[test]
+    fn replay_filter() {
+        let mut cfg = mock_config(4);
+        cfg.message_window = 2;
+        cfg.replay_cache_size = 2;
+        let mut filter = ReplayFilter::new(&cfg);
+        let peer_id = cfg.peers[1].clone();
+
+        let msg_type = PbftMessageType::Commit;
+        let info = |view, seq_num| make_msg_info(&msg_type, view, seq_num, peer_id.clone());
+
+        // Within the window
+        assert_eq!(filter.check(&msg_type, &info(3, 8), b"a", 5, 10), Ok(()));
+
+        // Too far behind in view, then in sequence number
+        assert_eq!(
+            filter.check(&msg_type, &info(2, 10), b"b", 5, 10),
+            Err(Rejection::Stale)
+        );
+        assert_eq!(
+            filter.check(&msg_type, &info(5, 7), b"c", 5, 10),
+            Err(Rejection::Stale)
+        );
+
+        // Too far ahead in view, then in sequence number
+        assert_eq!(
+            filter.check(&msg_type, &info(50, 10), b"d", 5, 10),
+            Err(Rejection::Ahead)
+        );
+        assert_eq!(
+            filter.check(&msg_type, &info(5, 100), b"d", 5, 10),
+            Err(Rejection::Ahead)
+        );
+
+        // A copy of a message that was just received
+        assert_eq!(filter.check(&msg_type, &info(7, 12), b"d", 5, 10), Ok(()));
+        assert_eq!(
+            filter.check(&msg_type, &info(7, 12), b"d", 5, 10),
+            Err(Rejection::Replayed)
+        );
+
+        // Once enough newer messages have been received, old ones are forgotten
+        assert_eq!(filter.check(&msg_type, &info(5, 10), b"e", 5, 10), Ok(()));
+        assert_eq!(filter.check(&msg_type, &info(3, 8), b"a", 5, 10), Ok(()));
+
+        // Messages that can be sent more than once aren't replays
+        let probe = PbftMessageType::Probe;
+        let probe_info = make_msg_info(&probe, 5, 10, peer_id.clone());
+        assert_eq!(filter.check(&probe, &probe_info, b"f", 5, 10), Ok(()));
+        assert_eq!(filter.check(&probe, &probe_info, b"f", 5, 10), Ok(()));
+
+        // Nor is asking again for messages that didn't arrive
+        let request = PbftMessageType::RetransmitRequest;
+        let request_info = make_msg_info(&request, 5, 10, peer_id);
+        assert_eq!(filter.check(&request, &request_info, b"g", 5, 10), Ok(()));
+        assert_eq!(filter.check(&request, &request_info, b"g", 5, 10), Ok(()));
+    }
+
+    /// Make sure that the window includes its edges on both sides, and that views and sequence
+    /// numbers near the largest possible value don't overflow
+    #[test]
+    fn replay_filter_edges() {
+        let mut cfg = mock_config(4);
+        cfg.message_window = 2;
+        let mut filter = ReplayFilter::new(&cfg);
+        let peer_id = cfg.peers[1].clone();
+
+        let msg_type = PbftMessageType::Commit;
+        let info = |view, seq_num| make_msg_info(&msg_type, view, seq_num, peer_id.clone());
+
+        // Exactly the window behind or ahead, then one more
+        assert_eq!(filter.check(&msg_type, &info(3, 8), b"a", 5, 10), Ok(()));
+        assert_eq!(filter.check(&msg_type, &info(7, 12), b"b", 5, 10), Ok(()));
+        assert_eq!(
+            filter.check(&msg_type, &info(5, 7), b"c", 5, 10),
+            Err(Rejection::Stale)
+        );
+        assert_eq!(
+            filter.check(&msg_type, &info(5, 13), b"c", 5, 10),
+            Err(Rejection::Ahead)
+        );
+
+        // The largest values don't overflow, on either side
+        let max = u64::max_value();
+        assert_eq!(
+            filter.check(&msg_type, &info(max, max), b"d", max, max),
+            Ok(())
+        );
+        assert_eq!(
+            filter.check(&msg_type, &info(max, max), b"e", 5, 10),
+            Err(Rejection::Ahead)
+        );
+        assert_eq!(
+            filter.check(&msg_type, &info(5, 10), b"f", max, max),
+            Err(Rejection::Stale)
+        );
+
+        // A node that's behind catches up from StateResponses, however far ahead they are
+        let response = PbftMessageType::StateResponse;
+        let response_info = make_msg_info(&response, 5, 100, peer_id);
+        assert_eq!(filter.check(&response, &response_info, b"g", 5, 10), Ok(()));
+    }
+
+    /// Make sure that a `ViewChange` whose stable checkpoint is more than the window behind isn't
+    /// dropped, since that's where an honest node's checkpoint is until the next one is stable,
+    /// but that one for a view too far behind still is
+    #[test]
+    fn view_change_checkpoint() {
+        let mut cfg = mock_config(4);
+        cfg.message_window = 2;
+        let mut filter = ReplayFilter::new(&cfg);
+        let peer_id = cfg.peers[1].clone();
+
+        let view_change = PbftMessageType::ViewChange;
+        let info = |view, seq_num| make_msg_info(&view_change, view, seq_num, peer_id.clone());
+        assert_eq!(filter.check(&view_change, &info(6, 0), b"a", 5, 10), Ok(()));
+        assert_eq!(
+            filter.check(&view_change, &info(2, 10), b"b", 5, 10),
+            Err(Rejection::Stale)
+        );
+
+        // Other messages for the same sequence number are still stale
+        let commit = PbftMessageType::Commit;
+        let commit_info = make_msg_info(&commit, 5, 0, peer_id.clone());
+        assert_eq!(
+            filter.check(&commit, &commit_info, b"c", 5, 10),
+            Err(Rejection::Stale)
+        );
+    }
+}
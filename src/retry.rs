@@ -0,0 +1,212 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Retrying operations that can fail for a while, such as calls to the validator
+//!
+//! A `Retry` tries an operation until it succeeds, waiting longer after each failure according to
+//! its `RetryStrategy`, and gives up after a maximum number of attempts. The caller does the
+//! waiting, so it can stop early instead of retrying forever while, for instance, the validator is
+//! asking the engine to shut down.
+
+use std::time::Duration;
+
+/// How long to wait before each retry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryStrategy {
+    /// Double the delay after each failure: `initial`, `2 * initial`, `4 * initial`, ...
+    Exponential { initial: Duration, max: Duration },
+
+    /// Follow the Fibonacci sequence: `initial`, `initial`, `2 * initial`, `3 * initial`, ...
+    Fibonacci { initial: Duration, max: Duration },
+
+    /// Add `initial` after each failure: `initial`, `2 * initial`, `3 * initial`, ...
+    Linear { initial: Duration, max: Duration },
+}
+
+impl RetryStrategy {
+    /// How long to wait after the given number of consecutive failures, less one; so `delay(0)` is
+    /// the wait before the first retry. The delay never exceeds the strategy's `max`.
+    pub fn delay(&self, retry: u32) -> Duration {
+        match *self {
+            RetryStrategy::Exponential { initial, max } => 1u32
+                .checked_shl(retry)
+                .and_then(|factor| initial.checked_mul(factor))
+                .map_or(max, |delay| delay.min(max)),
+            RetryStrategy::Fibonacci { initial, max } => {
+                let (mut current, mut next) = (initial, initial);
+                for _ in 0..retry {
+                    if current >= max {
+                        break;
+                    }
+                    let after = current.checked_add(next).unwrap_or(max);
+                    current = next;
+                    next = after;
+                }
+                current.min(max)
+            }
+            RetryStrategy::Linear { initial, max } => initial
+                .checked_mul(retry.saturating_add(1))
+                .map_or(max, |delay| delay.min(max)),
+        }
+    }
+}
+
+/// Tries an operation until it succeeds, it has been tried too many times, or the caller stops it
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    strategy: RetryStrategy,
+
+    /// The most times the operation is tried, including the first; `None` for no limit
+    max_attempts: Option<u32>,
+}
+
+impl Retry {
+    pub fn new(strategy: RetryStrategy) -> Self {
+        Retry {
+            strategy,
+            max_attempts: None,
+        }
+    }
+
+    /// Give up after the operation has been tried this many times
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Call `attempt` until it succeeds. After each failure, `wait` is given the error and how
+    /// long to wait before trying again; it does the waiting, and returns `false` to stop retrying
+    /// (because the error won't go away, or because the caller was told to shut down).
+    ///
+    /// # Errors
+    ///
+    /// Returns the last error if `attempt` never succeeded.
+    pub fn run<T, E, F, W>(&self, mut attempt: F, mut wait: W) -> Result<T, E>
+    where
+        F: FnMut() -> Result<T, E>,
+        W: FnMut(&E, Duration) -> bool,
+    {
+        let mut failures = 0;
+        loop {
+            let err = match attempt() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            failures += 1;
+
+            if self.max_attempts.map_or(false, |max| failures >= max) {
+                return Err(err);
+            }
+            if !wait(&err, self.strategy.delay(failures - 1)) {
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that each strategy's delays grow as they should and stop at the maximum, and that
+    /// a `Retry` stops when the operation succeeds, when it runs out of attempts, and when it's
+    /// cancelled
+    #[test]
+    fn retry() {
+        let ms = Duration::from_millis;
+        let delays =
+            |strategy: RetryStrategy| (0..7).map(|i| strategy.delay(i)).collect::<Vec<_>>();
+
+        let exponential = RetryStrategy::Exponential {
+            initial: ms(10),
+            max: ms(250),
+        };
+        assert_eq!(
+            delays(exponential),
+            vec![ms(10), ms(20), ms(40), ms(80), ms(160), ms(250), ms(250)]
+        );
+        assert_eq!(exponential.delay(u32::max_value()), ms(250));
+
+        let fibonacci = RetryStrategy::Fibonacci {
+            initial: ms(10),
+            max: ms(60),
+        };
+        assert_eq!(
+            delays(fibonacci),
+            vec![ms(10), ms(10), ms(20), ms(30), ms(50), ms(60), ms(60)]
+        );
+        assert_eq!(fibonacci.delay(u32::max_value()), ms(60));
+
+        let linear = RetryStrategy::Linear {
+            initial: ms(10),
+            max: ms(45),
+        };
+        assert_eq!(
+            delays(linear),
+            vec![ms(10), ms(20), ms(30), ms(40), ms(45), ms(45), ms(45)]
+        );
+        assert_eq!(linear.delay(u32::max_value()), ms(45));
+
+        // Succeeds on the third attempt, after waiting for the first two delays
+        let retry = Retry::new(exponential).with_max_attempts(5);
+        let mut attempts = 0;
+        let mut waits = vec![];
+        let res: Result<u32, u32> = retry.run(
+            || {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(attempts)
+                } else {
+                    Ok(attempts)
+                }
+            },
+            |_, delay| {
+                waits.push(delay);
+                true
+            },
+        );
+        assert_eq!(res, Ok(3));
+        assert_eq!(waits, vec![ms(10), ms(20)]);
+
+        // Gives up with the last error once the attempts run out, without waiting after it
+        let mut attempts = 0;
+        let mut waits = 0;
+        let res: Result<(), u32> = retry.run(
+            || {
+                attempts += 1;
+                Err(attempts)
+            },
+            |_, _| {
+                waits += 1;
+                true
+            },
+        );
+        assert_eq!(res, Err(5));
+        assert_eq!(waits, 4);
+
+        // Stops as soon as the wait is cancelled
+        let mut attempts = 0;
+        let res: Result<(), u32> = Retry::new(linear).run(
+            || {
+                attempts += 1;
+                Err(attempts)
+            },
+            |&err, _| err < 2,
+        );
+        assert_eq!(res, Err(2));
+    }
+}
@@ -0,0 +1,592 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Checking that a seal proves its block was committed
+//!
+//! A seal contains `Commit` messages for its block from a quorum of the network's nodes, all in the
+//! same view and for the same sequence number. It may also contain the `NewView` message for that
+//! view, with `ViewChange` messages for it from a quorum of the network's nodes. Checking one only
+//! needs the seal and the network's members, so this module depends on nothing but the Protobuf
+//! messages and the `secp256k1` crate. The engine uses it to check the seals it catches up from,
+//! and the library exposes it (with the `seal-verification` feature) for block explorers and light
+//! clients.
+//!
+//! A message's signer ID is just a claim, so a seal is only a proof that its block was committed
+//! if every `Commit` comes with a signed copy from the member it names: `verify_consensus_seal`
+//! checks each of the seal's `signed_commit_messages` (and, if it has a `NewView`, each of its
+//! `signed_view_changes`) against the public key of the member it's from. Seals are only signed
+//! when the network has `sawtooth.consensus.pbft.authenticate_messages` enabled, so a seal from a
+//! network without it can't prove anything. The engine checks a seal's structure with
+//! `verify_seal_structure`, and the signatures itself, on several threads, when it authenticates
+//! messages.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use protobuf::{self, Message};
+
+use protos::pbft_message::{PbftSeal, PbftSignedMessage};
+use signature;
+
+/// The version of the seal format that this code makes seals in; it can verify seals of this and
+/// every earlier version
+pub const SEAL_VERSION: u32 = 1;
+
+/// Why a seal doesn't prove that its block was committed
+#[derive(Debug, PartialEq)]
+pub enum SealError {
+    /// The seal is for a different block than the one being checked
+    WrongBlock,
+
+    /// A message in the seal isn't a `Commit` for the seal's block and sequence number
+    MessageMismatch,
+
+    /// The seal's `Commit` messages aren't all from the same view (expected, got)
+    ViewMismatch(u64, u64),
+
+    /// A `Commit` in the seal is from a node that isn't a member of the network
+    UnknownSigner,
+
+    /// Too few different nodes' `Commit` messages (needed, got)
+    NotEnoughCommits(u64, u64),
+
+    /// The seal's `NewView` message doesn't prove that the view its `Commit`s are from was
+    /// changed to
+    InvalidViewEvidence,
+
+    /// A message in the seal doesn't come with a copy of it signed by the member it's from
+    InvalidSignature,
+
+    /// The seal couldn't be decoded
+    InvalidEncoding(String),
+
+    /// The seal is in a newer format than this code knows how to verify
+    UnsupportedVersion(u32),
+}
+
+impl Error for SealError {
+    fn description(&self) -> &str {
+        use self::SealError::*;
+        match self {
+            WrongBlock => "WrongBlock",
+            MessageMismatch => "MessageMismatch",
+            ViewMismatch(_, _) => "ViewMismatch",
+            UnknownSigner => "UnknownSigner",
+            NotEnoughCommits(_, _) => "NotEnoughCommits",
+            InvalidViewEvidence => "InvalidViewEvidence",
+            InvalidSignature => "InvalidSignature",
+            InvalidEncoding(_) => "InvalidEncoding",
+            UnsupportedVersion(_) => "UnsupportedVersion",
+        }
+    }
+}
+
+impl fmt::Display for SealError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SealError::WrongBlock => write!(f, "Seal is for a different block"),
+            SealError::MessageMismatch => {
+                write!(f, "Seal contains a message that isn't a matching Commit")
+            }
+            SealError::ViewMismatch(exp, got) => write!(
+                f,
+                "Seal's Commits are from different views: {} != {}",
+                exp, got
+            ),
+            SealError::UnknownSigner => {
+                write!(f, "Seal contains a Commit from a node outside the network")
+            }
+            SealError::NotEnoughCommits(exp, got) => write!(
+                f,
+                "Seal has Commits from too few nodes (expected {}, got {})",
+                exp, got
+            ),
+            SealError::InvalidViewEvidence => write!(
+                f,
+                "Seal's NewView doesn't prove the view change to the Commits' view"
+            ),
+            SealError::InvalidSignature => write!(
+                f,
+                "Seal contains a message that isn't signed by the node it's from"
+            ),
+            SealError::InvalidEncoding(err) => write!(f, "Couldn't decode seal: {}", err),
+            SealError::UnsupportedVersion(version) => write!(
+                f,
+                "Seal is in format version {}, but only versions up to {} are supported",
+                version, SEAL_VERSION
+            ),
+        }
+    }
+}
+
+/// How many different nodes' `Commit` messages a seal needs in a network of `num_members` nodes
+/// that tolerates as many faulty nodes as it can: `2f + 1`, where `f = (n - 1) / 3`
+pub fn default_quorum(num_members: usize) -> u64 {
+    2 * ((num_members as u64).saturating_sub(1) / 3) + 1
+}
+
+/// Decode a seal from its serialized form, making sure that it's in a format this code can verify
+pub fn parse_seal(bytes: &[u8]) -> Result<PbftSeal, SealError> {
+    let seal = protobuf::parse_from_bytes::<PbftSeal>(bytes)
+        .map_err(|err| SealError::InvalidEncoding(err.to_string()))?;
+    if seal.get_version() > SEAL_VERSION {
+        return Err(SealError::UnsupportedVersion(seal.get_version()));
+    }
+    Ok(seal)
+}
+
+/// Check that `seal` proves that the block with ID `block_id` was committed by the network whose
+/// members are given (their public keys, as listed in `sawtooth.consensus.pbft.peers` as of the
+/// block before it). This assumes the network tolerates as many faulty nodes as it can; a network
+/// with a lower `sawtooth.consensus.pbft.fault_tolerance` needs more `Commit`s, so use
+/// `verify_consensus_seal_with_quorum` for it.
+pub fn verify_consensus_seal(
+    seal: &PbftSeal,
+    members: &[Vec<u8>],
+    block_id: &[u8],
+) -> Result<(), SealError> {
+    verify_consensus_seal_with_quorum(seal, members, block_id, default_quorum(members.len()))
+}
+
+/// Check that `seal` proves that the block with ID `block_id` was committed, with signed `Commit`
+/// messages from at least `quorum` different members of the network
+pub fn verify_consensus_seal_with_quorum(
+    seal: &PbftSeal,
+    members: &[Vec<u8>],
+    block_id: &[u8],
+    quorum: u64,
+) -> Result<(), SealError> {
+    verify_seal_structure(seal, members, block_id, quorum)?;
+
+    verify_signed_copies(
+        seal.get_commit_messages(),
+        |commit| commit.get_info().get_signer_id(),
+        seal.get_signed_commit_messages(),
+    )?;
+    if seal.has_new_view() {
+        let new_view = seal.get_new_view();
+        verify_signed_copies(
+            new_view.get_view_changes(),
+            |vc| vc.get_info().get_signer_id(),
+            new_view.get_signed_view_changes(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Check that `seal` is consistent with the block with ID `block_id` having been committed, with
+/// `Commit` messages from at least `quorum` different members of the network, without checking
+/// any signatures. On its own, this isn't a proof that the block was committed, since anyone can
+/// make up a seal that passes it.
+pub fn verify_seal_structure(
+    seal: &PbftSeal,
+    members: &[Vec<u8>],
+    block_id: &[u8],
+    quorum: u64,
+) -> Result<(), SealError> {
+    // Versions 0 and 1 only differ in whether the version is set; a later version that changes
+    // what's in a seal gets its own checks here
+    if seal.get_version() > SEAL_VERSION {
+        return Err(SealError::UnsupportedVersion(seal.get_version()));
+    }
+
+    if seal.get_block().get_block_id() != block_id {
+        return Err(SealError::WrongBlock);
+    }
+
+    let view = seal
+        .get_commit_messages()
+        .first()
+        .map(|commit| commit.get_info().get_view())
+        .unwrap_or(0);
+
+    let mut signers: HashSet<&[u8]> = HashSet::new();
+    for commit in seal.get_commit_messages() {
+        let info = commit.get_info();
+        if info.get_msg_type() != "Commit"
+            || info.get_seq_num() != seal.get_seq_num()
+            || commit.get_block().get_block_id() != block_id
+        {
+            return Err(SealError::MessageMismatch);
+        }
+        if info.get_view() != view {
+            return Err(SealError::ViewMismatch(view, info.get_view()));
+        }
+        if !members
+            .iter()
+            .any(|member| member.as_slice() == info.get_signer_id())
+        {
+            return Err(SealError::UnknownSigner);
+        }
+        signers.insert(info.get_signer_id());
+    }
+
+    if (signers.len() as u64) < quorum {
+        return Err(SealError::NotEnoughCommits(quorum, signers.len() as u64));
+    }
+
+    if seal.has_new_view() {
+        verify_view_change_evidence(seal, members, view, quorum)?;
+    }
+
+    Ok(())
+}
+
+// Check that each of the given messages has a signed copy, in the same order, that's the message
+// in its canonical encoding and is signed by the node the message says it's from
+fn verify_signed_copies<M, F>(
+    messages: &[M],
+    signer_id: F,
+    signed: &[PbftSignedMessage],
+) -> Result<(), SealError>
+where
+    M: Message,
+    F: Fn(&M) -> &[u8],
+{
+    if messages.len() != signed.len() {
+        return Err(SealError::InvalidSignature);
+    }
+    for (msg, signed) in messages.iter().zip(signed) {
+        let msg_bytes = msg
+            .write_to_bytes()
+            .map_err(|err| SealError::InvalidEncoding(err.to_string()))?;
+        if signed.get_message() != msg_bytes.as_slice()
+            || signed.get_signer_id() != signer_id(msg)
+            || !signature::is_valid(signed)
+        {
+            return Err(SealError::InvalidSignature);
+        }
+    }
+    Ok(())
+}
+
+// Check that the seal's `NewView` message is for the given view, and contains `ViewChange`
+// messages for that view from at least `quorum` different members of the network
+fn verify_view_change_evidence(
+    seal: &PbftSeal,
+    members: &[Vec<u8>],
+    view: u64,
+    quorum: u64,
+) -> Result<(), SealError> {
+    let new_view = seal.get_new_view();
+    if new_view.get_info().get_msg_type() != "NewView" || new_view.get_info().get_view() != view {
+        return Err(SealError::InvalidViewEvidence);
+    }
+
+    let mut signers: HashSet<&[u8]> = HashSet::new();
+    for vc in new_view.get_view_changes() {
+        let info = vc.get_info();
+        if info.get_msg_type() != "ViewChange"
+            || info.get_view() != view
+            || !members
+                .iter()
+                .any(|member| member.as_slice() == info.get_signer_id())
+            || !signers.insert(info.get_signer_id())
+        {
+            return Err(SealError::InvalidViewEvidence);
+        }
+    }
+
+    if (signers.len() as u64) < quorum {
+        return Err(SealError::InvalidViewEvidence);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+    use protobuf::RepeatedField;
+    use protos::pbft_message::{
+        PbftBlock, PbftMessage, PbftMessageInfo, PbftNewView, PbftViewChange,
+    };
+    use secp256k1::{Message as SecpMessage, PublicKey, Secp256k1, SecretKey};
+    use sha2::{Digest, Sha256};
+
+    fn secret_key(member: u8) -> SecretKey {
+        SecretKey::from_slice(&[member + 1; 32]).unwrap()
+    }
+
+    // The public key of the test member with the given index
+    fn member(member: u8) -> Vec<u8> {
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret_key(member))
+            .serialize()
+            .to_vec()
+    }
+
+    // Sign a message as the test member whose public key is `signer_id`
+    fn sign<M: Message>(msg: &M, signer_id: &[u8]) -> PbftSignedMessage {
+        let index = (0..=255u8)
+            .find(|i| member(*i) == signer_id)
+            .expect("Not a test member");
+        let msg_bytes = msg.write_to_bytes().unwrap();
+        let signature = Secp256k1::signing_only().sign(
+            &SecpMessage::from_slice(&Sha256::digest(&msg_bytes)).unwrap(),
+            &secret_key(index),
+        );
+
+        let mut signed = PbftSignedMessage::new();
+        signed.set_message(msg_bytes);
+        signed.set_signer_id(signer_id.to_vec());
+        signed.set_signature(hex::encode(&signature.serialize_compact()[..]));
+        signed
+    }
+
+    // Add signed copies of all of a seal's messages, as a node that authenticates messages does
+    fn sign_seal(seal: &mut PbftSeal) {
+        let signed_commits = seal
+            .get_commit_messages()
+            .iter()
+            .map(|commit| sign(commit, commit.get_info().get_signer_id()))
+            .collect();
+        seal.set_signed_commit_messages(RepeatedField::from_vec(signed_commits));
+        if seal.has_new_view() {
+            let signed_view_changes = seal
+                .get_new_view()
+                .get_view_changes()
+                .iter()
+                .map(|vc| sign(vc, vc.get_info().get_signer_id()))
+                .collect();
+            seal.mut_new_view()
+                .set_signed_view_changes(RepeatedField::from_vec(signed_view_changes));
+        }
+    }
+
+    fn commit(view: u64, seq_num: u64, block_id: &[u8], signer: u8) -> PbftMessage {
+        let mut info = PbftMessageInfo::new();
+        info.set_msg_type(String::from("Commit"));
+        info.set_view(view);
+        info.set_seq_num(seq_num);
+        info.set_signer_id(member(signer));
+        let mut block = PbftBlock::new();
+        block.set_block_id(block_id.to_vec());
+        let mut msg = PbftMessage::new();
+        msg.set_info(info);
+        msg.set_block(block);
+        msg
+    }
+
+    fn seal(commits: Vec<PbftMessage>) -> PbftSeal {
+        let mut block = PbftBlock::new();
+        block.set_block_id(b"block".to_vec());
+        let mut seal = PbftSeal::new();
+        seal.set_seq_num(3);
+        seal.set_block(block);
+        seal.set_commit_messages(RepeatedField::from_vec(commits));
+        sign_seal(&mut seal);
+        seal
+    }
+
+    /// Make sure that a seal is only accepted for its own block, with matching `Commit`s from
+    /// enough different members of the network
+    #[test]
+    fn verify_consensus_seal_checks() {
+        let members: Vec<Vec<u8>> = (0..4).map(member).collect();
+        let check = |last: PbftMessage| {
+            verify_consensus_seal(
+                &seal(vec![
+                    commit(0, 3, b"block", 0),
+                    commit(0, 3, b"block", 1),
+                    last,
+                ]),
+                &members,
+                b"block",
+            )
+        };
+
+        assert_eq!(default_quorum(1), 1);
+        assert_eq!(default_quorum(4), 3);
+        assert_eq!(check(commit(0, 3, b"block", 2)), Ok(()));
+        assert_eq!(
+            verify_consensus_seal(&seal(vec![commit(0, 3, b"block", 0)]), &members, b"other"),
+            Err(SealError::WrongBlock)
+        );
+        assert_eq!(
+            check(commit(0, 3, b"block", 1)),
+            Err(SealError::NotEnoughCommits(3, 2))
+        );
+        assert_eq!(
+            check(commit(0, 4, b"block", 2)),
+            Err(SealError::MessageMismatch)
+        );
+        assert_eq!(
+            check(commit(0, 3, b"other", 2)),
+            Err(SealError::MessageMismatch)
+        );
+        assert_eq!(
+            check(commit(1, 3, b"block", 2)),
+            Err(SealError::ViewMismatch(0, 1))
+        );
+        assert_eq!(
+            check(commit(0, 3, b"block", 7)),
+            Err(SealError::UnknownSigner)
+        );
+    }
+
+    /// Make sure that a seal is only accepted if each of its `Commit`s comes with a copy of it
+    /// signed by the member it's from, so a seal can't be made up by anyone but a quorum of the
+    /// network's members; the structural checks alone accept a seal without signatures
+    #[test]
+    fn verify_consensus_seal_signatures() {
+        let members: Vec<Vec<u8>> = (0..4).map(member).collect();
+        let valid = seal((0..3).map(|i| commit(0, 3, b"block", i)).collect());
+        assert_eq!(verify_consensus_seal(&valid, &members, b"block"), Ok(()));
+
+        // The seal isn't signed at all, or one of its Commits isn't
+        let mut unsigned = valid.clone();
+        unsigned.clear_signed_commit_messages();
+        assert_eq!(
+            verify_consensus_seal(&unsigned, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_seal_structure(&unsigned, &members, b"block", 3),
+            Ok(())
+        );
+        unsigned = valid.clone();
+        unsigned.mut_signed_commit_messages().pop();
+        assert_eq!(
+            verify_consensus_seal(&unsigned, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+
+        // A signed copy is of a different message than its Commit, or names a different signer
+        let mut mismatched = valid.clone();
+        let other = sign(&commit(0, 4, b"block", 2), &member(2));
+        mismatched.mut_signed_commit_messages()[2] = other;
+        assert_eq!(
+            verify_consensus_seal(&mismatched, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+        mismatched = valid.clone();
+        mismatched.mut_signed_commit_messages()[2].set_signer_id(member(3));
+        assert_eq!(
+            verify_consensus_seal(&mismatched, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+
+        // A Commit's signature was made up, or made by a different member than the one it names
+        let mut forged = valid.clone();
+        forged.mut_signed_commit_messages()[1].set_signature(String::from("00"));
+        assert_eq!(
+            verify_consensus_seal(&forged, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+        forged = valid.clone();
+        let signature = forged.get_signed_commit_messages()[0]
+            .get_signature()
+            .to_string();
+        forged.mut_signed_commit_messages()[1].set_signature(signature);
+        assert_eq!(
+            verify_consensus_seal(&forged, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+    }
+
+    /// Make sure that a seal's `NewView` is only accepted if it proves the change to the view of the
+    /// seal's `Commit`s, with `ViewChange`s signed by the members they're from
+    #[test]
+    fn verify_view_change_evidence_checks() {
+        let members: Vec<Vec<u8>> = (0..4).map(member).collect();
+        let make_seal = |view: u64, view_changes: Vec<(u64, u8)>| {
+            let mut new_view = PbftNewView::new();
+            new_view.mut_info().set_msg_type(String::from("NewView"));
+            new_view.mut_info().set_view(view);
+            new_view.set_view_changes(RepeatedField::from_vec(
+                view_changes
+                    .into_iter()
+                    .map(|(view, signer)| {
+                        let mut vc = PbftViewChange::new();
+                        vc.mut_info().set_msg_type(String::from("ViewChange"));
+                        vc.mut_info().set_view(view);
+                        vc.mut_info().set_signer_id(member(signer));
+                        vc
+                    })
+                    .collect(),
+            ));
+
+            let mut seal = seal((0..3).map(|i| commit(2, 3, b"block", i)).collect());
+            seal.set_new_view(new_view);
+            sign_seal(&mut seal);
+            seal
+        };
+        let check = |view: u64, view_changes: Vec<(u64, u8)>| {
+            verify_consensus_seal(&make_seal(view, view_changes), &members, b"block")
+        };
+
+        assert_eq!(check(2, vec![(2, 0), (2, 1), (2, 3)]), Ok(()));
+        assert_eq!(
+            check(1, vec![(1, 0), (1, 1), (1, 3)]),
+            Err(SealError::InvalidViewEvidence)
+        );
+        assert_eq!(
+            check(2, vec![(2, 0), (2, 1), (1, 3)]),
+            Err(SealError::InvalidViewEvidence)
+        );
+        assert_eq!(
+            check(2, vec![(2, 0), (2, 1), (2, 1)]),
+            Err(SealError::InvalidViewEvidence)
+        );
+        assert_eq!(
+            check(2, vec![(2, 0), (2, 1), (2, 7)]),
+            Err(SealError::InvalidViewEvidence)
+        );
+
+        // The ViewChanges aren't signed
+        let mut unsigned = make_seal(2, vec![(2, 0), (2, 1), (2, 3)]);
+        unsigned.mut_new_view().clear_signed_view_changes();
+        assert_eq!(
+            verify_consensus_seal(&unsigned, &members, b"block"),
+            Err(SealError::InvalidSignature)
+        );
+    }
+
+    /// Make sure that seals from before the version was added and seals of the current version
+    /// are accepted, but seals of a later version are rejected rather than checked as if they were
+    /// the current version
+    #[test]
+    fn parse_seal_versions() {
+        let mut seal = seal((0..3).map(|i| commit(0, 3, b"block", i)).collect());
+        let members: Vec<Vec<u8>> = (0..4).map(member).collect();
+
+        for version in 0..=SEAL_VERSION {
+            seal.set_version(version);
+            let parsed = parse_seal(&seal.write_to_bytes().unwrap()).unwrap();
+            assert_eq!(parsed, seal);
+            assert_eq!(verify_consensus_seal(&parsed, &members, b"block"), Ok(()));
+        }
+
+        seal.set_version(SEAL_VERSION + 1);
+        assert_eq!(
+            parse_seal(&seal.write_to_bytes().unwrap()),
+            Err(SealError::UnsupportedVersion(SEAL_VERSION + 1))
+        );
+        assert_eq!(
+            verify_consensus_seal(&seal, &members, b"block"),
+            Err(SealError::UnsupportedVersion(SEAL_VERSION + 1))
+        );
+
+        match parse_seal(b"not a seal") {
+            Err(SealError::InvalidEncoding(_)) => {}
+            other => panic!("Expected InvalidEncoding, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Checking the secp256k1 signature on a `PbftSignedMessage`
+//!
+//! This is the same check that the Sawtooth SDK's secp256k1 context makes (the signature is over
+//! the SHA-256 hash of the message, in compact form and hex-encoded), but it only needs the
+//! `secp256k1` crate, so seal verification can use it without the SDK. Message authentication in
+//! the engine uses it as well, so both check signatures the same way.
+
+use hex;
+use secp256k1::{Message, PublicKey, Secp256k1, Signature};
+use sha2::{Digest, Sha256};
+
+use protos::pbft_message::PbftSignedMessage;
+
+/// Check that a signed message's signature was made over its message with the private key for the
+/// public key in its `signer_id`. A signer ID that isn't a valid public key, or a signature that
+/// isn't well-formed, doesn't check out.
+pub fn is_valid(signed: &PbftSignedMessage) -> bool {
+    let public_key = match PublicKey::from_slice(signed.get_signer_id()) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    let signature = match hex::decode(signed.get_signature())
+        .ok()
+        .and_then(|bytes| Signature::from_compact(&bytes).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let message = match Message::from_slice(&Sha256::digest(signed.get_message())) {
+        Ok(message) => message,
+        Err(_) => return false,
+    };
+
+    Secp256k1::verification_only()
+        .verify(&message, &signature, &public_key)
+        .is_ok()
+}
@@ -0,0 +1,844 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! A deterministic, in-process network of nodes, for testing the protocol without Docker
+//!
+//! A `Simulation` runs a number of `PbftNode`s on one thread, each with a simulated validator in
+//! place of a real one. Messages between nodes and updates from each node's validator are
+//! delivered by a scheduler in order of a virtual clock (see `timing::now`), and the nodes' timers
+//! run on the same clock, so a simulation of minutes of network time finishes in moments. Network
+//! latency is random, but drawn from the simulation's seed, so every run with the same seed goes
+//! exactly the same way. Messages can also be lost at random, and nodes can be crashed, to check
+//! that the rest of the network keeps going.
+//!
+//! Simulations are run by the unit tests, and by the `pbft-sim` binary (built with the `simulator`
+//! feature), which reports how many blocks a network commits under given conditions.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use hex;
+use serde_json;
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error, PeerId, PeerMessage, Update};
+use sawtooth_sdk::consensus::service::Service;
+
+use authentication::MessageSigner;
+use config::PbftConfig;
+use node::PbftNode;
+use timing::{self, Ticker};
+use view_stats::ViewChangeReason;
+
+/// How long a simulated validator takes to answer its node, in milliseconds
+const VALIDATOR_LATENCY_MS: u64 = 1;
+
+// An update that is waiting to be delivered to a node; updates are delivered in order of time,
+// then in the order they were scheduled
+struct Event {
+    time: Instant,
+    order: u64,
+    node: usize,
+    update: Update,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Event) -> bool {
+        self.time == other.time && self.order == other.order
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Event) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, so the `BinaryHeap` gives the earliest event first
+impl Ord for Event {
+    fn cmp(&self, other: &Event) -> Ordering {
+        other
+            .time
+            .cmp(&self.time)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+// Everything the simulated validators share: the scheduler, and the blocks
+struct Network {
+    peers: Vec<PeerId>,
+    events: BinaryHeap<Event>,
+    next_order: u64,
+    min_latency: u64,
+    max_latency: u64,
+
+    // The chance of each message between nodes being lost, from 0 to 1
+    loss: f64,
+    crashed: Vec<bool>,
+
+    // How many messages between nodes were sent, and how many of those were lost
+    messages_sent: u64,
+    messages_lost: u64,
+
+    // Every block that has been published, by ID
+    blocks: HashMap<BlockId, Block>,
+
+    // Each node's chain of committed blocks, starting with the genesis block
+    chains: Vec<Vec<Block>>,
+
+    // When each block was first committed by any node, by height
+    commit_times: Vec<Instant>,
+}
+
+impl Network {
+    fn schedule(&mut self, node: usize, delay_ms: u64, update: Update) {
+        if self.crashed[node] {
+            return;
+        }
+        self.events.push(Event {
+            time: timing::now() + Duration::from_millis(delay_ms),
+            order: self.next_order,
+            node,
+            update,
+        });
+        self.next_order += 1;
+    }
+
+    fn latency(&self) -> u64 {
+        self.min_latency + timing::random_u64() % (self.max_latency - self.min_latency + 1)
+    }
+
+    fn send(&mut self, from: usize, to: usize, update: Update) {
+        let latency = self.latency();
+        if from == to {
+            return;
+        }
+
+        // Only messages between nodes are lost; the validators' gossip of blocks is reliable.
+        // Nothing random is drawn unless messages can be lost, so without loss, each seed still
+        // gives the same run as the tests expect.
+        if let Update::PeerMessage(..) = update {
+            self.messages_sent += 1;
+            if self.loss > 0.0
+                && (timing::random_u64() % 1_000_000) as f64 / 1_000_000.0 < self.loss
+            {
+                self.messages_lost += 1;
+                return;
+            }
+        }
+        self.schedule(to, latency, update);
+    }
+
+    fn node_index(&self, peer_id: &PeerId) -> Result<usize, Error> {
+        self.peers
+            .iter()
+            .position(|id| id == peer_id)
+            .ok_or_else(|| Error::UnknownPeer(hex::encode(peer_id)))
+    }
+
+    fn get_block(&self, block_id: &BlockId) -> Result<Block, Error> {
+        self.blocks
+            .get(block_id)
+            .cloned()
+            .ok_or_else(|| Error::UnknownBlock(hex::encode(block_id)))
+    }
+}
+
+/// Stands in for a node's validator: sends messages through the simulated network, publishes
+/// blocks to every node, and checks and commits blocks on this node's chain
+struct SimulatedValidator {
+    node: usize,
+    network: Rc<RefCell<Network>>,
+
+    // The block the next published block will build on, if one was initialized
+    building_on: Option<BlockId>,
+}
+
+impl Service for SimulatedValidator {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        let to = network.node_index(peer)?;
+        let sender_id = network.peers[self.node].clone();
+        network.send(
+            self.node,
+            to,
+            Update::PeerMessage(peer_message(message_type, payload), sender_id),
+        );
+        Ok(())
+    }
+
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        let sender_id = network.peers[self.node].clone();
+        for to in 0..network.peers.len() {
+            network.send(
+                self.node,
+                to,
+                Update::PeerMessage(
+                    peer_message(message_type, payload.clone()),
+                    sender_id.clone(),
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error> {
+        let head = self.get_chain_head()?;
+        self.building_on = Some(previous_id.unwrap_or(head.block_id));
+        Ok(())
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        match self.building_on {
+            Some(_) => Ok(vec![]),
+            None => Err(Error::InvalidState(String::from("No block initialized"))),
+        }
+    }
+
+    fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
+        let previous_id = self
+            .building_on
+            .take()
+            .ok_or_else(|| Error::InvalidState(String::from("No block initialized")))?;
+
+        let mut network = self.network.borrow_mut();
+        let previous = network.get_block(&previous_id)?;
+        let block = Block {
+            block_id: BlockId::from(format!("{:016x}", timing::random_u64()).into_bytes()),
+            previous_id,
+            signer_id: network.peers[self.node].clone(),
+            block_num: previous.block_num + 1,
+            payload: vec![],
+            summary: vec![],
+        };
+        network.blocks.insert(block.block_id.clone(), block.clone());
+
+        // The publishing node's validator has the block right away; the others get it over the
+        // network
+        network.schedule(
+            self.node,
+            VALIDATOR_LATENCY_MS,
+            Update::BlockNew(block.clone()),
+        );
+        for to in 0..network.peers.len() {
+            network.send(self.node, to, Update::BlockNew(block.clone()));
+        }
+
+        Ok(block.block_id)
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        self.building_on = None;
+        Ok(())
+    }
+
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        for block_id in priority {
+            network.get_block(&block_id)?;
+            network.schedule(
+                self.node,
+                VALIDATOR_LATENCY_MS,
+                Update::BlockValid(block_id),
+            );
+        }
+        Ok(())
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        let block = network.get_block(&block_id)?;
+        let head_id = network.chains[self.node]
+            .last()
+            .map(|head| head.block_id.clone())
+            .unwrap_or_default();
+        if block.previous_id != head_id {
+            return Err(Error::InvalidState(format!(
+                "Block {} doesn't build on the chain head",
+                hex::encode(&block_id)
+            )));
+        }
+
+        network.chains[self.node].push(block);
+        if network.chains[self.node].len() > network.commit_times.len() + 1 {
+            network.commit_times.push(timing::now());
+        }
+        network.schedule(
+            self.node,
+            VALIDATOR_LATENCY_MS,
+            Update::BlockCommit(block_id),
+        );
+        Ok(())
+    }
+
+    fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        let network = self.network.borrow();
+        block_ids
+            .into_iter()
+            .map(|block_id| network.get_block(&block_id).map(|block| (block_id, block)))
+            .collect()
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        self.network.borrow().chains[self.node]
+            .last()
+            .cloned()
+            .ok_or(Error::NoChainHead)
+    }
+
+    fn get_settings(
+        &mut self,
+        _block_id: BlockId,
+        settings: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        let mut values = HashMap::new();
+        if settings.contains(&String::from("sawtooth.consensus.pbft.peers")) {
+            let peers: Vec<String> = self
+                .network
+                .borrow()
+                .peers
+                .iter()
+                .map(hex::encode)
+                .collect();
+            values.insert(
+                String::from("sawtooth.consensus.pbft.peers"),
+                serde_json::to_string(&peers)
+                    .map_err(|err| Error::EncodingError(err.to_string()))?,
+            );
+        }
+        Ok(values)
+    }
+
+    fn get_state(
+        &mut self,
+        _block_id: BlockId,
+        _addresses: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u8>>, Error> {
+        Ok(HashMap::new())
+    }
+}
+
+fn peer_message(message_type: &str, content: Vec<u8>) -> PeerMessage {
+    PeerMessage {
+        message_type: String::from(message_type),
+        content,
+    }
+}
+
+// A node, along with the timers that the engine's event loop would keep for it
+struct SimulatedNode {
+    node: PbftNode,
+    working_ticker: Ticker,
+    backlog_ticker: Ticker,
+    probe_ticker: Option<Ticker>,
+    heartbeat_ticker: Option<Ticker>,
+}
+
+/// Make up IDs for the given number of nodes, for a configuration to simulate
+pub fn peer_ids(num_nodes: usize) -> Vec<PeerId> {
+    (0..num_nodes)
+        .map(|i| PeerId::from(format!("simulated-node-{:04}", i).into_bytes()))
+        .collect()
+}
+
+/// Make up IDs for the given number of nodes, for a configuration to simulate with message
+/// authentication enabled; each is the public key that node signs its messages with
+pub fn signed_peer_ids(num_nodes: usize) -> Vec<PeerId> {
+    (0..num_nodes)
+        .map(|i| simulated_signer(i).get_signer_id())
+        .collect()
+}
+
+// The signer of a simulated node in a network whose messages are authenticated
+fn simulated_signer(node: usize) -> MessageSigner {
+    MessageSigner::from_hex(&format!("{:064x}", node + 1)).expect("Invalid simulated signing key")
+}
+
+/// A network of nodes that runs on a virtual clock
+pub struct Simulation {
+    nodes: Vec<SimulatedNode>,
+    network: Rc<RefCell<Network>>,
+
+    /// How often each node wakes up when no updates arrive
+    message_timeout: Duration,
+
+    /// When the simulation started, in virtual time
+    start: Instant,
+}
+
+impl Simulation {
+    /// Start a network with one node for each of the configuration's peers. Messages take between
+    /// 1 and 10ms to be delivered, unless `set_latency` is called. If the configuration
+    /// authenticates messages, its peers must be the ones from `signed_peer_ids`.
+    pub fn new(config: &PbftConfig, seed: u64) -> Self {
+        timing::start_virtual_time(seed);
+
+        let genesis = Block {
+            block_id: BlockId::from(b"genesis".to_vec()),
+            previous_id: BlockId::from(vec![0; 7]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 0,
+            payload: vec![],
+            summary: vec![],
+        };
+        let num_nodes = config.peers.len();
+        let network = Rc::new(RefCell::new(Network {
+            peers: config.peers.clone(),
+            events: BinaryHeap::new(),
+            next_order: 0,
+            min_latency: 1,
+            max_latency: 10,
+            loss: 0.0,
+            crashed: vec![false; num_nodes],
+            messages_sent: 0,
+            messages_lost: 0,
+            blocks: vec![(genesis.block_id.clone(), genesis.clone())]
+                .into_iter()
+                .collect(),
+            chains: vec![vec![genesis]; num_nodes],
+            commit_times: vec![],
+        }));
+
+        let nodes = (0..num_nodes)
+            .map(|id| {
+                let validator = SimulatedValidator {
+                    node: id,
+                    network: Rc::clone(&network),
+                    building_on: None,
+                };
+                let mut node: PbftNode = PbftNode::new(id as u64, config, Box::new(validator));
+                if config.authenticate_messages {
+                    node.signer = Some(simulated_signer(id));
+                }
+                #[cfg(test)]
+                {
+                    node.simulated = true;
+                }
+                SimulatedNode {
+                    node,
+                    working_ticker: Ticker::new(config.block_duration),
+                    backlog_ticker: Ticker::new(config.message_timeout),
+                    probe_ticker: config.probe_interval.map(Ticker::new),
+                    heartbeat_ticker: config.heartbeat_interval.map(Ticker::new),
+                }
+            })
+            .collect();
+
+        Simulation {
+            nodes,
+            network,
+            message_timeout: config.message_timeout,
+            start: timing::now(),
+        }
+    }
+
+    /// Change how long messages between nodes take to be delivered; each message's latency is
+    /// picked at random between the two
+    pub fn set_latency(&mut self, min: Duration, max: Duration) {
+        let mut network = self.network.borrow_mut();
+        network.min_latency = timing::millis(min);
+        network.max_latency = timing::millis(max);
+    }
+
+    /// Make each message between nodes be lost with the given probability, from 0 (none are lost)
+    /// to 1 (all of them are)
+    pub fn set_loss(&mut self, probability: f64) {
+        self.network.borrow_mut().loss = probability.max(0.0).min(1.0);
+    }
+
+    /// Stop a node; it doesn't handle or send anything from now on, and messages to it are lost
+    pub fn crash(&mut self, node: usize) {
+        self.network.borrow_mut().crashed[node] = true;
+    }
+
+    /// Bring a crashed node back where it left off, as if it had only been cut off from the
+    /// network; its validator delivers the blocks that were published in the meantime
+    pub fn reconnect(&mut self, node: usize) {
+        let mut network = self.network.borrow_mut();
+        network.crashed[node] = false;
+
+        let head_num = network.chains[node]
+            .last()
+            .map_or(0, |block| block.block_num);
+        let mut missed: Vec<Block> = network
+            .blocks
+            .values()
+            .filter(|block| block.block_num > head_num)
+            .cloned()
+            .collect();
+        missed.sort_by_key(|block| block.block_num);
+        for block in missed {
+            network.schedule(node, VALIDATOR_LATENCY_MS, Update::BlockNew(block));
+        }
+    }
+
+    /// Shut a node down the way the validator does: it gets a `Shutdown` update, then stops
+    pub fn shut_down(&mut self, node: usize) {
+        if let Err(err) = self.nodes[node].node.hand_off() {
+            debug!("Node {}: {}", node, err);
+        }
+        self.crash(node);
+    }
+
+    /// Get a node, to check on its state
+    pub fn node(&self, node: usize) -> &PbftNode {
+        &self.nodes[node].node
+    }
+
+    /// How much virtual time has passed since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        timing::now() - self.start
+    }
+
+    /// Get the IDs of the blocks a node has committed, in order, not including the genesis block
+    pub fn chain(&self, node: usize) -> Vec<BlockId> {
+        self.network.borrow().chains[node][1..]
+            .iter()
+            .map(|block| block.block_id.clone())
+            .collect()
+    }
+
+    /// How long after the start of the simulation each block was first committed by any node, in
+    /// order of height
+    pub fn commit_times(&self) -> Vec<Duration> {
+        self.network
+            .borrow()
+            .commit_times
+            .iter()
+            .map(|time| *time - self.start)
+            .collect()
+    }
+
+    /// How many messages between nodes have been sent, and how many of those were lost
+    pub fn message_counts(&self) -> (u64, u64) {
+        let network = self.network.borrow();
+        (network.messages_sent, network.messages_lost)
+    }
+
+    /// The fewest blocks that any node that hasn't crashed has committed
+    pub fn min_height(&self) -> usize {
+        let network = self.network.borrow();
+        network
+            .chains
+            .iter()
+            .zip(network.crashed.iter())
+            .filter(|(_, crashed)| !**crashed)
+            .map(|(chain, _)| chain.len() - 1)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Check that no two nodes committed different blocks at the same height
+    /// # Panics
+    /// Panics if they did
+    pub fn check_safety(&self) {
+        let network = self.network.borrow();
+        for (i, chain) in network.chains.iter().enumerate() {
+            for (j, other) in network.chains.iter().enumerate().skip(i + 1) {
+                for (block, other_block) in chain.iter().zip(other.iter()) {
+                    assert_eq!(
+                        block.block_id, other_block.block_id,
+                        "Nodes {} and {} committed different blocks at height {}",
+                        i, j, block.block_num
+                    );
+                }
+            }
+        }
+    }
+
+    /// Deliver updates and run the nodes' timers until `done` returns `true`, or until `limit`
+    /// more virtual time has passed (in which case the clock is left at the limit). Returns
+    /// whether `done` was reached.
+    pub fn run_until<F: Fn(&Simulation) -> bool>(&mut self, limit: Duration, done: F) -> bool {
+        let deadline = timing::now() + limit;
+        let mut next_tick = timing::now() + self.message_timeout;
+
+        while !done(self) {
+            let next_event = self.network.borrow().events.peek().map(|event| event.time);
+            match next_event {
+                Some(time) if time <= next_tick => {
+                    if time > deadline {
+                        timing::set_virtual_time(deadline);
+                        return false;
+                    }
+                    let event = self.network.borrow_mut().events.pop().unwrap();
+                    timing::set_virtual_time(time.max(timing::now()));
+                    self.deliver(event.node, event.update);
+                    self.tick(event.node);
+                }
+                _ => {
+                    if next_tick > deadline {
+                        timing::set_virtual_time(deadline);
+                        return false;
+                    }
+                    timing::set_virtual_time(next_tick);
+                    for node in 0..self.nodes.len() {
+                        self.tick(node);
+                    }
+                    next_tick += self.message_timeout;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Hand an update to a node, the way the engine's event loop does
+    fn deliver(&mut self, node: usize, update: Update) {
+        if self.network.borrow().crashed[node] {
+            return;
+        }
+
+        let pbft_node = &mut self.nodes[node].node;
+        let res = match update {
+            Update::BlockNew(block) => pbft_node.on_block_new(block),
+            Update::BlockValid(block_id) => pbft_node.on_block_valid(block_id),
+            Update::BlockInvalid(block_id) => pbft_node.on_block_invalid(block_id),
+            Update::BlockCommit(block_id) => pbft_node.on_block_commit(block_id),
+            Update::PeerMessage(message, sender_id) => {
+                pbft_node.on_network_message(&message, &sender_id)
+            }
+            Update::PeerConnected(info) => pbft_node.on_peer_connected(info.peer_id),
+            Update::PeerDisconnected(peer_id) => pbft_node.on_peer_disconnected(peer_id),
+            _ => Ok(()),
+        };
+        if let Err(err) = res {
+            debug!("Node {}: {}", node, err);
+        }
+    }
+
+    // Run the work that the engine's event loop does on every pass, and whichever of a node's
+    // timers are due
+    fn tick(&mut self, node: usize) {
+        if self.network.borrow().crashed[node] {
+            return;
+        }
+
+        let SimulatedNode {
+            ref mut node,
+            ref mut working_ticker,
+            ref mut backlog_ticker,
+            ref mut probe_ticker,
+            ref mut heartbeat_ticker,
+        } = self.nodes[node];
+
+        let mut results = vec![node.try_publish_early()];
+        working_ticker.tick(|| {
+            results.push(node.try_publish());
+            results.push(node.rebroadcast_if_stalled());
+            if node.check_timeout_expired() {
+                results.push(node.start_view_change(ViewChangeReason::CommitTimeout));
+            }
+            results.push(node.check_chain_head());
+        });
+        backlog_ticker.tick(|| {
+            results.push(node.retry_backlog());
+            results.push(node.retransmit());
+            results.push(node.continue_recovery());
+            results.push(node.check_fast_path_timeout());
+        });
+        if let Some(ref mut ticker) = probe_ticker {
+            ticker.tick(|| results.push(node.probe_primary()));
+        }
+        if let Some(ref mut ticker) = heartbeat_ticker {
+            ticker.tick(|| results.push(node.heartbeat()));
+        }
+
+        for err in results.into_iter().filter_map(Result::err) {
+            debug!("{}: {}", node.state, err);
+        }
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        timing::stop_virtual_time();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use state::PbftMode;
+
+    /// Make sure that a network of four nodes keeps committing the same blocks, and that two runs
+    /// with the same seed go exactly the same way
+    #[test]
+    fn simulated_network() {
+        let run = |seed| {
+            let mut sim = Simulation::new(&mock_config(4), seed);
+            assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 10));
+            sim.check_safety();
+            (sim.elapsed(), sim.chain(0))
+        };
+
+        let (elapsed, chain) = run(1);
+        assert_eq!(chain.len(), 10);
+        assert_eq!(run(1), (elapsed, chain));
+    }
+
+    /// Make sure that when the primary crashes, the other nodes notice, change views, and keep
+    /// committing blocks
+    #[test]
+    fn simulated_primary_crash() {
+        let mut cfg = mock_config(4);
+        cfg.probe_interval = Some(Duration::from_millis(500));
+        let mut sim = Simulation::new(&cfg, 7);
+        sim.set_latency(Duration::from_millis(5), Duration::from_millis(50));
+
+        assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 3));
+        sim.crash(0);
+        assert!(sim.run_until(Duration::from_secs(120), |sim| sim.min_height() >= 6));
+
+        sim.check_safety();
+        for node in 1..4 {
+            assert!(sim.node(node).state.view > 0);
+        }
+    }
+
+    /// Make sure that a primary that is shut down hands off to the next one, so that the other
+    /// nodes move on without having to notice that it's gone
+    #[test]
+    fn simulated_primary_handoff() {
+        let mut sim = Simulation::new(&mock_config(4), 11);
+
+        assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 3));
+        let shut_down_at = sim.elapsed();
+        sim.shut_down(0);
+        assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 6));
+
+        sim.check_safety();
+        for node in 1..4 {
+            assert_eq!(sim.node(node).state.view, 1);
+
+            let ended: Vec<_> = sim.node(node).state.view_history.ended().collect();
+            assert_eq!(ended.len(), 1);
+            assert!(ended[0].blocks_committed > 0);
+            assert_eq!(ended[0].end_reason, Some(ViewChangeReason::Handoff));
+        }
+        assert!(sim.elapsed() - shut_down_at < Duration::from_secs(10));
+    }
+
+    /// Make sure that heartbeats from the primary keep the other nodes from changing views while
+    /// it's idle, and that they notice quickly once the heartbeats stop
+    #[test]
+    fn simulated_heartbeats() {
+        let mut cfg = mock_config(4);
+        cfg.heartbeat_interval = Some(Duration::from_millis(100));
+        cfg.idle_timeout = Duration::from_millis(500);
+        let mut sim = Simulation::new(&cfg, 5);
+
+        assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 5));
+        for node in 0..4 {
+            assert_eq!(sim.node(node).state.view, 0);
+        }
+
+        sim.crash(0);
+        assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 8));
+
+        sim.check_safety();
+        for node in 1..4 {
+            assert!(sim.node(node).state.view > 0);
+        }
+    }
+
+    /// Make sure that losing messages between nodes never makes them commit different blocks, and
+    /// doesn't stop them from committing blocks either
+    #[test]
+    fn simulated_message_loss() {
+        let mut sim = Simulation::new(&mock_config(4), 17);
+        sim.set_loss(0.05);
+
+        assert!(!sim.run_until(Duration::from_secs(60), |_| false));
+        assert_eq!(sim.elapsed(), Duration::from_secs(60));
+        sim.check_safety();
+
+        let (sent, lost) = sim.message_counts();
+        assert!(lost > 0 && lost < sent);
+        let commit_times = sim.commit_times();
+        assert!(commit_times.len() >= sim.min_height());
+        assert!(commit_times.windows(2).all(|times| times[0] <= times[1]));
+
+        // Every node keeps up, rather than some of them getting stuck on a block whose messages
+        // they lost
+        let height = sim.min_height();
+        assert!(sim.run_until(Duration::from_secs(60), |sim| {
+            sim.min_height() >= height + 20
+        }));
+        sim.check_safety();
+    }
+
+    /// Make sure that a node that fell behind by more than its log window recovers from seals,
+    /// even though the other nodes have garbage collected the messages for the blocks it missed;
+    /// the seals' `Commit`s are signed, so that the node can check them
+    #[test]
+    fn simulated_recovery() {
+        let mut cfg = mock_config(4);
+        cfg.peers = signed_peer_ids(4);
+        cfg.authenticate_messages = true;
+        cfg.checkpoint_period = 5;
+        cfg.max_log_size = 20;
+        let mut sim = Simulation::new(&cfg, 13);
+
+        sim.crash(3);
+        assert!(sim.run_until(Duration::from_secs(120), |sim| sim.min_height() >= 40));
+
+        sim.reconnect(3);
+        assert!(sim.run_until(Duration::from_secs(60), |sim| {
+            sim.node(3).state.mode == PbftMode::Recovering
+        }));
+        assert!(sim.run_until(Duration::from_secs(60), |sim| {
+            sim.node(3).state.mode == PbftMode::Normal && sim.chain(3).len() >= 40
+        }));
+
+        sim.check_safety();
+    }
+
+    /// Make sure that a node that's alone in its network commits blocks by itself, each with a
+    /// seal of its own `Commit`
+    #[test]
+    fn simulated_single_node() {
+        let mut sim = Simulation::new(&mock_config(1), 3);
+        assert!(sim.run_until(Duration::from_secs(60), |sim| sim.min_height() >= 5));
+
+        let node = sim.node(0);
+        assert!(node.state.single_node);
+        for seq_num in 1..=5 {
+            let seal = node.msg_log.get_seal(seq_num, node.state.quorum()).unwrap();
+            assert_eq!(seal.get_commit_messages().len(), 1);
+        }
+    }
+}
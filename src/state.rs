@@ -17,7 +17,12 @@
 
 //! Information about a PBFT node's state
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use hex;
 
@@ -25,10 +30,27 @@ use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
 
 use protos::pbft_message::PbftBlock;
 
+use circuit_breaker::CircuitBreaker;
 use config::PbftConfig;
 use error::PbftError;
+use events::ConsensusEvent;
+use finality::FinalityHistory;
+use incidents::Incidents;
 use message_type::PbftMessageType;
-use timing::Timeout;
+use metrics::Metrics;
+use outbox::Outbox;
+use peer_stats::PeerStats;
+use primary::{self, Blacklist, PrimaryFailure, PrimarySelector};
+use protocol;
+use quarantine::Quarantine;
+use rate_limit::RateLimiter;
+use replay::ReplayFilter;
+use timing::{AdaptiveTimeout, Timeout, ViewChangeBackoff};
+use validation::RejectedMessages;
+use view_stats::{ViewChangeReason, ViewChangeRecord, ViewHistory, ViewStats};
+use votes::SentVotes;
+
+pub use protocol::{PbftMode, PbftPhase};
 
 // Possible roles for a node
 // Primary is in charge of making consensus decisions
@@ -38,32 +60,14 @@ enum PbftNodeRole {
     Secondary,
 }
 
-/// Phases of the PBFT algorithm, in `Normal` mode
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
-pub enum PbftPhase {
-    NotStarted,
-    PrePreparing,
-    Preparing,
-    Checking,
-    Committing,
-    Finished,
-}
-
-/// Modes that the PBFT algorithm can possibly be in
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum PbftMode {
-    Normal,
-    ViewChanging,
-    Checkpointing,
-}
-
 impl fmt::Display for PbftState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let ast = if self.is_primary() { "*" } else { " " };
         let mode = match self.mode {
             PbftMode::Normal => "N",
-            PbftMode::Checkpointing => "C",
             PbftMode::ViewChanging => "V",
+            PbftMode::Recovering => "R",
+            PbftMode::NonVoting => "X",
         };
 
         let phase = match self.phase {
@@ -89,7 +93,11 @@ impl fmt::Display for PbftState {
             f,
             "({} {} {}, seq {}, wb {}), Node {}{:02}",
             phase, mode, self.view, self.seq_num, wb, ast, self.id,
-        )
+        )?;
+        match self.network {
+            Some(ref network) => write!(f, " on {}", network),
+            None => Ok(()),
+        }
     }
 }
 
@@ -123,7 +131,7 @@ pub struct PbftState {
     /// Always starts at 0; representative of an unknown sequence number.
     pub seq_num: u64,
 
-    /// The current view (where the primary's ID is p = v mod network_node_ids.len())
+    /// The current view (the primary for each view is chosen by `primary_selector`)
     pub view: u64,
 
     /// Current phase of the algorithm
@@ -132,65 +140,288 @@ pub struct PbftState {
     /// Is this node primary or secondary?
     role: PbftNodeRole,
 
-    /// Normal operation, view change, or checkpointing. Previous mode is stored when checkpointing
+    /// Normal operation or view change
     pub mode: PbftMode,
-    pub pre_checkpoint_mode: PbftMode,
 
-    /// Map of peers in the network, including ourselves
+    /// Map of peers in the network, including ourselves (unless this node was removed)
     peer_ids: Vec<PeerId>,
 
+    /// This node's own ID, which stays the same if it's removed from `peer_ids`
+    own_peer_id: PeerId,
+
     /// The maximum number of faulty nodes in the network
     pub f: u64,
 
+    /// The most faulty nodes the network was configured to tolerate, if fewer than its size
+    /// allows (see `PbftConfig::fault_tolerance`)
+    pub fault_tolerance: Option<u64>,
+
+    /// Whether this node is the only one in the network. There's nobody to agree with, so blocks
+    /// skip the `PrePrepare` and `Prepare` phases, and are committed once the validator has found
+    /// them valid; each block's seal is just this node's own `Commit`.
+    pub single_node: bool,
+
+    /// Whether blocks can be committed on the fast path (see `PbftLog::prepared_by_all`)
+    pub fast_path: bool,
+
+    /// With the fast path, started when the working block is found valid before this node has
+    /// `Prepare`s from every node; this node's `Commit` is held back until it expires
+    pub fast_path_timeout: Timeout,
+
+    /// Whether seals include evidence of view changes (see `PbftLog::view_change_evidence`)
+    pub seal_view_change_evidence: bool,
+
+    /// Whether messages are signed; if they are, the votes that one node passes on for others
+    /// must be signed too (see `handlers::verify_new_view`)
+    pub authenticate_messages: bool,
+
+    /// How many threads the signatures of the votes passed on in a seal or `NewView` are checked
+    /// on; 0 or 1 checks them on the calling thread (see `authentication::verify_votes`)
+    pub verify_threads: usize,
+
+    /// Chooses the primary for each view
+    primary_selector: Box<PrimarySelector>,
+
+    /// Skips nodes that have recently failed as primary too often
+    primary_blacklist: Blacklist,
+
+    /// Views that ended with a view change, and the nodes that were primary in them
+    primary_failures: Vec<PrimaryFailure>,
+
+    /// What happened in the current view and the most recent ones before it
+    pub view_history: ViewHistory,
+
     // Timer used to make sure the primary is executing BlockCommits in a timely manner. If not,
     /// then this node will initiate a view change.
     pub timeout: Timeout,
 
+    /// Adjusts `timeout` based on recent commit latencies, if enabled
+    pub adaptive_timeout: Option<AdaptiveTimeout>,
+
+    /// Timer used to make sure a view change completes in time. If not, then this node will try to
+    /// change to the next view instead.
+    pub view_change_timeout: Timeout,
+
+    /// Decides how long `view_change_timeout` lasts, based on how many view changes have failed
+    pub view_change_backoff: ViewChangeBackoff,
+
+    /// Whether the last `Probe` sent to the primary is still waiting for a response
+    pub probe_outstanding: bool,
+
+    /// How many `Probe`s in a row the primary has failed to respond to
+    pub failed_probes: u64,
+
+    /// How many `Probe`s in a row the primary can fail to respond to before a view change starts
+    pub max_probe_failures: u64,
+
+    /// Timer restarted whenever the primary is heard from, or a block is in progress; if it
+    /// expires, the primary is considered faulty (only used if `heartbeat_interval` is set)
+    pub idle_timeout: Option<Timeout>,
+
+    /// How far another node's clock can be from this node's before it's warned about
+    pub max_clock_skew: Duration,
+
+    /// The view and sequence number of the block that this node last sent its own `Prepare` and
+    /// `Commit` again for, because the block was about to time out
+    pub last_rebroadcast: Option<(u64, u64)>,
+
+    /// Timer for the last `StateRequest` this node sent; another one isn't sent until it expires
+    pub state_request_timeout: Timeout,
+
+    /// The node that the last `StateRequest` was sent to
+    pub state_request_peer: Option<PeerId>,
+
+    /// The sequence number that this node last asked its peers to retransmit messages for
+    pub retransmit_seq_num: u64,
+
+    /// Timer for the last `RetransmitRequest`s this node sent; if it expires before the missing
+    /// messages arrive, the node catches up from seals instead
+    pub retransmit_timeout: Timeout,
+
+    /// The highest sequence number another node is known to have reached, while `Recovering`
+    pub recovery_target: u64,
+
+    /// The node to send the next `StateRequest` to while `Recovering`, if the last one went
+    /// unanswered
+    pub recovery_peer: usize,
+
+    /// Timer started when each block is committed; once it expires, the primary publishes the next
+    /// block as soon as it's ready (only used if `min_block_interval` is set)
+    pub block_interval_timeout: Option<Timeout>,
+
+    /// The largest message from another node that will be decoded, in bytes
+    pub max_message_size: u64,
+
+    /// Finds stale and replayed messages from other nodes
+    pub replay_filter: ReplayFilter,
+
+    /// Finds messages from nodes that send them too fast
+    pub rate_limiter: RateLimiter,
+
+    /// Nodes whose messages are ignored for a while, because they sent too many bad ones
+    pub quarantine: Quarantine,
+
+    /// The votes this node has sent for blocks it hasn't committed yet
+    pub sent_votes: SentVotes,
+
+    /// How many messages from each other node have been dropped before being handled, and why
+    pub rejected_messages: HashMap<PeerId, RejectedMessages>,
+
+    /// Messages sent to and received from each other node, and when each was last heard from
+    pub peer_stats: HashMap<PeerId, PeerStats>,
+
+    /// Counts of the messages received and view changes started, for metrics
+    pub metrics: Metrics,
+
+    /// Whether calls to the validator are paused after failing repeatedly; shared with the
+    /// service that makes the calls
+    pub service_breaker: Rc<RefCell<CircuitBreaker>>,
+
+    /// Consensus events that haven't been published yet
+    pub events: Vec<ConsensusEvent>,
+
+    /// Problems going on that need an operator's attention
+    pub incidents: Incidents,
+
+    /// View changes that haven't been written to the audit trail yet
+    pub view_change_records: Vec<ViewChangeRecord>,
+
+    /// When the most recent blocks became final on this node
+    pub finality: FinalityHistory,
+
+    /// Messages for other nodes that couldn't be sent yet
+    pub outbox: Outbox,
+
     /// The current block this node is working on
     pub working_block: WorkingBlockOption,
+
+    /// Blocks after the working block that the validator was asked to check ahead of time, and
+    /// hasn't answered for yet
+    pub speculative_blocks: HashSet<BlockId>,
+
+    /// The block that this node last saw committed, which should be the validator's chain head
+    pub chain_head: BlockId,
+
+    /// The block number of `chain_head`
+    pub chain_head_num: u64,
+
+    /// When this node last committed a block (`None` if it hasn't since it started)
+    pub last_commit: Option<Instant>,
+
+    /// Whether an operator put this node in maintenance mode: it keeps voting, but doesn't
+    /// publish blocks, and hands off whenever it's the primary
+    pub maintenance: bool,
+
+    /// The view this node last handed off in because of maintenance mode, so it only hands off
+    /// once per view
+    pub maintenance_handoff: Option<u64>,
+
+    /// The network this node is on, if its process runs consensus for more than one
+    pub network: Option<String>,
 }
 
 impl PbftState {
     /// Construct the initial state for a PBFT node
     /// # Panics
     /// Panics if the network this node is on does not have enough nodes to be Byzantine fault
-    /// tolernant.
+    /// tolernant, unless it's the only node in the network.
     pub fn new(id: u64, config: &PbftConfig) -> Self {
         // Maximum number of faulty nodes in this network. Panic if there are not enough nodes.
-        let f = ((config.peers.len() - 1) / 3) as u64;
-        if f == 0 {
+        let f = protocol::tolerated_faults(config.peers.len() as u64, config.fault_tolerance);
+        let single_node = config.peers.len() == 1;
+        if config.peers.len() < 4 && !single_node {
             panic!("This network does not contain enough nodes to be fault tolerant");
         }
 
-        PbftState {
+        let mut state = PbftState {
             id,
             seq_num: 0, // Default to unknown
-            view: 0,    // Node ID 0 is default primary
+            view: 0,
             phase: PbftPhase::NotStarted,
-            role: if id == 0 {
-                PbftNodeRole::Primary
-            } else {
-                PbftNodeRole::Secondary
-            },
+            role: PbftNodeRole::Secondary,
             mode: PbftMode::Normal,
-            pre_checkpoint_mode: PbftMode::Normal,
             f,
+            own_peer_id: config.peers[id as usize].clone(),
+            fault_tolerance: config.fault_tolerance,
+            single_node,
+            fast_path: config.fast_path,
+            fast_path_timeout: Timeout::new(config.fast_path_timeout),
+            seal_view_change_evidence: config.seal_view_change_evidence,
+            authenticate_messages: config.authenticate_messages,
+            verify_threads: 1,
+            primary_selector: primary::new_selector(&config.primary_selection),
+            primary_blacklist: config.primary_blacklist.clone(),
+            primary_failures: Vec::new(),
+            view_history: ViewHistory::new(0),
             peer_ids: config.peers.clone(),
             timeout: Timeout::new(config.view_change_timeout),
+            adaptive_timeout: if config.adaptive_timeout {
+                Some(AdaptiveTimeout::new(
+                    config.adaptive_timeout_factor,
+                    config.min_view_change_timeout,
+                    config.view_change_timeout,
+                ))
+            } else {
+                None
+            },
+            view_change_timeout: Timeout::new(config.view_change_duration),
+            view_change_backoff: ViewChangeBackoff::new(config.view_change_duration),
+            probe_outstanding: false,
+            failed_probes: 0,
+            max_probe_failures: config.max_probe_failures,
+            idle_timeout: config.heartbeat_interval.map(|_| {
+                let mut timeout = Timeout::new(config.idle_timeout);
+                timeout.start();
+                timeout
+            }),
+            max_clock_skew: config.max_clock_skew,
+            last_rebroadcast: None,
+            state_request_timeout: Timeout::new(config.view_change_timeout),
+            state_request_peer: None,
+            retransmit_seq_num: 0,
+            retransmit_timeout: Timeout::new(config.block_duration),
+            recovery_target: 0,
+            recovery_peer: 0,
+            block_interval_timeout: config.min_block_interval.map(|interval| {
+                let mut timeout = Timeout::new(interval);
+                timeout.start();
+                timeout
+            }),
+            max_message_size: config.max_message_size,
+            replay_filter: ReplayFilter::new(config),
+            rate_limiter: RateLimiter::new(config),
+            quarantine: Quarantine::new(),
+            sent_votes: SentVotes::new(),
+            rejected_messages: HashMap::new(),
+            peer_stats: HashMap::new(),
+            metrics: Metrics::new(),
+            service_breaker: Rc::new(RefCell::new(CircuitBreaker::new())),
+            events: Vec::new(),
+            incidents: Incidents::new(),
+            view_change_records: Vec::new(),
+            finality: FinalityHistory::new(),
+            outbox: Outbox::new(),
             working_block: WorkingBlockOption::NoWorkingBlock,
+            speculative_blocks: HashSet::new(),
+            chain_head: BlockId::from(vec![]),
+            chain_head_num: 0,
+            last_commit: None,
+            maintenance: false,
+            maintenance_handoff: None,
+            network: None,
+        };
+
+        if state.get_primary_peer_id() == state.get_own_peer_id() {
+            state.upgrade_role();
         }
+
+        state
     }
 
     /// Check to see what type of message this node is expecting or sending, based on the current
     /// phase
     pub fn check_msg_type(&self) -> PbftMessageType {
-        match self.phase {
-            PbftPhase::PrePreparing => PbftMessageType::PrePrepare,
-            PbftPhase::Preparing => PbftMessageType::Prepare,
-            PbftPhase::Checking => PbftMessageType::Prepare,
-            PbftPhase::Committing => PbftMessageType::Commit,
-            _ => PbftMessageType::Unset,
-        }
+        protocol::expected_msg_type(&self.phase)
     }
 
     /// Obtain the node ID (u64) from a serialized PeerId
@@ -204,17 +435,234 @@ impl PbftState {
         }
     }
 
+    /// Obtain the Peer IDs of all nodes in the network, including this one
+    pub fn peers(&self) -> &[PeerId] {
+        &self.peer_ids
+    }
+
+    /// Switch to a new set of nodes, recalculating this node's ID, `f`, whether it's the only node,
+    /// and its role in the current view. If the current primary isn't in the new set, this moves
+    /// to the next view, so a new primary takes over right away. Nothing is changed if this node
+    /// isn't in the new set, or if the new network wouldn't be fault tolerant.
+    pub fn set_peers(&mut self, peers: Vec<PeerId>) -> Result<(), PbftError> {
+        let own_peer_id = self.get_own_peer_id();
+        let old_primary = self.get_primary_peer_id();
+        let id = peers
+            .iter()
+            .position(|peer_id| peer_id == &own_peer_id)
+            .ok_or(PbftError::NodeNotFound)?;
+
+        let single_node = peers.len() == 1;
+        if peers.len() < 4 && !single_node {
+            return Err(PbftError::InvalidConfig(format!(
+                "Network of {} nodes would not be fault tolerant",
+                peers.len()
+            )));
+        }
+
+        self.id = id as u64;
+        self.f = protocol::tolerated_faults(peers.len() as u64, self.fault_tolerance);
+        self.single_node = single_node;
+        self.peer_ids = peers;
+
+        if !self.peer_ids.contains(&old_primary) {
+            self.view += 1;
+            self.view_history
+                .record_end_reason(ViewChangeReason::PrimaryRemoved);
+            let ended = self.view_history.enter_view(self.view);
+            self.record_view_change(&ended, vec![]);
+        }
+
+        if self.get_primary_peer_id() == own_peer_id {
+            self.upgrade_role();
+        } else {
+            self.downgrade_role();
+        }
+
+        Ok(())
+    }
+
+    /// Switch to a new set of nodes that doesn't include this one. This node stops taking part in
+    /// consensus, and only follows the chain (see `PbftMode::NonVoting`); its ID is left as it
+    /// was, for logging. Nothing is changed if the new network wouldn't be fault tolerant, since
+    /// the remaining nodes keep the old membership in that case.
+    pub fn leave(&mut self, peers: Vec<PeerId>) -> Result<(), PbftError> {
+        if peers.len() < 4 {
+            return Err(PbftError::InvalidConfig(format!(
+                "Network of {} nodes would not be fault tolerant",
+                peers.len()
+            )));
+        }
+
+        self.f = protocol::tolerated_faults(peers.len() as u64, self.fault_tolerance);
+        self.peer_ids = peers;
+        self.downgrade_role();
+        self.mode = PbftMode::NonVoting;
+        self.timeout.stop();
+        self.view_change_timeout.stop();
+
+        Ok(())
+    }
+
+    /// Construct the state of a node that isn't in `config.peers`. It starts out following the
+    /// chain without voting, as if it had been removed, so it's caught up as soon as it's added.
+    /// Since it has no place in the membership, its ID is one past the last member's.
+    pub fn new_observer(own_peer_id: PeerId, config: &PbftConfig) -> Result<Self, PbftError> {
+        let mut state = PbftState::new(0, config);
+        state.id = config.peers.len() as u64;
+        state.own_peer_id = own_peer_id;
+        state.leave(config.peers.clone())?;
+        Ok(state)
+    }
+
+    /// Rebuild this node's state from freshly loaded on-chain settings, for when the validator's
+    /// chain head moved without this node committing a block. The view, sequence number, chain
+    /// head, and failed primaries are kept, since they describe the network rather than this
+    /// node, as are the record of messages received from other nodes and the view statistics;
+    /// everything else starts over as if the node had just started.
+    pub fn resync(&mut self, config: &PbftConfig) -> Result<(), PbftError> {
+        let own_peer_id = self.get_own_peer_id();
+        let id = config
+            .peers
+            .iter()
+            .position(|peer_id| peer_id == &own_peer_id)
+            .ok_or(PbftError::NodeNotFound)?;
+
+        if config.peers.len() < 4 && config.peers.len() != 1 {
+            return Err(PbftError::InvalidConfig(format!(
+                "Network of {} nodes would not be fault tolerant",
+                config.peers.len()
+            )));
+        }
+
+        let mut state = PbftState::new(id as u64, config);
+        state.seq_num = self.seq_num;
+        state.view = self.view;
+        state.chain_head = self.chain_head.clone();
+        state.chain_head_num = self.chain_head_num;
+        state.last_commit = self.last_commit;
+        state.maintenance = self.maintenance;
+        state.verify_threads = self.verify_threads;
+        state.network = self.network.clone();
+        state.primary_failures = mem::replace(&mut self.primary_failures, vec![]);
+        mem::swap(&mut state.view_history, &mut self.view_history);
+        mem::swap(&mut state.replay_filter, &mut self.replay_filter);
+        mem::swap(&mut state.rate_limiter, &mut self.rate_limiter);
+        mem::swap(&mut state.quarantine, &mut self.quarantine);
+        mem::swap(&mut state.sent_votes, &mut self.sent_votes);
+        mem::swap(&mut state.rejected_messages, &mut self.rejected_messages);
+        mem::swap(&mut state.peer_stats, &mut self.peer_stats);
+        mem::swap(&mut state.metrics, &mut self.metrics);
+        mem::swap(&mut state.service_breaker, &mut self.service_breaker);
+        mem::swap(&mut state.events, &mut self.events);
+        mem::swap(&mut state.incidents, &mut self.incidents);
+        mem::swap(
+            &mut state.view_change_records,
+            &mut self.view_change_records,
+        );
+        mem::swap(&mut state.finality, &mut self.finality);
+        mem::swap(&mut state.outbox, &mut self.outbox);
+
+        if state.get_primary_peer_id() == own_peer_id {
+            state.upgrade_role();
+        } else {
+            state.downgrade_role();
+        }
+
+        *self = state;
+        Ok(())
+    }
+
+    /// Obtain the number of nodes in the network, including this one
+    pub fn num_nodes(&self) -> u64 {
+        self.peer_ids.len() as u64
+    }
+
+    /// How many nodes have to agree for a decision to stand: `2f + 1`, unless `f` was configured
+    /// lower than the network could tolerate. Then it's `n - f`, so that any two quorums still
+    /// have at least `f + 1` nodes in common.
+    pub fn quorum(&self) -> u64 {
+        protocol::quorum(self.num_nodes(), self.f)
+    }
+
+    /// How many nodes have to report the same thing for at least one of them to be honest: `f + 1`
+    pub fn weak_quorum(&self) -> u64 {
+        protocol::weak_quorum(self.f)
+    }
+
     /// Obtain the Peer ID for this node
     pub fn get_own_peer_id(&self) -> PeerId {
-        self.peer_ids[self.id as usize].clone()
+        self.own_peer_id.clone()
     }
 
     /// Obtain the Peer ID for the primary node in the network
     pub fn get_primary_peer_id(&self) -> PeerId {
-        let primary_node_id = (self.view % (self.peer_ids.len() as u64)) as usize;
+        self.get_primary_peer_id_for_view(self.view)
+    }
+
+    /// Obtain the Peer ID for the node that is primary in the given view
+    pub fn get_primary_peer_id_for_view(&self, view: u64) -> PeerId {
+        self.select_primary(view, &self.primary_failures)
+    }
+
+    /// Obtain the Peer ID for the node that will be primary in the given view once this node
+    /// enters it. Entering a view records the primaries of the views before it as failed (see
+    /// `record_primary_failure`), which can change who is chosen, so those failures are counted
+    /// here without being recorded.
+    pub fn get_primary_peer_id_on_entering(&self, view: u64) -> PeerId {
+        let mut failures = self.primary_failures.clone();
+        for failed_view in self.view..view {
+            if !failures.iter().any(|failure| failure.view == failed_view) {
+                let peer_id = self.select_primary(failed_view, &failures);
+                failures.push(PrimaryFailure {
+                    view: failed_view,
+                    peer_id,
+                });
+            }
+        }
+        self.select_primary(view, &failures)
+    }
+
+    fn select_primary(&self, view: u64, failures: &[PrimaryFailure]) -> PeerId {
+        let selected = self
+            .primary_selector
+            .select_primary(view, &self.peer_ids, failures);
+        let primary_node_id =
+            self.primary_blacklist
+                .apply(view, selected, &self.peer_ids, failures);
         self.peer_ids[primary_node_id].clone()
     }
 
+    /// Record that the primary of the given view was deemed faulty, and replaced by a view change
+    pub fn record_primary_failure(&mut self, view: u64) {
+        if self
+            .primary_failures
+            .iter()
+            .any(|failure| failure.view == view)
+        {
+            return;
+        }
+        let peer_id = self.get_primary_peer_id_for_view(view);
+        self.events.push(ConsensusEvent::PeerFaulty {
+            peer_id: peer_id.clone(),
+            view,
+        });
+        self.primary_failures.push(PrimaryFailure { view, peer_id });
+    }
+
+    /// Log, publish, and audit the end of the given view, now that this node is in the current
+    /// view; `voters` are the nodes whose `ViewChange` messages were counted for it
+    pub fn record_view_change(&mut self, ended: &ViewStats, voters: Vec<PeerId>) {
+        info!("{}: Finished {}", self, ended);
+        self.events.push(ConsensusEvent::ViewChanged {
+            from_view: ended.view,
+            view: self.view,
+            reason: ended.end_reason,
+        });
+        self.view_change_records
+            .push(ViewChangeRecord::new(ended, self.view, voters));
+    }
+
     /// Tell if this node is currently the primary
     pub fn is_primary(&self) -> bool {
         self.role == PbftNodeRole::Primary
@@ -230,18 +678,22 @@ impl PbftState {
         self.role = PbftNodeRole::Secondary;
     }
 
+    /// The sequence number of the last block this node committed, or asked the validator to
+    /// commit. `seq_num` only moves on once the next block is pre-prepared, so it's one past that
+    /// while the block is still being prepared or committed.
+    pub fn committed_seq_num(&self) -> u64 {
+        match self.phase {
+            PbftPhase::Preparing | PbftPhase::Checking | PbftPhase::Committing => {
+                self.seq_num.saturating_sub(1)
+            }
+            _ => self.seq_num,
+        }
+    }
+
     /// Go to a phase and return new phase, if successfully changed
     /// Enforces sequential ordering of PBFT phases in normal mode.
     pub fn switch_phase(&mut self, desired_phase: PbftPhase) -> Option<PbftPhase> {
-        let next = match self.phase {
-            PbftPhase::NotStarted => PbftPhase::PrePreparing,
-            PbftPhase::PrePreparing => PbftPhase::Preparing,
-            PbftPhase::Preparing => PbftPhase::Checking,
-            PbftPhase::Checking => PbftPhase::Committing,
-            PbftPhase::Committing => PbftPhase::Finished,
-            PbftPhase::Finished => PbftPhase::NotStarted,
-        };
-        if desired_phase == next {
+        if desired_phase == protocol::next_phase(&self.phase) {
             debug!("{}: Changing to {:?}", self, desired_phase);
             self.phase = desired_phase.clone();
             Some(desired_phase)
@@ -257,14 +709,40 @@ mod tests {
     use super::*;
     use config::mock_config;
 
-    /// Check that state responds to having an inadequately sized network
+    /// Check that state responds to having an inadequately sized network, and that a network of
+    /// just one node is allowed
     #[test]
     fn no_fault_tolerance() {
-        let config = mock_config(1);
+        let config = mock_config(2);
         let caught = ::std::panic::catch_unwind(|| {
             PbftState::new(0, &config);
         }).is_err();
         assert!(caught);
+
+        let state = PbftState::new(0, &mock_config(1));
+        assert!(state.single_node);
+        assert!(state.is_primary());
+        assert_eq!(state.f, 0);
+    }
+
+    /// Check that `f` can be configured lower than the network's size allows, but not higher,
+    /// and that quorums grow to make up for a lower `f`
+    #[test]
+    fn fault_tolerance() {
+        let mut config = mock_config(7);
+        let state = PbftState::new(0, &config);
+        assert_eq!((state.f, state.quorum()), (2, 5));
+
+        config.fault_tolerance = Some(1);
+        let state = PbftState::new(0, &config);
+        assert_eq!((state.f, state.quorum()), (1, 6));
+
+        config.fault_tolerance = Some(3);
+        let state = PbftState::new(0, &config);
+        assert_eq!((state.f, state.quorum()), (2, 5));
+
+        let state = PbftState::new(0, &mock_config(5));
+        assert_eq!((state.f, state.quorum()), (1, 3));
     }
 
     /// Check that the initial configuration of state is as we expect:
@@ -321,6 +799,102 @@ mod tests {
         assert!(state.is_primary());
     }
 
+    /// Make sure that changing the network's membership recalculates this node's ID, `f`, and role,
+    /// and that invalid memberships are rejected
+    #[test]
+    fn membership_changes() {
+        let config = mock_config(7);
+        let mut state = PbftState::new(1, &config);
+        state.view = 3;
+
+        // Node 0 leaves; this node becomes ID 0, and node 4 (now ID 3) is primary in view 3
+        assert!(state.set_peers(config.peers[1..].to_vec()).is_ok());
+        assert_eq!(state.id, 0);
+        assert_eq!(state.f, 1);
+        assert_eq!(state.get_primary_peer_id(), config.peers[4]);
+        assert!(!state.is_primary());
+
+        // Too few nodes
+        assert!(state.set_peers(config.peers[1..4].to_vec()).is_err());
+        assert_eq!(state.num_nodes(), 6);
+
+        // This node isn't a member
+        assert!(state.set_peers(config.peers[2..].to_vec()).is_err());
+        assert_eq!(state.id, 0);
+
+        // This node is removed; it stops taking part, but follows the new membership
+        let mut state = PbftState::new(1, &config);
+        state.upgrade_role();
+        assert!(state.leave(config.peers[2..5].to_vec()).is_err());
+        assert_eq!(state.mode, PbftMode::Normal);
+        assert!(state.leave(config.peers[2..].to_vec()).is_ok());
+        assert_eq!(state.mode, PbftMode::NonVoting);
+        assert!(!state.is_primary());
+        assert_eq!(state.num_nodes(), 5);
+        assert_eq!(state.get_own_peer_id(), config.peers[1]);
+
+        // Back to all 7 nodes; node 3 is primary in view 3
+        let mut state = PbftState::new(3, &config);
+        state.view = 3;
+        assert!(state.set_peers(config.peers.clone()).is_ok());
+        assert_eq!(state.f, 2);
+        assert!(state.is_primary());
+    }
+
+    /// Make sure that removing the current primary moves all remaining nodes to the next view
+    #[test]
+    fn primary_removal() {
+        let config = mock_config(5);
+        let mut state = PbftState::new(1, &config);
+        assert_eq!(state.get_primary_peer_id(), config.peers[0]);
+
+        // Node 0 is removed; view 1's primary (index 1 of the new list) is node 2
+        assert!(state.set_peers(config.peers[1..].to_vec()).is_ok());
+        assert_eq!(state.view, 1);
+        assert_eq!(state.get_primary_peer_id(), config.peers[2]);
+        assert!(!state.is_primary());
+
+        // Removing a secondary doesn't change the view
+        assert!(state.set_peers(config.peers[1..].to_vec()).is_ok());
+        let mut peers = config.peers[1..].to_vec();
+        peers.push(config.peers[0].clone());
+        assert!(state.set_peers(peers).is_ok());
+        assert_eq!(state.view, 1);
+    }
+
+    /// Make sure that resynchronizing keeps what the node shares with the network, and starts
+    /// everything else over
+    #[test]
+    fn resync() {
+        let config = mock_config(4);
+        let mut state = PbftState::new(3, &config);
+        state.view = 2;
+        state.seq_num = 5;
+        state.record_primary_failure(1);
+        state.phase = PbftPhase::Committing;
+        state.mode = PbftMode::ViewChanging;
+        let breaker = Rc::clone(&state.service_breaker);
+
+        // Node 0 was replaced by node 4, so this node is now ID 2 and primary in view 2
+        let mut new_config = mock_config(5);
+        new_config.peers.remove(0);
+        assert!(state.resync(&new_config).is_ok());
+        assert_eq!(state.id, 2);
+        assert_eq!(state.view, 2);
+        assert_eq!(state.seq_num, 5);
+        assert_eq!(state.primary_failures.len(), 1);
+        assert_eq!(state.phase, PbftPhase::NotStarted);
+        assert_eq!(state.mode, PbftMode::Normal);
+        assert!(state.is_primary());
+        assert!(Rc::ptr_eq(&state.service_breaker, &breaker));
+
+        // This node isn't a member
+        let mut new_config = mock_config(5);
+        new_config.peers.remove(3);
+        assert!(state.resync(&new_config).is_err());
+        assert_eq!(state.id, 2);
+    }
+
     /// Make sure that a normal PBFT cycle works properly
     /// `NotStarted` => `PrePreparing` => `Preparing` => `Committing` => `Finished` => `NotStarted`
     /// Also make sure that no illegal phase changes are allowed to happen
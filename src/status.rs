@@ -0,0 +1,659 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! A summary of whether a node is healthy
+//!
+//! `NodeStatus` collects what an operator checks first when a node seems to be misbehaving: its
+//! role and mode, how far behind the rest of the network it is, when it last committed a block,
+//! and which other nodes it has heard from recently. From those it decides whether the node is
+//! healthy, degraded (working, but not keeping up), or stuck (not making progress at all). The
+//! metrics server serves the status as JSON at `/status`.
+//!
+//! For a closer look, `state_to_json` describes everything the node currently believes about where
+//! the algorithm is, including its working block and timers; the metrics server serves it at
+//! `/state`.
+//!
+//! `StateSummary` condenses the same information into one line, which the engine logs every so
+//! often, so that a node's health over time can be followed from its log alone.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use hex;
+use serde_json::{self, Value};
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use circuit_breaker::BreakerState;
+use metrics;
+use peer_stats::PeerStats;
+use state::{PbftMode, PbftPhase, PbftState, WorkingBlockOption};
+use timing::{self, millis, Timeout};
+
+/// How long it can have been since a node was heard from for it to count as heard from recently
+const RECENTLY: Duration = Duration::from_secs(60);
+
+/// How far behind the rest of the network a node can be while still being healthy; being one
+/// block behind is normal, since other nodes may commit a block slightly sooner
+const MAX_HEALTHY_LAG: u64 = 1;
+
+/// How long a node can go without committing a block, while it has blocks to commit, before it's
+/// considered stuck
+const STUCK_AFTER: Duration = Duration::from_secs(300);
+
+/// A running timer with no more than this fraction of its duration left is close to expiring
+const CLOSE_TO_EXPIRING: f64 = 0.25;
+
+/// How well a node is doing, from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Health {
+    Healthy,
+    Degraded,
+    Stuck,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let health = match self {
+            Health::Healthy => "healthy",
+            Health::Degraded => "degraded",
+            Health::Stuck => "stuck",
+        };
+        write!(f, "{}", health)
+    }
+}
+
+/// A snapshot of a node's health, and what it's based on
+#[derive(Clone, Debug)]
+pub struct NodeStatus {
+    pub health: Health,
+
+    /// Why the node isn't healthy (empty if it is)
+    pub problems: Vec<String>,
+
+    pub is_primary: bool,
+    pub mode: PbftMode,
+    pub phase: PbftPhase,
+    pub view: u64,
+    pub seq_num: u64,
+    pub chain_head_num: u64,
+
+    /// How many blocks the rest of the network is ahead of this node
+    pub lag: u64,
+
+    /// How long it's been since this node committed a block (`None` if it hasn't yet)
+    pub since_last_commit: Option<Duration>,
+
+    /// How many of the other nodes this node has heard from recently
+    pub peers_heard_from: usize,
+
+    /// How many other nodes there are
+    pub peers: usize,
+
+    /// How many of the other nodes the validator reports as connected
+    pub peers_connected: usize,
+
+    /// The other nodes whose messages are being ignored, because they sent too many bad ones
+    pub quarantined: Vec<PeerId>,
+}
+
+impl NodeStatus {
+    pub fn new(state: &PbftState) -> Self {
+        let peer_stats = other_peer_stats(state);
+        let lag = lag(state);
+
+        let since_last_commit = state
+            .last_commit
+            .map(|last_commit| timing::now() - last_commit);
+        let peers_heard_from = peer_stats
+            .iter()
+            .filter_map(|stats| stats.and_then(|stats| stats.since_last_seen()))
+            .filter(|since| *since <= RECENTLY)
+            .count();
+
+        let mut status = NodeStatus {
+            health: Health::Healthy,
+            problems: Vec::new(),
+            is_primary: state.is_primary(),
+            mode: state.mode,
+            phase: state.phase.clone(),
+            view: state.view,
+            seq_num: state.seq_num,
+            chain_head_num: state.chain_head_num,
+            lag,
+            since_last_commit,
+            peers_heard_from,
+            peers: peer_stats.len(),
+            peers_connected: state
+                .peers()
+                .iter()
+                .filter(|peer_id| **peer_id != state.get_own_peer_id())
+                .filter(|peer_id| !state.outbox.is_disconnected(peer_id))
+                .count(),
+            quarantined: state
+                .quarantine
+                .quarantined()
+                .into_iter()
+                .cloned()
+                .collect(),
+        };
+
+        match state.mode {
+            PbftMode::Normal => {}
+            PbftMode::ViewChanging => {
+                status.report(Health::Degraded, String::from("view change in progress"))
+            }
+            PbftMode::Recovering => status.report(
+                Health::Degraded,
+                String::from("recovering from falling too far behind"),
+            ),
+            PbftMode::NonVoting => status.report(
+                Health::Degraded,
+                String::from("not a member of the network; following the chain without voting"),
+            ),
+        }
+        if state.maintenance {
+            status.report(
+                Health::Degraded,
+                String::from("in maintenance mode; not publishing blocks"),
+            );
+        }
+        if lag > MAX_HEALTHY_LAG {
+            status.report(Health::Degraded, format!("{} blocks behind", lag));
+        }
+        if (status.peers_heard_from as u64) + 1 < state.quorum() {
+            status.report(
+                Health::Degraded,
+                format!(
+                    "heard from only {} of {} other nodes recently",
+                    status.peers_heard_from, status.peers
+                ),
+            );
+        }
+
+        if (status.peers_connected as u64) + 1 < state.quorum() {
+            status.report(
+                Health::Degraded,
+                format!(
+                    "connected to only {} of {} other nodes; not enough for a quorum",
+                    status.peers_connected, status.peers
+                ),
+            );
+        }
+
+        let breaker = state.service_breaker.borrow();
+        if breaker.state() != BreakerState::Closed {
+            status.report(
+                Health::Degraded,
+                format!(
+                    "calls to the validator paused after repeated failures ({}); retrying in {}s",
+                    breaker.cause().unwrap_or("unknown error"),
+                    breaker.retry_in().as_secs()
+                ),
+            );
+        }
+
+        for (incident, detail) in state.incidents.ongoing() {
+            status.report(
+                Health::Degraded,
+                format!("needs an operator: {} ({})", incident, detail),
+            );
+        }
+
+        // A node that isn't a member has nothing to do but follow the chain, which may be idle
+        let has_work = lag > 0
+            || (state.mode != PbftMode::Normal && state.mode != PbftMode::NonVoting)
+            || !state.working_block.is_none();
+        if let Some(since) = since_last_commit {
+            if has_work && since > STUCK_AFTER {
+                status.report(
+                    Health::Stuck,
+                    format!("no block committed for {}s", since.as_secs()),
+                );
+            }
+        }
+
+        status
+    }
+
+    // Note a problem, and lower the node's health to at most the given level
+    fn report(&mut self, health: Health, problem: String) {
+        if health > self.health {
+            self.health = health;
+        }
+        self.problems.push(problem);
+    }
+
+    /// Write the status as JSON
+    pub fn to_json(&self) -> String {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+        fields.insert(String::from("health"), Value::from(self.health.to_string()));
+        fields.insert(
+            String::from("problems"),
+            Value::Array(self.problems.iter().cloned().map(Value::from).collect()),
+        );
+        fields.insert(String::from("is_primary"), Value::Bool(self.is_primary));
+        fields.insert(
+            String::from("mode"),
+            Value::from(format!("{:?}", self.mode)),
+        );
+        fields.insert(
+            String::from("phase"),
+            Value::from(format!("{:?}", self.phase)),
+        );
+        fields.insert(String::from("view"), Value::from(self.view));
+        fields.insert(String::from("seq_num"), Value::from(self.seq_num));
+        fields.insert(
+            String::from("chain_head_num"),
+            Value::from(self.chain_head_num),
+        );
+        fields.insert(String::from("lag"), Value::from(self.lag));
+        fields.insert(
+            String::from("seconds_since_last_commit"),
+            self.since_last_commit
+                .map_or(Value::Null, |since| Value::from(since.as_secs())),
+        );
+        fields.insert(
+            String::from("peers_heard_from"),
+            Value::from(self.peers_heard_from as u64),
+        );
+        fields.insert(String::from("peers"), Value::from(self.peers as u64));
+        fields.insert(
+            String::from("peers_connected"),
+            Value::from(self.peers_connected as u64),
+        );
+        fields.insert(
+            String::from("quarantined"),
+            Value::Array(
+                self.quarantined
+                    .iter()
+                    .map(|peer_id| Value::from(hex::encode(Vec::<u8>::from(peer_id.clone()))))
+                    .collect(),
+            ),
+        );
+        serde_json::to_string(&fields).expect("Couldn't write status as JSON")
+    }
+}
+
+// The stats of every member of the network other than this node (`None` for those it has never
+// heard from)
+fn other_peer_stats(state: &PbftState) -> Vec<Option<&PeerStats>> {
+    let own_peer_id = state.get_own_peer_id();
+    state
+        .peers()
+        .iter()
+        .filter(|peer_id| **peer_id != own_peer_id)
+        .map(|peer_id| state.peer_stats.get(peer_id))
+        .collect()
+}
+
+/// The highest sequence number that `f + 1` other members have sent messages for (0 if fewer
+/// than `f + 1` have been heard from)
+pub fn network_seq_num(state: &PbftState) -> u64 {
+    // At least one of the `f + 1` nodes that are furthest along is honest, so the network has
+    // reached the lowest of their sequence numbers
+    let mut highest_seq_nums: Vec<u64> = other_peer_stats(state)
+        .iter()
+        .map(|stats| stats.map_or(0, |stats| stats.highest_seq_num))
+        .collect();
+    highest_seq_nums.sort_by(|a, b| b.cmp(a));
+    highest_seq_nums.get(state.f as usize).cloned().unwrap_or(0)
+}
+
+/// How many blocks behind the rest of the network the node is
+pub fn lag(state: &PbftState) -> u64 {
+    network_seq_num(state).saturating_sub(state.seq_num)
+}
+
+/// Describe the node's live state as JSON: its place in the algorithm, the block it's working on,
+/// and its timers
+pub fn state_to_json(state: &PbftState) -> String {
+    let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+    fields.insert(String::from("id"), Value::from(state.id));
+    fields.insert(
+        String::from("peer_id"),
+        Value::from(hex::encode(Vec::<u8>::from(state.get_own_peer_id()))),
+    );
+    fields.insert(
+        String::from("primary"),
+        Value::from(hex::encode(Vec::<u8>::from(state.get_primary_peer_id()))),
+    );
+    fields.insert(String::from("is_primary"), Value::Bool(state.is_primary()));
+    fields.insert(String::from("view"), Value::from(state.view));
+    fields.insert(String::from("seq_num"), Value::from(state.seq_num));
+    fields.insert(
+        String::from("phase"),
+        Value::from(format!("{:?}", state.phase)),
+    );
+    fields.insert(
+        String::from("mode"),
+        Value::from(format!("{:?}", state.mode)),
+    );
+    fields.insert(String::from("maintenance"), Value::Bool(state.maintenance));
+    if state.mode == PbftMode::Recovering {
+        fields.insert(
+            String::from("recovery_target"),
+            Value::from(state.recovery_target),
+        );
+    }
+    fields.insert(
+        String::from("members"),
+        Value::from(state.peers().len() as u64),
+    );
+    fields.insert(String::from("f"), Value::from(state.f));
+    fields.insert(
+        String::from("chain_head"),
+        Value::from(hex::encode(Vec::<u8>::from(state.chain_head.clone()))),
+    );
+    fields.insert(
+        String::from("chain_head_num"),
+        Value::from(state.chain_head_num),
+    );
+    fields.insert(
+        String::from("working_block"),
+        working_block_to_json(&state.working_block),
+    );
+    fields.insert(
+        String::from("commit_timeout"),
+        timeout_to_json(&state.timeout),
+    );
+    fields.insert(
+        String::from("view_change_timeout"),
+        timeout_to_json(&state.view_change_timeout),
+    );
+    fields.insert(
+        String::from("idle_timeout"),
+        state
+            .idle_timeout
+            .as_ref()
+            .map_or(Value::Null, timeout_to_json),
+    );
+    serde_json::to_string(&fields).expect("Couldn't write state as JSON")
+}
+
+fn working_block_to_json(working_block: &WorkingBlockOption) -> Value {
+    let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+    let kind = match working_block {
+        WorkingBlockOption::NoWorkingBlock => "none",
+        WorkingBlockOption::TentativeWorkingBlock(block_id) => {
+            fields.insert(
+                String::from("block_id"),
+                Value::from(hex::encode(Vec::<u8>::from(block_id.clone()))),
+            );
+            "tentative"
+        }
+        WorkingBlockOption::WorkingBlock(block) => {
+            fields.insert(
+                String::from("block_id"),
+                Value::from(hex::encode(block.get_block_id())),
+            );
+            fields.insert(
+                String::from("block_num"),
+                Value::from(block.get_block_num()),
+            );
+            fields.insert(
+                String::from("signer_id"),
+                Value::from(hex::encode(block.get_signer_id())),
+            );
+            "working"
+        }
+    };
+    fields.insert(String::from("kind"), Value::from(kind));
+    Value::Object(fields.into_iter().collect())
+}
+
+// Whether the timer is stopped, running, or expired, and how long it has left
+fn timeout_to_json(timeout: &Timeout) -> Value {
+    let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+    let remaining = timeout.remaining();
+    let status = match remaining {
+        None => "stopped",
+        Some(remaining) if remaining == Duration::from_secs(0) => "expired",
+        Some(_) => "running",
+    };
+    fields.insert(String::from("status"), Value::from(status));
+    fields.insert(
+        String::from("remaining_ms"),
+        remaining.map_or(Value::Null, |remaining| Value::from(millis(remaining))),
+    );
+    fields.insert(
+        String::from("duration_ms"),
+        Value::from(millis(timeout.duration())),
+    );
+    Value::Object(fields.into_iter().collect())
+}
+
+/// Summarizes the node's state in one line, for logging at an interval
+#[derive(Debug)]
+pub struct StateSummary {
+    /// The chain head's block number as of the last summary
+    last_chain_head_num: u64,
+}
+
+impl StateSummary {
+    pub fn new(state: &PbftState) -> Self {
+        StateSummary {
+            last_chain_head_num: state.chain_head_num,
+        }
+    }
+
+    /// Describe where the node is, how many other nodes it has heard from recently, how many
+    /// blocks it has committed since the last summary, and which of its timers are close to
+    /// expiring
+    pub fn summarize(&mut self, state: &PbftState) -> String {
+        let status = NodeStatus::new(state);
+        let committed = state
+            .chain_head_num
+            .saturating_sub(self.last_chain_head_num);
+        self.last_chain_head_num = state.chain_head_num;
+
+        let mut timers = vec![
+            ("commit", Some(&state.timeout)),
+            ("view change", Some(&state.view_change_timeout)),
+            ("idle", state.idle_timeout.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, timeout)| {
+            let timeout = timeout?;
+            let remaining = timeout.remaining()?;
+            if metrics::seconds(remaining)
+                <= metrics::seconds(timeout.duration()) * CLOSE_TO_EXPIRING
+            {
+                Some(format!("{} timer {}ms left", name, millis(remaining)))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+        if timers.is_empty() {
+            timers.push(String::from("no timers close to expiring"));
+        }
+
+        format!(
+            "view {}, seq {}, {:?} ({:?}), heard from {}/{} peers, {} committed since last \
+             summary, {}",
+            state.view,
+            state.seq_num,
+            state.phase,
+            state.mode,
+            status.peers_heard_from,
+            status.peers,
+            committed,
+            timers.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use quarantine;
+    use sawtooth_sdk::consensus::engine::BlockId;
+
+    /// Make sure that a node's health reflects how far behind it is, whether it's heard from and
+    /// is connected to enough other nodes, and whether it has stopped committing blocks
+    #[test]
+    fn node_status() {
+        timing::start_virtual_time(1);
+        let mut state = PbftState::new(0, &mock_config(4));
+        state.seq_num = 10;
+
+        // Not having heard from any other nodes
+        let status = NodeStatus::new(&state);
+        assert_eq!(status.health, Health::Degraded);
+        assert_eq!(status.peers, 3);
+        assert_eq!(status.peers_heard_from, 0);
+
+        // Hearing from the others, one of which claims to be far ahead; a single node can't make
+        // this one look behind
+        for (i, seq_num) in [11, 11, 500].iter().enumerate() {
+            let mut stats = PeerStats::default();
+            stats.count_received("Commit");
+            stats.record_seq_num(*seq_num);
+            state.peer_stats.insert(state.peers()[i + 1].clone(), stats);
+        }
+        let status = NodeStatus::new(&state);
+        assert_eq!(status.lag, 1);
+        assert_eq!(status.health, Health::Healthy);
+        assert!(status.problems.is_empty());
+
+        // Behind the network, and not committing anything
+        let peer_id = state.peers()[2].clone();
+        state
+            .peer_stats
+            .get_mut(&peer_id)
+            .unwrap()
+            .record_seq_num(15);
+        state.last_commit = Some(timing::now());
+        timing::set_virtual_time(timing::now() + Duration::from_secs(30));
+        let status = NodeStatus::new(&state);
+        assert_eq!(status.lag, 5);
+        assert_eq!(status.health, Health::Degraded);
+        assert_eq!(status.since_last_commit, Some(Duration::from_secs(30)));
+
+        timing::set_virtual_time(timing::now() + STUCK_AFTER);
+        let status = NodeStatus::new(&state);
+        assert_eq!(status.health, Health::Stuck);
+        assert_eq!(status.problems.len(), 3);
+        assert!(status.to_json().contains("\"health\":\"stuck\""));
+
+        // A node that sent too many bad messages is listed, without affecting this node's health
+        let faulty = state.peers()[3].clone();
+        for _ in 0..quarantine::MAX_FAILURES {
+            state.quarantine.record_failure(&faulty);
+        }
+        let status = NodeStatus::new(&state);
+        assert_eq!(status.quarantined, vec![faulty]);
+        assert_eq!(status.problems.len(), 3);
+
+        // An idle network isn't stuck, even if nothing is committed for a long time
+        let mut state = PbftState::new(0, &mock_config(4));
+        state.working_block = WorkingBlockOption::NoWorkingBlock;
+        state.last_commit = Some(timing::now());
+        timing::set_virtual_time(timing::now() + STUCK_AFTER * 2);
+        for peer_id in state.peers()[1..].to_vec() {
+            let mut stats = PeerStats::default();
+            stats.count_received("Heartbeat");
+            state.peer_stats.insert(peer_id, stats);
+        }
+        assert_eq!(NodeStatus::new(&state).health, Health::Healthy);
+
+        // Disconnected nodes may still have been heard from recently, but can't form a quorum
+        for peer_id in state.peers()[2..].to_vec() {
+            state.outbox.disconnect(peer_id);
+        }
+        let status = NodeStatus::new(&state);
+        assert_eq!(status.peers_connected, 1);
+        assert_eq!(status.health, Health::Degraded);
+        assert_eq!(status.problems.len(), 1);
+
+        timing::stop_virtual_time();
+    }
+
+    /// Make sure that the state dump has the node's place in the algorithm, its working block, and
+    /// its timers
+    #[test]
+    fn state_json() {
+        timing::start_virtual_time(1);
+        let mut state = PbftState::new(1, &mock_config(4));
+        state.seq_num = 7;
+        state.working_block =
+            WorkingBlockOption::TentativeWorkingBlock(BlockId::from(vec![0xab, 0xcd]));
+        state.timeout.start();
+        timing::set_virtual_time(timing::now() + Duration::from_millis(250));
+
+        let json: Value = serde_json::from_str(&state_to_json(&state)).unwrap();
+        assert_eq!(json.get("seq_num").and_then(Value::as_u64), Some(7));
+        assert_eq!(json.get("is_primary").and_then(Value::as_bool), Some(false));
+        assert_eq!(json.get("mode").and_then(Value::as_str), Some("Normal"));
+        let working_block = json.get("working_block").unwrap();
+        assert_eq!(
+            working_block.get("kind").and_then(Value::as_str),
+            Some("tentative")
+        );
+        assert_eq!(
+            working_block.get("block_id").and_then(Value::as_str),
+            Some("abcd")
+        );
+
+        let commit_timeout = json.get("commit_timeout").unwrap();
+        assert_eq!(
+            commit_timeout.get("status").and_then(Value::as_str),
+            Some("running")
+        );
+        assert_eq!(
+            commit_timeout.get("remaining_ms").and_then(Value::as_u64),
+            Some(millis(state.timeout.duration()) - 250)
+        );
+        assert_eq!(
+            json.get("view_change_timeout")
+                .and_then(|timeout| timeout.get("status"))
+                .and_then(Value::as_str),
+            Some("stopped")
+        );
+
+        timing::stop_virtual_time();
+    }
+
+    /// Make sure that the summary counts the blocks committed since the last one, and only
+    /// mentions timers that are close to expiring
+    #[test]
+    fn state_summary() {
+        timing::start_virtual_time(1);
+        let mut state = PbftState::new(0, &mock_config(4));
+        state.seq_num = 3;
+        state.chain_head_num = 2;
+        let mut summary = StateSummary::new(&state);
+
+        state.chain_head_num = 5;
+        state.timeout.start();
+        let line = summary.summarize(&state);
+        assert!(line.starts_with("view 0, seq 3, "));
+        assert!(line.contains("heard from 0/3 peers, 3 committed since last summary"));
+        assert!(line.ends_with("no timers close to expiring"));
+
+        timing::set_virtual_time(timing::now() + state.timeout.duration() * 9 / 10);
+        let line = summary.summarize(&state);
+        assert!(line.contains("0 committed since last summary"));
+        assert!(line.contains("commit timer"));
+        assert!(!line.contains("view change timer"));
+
+        timing::stop_virtual_time();
+    }
+}
@@ -0,0 +1,387 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Saving a node's view change progress to disk
+//!
+//! Without this, a node that restarts in the middle of a view change comes back in view 0 with no
+//! memory of the `ViewChange` it already sent, so it may vote for a different view than before, or
+//! lag behind the view the rest of the network moved to. The progress is written to a new file
+//! that then replaces the old one, so a crash while saving leaves the last complete copy behind.
+//! It carries a checksum, and it's checked for progress that couldn't have been saved; if either
+//! check fails, the progress is refused, as if it couldn't be read at all.
+//!
+//! The same directory holds an audit trail of every view change the node has made, for reviewing
+//! incidents after the fact. It is only ever appended to, one line of JSON per view change, so it
+//! keeps the node's whole history across restarts. When each block became final on the node is
+//! kept the same way, in a file of its own.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use protobuf::{self, Message};
+use serde_json::{self, Value};
+
+use finality::FinalityRecord;
+use protos::pbft_message::PbftViewChangeProgress;
+use timing;
+use view_stats::ViewChangeRecord;
+
+/// The name of the file that the progress is saved in
+const PROGRESS_FILE: &str = "pbft-view-change-progress";
+
+/// The extension given to a progress file that couldn't be loaded, when it's set aside
+const UNUSABLE_EXTENSION: &str = "unusable";
+
+/// The name of the file that view changes are appended to
+const AUDIT_FILE: &str = "pbft-view-change-audit";
+
+/// The name of the file that blocks' finality records are appended to
+const FINALITY_FILE: &str = "pbft-block-finality";
+
+/// Saves and loads a node's view change progress in a directory, and keeps its audit trail there
+#[derive(Debug)]
+pub struct ViewChangeStorage {
+    path: PathBuf,
+    audit_path: PathBuf,
+    finality_path: PathBuf,
+
+    /// The progress that was last saved or loaded, so unchanged progress isn't written again
+    saved: Option<PbftViewChangeProgress>,
+}
+
+impl ViewChangeStorage {
+    /// Keep the progress in the given directory, creating it if necessary
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(ViewChangeStorage {
+            path: dir.join(PROGRESS_FILE),
+            audit_path: dir.join(AUDIT_FILE),
+            finality_path: dir.join(FINALITY_FILE),
+            saved: None,
+        })
+    }
+
+    /// Load the saved progress, if any has been saved
+    pub fn load(&mut self) -> io::Result<Option<PbftViewChangeProgress>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let mut progress: PbftViewChangeProgress = protobuf::parse_from_bytes(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let checksum = progress.get_checksum();
+        progress.clear_checksum();
+        if checksum != 0 && checksum != crc32(&serialize(&progress)?) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Saved progress doesn't match its checksum",
+            ));
+        }
+        check_integrity(&progress)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        self.saved = Some(progress.clone());
+        Ok(Some(progress))
+    }
+
+    /// Replace the saved progress, unless it hasn't changed
+    pub fn save(&mut self, progress: &PbftViewChangeProgress) -> io::Result<()> {
+        if self.saved.as_ref() == Some(progress) {
+            return Ok(());
+        }
+
+        let mut checked = progress.clone();
+        checked.clear_checksum();
+        checked.set_checksum(crc32(&serialize(&checked)?));
+        let bytes = serialize(&checked)?;
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        // Make sure the rename itself survives a crash
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.saved = Some(progress.clone());
+        Ok(())
+    }
+
+    /// Move the saved progress out of the way, after it couldn't be loaded, so it can be looked
+    /// at later and new progress can be saved in its place. Returns where it was moved to.
+    pub fn set_aside(&mut self) -> io::Result<PathBuf> {
+        let unusable_path = self.path.with_extension(UNUSABLE_EXTENSION);
+        fs::rename(&self.path, &unusable_path)?;
+        self.saved = None;
+        Ok(unusable_path)
+    }
+
+    /// Add an incident, such as having to rebuild this node's state, to the end of the audit
+    /// trail, with what happened and why
+    pub fn append_incident(&mut self, incident: &str, detail: &str) -> io::Result<()> {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+        fields.insert(String::from("time"), Value::from(timing::unix_millis()));
+        fields.insert(String::from("incident"), Value::from(incident));
+        fields.insert(String::from("detail"), Value::from(detail));
+        let line = serde_json::to_string(&fields).expect("Couldn't write incident as JSON");
+        append_lines(&self.audit_path, Some(line).into_iter())
+    }
+
+    /// Add the given view changes to the end of the audit trail
+    pub fn append_to_audit_trail(&mut self, records: &[ViewChangeRecord]) -> io::Result<()> {
+        append_lines(
+            &self.audit_path,
+            records.iter().map(ViewChangeRecord::to_json),
+        )
+    }
+
+    /// Add the given blocks' finality records to the end of the finality file
+    pub fn append_finality_records(&mut self, records: &[FinalityRecord]) -> io::Result<()> {
+        append_lines(
+            &self.finality_path,
+            records.iter().map(FinalityRecord::to_json),
+        )
+    }
+}
+
+fn serialize(progress: &PbftViewChangeProgress) -> io::Result<Vec<u8>> {
+    progress
+        .write_to_bytes()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// Check for progress that this node could never have saved, which means the file was damaged in a
+// way that the checksum didn't catch, or was saved without a checksum
+fn check_integrity(progress: &PbftViewChangeProgress) -> Result<(), String> {
+    let view = progress.get_view();
+    if progress.get_target_view() != 0 && progress.get_target_view() <= view {
+        return Err(format!(
+            "Saved view change target {} isn't after view {}",
+            progress.get_target_view(),
+            view
+        ));
+    }
+    if let Some(vc) = progress
+        .get_view_changes()
+        .iter()
+        .find(|vc| vc.get_info().get_view() <= view)
+    {
+        return Err(format!(
+            "Saved ViewChange for view {} isn't after view {}",
+            vc.get_info().get_view(),
+            view
+        ));
+    }
+    for vote in progress.get_votes() {
+        match vote.get_msg_type() {
+            "PrePrepare" | "Prepare" | "Commit" => {}
+            msg_type => return Err(format!("Saved vote has unknown type {:?}", msg_type)),
+        }
+        if vote.get_view() > view {
+            return Err(format!(
+                "Saved vote for view {} is after view {}",
+                vote.get_view(),
+                view
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The CRC-32 (IEEE) of the given bytes
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Add each line to the end of the file, creating it if it doesn't exist yet
+fn append_lines<I: Iterator<Item = String>>(path: &Path, lines: I) -> io::Result<()> {
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    if contents.is_empty() {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use finality::FinalityHistory;
+    use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+    use serde_json::{self, Value};
+    use std::env;
+    use view_stats::{ViewChangeReason, ViewHistory};
+
+    /// Make sure that saved progress is loaded back the same, replacing what was saved before it,
+    /// and that there's nothing to load before anything is saved
+    #[test]
+    fn storage() {
+        let dir = env::temp_dir().join("pbft-storage-test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut storage = ViewChangeStorage::new(&dir).unwrap();
+        assert_eq!(storage.load().unwrap(), None);
+
+        let mut progress = PbftViewChangeProgress::new();
+        progress.set_view(2);
+        storage.save(&progress).unwrap();
+        progress.set_target_view(3);
+        storage.save(&progress).unwrap();
+        assert_eq!(storage.load().unwrap(), Some(progress.clone()));
+
+        // A new storage for the same directory picks up the saved progress
+        let mut restarted = ViewChangeStorage::new(&dir).unwrap();
+        assert_eq!(restarted.load().unwrap(), Some(progress));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Make sure that progress which was damaged on disk, or which this node couldn't have saved,
+    /// is refused, and that it can be set aside and the incident added to the audit trail
+    #[test]
+    fn unusable_progress() {
+        let dir = env::temp_dir().join("pbft-unusable-progress-test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut storage = ViewChangeStorage::new(&dir).unwrap();
+
+        let mut progress = PbftViewChangeProgress::new();
+        progress.set_view(2);
+        storage.save(&progress).unwrap();
+
+        // Progress saved without a checksum is still loaded
+        fs::write(dir.join(PROGRESS_FILE), progress.write_to_bytes().unwrap()).unwrap();
+        assert_eq!(storage.load().unwrap(), Some(progress.clone()));
+
+        // Change the view without updating the checksum
+        storage.save(&PbftViewChangeProgress::new()).unwrap();
+        storage.save(&progress).unwrap();
+        let mut bytes = fs::read(dir.join(PROGRESS_FILE)).unwrap();
+        assert_eq!(&bytes[..2], &[0x08, 0x02]);
+        bytes[1] = 0x03;
+        fs::write(dir.join(PROGRESS_FILE), &bytes).unwrap();
+        assert_eq!(
+            storage.load().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // A view change target that isn't after the view is refused, even with a good checksum
+        progress.set_target_view(2);
+        storage.save(&progress).unwrap();
+        assert!(storage.load().is_err());
+
+        let unusable = storage.set_aside().unwrap();
+        assert!(unusable.exists());
+        assert_eq!(storage.load().unwrap(), None);
+
+        storage.append_incident("state_rebuilt", "corrupt").unwrap();
+        let trail = fs::read_to_string(dir.join(AUDIT_FILE)).unwrap();
+        let entry: Value = serde_json::from_str(trail.trim()).unwrap();
+        assert_eq!(
+            entry.get("incident").and_then(Value::as_str),
+            Some("state_rebuilt")
+        );
+        assert_eq!(entry.get("detail").and_then(Value::as_str), Some("corrupt"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Make sure that view changes and finality records are appended to the audit trail, one line
+    /// each, and that a restarted node adds to the trail instead of replacing it
+    #[test]
+    fn audit_trail() {
+        let dir = env::temp_dir().join("pbft-audit-trail-test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut storage = ViewChangeStorage::new(&dir).unwrap();
+
+        let mut history = ViewHistory::new(0);
+        history.record_end_reason(ViewChangeReason::CommitTimeout);
+        let ended = history.enter_view(1);
+        let voters = vec![
+            PeerId::from(vec![2]),
+            PeerId::from(vec![1]),
+            PeerId::from(vec![2]),
+        ];
+        let first = ViewChangeRecord::new(&ended, 1, voters);
+        assert_eq!(
+            first.voters,
+            vec![PeerId::from(vec![1]), PeerId::from(vec![2])]
+        );
+        storage.append_to_audit_trail(&[first.clone()]).unwrap();
+
+        let ended = history.enter_view(2);
+        let second = ViewChangeRecord::new(&ended, 2, vec![]);
+        assert_eq!(second.view_change_duration, None);
+        let mut restarted = ViewChangeStorage::new(&dir).unwrap();
+        restarted.append_to_audit_trail(&[second.clone()]).unwrap();
+
+        let trail = fs::read_to_string(dir.join(AUDIT_FILE)).unwrap();
+        let lines: Vec<&str> = trail.lines().collect();
+        assert_eq!(lines, vec![first.to_json(), second.to_json()]);
+
+        let entry: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry.get("from_view").and_then(Value::as_u64), Some(0));
+        assert_eq!(entry.get("view").and_then(Value::as_u64), Some(1));
+        assert_eq!(
+            entry.get("reason").and_then(Value::as_str),
+            Some("CommitTimeout")
+        );
+        assert_eq!(
+            entry.get("voters").and_then(Value::as_array).map(Vec::len),
+            Some(2)
+        );
+
+        // Finality records go in a file of their own
+        let mut finality = FinalityHistory::new();
+        finality.record(5, BlockId::from(vec![5]), 2, None, false, vec![]);
+        let finality_records = finality.take_unsaved();
+        restarted
+            .append_finality_records(&finality_records)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join(FINALITY_FILE)).unwrap(),
+            format!("{}\n", finality_records[0].to_json())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
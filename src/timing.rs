@@ -17,7 +17,69 @@
 
 //! Timing-related structures
 
-use std::time::{Duration, Instant};
+#[cfg(any(test, feature = "simulator"))]
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(any(test, feature = "simulator"))]
+thread_local! {
+    // The virtual time of the simulation running on this thread, if any (see `simulation`)
+    static VIRTUAL_TIME: Cell<Option<Instant>> = Cell::new(None);
+
+    // The state of the simulation's random number generator
+    static VIRTUAL_RNG: Cell<u64> = Cell::new(0);
+}
+
+/// Get the current time. All of the node's timers read the time through this, so that in tests
+/// (and the `pbft-sim` simulator), a simulation can replace it with a virtual clock.
+#[cfg(not(any(test, feature = "simulator")))]
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+#[cfg(any(test, feature = "simulator"))]
+pub fn now() -> Instant {
+    VIRTUAL_TIME
+        .with(|time| time.get())
+        .unwrap_or_else(Instant::now)
+}
+
+/// Get the system's wall-clock time, in milliseconds since the Unix epoch. Unlike `now`, this can
+/// be compared with other nodes' clocks (and can jump if the system's clock is set).
+pub fn unix_millis() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    millis(since_epoch)
+}
+
+/// Get a duration in whole milliseconds, for JSON, metrics, and anything else that counts in them
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+/// Replace the clock on this thread with a virtual one that starts at the current time and only
+/// moves when it's set, and make random numbers on this thread come from the given seed
+#[cfg(any(test, feature = "simulator"))]
+pub fn start_virtual_time(seed: u64) {
+    VIRTUAL_TIME.with(|time| time.set(Some(Instant::now())));
+    VIRTUAL_RNG.with(|rng| rng.set(seed | 1));
+}
+
+/// Move the virtual clock to the given time
+#[cfg(any(test, feature = "simulator"))]
+pub fn set_virtual_time(now: Instant) {
+    VIRTUAL_TIME.with(|time| time.set(Some(now)));
+}
+
+/// Go back to the real clock and real random numbers on this thread
+#[cfg(any(test, feature = "simulator"))]
+pub fn stop_virtual_time() {
+    VIRTUAL_TIME.with(|time| time.set(None));
+}
 
 /// Encapsulates calling a function every so often
 pub struct Ticker {
@@ -28,17 +90,17 @@ pub struct Ticker {
 impl Ticker {
     pub fn new(period: Duration) -> Self {
         Ticker {
-            last: Instant::now(),
+            last: now(),
             timeout: period,
         }
     }
 
     // Do some work if the timeout has expired
     pub fn tick<T: FnMut()>(&mut self, mut callback: T) {
-        let elapsed = Instant::now() - self.last;
+        let elapsed = now() - self.last;
         if elapsed >= self.timeout {
             callback();
-            self.last = Instant::now();
+            self.last = now();
         }
     }
 }
@@ -64,13 +126,13 @@ impl Timeout {
         Timeout {
             state: TimeoutState::Inactive,
             duration,
-            start: Instant::now(),
+            start: now(),
         }
     }
 
     /// Update the timer state, and check if the timer is expired
     pub fn check_expired(&mut self) -> bool {
-        if self.state == TimeoutState::Active && Instant::now() - self.start > self.duration {
+        if self.state == TimeoutState::Active && now() - self.start > self.duration {
             self.state = TimeoutState::Expired;
         }
         match self.state {
@@ -81,15 +143,170 @@ impl Timeout {
 
     pub fn start(&mut self) {
         self.state = TimeoutState::Active;
-        self.start = Instant::now();
+        self.start = now();
     }
 
     pub fn stop(&mut self) {
         self.state = TimeoutState::Inactive;
-        self.start = Instant::now();
+        self.start = now();
+    }
+
+    /// Tell if the timer has been started, and hasn't been stopped or expired since
+    pub fn is_active(&mut self) -> bool {
+        !self.check_expired() && self.state == TimeoutState::Active
+    }
+
+    /// How long it's been since the timer was last started or stopped
+    pub fn elapsed(&self) -> Duration {
+        now() - self.start
+    }
+
+    /// How long until the timer expires, if it's running; zero once it has expired, even if that
+    /// hasn't been checked for yet
+    pub fn remaining(&self) -> Option<Duration> {
+        match self.state {
+            TimeoutState::Inactive => None,
+            TimeoutState::Expired => Some(Duration::from_secs(0)),
+            TimeoutState::Active => Some(
+                self.duration
+                    .checked_sub(self.elapsed())
+                    .unwrap_or_else(|| Duration::from_secs(0)),
+            ),
+        }
+    }
+
+    /// How long the timer lasts
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Change how long the timer lasts; takes effect the next time the timer is checked
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+}
+
+/// How many of the most recent commit latencies are kept by an `AdaptiveTimeout`
+const LATENCY_WINDOW: usize = 100;
+
+/// Picks the commit timeout based on how long recent blocks took to commit: the 99th percentile of
+/// the recent commit latencies, multiplied by a factor and kept within the given bounds
+#[derive(Debug)]
+pub struct AdaptiveTimeout {
+    latencies: VecDeque<Duration>,
+    factor: f64,
+    min: Duration,
+    max: Duration,
+}
+
+impl AdaptiveTimeout {
+    pub fn new(factor: f64, min: Duration, max: Duration) -> Self {
+        AdaptiveTimeout {
+            latencies: VecDeque::with_capacity(LATENCY_WINDOW),
+            factor,
+            min,
+            max,
+        }
+    }
+
+    /// Add the latency of a block commit, dropping the oldest one if the window is full
+    pub fn record(&mut self, latency: Duration) {
+        if self.latencies.len() == LATENCY_WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+    }
+
+    /// Get the timeout to use for the next block (the maximum, if no commits have been recorded)
+    pub fn duration(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return self.max;
+        }
+
+        let mut sorted: Vec<Duration> = self.latencies.iter().cloned().collect();
+        sorted.sort();
+        let index = ((sorted.len() * 99 + 99) / 100).saturating_sub(1);
+        let p99 = sorted[index];
+
+        let duration = Duration::from_millis((millis(p99) as f64 * self.factor) as u64);
+
+        if duration < self.min {
+            self.min
+        } else if duration > self.max {
+            self.max
+        } else {
+            duration
+        }
     }
 }
 
+/// The most times the view change duration is doubled, no matter how many view changes fail
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+/// Decides how long to wait for a view change to complete before trying the next view. The wait
+/// doubles with each consecutive failed view change, and a random amount of up to half of it is
+/// added, so nodes that are retrying don't stay in step with each other.
+#[derive(Debug)]
+pub struct ViewChangeBackoff {
+    base: Duration,
+    attempts: u32,
+}
+
+impl ViewChangeBackoff {
+    pub fn new(base: Duration) -> Self {
+        ViewChangeBackoff { base, attempts: 0 }
+    }
+
+    /// How many view changes have failed in a row
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Record that a view change failed to complete in time
+    pub fn fail(&mut self) {
+        self.attempts += 1;
+    }
+
+    /// Start over, after a view change completed
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Get how long to wait for the current view change, including a new random jitter
+    pub fn duration(&self) -> Duration {
+        let wait = self.base * 2u32.pow(self.attempts.min(MAX_BACKOFF_DOUBLINGS));
+        wait + Duration::from_millis(random_u64() % (millis(wait) / 2 + 1))
+    }
+}
+
+/// Get a random number; every `RandomState` is seeded differently, so this doesn't need an RNG
+#[cfg(not(any(test, feature = "simulator")))]
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+/// In a simulation, random numbers come from the simulation's seed (using xorshift), so that
+/// every run with the same seed goes the same way
+#[cfg(any(test, feature = "simulator"))]
+pub fn random_u64() -> u64 {
+    if VIRTUAL_TIME.with(|time| time.get()).is_none() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        return hasher.finish();
+    }
+
+    VIRTUAL_RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        x
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,4 +364,80 @@ mod tests {
         t.stop();
         assert_eq!(t.state, TimeoutState::Inactive);
     }
+
+    /// Make sure that a timer's remaining time is only given while it's running, and is zero once
+    /// it has run out, whether or not that has been checked for yet
+    #[test]
+    fn timeout_remaining() {
+        let mut t = Timeout::new(Duration::from_millis(100));
+        assert_eq!(t.remaining(), None);
+
+        t.start();
+        assert!(t.remaining().unwrap() > Duration::from_millis(50));
+        ::std::thread::sleep(Duration::from_millis(110));
+        assert_eq!(t.remaining(), Some(Duration::from_secs(0)));
+
+        assert!(t.check_expired());
+        assert_eq!(t.remaining(), Some(Duration::from_secs(0)));
+
+        t.stop();
+        assert_eq!(t.remaining(), None);
+    }
+
+    /// Check that the adaptive timeout follows the 99th percentile of the recent latencies, scaled
+    /// by the factor and kept within its bounds
+    #[test]
+    fn adaptive_timeout() {
+        let mut t = AdaptiveTimeout::new(
+            3.0,
+            Duration::from_millis(1000),
+            Duration::from_millis(10000),
+        );
+        assert_eq!(t.duration(), Duration::from_millis(10000));
+
+        // Below the minimum
+        t.record(Duration::from_millis(100));
+        assert_eq!(t.duration(), Duration::from_millis(1000));
+
+        // The slowest 1% of commits set the timeout, until they fall out of the window
+        for _ in 0..98 {
+            t.record(Duration::from_millis(500));
+        }
+        t.record(Duration::from_millis(2000));
+        assert_eq!(t.duration(), Duration::from_millis(1500));
+        t.record(Duration::from_millis(2000));
+        assert_eq!(t.duration(), Duration::from_millis(6000));
+        for _ in 0..100 {
+            t.record(Duration::from_millis(500));
+        }
+        assert_eq!(t.duration(), Duration::from_millis(1500));
+
+        // Above the maximum
+        t.record(Duration::from_millis(5000));
+        t.record(Duration::from_millis(5000));
+        assert_eq!(t.duration(), Duration::from_millis(10000));
+    }
+
+    /// Check that the view change duration doubles with each failure, up to the limit, with up to
+    /// half of it added as jitter, and starts over once reset
+    #[test]
+    fn view_change_backoff() {
+        let base = Duration::from_millis(1000);
+        let mut backoff = ViewChangeBackoff::new(base);
+
+        for attempts in 0..10 {
+            assert_eq!(backoff.attempts(), attempts);
+            let wait = base * 2u32.pow(attempts.min(MAX_BACKOFF_DOUBLINGS));
+            for _ in 0..20 {
+                let duration = backoff.duration();
+                assert!(duration >= wait);
+                assert!(duration <= wait + wait / 2);
+            }
+            backoff.fail();
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert!(backoff.duration() <= base + base / 2);
+    }
 }
@@ -0,0 +1,125 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Tracing spans for calls to the validator
+//!
+//! The node calls its `Service` from many places, so rather than instrumenting each call, the
+//! engine wraps the service in a `TracedService`. Every call then gets a `service` span, named
+//! after the method, and shows up inside the span of the update that made it.
+
+use std::collections::HashMap;
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error, PeerId};
+use sawtooth_sdk::consensus::service::Service;
+
+use tracing;
+
+/// A `Service` that makes a span for each call to the one it wraps
+pub struct TracedService {
+    inner: Box<Service>,
+}
+
+impl TracedService {
+    pub fn new(inner: Box<Service>) -> Self {
+        TracedService { inner }
+    }
+}
+
+impl Service for TracedService {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "send_to", message_type).entered();
+        self.inner.send_to(peer, message_type, payload)
+    }
+
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "broadcast", message_type).entered();
+        self.inner.broadcast(message_type, payload)
+    }
+
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "initialize_block").entered();
+        self.inner.initialize_block(previous_id)
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        let _span = tracing::debug_span!("service", call = "summarize_block").entered();
+        self.inner.summarize_block()
+    }
+
+    fn finalize_block(&mut self, data: Vec<u8>) -> Result<BlockId, Error> {
+        let _span = tracing::debug_span!("service", call = "finalize_block").entered();
+        self.inner.finalize_block(data)
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "cancel_block").entered();
+        self.inner.cancel_block()
+    }
+
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "check_blocks").entered();
+        self.inner.check_blocks(priority)
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "commit_block").entered();
+        self.inner.commit_block(block_id)
+    }
+
+    fn ignore_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "ignore_block").entered();
+        self.inner.ignore_block(block_id)
+    }
+
+    fn fail_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        let _span = tracing::debug_span!("service", call = "fail_block").entered();
+        self.inner.fail_block(block_id)
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        let _span = tracing::debug_span!("service", call = "get_blocks").entered();
+        self.inner.get_blocks(block_ids)
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        let _span = tracing::debug_span!("service", call = "get_chain_head").entered();
+        self.inner.get_chain_head()
+    }
+
+    fn get_settings(
+        &mut self,
+        block_id: BlockId,
+        settings: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        let _span = tracing::debug_span!("service", call = "get_settings").entered();
+        self.inner.get_settings(block_id, settings)
+    }
+
+    fn get_state(
+        &mut self,
+        block_id: BlockId,
+        addresses: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u8>>, Error> {
+        let _span = tracing::debug_span!("service", call = "get_state").entered();
+        self.inner.get_state(block_id, addresses)
+    }
+}
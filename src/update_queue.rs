@@ -0,0 +1,120 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Measuring how far the engine's event loop is falling behind the validator's updates
+//!
+//! The engine handles every update from the validator on a single thread, so if it can't keep up,
+//! updates pile up in the channel they arrive on. The channel doesn't say how many are waiting, so
+//! an `UpdateQueue` takes each update off it as soon as it arrives, on a thread of its own, notes
+//! when it arrived, and passes it on. The engine then knows how many updates are still waiting and
+//! how long each one waited before it was handled.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sawtooth_sdk::consensus::engine::Update;
+
+/// The validator's updates, with how long each one waited to be handled
+pub struct UpdateQueue {
+    receiver: Receiver<(Update, Instant)>,
+
+    /// How many updates have arrived but haven't been taken off the queue yet
+    depth: Arc<AtomicUsize>,
+}
+
+impl UpdateQueue {
+    /// Start passing on the updates from the given channel; the queue is disconnected once the
+    /// channel is
+    pub fn new(updates: Receiver<Update>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        let arrived = Arc::clone(&depth);
+        thread::spawn(move || {
+            for update in updates {
+                arrived.fetch_add(1, Ordering::SeqCst);
+                if sender.send((update, Instant::now())).is_err() {
+                    break;
+                }
+            }
+        });
+
+        UpdateQueue { receiver, depth }
+    }
+
+    /// Wait up to `timeout` for the next update, and return it with how long it was queued
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<(Update, Duration), RecvTimeoutError> {
+        let (update, arrived) = self.receiver.recv_timeout(timeout)?;
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        Ok((update, arrived.elapsed()))
+    }
+
+    /// Take the next update if one is waiting, with how long it was queued
+    pub fn try_recv(&self) -> Option<(Update, Duration)> {
+        let (update, arrived) = self.receiver.try_recv().ok()?;
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        Some((update, arrived.elapsed()))
+    }
+
+    /// How many updates are waiting to be handled
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that updates are passed on in order, that the queue counts the ones waiting, and
+    /// that it's disconnected along with the validator's channel
+    #[test]
+    fn update_queue() {
+        let (sender, receiver) = mpsc::channel();
+        let queue = UpdateQueue::new(receiver);
+        sender.send(Update::Shutdown).unwrap();
+        sender.send(Update::BlockCommit(vec![1].into())).unwrap();
+
+        // Give the queue's thread a chance to take both updates
+        let start = Instant::now();
+        while queue.depth() < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(queue.depth(), 2);
+        thread::sleep(Duration::from_millis(20));
+
+        match queue.recv_timeout(Duration::from_secs(1)) {
+            Ok((Update::Shutdown, queued)) => assert!(queued >= Duration::from_millis(20)),
+            _ => panic!("Expected the first update"),
+        }
+        assert_eq!(queue.depth(), 1);
+        match queue.recv_timeout(Duration::from_secs(1)) {
+            Ok((Update::BlockCommit(_), _)) => (),
+            _ => panic!("Expected the second update"),
+        }
+        assert_eq!(queue.depth(), 0);
+
+        drop(sender);
+        match queue.recv_timeout(Duration::from_secs(1)) {
+            Err(RecvTimeoutError::Disconnected) => (),
+            _ => panic!("Expected the queue to be disconnected"),
+        }
+    }
+}
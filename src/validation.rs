@@ -0,0 +1,163 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Checks on the form of incoming peer messages, done before they are fully decoded and handled,
+//! and counts of the messages each node has had dropped
+
+use sawtooth_sdk::consensus::engine::PeerMessage;
+
+use protos::pbft_message::PbftMessageInfo;
+
+use error::PbftError;
+use handlers::TRACE_ID_LENGTH;
+use message_type::PbftMessageType;
+
+/// Reasons that a message from another node is dropped before it is handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The message was too large, of an unknown type, or missing required fields
+    Malformed,
+
+    /// The node sent messages of this type faster than its rate limit allows
+    RateLimited,
+
+    /// The message was for a view or sequence number too far behind this node's own
+    Stale,
+
+    /// The message was for a view or sequence number too far ahead of this node's own
+    Ahead,
+
+    /// The message was a copy of one that was already received
+    Replayed,
+
+    /// The node was quarantined for sending too many bad messages
+    Quarantined,
+}
+
+/// How many messages from a single node have been dropped, and why
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RejectedMessages {
+    pub malformed: u64,
+    pub rate_limited: u64,
+    pub stale: u64,
+    pub ahead: u64,
+    pub replayed: u64,
+    pub quarantined: u64,
+}
+
+impl RejectedMessages {
+    /// Count one more dropped message
+    pub fn count(&mut self, rejection: Rejection) {
+        match rejection {
+            Rejection::Malformed => self.malformed += 1,
+            Rejection::RateLimited => self.rate_limited += 1,
+            Rejection::Stale => self.stale += 1,
+            Rejection::Ahead => self.ahead += 1,
+            Rejection::Replayed => self.replayed += 1,
+            Rejection::Quarantined => self.quarantined += 1,
+        }
+    }
+}
+
+/// Check the parts of a message that can be checked without decoding it: that it isn't larger
+/// than `max_size` bytes, and that it's of a type that nodes send to each other
+pub fn check_wire_format(msg: &PeerMessage, max_size: u64) -> Result<PbftMessageType, PbftError> {
+    if msg.content.len() as u64 > max_size {
+        return Err(PbftError::MalformedMessage(format!(
+            "{} bytes is larger than the maximum of {}",
+            msg.content.len(),
+            max_size
+        )));
+    }
+
+    match PbftMessageType::from(msg.message_type.as_str()) {
+        PbftMessageType::BlockNew | PbftMessageType::Unset => Err(PbftError::MalformedMessage(
+            format!("Unknown message type '{}'", msg.message_type),
+        )),
+        msg_type => Ok(msg_type),
+    }
+}
+
+/// Check that a decoded message's info has the fields every message needs: the same message type
+/// that the message was sent as, and the node that sent it. A trace ID is optional, but has to be
+/// the right size if it's there.
+pub fn check_info(msg_type: &PbftMessageType, info: &PbftMessageInfo) -> Result<(), PbftError> {
+    if info.get_msg_type() != String::from(msg_type) {
+        return Err(PbftError::MalformedMessage(format!(
+            "{:?} message has message type '{}' in its info",
+            msg_type,
+            info.get_msg_type()
+        )));
+    }
+
+    if info.get_signer_id().is_empty() {
+        return Err(PbftError::MalformedMessage(format!(
+            "{:?} message has no signer ID",
+            msg_type
+        )));
+    }
+
+    if !info.get_trace_id().is_empty() && info.get_trace_id().len() != TRACE_ID_LENGTH {
+        return Err(PbftError::MalformedMessage(format!(
+            "{:?} message has a {}-byte trace ID",
+            msg_type,
+            info.get_trace_id().len()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::{make_msg_info, new_trace_id};
+    use sawtooth_sdk::consensus::engine::PeerId;
+
+    /// Make sure that messages that are too large, of unknown types, missing required fields, or
+    /// with trace IDs of the wrong size are rejected, and that well-formed messages are accepted
+    #[test]
+    fn validation() {
+        let msg = |message_type: &str, size| PeerMessage {
+            message_type: String::from(message_type),
+            content: vec![0; size],
+        };
+
+        assert_eq!(
+            check_wire_format(&msg("Commit", 10), 10).unwrap(),
+            PbftMessageType::Commit
+        );
+        assert!(check_wire_format(&msg("Commit", 11), 10).is_err());
+        assert!(check_wire_format(&msg("BlockNew", 1), 10).is_err());
+        assert!(check_wire_format(&msg("Gossip", 1), 10).is_err());
+
+        let peer_id = PeerId::from(vec![1, 2, 3]);
+        let info = make_msg_info(&PbftMessageType::Commit, 0, 1, peer_id.clone());
+        assert!(check_info(&PbftMessageType::Commit, &info).is_ok());
+        assert!(check_info(&PbftMessageType::Prepare, &info).is_err());
+
+        let mut traced = info.clone();
+        traced.set_trace_id(new_trace_id(b"block", &peer_id, 0, 1));
+        assert!(check_info(&PbftMessageType::Commit, &traced).is_ok());
+        traced.set_trace_id(vec![0; 1000]);
+        assert!(check_info(&PbftMessageType::Commit, &traced).is_err());
+
+        let info = make_msg_info(&PbftMessageType::Commit, 0, 1, PeerId::from(vec![]));
+        assert!(check_info(&PbftMessageType::Commit, &info).is_err());
+        assert!(check_info(&PbftMessageType::Commit, &PbftMessageInfo::new()).is_err());
+    }
+}
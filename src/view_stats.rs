@@ -0,0 +1,319 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Statistics about each view this node has been in
+//!
+//! Frequent view changes are a sign that something is wrong with the network or its primaries, and
+//! why each view ended says what. Each view's statistics are logged when the view ends, and the
+//! most recent views are kept in the node's state, so they show up in state dumps and crash dumps.
+//! Each view change is also described by a `ViewChangeRecord`, for the node's audit trail.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hex;
+use sawtooth_sdk::consensus::engine::PeerId;
+use serde_json::{self, Value};
+
+use timing::{self, millis};
+
+/// How many views that have ended are remembered
+const MAX_VIEW_HISTORY: usize = 100;
+
+/// Why a view ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViewChangeReason {
+    /// A block took too long to commit, or the primary didn't publish one in time
+    CommitTimeout,
+
+    /// The primary didn't respond to `Probe`s
+    ProbeFailures,
+
+    /// The primary didn't send `Heartbeat`s while idle
+    IdleTimeout,
+
+    /// The validator found the primary's block invalid
+    InvalidBlock,
+
+    /// The primary handed off before shutting down
+    Handoff,
+
+    /// The primary was removed from the network
+    PrimaryRemoved,
+
+    /// Other nodes started the view change, and this node joined them
+    OtherNodes,
+
+    /// An operator asked for it
+    Operator,
+}
+
+impl fmt::Display for ViewChangeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            ViewChangeReason::CommitTimeout => "commit timeout",
+            ViewChangeReason::ProbeFailures => "probe failures",
+            ViewChangeReason::IdleTimeout => "idle timeout",
+            ViewChangeReason::InvalidBlock => "invalid block",
+            ViewChangeReason::Handoff => "handoff",
+            ViewChangeReason::PrimaryRemoved => "primary removed",
+            ViewChangeReason::OtherNodes => "view change by other nodes",
+            ViewChangeReason::Operator => "operator request",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// What happened during a single view
+#[derive(Clone, Debug)]
+pub struct ViewStats {
+    pub view: u64,
+
+    /// How many blocks were committed
+    pub blocks_committed: u64,
+
+    /// How many blocks were found invalid, or were abandoned because the view ended
+    pub failed_proposals: u64,
+
+    /// When this node entered the view
+    pub started: Instant,
+
+    /// How long this node was in the view (`None` until it ends)
+    pub duration: Option<Duration>,
+
+    /// Why the view ended, once a view change has started (this node's own reason, if it started
+    /// the view change itself)
+    pub end_reason: Option<ViewChangeReason>,
+
+    /// When this node started the view change that ended the view (`None` if it never started
+    /// one, because the other nodes finished the view change first)
+    pub view_change_started: Option<Instant>,
+}
+
+impl ViewStats {
+    fn new(view: u64) -> Self {
+        ViewStats {
+            view,
+            blocks_committed: 0,
+            failed_proposals: 0,
+            started: timing::now(),
+            duration: None,
+            end_reason: None,
+            view_change_started: None,
+        }
+    }
+}
+
+impl fmt::Display for ViewStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "view {}: {} blocks committed, {} failed proposals",
+            self.view, self.blocks_committed, self.failed_proposals
+        )?;
+        if let Some(duration) = self.duration {
+            write!(f, ", lasted {:?}", duration)?;
+        }
+        if let Some(reason) = self.end_reason {
+            write!(f, ", ended by {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics for the current view and the views before it
+#[derive(Debug)]
+pub struct ViewHistory {
+    current: ViewStats,
+    ended: VecDeque<ViewStats>,
+}
+
+impl ViewHistory {
+    pub fn new(view: u64) -> Self {
+        ViewHistory {
+            current: ViewStats::new(view),
+            ended: VecDeque::new(),
+        }
+    }
+
+    /// Statistics for the view this node is in
+    pub fn current(&self) -> &ViewStats {
+        &self.current
+    }
+
+    /// Statistics for the views that have ended, oldest first
+    pub fn ended(&self) -> impl Iterator<Item = &ViewStats> {
+        self.ended.iter()
+    }
+
+    pub fn record_commit(&mut self) {
+        self.current.blocks_committed += 1;
+    }
+
+    pub fn record_failed_proposal(&mut self) {
+        self.current.failed_proposals += 1;
+    }
+
+    /// Record why the current view is ending; only the first reason given is kept, since later
+    /// ones are just the view change running its course
+    pub fn record_end_reason(&mut self, reason: ViewChangeReason) {
+        if self.current.end_reason.is_none() {
+            self.current.end_reason = Some(reason);
+            self.current.view_change_started = Some(timing::now());
+        }
+    }
+
+    /// End the current view and start recording a new one. Returns the statistics of the view
+    /// that ended.
+    pub fn enter_view(&mut self, view: u64) -> ViewStats {
+        let mut ended = ::std::mem::replace(&mut self.current, ViewStats::new(view));
+        ended.duration = Some(timing::now() - ended.started);
+        if ended.end_reason.is_none() {
+            ended.end_reason = Some(ViewChangeReason::OtherNodes);
+        }
+
+        self.ended.push_back(ended.clone());
+        if self.ended.len() > MAX_VIEW_HISTORY {
+            self.ended.pop_front();
+        }
+
+        ended
+    }
+}
+
+/// An entry in the audit trail of view changes, written when this node enters a new view
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewChangeRecord {
+    /// When the node entered the new view
+    pub time: SystemTime,
+
+    pub from_view: u64,
+    pub view: u64,
+    pub reason: Option<ViewChangeReason>,
+
+    /// The nodes whose `ViewChange` messages for the new view were counted, sorted (empty if the
+    /// node entered the view without them, such as when the primary was removed)
+    pub voters: Vec<PeerId>,
+
+    /// How long the node was in the view it left
+    pub view_duration: Duration,
+
+    /// How long the view change took, from when this node started it (`None` if it didn't)
+    pub view_change_duration: Option<Duration>,
+}
+
+impl ViewChangeRecord {
+    /// Describe the view change that ended the given view, now that the node is in the new one
+    pub fn new(ended: &ViewStats, view: u64, mut voters: Vec<PeerId>) -> Self {
+        voters.sort();
+        voters.dedup();
+        ViewChangeRecord {
+            time: SystemTime::now(),
+            from_view: ended.view,
+            view,
+            reason: ended.end_reason,
+            voters,
+            view_duration: ended.duration.unwrap_or_default(),
+            view_change_duration: ended
+                .view_change_started
+                .map(|started| timing::now() - started),
+        }
+    }
+
+    /// Write the record as a single line of JSON; times are in milliseconds, since the Unix epoch
+    /// for `time`
+    pub fn to_json(&self) -> String {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        fields.insert(String::from("time"), Value::from(millis(since_epoch)));
+        fields.insert(String::from("from_view"), Value::from(self.from_view));
+        fields.insert(String::from("view"), Value::from(self.view));
+        fields.insert(
+            String::from("reason"),
+            self.reason
+                .map_or(Value::Null, |reason| Value::from(format!("{:?}", reason))),
+        );
+        fields.insert(
+            String::from("voters"),
+            Value::Array(
+                self.voters
+                    .iter()
+                    .map(|peer_id| Value::from(hex::encode(Vec::<u8>::from(peer_id.clone()))))
+                    .collect(),
+            ),
+        );
+        fields.insert(
+            String::from("view_duration_ms"),
+            Value::from(millis(self.view_duration)),
+        );
+        fields.insert(
+            String::from("view_change_duration_ms"),
+            self.view_change_duration
+                .map_or(Value::Null, |duration| Value::from(millis(duration))),
+        );
+        serde_json::to_string(&fields).expect("Couldn't write view change record as JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that each view's counters, duration, and end reason are recorded when the view
+    /// ends, that only the first end reason counts, and that only so many views are remembered
+    #[test]
+    fn view_history() {
+        timing::start_virtual_time(1);
+        let start = timing::now();
+        let mut history = ViewHistory::new(0);
+
+        history.record_commit();
+        history.record_commit();
+        history.record_failed_proposal();
+        history.record_end_reason(ViewChangeReason::InvalidBlock);
+        history.record_end_reason(ViewChangeReason::CommitTimeout);
+        timing::set_virtual_time(start + Duration::from_secs(5));
+
+        let ended = history.enter_view(2);
+        assert_eq!(ended.view, 0);
+        assert_eq!(ended.blocks_committed, 2);
+        assert_eq!(ended.failed_proposals, 1);
+        assert_eq!(ended.duration, Some(Duration::from_secs(5)));
+        assert_eq!(ended.end_reason, Some(ViewChangeReason::InvalidBlock));
+
+        assert_eq!(history.current().view, 2);
+        assert_eq!(history.current().blocks_committed, 0);
+        assert_eq!(history.current().end_reason, None);
+
+        // A view that ends without this node starting the view change was ended by other nodes
+        let ended = history.enter_view(3);
+        assert_eq!(ended.end_reason, Some(ViewChangeReason::OtherNodes));
+        assert_eq!(
+            history.ended().map(|stats| stats.view).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+
+        for view in 4..(4 + MAX_VIEW_HISTORY as u64) {
+            history.enter_view(view);
+        }
+        assert_eq!(history.ended().count(), MAX_VIEW_HISTORY);
+        assert_eq!(history.ended().next().unwrap().view, 3);
+
+        timing::stop_virtual_time();
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! The votes a node has sent, so that it never contradicts them
+//!
+//! A node that sends a `Prepare` for one block and then, after restarting, a `Prepare` for
+//! another block in the same view and sequence number is indistinguishable from a faulty one. So
+//! every `PrePrepare`, `Prepare`, and `Commit` the node sends is recorded in its `SentVotes` first,
+//! and, if the node has a state directory, saved there before the message goes out. A vote that
+//! would contradict a recorded one is refused instead of sent.
+
+use std::collections::BTreeMap;
+
+use hex;
+use protobuf::RepeatedField;
+
+use error::PbftError;
+use message_type::PbftMessageType;
+use protos::pbft_message::PbftVote;
+
+/// The votes this node has sent for sequence numbers it hasn't committed yet
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SentVotes {
+    /// The block voted for, by sequence number, view, and message type
+    votes: BTreeMap<(u64, u64, String), Vec<u8>>,
+}
+
+impl SentVotes {
+    pub fn new() -> Self {
+        SentVotes::default()
+    }
+
+    /// Record a vote that's about to be sent. Returns `true` if it wasn't recorded already, so
+    /// the record has to be saved before the vote is sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InternalError` if this node already voted for a different block with the same
+    /// type of message, in the same view and sequence number.
+    pub fn record(
+        &mut self,
+        msg_type: &PbftMessageType,
+        view: u64,
+        seq_num: u64,
+        block_id: &[u8],
+    ) -> Result<bool, PbftError> {
+        let key = (seq_num, view, String::from(msg_type));
+        if let Some(voted) = self.votes.get(&key) {
+            if voted.as_slice() == block_id {
+                return Ok(false);
+            }
+            return Err(PbftError::InternalError(format!(
+                "Refusing to send {} for block {} (v {}, seq {}); already sent one for block {}",
+                msg_type,
+                &hex::encode(block_id),
+                view,
+                seq_num,
+                &hex::encode(voted),
+            )));
+        }
+
+        self.votes.insert(key, block_id.to_vec());
+        Ok(true)
+    }
+
+    /// Forget the votes for sequence numbers before the given one, which have been committed
+    pub fn prune(&mut self, seq_num: u64) {
+        self.votes = self.votes.split_off(&(seq_num, 0, String::new()));
+    }
+
+    /// For each sequence number after the given one that this node sent a vote of the given type
+    /// for, the highest view it sent one in and the block it voted for there, as `(sequence
+    /// number, view, block ID)`, ordered by sequence number
+    pub fn latest_votes(&self, msg_type: &PbftMessageType, after: u64) -> Vec<(u64, u64, &[u8])> {
+        let msg_type = String::from(msg_type);
+        let mut latest: BTreeMap<u64, (u64, &[u8])> = BTreeMap::new();
+        for (&(seq_num, view, ref vote_type), block_id) in &self.votes {
+            // Votes are ordered by sequence number and then view, so later ones replace earlier
+            if seq_num > after && *vote_type == msg_type {
+                latest.insert(seq_num, (view, block_id.as_slice()));
+            }
+        }
+        latest
+            .into_iter()
+            .map(|(seq_num, (view, block_id))| (seq_num, view, block_id))
+            .collect()
+    }
+
+    /// The votes, for saving
+    pub fn to_protos(&self) -> RepeatedField<PbftVote> {
+        self.votes
+            .iter()
+            .map(|(&(seq_num, view, ref msg_type), block_id)| {
+                let mut vote = PbftVote::new();
+                vote.set_msg_type(msg_type.clone());
+                vote.set_view(view);
+                vote.set_seq_num(seq_num);
+                vote.set_block_id(block_id.clone());
+                vote
+            })
+            .collect()
+    }
+
+    /// Restore saved votes
+    pub fn from_protos(votes: &[PbftVote]) -> Self {
+        SentVotes {
+            votes: votes
+                .iter()
+                .map(|vote| {
+                    (
+                        (
+                            vote.get_seq_num(),
+                            vote.get_view(),
+                            vote.get_msg_type().to_string(),
+                        ),
+                        vote.get_block_id().to_vec(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that sending the same vote again is allowed, that a contradicting vote isn't,
+    /// that votes for committed sequence numbers are forgotten, and that saved votes are restored
+    /// the same
+    #[test]
+    fn sent_votes() {
+        let mut votes = SentVotes::new();
+        assert!(votes.record(&PbftMessageType::Prepare, 0, 5, &[1]).unwrap());
+        assert!(!votes.record(&PbftMessageType::Prepare, 0, 5, &[1]).unwrap());
+        assert!(votes.record(&PbftMessageType::Prepare, 0, 5, &[2]).is_err());
+
+        // A different type, view, or sequence number isn't a contradiction
+        assert!(votes.record(&PbftMessageType::Commit, 0, 5, &[1]).unwrap());
+        assert!(votes.record(&PbftMessageType::Prepare, 1, 5, &[2]).unwrap());
+        assert!(votes.record(&PbftMessageType::Prepare, 0, 6, &[2]).unwrap());
+
+        let restored = SentVotes::from_protos(votes.to_protos().as_slice());
+        assert_eq!(restored, votes);
+
+        votes.prune(6);
+        assert_eq!(votes.to_protos().len(), 1);
+        assert!(votes.record(&PbftMessageType::Prepare, 0, 5, &[2]).unwrap());
+    }
+
+    /// Make sure that only the vote from the highest view counts for each sequence number, and
+    /// only for the given type of message
+    #[test]
+    fn latest_votes() {
+        let mut votes = SentVotes::new();
+        votes.record(&PbftMessageType::Prepare, 0, 5, &[1]).unwrap();
+        votes.record(&PbftMessageType::Prepare, 2, 5, &[2]).unwrap();
+        votes.record(&PbftMessageType::Prepare, 0, 6, &[3]).unwrap();
+        votes.record(&PbftMessageType::Commit, 3, 6, &[4]).unwrap();
+
+        assert_eq!(
+            votes.latest_votes(&PbftMessageType::Prepare, 0),
+            vec![(5, 2, &[2][..]), (6, 0, &[3][..])]
+        );
+        assert_eq!(
+            votes.latest_votes(&PbftMessageType::Prepare, 5),
+            vec![(6, 0, &[3][..])]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Noticing when the engine's event loop stops making progress
+//!
+//! The event loop goes around at least once per `message_timeout`, whether or not there's an
+//! update to handle, and feeds the `Watchdog` each time. If a deadlock or a handler that never
+//! returns keeps it from doing so for the watchdog's whole interval, the watchdog's own thread
+//! logs an error (the loop can't report anything itself while it's stuck), and, if it was told
+//! to, aborts the process so a supervisor can restart the node. Otherwise, once the loop gets
+//! going again, the next feeding reports how long the stall lasted, so it can be counted in the
+//! node's metrics.
+
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use timing;
+
+/// How many times per interval the watchdog checks on the loop
+const CHECKS_PER_INTERVAL: u32 = 4;
+
+#[derive(Debug)]
+struct Shared {
+    /// When the watchdog started; times below are in milliseconds since then
+    started: Instant,
+
+    /// When the loop last fed the watchdog
+    last_fed: AtomicU64,
+
+    /// Whether the watchdog has reported the loop as stalled since it was last fed
+    stalled: AtomicBool,
+
+    /// Set to stop the watchdog's thread
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn now(&self) -> u64 {
+        timing::millis(self.started.elapsed())
+    }
+}
+
+/// Watches for the engine's event loop going too long without feeding it
+#[derive(Debug)]
+pub struct Watchdog {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Start watching, in a thread of its own. If the loop goes `interval` without feeding the
+    /// watchdog, it's reported as stalled, and if `abort` is set, the process is aborted.
+    pub fn start(interval: Duration, abort: bool) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            started: Instant::now(),
+            last_fed: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+        });
+
+        let watched = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name(String::from("pbft-watchdog"))
+            .spawn(move || watch(&watched, interval, abort))?;
+
+        Ok(Watchdog {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Let the watchdog know that the loop is making progress. If the watchdog had reported the
+    /// loop as stalled, returns how long it went without feeding the watchdog.
+    pub fn feed(&self) -> Option<Duration> {
+        let now = self.shared.now();
+        let last_fed = self.shared.last_fed.swap(now, Ordering::SeqCst);
+        if self.shared.stalled.swap(false, Ordering::SeqCst) {
+            let stall = Duration::from_millis(now.saturating_sub(last_fed));
+            warn!("Event loop is making progress again, after {:?}", stall);
+            Some(stall)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(shared: &Shared, interval: Duration, abort: bool) {
+    let check_interval = interval / CHECKS_PER_INTERVAL;
+    while !shared.stop.load(Ordering::SeqCst) {
+        thread::park_timeout(check_interval);
+
+        let since_fed =
+            Duration::from_millis(shared.now() - shared.last_fed.load(Ordering::SeqCst));
+        if since_fed < interval || shared.stalled.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        shared.stalled.store(true, Ordering::SeqCst);
+        error!(
+            "Event loop hasn't made progress in {:?}; it may be deadlocked, or stuck handling an \
+             update",
+            since_fed
+        );
+        if abort {
+            error!("Aborting, so the node can be restarted");
+            process::abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that a loop that keeps feeding the watchdog isn't reported, that one that stops
+    /// is, and that feeding it again reports how long the stall lasted
+    #[test]
+    fn watchdog() {
+        let interval = Duration::from_millis(200);
+        let watchdog = Watchdog::start(interval, false).unwrap();
+
+        for _ in 0..20 {
+            thread::sleep(interval / 10);
+            assert_eq!(watchdog.feed(), None);
+        }
+
+        thread::sleep(interval * 3);
+        assert!(watchdog.shared.stalled.load(Ordering::SeqCst));
+        let stall = watchdog.feed().expect("Stall wasn't reported");
+        assert!(stall >= interval * 3);
+        assert_eq!(watchdog.feed(), None);
+    }
+}